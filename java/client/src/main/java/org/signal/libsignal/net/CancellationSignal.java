@@ -0,0 +1,42 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+import org.signal.libsignal.internal.Native;
+import org.signal.libsignal.internal.NativeHandleGuard;
+
+/**
+ * Cancels in-flight network operations.
+ *
+ * <p>A single signal can be passed to any number of operations. Cancelling it stops all of them,
+ * including any started afterwards; cancelling after an operation has completed has no effect.
+ * Futures of cancelled operations complete with a {@link java.util.concurrent.CancellationException}
+ * (wrapped in an {@link java.util.concurrent.ExecutionException}).
+ */
+public class CancellationSignal implements NativeHandleGuard.Owner {
+  private long nativeHandle;
+
+  public CancellationSignal() {
+    this.nativeHandle = Native.CancellationSignal_new();
+  }
+
+  public void cancel() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this)) {
+      Native.CancellationSignal_cancel(guard.nativeHandle());
+    }
+  }
+
+  @Override
+  public long unsafeNativeHandleWithoutGuard() {
+    return this.nativeHandle;
+  }
+
+  @Override
+  @SuppressWarnings("deprecation")
+  protected void finalize() {
+    Native.CancellationSignal_Destroy(this.nativeHandle);
+  }
+}
@@ -20,29 +20,35 @@ class CdsiLookup implements NativeHandleGuard.Owner {
       String username,
       String password,
       CdsiLookupRequest request,
-      Duration timeout)
+      Duration timeout,
+      CancellationSignal cancellation)
       throws IOException, InterruptedException, ExecutionException {
 
     CdsiLookupRequest.NativeRequest nativeRequest = request.makeNative();
     try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(network.getAsyncContext());
         NativeHandleGuard connectionManager =
-            new NativeHandleGuard(network.getConnectionManager())) {
+            new NativeHandleGuard(network.getConnectionManager());
+        NativeHandleGuard cancellationSignal = new NativeHandleGuard(cancellation)) {
 
       return Native.CdsiLookup_new(
               asyncRuntime.nativeHandle(),
               connectionManager.nativeHandle(),
+              cancellationSignal.nativeHandle(),
               username,
               password,
               nativeRequest.getHandle(),
               timeoutMillis(timeout))
-          .thenApply((Long nativeHandle) -> new CdsiLookup(nativeHandle, network));
+          .thenApply(
+              (Long nativeHandle) -> new CdsiLookup(nativeHandle, network, cancellation));
     }
   }
 
   public CompletableFuture<CdsiLookupResponse> complete() {
     try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(this.network.getAsyncContext());
-        NativeHandleGuard self = new NativeHandleGuard(this)) {
-      return Native.CdsiLookup_complete(asyncRuntime.nativeHandle(), self.nativeHandle())
+        NativeHandleGuard self = new NativeHandleGuard(this);
+        NativeHandleGuard cancellationSignal = new NativeHandleGuard(this.cancellation)) {
+      return Native.CdsiLookup_complete(
+              asyncRuntime.nativeHandle(), self.nativeHandle(), cancellationSignal.nativeHandle())
           .thenApply(response -> (CdsiLookupResponse) response);
     }
   }
@@ -56,12 +62,14 @@ class CdsiLookup implements NativeHandleGuard.Owner {
     return this.nativeHandle;
   }
 
-  private CdsiLookup(long nativeHandle, Network network) {
+  private CdsiLookup(long nativeHandle, Network network, CancellationSignal cancellation) {
     this.nativeHandle = nativeHandle;
     this.network = network;
+    this.cancellation = cancellation;
   }
 
   private Network network;
+  private CancellationSignal cancellation;
   private long nativeHandle;
 
   @Override
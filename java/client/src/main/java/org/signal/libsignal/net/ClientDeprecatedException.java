@@ -0,0 +1,13 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+/** The server no longer accepts this version of the app; it has to be updated. */
+public class ClientDeprecatedException extends NetworkException {
+  public ClientDeprecatedException(String message) {
+    super(message);
+  }
+}
@@ -23,6 +23,20 @@ public class Network {
     private Environment(int value) {
       this.value = value;
     }
+
+    /**
+     * Describe the SVR3 and CDSI enclaves of this environment, for checking which ones a client is
+     * configured with.
+     *
+     * <p>The result is safe to log. It holds the hostnames, ports, path prefixes, route weights,
+     * enclave measurements, and SVR3 server IDs, along with a short {@code hash} of each part that
+     * only changes when the configuration does.
+     *
+     * @return a JSON object with the fields {@code svr3} and {@code cdsi}.
+     */
+    public String fingerprint() {
+      return Native.Environment_Fingerprint(this.value);
+    }
   }
 
   /**
@@ -42,6 +56,18 @@ public class Network {
     return this.svr3;
   }
 
+  /**
+   * Notify libsignal that the device's network changed, for example from Wi-Fi to cellular.
+   *
+   * <p>Operations that then fail on connections made before the change throw a {@link
+   * NetworkException} saying so, and should be run again.
+   */
+  public void onNetworkChange() {
+    try (NativeHandleGuard guard = new NativeHandleGuard(this.connectionManager)) {
+      Native.ConnectionManager_on_network_change(guard.nativeHandle());
+    }
+  }
+
   public CompletableFuture<CdsiLookupResponse> cdsiLookup(
       String username,
       String password,
@@ -49,7 +75,26 @@ public class Network {
       Duration timeout,
       Consumer<byte[]> tokenConsumer)
       throws IOException, InterruptedException, ExecutionException {
-    return CdsiLookup.start(this, username, password, request, timeout)
+    return cdsiLookup(
+        username, password, request, timeout, tokenConsumer, new CancellationSignal());
+  }
+
+  /**
+   * Look up phone numbers with CDSI, unless {@code cancellation} is cancelled first.
+   *
+   * <p>Same as {@link #cdsiLookup(String, String, CdsiLookupRequest, Duration, Consumer)}, except
+   * that cancelling the signal abandons the lookup and completes the future with a {@link
+   * java.util.concurrent.CancellationException}.
+   */
+  public CompletableFuture<CdsiLookupResponse> cdsiLookup(
+      String username,
+      String password,
+      CdsiLookupRequest request,
+      Duration timeout,
+      Consumer<byte[]> tokenConsumer,
+      CancellationSignal cancellation)
+      throws IOException, InterruptedException, ExecutionException {
+    return CdsiLookup.start(this, username, password, request, timeout, cancellation)
         .thenCompose(
             (CdsiLookup lookup) -> {
               tokenConsumer.accept(lookup.getToken());
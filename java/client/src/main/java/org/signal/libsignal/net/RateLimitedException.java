@@ -0,0 +1,22 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+import java.time.Duration;
+
+/** The server is rate limiting this client; try again after {@link #getRetryAfter()}. */
+public class RateLimitedException extends NetworkException {
+  private final Duration retryAfter;
+
+  public RateLimitedException(String message, long retryAfterSeconds) {
+    super(message);
+    this.retryAfter = Duration.ofSeconds(retryAfterSeconds);
+  }
+
+  public Duration getRetryAfter() {
+    return this.retryAfter;
+  }
+}
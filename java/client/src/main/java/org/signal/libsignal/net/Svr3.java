@@ -7,6 +7,7 @@ package org.signal.libsignal.net;
 
 import static org.signal.libsignal.net.DurationExt.timeoutMillis;
 
+import java.nio.charset.StandardCharsets;
 import java.time.Duration;
 import org.signal.libsignal.internal.CompletableFuture;
 import org.signal.libsignal.internal.Native;
@@ -79,13 +80,32 @@ public final class Svr3 {
    */
   public final CompletableFuture<byte[]> backup(
       byte[] what, String password, int maxTries, EnclaveAuth auth, Duration timeout) {
+    return backup(what, password, maxTries, auth, timeout, new CancellationSignal());
+  }
+
+  /**
+   * Backup a secret to SVR3, unless {@code cancellation} is cancelled first.
+   *
+   * <p>Same as {@link #backup(byte[], String, int, EnclaveAuth, Duration)}, except that cancelling
+   * the signal abandons the operation and completes the future with a {@link
+   * java.util.concurrent.CancellationException}.
+   */
+  public final CompletableFuture<byte[]> backup(
+      byte[] what,
+      String password,
+      int maxTries,
+      EnclaveAuth auth,
+      Duration timeout,
+      CancellationSignal cancellation) {
     try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(this.network.getAsyncContext());
         NativeHandleGuard connectionManager =
-            new NativeHandleGuard(this.network.getConnectionManager())) {
+            new NativeHandleGuard(this.network.getConnectionManager());
+        NativeHandleGuard cancellationSignal = new NativeHandleGuard(cancellation)) {
 
       return Native.Svr3Backup(
           asyncRuntime.nativeHandle(),
           connectionManager.nativeHandle(),
+          cancellationSignal.nativeHandle(),
           what,
           password,
           maxTries,
@@ -137,13 +157,31 @@ public final class Svr3 {
    */
   public final CompletableFuture<byte[]> restore(
       String password, byte[] shareSet, EnclaveAuth auth, Duration timeout) {
+    return restore(password, shareSet, auth, timeout, new CancellationSignal());
+  }
+
+  /**
+   * Restore a secret from SVR3, unless {@code cancellation} is cancelled first.
+   *
+   * <p>Same as {@link #restore(String, byte[], EnclaveAuth, Duration)}, except that cancelling the
+   * signal abandons the operation and completes the future with a {@link
+   * java.util.concurrent.CancellationException}.
+   */
+  public final CompletableFuture<byte[]> restore(
+      String password,
+      byte[] shareSet,
+      EnclaveAuth auth,
+      Duration timeout,
+      CancellationSignal cancellation) {
     try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(this.network.getAsyncContext());
         NativeHandleGuard connectionManager =
-            new NativeHandleGuard(this.network.getConnectionManager())) {
+            new NativeHandleGuard(this.network.getConnectionManager());
+        NativeHandleGuard cancellationSignal = new NativeHandleGuard(cancellation)) {
 
       return Native.Svr3Restore(
           asyncRuntime.nativeHandle(),
           connectionManager.nativeHandle(),
+          cancellationSignal.nativeHandle(),
           password,
           shareSet,
           auth.username,
@@ -151,4 +189,81 @@ public final class Svr3 {
           timeoutMillis(timeout));
     }
   }
+
+  /**
+   * Remove a value stored in SVR3.
+   *
+   * <p>This method will succeed even if the data has never been backed up in the first place.
+   *
+   * <p>Exception messages are expected to be log-safe and not contain any sensitive data.
+   *
+   * @param auth an instance of {@link org.signal.libsignal.net.EnclaveAuth} containing the username
+   *     and password obtained from the Chat Server. The password is an OTP which is generally good
+   *     for about 15 minutes, therefore it can be reused for the subsequent calls to either backup
+   *     or restore that are not too far apart in time.
+   * @param timeout The maximum wall time libsignal is allowed to spend communicating with SVR3
+   *     service.
+   * @return an instance of {@link org.signal.libsignal.internal.CompletableFuture} which-when
+   *     awaited-will signal if the operation succeeded or not.
+   * @throws {@link org.signal.libsignal.net.NetworkException} in case of network related errors,
+   *     including timeouts and failed auth.
+   * @throws {@link org.signal.libsignal.attest.AttestationFailedException} when an attempt to
+   *     validate the server attestation document fails.
+   * @throws {@link org.signal.libsignal.sgxsession.SgxCommunicationFailureException} when a Noise
+   *     connection error happens.
+   */
+  public final CompletableFuture<Void> remove(EnclaveAuth auth, Duration timeout) {
+    return remove(auth, timeout, new CancellationSignal());
+  }
+
+  /**
+   * Remove a value stored in SVR3, unless {@code cancellation} is cancelled first.
+   *
+   * <p>Same as {@link #remove(EnclaveAuth, Duration)}, except that cancelling the signal abandons
+   * the operation and completes the future with a {@link
+   * java.util.concurrent.CancellationException}.
+   */
+  public final CompletableFuture<Void> remove(
+      EnclaveAuth auth, Duration timeout, CancellationSignal cancellation) {
+    try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(this.network.getAsyncContext());
+        NativeHandleGuard connectionManager =
+            new NativeHandleGuard(this.network.getConnectionManager());
+        NativeHandleGuard cancellationSignal = new NativeHandleGuard(cancellation)) {
+
+      return Native.Svr3Remove(
+          asyncRuntime.nativeHandle(),
+          connectionManager.nativeHandle(),
+          cancellationSignal.nativeHandle(),
+          auth.username,
+          auth.password,
+          timeoutMillis(timeout));
+    }
+  }
+
+  /**
+   * Check connectivity to each of the SVR3 enclaves.
+   *
+   * <p>Connects and attests to every enclave, then disconnects without making any requests, so no
+   * stored data is affected. Failures are reported in the result rather than thrown.
+   *
+   * @param auth an instance of {@link org.signal.libsignal.net.EnclaveAuth} containing the username
+   *     and password obtained from the Chat Server.
+   * @return an instance of {@link org.signal.libsignal.internal.CompletableFuture} which-when
+   *     awaited-will return a JSON array with one object per enclave, holding the fields {@code
+   *     enclave}, {@code error} (null on success), {@code route}, {@code websocketMillis}, and
+   *     {@code attestationMillis}.
+   */
+  public final CompletableFuture<String> connectDiagnostics(EnclaveAuth auth) {
+    try (NativeHandleGuard asyncRuntime = new NativeHandleGuard(this.network.getAsyncContext());
+        NativeHandleGuard connectionManager =
+            new NativeHandleGuard(this.network.getConnectionManager())) {
+
+      return Native.Svr3ConnectDiagnostics(
+              asyncRuntime.nativeHandle(),
+              connectionManager.nativeHandle(),
+              auth.username,
+              auth.password)
+          .thenApply(json -> new String(json, StandardCharsets.UTF_8));
+    }
+  }
 }
@@ -0,0 +1,26 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.svr;
+
+/**
+ * Only some of the enclaves accepted a new backup.
+ *
+ * <p>Neither the new backup nor the previous one can be restored until the secret is backed up
+ * again, over new connections.
+ */
+public final class EnclaveDisagreementException extends SvrException {
+  private final boolean[] accepted;
+
+  public EnclaveDisagreementException(String message, boolean[] accepted) {
+    super(message);
+    this.accepted = accepted;
+  }
+
+  /** Whether each enclave accepted the backup, in the order of the environment's enclaves. */
+  public boolean[] getAccepted() {
+    return this.accepted.clone();
+  }
+}
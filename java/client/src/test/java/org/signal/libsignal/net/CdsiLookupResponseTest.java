@@ -7,8 +7,12 @@ package org.signal.libsignal.net;
 
 import static org.junit.Assert.*;
 
+import java.time.Duration;
 import java.util.Map;
+import java.util.Optional;
+import java.util.Set;
 import java.util.UUID;
+import java.util.concurrent.CancellationException;
 import java.util.concurrent.ExecutionException;
 import java.util.concurrent.Future;
 import org.junit.Test;
@@ -53,4 +57,26 @@ public class CdsiLookupResponseTest {
   public void cdsiLookupErrorConvert() {
     assertThrows(java.lang.Exception.class, () -> Native.TESTING_CdsiLookupErrorConvert());
   }
+
+  @Test
+  public void cdsiLookupCancelledBeforeStart() throws Exception {
+    Network net = new Network(Network.Environment.STAGING);
+    CancellationSignal cancellation = new CancellationSignal();
+    cancellation.cancel();
+    CdsiLookupRequest request = new CdsiLookupRequest(Set.of(), Set.of(), Map.of(), false, Optional.empty());
+    ExecutionException ex =
+        assertThrows(
+            ExecutionException.class,
+            () ->
+                net.cdsiLookup(
+                        "username",
+                        "password",
+                        request,
+                        Duration.ofSeconds(10),
+                        token -> {},
+                        cancellation)
+                    .get());
+    assertTrue(
+        "Unexpected exception: " + ex.getCause(), ex.getCause() instanceof CancellationException);
+  }
 }
@@ -0,0 +1,36 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+import static org.junit.Assert.*;
+
+import org.junit.Test;
+
+public class EnvironmentFingerprintTest {
+  @Test
+  public void describesEnclaves() {
+    String fingerprint = Network.Environment.STAGING.fingerprint();
+    assertTrue(fingerprint, fingerprint.startsWith("{"));
+    for (String key :
+        new String[] {
+          "\"svr3\"",
+          "\"cdsi\"",
+          "\"serverIds\":[1,2]",
+          "\"pathPrefix\"",
+          "\"routeWeights\"",
+          "\"mrEnclave\"",
+          "\"hash\""
+        }) {
+      assertTrue(fingerprint, fingerprint.contains(key));
+    }
+  }
+
+  @Test
+  public void differsBetweenEnvironments() {
+    assertNotEquals(
+        Network.Environment.STAGING.fingerprint(), Network.Environment.PRODUCTION.fingerprint());
+  }
+}
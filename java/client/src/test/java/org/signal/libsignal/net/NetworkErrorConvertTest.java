@@ -0,0 +1,36 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+import static org.junit.Assert.*;
+
+import java.time.Duration;
+import org.junit.Test;
+import org.signal.libsignal.internal.Native;
+
+public class NetworkErrorConvertTest {
+  @Test
+  public void clientDeprecatedErrorConvert() {
+    assertThrows(
+        ClientDeprecatedException.class, () -> Native.TESTING_Svr3ClientDeprecatedErrorConvert());
+  }
+
+  @Test
+  public void rateLimitedErrorConvert() {
+    RateLimitedException e =
+        assertThrows(
+            RateLimitedException.class, () -> Native.TESTING_Svr3RateLimitedErrorConvert());
+    assertEquals(Duration.ofSeconds(42), e.getRetryAfter());
+  }
+
+  @Test
+  public void cdsiRateLimitedErrorConvert() {
+    RateLimitedException e =
+        assertThrows(
+            RateLimitedException.class, () -> Native.TESTING_CdsiRateLimitedErrorConvert());
+    assertEquals(Duration.ofSeconds(42), e.getRetryAfter());
+  }
+}
@@ -9,6 +9,7 @@ import static org.junit.Assert.*;
 
 import java.security.SecureRandom;
 import java.time.Duration;
+import java.util.concurrent.CancellationException;
 import java.util.concurrent.ExecutionException;
 import org.junit.Assume;
 import org.junit.Before;
@@ -118,6 +119,40 @@ public class Svr3Test {
     }
   }
 
+  @Test
+  public void removeDeletesData() throws Exception {
+    Network net = new Network(Network.Environment.STAGING);
+    byte[] shareSet = net.svr3().backup(STORED_SECRET, "password", 2, this.auth, TIMEOUT).get();
+    net.svr3().remove(this.auth, TIMEOUT).get();
+    try {
+      net.svr3().restore("password", shareSet, this.auth, TIMEOUT).get();
+      fail("removed data should be missing");
+    } catch (ExecutionException ex) {
+      Throwable cause = ex.getCause();
+      assertTrue("Unexpected exception: " + cause, cause instanceof DataMissingException);
+    }
+  }
+
+  @Test
+  public void removeWithoutBackup() throws Exception {
+    Network net = new Network(Network.Environment.STAGING);
+    net.svr3().remove(this.auth, TIMEOUT).get();
+  }
+
+  @Test
+  public void cancelledBeforeStart() throws Exception {
+    Network net = new Network(Network.Environment.STAGING);
+    CancellationSignal cancellation = new CancellationSignal();
+    cancellation.cancel();
+    try {
+      net.svr3().backup(STORED_SECRET, "password", 1, this.auth, TIMEOUT, cancellation).get();
+      fail("should have been cancelled");
+    } catch (ExecutionException ex) {
+      Throwable cause = ex.getCause();
+      assertTrue("Unexpected exception: " + cause, cause instanceof CancellationException);
+    }
+  }
+
   @Test
   public void timeout() throws Exception {
     final Duration SHORT_TIMEOUT = Duration.ofMillis(100);
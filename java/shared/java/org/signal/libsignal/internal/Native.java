@@ -155,19 +155,24 @@ public final class Native {
   public static native byte[] CallLinkSecretParams_DeriveFromRootKey(byte[] rootKey);
   public static native byte[] CallLinkSecretParams_GetPublicParams(byte[] paramsBytes);
 
+  public static native void CancellationSignal_Destroy(long handle);
+  public static native void CancellationSignal_cancel(long signal);
+  public static native long CancellationSignal_new();
+
   public static native long Cds2ClientState_New(byte[] mrenclave, byte[] attestationMsg, long currentTimestamp) throws Exception;
 
   public static native Map Cds2Metrics_extract(byte[] attestationMsg) throws Exception;
 
   public static native void CdsiLookup_Destroy(long handle);
-  public static native CompletableFuture<Object> CdsiLookup_complete(long asyncRuntime, long lookup);
-  public static native CompletableFuture<Long> CdsiLookup_new(long asyncRuntime, long connectionManager, String username, String password, long request, int timeoutMillis);
+  public static native CompletableFuture<Object> CdsiLookup_complete(long asyncRuntime, long lookup, long cancellation);
+  public static native CompletableFuture<Long> CdsiLookup_new(long asyncRuntime, long connectionManager, long cancellation, String username, String password, long request, int timeoutMillis);
   public static native byte[] CdsiLookup_token(long lookup);
 
   public static native void Chat_Destroy(long handle);
 
   public static native void ConnectionManager_Destroy(long handle);
   public static native long ConnectionManager_new(int environment);
+  public static native void ConnectionManager_on_network_change(long connectionManager);
 
   public static native void CreateCallLinkCredentialPresentation_CheckValidContents(byte[] presentationBytes) throws Exception;
   public static native void CreateCallLinkCredentialPresentation_Verify(byte[] presentationBytes, byte[] roomId, long now, byte[] serverParamsBytes, byte[] callLinkParamsBytes) throws Exception;
@@ -229,6 +234,8 @@ public final class Native {
   public static native byte[] ECPublicKey_Serialize(long obj) throws Exception;
   public static native boolean ECPublicKey_Verify(long key, byte[] message, byte[] signature) throws Exception;
 
+  public static native String Environment_Fingerprint(int environment);
+
   public static native void ExpiringProfileKeyCredentialResponse_CheckValidContents(byte[] buffer) throws Exception;
 
   public static native void ExpiringProfileKeyCredential_CheckValidContents(byte[] buffer) throws Exception;
@@ -586,12 +593,17 @@ public final class Native {
 
   public static native long Svr2Client_New(byte[] mrenclave, byte[] attestationMsg, long currentTimestamp) throws Exception;
 
-  public static native CompletableFuture<byte[]> Svr3Backup(long asyncRuntime, long connectionManager, byte[] secret, String password, int maxTries, String username, String enclavePassword, int opTimeoutMs);
+  public static native CompletableFuture<byte[]> Svr3Backup(long asyncRuntime, long connectionManager, long cancellation, byte[] secret, String password, int maxTries, String username, String enclavePassword, int opTimeoutMs);
+
+  public static native CompletableFuture<byte[]> Svr3ConnectDiagnostics(long asyncRuntime, long connectionManager, String username, String enclavePassword);
+
+  public static native CompletableFuture<Void> Svr3Remove(long asyncRuntime, long connectionManager, long cancellation, String username, String enclavePassword, int opTimeoutMs);
 
-  public static native CompletableFuture<byte[]> Svr3Restore(long asyncRuntime, long connectionManager, String password, byte[] shareSet, String username, String enclavePassword, int opTimeoutMs);
+  public static native CompletableFuture<byte[]> Svr3Restore(long asyncRuntime, long connectionManager, long cancellation, String password, byte[] shareSet, String username, String enclavePassword, int opTimeoutMs);
 
   public static native void TESTING_CdsiLookupErrorConvert() throws Exception;
   public static native CompletableFuture<Object> TESTING_CdsiLookupResponseConvert(long asyncRuntime);
+  public static native void TESTING_CdsiRateLimitedErrorConvert() throws Exception;
   public static native void TESTING_ErrorOnBorrowAsync(Object input);
   public static native CompletableFuture TESTING_ErrorOnBorrowIo(long asyncRuntime, Object input);
   public static native void TESTING_ErrorOnBorrowSync(Object input);
@@ -618,6 +630,8 @@ public final class Native {
   public static native CompletableFuture<Object> TESTING_PanicOnReturnIo(long asyncRuntime, Object needsCleanup);
   public static native Object TESTING_PanicOnReturnSync(Object needsCleanup);
   public static native Object[] TESTING_ReturnStringArray();
+  public static native void TESTING_Svr3ClientDeprecatedErrorConvert() throws Exception;
+  public static native void TESTING_Svr3RateLimitedErrorConvert() throws Exception;
   public static native int TESTING_TestingHandleType_getValue(long handle);
 
   public static native void TestingHandleType_Destroy(long handle);
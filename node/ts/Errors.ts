@@ -40,10 +40,14 @@ export enum ErrorCode {
   InvalidUsernameLinkEncryptedData,
 
   RateLimitedError,
+  ClientDeprecatedError,
 
   SvrDataMissing,
+  SvrEnclaveDisagreement,
   SvrRequestFailed,
   SvrRestoreFailed,
+
+  Cancelled,
 }
 
 export class LibSignalErrorBase extends Error {
@@ -199,10 +203,21 @@ export type RateLimitedError = LibSignalErrorBase & {
   readonly retryAfterSecs: number;
 };
 
+/** The server no longer accepts this version of the app; it has to be updated. */
+export type ClientDeprecatedError = LibSignalErrorCommon & {
+  code: ErrorCode.ClientDeprecatedError;
+};
+
 export type SvrDataMissingError = LibSignalErrorBase & {
   code: ErrorCode.SvrDataMissing;
 };
 
+export type SvrEnclaveDisagreementError = LibSignalErrorCommon & {
+  code: ErrorCode.SvrEnclaveDisagreement;
+  /** Whether each enclave accepted the backup, in order. */
+  readonly accepted: boolean[];
+};
+
 export type SvrRequestFailedError = LibSignalErrorCommon & {
   code: ErrorCode.SvrRequestFailed;
 };
@@ -211,6 +226,10 @@ export type SvrRestoreFailedError = LibSignalErrorCommon & {
   code: ErrorCode.SvrRestoreFailed;
 };
 
+export type CancelledError = LibSignalErrorCommon & {
+  code: ErrorCode.Cancelled;
+};
+
 export type LibSignalError =
   | GenericError
   | DuplicatedMessageError
@@ -237,7 +256,10 @@ export type LibSignalError =
   | InvalidUsernameLinkEncryptedData
   | IoError
   | InvalidMediaInputError
+  | ClientDeprecatedError
   | SvrDataMissingError
+  | SvrEnclaveDisagreementError
   | SvrRestoreFailedError
   | SvrRequestFailedError
-  | UnsupportedMediaInputError;
+  | UnsupportedMediaInputError
+  | CancelledError;
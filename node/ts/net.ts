@@ -7,6 +7,7 @@ import type { ReadonlyDeep } from 'type-fest';
 import * as Native from '../Native';
 import { Aci } from './Address';
 import {
+  CancelledError,
   IoError,
   SvrDataMissingError,
   SvrRestoreFailedError,
@@ -21,6 +22,20 @@ export enum Environment {
   Production = 1,
 }
 
+/**
+ * Describes the SVR3 and CDSI enclaves of `env`, for checking which ones a
+ * client is configured with.
+ *
+ * The result is safe to log. It holds the hostnames, ports, path prefixes,
+ * route weights, enclave measurements, and SVR3 server IDs, along with a short
+ * `hash` of each part that only changes when the configuration does.
+ *
+ * @returns A JSON object with the fields `svr3` and `cdsi`.
+ */
+export function environmentFingerprint(env: Environment): string {
+  return Native.Environment_Fingerprint(env);
+}
+
 export type ServiceAuth = {
   username: string;
   password: string;
@@ -56,6 +71,49 @@ export type ChatRequest = Readonly<{
   timeoutMillis?: number;
 }>;
 
+/**
+ * Result of a connection check against a single SVR3 enclave.
+ *
+ * Durations are only present for the phases that were reached.
+ */
+export type Svr3EnclaveDiagnostics = {
+  enclave: string;
+  /** `null` if the enclave was reached and attested successfully. */
+  error:
+    | 'Timeout'
+    | 'RateLimited'
+    | 'TcpConnect'
+    | 'ConnectionLost'
+    | 'Network'
+    | 'Attestation'
+    | 'Protocol'
+    | null;
+  /** A log-safe description of the route used, with credentials masked. */
+  route: string | null;
+  websocketMillis: number | null;
+  attestationMillis: number | null;
+};
+
+/**
+ * Cancels in-flight network operations.
+ *
+ * A single signal can be passed to any number of operations. Cancelling it
+ * stops all of them, including any started afterwards; cancelling after an
+ * operation has completed has no effect. Cancelled operations are rejected
+ * with {@link CancelledError}.
+ */
+export class CancellationSignal {
+  readonly _nativeHandle: Native.CancellationSignal;
+
+  constructor() {
+    this._nativeHandle = Native.CancellationSignal_new();
+  }
+
+  cancel(): void {
+    Native.CancellationSignal_cancel(this);
+  }
+}
+
 export class Net {
   private readonly _asyncContext: { _nativeHandle: Native.TokioAsyncContext };
   private readonly _chatService: { _nativeHandle: Native.Chat };
@@ -79,6 +137,17 @@ export class Net {
     this.svr3 = new Svr3ClientImpl(this._asyncContext, this._connectionManager);
   }
 
+  /**
+   * Notifies libsignal that the device's network changed, for example from
+   * Wi-Fi to cellular.
+   *
+   * Operations that then fail on connections made before the change are
+   * rejected with an {@link IoError} saying so, and should be run again.
+   */
+  onNetworkChange(): void {
+    Native.ConnectionManager_on_network_change(this._connectionManager);
+  }
+
   async disconnectChatService(): Promise<void> {
     await Native.ChatService_disconnect(this._asyncContext, this._chatService);
   }
@@ -121,6 +190,12 @@ export class Net {
     return httpRequest;
   }
 
+  /**
+   * Looks up phone numbers and ACIs with CDSI.
+   *
+   * If `cancellation` gets cancelled, the lookup is abandoned and the
+   * `Promise` is rejected with {@link CancelledError}.
+   */
   async cdsiLookup(
     { username, password }: Readonly<ServiceAuth>,
     {
@@ -128,7 +203,8 @@ export class Net {
       acisAndAccessKeys,
       timeout,
       returnAcisWithoutUaks,
-    }: ReadonlyDeep<CDSRequestOptionsType>
+    }: ReadonlyDeep<CDSRequestOptionsType>,
+    cancellation: CancellationSignal = new CancellationSignal()
   ): Promise<CDSResponseType<string, string>> {
     const request = { _nativeHandle: Native.LookupRequest_new() };
     e164s.forEach((e164) => {
@@ -151,15 +227,20 @@ export class Net {
     const lookup = await Native.CdsiLookup_new(
       this._asyncContext,
       this._connectionManager,
+      cancellation,
       username,
       password,
       request,
       timeout
     );
 
-    return await Native.CdsiLookup_complete(this._asyncContext, {
-      _nativeHandle: lookup,
-    });
+    return await Native.CdsiLookup_complete(
+      this._asyncContext,
+      {
+        _nativeHandle: lookup,
+      },
+      cancellation
+    );
   }
 }
 
@@ -203,6 +284,9 @@ export interface Svr3Client {
    * time.
    * @param opTimeoutMs - The maximum wall time libsignal is allowed to spend
    * communicating with SVR3 service.
+   * @param cancellation - Optional {@link CancellationSignal}; if it gets
+   * cancelled, the operation is abandoned and the `Promise` is rejected with
+   * {@link CancelledError}.
    * @returns A `Promise` which--when awaited--will return a byte array with a
    * serialized masked share set. It is supposed to be an opaque blob for the
    * clients and therefore no assumptions should be made about its contents.
@@ -223,7 +307,8 @@ export interface Svr3Client {
     password: string,
     maxTries: number,
     auth: Readonly<ServiceAuth>,
-    opTimeoutMs: number
+    opTimeoutMs: number,
+    cancellation?: CancellationSignal
   ): Promise<Buffer>;
 
   /**
@@ -243,6 +328,9 @@ export interface Svr3Client {
    * time.
    * @param opTimeoutMs - The maximum wall time libsignal is allowed to spend
    * communicating with SVR3 service.
+   * @param cancellation - Optional {@link CancellationSignal}; if it gets
+   * cancelled, the operation is abandoned and the `Promise` is rejected with
+   * {@link CancelledError}.
    * @returns A `Promise` which--when awaited--will return a byte array with the
    * restored secret.
    *
@@ -267,8 +355,59 @@ export interface Svr3Client {
     password: string,
     shareSet: Buffer,
     auth: Readonly<ServiceAuth>,
-    opTimeoutMs: number
+    opTimeoutMs: number,
+    cancellation?: CancellationSignal
   ): Promise<Buffer>;
+
+  /**
+   * Remove a value stored in SVR3.
+   *
+   * This method will succeed even if the data has never been backed up in the
+   * first place.
+   *
+   * Error messages are expected to be log-safe and not contain any sensitive
+   * data.
+   *
+   * @param auth - An instance of {@link ServiceAuth} containing the username
+   * and password obtained from the Chat Server. The password is an OTP which is
+   * generally good for about 15 minutes, therefore it can be reused for the
+   * subsequent calls to either backup or restore that are not too far apart in
+   * time.
+   * @param opTimeoutMs - The maximum wall time libsignal is allowed to spend
+   * communicating with SVR3 service.
+   * @param cancellation - Optional {@link CancellationSignal}; if it gets
+   * cancelled, the operation is abandoned and the `Promise` is rejected with
+   * {@link CancelledError}.
+   * @returns A `Promise` which--when awaited--will signal if the operation
+   * succeeded or not.
+   *
+   * The returned `Promise` can fail due to the network issues (including the
+   * timeout) or problems establishing the Noise connection to the enclaves.
+   * {@link IoError} errors can, in general, be retried, although there is
+   * already a retry-with-backoff mechanism inside libsignal used to connect to
+   * the SVR3 servers.
+   */
+  remove(
+    auth: Readonly<ServiceAuth>,
+    opTimeoutMs: number,
+    cancellation?: CancellationSignal
+  ): Promise<void>;
+
+  /**
+   * Check connectivity to each of the SVR3 enclaves.
+   *
+   * Connects and attests to every enclave, then disconnects without making
+   * any requests, so no stored data is affected. Failures are reported in the
+   * result instead of rejecting the returned `Promise`.
+   *
+   * @param auth - An instance of {@link ServiceAuth} containing the username
+   * and password obtained from the Chat Server.
+   * @returns A `Promise` which--when awaited--will return one
+   * {@link Svr3EnclaveDiagnostics} per enclave.
+   */
+  connectDiagnostics(
+    auth: Readonly<ServiceAuth>
+  ): Promise<Svr3EnclaveDiagnostics[]>;
 }
 
 class Svr3ClientImpl implements Svr3Client {
@@ -284,11 +423,13 @@ class Svr3ClientImpl implements Svr3Client {
     password: string,
     maxTries: number,
     auth: Readonly<ServiceAuth>,
-    opTimeoutMs: number
+    opTimeoutMs: number,
+    cancellation: CancellationSignal = new CancellationSignal()
   ): Promise<Buffer> {
     return Native.Svr3Backup(
       this._asyncContext,
       this._connectionManager,
+      cancellation,
       what,
       password,
       maxTries,
@@ -302,11 +443,13 @@ class Svr3ClientImpl implements Svr3Client {
     password: string,
     shareSet: Buffer,
     auth: Readonly<ServiceAuth>,
-    opTimeoutMs: number
+    opTimeoutMs: number,
+    cancellation: CancellationSignal = new CancellationSignal()
   ): Promise<Buffer> {
     return Native.Svr3Restore(
       this._asyncContext,
       this._connectionManager,
+      cancellation,
       password,
       shareSet,
       auth.username,
@@ -314,4 +457,31 @@ class Svr3ClientImpl implements Svr3Client {
       opTimeoutMs
     );
   }
+
+  async remove(
+    auth: Readonly<ServiceAuth>,
+    opTimeoutMs: number,
+    cancellation: CancellationSignal = new CancellationSignal()
+  ): Promise<void> {
+    return Native.Svr3Remove(
+      this._asyncContext,
+      this._connectionManager,
+      cancellation,
+      auth.username,
+      auth.password,
+      opTimeoutMs
+    );
+  }
+
+  async connectDiagnostics(
+    auth: Readonly<ServiceAuth>
+  ): Promise<Svr3EnclaveDiagnostics[]> {
+    const json = await Native.Svr3ConnectDiagnostics(
+      this._asyncContext,
+      this._connectionManager,
+      auth.username,
+      auth.password
+    );
+    return JSON.parse(json.toString('utf8')) as Svr3EnclaveDiagnostics[];
+  }
 }
@@ -9,7 +9,13 @@ import * as util from './util';
 import { Aci, Pni } from '../Address';
 import * as Native from '../../Native';
 import { ErrorCode, LibSignalErrorBase } from '../Errors';
-import { Environment, Net, ServiceAuth } from '../net';
+import {
+  CancellationSignal,
+  Environment,
+  environmentFingerprint,
+  Net,
+  ServiceAuth,
+} from '../net';
 import { randomBytes } from 'crypto';
 import { Response } from '../../Native';
 
@@ -125,6 +131,52 @@ describe('cdsi lookup', () => {
         .with.property('code', ErrorCode.IoError);
     });
   });
+
+  it('can be cancelled', () => {
+    const net = new Net(Environment.Staging);
+    const cancellation = new CancellationSignal();
+    cancellation.cancel();
+    return expect(
+      net.cdsiLookup(
+        { username: 'username', password: 'password' },
+        {
+          e164s: [],
+          acisAndAccessKeys: [],
+          timeout: 5000,
+          returnAcisWithoutUaks: false,
+        },
+        cancellation
+      )
+    )
+      .to.eventually.be.rejectedWith(LibSignalErrorBase)
+      .and.have.property('code', ErrorCode.Cancelled);
+  });
+});
+
+describe('environment fingerprint', () => {
+  it('describes the SVR3 and CDSI enclaves', () => {
+    const fingerprint = JSON.parse(environmentFingerprint(Environment.Staging));
+    expect(fingerprint.svr3.serverIds).deep.equals([1, 2]);
+    expect(fingerprint.svr3.nitro).to.include.keys(
+      'hostname',
+      'port',
+      'pathPrefix',
+      'routeWeights',
+      'mrEnclave',
+      'hash'
+    );
+    expect(fingerprint.svr3.hash).to.match(/^[0-9a-f]{16}$/);
+    expect(fingerprint.cdsi.hash).to.match(/^[0-9a-f]{16}$/);
+  });
+
+  it('differs between environments', () => {
+    const staging = JSON.parse(environmentFingerprint(Environment.Staging));
+    const production = JSON.parse(
+      environmentFingerprint(Environment.Production)
+    );
+    expect(staging.svr3.hash).not.equals(production.svr3.hash);
+    expect(staging.cdsi.hash).not.equals(production.cdsi.hash);
+  });
 });
 
 describe('SVR3', () => {
@@ -141,6 +193,18 @@ describe('SVR3', () => {
     return { username: USERNAME, password: otp };
   }
 
+  it('converts deprecated client errors to native', () => {
+    expect(() => Native.TESTING_Svr3ClientDeprecatedErrorConvert())
+      .throws(LibSignalErrorBase)
+      .with.property('code', ErrorCode.ClientDeprecatedError);
+  });
+
+  it('converts rate limiting errors to native', () => {
+    expect(() => Native.TESTING_Svr3RateLimitedErrorConvert())
+      .throws(LibSignalErrorBase)
+      .with.property('retryAfterSecs', 42);
+  });
+
   describe('Backup', () => {
     // It is OK to reuse the auth in "input validation" tests.
     const AUTH = make_auth();
@@ -176,6 +240,29 @@ describe('SVR3', () => {
     });
   });
 
+  describe('Cancellation', () => {
+    it('Already cancelled', () => {
+      const auth = make_auth();
+      const secret = randomBytes(32);
+      const cancellation = new CancellationSignal();
+      cancellation.cancel();
+      return expect(
+        SVR3.backup(secret, 'password', 10, auth, TIMEOUT, cancellation)
+      )
+        .to.eventually.be.rejectedWith(LibSignalErrorBase)
+        .and.have.property('code', ErrorCode.Cancelled);
+    });
+
+    it('Cancelling again is harmless', () => {
+      const cancellation = new CancellationSignal();
+      cancellation.cancel();
+      cancellation.cancel();
+      return expect(SVR3.remove(make_auth(), TIMEOUT, cancellation))
+        .to.eventually.be.rejectedWith(LibSignalErrorBase)
+        .and.have.property('code', ErrorCode.Cancelled);
+    });
+  });
+
   // Integration tests require access to the staging environment and make real
   // network calls and as such require the secret (and lacking the secret will
   // not be run).
@@ -231,6 +318,21 @@ describe('SVR3', () => {
         .and.have.property('code', ErrorCode.SvrDataMissing);
     });
 
+    it('Remove', async () => {
+      const auth = make_auth();
+      const secret = randomBytes(32);
+      const shareSet = await SVR3.backup(secret, 'password', 10, auth, TIMEOUT);
+      await SVR3.remove(auth, TIMEOUT);
+      return expect(SVR3.restore('password', shareSet, auth, TIMEOUT))
+        .to.eventually.be.rejectedWith(LibSignalErrorBase)
+        .and.have.property('code', ErrorCode.SvrDataMissing);
+    }).timeout(10000);
+
+    it('Remove without backup', async () => {
+      const auth = make_auth();
+      return expect(SVR3.remove(auth, TIMEOUT)).to.eventually.be.fulfilled;
+    });
+
     it('Timeout', async () => {
       const auth = make_auth();
       const secret = randomBytes(32);
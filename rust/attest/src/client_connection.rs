@@ -9,8 +9,19 @@
 //! messages can be encrypted into one or more noise transport messages for sending
 //! with [ClientConnection::send]. Likewise a single received message consisting internally
 //! of one or more noise transport messages can be decrypted with [ClientConnection::recv]
+//!
+//! ## Rekeying
+//!
+//! Long-lived sessions can replace their transport keys as they go, see
+//! [ClientConnection::with_rekey_interval]. The rule is the same on both ends and for both
+//! directions: each direction counts the noise transport messages that pass through it (a single
+//! [ClientConnection::send] may produce several), and right after every `interval`th one, its
+//! key is replaced by the result of the Noise `Rekey()` function. Only the key changes; nonces
+//! keep counting up. The other end has to follow the rule with the same interval, or the first
+//! message after the missed rekey fails to decrypt, so rekeying is off unless configured.
 
 use std::fmt;
+use std::num::NonZeroU64;
 
 pub const NOISE_PATTERN: &str = "Noise_NK_25519_ChaChaPoly_SHA256";
 
@@ -23,7 +34,10 @@ pub(crate) const NOISE_TRANSPORT_PER_PAYLOAD_MAX: usize =
 
 #[derive(Debug)]
 pub struct ClientConnection {
-    pub transport: snow::TransportState,
+    pub(crate) transport: snow::TransportState,
+    rekey_interval: Option<NonZeroU64>,
+    messages_sent: u64,
+    messages_received: u64,
 }
 
 /// Result type for client connection.
@@ -54,6 +68,28 @@ fn ceil_div(total: usize, chunk_size: usize) -> usize {
 }
 
 impl ClientConnection {
+    /// Wrap the transport of a completed handshake, without rekeying.
+    pub fn new(transport: snow::TransportState) -> Self {
+        Self {
+            transport,
+            rekey_interval: None,
+            messages_sent: 0,
+            messages_received: 0,
+        }
+    }
+
+    /// Rekey each direction after every `interval` noise transport messages, counting from the
+    /// start of the session. See the [module docs](self) for the exact rule.
+    pub fn with_rekey_interval(mut self, interval: NonZeroU64) -> Self {
+        self.rekey_interval = Some(interval);
+        self
+    }
+
+    fn is_rekey_due(&self, messages: u64) -> bool {
+        self.rekey_interval
+            .is_some_and(|interval| messages % interval.get() == 0)
+    }
+
     /// Wrap a plaintext message to be sent, returning the ciphertext.
     pub fn send(&mut self, plaintext_to_send: &[u8]) -> Result<Vec<u8>> {
         let max_ciphertext_size = plaintext_to_send.len()
@@ -65,6 +101,10 @@ impl ClientConnection {
             total_size += self
                 .transport
                 .write_message(chunk, &mut ciphertext[total_size..])?;
+            self.messages_sent += 1;
+            if self.is_rekey_due(self.messages_sent) {
+                self.transport.rekey_outgoing();
+            }
         }
         ciphertext.truncate(total_size);
         Ok(ciphertext)
@@ -78,8 +118,97 @@ impl ClientConnection {
             total_size += self
                 .transport
                 .read_message(chunk, &mut received_plaintext[total_size..])?;
+            self.messages_received += 1;
+            if self.is_rekey_due(self.messages_received) {
+                self.transport.rekey_incoming();
+            }
         }
         received_plaintext.truncate(total_size);
         Ok(received_plaintext)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn interval(n: u64) -> NonZeroU64 {
+        NonZeroU64::new(n).expect("nonzero")
+    }
+
+    fn connected_pair() -> (snow::TransportState, snow::TransportState) {
+        let params: snow::params::NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+        let mut initiator = snow::Builder::new(params.clone())
+            .build_initiator()
+            .unwrap();
+        let mut responder = snow::Builder::new(params).build_responder().unwrap();
+        let mut message = [0u8; NOISE_HANDSHAKE_OVERHEAD];
+        let mut payload = [0u8; NOISE_HANDSHAKE_OVERHEAD];
+        let size = initiator.write_message(&[], &mut message).unwrap();
+        responder
+            .read_message(&message[..size], &mut payload)
+            .unwrap();
+        let size = responder.write_message(&[], &mut message).unwrap();
+        initiator
+            .read_message(&message[..size], &mut payload)
+            .unwrap();
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    #[test]
+    fn rekeying_in_step_keeps_the_session_working() {
+        let (client, server) = connected_pair();
+        let mut client = ClientConnection::new(client).with_rekey_interval(interval(3));
+        let mut server = ClientConnection::new(server).with_rekey_interval(interval(3));
+        for i in 0..10u8 {
+            let request = client.send(&[i]).unwrap();
+            assert_eq!(server.recv(&request).unwrap(), [i]);
+            let response = server.send(&[i, i]).unwrap();
+            assert_eq!(client.recv(&response).unwrap(), [i, i]);
+        }
+    }
+
+    #[test]
+    fn multi_packet_messages_count_every_packet() {
+        let (client, server) = connected_pair();
+        let mut client = ClientConnection::new(client).with_rekey_interval(interval(2));
+        let mut server = ClientConnection::new(server).with_rekey_interval(interval(2));
+        // Three transport messages, so the rekey happens in the middle of this one.
+        let plaintext = vec![7u8; 2 * NOISE_TRANSPORT_PER_PAYLOAD_MAX + 1];
+        let ciphertext = client.send(&plaintext).unwrap();
+        assert_eq!(server.recv(&ciphertext).unwrap(), plaintext);
+        let ciphertext = client.send(b"after").unwrap();
+        assert_eq!(server.recv(&ciphertext).unwrap(), b"after");
+    }
+
+    #[test]
+    fn mismatched_rekeying_breaks_the_session() {
+        let (client, server) = connected_pair();
+        let mut client = ClientConnection::new(client).with_rekey_interval(interval(2));
+        let mut server = ClientConnection::new(server);
+        for i in 0..2u8 {
+            let request = client.send(&[i]).unwrap();
+            assert_eq!(server.recv(&request).unwrap(), [i]);
+        }
+        let request = client.send(&[2]).unwrap();
+        assert!(server.recv(&request).is_err());
+    }
+
+    #[test]
+    fn replayed_message_is_rejected() {
+        let (client, server) = connected_pair();
+        let mut client = ClientConnection::new(client);
+        let mut server = ClientConnection::new(server);
+        let request = client.send(b"backup").unwrap();
+        assert_eq!(server.recv(&request).unwrap(), b"backup");
+        // Noise nonces are implicit counters, so the same ciphertext no
+        // longer decrypts once the receiver has moved past it.
+        assert!(server.recv(&request).is_err());
+        // The failed attempt doesn't consume a nonce.
+        let request = client.send(b"restore").unwrap();
+        assert_eq!(server.recv(&request).unwrap(), b"restore");
+    }
+}
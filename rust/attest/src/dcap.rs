@@ -545,14 +545,16 @@ fn verify_tcb_status(evidence: &Evidence, endorsements: &SgxEndorsements) -> Res
     // make sure the tcb_info matches our enclave's model/PCE version
     if pck_ext.fmspc != tcb_info.fmspc {
         return Err(Error::new(format!(
-            "tcb fmspc mismatch (pck extension fmspc was {:?}, tcb_info fmspc was {:?})",
-            &pck_ext.fmspc, &tcb_info.fmspc
+            "tcb fmspc mismatch (pck extension fmspc was {}, tcb_info fmspc was {})",
+            hex::encode(pck_ext.fmspc),
+            hex::encode(tcb_info.fmspc)
         )));
     }
     if pck_ext.pceid != tcb_info.pce_id {
         return Err(Error::new(format!(
-            "tcb pceid mismatch (pck extension pceid was {:?}, tcb_info pceid was {:?})",
-            &pck_ext.pceid, &tcb_info.pce_id
+            "tcb pceid mismatch (pck extension pceid was {}, tcb_info pceid was {})",
+            hex::encode(pck_ext.pceid),
+            hex::encode(tcb_info.pce_id)
         )));
     }
 
@@ -38,7 +38,7 @@ impl From<dcap::Error> for AttestationError {
 /// Error types for an enclave noise session.
 #[derive(Display, Debug)]
 pub enum Error {
-    /// failure to attest remote enclave: {0:?}
+    /// failure to attest remote enclave: {0}
     AttestationError(AttestationError),
     /// failure to communicate on established Noise channel to the enclave: {0}
     NoiseError(client_connection::Error),
@@ -46,10 +46,14 @@ pub enum Error {
     NoiseHandshakeError(snow::Error),
     /// attestation data invalid: {reason}
     AttestationDataError { reason: String },
+    /// enclave presented raft group id {actual}, expected {expected}
+    GroupIdMismatch { expected: u64, actual: u64 },
     /// invalid bridge state
     InvalidBridgeStateError,
 }
 
+impl std::error::Error for Error {}
+
 impl From<snow::Error> for Error {
     fn from(e: snow::Error) -> Self {
         Error::NoiseHandshakeError(e)
@@ -126,7 +130,16 @@ impl Handshake {
         self.handshake.read_message(initial_received, &mut [])?;
         let transport = self.handshake.into_transport_mode()?;
         log::info!("Successfully completed attested connection");
-        Ok(ClientConnection { transport })
+        Ok(ClientConnection::new(transport))
+    }
+
+    /// The group id from the raft config the enclave presented, if it
+    /// presented one.
+    pub fn raft_group_id(&self) -> Option<u64> {
+        self.claims
+            .raft_group_config
+            .as_ref()
+            .map(|config| config.group_id)
     }
 
     pub(crate) fn with_claims(claims: Claims) -> Result<UnvalidatedHandshake> {
@@ -163,6 +176,7 @@ impl UnvalidatedHandshake {
                 .ok_or(Error::AttestationDataError {
                     reason: "Claims must contain a raft group config".to_string(),
                 })?;
+        log::debug!("enclave presented raft config {:?}", actual_config);
         if expected_raft_config != *actual_config {
             return Err(Error::AttestationDataError {
                 reason: format!(
@@ -153,6 +153,6 @@ impl ClientConnectionEstablishment {
             "Successfully completed HSM-enclave connection to codehash {:x?}",
             received_hash
         );
-        Ok(client_connection::ClientConnection { transport })
+        Ok(client_connection::ClientConnection::new(transport))
     }
 }
@@ -3,22 +3,25 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use boring::bn::BigNum;
 use boring::ecdsa::EcdsaSig;
 use boring::stack;
 use boring::x509::store::X509StoreBuilder;
+use boring::x509::verify::X509VerifyFlags;
 use boring::x509::{X509StoreContext, X509};
 use ciborium::value::{Integer, Value};
 use prost::{DecodeError, Message};
-use sha2::{Digest, Sha384};
+use sha2::{Digest, Sha256, Sha384};
 use subtle::ConstantTimeEq;
 
 use crate::enclave::{self, Claims, Handshake};
 use crate::proto;
 use crate::svr2::{expected_raft_config, RaftConfig};
-use crate::util::SmallMap;
+use crate::util::{system_time_to_asn1_time, SmallMap};
 
 use crate::constants::NITRO_EXPECTED_PCRS;
 
@@ -36,14 +39,15 @@ impl Handshake {
         evidence: &[u8],
         expected_raft_config: &RaftConfig,
         now: SystemTime,
+        chain_cache: Option<&NitroChainCache>,
     ) -> Result<Self, enclave::Error> {
         let expected_pcrs = NITRO_EXPECTED_PCRS.get(&enclave).ok_or_else(|| {
             enclave::Error::AttestationDataError {
-                reason: format!("unknown enclave {:?}", enclave),
+                reason: "unknown enclave".to_string(),
             }
         })?;
         let cose_sign1 = CoseSign1::from_bytes(evidence)?;
-        let doc = cose_sign1.extract_attestation_doc(now)?;
+        let doc = cose_sign1.extract_attestation_doc(now, chain_cache)?;
         let attestation_data = doc.extract_attestation_data(expected_pcrs)?;
         let attestation_data = attestation_data.ok_or(NitroError::UserDataMissing)?;
         Self::with_claims(Claims::from_attestation_data(attestation_data)?)?
@@ -56,6 +60,34 @@ pub fn new_handshake(
     attestation_msg: &[u8],
     now: SystemTime,
     raft_config_override: Option<&'static RaftConfig>,
+) -> Result<Handshake, enclave::Error> {
+    new_handshake_with_optional_cache(mr_enclave, attestation_msg, now, raft_config_override, None)
+}
+
+/// Like [`new_handshake`], but reuses the validation of the document's
+/// certificate chain from earlier handshakes where `chain_cache` allows.
+pub fn new_handshake_with_chain_cache(
+    mr_enclave: &[u8],
+    attestation_msg: &[u8],
+    now: SystemTime,
+    raft_config_override: Option<&'static RaftConfig>,
+    chain_cache: &NitroChainCache,
+) -> Result<Handshake, enclave::Error> {
+    new_handshake_with_optional_cache(
+        mr_enclave,
+        attestation_msg,
+        now,
+        raft_config_override,
+        Some(chain_cache),
+    )
+}
+
+fn new_handshake_with_optional_cache(
+    mr_enclave: &[u8],
+    attestation_msg: &[u8],
+    now: SystemTime,
+    raft_config_override: Option<&'static RaftConfig>,
+    chain_cache: Option<&NitroChainCache>,
 ) -> Result<Handshake, enclave::Error> {
     let expected_raft_config = expected_raft_config(mr_enclave, raft_config_override)?;
     let handshake_start = proto::svr2::ClientHandshakeStart::decode(attestation_msg)?;
@@ -64,10 +96,155 @@ pub fn new_handshake(
         &handshake_start.evidence,
         expected_raft_config,
         now,
+        chain_cache,
     )?;
     Ok(handshake)
 }
 
+/// Remembers the certificate chains of attestation documents that were
+/// validated, so that later documents presenting the same chain only need
+/// their own certificate checked against the one that issued it.
+///
+/// The certificate that signs a document is short-lived, but it is issued
+/// through intermediates that stay the same for days, and checking those is
+/// most of the work of validating a document. The signature and PCRs of each
+/// document are checked regardless.
+///
+/// A validated chain is reused for at most `max_age`, never after any of its
+/// certificates expires, and never for a time earlier than the one it was
+/// validated for.
+pub struct NitroChainCache {
+    capacity: usize,
+    max_age: Duration,
+    chains: Mutex<HashMap<[u8; 32], ValidatedChain>>,
+    chain_validations: AtomicU64,
+}
+
+struct ValidatedChain {
+    /// The certificate that issued the document's certificate.
+    issuer: X509,
+    validated_at: SystemTime,
+    /// When the first certificate in the chain expires.
+    valid_until: SystemTime,
+}
+
+impl NitroChainCache {
+    /// Used by [`Default`]. Enclaves of different services present different
+    /// chains, but only a few of them.
+    pub const DEFAULT_CAPACITY: usize = 4;
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+    /// Keeps up to `capacity` chains, replacing the one validated earliest
+    /// when full. With a `capacity` of zero nothing is kept.
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            capacity,
+            max_age,
+            chains: Default::default(),
+            chain_validations: AtomicU64::new(0),
+        }
+    }
+
+    /// Forgets every chain, so that each is validated in full again.
+    pub fn clear(&self) {
+        self.chains.lock().expect("not poisoned").clear()
+    }
+
+    /// The number of chains kept, including any that are too old to reuse.
+    pub fn len(&self) -> usize {
+        self.chains.lock().expect("not poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many times a chain was validated in full because it couldn't be
+    /// reused.
+    pub fn chain_validations(&self) -> u64 {
+        self.chain_validations.load(Ordering::Relaxed)
+    }
+
+    fn key(cabundle: &[Vec<u8>]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for der in cabundle {
+            hasher.update((der.len() as u64).to_be_bytes());
+            hasher.update(der);
+        }
+        hasher.finalize().into()
+    }
+
+    fn is_current(&self, chain: &ValidatedChain, now: SystemTime) -> bool {
+        now.duration_since(chain.validated_at)
+            .is_ok_and(|age| age < self.max_age)
+            && now < chain.valid_until
+    }
+
+    /// The issuer to check a certificate against at `now`, if the chain
+    /// hashed to `key` can be reused.
+    fn issuer(&self, key: &[u8; 32], now: SystemTime) -> Option<X509> {
+        let chains = self.chains.lock().expect("not poisoned");
+        chains
+            .get(key)
+            .filter(|chain| self.is_current(chain, now))
+            .map(|chain| chain.issuer.clone())
+    }
+
+    /// Records that `chain`, starting with the document's certificate, was
+    /// validated at `now`.
+    fn insert(&self, key: [u8; 32], chain: &[X509], now: SystemTime) -> Result<(), NitroError> {
+        self.chain_validations.fetch_add(1, Ordering::Relaxed);
+        let Some(issuer) = chain.get(1) else {
+            return Ok(());
+        };
+        // The chain was just validated for `now`, which therefore converts.
+        let now_asn1 = system_time_to_asn1_time(now).expect("valid time");
+        let now_secs = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("current time is after 1970")
+            .as_secs();
+        let expiry = |cert: &X509| -> Result<SystemTime, NitroError> {
+            const DAY_SECS: i64 = 24 * 60 * 60;
+            let remaining = now_asn1.diff(cert.not_after())?;
+            let remaining_secs = i64::from(remaining.days) * DAY_SECS + i64::from(remaining.secs);
+            Ok(SystemTime::UNIX_EPOCH
+                + Duration::from_secs(now_secs.saturating_add_signed(remaining_secs)))
+        };
+        let mut valid_until = expiry(issuer)?;
+        for cert in &chain[2..] {
+            valid_until = valid_until.min(expiry(cert)?);
+        }
+        let validated = ValidatedChain {
+            issuer: issuer.clone(),
+            validated_at: now,
+            valid_until,
+        };
+
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut chains = self.chains.lock().expect("not poisoned");
+        chains.retain(|_, chain| self.is_current(chain, now));
+        if chains.len() >= self.capacity && !chains.contains_key(&key) {
+            let earliest = chains
+                .iter()
+                .min_by_key(|(_, chain)| chain.validated_at)
+                .map(|(key, _)| *key);
+            if let Some(earliest) = earliest {
+                chains.remove(&earliest);
+            }
+        }
+        chains.insert(key, validated);
+        Ok(())
+    }
+}
+
+impl Default for NitroChainCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY, Self::DEFAULT_MAX_AGE)
+    }
+}
+
 #[derive(Debug, displaydoc::Display, PartialEq, Eq)]
 pub enum NitroError {
     /// Invalid CBOR
@@ -122,14 +299,18 @@ impl CoseSign1 {
         value.try_into()
     }
 
-    pub fn extract_attestation_doc(&self, now: SystemTime) -> Result<AttestationDoc, NitroError> {
+    pub fn extract_attestation_doc(
+        &self,
+        now: SystemTime,
+        chain_cache: Option<&NitroChainCache>,
+    ) -> Result<AttestationDoc, NitroError> {
         let hash = Sha384::digest(self.to_canonical());
         let r = BigNum::from_slice(&self.signature[..48]).expect("can extract r");
         let s = BigNum::from_slice(&self.signature[48..]).expect("can extract s");
         let sig = EcdsaSig::from_private_components(r, s).expect("can initialize signature");
 
         let doc = AttestationDoc::from_bytes(self.payload.as_slice()).expect("can parse doc");
-        let cert = doc.verified_cert(now)?;
+        let cert = doc.verified_cert(now, chain_cache)?;
         let key = cert
             .public_key()
             .and_then(|pub_key| pub_key.ec_key())
@@ -361,32 +542,27 @@ impl AttestationDoc {
         })
     }
 
-    fn verified_cert(&self, now: SystemTime) -> Result<X509, NitroError> {
-        let mut context = X509StoreContext::new()?;
+    fn verified_cert(
+        &self,
+        now: SystemTime,
+        chain_cache: Option<&NitroChainCache>,
+    ) -> Result<X509, NitroError> {
         let certificate = X509::from_der(&self.certificate)?;
-        let mut stack = stack::Stack::<X509>::new()?;
-        for der in self.cabundle.iter() {
-            let cert = X509::from_der(der)?;
-            stack.push(cert)?;
-        }
-        let stack = stack;
-        let trust = {
-            let root = X509::from_pem(ROOT_CERTIFICATE_PEM)?;
-            let mut builder = X509StoreBuilder::new()?;
-            builder.param_mut().set_time(
-                now.duration_since(SystemTime::UNIX_EPOCH)
-                    .expect("current time is after 1970")
-                    .as_secs()
-                    .try_into()
-                    .expect("haven't yet overflowed time_t"),
-            );
-            builder.add_cert(root)?;
-            builder.build()
+        let root = X509::from_pem(ROOT_CERTIFICATE_PEM)?;
+        let Some(chain_cache) = chain_cache else {
+            verify_certificate(&certificate, &self.cabundle, root, false, now)?;
+            return Ok(certificate);
         };
-        let is_valid = context.init(&trust, &certificate, &stack, |ctx| ctx.verify_cert())?;
-        if !is_valid {
-            let message = context.error().to_string();
-            return Err(NitroError::InvalidCertificate(message));
+
+        let key = NitroChainCache::key(&self.cabundle);
+        match chain_cache.issuer(&key, now) {
+            Some(issuer) => {
+                verify_certificate(&certificate, &[], issuer, true, now)?;
+            }
+            None => {
+                let chain = verify_certificate(&certificate, &self.cabundle, root, false, now)?;
+                chain_cache.insert(key, &chain, now)?;
+            }
         }
         Ok(certificate)
     }
@@ -423,6 +599,56 @@ impl AttestationDoc {
 
 const ROOT_CERTIFICATE_PEM: &[u8] = include_bytes!("../res/nitro_root_certificate.pem");
 
+/// Verifies `certificate` as of `now`, building its chain from the DER-encoded
+/// `intermediates` up to `trusted`, and returns that chain, starting with
+/// `certificate`.
+///
+/// With `partial_chain`, `trusted` is taken as an anchor even though it isn't
+/// self-signed, and the certificates that issued it are not checked.
+fn verify_certificate(
+    certificate: &X509,
+    intermediates: &[Vec<u8>],
+    trusted: X509,
+    partial_chain: bool,
+    now: SystemTime,
+) -> Result<Vec<X509>, NitroError> {
+    let mut context = X509StoreContext::new()?;
+    let mut stack = stack::Stack::<X509>::new()?;
+    for der in intermediates {
+        let cert = X509::from_der(der)?;
+        stack.push(cert)?;
+    }
+    let stack = stack;
+    let trust = {
+        let mut builder = X509StoreBuilder::new()?;
+        builder.param_mut().set_time(
+            now.duration_since(SystemTime::UNIX_EPOCH)
+                .expect("current time is after 1970")
+                .as_secs()
+                .try_into()
+                .expect("haven't yet overflowed time_t"),
+        );
+        if partial_chain {
+            builder
+                .param_mut()
+                .set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        }
+        builder.add_cert(trusted)?;
+        builder.build()
+    };
+    let verified = context.init(&trust, certificate, &stack, |ctx| {
+        if !ctx.verify_cert()? {
+            return Ok(Err(ctx.error().to_string()));
+        }
+        let chain = ctx
+            .chain()
+            .map(|chain| chain.iter().map(ToOwned::to_owned).collect())
+            .unwrap_or_default();
+        Ok(Ok(chain))
+    })?;
+    verified.map_err(NitroError::InvalidCertificate)
+}
+
 #[cfg(test)]
 mod test {
     use std::time::Duration;
@@ -436,7 +662,7 @@ mod test {
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1684362463);
         let cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_1).expect("can parse");
         cose_sign1
-            .extract_attestation_doc(timestamp)
+            .extract_attestation_doc(timestamp, None)
             .expect("valid signature");
     }
 
@@ -445,7 +671,7 @@ mod test {
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1705432216);
         let _pk = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_2)
             .expect("can parse")
-            .extract_attestation_doc(timestamp)
+            .extract_attestation_doc(timestamp, None)
             .expect("valid signature")
             .extract_attestation_data(&get_test_pcrs())
             .expect("valid pcrs");
@@ -454,7 +680,7 @@ mod test {
     #[test]
     fn test_expired_cert() {
         let cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_1).expect("can parse");
-        match cose_sign1.extract_attestation_doc(SystemTime::now()) {
+        match cose_sign1.extract_attestation_doc(SystemTime::now(), None) {
             Err(err) => assert!(format!("{err:?}").contains("expired")),
             Ok(_) => panic!("Should have failed"),
         }
@@ -463,7 +689,7 @@ mod test {
     #[test]
     fn test_not_yet_valid_cert() {
         let cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_1).expect("can parse");
-        match cose_sign1.extract_attestation_doc(SystemTime::UNIX_EPOCH) {
+        match cose_sign1.extract_attestation_doc(SystemTime::UNIX_EPOCH, None) {
             Err(err) => assert!(format!("{err:?}").contains("not yet valid")),
             Ok(_) => panic!("Should have failed"),
         }
@@ -474,12 +700,84 @@ mod test {
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1684362463);
         let mut cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_1).expect("can parse");
         cose_sign1.signature[0] ^= 0xff;
-        match cose_sign1.extract_attestation_doc(timestamp) {
+        match cose_sign1.extract_attestation_doc(timestamp, None) {
             Err(err) => assert_eq!(NitroError::InvalidSignature, err),
             Ok(_) => panic!("Should have failed"),
         }
     }
 
+    #[test]
+    fn chain_cache_skips_revalidating_chain() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1705432216);
+        let cache = NitroChainCache::default();
+        let cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_2).expect("can parse");
+        for _ in 0..3 {
+            cose_sign1
+                .extract_attestation_doc(timestamp, Some(&cache))
+                .expect("valid signature")
+                .extract_attestation_data(&get_test_pcrs())
+                .expect("valid pcrs");
+        }
+        assert_eq!(cache.chain_validations(), 1);
+        assert_eq!(cache.len(), 1);
+
+        // The document itself is still checked when its chain is reused.
+        let mut tampered = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_2).expect("can parse");
+        tampered.signature[0] ^= 0xff;
+        assert_eq!(
+            tampered
+                .extract_attestation_doc(timestamp, Some(&cache))
+                .err(),
+            Some(NitroError::InvalidSignature)
+        );
+        let doc = cose_sign1
+            .extract_attestation_doc(timestamp, Some(&cache))
+            .expect("valid signature");
+        let wrong_pcrs = PcrMap::new([(0, [0; 48]), (1, [0; 48]), (2, [0; 48])]);
+        assert_eq!(
+            doc.extract_attestation_data(&wrong_pcrs).err(),
+            Some(NitroError::InvalidPcrs)
+        );
+        // So is its own certificate, which expires before the intermediates.
+        let leaf_expired = SystemTime::UNIX_EPOCH + Duration::from_secs(1705437896);
+        match cose_sign1.extract_attestation_doc(leaf_expired, Some(&cache)) {
+            Err(err) => assert!(format!("{err:?}").contains("expired")),
+            Ok(_) => panic!("Should have failed"),
+        }
+        assert_eq!(cache.chain_validations(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cose_sign1
+            .extract_attestation_doc(timestamp, Some(&cache))
+            .expect("valid signature");
+        assert_eq!(cache.chain_validations(), 2);
+    }
+
+    #[test]
+    fn chain_cache_reuses_chains_only_while_current() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1705432216);
+        let cose_sign1 = CoseSign1::from_bytes(VALID_DOCUMENT_BYTES_2).expect("can parse");
+        let validations_at = |cache: &NitroChainCache, times: &[u64]| {
+            for &secs in times {
+                cose_sign1
+                    .extract_attestation_doc(timestamp + Duration::from_secs(secs), Some(cache))
+                    .expect("valid signature");
+            }
+            cache.chain_validations()
+        };
+
+        let cache = NitroChainCache::new(1, Duration::from_secs(60));
+        assert_eq!(validations_at(&cache, &[0, 59]), 1);
+        assert_eq!(validations_at(&cache, &[60, 61]), 2);
+        // Not for times before it was validated, either.
+        assert_eq!(validations_at(&cache, &[0]), 3);
+
+        let cache = NitroChainCache::new(0, Duration::from_secs(60));
+        assert_eq!(validations_at(&cache, &[0, 1, 2]), 3);
+        assert!(cache.is_empty());
+    }
+
     fn invalid_cose_sign1_test<F>(mut f: F)
     where
         F: FnMut(&mut CoseSign1),
@@ -33,8 +33,8 @@ pub(crate) fn expected_raft_config(
 ) -> Result<&'static RaftConfig> {
     config_override
         .or_else(|| EXPECTED_RAFT_CONFIG.get(&mr_enclave).copied())
-        .ok_or(Error::AttestationDataError {
-            reason: format!("unknown mrenclave {:?}", mr_enclave),
+        .ok_or_else(|| Error::AttestationDataError {
+            reason: "unknown mrenclave".to_string(),
         })
 }
 /// Lookup the group id constant associated with the `mrenclave`
@@ -91,6 +91,12 @@ fn new_handshake_with_constants(
     Ok(handshake)
 }
 
+pub mod testutil {
+    /// A handshake start message recorded from the SVR2 staging enclave,
+    /// attested as of 2024-02-29T22:29:13Z.
+    pub const HANDSHAKE_START: &[u8] = include_bytes!("../tests/data/svr2handshakestart.data");
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::{Duration, SystemTime};
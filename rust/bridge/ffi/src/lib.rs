@@ -157,6 +157,30 @@ pub unsafe extern "C" fn signal_error_get_retry_after_seconds(
     })
 }
 
+/// Writes one byte for each enclave of a failed SVR3 backup, in order: 1 if it
+/// accepted the backup, 0 if not.
+#[no_mangle]
+pub unsafe extern "C" fn signal_error_get_svr_accepted(
+    err: *const SignalFfiError,
+    out: *mut OwnedBufferOf<c_uchar>,
+) -> *mut SignalFfiError {
+    let err = AssertUnwindSafe(err);
+    run_ffi_safe(|| {
+        let err = err.as_ref().ok_or(SignalFfiError::NullPointer)?;
+        match err {
+            SignalFfiError::Svr(libsignal_net::svr3::Error::EnclaveDisagreement { accepted }) => {
+                let accepted: Vec<u8> = accepted.iter().map(|&a| a.into()).collect();
+                write_result_to(out, accepted)
+            }
+            err => Err(SignalFfiError::Signal(
+                SignalProtocolError::InvalidArgument(format!(
+                    "cannot get accepted enclaves from error ({err})"
+                )),
+            )),
+        }
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn signal_error_free(err: *mut SignalFfiError) {
     if !err.is_null() {
@@ -7,6 +7,7 @@ use attest::enclave::Error as EnclaveError;
 use attest::hsm_enclave::Error as HsmEnclaveError;
 use device_transfer::Error as DeviceTransferError;
 use libsignal_bridge::ffi::*;
+use libsignal_net::infra::errors::NetError;
 use libsignal_net::svr3::Error as Svr3Error;
 use libsignal_protocol::*;
 use signal_crypto::Error as SignalCryptoError;
@@ -82,9 +83,12 @@ pub enum SignalErrorCode {
     Network = 133,
     NetworkProtocol = 134,
     RateLimited = 135,
+    Cancelled = 136,
+    ClientDeprecated = 137,
 
     SvrDataMissing = 150,
     SvrRestoreFailed = 151,
+    SvrEnclaveDisagreement = 152,
 }
 
 impl From<&SignalFfiError> for SignalErrorCode {
@@ -135,7 +139,8 @@ impl From<&SignalFfiError> for SignalErrorCode {
                 SignalErrorCode::InvalidKey
             }
 
-            SignalFfiError::Sgx(EnclaveError::AttestationDataError { .. }) => {
+            SignalFfiError::Sgx(EnclaveError::AttestationDataError { .. })
+            | SignalFfiError::Sgx(EnclaveError::GroupIdMismatch { .. }) => {
                 SignalErrorCode::InvalidAttestationData
             }
 
@@ -317,13 +322,23 @@ impl From<&SignalFfiError> for SignalErrorCode {
                     }
                 }
             }
+            SignalFfiError::Network(NetError::ClientDeprecated) => {
+                SignalErrorCode::ClientDeprecated
+            }
             SignalFfiError::Network(_) => SignalErrorCode::Network,
             SignalFfiError::NetworkProtocol(_) => SignalErrorCode::NetworkProtocol,
             SignalFfiError::RateLimited {
                 retry_after_seconds: _,
             } => SignalErrorCode::RateLimited,
             SignalFfiError::Svr(Svr3Error::DataMissing) => SignalErrorCode::SvrDataMissing,
-            SignalFfiError::Svr(Svr3Error::RestoreFailed) => SignalErrorCode::SvrRestoreFailed,
+            SignalFfiError::Svr(Svr3Error::RestoreFailed(_)) => SignalErrorCode::SvrRestoreFailed,
+            SignalFfiError::Svr(Svr3Error::Cancelled) | SignalFfiError::Cancelled => {
+                SignalErrorCode::Cancelled
+            }
+            SignalFfiError::Svr(Svr3Error::NetworkChanged) => SignalErrorCode::Network,
+            SignalFfiError::Svr(Svr3Error::EnclaveDisagreement { .. }) => {
+                SignalErrorCode::SvrEnclaveDisagreement
+            }
             SignalFfiError::Svr(_) => SignalErrorCode::UnknownError,
         }
     }
@@ -9,6 +9,7 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use attest::enclave::Error as EnclaveError;
 use attest::hsm_enclave::Error as HsmEnclaveError;
 use device_transfer::Error as DeviceTransferError;
+use libsignal_net::infra::errors::NetError;
 use libsignal_net::svr3::Error as Svr3Error;
 use libsignal_protocol::*;
 use signal_crypto::Error as SignalCryptoError;
@@ -35,12 +36,13 @@ pub enum SignalFfiError {
     UsernameProofError(usernames::ProofVerificationFailure),
     UsernameLinkError(UsernameLinkError),
     Io(IoError),
-    Network(libsignal_net::infra::errors::NetError),
+    Network(NetError),
     NetworkProtocol(String),
     RateLimited {
         retry_after_seconds: u32,
     },
     Svr(Svr3Error),
+    Cancelled,
     #[cfg(feature = "signal-media")]
     Mp4SanitizeParse(signal_media::sanitize::mp4::ParseErrorReport),
     #[cfg(feature = "signal-media")]
@@ -79,6 +81,7 @@ impl fmt::Display for SignalFfiError {
                 retry_after_seconds,
             } => write!(f, "Rate limited; try again after {}s", retry_after_seconds),
             SignalFfiError::Svr(e) => write!(f, "SVR error: {e}"),
+            SignalFfiError::Cancelled => write!(f, "Operation cancelled by caller"),
             #[cfg(feature = "signal-media")]
             SignalFfiError::Mp4SanitizeParse(e) => {
                 write!(f, "Mp4 sanitizer failed to parse mp4 file: {}", e)
@@ -192,10 +195,15 @@ impl From<libsignal_net::cdsi::LookupError> for SignalFfiError {
 
         match value {
             LookupError::AttestationError(e) => SignalFfiError::Sgx(e),
-            LookupError::Net(e) => SignalFfiError::Network(e),
-            LookupError::ParseError | LookupError::Protocol | LookupError::InvalidResponse => {
-                SignalFfiError::NetworkProtocol(value.to_string())
+            LookupError::Net(e) | LookupError::UploadInterrupted { error: e, .. } => {
+                SignalFfiError::Network(e)
             }
+            LookupError::ParseError
+            | LookupError::Protocol
+            | LookupError::InvalidResponse
+            | LookupError::InvalidToken
+            | LookupError::ServerCrashed => SignalFfiError::NetworkProtocol(value.to_string()),
+            LookupError::Cancelled => SignalFfiError::Cancelled,
             LookupError::RateLimited {
                 retry_after_seconds: retry_after,
             } => SignalFfiError::RateLimited {
@@ -208,12 +216,25 @@ impl From<libsignal_net::cdsi::LookupError> for SignalFfiError {
 impl From<Svr3Error> for SignalFfiError {
     fn from(err: Svr3Error) -> Self {
         match err {
-            Svr3Error::Net(inner) => SignalFfiError::Network(inner),
+            Svr3Error::Net(NetError::RateLimited {
+                retry_after_seconds,
+            }) => SignalFfiError::RateLimited {
+                retry_after_seconds,
+            },
+            Svr3Error::Net(inner) | Svr3Error::RequestNotSent(inner) => {
+                SignalFfiError::Network(inner)
+            }
             Svr3Error::AttestationError(inner) => SignalFfiError::Sgx(inner),
             Svr3Error::Protocol(inner) => SignalFfiError::NetworkProtocol(inner.to_string()),
-            Svr3Error::RequestFailed(_) | Svr3Error::RestoreFailed | Svr3Error::DataMissing => {
-                SignalFfiError::Svr(err)
-            }
+            Svr3Error::RequestFailed(_)
+            | Svr3Error::RestoreFailed(_)
+            | Svr3Error::DataMissing
+            | Svr3Error::Cancelled
+            | Svr3Error::NetworkChanged
+            | Svr3Error::EnvironmentMismatch
+            | Svr3Error::Strengthening(_)
+            | Svr3Error::EnclaveDisagreement { .. }
+            | Svr3Error::InvalidBackupParams(_) => SignalFfiError::Svr(err),
         }
     }
 }
@@ -232,7 +232,9 @@ impl From<libsignal_net::cdsi::LookupError> for SignalJniError {
         use libsignal_net::cdsi::LookupError;
         SignalJniError::Cdsi(match e {
             LookupError::AttestationError(e) => return e.into(),
-            LookupError::Net(e) => return e.into(),
+            LookupError::Net(e) | LookupError::UploadInterrupted { error: e, .. } => {
+                return e.into()
+            }
             LookupError::InvalidResponse => CdsiError::InvalidResponse,
             LookupError::Protocol => CdsiError::Protocol,
             LookupError::RateLimited {
@@ -240,7 +242,10 @@ impl From<libsignal_net::cdsi::LookupError> for SignalJniError {
             } => CdsiError::RateLimited {
                 retry_after: Duration::from_secs(retry_after_seconds.into()),
             },
+            LookupError::InvalidToken => CdsiError::InvalidToken,
+            LookupError::ServerCrashed => CdsiError::ServerCrashed,
             LookupError::ParseError => CdsiError::ParseError,
+            LookupError::Cancelled => CdsiError::Cancelled,
         })
     }
 }
@@ -266,12 +271,18 @@ impl From<jni::errors::Error> for BridgeLayerError {
 impl From<Svr3Error> for SignalJniError {
     fn from(err: Svr3Error) -> Self {
         match err {
-            Svr3Error::Net(inner) => inner.into(),
+            Svr3Error::Net(inner) | Svr3Error::RequestNotSent(inner) => inner.into(),
             Svr3Error::AttestationError(inner) => inner.into(),
             Svr3Error::Protocol(_)
             | Svr3Error::RequestFailed(_)
-            | Svr3Error::RestoreFailed
-            | Svr3Error::DataMissing => SignalJniError::Svr3(err),
+            | Svr3Error::RestoreFailed(_)
+            | Svr3Error::DataMissing
+            | Svr3Error::Cancelled
+            | Svr3Error::NetworkChanged
+            | Svr3Error::EnvironmentMismatch
+            | Svr3Error::Strengthening(_)
+            | Svr3Error::EnclaveDisagreement { .. }
+            | Svr3Error::InvalidBackupParams(_) => SignalJniError::Svr3(err),
         }
     }
 }
@@ -12,6 +12,8 @@ use attest::hsm_enclave::Error as HsmEnclaveError;
 use device_transfer::Error as DeviceTransferError;
 use jni::objects::{GlobalRef, JThrowable, JValue, JValueOwned};
 use jni::JavaVM;
+use libsignal_net::cdsi::CdsiError;
+use libsignal_net::infra::errors::NetError;
 use libsignal_net::svr3::Error as Svr3Error;
 use libsignal_protocol::*;
 use once_cell::sync::OnceCell;
@@ -272,6 +274,65 @@ where
             return;
         }
 
+        SignalJniError::Net(NetError::RateLimited {
+            retry_after_seconds,
+        }) => {
+            let throwable = env.new_string(error.to_string()).and_then(|message| {
+                Ok(new_object(
+                    env,
+                    jni_class_name!(org.signal.libsignal.net.RateLimitedException),
+                    jni_args!((
+                        message => java.lang.String,
+                        jlong::from(retry_after_seconds) => long,
+                    ) -> void),
+                )?
+                .into())
+            });
+
+            consume(env, throwable.map_err(Into::into), &error);
+            return;
+        }
+
+        SignalJniError::Cdsi(CdsiError::RateLimited { retry_after }) => {
+            let retry_after_seconds = jlong::try_from(retry_after.as_secs()).unwrap_or(jlong::MAX);
+            let throwable = env.new_string(error.to_string()).and_then(|message| {
+                Ok(new_object(
+                    env,
+                    jni_class_name!(org.signal.libsignal.net.RateLimitedException),
+                    jni_args!((
+                        message => java.lang.String,
+                        retry_after_seconds => long,
+                    ) -> void),
+                )?
+                .into())
+            });
+
+            consume(env, throwable.map_err(Into::into), &error);
+            return;
+        }
+
+        SignalJniError::Svr3(Svr3Error::EnclaveDisagreement { ref accepted }) => {
+            let throwable = env
+                .new_string(error.to_string())
+                .and_then(|message| {
+                    let flags: Vec<jboolean> = accepted.iter().map(|&a| a.into()).collect();
+                    let accepted = env.new_boolean_array(flags.len() as jint)?;
+                    env.set_boolean_array_region(&accepted, 0, &flags)?;
+                    Ok((message, accepted))
+                })
+                .and_then(|(message, accepted)| {
+                    Ok(new_object(
+                        env,
+                        jni_class_name!(org.signal.libsignal.svr.EnclaveDisagreementException),
+                        jni_args!((message => java.lang.String, accepted => [boolean]) -> void),
+                    )?
+                    .into())
+                });
+
+            consume(env, throwable.map_err(Into::into), &error);
+            return;
+        }
+
         e => e,
     };
 
@@ -406,7 +467,8 @@ where
         SignalJniError::Enclave(EnclaveError::AttestationError(_)) => {
             jni_class_name!(org.signal.libsignal.attest.AttestationFailedException)
         }
-        SignalJniError::Enclave(EnclaveError::AttestationDataError { .. }) => {
+        SignalJniError::Enclave(EnclaveError::AttestationDataError { .. })
+        | SignalJniError::Enclave(EnclaveError::GroupIdMismatch { .. }) => {
             jni_class_name!(org.signal.libsignal.attest.AttestationDataException)
         }
         SignalJniError::Enclave(EnclaveError::InvalidBridgeStateError) => {
@@ -540,15 +602,27 @@ where
             jni_class_name!(org.signal.libsignal.media.ParseException)
         }
 
+        SignalJniError::Cdsi(CdsiError::Cancelled) => {
+            jni_class_name!(java.util.concurrent.CancellationException)
+        }
         SignalJniError::Cdsi(_) => jni_class_name!(org.signal.libsignal.net.CdsiLookupException),
+        SignalJniError::Net(NetError::ClientDeprecated) => {
+            jni_class_name!(org.signal.libsignal.net.ClientDeprecatedException)
+        }
         SignalJniError::Net(_) => jni_class_name!(org.signal.libsignal.net.NetworkException),
 
-        SignalJniError::Svr3(Svr3Error::RestoreFailed) => {
+        SignalJniError::Svr3(Svr3Error::RestoreFailed(_)) => {
             jni_class_name!(org.signal.libsignal.svr.RestoreFailedException)
         }
         SignalJniError::Svr3(Svr3Error::DataMissing) => {
             jni_class_name!(org.signal.libsignal.svr.DataMissingException)
         }
+        SignalJniError::Svr3(Svr3Error::Cancelled) => {
+            jni_class_name!(java.util.concurrent.CancellationException)
+        }
+        SignalJniError::Svr3(Svr3Error::NetworkChanged) => {
+            jni_class_name!(org.signal.libsignal.net.NetworkException)
+        }
         SignalJniError::Svr3(_) => jni_class_name!(org.signal.libsignal.svr.SvrException),
 
         #[cfg(feature = "testing-fns")]
@@ -10,12 +10,15 @@ use std::panic::RefUnwindSafe;
 use std::sync::Arc;
 use std::time::Duration;
 
+use attest::nitro::NitroChainCache;
 use base64::prelude::{Engine, BASE64_STANDARD};
 use futures_util::future::TryFutureExt as _;
 use http::uri::PathAndQuery;
 use http::{HeaderMap, HeaderName, HeaderValue};
+use nonzero_ext::nonzero;
 use rand::rngs::OsRng;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 use libsignal_bridge_macros::{bridge_fn, bridge_fn_void, bridge_io};
 use libsignal_net::auth::Auth;
@@ -26,14 +29,19 @@ use libsignal_net::chat::{chat_service, ChatServiceWithDebugInfo, DebugInfo, Req
 use libsignal_net::enclave::{
     Cdsi, EnclaveEndpoint, EnclaveEndpointConnection, EnclaveKind, Nitro, PpssSetup, Sgx,
 };
-use libsignal_net::env::{Env, Svr3Env};
+use libsignal_net::env::{Env, Svr3ConnectTimeouts, Svr3Env};
 use libsignal_net::infra::connection_manager::MultiRouteConnectionManager;
+use libsignal_net::infra::correlation::CorrelationId;
 use libsignal_net::infra::dns::DnsResolver;
 use libsignal_net::infra::errors::NetError;
-use libsignal_net::infra::{make_ws_config, EndpointConnection, TcpSslTransportConnector};
+use libsignal_net::infra::network_state::NetworkState;
+use libsignal_net::infra::{
+    make_ws_config, ConnectTimeouts, EndpointConnection, TcpSslTransportConnector,
+};
 use libsignal_net::svr::{self, SvrConnection};
+use libsignal_net::svr3::diagnostics::diagnose;
 use libsignal_net::svr3::{self, OpaqueMaskedShareSet, PpssOps as _};
-use libsignal_net::utils::timeout;
+use libsignal_net::utils::{cancellable, timeout};
 use libsignal_net::{chat, env};
 use libsignal_protocol::{Aci, SignalProtocolError};
 
@@ -87,6 +95,13 @@ impl Environment {
             Self::Prod => libsignal_net::env::PROD,
         }
     }
+
+    fn svr3_env(self) -> &'static Svr3Env<'static> {
+        match self {
+            Self::Staging => &libsignal_net::env::STAGING.svr3,
+            Self::Prod => &libsignal_net::env::PROD.svr3,
+        }
+    }
 }
 
 pub struct ConnectionManager {
@@ -96,7 +111,9 @@ pub struct ConnectionManager {
         EnclaveEndpointConnection<Sgx, MultiRouteConnectionManager>,
         EnclaveEndpointConnection<Nitro, MultiRouteConnectionManager>,
     ),
+    svr3_env: &'static Svr3Env<'static>,
     transport_connector: TcpSslTransportConnector,
+    network_state: Arc<NetworkState>,
 }
 
 impl RefUnwindSafe for ConnectionManager {}
@@ -108,35 +125,53 @@ impl ConnectionManager {
             DnsResolver::new_with_static_fallback(environment.env().static_fallback());
         let transport_connector = TcpSslTransportConnector::new(dns_resolver);
         let chat_endpoint = PathAndQuery::from_static(env::constants::WEB_SOCKET_PATH);
-        let chat_connection_params = environment
-            .env()
-            .chat_domain_config
-            .connection_params_with_fallback();
+        let chat_domain_config = environment.env().chat_domain_config;
+        let chat_connection_params = chat_domain_config.connection_params_with_fallback();
         let chat_ws_config = make_ws_config(chat_endpoint, Self::DEFAULT_CONNECT_TIMEOUT);
+        let network_state = Arc::new(NetworkState::new());
+        let svr3_timeouts = Svr3ConnectTimeouts::from(Self::DEFAULT_CONNECT_TIMEOUT);
         Self {
             chat: EndpointConnection::new_multi(
                 chat_connection_params,
                 Self::DEFAULT_CONNECT_TIMEOUT,
                 chat_ws_config,
+            )
+            .with_route_selection(chat_domain_config.route_selection()),
+            cdsi: Self::endpoint_connection(
+                environment.env().cdsi,
+                Self::DEFAULT_CONNECT_TIMEOUT,
+                &network_state,
             ),
-            cdsi: Self::endpoint_connection(environment.env().cdsi),
             svr3: (
-                Self::endpoint_connection(environment.env().svr3.sgx()),
-                Self::endpoint_connection(environment.env().svr3.nitro()),
+                Self::endpoint_connection(
+                    environment.env().svr3.sgx(),
+                    svr3_timeouts.sgx,
+                    &network_state,
+                ),
+                // The cache is cleared by ConnectionManager_on_network_change.
+                Self::endpoint_connection(
+                    environment.env().svr3.nitro(),
+                    svr3_timeouts.nitro,
+                    &network_state,
+                )
+                .with_chain_cache(Arc::new(NitroChainCache::default())),
             ),
+            svr3_env: environment.svr3_env(),
             transport_connector,
+            network_state,
         }
     }
 
     fn endpoint_connection<E: EnclaveKind>(
         endpoint: EnclaveEndpoint<'static, E>,
+        timeouts: impl Into<ConnectTimeouts>,
+        network_state: &Arc<NetworkState>,
     ) -> EnclaveEndpointConnection<E, MultiRouteConnectionManager> {
         let params = endpoint.domain_config.connection_params_with_fallback();
-        EnclaveEndpointConnection::new_multi(
-            endpoint.mr_enclave,
-            params,
-            Self::DEFAULT_CONNECT_TIMEOUT,
-        )
+        EnclaveEndpointConnection::new_multi(endpoint.mr_enclave, params, timeouts)
+            .with_path_prefix(endpoint.domain_config.path_prefix)
+            .with_route_selection(endpoint.domain_config.route_selection())
+            .with_network_state(network_state.clone())
     }
 }
 
@@ -145,8 +180,55 @@ fn ConnectionManager_new(environment: AsType<Environment, u8>) -> ConnectionMana
     ConnectionManager::new(environment.into_inner())
 }
 
+/// Tells `connection_manager` that the device's network changed, so that
+/// operations failing on connections made before the change report it, and
+/// Nitro certificate chains validated on the old network are checked again.
+#[bridge_fn]
+fn ConnectionManager_on_network_change(connection_manager: &ConnectionManager) {
+    connection_manager.network_state.mark_changed()
+}
+
 bridge_handle!(ConnectionManager, clone = false);
 
+/// Returns a JSON object with the log-safe
+/// [`Svr3Fingerprint`](env::Svr3Fingerprint) and
+/// [`EnclaveFingerprint`](libsignal_net::enclave::EnclaveFingerprint) of the
+/// SVR3 and CDSI enclaves of `environment`.
+#[bridge_fn]
+fn Environment_Fingerprint(environment: AsType<Environment, u8>) -> String {
+    let Env { svr3, cdsi, .. } = environment.into_inner().env();
+    serde_json::json!({
+        "svr3": svr3.fingerprint(),
+        "cdsi": cdsi.fingerprint(),
+    })
+    .to_string()
+}
+
+/// Lets the app cancel in-flight network operations.
+///
+/// A signal can be passed to any number of operations. Cancelling it stops all
+/// of them, including ones started afterwards; cancelling after they have
+/// completed has no effect.
+#[derive(Default)]
+pub struct CancellationSignal(CancellationToken);
+
+/// Assert [`CancellationSignal`] is unwind-safe.
+///
+/// Cancelling is the only operation on the token, and it is idempotent.
+impl RefUnwindSafe for CancellationSignal {}
+
+#[bridge_fn]
+fn CancellationSignal_new() -> CancellationSignal {
+    CancellationSignal::default()
+}
+
+#[bridge_fn]
+fn CancellationSignal_cancel(signal: &CancellationSignal) {
+    signal.0.cancel()
+}
+
+bridge_handle!(CancellationSignal, clone = false);
+
 #[derive(Default)]
 pub struct LookupRequest(std::sync::Mutex<cdsi::LookupRequest>);
 
@@ -215,6 +297,7 @@ bridge_handle!(CdsiLookup, clone = false);
 #[bridge_io(TokioAsyncContext)]
 async fn CdsiLookup_new(
     connection_manager: &ConnectionManager,
+    cancellation: &CancellationSignal,
     username: String,
     password: String,
     request: &LookupRequest,
@@ -223,18 +306,22 @@ async fn CdsiLookup_new(
     let request = std::mem::take(&mut *request.0.lock().expect("not poisoned"));
     let auth = Auth { username, password };
 
-    let connected = CdsiConnection::connect(
-        &connection_manager.cdsi,
-        connection_manager.transport_connector.clone(),
-        auth,
-    )
-    .await?;
-    let (token, remaining_response) = timeout(
-        Duration::from_millis(timeout_millis.into()),
-        cdsi::LookupError::Net(NetError::Timeout),
-        connected.send_request(request),
-    )
-    .await?;
+    let (token, remaining_response) =
+        cancellable(&cancellation.0, cdsi::LookupError::Cancelled, async {
+            let connected = CdsiConnection::connect(
+                &connection_manager.cdsi,
+                connection_manager.transport_connector.clone(),
+                auth,
+            )
+            .await?;
+            timeout(
+                Duration::from_millis(timeout_millis.into()),
+                cdsi::LookupError::Net(NetError::Timeout),
+                connected.send_request(request),
+            )
+            .await
+        })
+        .await?;
 
     Ok(CdsiLookup {
         token,
@@ -248,7 +335,10 @@ fn CdsiLookup_token(lookup: &CdsiLookup) -> &[u8] {
 }
 
 #[bridge_io(TokioAsyncContext)]
-async fn CdsiLookup_complete(lookup: &CdsiLookup) -> Result<LookupResponse, cdsi::LookupError> {
+async fn CdsiLookup_complete(
+    lookup: &CdsiLookup,
+    cancellation: &CancellationSignal,
+) -> Result<LookupResponse, cdsi::LookupError> {
     let CdsiLookup {
         token: _,
         remaining,
@@ -260,7 +350,12 @@ async fn CdsiLookup_complete(lookup: &CdsiLookup) -> Result<LookupResponse, cdsi
         .take()
         .expect("not completed yet");
 
-    remaining.collect().await
+    cancellable(
+        &cancellation.0,
+        cdsi::LookupError::Cancelled,
+        remaining.collect(),
+    )
+    .await
 }
 
 #[bridge_fn]
@@ -277,6 +372,7 @@ fn CreateOTPFromBase64(username: String, secret: String) -> String {
 #[bridge_io(TokioAsyncContext)]
 async fn Svr3Backup(
     connection_manager: &ConnectionManager,
+    cancellation: &CancellationSignal,
     secret: Box<[u8]>,
     password: String,
     max_tries: AsType<NonZeroU32, u32>,
@@ -288,48 +384,128 @@ async fn Svr3Backup(
         .as_ref()
         .try_into()
         .expect("can only backup 32 bytes");
+    // Fail before spending time connecting to enclaves that would reject it.
+    let max_tries = max_tries.into_inner();
+    connection_manager
+        .svr3_env
+        .validate_backup_params(max_tries)?;
     let mut rng = OsRng;
-    let share_set = timeout(
-        Duration::from_millis(op_timeout_ms.into()),
-        svr::Error::Net(NetError::Timeout).into(),
-        svr3_connect(connection_manager, username, enclave_password)
-            .map_err(|err| err.into())
-            .and_then(|connections| {
-                Svr3Env::backup(
-                    connections,
-                    &password,
-                    secret,
-                    max_tries.into_inner(),
-                    &mut rng,
-                )
-            }),
+    let share_set = cancellable(
+        &cancellation.0,
+        svr3::Error::Cancelled,
+        timeout(
+            Duration::from_millis(op_timeout_ms.into()),
+            svr::Error::Net(NetError::Timeout).into(),
+            svr3_connect(connection_manager, username, enclave_password)
+                .map_err(|err| err.into())
+                .and_then(|connections| {
+                    connection_manager.svr3_env.backup(
+                        connections,
+                        &password,
+                        secret,
+                        max_tries,
+                        None,
+                        &mut rng,
+                    )
+                }),
+        ),
     )
     .await?;
     Ok(share_set.serialize().expect("can serialize the share set"))
 }
 
+const SVR3_RESTORE_ATTEMPTS: NonZeroU32 = nonzero!(2u32);
+
 #[bridge_io(TokioAsyncContext)]
 async fn Svr3Restore(
     connection_manager: &ConnectionManager,
+    cancellation: &CancellationSignal,
     password: String,
     share_set: Box<[u8]>,
     username: String,         // hex-encoded uid
     enclave_password: String, // timestamp:otp(...)
     op_timeout_ms: u32,       // timeout spans both connecting and performing the operation
 ) -> Result<Vec<u8>, svr3::Error> {
-    let mut rng = OsRng;
     let share_set = OpaqueMaskedShareSet::deserialize(&share_set)?;
-    let restored_secret = timeout(
-        Duration::from_millis(op_timeout_ms.into()),
-        svr::Error::Net(NetError::Timeout).into(),
-        svr3_connect(connection_manager, username, enclave_password)
-            .map_err(|err| err.into())
-            .and_then(|connections| Svr3Env::restore(connections, &password, share_set, &mut rng)),
+    let restored_secret = cancellable(
+        &cancellation.0,
+        svr3::Error::Cancelled,
+        timeout(
+            Duration::from_millis(op_timeout_ms.into()),
+            svr::Error::Net(NetError::Timeout).into(),
+            // Each try at a restore uses up one of the guesses allowed, so only
+            // retry if the enclaves cannot have seen the request.
+            svr3::retry_if_not_sent(SVR3_RESTORE_ATTEMPTS, || {
+                let username = username.clone();
+                let enclave_password = enclave_password.clone();
+                let share_set = share_set.clone();
+                let password = &password;
+                async move {
+                    let connections =
+                        svr3_connect(connection_manager, username, enclave_password).await?;
+                    connection_manager
+                        .svr3_env
+                        .restore(connections, password, share_set, false, None, &mut OsRng)
+                        .await
+                }
+            }),
+        ),
     )
     .await?;
     Ok(restored_secret.to_vec())
 }
 
+#[bridge_io(TokioAsyncContext)]
+async fn Svr3Remove(
+    connection_manager: &ConnectionManager,
+    cancellation: &CancellationSignal,
+    username: String,         // hex-encoded uid
+    enclave_password: String, // timestamp:otp(...)
+    op_timeout_ms: u32,       // timeout spans both connecting and performing the operation
+) -> Result<(), svr3::Error> {
+    cancellable(
+        &cancellation.0,
+        svr3::Error::Cancelled,
+        timeout(
+            Duration::from_millis(op_timeout_ms.into()),
+            svr::Error::Net(NetError::Timeout).into(),
+            svr3_connect(connection_manager, username, enclave_password)
+                .map_err(|err| err.into())
+                .and_then(|connections| connection_manager.svr3_env.remove(connections)),
+        ),
+    )
+    .await
+}
+
+/// Connects to each SVR3 enclave without performing any operation.
+///
+/// Returns a JSON array of [`EnclaveDiagnostics`](svr3::diagnostics::EnclaveDiagnostics).
+#[bridge_io(TokioAsyncContext)]
+async fn Svr3ConnectDiagnostics(
+    connection_manager: &ConnectionManager,
+    username: String,         // hex-encoded uid
+    enclave_password: String, // timestamp:otp(...)
+) -> Vec<u8> {
+    let auth = Auth {
+        username,
+        password: enclave_password,
+    };
+    let ConnectionManager {
+        chat: _chat,
+        cdsi: _cdsi,
+        svr3: (sgx, nitro),
+        svr3_env: _svr3_env,
+        transport_connector,
+        network_state: _network_state,
+    } = connection_manager;
+    let results = futures_util::future::join(
+        diagnose(auth.clone(), sgx, transport_connector.clone()),
+        diagnose(auth, nitro, transport_connector.clone()),
+    )
+    .await;
+    serde_json::to_vec(&[results.0, results.1]).expect("can serialize diagnostics")
+}
+
 async fn svr3_connect<'a>(
     connection_manager: &ConnectionManager,
     username: String,
@@ -340,11 +516,28 @@ async fn svr3_connect<'a>(
         chat: _chat,
         cdsi: _cdsi,
         svr3: (sgx, nitro),
+        svr3_env: _svr3_env,
         transport_connector,
+        network_state: _network_state,
     } = connection_manager;
-    let sgx = SvrConnection::connect(auth.clone(), sgx, transport_connector.clone()).await?;
-    let nitro = SvrConnection::connect(auth, nitro, transport_connector.clone()).await?;
-    Ok((sgx, nitro))
+    let correlation_id = CorrelationId::random();
+    // As in BlockingSvr3Client::connect, the first enclave to fail drops the
+    // attempt to connect to the other.
+    futures_util::future::try_join(
+        SvrConnection::connect_with_correlation_id(
+            auth.clone(),
+            sgx,
+            transport_connector.clone(),
+            correlation_id,
+        ),
+        SvrConnection::connect_with_correlation_id(
+            auth,
+            nitro,
+            transport_connector.clone(),
+            correlation_id,
+        ),
+    )
+    .await
 }
 
 pub struct Chat {
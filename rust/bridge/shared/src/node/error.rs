@@ -4,6 +4,7 @@
 //
 use std::fmt;
 
+use libsignal_net::infra::errors::NetError;
 use libsignal_net::svr3::Error as Svr3Error;
 use paste::paste;
 use signal_media::sanitize::mp4::{Error as Mp4Error, ParseError as Mp4ParseError};
@@ -132,10 +133,13 @@ pub trait SignalNodeError: Sized + fmt::Display {
     }
 }
 
+const CANCELLED: &str = "Cancelled";
+const CLIENT_DEPRECATED_ERROR: &str = "ClientDeprecatedError";
 const INVALID_MEDIA_INPUT: &str = "InvalidMediaInput";
 const IO_ERROR: &str = "IoError";
 const RATE_LIMITED_ERROR: &str = "RateLimitedError";
 const SVR3_DATA_MISSING: &str = "SvrDataMissing";
+const SVR3_ENCLAVE_DISAGREEMENT: &str = "SvrEnclaveDisagreement";
 const SVR3_REQUEST_FAILED: &str = "SvrRequestFailed";
 const SVR3_RESTORE_FAILED: &str = "SvrRestoreFailed";
 const UNSUPPORTED_MEDIA_INPUT: &str = "UnsupportedMediaInput";
@@ -396,14 +400,17 @@ impl SignalNodeError for std::io::Error {
     }
 }
 
-impl SignalNodeError for libsignal_net::infra::errors::NetError {
+impl SignalNodeError for NetError {
     fn throw<'a>(
         self,
         cx: &mut impl Context<'a>,
         module: Handle<'a, JsObject>,
         operation_name: &str,
     ) -> JsResult<'a, JsValue> {
-        let name = Some(IO_ERROR);
+        let name = match self {
+            NetError::ClientDeprecated => Some(CLIENT_DEPRECATED_ERROR),
+            _ => Some(IO_ERROR),
+        };
         let message = self.to_string();
         match new_js_error(cx, module, name, &message, operation_name, None) {
             Some(error) => cx.throw(error),
@@ -435,9 +442,15 @@ impl SignalNodeError for libsignal_net::cdsi::LookupError {
                 }),
             ),
             Self::AttestationError(e) => return e.throw(cx, module, operation_name),
-            Self::Net(_) | Self::Protocol | Self::InvalidResponse | Self::ParseError => {
-                (IO_ERROR, None)
-            }
+            Self::Net(NetError::ClientDeprecated) => (CLIENT_DEPRECATED_ERROR, None),
+            Self::Net(_)
+            | Self::Protocol
+            | Self::InvalidResponse
+            | Self::InvalidToken
+            | Self::ServerCrashed
+            | Self::UploadInterrupted { .. }
+            | Self::ParseError => (IO_ERROR, None),
+            Self::Cancelled => (CANCELLED, None),
         };
         let message = self.to_string();
         new_js_error(
@@ -461,19 +474,53 @@ impl SignalNodeError for libsignal_net::svr3::Error {
         module: Handle<'a, JsObject>,
         operation_name: &str,
     ) -> JsResult<'a, JsValue> {
-        let name = match self {
-            Svr3Error::Net(_) => Some(IO_ERROR),
+        let (name, extra_props) = match self {
+            Svr3Error::Net(NetError::RateLimited {
+                retry_after_seconds,
+            }) => (
+                Some(RATE_LIMITED_ERROR),
+                Some({
+                    let props = cx.empty_object();
+                    let retry_after = retry_after_seconds.convert_into(cx)?;
+                    props.set(cx, "retryAfterSecs", retry_after)?;
+                    props
+                }),
+            ),
+            Svr3Error::Net(NetError::ClientDeprecated)
+            | Svr3Error::RequestNotSent(NetError::ClientDeprecated) => {
+                (Some(CLIENT_DEPRECATED_ERROR), None)
+            }
+            Svr3Error::Net(_) | Svr3Error::RequestNotSent(_) | Svr3Error::NetworkChanged => {
+                (Some(IO_ERROR), None)
+            }
             Svr3Error::AttestationError(inner) => {
                 return inner.throw(cx, module, operation_name);
             }
-            Svr3Error::RequestFailed(_) => Some(SVR3_REQUEST_FAILED),
-            Svr3Error::RestoreFailed => Some(SVR3_RESTORE_FAILED),
-            Svr3Error::DataMissing => Some(SVR3_DATA_MISSING),
-            Svr3Error::Protocol(_) => None,
+            Svr3Error::RequestFailed(_) => (Some(SVR3_REQUEST_FAILED), None),
+            Svr3Error::RestoreFailed(_) => (Some(SVR3_RESTORE_FAILED), None),
+            Svr3Error::DataMissing => (Some(SVR3_DATA_MISSING), None),
+            Svr3Error::Cancelled => (Some(CANCELLED), None),
+            Svr3Error::EnclaveDisagreement { ref accepted } => (
+                Some(SVR3_ENCLAVE_DISAGREEMENT),
+                Some({
+                    let props = cx.empty_object();
+                    let array = JsArray::new(cx, accepted.len());
+                    for (&accepted, i) in accepted.iter().zip(0..) {
+                        let accepted = cx.boolean(accepted);
+                        array.set(cx, i, accepted)?;
+                    }
+                    props.set(cx, "accepted", array)?;
+                    props
+                }),
+            ),
+            Svr3Error::Protocol(_)
+            | Svr3Error::EnvironmentMismatch
+            | Svr3Error::Strengthening(_)
+            | Svr3Error::InvalidBackupParams(_) => (None, None),
         };
 
         let message = self.to_string();
-        match new_js_error(cx, module, name, &message, operation_name, None) {
+        match new_js_error(cx, module, name, &message, operation_name, extra_props) {
             Some(error) => cx.throw(error),
             None => {
                 // Make sure we still throw something.
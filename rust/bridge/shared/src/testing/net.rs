@@ -8,6 +8,7 @@ use libsignal_bridge_macros::*;
 use libsignal_net::cdsi::{LookupError, LookupResponse, LookupResponseEntry, E164};
 use libsignal_net::chat::{DebugInfo, IpType, Response};
 use libsignal_net::infra::errors::NetError;
+use libsignal_net::svr3;
 use libsignal_protocol::{Aci, Pni};
 use nonzero_ext::nonzero;
 use uuid::Uuid;
@@ -49,6 +50,25 @@ fn TESTING_CdsiLookupErrorConvert() -> Result<(), LookupError> {
     Err(LookupError::ParseError)
 }
 
+#[bridge_fn]
+fn TESTING_CdsiRateLimitedErrorConvert() -> Result<(), LookupError> {
+    Err(LookupError::RateLimited {
+        retry_after_seconds: 42,
+    })
+}
+
+#[bridge_fn]
+fn TESTING_Svr3ClientDeprecatedErrorConvert() -> Result<(), svr3::Error> {
+    Err(svr3::Error::Net(NetError::ClientDeprecated))
+}
+
+#[bridge_fn]
+fn TESTING_Svr3RateLimitedErrorConvert() -> Result<(), svr3::Error> {
+    Err(svr3::Error::Net(NetError::RateLimited {
+        retry_after_seconds: 42,
+    }))
+}
+
 #[bridge_fn(ffi = false, jni = false)]
 fn TESTING_ChatServiceErrorConvert() -> Result<(), NetError> {
     Err(NetError::Timeout)
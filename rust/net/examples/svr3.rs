@@ -18,7 +18,6 @@ use rand_core::{CryptoRngCore, OsRng, RngCore};
 
 use libsignal_net::auth::Auth;
 use libsignal_net::enclave::{EnclaveEndpointConnection, Nitro, Sgx};
-use libsignal_net::env::Svr3Env;
 use libsignal_net::infra::TcpSslTransportConnector;
 use libsignal_net::svr::SvrConnection;
 use libsignal_net::svr3::{OpaqueMaskedShareSet, PpssOps};
@@ -74,15 +73,17 @@ async fn main() {
     println!("Secret to be stored: {}", hex::encode(secret));
 
     let share_set_bytes = {
-        let opaque_share_set = Svr3Env::backup(
-            connect().await,
-            &args.password,
-            secret,
-            nonzero!(10u32),
-            &mut rng,
-        )
-        .await
-        .expect("can multi backup");
+        let opaque_share_set = env
+            .backup(
+                connect().await,
+                &args.password,
+                secret,
+                nonzero!(10u32),
+                None,
+                &mut rng,
+            )
+            .await
+            .expect("can multi backup");
         opaque_share_set.serialize().expect("can serialize")
     };
     println!("Share set: {}", hex::encode(&share_set_bytes));
@@ -90,9 +91,16 @@ async fn main() {
     let restored = {
         let opaque_share_set =
             OpaqueMaskedShareSet::deserialize(&share_set_bytes).expect("can deserialize");
-        Svr3Env::restore(connect().await, &args.password, opaque_share_set, &mut rng)
-            .await
-            .expect("can mutli restore")
+        env.restore(
+            connect().await,
+            &args.password,
+            opaque_share_set,
+            false,
+            None,
+            &mut rng,
+        )
+        .await
+        .expect("can mutli restore")
     };
     println!("Restored secret: {}", hex::encode(restored));
 
@@ -39,10 +39,13 @@ const TEST_SERVER_RAFT_CONFIG: RaftConfig = RaftConfig {
 };
 const TEST_SERVER_DOMAIN_CONFIG: DomainConfig = DomainConfig {
     hostname: "backend1.svr3.test.signal.org",
+    port: 443,
     ip_v4: &[],
     ip_v6: &[],
     cert: &TEST_SERVER_CERT,
     proxy_path: "/svr3-test",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub struct TwoForTwoEnv<'a, A, B>(EnclaveEndpoint<'a, A>, EnclaveEndpoint<'a, B>)
@@ -58,9 +61,13 @@ where
     type Connections = (SvrConnection<A>, SvrConnection<B>);
     type ServerIds = [u64; 2];
 
-    fn server_ids() -> Self::ServerIds {
+    fn server_ids(&self) -> Self::ServerIds {
         [0, 1]
     }
+
+    fn mr_enclaves(&self) -> Vec<&[u8]> {
+        vec![self.0.mr_enclave.as_ref(), self.1.mr_enclave.as_ref()]
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -134,15 +141,17 @@ async fn main() {
     println!("Secret to be stored: {}", hex::encode(secret));
 
     let share_set_bytes = {
-        let opaque_share_set = TwoForTwoEnv::backup(
-            connect().await,
-            &args.password,
-            secret,
-            nonzero!(10u32),
-            &mut rng,
-        )
-        .await
-        .expect("can multi backup");
+        let opaque_share_set = two_sgx_env
+            .backup(
+                connect().await,
+                &args.password,
+                secret,
+                nonzero!(10u32),
+                None,
+                &mut rng,
+            )
+            .await
+            .expect("can multi backup");
         opaque_share_set.serialize().expect("can serialize")
     };
     println!("Share set: {}", hex::encode(&share_set_bytes));
@@ -150,7 +159,15 @@ async fn main() {
     let restored = {
         let opaque_share_set =
             OpaqueMaskedShareSet::deserialize(&share_set_bytes).expect("can deserialize");
-        TwoForTwoEnv::restore(connect().await, &args.password, opaque_share_set, &mut rng)
+        two_sgx_env
+            .restore(
+                connect().await,
+                &args.password,
+                opaque_share_set,
+                false,
+                None,
+                &mut rng,
+            )
             .await
             .expect("can multi restore")
     };
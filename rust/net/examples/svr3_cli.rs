@@ -0,0 +1,170 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A command line tool that runs a single SVR3 operation for a given user.
+//!
+//! The auth secrets for the enclaves are read from the `SVR3_SGX_SECRET` and
+//! `SVR3_NITRO_SECRET` environment variables, in hex or base64. A backup stores
+//! a random secret and prints the resulting share set in base64, which is what
+//! `restore` takes back:
+//!
+//! ```text
+//! svr3_cli --uid <hex> backup --password <password> --max-tries 10
+//! svr3_cli --uid <hex> restore --password <password> --share-set <base64>
+//! svr3_cli --uid <hex> query
+//! svr3_cli --uid <hex> remove
+//! ```
+//!
+//! The staging enclaves are used unless `--production` is passed.
+use std::num::NonZeroU32;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use rand_core::{OsRng, RngCore};
+
+use libsignal_net::auth::{parse_auth_secret, Auth};
+use libsignal_net::env::Svr3Env;
+use libsignal_net::infra::errors::RetryLater;
+use libsignal_net::svr3::blocking::{BlockingError, BlockingSvr3Client};
+use libsignal_net::svr3::{Error, OpaqueMaskedShareSet};
+
+#[derive(Parser)]
+struct Args {
+    /// UID of the user, as 32 hex digits
+    #[arg(long, value_parser = parse_uid)]
+    uid: [u8; 16],
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Backs up a random secret and prints the share set
+    Backup {
+        /// Password to protect the secret with
+        #[arg(long)]
+        password: String,
+        /// Number of restore attempts allowed before the data is deleted
+        #[arg(long, default_value = "10")]
+        max_tries: NonZeroU32,
+    },
+    /// Restores the secret from a share set printed by `backup`
+    Restore {
+        /// Password the secret was backed up with
+        #[arg(long)]
+        password: String,
+        /// base64 encoding of the share set
+        #[arg(long, value_parser = parse_share_set)]
+        share_set: OpaqueMaskedShareSet,
+    },
+    /// Prints the number of restore attempts left
+    Query,
+    /// Removes the stored data
+    Remove,
+}
+
+fn main() -> ExitCode {
+    init_logger();
+    let matches = Svr3Env::register_clap_args(Args::command()).get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let env = Svr3Env::from_arg_matches(&matches);
+
+    let auth = |var: &str| {
+        let encoded = std::env::var(var).map_err(|_| format!("{var} is not set"))?;
+        let secret = parse_auth_secret(&encoded).map_err(|e| format!("{var}: {e}"))?;
+        Ok::<_, String>(Auth::from_uid_and_secret(args.uid, secret))
+    };
+    let (sgx_auth, nitro_auth) = match (auth("SVR3_SGX_SECRET"), auth("SVR3_NITRO_SECRET")) {
+        (Ok(sgx_auth), Ok(nitro_auth)) => (sgx_auth, nitro_auth),
+        (Err(message), _) | (_, Err(message)) => {
+            eprintln!("{message}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let client = BlockingSvr3Client::new(env, Duration::from_secs(10));
+    match run(&client, sgx_auth, nitro_auth, args.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", describe(&err));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(
+    client: &BlockingSvr3Client,
+    sgx_auth: Auth,
+    nitro_auth: Auth,
+    command: Command,
+) -> Result<(), BlockingError> {
+    let connections = client.connect(sgx_auth, nitro_auth)?;
+    match command {
+        Command::Backup {
+            password,
+            max_tries,
+        } => {
+            let mut secret = [0; 32];
+            OsRng.fill_bytes(&mut secret);
+            let share_set =
+                client.backup(connections, &password, secret, max_tries, None, &mut OsRng)?;
+            let serialized = share_set.serialize().expect("can serialize");
+            println!("Secret: {}", hex::encode(secret));
+            println!("Share set: {}", BASE64_STANDARD.encode(serialized));
+        }
+        Command::Restore {
+            password,
+            share_set,
+        } => {
+            let secret =
+                client.restore(connections, &password, share_set, false, None, &mut OsRng)?;
+            println!("Secret: {}", hex::encode(secret));
+        }
+        Command::Query => {
+            let tries_remaining = client.query(connections)?;
+            println!("Tries remaining: {tries_remaining}");
+        }
+        Command::Remove => {
+            client.remove(connections)?;
+            println!("Removed");
+        }
+    }
+    Ok(())
+}
+
+/// Spells out what went wrong, including anything the user can act on.
+fn describe(err: &BlockingError) -> String {
+    if let Some(delay) = err.retry_after() {
+        return format!("Rate limited: retry after {}s", delay.as_secs());
+    }
+    match err {
+        BlockingError::Svr3(Error::RestoreFailed(tries_remaining)) => format!(
+            "Restore failed: wrong password or share set, {tries_remaining} tries remaining"
+        ),
+        BlockingError::Svr3(Error::DataMissing) => {
+            "Data missing: nothing is backed up, or the tries ran out".to_string()
+        }
+        BlockingError::Svr3(err) if err.is_safe_to_retry() => format!("{err} (safe to retry)"),
+        err => err.to_string(),
+    }
+}
+
+fn parse_uid(encoded: &str) -> Result<[u8; 16], String> {
+    let bytes = hex::decode(encoded).map_err(|e| e.to_string())?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("UID is {} bytes long instead of 16", bytes.len()))
+}
+
+fn parse_share_set(encoded: &str) -> Result<OpaqueMaskedShareSet, String> {
+    let bytes = BASE64_STANDARD.decode(encoded).map_err(|e| e.to_string())?;
+    OpaqueMaskedShareSet::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+fn init_logger() {
+    let _ = env_logger::builder().try_init();
+}
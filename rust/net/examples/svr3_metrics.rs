@@ -0,0 +1,100 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+//! An example program serving SVR3 metrics to a Prometheus scraper.
+//!
+//! Every `--interval-secs`, it checks that both staging enclaves can be connected to and attested,
+//! recording each attempt in a [`PrometheusMetrics`], and serves what was recorded at
+//! `http://127.0.0.1:<port>/metrics`. To also record operation latencies, pass the same
+//! `PrometheusMetrics` to `Svr3Env::with_request_logger` for the environment operations run on.
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::Parser;
+use rand_core::{OsRng, RngCore};
+use warp::Filter as _;
+
+use libsignal_net::auth::Auth;
+use libsignal_net::enclave::EnclaveEndpointConnection;
+use libsignal_net::infra::dns::DnsResolver;
+use libsignal_net::infra::TcpSslTransportConnector;
+use libsignal_net::svr3::diagnostics::diagnose;
+use libsignal_net::svr3::prometheus::PrometheusMetrics;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// base64 encoding of the auth secret for SGX
+    #[arg(long)]
+    sgx_secret: String,
+    /// base64 encoding of the auth secret for Nitro
+    #[arg(long)]
+    nitro_secret: String,
+    /// Port to serve the metrics on
+    #[arg(long, default_value_t = 9100)]
+    port: u16,
+    /// Time between connection checks
+    #[arg(long, default_value_t = 60)]
+    interval_secs: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+    let args = Args::parse();
+
+    let sgx_secret = parse_auth_secret(&args.sgx_secret);
+    let nitro_secret = parse_auth_secret(&args.nitro_secret);
+    let metrics = Arc::new(PrometheusMetrics::new());
+
+    let env = libsignal_net::env::STAGING.svr3;
+    let sgx_connection = EnclaveEndpointConnection::new(env.sgx(), Duration::from_secs(10));
+    let nitro_connection = EnclaveEndpointConnection::new(env.nitro(), Duration::from_secs(10));
+    let connector = TcpSslTransportConnector::new(DnsResolver::default());
+
+    let checks = {
+        let metrics = metrics.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(args.interval_secs));
+            loop {
+                interval.tick().await;
+                let uid = {
+                    let mut bytes = [0u8; 16];
+                    OsRng.fill_bytes(&mut bytes[..]);
+                    bytes
+                };
+                let sgx = diagnose(
+                    Auth::from_uid_and_secret(uid, sgx_secret),
+                    &sgx_connection,
+                    connector.clone(),
+                )
+                .await;
+                metrics.record_connect(&sgx);
+                let nitro = diagnose(
+                    Auth::from_uid_and_secret(uid, nitro_secret),
+                    &nitro_connection,
+                    connector.clone(),
+                )
+                .await;
+                metrics.record_connect(&nitro);
+            }
+        }
+    };
+
+    let route = warp::path("metrics").map(move || metrics.gather());
+    println!("Serving metrics at http://127.0.0.1:{}/metrics", args.port);
+    tokio::join!(warp::serve(route).run(([127, 0, 0, 1], args.port)), checks);
+}
+
+fn parse_auth_secret(b64: &str) -> [u8; 32] {
+    BASE64_STANDARD
+        .decode(b64)
+        .expect("valid b64")
+        .try_into()
+        .expect("secret is 32 bytes")
+}
+
+fn init_logger() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
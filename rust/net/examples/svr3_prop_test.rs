@@ -3,29 +3,26 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use std::collections::HashMap;
 use std::time::Duration;
 
 use assert_matches::assert_matches;
-use libsignal_net::infra::dns::DnsResolver;
 use proptest::prelude::*;
 use proptest::test_runner::Config;
 use proptest_state_machine::{prop_state_machine, ReferenceStateMachine, StateMachineTest};
 use rand_core::OsRng;
 
-use libsignal_net::auth::Auth;
-use libsignal_net::enclave::{EnclaveEndpointConnection, Nitro, PpssSetup, Sgx};
-use libsignal_net::env::Svr3Env;
-use libsignal_net::infra::TcpSslTransportConnector;
-use libsignal_net::svr::SvrConnection;
-use libsignal_net::svr3::{Error, OpaqueMaskedShareSet, PpssOps as _};
+use libsignal_net::auth::{parse_auth_secret, Auth};
+use libsignal_net::enclave::{EnclaveEndpoint, PpssSetup};
+use libsignal_net::env::{Svr3Env, SIGNAL_SVR3_SERVER_IDS};
+use libsignal_net::proptest_support::{
+    backup_pair, uid, InMemoryStorage, Secret, Transition, TransitionOutcome, Uid,
+};
+use libsignal_net::svr3::blocking::{BlockingError, BlockingSvr3Client};
+use libsignal_net::svr3::{
+    Error, InMemoryShareSetStore, OpaqueMaskedShareSet, ShareSetStore, StoredShareSet,
+};
 use support::*;
 
-const MAX_TRIES_LIMIT: u32 = 10;
-
-// This will result in ~6 requests per minute for each UID. Good enough to avoid throttling
-const SLEEP_DURATION: Duration = Duration::from_secs(6);
-
 prop_state_machine! {
     #![proptest_config(Config {
         // Turn failure persistence off for demonstration. This means that no
@@ -55,55 +52,6 @@ fn main() {
     run_test()
 }
 
-type Uid = [u8; 16];
-type Secret = [u8; 32];
-
-#[derive(Clone, Debug)]
-struct Svr3Cell {
-    secret: Secret,
-    tries_left: u32,
-}
-
-impl Svr3Cell {
-    pub fn new(secret: Secret, tries_left: u32) -> Self {
-        Self { secret, tries_left }
-    }
-}
-
-#[derive(Clone, Debug)]
-pub enum Transition {
-    SetUid(Uid),
-    Backup(Secret, u32),
-    Restore,
-    RestoreWithBadPassword,
-}
-
-#[derive(Clone, Debug)]
-pub struct InMemoryStorage {
-    uid: Option<Uid>,
-    data: HashMap<Uid, Svr3Cell>,
-    last_transition_outcome: TransitionOutcome,
-}
-
-impl Default for InMemoryStorage {
-    fn default() -> Self {
-        InMemoryStorage {
-            uid: None,
-            data: HashMap::default(),
-            last_transition_outcome: TransitionOutcome::Nothing,
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
-pub enum TransitionOutcome {
-    Nothing,
-    NotFound,
-    Restored(Secret),
-    MaxTriesReached,
-    BadCommitment,
-}
-
 #[derive(Debug)]
 pub struct SUTConfig {
     // Sleep between reconnects to avoid server throttling
@@ -115,24 +63,26 @@ pub struct SUTConfig {
 impl Default for SUTConfig {
     fn default() -> Self {
         Self {
-            sleep: Some(SLEEP_DURATION),
+            sleep: Some(sleep_duration()),
             forget_share_set: false,
         }
     }
 }
 
 pub struct Svr3Storage {
-    runtime: tokio::runtime::Runtime,
-    env: Svr3Env<'static>,
+    client: BlockingSvr3Client,
     current_uid: Option<Uid>,
-    sgx_secret: Secret,
-    nitro_secret: Secret,
-    share_sets: HashMap<Uid, OpaqueMaskedShareSet>,
+    sgx_secret: [u8; 32],
+    nitro_secret: [u8; 32],
+    share_sets: Box<dyn ShareSetStore>,
     config: SUTConfig,
 }
 
-impl ReferenceStateMachine for InMemoryStorage {
-    type State = Self;
+/// The reference state machine, driving the [`InMemoryStorage`] model.
+pub struct Model;
+
+impl ReferenceStateMachine for Model {
+    type State = InMemoryStorage;
     type Transition = Transition;
 
     fn init_state() -> BoxedStrategy<Self::State> {
@@ -140,7 +90,7 @@ impl ReferenceStateMachine for InMemoryStorage {
     }
 
     fn transitions(state: &Self::State) -> BoxedStrategy<Self::Transition> {
-        if state.uid.is_none() {
+        if state.uid().is_none() {
             return uid().prop_map(Transition::SetUid).boxed();
         }
         // The weights (1, 2 and 3) are to represent that we perform backups twice as often as UID
@@ -155,56 +105,14 @@ impl ReferenceStateMachine for InMemoryStorage {
     }
 
     fn apply(mut state: Self::State, transition: &Self::Transition) -> Self::State {
-        match transition {
-            Transition::SetUid(uid) => {
-                log::info!("MODEL: set uid to {}", hex::encode(uid));
-                state.uid = Some(*uid);
-                state.last_transition_outcome = TransitionOutcome::Nothing;
-            }
-            Transition::Backup(secret, tries_left) => {
-                log::info!("MODEL: backup");
-                log::debug!("[{}] with {} tries", hex::encode(secret), tries_left);
-                let _ = state
-                    .data
-                    .insert(state.uid.unwrap(), Svr3Cell::new(*secret, *tries_left));
-                state.last_transition_outcome = TransitionOutcome::Nothing;
-            }
-            Transition::Restore | Transition::RestoreWithBadPassword => {
-                let expect_bad_commitment =
-                    matches!(transition, Transition::RestoreWithBadPassword);
-                log::info!("MODEL: restore -> ");
-                let uid = state.uid.unwrap();
-                let maybe_cell = state.data.get_mut(&uid);
-                match maybe_cell {
-                    None => {
-                        log::info!("\tnot found");
-                        state.last_transition_outcome = TransitionOutcome::NotFound;
-                    }
-                    Some(cell) if cell.tries_left == 0 => {
-                        log::info!("\tno more attempts");
-                        let _ = state.data.remove(&uid);
-                        state.last_transition_outcome = TransitionOutcome::MaxTriesReached;
-                    }
-                    Some(cell) if expect_bad_commitment => {
-                        log::info!("\tbad commitment");
-                        cell.tries_left = cell.tries_left.saturating_sub(1);
-                        state.last_transition_outcome = TransitionOutcome::BadCommitment;
-                    }
-                    Some(cell) => {
-                        log::info!("\tgood restore");
-                        cell.tries_left = cell.tries_left.saturating_sub(1);
-                        state.last_transition_outcome = TransitionOutcome::Restored(cell.secret);
-                    }
-                }
-            }
-        }
+        state.apply(transition);
         state
     }
 }
 
 impl StateMachineTest for Svr3Storage {
     type SystemUnderTest = Self;
-    type Reference = InMemoryStorage;
+    type Reference = Model;
 
     fn init_test(
         _ref_state: &<Self::Reference as ReferenceStateMachine>::State,
@@ -224,29 +132,36 @@ impl StateMachineTest for Svr3Storage {
             }
             Transition::Backup(secret, tries_left) => {
                 log::info!("SUT: backup");
-                log::debug!("[{}] with {} tries", hex::encode(secret), tries_left);
+                log::debug!(
+                    "[{}] with {} tries",
+                    hex::encode(secret.as_bytes()),
+                    tries_left
+                );
                 let uid = state.current_uid.expect("uid must be set");
-                let share_set = state.backup(uid, secret, tries_left);
-                let _ = state.share_sets.insert(uid, share_set);
+                let share_set = state.backup(uid, &secret, tries_left);
+                let max_tries = tries_left.try_into().expect("nonzero");
+                let stored =
+                    StoredShareSet::new(share_set, "password", secret.as_bytes(), max_tries);
+                state.share_sets.set(uid, stored).expect("kept in memory");
             }
             Transition::Restore | Transition::RestoreWithBadPassword => {
                 let expect_bad_commitment =
                     matches!(transition, Transition::RestoreWithBadPassword);
                 log::info!("SUT: restore -> ");
                 let uid = state.current_uid.expect("uid must be set");
-                match state.share_sets.get(&uid) {
+                match state.share_sets.get(&uid).map(|s| s.share_set.clone()) {
                     Some(share_set) => {
                         let password = if expect_bad_commitment {
                             "bad password"
                         } else {
                             "password"
                         };
-                        match state.restore(uid, share_set.clone(), password) {
+                        match state.restore(uid, share_set, password) {
                             Ok(actual_secret) => {
                                 assert_matches!(
-                                ref_state.last_transition_outcome,
+                                ref_state.last_transition_outcome(),
                                 TransitionOutcome::Restored(expected_secret) => {
-                                    assert_eq!(actual_secret, expected_secret)
+                                    assert_eq!(&Secret::from(actual_secret), expected_secret)
                                 });
                                 log::info!("\tgood restore");
                             }
@@ -257,16 +172,19 @@ impl StateMachineTest for Svr3Storage {
                                         // "Forget" the share-set value
                                         // This is what a good client would do.
                                         if state.config.forget_share_set {
-                                            let _ = state.share_sets.remove(&uid);
+                                            let _ = state
+                                                .share_sets
+                                                .remove(&uid)
+                                                .expect("kept in memory");
                                         }
                                         assert_matches!(
-                                            ref_state.last_transition_outcome,
+                                            ref_state.last_transition_outcome(),
                                             TransitionOutcome::MaxTriesReached
                                                 | TransitionOutcome::NotFound,
                                             "Should have exceeded the tries limit"
                                         );
                                     }
-                                    Error::RestoreFailed if expect_bad_commitment => {
+                                    Error::RestoreFailed(_) if expect_bad_commitment => {
                                         log::info!(
                                             "\tbad commitment error (as expected) [{}]",
                                             err
@@ -283,7 +201,7 @@ impl StateMachineTest for Svr3Storage {
                     None => {
                         log::info!("\tnothing to restore");
                         assert_matches!(
-                            ref_state.last_transition_outcome,
+                            ref_state.last_transition_outcome(),
                             TransitionOutcome::NotFound,
                             "Unexpected not-found"
                         );
@@ -291,73 +209,106 @@ impl StateMachineTest for Svr3Storage {
                 }
             }
         }
+        // Unless the client forgets share sets it can no longer restore, it
+        // keeps some for data the model knows to be gone.
+        let known_uids = state.known_uids();
+        if state.config.forget_share_set {
+            assert_eq!(known_uids, ref_state.known_uids());
+        } else {
+            assert!(ref_state
+                .known_uids()
+                .iter()
+                .all(|uid| known_uids.contains(uid)));
+        }
+        assert_eq!(state.num_backups(), known_uids.len());
+        assert!(state.num_backups() >= ref_state.num_backups());
         state
     }
 }
 
+/// Test endpoints for enclave servers listening locally on the ports given in
+/// `SVR3_SGX_PORT` and `SVR3_NITRO_PORT`.
+fn local_svr3_env() -> &'static Svr3Env<'static> {
+    let port = |var: &str| -> u16 {
+        let value = std::env::var(var).unwrap_or_else(|_| panic!("{var} should be set"));
+        value.parse().expect("valid port")
+    };
+    Box::leak(Box::new(Svr3Env::custom(
+        EnclaveEndpoint::test_endpoint(port("SVR3_SGX_PORT")),
+        EnclaveEndpoint::test_endpoint(port("SVR3_NITRO_PORT")),
+        SIGNAL_SVR3_SERVER_IDS,
+    )))
+}
+
 impl Svr3Storage {
     fn new() -> Self {
         let sgx_secret = {
-            let b64 = std::env::var("SVR3_SGX_SECRET").expect("SGX secret should be set");
-            parse_auth_secret(&b64)
+            let encoded = std::env::var("SVR3_SGX_SECRET").expect("SGX secret should be set");
+            parse_auth_secret(&encoded).expect("valid SGX secret")
         };
 
         let nitro_secret = {
-            let b64 = std::env::var("SVR3_NITRO_SECRET").expect("Nitro secret should be set");
-            parse_auth_secret(&b64)
+            let encoded = std::env::var("SVR3_NITRO_SECRET").expect("Nitro secret should be set");
+            parse_auth_secret(&encoded).expect("valid Nitro secret")
+        };
+        // Local test servers with SVR3_LOCAL_SERVER=1, otherwise staging unless
+        // explicitly turned off with SVR3_USE_STAGING=false.
+        let use_local_server = std::env::var("SVR3_LOCAL_SERVER").is_ok_and(|value| value == "1");
+        let env = if use_local_server {
+            local_svr3_env()
+        } else {
+            let use_staging =
+                std::env::var("SVR3_USE_STAGING").map_or(true, |value| value == "true");
+            Svr3Env::from_flags(use_staging)
         };
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(1)
-            .build()
-            .expect("can build runtime");
         Self {
-            runtime,
-            env: libsignal_net::env::STAGING.svr3,
+            client: BlockingSvr3Client::new(env, Duration::from_secs(10)),
             current_uid: None,
             sgx_secret,
             nitro_secret,
-            share_sets: HashMap::default(),
-            config: SUTConfig::default(),
+            // Each case starts from an empty model, so nothing is kept across
+            // cases.
+            share_sets: Box::<InMemoryShareSetStore>::default(),
+            config: SUTConfig {
+                // Local servers don't throttle.
+                sleep: (!use_local_server).then(sleep_duration),
+                ..SUTConfig::default()
+            },
         }
     }
 
-    async fn connect(&self, uid: Uid) -> <Svr3Env as PpssSetup>::Connections {
-        let connector = TcpSslTransportConnector::new(DnsResolver::default());
+    pub fn num_backups(&self) -> usize {
+        self.share_sets.all_uids().len()
+    }
+
+    /// The UIDs that have a share set stored, in sorted order.
+    pub fn known_uids(&self) -> Vec<Uid> {
+        self.share_sets.all_uids()
+    }
+
+    fn connect(&self, uid: Uid) -> <Svr3Env as PpssSetup>::Connections {
         if let Some(duration) = self.config.sleep {
-            tokio::time::sleep(duration).await;
+            std::thread::sleep(duration);
         }
-        let sgx_connection =
-            EnclaveEndpointConnection::new(self.env.sgx(), Duration::from_secs(10));
         let sgx_auth = Auth::from_uid_and_secret(uid, self.sgx_secret);
-        let a = SvrConnection::<Sgx>::connect(sgx_auth, &sgx_connection, connector.clone())
-            .await
-            .expect("can attestedly connect to SGX");
-
-        let nitro_connection =
-            EnclaveEndpointConnection::new(self.env.nitro(), Duration::from_secs(10));
         let nitro_auth = Auth::from_uid_and_secret(uid, self.nitro_secret);
-        let b = SvrConnection::<Nitro>::connect(nitro_auth, &nitro_connection, connector)
-            .await
-            .expect("can attestedly connect to Nitro");
-
-        (a, b)
+        self.client
+            .connect(sgx_auth, nitro_auth)
+            .expect("can attestedly connect to SGX and Nitro")
     }
 
-    fn backup(&mut self, uid: Uid, what: Secret, max_tries: u32) -> OpaqueMaskedShareSet {
-        self.runtime.block_on(async {
-            let mut rng = OsRng;
-            let connections = self.connect(uid).await;
-            Svr3Env::backup(
+    fn backup(&mut self, uid: Uid, what: &Secret, max_tries: u32) -> OpaqueMaskedShareSet {
+        let connections = self.connect(uid);
+        self.client
+            .backup(
                 connections,
                 "password",
-                what,
+                *what.as_bytes(),
                 max_tries.try_into().unwrap(),
-                &mut rng,
+                None,
+                &mut OsRng,
             )
-            .await
             .expect("can backup")
-        })
     }
 
     fn restore(
@@ -366,49 +317,63 @@ impl Svr3Storage {
         share_set: OpaqueMaskedShareSet,
         password: &str,
     ) -> Result<[u8; 32], Error> {
-        self.runtime.block_on(async {
-            let mut rng = OsRng;
-            let connections = self.connect(uid).await;
-            Svr3Env::restore(connections, password, share_set, &mut rng).await
-        })
+        let connections = self.connect(uid);
+        match self
+            .client
+            .restore(connections, password, share_set, false, None, &mut OsRng)
+        {
+            Ok(secret) => Ok(secret),
+            Err(BlockingError::Svr3(err)) => Err(err),
+            Err(err @ BlockingError::InAsyncContext) => panic!("{err}"),
+        }
     }
 }
 
-fn uid() -> impl Strategy<Value = Uid> {
-    prop_oneof![
-        Just([0u8; 16]),
-        Just([1u8; 16]),
-        Just([2u8; 16]),
-        Just([3u8; 16]),
-    ]
-}
+mod support {
+    use std::time::Duration;
 
-fn secret() -> impl Strategy<Value = Secret> {
-    any::<Secret>()
-}
+    use lazy_static::lazy_static;
+    use libsignal_net::proptest_support::max_tries_limit;
 
-fn max_tries() -> impl Strategy<Value = u32> {
-    1..MAX_TRIES_LIMIT
-}
+    // This will result in ~6 requests per minute for each UID. Good enough to avoid throttling
+    const DEFAULT_SLEEP_DURATION: Duration = Duration::from_secs(6);
 
-prop_compose! {
-    fn backup_pair()(s in secret(), t in max_tries()) -> (Secret, u32) {
-        (s, t)
+    lazy_static! {
+        static ref SLEEP_DURATION: Result<Duration, String> =
+            parse_sleep_duration(std::env::var("SVR3_SLEEP_DURATION_MS").ok());
     }
-}
 
-mod support {
-    use base64::prelude::{Engine, BASE64_STANDARD};
-
-    pub fn parse_auth_secret(b64: &str) -> [u8; 32] {
-        BASE64_STANDARD
-            .decode(b64)
-            .expect("valid b64")
-            .try_into()
-            .expect("secret is 32 bytes")
+    fn parse_sleep_duration(value: Option<String>) -> Result<Duration, String> {
+        let Some(value) = value else {
+            return Ok(DEFAULT_SLEEP_DURATION);
+        };
+        match value.parse() {
+            Ok(millis) if millis >= 1 => Ok(Duration::from_millis(millis)),
+            _ => Err(format!(
+                "SVR3_SLEEP_DURATION_MS must be a number of milliseconds of at least 1, got {value:?}"
+            )),
+        }
+    }
+
+    /// How long to sleep before each connection to servers that throttle:
+    /// `SVR3_SLEEP_DURATION_MS` if set, six seconds otherwise.
+    pub fn sleep_duration() -> Duration {
+        *SLEEP_DURATION.as_ref().expect("checked by init_logger")
     }
 
+    /// Also checks the settings taken from the environment, exiting if any is
+    /// invalid, and logs the values in effect.
     pub fn init_logger() {
         let _ = env_logger::builder().try_init();
+
+        let max_tries_limit = max_tries_limit().unwrap_or_else(|e| exit_with(&e));
+        let sleep_duration = SLEEP_DURATION.as_ref().unwrap_or_else(|e| exit_with(e));
+        log::info!("max tries limit: {max_tries_limit} (exclusive)");
+        log::info!("sleep duration: {}ms", sleep_duration.as_millis());
+    }
+
+    fn exit_with(error: &dyn std::fmt::Display) -> ! {
+        eprintln!("invalid configuration: {error}");
+        std::process::exit(1)
     }
 }
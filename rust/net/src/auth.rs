@@ -4,6 +4,7 @@
 //
 use std::time::SystemTime;
 
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 
@@ -65,3 +66,54 @@ impl HttpBasicAuth for Auth {
         &self.password
     }
 }
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ParseSecretError {
+    /// auth secret is neither hex nor base64
+    InvalidEncoding,
+    /// auth secret is {0} bytes long instead of 32
+    WrongLength(usize),
+}
+
+/// Decodes a 32-byte auth secret, as passed to [`Auth::from_uid_and_secret`].
+///
+/// Accepts either hex or base64, telling them apart by length: a 32-byte
+/// value is 64 characters in hex and 44 in padded base64.
+pub fn parse_auth_secret(encoded: &str) -> Result<[u8; 32], ParseSecretError> {
+    let bytes = if encoded.len() == 64 {
+        hex::decode(encoded).map_err(|_| ParseSecretError::InvalidEncoding)?
+    } else {
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|_| ParseSecretError::InvalidEncoding)?
+    };
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| ParseSecretError::WrongLength(bytes.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn auth_secret_in_hex_or_base64() {
+        let secret = [0xab; 32];
+        assert_matches!(parse_auth_secret(&hex::encode(secret)), Ok(s) if s == secret);
+        assert_matches!(parse_auth_secret(&BASE64_STANDARD.encode(secret)), Ok(s) if s == secret);
+    }
+
+    #[test]
+    fn auth_secret_must_be_32_bytes() {
+        assert_matches!(
+            parse_auth_secret(&BASE64_STANDARD.encode([0; 16])),
+            Err(ParseSecretError::WrongLength(16))
+        );
+        assert_matches!(
+            parse_auth_secret(&"x".repeat(64)),
+            Err(ParseSecretError::InvalidEncoding)
+        );
+    }
+}
@@ -5,25 +5,29 @@
 
 use std::default::Default;
 use std::fmt::Display;
-use std::num::{NonZeroU64, ParseIntError};
+use std::num::{NonZeroU64, NonZeroUsize, ParseIntError};
 use std::str::FromStr;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
+use futures_util::Stream;
 use prost::Message as _;
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio_boring::SslStream;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
 use uuid::Uuid;
 
 use libsignal_core::{Aci, Pni};
 
 use crate::auth::HttpBasicAuth;
-use crate::enclave::{Cdsi, EnclaveEndpointConnection};
+use crate::enclave::{Cdsi, EnclaveEndpointConnection, NewHandshake};
 use crate::infra::connection_manager::ConnectionManager;
-use crate::infra::errors::NetError;
-use crate::infra::reconnect::{ServiceConnectorWithDecorator, ServiceInitializer, ServiceState};
+use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
+use crate::infra::reconnect::ServiceConnectorWithDecorator;
 use crate::infra::ws::{
-    AttestedConnection, AttestedConnectionError, NextOrClose, WebSocketClientConnector,
+    AttestedConnection, AttestedConnectionError, FragmentedSendError, NextOrClose,
+    RateLimitExceededResponse, WebSocketClientConnector, CLIENT_DEPRECATED_CLOSE_CODE,
 };
 use crate::infra::{AsyncDuplexStream, TransportConnector};
 use crate::proto::cds2::{ClientRequest, ClientResponse};
@@ -232,6 +236,7 @@ impl LookupResponseEntry {
     }
 }
 
+#[derive(Debug)]
 pub struct CdsiConnection<S>(AttestedConnection<S>);
 
 impl<S> AsMut<AttestedConnection<S>> for CdsiConnection<S> {
@@ -242,6 +247,7 @@ impl<S> AsMut<AttestedConnection<S>> for CdsiConnection<S> {
 
 /// Anything that can go wrong during a CDSI lookup.
 #[derive(Debug, Error, displaydoc::Display)]
+#[ignore_extra_doc_attributes]
 pub enum LookupError {
     /// Network error
     Net(#[from] NetError),
@@ -253,8 +259,56 @@ pub enum LookupError {
     InvalidResponse,
     /// Retry later.
     RateLimited { retry_after_seconds: u32 },
+    /// The request token was rejected by the server.
+    InvalidToken,
+    /// The server encountered an internal error while processing the request.
+    ServerCrashed,
+    /// Sending the request failed partway: {error} (token consumed: {token_consumed})
+    ///
+    /// If `token_consumed` is false, the server can't have processed the
+    /// request, so the rate limit token provided with it is still unspent.
+    UploadInterrupted {
+        error: NetError,
+        token_consumed: bool,
+    },
     /// Failed to parse the response from the server.
     ParseError,
+    /// Operation cancelled by caller
+    Cancelled,
+}
+
+impl LogSafeDisplay for LookupError {}
+
+impl RetryLater for LookupError {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Net(net) | Self::UploadInterrupted { error: net, .. } => net.retry_after(),
+            Self::RateLimited {
+                retry_after_seconds,
+            } => Some(Duration::from_secs((*retry_after_seconds).into())),
+            Self::Protocol
+            | Self::AttestationError(_)
+            | Self::InvalidResponse
+            | Self::InvalidToken
+            | Self::ServerCrashed
+            | Self::ParseError
+            | Self::Cancelled => None,
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        match self {
+            Self::Net(net) | Self::UploadInterrupted { error: net, .. } => net.is_permanent(),
+            Self::Protocol
+            | Self::AttestationError(_)
+            | Self::InvalidResponse
+            | Self::RateLimited { .. }
+            | Self::InvalidToken
+            | Self::ServerCrashed
+            | Self::ParseError
+            | Self::Cancelled => false,
+        }
+    }
 }
 
 /// CDSI-protocol-specific subset of [`LookupError`] cases.
@@ -268,15 +322,70 @@ pub enum CdsiError {
     InvalidResponse,
     /// Retry later.
     RateLimited { retry_after: Duration },
+    /// The request token was rejected by the server.
+    InvalidToken,
+    /// The server encountered an internal error while processing the request.
+    ServerCrashed,
     /// Failed to parse the response from the server.
     ParseError,
+    /// Operation cancelled by caller
+    Cancelled,
+}
+
+impl LogSafeDisplay for CdsiError {}
+
+/// Size of the websocket frames a lookup request is uploaded in.
+///
+/// Matches the largest Noise transport message, so that each frame carries
+/// whole encrypted packets.
+const REQUEST_FRAGMENT_LEN: NonZeroUsize = match NonZeroUsize::new(65535) {
+    Some(len) => len,
+    None => unreachable!(),
+};
+
+/// How long writing a single request frame may take before the upload is
+/// considered stalled.
+const REQUEST_FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Close code sent by the server when the request token is not valid.
+const INVALID_TOKEN_CLOSE_CODE: u16 = 4101;
+
+/// Maps the frame the server closed the connection with to a [`LookupError`].
+///
+/// Used both when the server hangs up instead of answering a request and
+/// when the close arrives in the middle of a streamed response.
+fn err_for_close(close: Option<CloseFrame<'_>>) -> LookupError {
+    let Some(frame) = close else {
+        return LookupError::Protocol;
+    };
+    if let Some(RateLimitExceededResponse {
+        retry_after_seconds,
+    }) = RateLimitExceededResponse::from_close_frame(&frame)
+    {
+        return LookupError::RateLimited {
+            retry_after_seconds,
+        };
+    }
+    match frame.code {
+        CloseCode::Library(INVALID_TOKEN_CLOSE_CODE) => LookupError::InvalidToken,
+        CloseCode::Library(CLIENT_DEPRECATED_CLOSE_CODE) => {
+            LookupError::Net(NetError::ClientDeprecated)
+        }
+        // RFC 6455 reserves 1011 for a server that hit "an unexpected
+        // condition that prevented it from fulfilling the request"; the
+        // enclave sends no more specific code, so treat it as a crash.
+        CloseCode::Error => LookupError::ServerCrashed,
+        _ => LookupError::Protocol,
+    }
 }
 
 impl From<AttestedConnectionError> for LookupError {
     fn from(value: AttestedConnectionError) -> Self {
         match value {
             AttestedConnectionError::ClientConnection(_) => Self::Protocol,
-            AttestedConnectionError::Net(net) => Self::Net(net),
+            AttestedConnectionError::Net(net) | AttestedConnectionError::SendFailed(net) => {
+                Self::Net(net)
+            }
             AttestedConnectionError::Protocol => Self::Protocol,
             AttestedConnectionError::Sgx(e) => Self::AttestationError(e),
         }
@@ -289,16 +398,7 @@ impl From<prost::DecodeError> for LookupError {
     }
 }
 
-#[derive(serde::Deserialize)]
-struct RateLimitExceededResponse {
-    retry_after_seconds: u32,
-}
-
-impl RateLimitExceededResponse {
-    /// Numeric code set by the server on the websocket close frame.
-    const CLOSE_CODE: u16 = 4008;
-}
-
+#[derive(Debug)]
 pub struct ClientResponseCollector<S = SslStream<TcpStream>>(CdsiConnection<S>);
 
 impl<S: AsyncDuplexStream> CdsiConnection<S> {
@@ -320,49 +420,68 @@ impl<S: AsyncDuplexStream> CdsiConnection<S> {
             ),
             auth_decorator,
         );
-        let service_initializer =
-            ServiceInitializer::new(&connector, &endpoint.endpoint_connection.manager);
-        let connection_attempt_result = service_initializer.connect().await;
-        let websocket = match connection_attempt_result {
-            ServiceState::Active(websocket, _) => Ok(websocket),
-            ServiceState::Cooldown(_) => Err(LookupError::Net(NetError::NoServiceConnection)),
-            ServiceState::Error(e) => Err(LookupError::Net(e)),
-            ServiceState::TimedOut => Err(LookupError::Net(NetError::Timeout)),
-        }?;
-        let attested = AttestedConnection::connect(websocket, |attestation_msg| {
-            attest::cds2::new_handshake(
-                endpoint.params.mr_enclave.as_ref(),
-                attestation_msg,
-                SystemTime::now(),
-            )
-        })
+        let connection_attempt_result = endpoint.connect_websocket(&connector).await;
+        let (websocket, _) = connection_attempt_result.ok()?;
+        let mut attested = AttestedConnection::connect_with_timeout(
+            websocket,
+            endpoint.attestation_timeout,
+            |attestation_msg| Cdsi::new_handshake(&endpoint.params, attestation_msg),
+        )
         .await?;
+        if let Some(interval) = endpoint.rekey_interval {
+            attested = attested.with_rekey_interval(interval);
+        }
 
         Ok(Self(attested))
     }
 
     pub async fn send_request(
+        self,
+        request: LookupRequest,
+    ) -> Result<(Token, ClientResponseCollector<S>), LookupError> {
+        self.send_request_with_progress(request, |_, _| {}).await
+    }
+
+    /// Like [`Self::send_request`], but calls `progress` with
+    /// `(bytes_sent, total)` as the request is uploaded.
+    pub async fn send_request_with_progress(
+        self,
+        request: LookupRequest,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<(Token, ClientResponseCollector<S>), LookupError> {
+        self.send_request_in_fragments(request, REQUEST_FRAGMENT_LEN, progress)
+            .await
+    }
+
+    async fn send_request_in_fragments(
         mut self,
         request: LookupRequest,
+        fragment_len: NonZeroUsize,
+        progress: impl FnMut(usize, usize),
     ) -> Result<(Token, ClientResponseCollector<S>), LookupError> {
-        self.0.send(request.into_client_request()).await?;
+        self.0
+            .send_fragmented(
+                request.into_client_request(),
+                fragment_len,
+                REQUEST_FRAGMENT_TIMEOUT,
+                progress,
+            )
+            .await
+            .map_err(
+                |FragmentedSendError {
+                     error,
+                     final_fragment,
+                 }| match error {
+                    AttestedConnectionError::Net(error) => LookupError::UploadInterrupted {
+                        error,
+                        token_consumed: final_fragment,
+                    },
+                    error => error.into(),
+                },
+            )?;
         let token_response: ClientResponse = match self.0.receive().await? {
             NextOrClose::Next(response) => response,
-            NextOrClose::Close(close) => {
-                if let Some(close) = close {
-                    if u16::from(close.code) == RateLimitExceededResponse::CLOSE_CODE {
-                        if let Ok(RateLimitExceededResponse {
-                            retry_after_seconds,
-                        }) = serde_json::from_str(&close.reason)
-                        {
-                            return Err(LookupError::RateLimited {
-                                retry_after_seconds,
-                            });
-                        }
-                    }
-                };
-                return Err(LookupError::Protocol);
-            }
+            NextOrClose::Close(close) => return Err(err_for_close(close)),
         };
 
         if token_response.token.is_empty() {
@@ -376,8 +495,54 @@ impl<S: AsyncDuplexStream> CdsiConnection<S> {
     }
 }
 
+/// Lookup results that are decoded incrementally as they arrive.
+pub struct LookupResponseStream<St> {
+    /// The value reported in the first response frame.
+    ///
+    /// A later frame may still update it; [`ClientResponseCollector::collect`]
+    /// reports the last value the server sent.
+    pub debug_permits_used: i32,
+    pub records: St,
+}
+
 impl<S: AsyncDuplexStream> ClientResponseCollector<S> {
     pub async fn collect(self) -> Result<LookupResponse, LookupError> {
+        let mut state = self.ack_token().await?;
+        let mut records = Vec::new();
+        while let Some(entry) = state.next_record().await? {
+            records.push(entry);
+        }
+        Ok(LookupResponse {
+            records,
+            debug_permits_used: state.debug_permits_used,
+        })
+    }
+
+    /// Acknowledges the token and returns the lookup results as a stream.
+    ///
+    /// Records are decoded from each response frame as it arrives. The next
+    /// frame is only read once every record of the previous one has been
+    /// consumed, so a slow consumer holds back the server instead of causing
+    /// the whole response to be buffered.
+    pub async fn lookup_stream(
+        self,
+    ) -> Result<
+        LookupResponseStream<impl Stream<Item = Result<LookupResponseEntry, LookupError>>>,
+        LookupError,
+    > {
+        let state = self.ack_token().await?;
+        let debug_permits_used = state.debug_permits_used;
+        let records = futures_util::stream::try_unfold(state, |mut state| async move {
+            Ok(state.next_record().await?.map(|entry| (entry, state)))
+        });
+
+        Ok(LookupResponseStream {
+            debug_permits_used,
+            records,
+        })
+    }
+
+    async fn ack_token(self) -> Result<LookupResponseState<S>, LookupError> {
         let Self(mut connection) = self;
 
         let token_ack = ClientRequest {
@@ -386,26 +551,132 @@ impl<S: AsyncDuplexStream> ClientResponseCollector<S> {
         };
 
         connection.0.send(token_ack).await?;
-        let mut response: ClientResponse = connection
-            .0
-            .receive()
-            .await?
-            .next_or(LookupError::Protocol)?;
-        while let NextOrClose::Next(decoded) = connection.0.receive_bytes().await? {
-            response
-                .merge(decoded.as_ref())
-                .map_err(LookupError::from)?;
+        let first_response = match connection.0.receive().await? {
+            NextOrClose::Next(response) => response,
+            NextOrClose::Close(close) => return Err(err_for_close(close)),
+        };
+
+        let mut state = LookupResponseState {
+            connection,
+            decoder: LookupResponseDecoder::default(),
+            debug_permits_used: 0,
+        };
+        state.push(first_response);
+        Ok(state)
+    }
+}
+
+/// The connection a lookup response is read from, along with what has been
+/// decoded from it so far.
+struct LookupResponseState<S> {
+    connection: CdsiConnection<S>,
+    decoder: LookupResponseDecoder,
+    debug_permits_used: i32,
+}
+
+impl<S: AsyncDuplexStream> LookupResponseState<S> {
+    async fn next_record(&mut self) -> Result<Option<LookupResponseEntry>, LookupError> {
+        loop {
+            if let Some(entry) = self.decoder.next_entry() {
+                return Ok(Some(entry));
+            }
+            match self.connection.0.receive_bytes().await? {
+                NextOrClose::Next(frame) => {
+                    self.push(ClientResponse::decode(frame.as_ref())?);
+                }
+                NextOrClose::Close(None)
+                | NextOrClose::Close(Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    ..
+                })) => {
+                    self.decoder.finish()?;
+                    return Ok(None);
+                }
+                NextOrClose::Close(close) => return Err(err_for_close(close)),
+            }
+        }
+    }
+
+    fn push(&mut self, response: ClientResponse) {
+        let ClientResponse {
+            e164_pni_aci_triples,
+            token: _,
+            debug_permits_used,
+        } = response;
+        // Like merging the frames into one message would, keep the last
+        // non-default value.
+        if debug_permits_used != 0 {
+            self.debug_permits_used = debug_permits_used;
+        }
+        self.decoder.push(&e164_pni_aci_triples);
+    }
+}
+
+/// Decodes [`LookupResponseEntry`] records from the triples of successive
+/// response frames.
+///
+/// Only the undecoded part of the latest frame, plus any partial record left
+/// over from the frame before, is kept around.
+#[derive(Default)]
+struct LookupResponseDecoder {
+    buffered: Vec<u8>,
+    offset: usize,
+}
+
+impl LookupResponseDecoder {
+    fn push(&mut self, triples: &[u8]) {
+        let _ = self.buffered.drain(..self.offset);
+        self.offset = 0;
+        self.buffered.extend_from_slice(triples);
+    }
+
+    fn next_entry(&mut self) -> Option<LookupResponseEntry> {
+        while let Some(record) = self
+            .buffered
+            .get(self.offset..self.offset + LookupResponseEntry::SERIALIZED_LEN)
+        {
+            self.offset += LookupResponseEntry::SERIALIZED_LEN;
+            let entry = LookupResponseEntry::try_parse_from(
+                record.try_into().expect("slice size is correct"),
+            );
+            if entry.is_some() {
+                return entry;
+            }
+        }
+        None
+    }
+
+    fn buffered_len(&self) -> usize {
+        self.buffered.len() - self.offset
+    }
+
+    fn finish(&self) -> Result<(), LookupResponseParseError> {
+        match self.buffered_len() {
+            0 => Ok(()),
+            actual_length => Err(LookupResponseParseError::InvalidNumberOfBytes { actual_length }),
         }
-        Ok(response.try_into()?)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use assert_matches::assert_matches;
+    use futures_util::TryStreamExt as _;
     use hex_literal::hex;
+    use prost::Message as _;
+    use tokio::io::DuplexStream;
     use uuid::Uuid;
 
+    use tokio_util::sync::CancellationToken;
+
     use super::*;
+    use crate::auth::Auth;
+    use crate::enclave::EnclaveEndpoint;
+    use crate::infra::ws::testutil::{
+        attested_server_handshake, fake_websocket, run_attested_server, websocket_test_client,
+        AttestedServerOutput, NeverConnects,
+    };
+    use crate::utils::cancellable;
 
     #[test]
     fn parse_lookup_response_entries() {
@@ -487,4 +758,481 @@ mod test {
             )
         );
     }
+
+    const TOKEN: &[u8] = b"new token";
+
+    async fn connect_to_fake_server(
+        on_message: impl FnMut(Vec<u8>) -> Vec<AttestedServerOutput> + Send + 'static,
+    ) -> CdsiConnection<DuplexStream> {
+        let (server, client) = fake_websocket().await;
+        tokio::spawn(run_attested_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+            on_message,
+        ));
+
+        let attested = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        CdsiConnection(attested)
+    }
+
+    fn close_with(code: CloseCode, reason: &'static str) -> AttestedServerOutput {
+        AttestedServerOutput::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }))
+    }
+
+    /// Server that hands out a token and then, once it's acked, sends an empty
+    /// response followed by `close_after_response`.
+    fn token_then_close(
+        close_after_response: AttestedServerOutput,
+    ) -> impl FnMut(Vec<u8>) -> Vec<AttestedServerOutput> + Send + 'static {
+        let mut close_after_response = Some(close_after_response);
+        move |request| {
+            let request = ClientRequest::decode(request.as_ref()).expect("valid request");
+            if !request.token_ack {
+                let response = ClientResponse {
+                    token: TOKEN.to_vec(),
+                    ..Default::default()
+                };
+                return vec![AttestedServerOutput::Message(response.encode_to_vec())];
+            }
+            vec![
+                AttestedServerOutput::Message(ClientResponse::default().encode_to_vec()),
+                close_after_response.take().expect("only acked once"),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn send_request_rate_limited_close() {
+        let connection = connect_to_fake_server(|_| {
+            vec![close_with(
+                CloseCode::Library(RateLimitExceededResponse::CLOSE_CODE),
+                r#"{"retry_after_seconds":42}"#,
+            )]
+        })
+        .await;
+
+        assert_matches!(
+            connection.send_request(LookupRequest::default()).await,
+            Err(LookupError::RateLimited {
+                retry_after_seconds: 42
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn send_request_invalid_token_close() {
+        let connection = connect_to_fake_server(|_| {
+            vec![close_with(CloseCode::Library(INVALID_TOKEN_CLOSE_CODE), "")]
+        })
+        .await;
+
+        assert_matches!(
+            connection.send_request(LookupRequest::default()).await,
+            Err(LookupError::InvalidToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn send_request_client_deprecated_close() {
+        let connection = connect_to_fake_server(|_| {
+            vec![close_with(
+                CloseCode::Library(CLIENT_DEPRECATED_CLOSE_CODE),
+                "",
+            )]
+        })
+        .await;
+
+        assert_matches!(
+            connection.send_request(LookupRequest::default()).await,
+            Err(LookupError::Net(NetError::ClientDeprecated))
+        );
+    }
+
+    #[tokio::test]
+    async fn send_request_server_crashed_close() {
+        let connection =
+            connect_to_fake_server(|_| vec![close_with(CloseCode::Error, "internal error")]).await;
+
+        assert_matches!(
+            connection.send_request(LookupRequest::default()).await,
+            Err(LookupError::ServerCrashed)
+        );
+    }
+
+    #[tokio::test]
+    async fn send_request_unknown_close_is_protocol_error() {
+        let connection =
+            connect_to_fake_server(|_| vec![close_with(CloseCode::Library(4999), "")]).await;
+
+        assert_matches!(
+            connection.send_request(LookupRequest::default()).await,
+            Err(LookupError::Protocol)
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_normal_close_succeeds() {
+        let connection =
+            connect_to_fake_server(token_then_close(close_with(CloseCode::Normal, ""))).await;
+
+        let (token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        assert_eq!(token, Token(TOKEN.into()));
+        assert_matches!(
+            collector.collect().await,
+            Ok(LookupResponse {
+                records,
+                debug_permits_used: 0
+            }) if records.is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_server_crashed_mid_response() {
+        let connection =
+            connect_to_fake_server(token_then_close(close_with(CloseCode::Error, ""))).await;
+
+        let (_token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        assert_matches!(collector.collect().await, Err(LookupError::ServerCrashed));
+    }
+
+    #[tokio::test]
+    async fn collect_rate_limited_mid_response() {
+        let connection = connect_to_fake_server(token_then_close(close_with(
+            CloseCode::Library(RateLimitExceededResponse::CLOSE_CODE),
+            r#"{"retry_after_seconds":7}"#,
+        )))
+        .await;
+
+        let (_token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        let err = collector.collect().await.expect_err("rate limited");
+        assert_matches!(
+            err,
+            LookupError::RateLimited {
+                retry_after_seconds: 7
+            }
+        );
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_while_connecting() {
+        let endpoint = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Cdsi>::test_endpoint(8443),
+            Duration::from_secs(10),
+        );
+        let auth = Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+        let token = CancellationToken::new();
+        tokio::spawn({
+            let token = token.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                token.cancel();
+            }
+        });
+
+        let result = cancellable(&token, LookupError::Cancelled, async {
+            let _connection = CdsiConnection::connect(&endpoint, NeverConnects, auth).await?;
+            Ok(())
+        })
+        .await;
+        assert_matches!(result, Err(LookupError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn cancel_in_flight_lookup_closes_connection() {
+        let token = CancellationToken::new();
+        let (server_done_tx, server_done_rx) = tokio::sync::oneshot::channel::<()>();
+        // The server never sends a response, and cancels the lookup once the
+        // client has acked the token. It stops when the client hangs up,
+        // dropping `server_done_tx`.
+        let connection = connect_to_fake_server({
+            let token = token.clone();
+            move |request| {
+                let _server_done_tx = &server_done_tx;
+                let request = ClientRequest::decode(request.as_ref()).expect("valid request");
+                if !request.token_ack {
+                    let response = ClientResponse {
+                        token: TOKEN.to_vec(),
+                        ..Default::default()
+                    };
+                    return vec![AttestedServerOutput::Message(response.encode_to_vec())];
+                }
+                token.cancel();
+                vec![]
+            }
+        })
+        .await;
+
+        let (_token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        assert_matches!(
+            cancellable(&token, LookupError::Cancelled, collector.collect()).await,
+            Err(LookupError::Cancelled)
+        );
+
+        tokio::time::timeout(Duration::from_secs(1), server_done_rx)
+            .await
+            .expect("server sees the connection close")
+            .expect_err("server never sends completion");
+    }
+
+    fn numbered_entries(count: usize) -> (Vec<LookupResponseEntry>, Vec<u8>) {
+        let entries: Vec<_> = (0..count)
+            .map(|i| LookupResponseEntry {
+                e164: E164(NonZeroU64::new(18005550000 + i as u64).unwrap()),
+                pni: Some(Pni::from(Uuid::from_u128(i as u128 + 1))),
+                aci: (i % 2 == 0).then(|| Aci::from(Uuid::from_u128(i as u128 + 0x1000))),
+            })
+            .collect();
+        let mut bytes = vec![0; entries.len() * LookupResponseEntry::SERIALIZED_LEN];
+        for (entry, chunk) in entries
+            .iter()
+            .zip(bytes.chunks_mut(LookupResponseEntry::SERIALIZED_LEN))
+        {
+            let (e164, uuids) = chunk.split_at_mut(E164::SERIALIZED_LEN);
+            entry.e164.serialize_into(e164);
+            let (pni, aci) = uuids.split_at_mut(LookupResponseEntry::UUID_LEN);
+            pni.copy_from_slice(Uuid::from(entry.pni.unwrap()).as_bytes());
+            if let Some(entry_aci) = entry.aci {
+                aci.copy_from_slice(Uuid::from(entry_aci).as_bytes());
+            }
+        }
+        (entries, bytes)
+    }
+
+    #[test]
+    fn decoder_buffers_at_most_one_frame() {
+        const FRAME_LEN: usize = 100;
+        let (expected, bytes) = numbered_entries(50);
+
+        let mut decoder = LookupResponseDecoder::default();
+        let mut decoded = vec![];
+        let mut peak_buffered = 0;
+        for frame in bytes.chunks(FRAME_LEN) {
+            decoder.push(frame);
+            peak_buffered = peak_buffered.max(decoder.buffered_len());
+            decoded.extend(std::iter::from_fn(|| decoder.next_entry()));
+        }
+        assert_eq!(decoder.finish(), Ok(()));
+        assert_eq!(decoded, expected);
+        assert!(
+            peak_buffered < FRAME_LEN + LookupResponseEntry::SERIALIZED_LEN,
+            "buffered {peak_buffered} bytes"
+        );
+    }
+
+    #[test]
+    fn decoder_rejects_trailing_partial_record() {
+        let (_, bytes) = numbered_entries(2);
+        let mut decoder = LookupResponseDecoder::default();
+        decoder.push(&bytes[..bytes.len() - 1]);
+        assert!(decoder.next_entry().is_some());
+        assert_eq!(decoder.next_entry(), None);
+        assert_eq!(
+            decoder.finish(),
+            Err(LookupResponseParseError::InvalidNumberOfBytes {
+                actual_length: LookupResponseEntry::SERIALIZED_LEN - 1
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_stream_decodes_response_split_across_frames() {
+        const FRAME_LEN: usize = 64;
+        let (expected, bytes) = numbered_entries(40);
+
+        let connection = connect_to_fake_server(move |request| {
+            let request = ClientRequest::decode(request.as_ref()).expect("valid request");
+            if !request.token_ack {
+                let response = ClientResponse {
+                    token: TOKEN.to_vec(),
+                    ..Default::default()
+                };
+                return vec![AttestedServerOutput::Message(response.encode_to_vec())];
+            }
+            let first = ClientResponse {
+                debug_permits_used: 40,
+                ..Default::default()
+            };
+            std::iter::once(first)
+                .chain(bytes.chunks(FRAME_LEN).map(|chunk| ClientResponse {
+                    e164_pni_aci_triples: chunk.to_vec(),
+                    ..Default::default()
+                }))
+                .map(|response| AttestedServerOutput::Message(response.encode_to_vec()))
+                .chain([close_with(CloseCode::Normal, "")])
+                .collect()
+        })
+        .await;
+
+        let (_token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        let LookupResponseStream {
+            debug_permits_used,
+            records,
+        } = collector.lookup_stream().await.expect("token acked");
+        assert_eq!(debug_permits_used, 40);
+
+        let records: Vec<_> = records.try_collect().await.expect("all frames decode");
+        assert_eq!(records, expected);
+    }
+
+    #[tokio::test]
+    async fn collect_keeps_last_debug_permits_used() {
+        let (expected, bytes) = numbered_entries(2);
+
+        let connection = connect_to_fake_server(move |request| {
+            let request = ClientRequest::decode(request.as_ref()).expect("valid request");
+            if !request.token_ack {
+                let response = ClientResponse {
+                    token: TOKEN.to_vec(),
+                    ..Default::default()
+                };
+                return vec![AttestedServerOutput::Message(response.encode_to_vec())];
+            }
+            let (first, second) = bytes.split_at(LookupResponseEntry::SERIALIZED_LEN);
+            [
+                ClientResponse {
+                    e164_pni_aci_triples: first.to_vec(),
+                    debug_permits_used: 1,
+                    ..Default::default()
+                },
+                ClientResponse {
+                    debug_permits_used: 2,
+                    ..Default::default()
+                },
+                ClientResponse {
+                    e164_pni_aci_triples: second.to_vec(),
+                    ..Default::default()
+                },
+            ]
+            .into_iter()
+            .map(|response| AttestedServerOutput::Message(response.encode_to_vec()))
+            .chain([close_with(CloseCode::Normal, "")])
+            .collect()
+        })
+        .await;
+
+        let (_token, collector) = connection
+            .send_request(LookupRequest::default())
+            .await
+            .expect("token received");
+        let response = collector.collect().await.expect("all frames decode");
+        assert_eq!(
+            response,
+            LookupResponse {
+                records: expected,
+                debug_permits_used: 2,
+            }
+        );
+    }
+
+    fn request_with_new_e164s(count: u64) -> LookupRequest {
+        LookupRequest {
+            new_e164s: (1..=count)
+                .map(|n| E164::new(NonZeroU64::new(18005550000 + n).unwrap()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn send_request_reports_upload_progress() {
+        const FRAGMENT_LEN: usize = 256;
+        const NUM_E164S: u64 = 100;
+
+        let connection = connect_to_fake_server(|request| {
+            let request = ClientRequest::decode(request.as_ref()).expect("reassembled request");
+            assert_eq!(
+                request.new_e164s.len(),
+                NUM_E164S as usize * E164::SERIALIZED_LEN
+            );
+            let response = ClientResponse {
+                token: TOKEN.to_vec(),
+                ..Default::default()
+            };
+            vec![AttestedServerOutput::Message(response.encode_to_vec())]
+        })
+        .await;
+
+        let mut progress = vec![];
+        let (token, _collector) = connection
+            .send_request_in_fragments(
+                request_with_new_e164s(NUM_E164S),
+                NonZeroUsize::new(FRAGMENT_LEN).unwrap(),
+                |sent, total| progress.push((sent, total)),
+            )
+            .await
+            .expect("token received");
+        assert_eq!(token, Token(TOKEN.into()));
+
+        let &(last_sent, total) = progress.last().expect("progress was reported");
+        assert_eq!(last_sent, total);
+        assert_eq!(progress.len(), total.div_ceil(FRAGMENT_LEN));
+        assert!(progress.iter().all(|&(_, t)| t == total));
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_request_stalled_upload_keeps_token() {
+        const FRAGMENT_LEN: usize = 512;
+
+        let (mut server, client) = fake_websocket().await;
+        tokio::spawn(async move {
+            let _transport = attested_server_handshake(
+                &mut server,
+                attest::sgx_session::testutil::private_key(),
+            )
+            .await;
+            // Stop reading without closing the connection.
+            std::future::pending::<()>().await;
+        });
+        let connection = CdsiConnection(
+            AttestedConnection::connect(websocket_test_client(client), |_| {
+                attest::sgx_session::testutil::handshake_from_tests_data()
+            })
+            .await
+            .expect("handshake succeeds"),
+        );
+
+        let mut progress = vec![];
+        let result = connection
+            .send_request_in_fragments(
+                request_with_new_e164s(500),
+                NonZeroUsize::new(FRAGMENT_LEN).unwrap(),
+                |sent, _total| progress.push(sent),
+            )
+            .await;
+        assert_matches!(
+            result,
+            Err(LookupError::UploadInterrupted {
+                error: NetError::Timeout,
+                token_consumed: false,
+            })
+        );
+        assert_eq!(progress, [FRAGMENT_LEN]);
+    }
 }
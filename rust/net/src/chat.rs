@@ -426,7 +426,7 @@ pub(crate) mod test {
         use crate::chat::{ChatService, Request, Response};
         use crate::infra::certs::RootCertificates;
         use crate::infra::connection_manager::SingleRouteThrottlingConnectionManager;
-        use crate::infra::errors::{LogSafeDisplay, NetError};
+        use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
         use crate::infra::reconnect::{ServiceConnector, ServiceState};
         use crate::infra::test::shared::{NoReconnectService, TIMEOUT_DURATION};
         use crate::infra::ConnectionParams;
@@ -437,7 +437,7 @@ pub(crate) mod test {
             C: ServiceConnector + Send + Sync + 'static,
             C::Service: ChatService + Clone + Send + Sync + 'static,
             C::Channel: Send + Sync,
-            C::Error: Send + Sync + Debug + LogSafeDisplay,
+            C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
         {
             async fn send(&self, msg: Request, timeout: Duration) -> Result<Response, NetError> {
                 match &*self.inner {
@@ -13,7 +13,7 @@ use crate::chat::{
     ChatService, ChatServiceWithDebugInfo, DebugInfo, IpType, RemoteAddressInfo, Request, Response,
 };
 use crate::infra::connection_manager::ConnectionManager;
-use crate::infra::errors::{LogSafeDisplay, NetError};
+use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
 use crate::infra::reconnect::{ServiceConnector, ServiceWithReconnect};
 
 #[async_trait]
@@ -23,7 +23,7 @@ where
     C: ServiceConnector + Send + Sync + 'static,
     C::Service: ChatService + Clone + Sync + Send + 'static,
     C::Channel: Send + Sync,
-    C::Error: Send + Sync + Debug + LogSafeDisplay,
+    C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
 {
     async fn send(&self, msg: Request, timeout: Duration) -> Result<Response, NetError> {
         let service = self.service_clone().await;
@@ -45,7 +45,7 @@ where
     C: ServiceConnector + Send + Sync + 'static,
     C::Service: ChatService + RemoteAddressInfo + Clone + Sync + Send + 'static,
     C::Channel: Send + Sync,
-    C::Error: Send + Sync + Debug + LogSafeDisplay,
+    C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
 {
     async fn send_and_debug(
         &self,
@@ -158,6 +158,10 @@ impl<T: TransportConnector> ServiceConnector for ChatOverWebSocketServiceConnect
             service_status,
         )
     }
+
+    fn reconnects_on_request(&self) -> bool {
+        self.ws_client_connector.reconnects_on_request()
+    }
 }
 
 async fn reader_task<S: AsyncDuplexStream + 'static>(
@@ -346,6 +350,7 @@ mod test {
     use futures_util::{SinkExt, StreamExt};
     use http::uri::PathAndQuery;
     use http::{Method, StatusCode};
+    use nonzero_ext::nonzero;
     use prost::Message;
     use tokio::io::DuplexStream;
     use tokio::sync::mpsc::Receiver;
@@ -373,6 +378,8 @@ mod test {
             max_connection_time: Duration::from_secs(1),
             keep_alive_interval: Duration::from_secs(5),
             max_idle_time: Duration::from_secs(15),
+            max_queued_send_bytes: nonzero!(1_048_576usize),
+            send_stall_timeout: Duration::from_secs(30),
         }
     }
 
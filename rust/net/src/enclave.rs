@@ -3,61 +3,234 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::time::{Duration, SystemTime};
+#[cfg(any(test, feature = "test-support"))]
+use std::net::Ipv4Addr;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use attest::nitro::NitroChainCache;
 use attest::svr2::RaftConfig;
 use attest::{cds2, enclave, nitro};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use derive_where::derive_where;
 use http::uri::PathAndQuery;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::sync::watch;
 
-use crate::env::{DomainConfig, Svr3Env};
+use crate::env::{
+    ws_path, DomainConfig, PathPrefix, Svr3Env, ValidationError, ENCLAVE_IDLE_TIMEOUT,
+};
+#[cfg(any(test, feature = "test-support"))]
+use crate::infra::certs::RootCertificates;
+use crate::infra::clock::{Clock, SystemClock};
 use crate::infra::connection_manager::{
-    MultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
+    ConnectionManager, MultiRouteConnectionManager, RouteSelectionPolicy,
+    SingleRouteThrottlingConnectionManager,
 };
-use crate::infra::ws::AttestedConnection;
-use crate::infra::{make_ws_config, ConnectionParams, EndpointConnection};
+use crate::infra::errors::{LogSafeDisplay, RetryLater};
+use crate::infra::network_state::{ChangeHookGuard, NetworkState};
+use crate::infra::reconnect::{ServiceConnector, ServiceInitializer, ServiceState};
+#[cfg(test)]
+use crate::infra::ws::testutil::FakeAttestedConnection;
+use crate::infra::ws::{AttestedConnection, AttestedConnectionLike};
+use crate::infra::{make_ws_config, ConnectTimeouts, ConnectionParams, EndpointConnection};
 use crate::svr::SvrConnection;
+use crate::svr3::{NoopRequestLogger, RequestLogger};
+use crate::utils::intern;
 
 pub trait EnclaveKind {
     fn url_path(enclave: &[u8]) -> PathAndQuery;
+    /// Parses a measurement as written in configuration files.
+    fn parse_mr_enclave(s: &str) -> Result<Vec<u8>, ParseError>;
+    /// Writes a measurement the way [`Self::parse_mr_enclave`] reads it.
+    fn format_mr_enclave(enclave: &[u8]) -> String {
+        hex::encode(enclave)
+    }
+    /// Measurement used by [`EnclaveEndpoint::test_endpoint`]; all zeros.
+    #[cfg(any(test, feature = "test-support"))]
+    const TEST_MR_ENCLAVE: &'static [u8] = &[0; 32];
+}
+pub trait Svr3Flavor: EnclaveKind {
+    /// Short name distinguishing the flavors at runtime, e.g. `"sgx"`.
+    ///
+    /// The names end up in logs and diagnostics, so they must not change.
+    fn flavor_name() -> &'static str;
+}
+
+/// Declares an SVR3 enclave flavor, implementing [`EnclaveKind`],
+/// [`Svr3Flavor`], and [`NewHandshake`] for it.
+///
+/// - `url_path` builds the websocket path from the measurement bytes.
+/// - `parse_mr_enclave` reads measurements from configuration files;
+///   `format_mr_enclave` writes them back, and defaults to hex.
+/// - `handshake` checks the attestation against the [`EndpointParams`], like
+///   [`svr2_handshake`]. The raft group id is checked afterwards, as for
+///   every flavor.
+/// - `test_mr_enclave` overrides [`EnclaveKind::TEST_MR_ENCLAVE`].
+macro_rules! impl_svr3_flavor {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $flavor:ident {
+            name: $name:literal,
+            url_path: |$enclave:ident| $url_path:expr,
+            parse_mr_enclave: $parse:path,
+            $(format_mr_enclave: $format:path,)?
+            handshake: $handshake:path,
+            $(test_mr_enclave: $test_mr_enclave:expr,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $flavor {}
+
+        impl $crate::enclave::EnclaveKind for $flavor {
+            $(
+                #[cfg(any(test, feature = "test-support"))]
+                const TEST_MR_ENCLAVE: &'static [u8] = $test_mr_enclave;
+            )?
+
+            fn url_path($enclave: &[u8]) -> ::http::uri::PathAndQuery {
+                ::http::uri::PathAndQuery::try_from($url_path).unwrap()
+            }
+
+            fn parse_mr_enclave(s: &str) -> Result<Vec<u8>, $crate::enclave::ParseError> {
+                $parse(s)
+            }
+
+            $(
+                fn format_mr_enclave(enclave: &[u8]) -> String {
+                    $format(enclave)
+                }
+            )?
+        }
+
+        impl $crate::enclave::Svr3Flavor for $flavor {
+            fn flavor_name() -> &'static str {
+                $name
+            }
+        }
+
+        impl $crate::enclave::NewHandshake for $flavor {
+            fn new_handshake(
+                params: &$crate::enclave::EndpointParams<Self>,
+                attestation_message: &[u8],
+            ) -> ::attest::enclave::Result<::attest::enclave::Handshake> {
+                let handshake = $handshake(params, attestation_message)?;
+                params.check_group_id(handshake)
+            }
+        }
+    };
 }
-pub trait Svr3Flavor: EnclaveKind {}
 
 pub enum Cdsi {}
 
-pub enum Sgx {}
+impl_svr3_flavor! {
+    pub enum Sgx {
+        name: "sgx",
+        url_path: |enclave| format!("/v1/{}", hex::encode(enclave)),
+        parse_mr_enclave: parse_sgx_mr_enclave,
+        handshake: svr2_handshake,
+    }
+}
 
-pub enum Nitro {}
+impl_svr3_flavor! {
+    pub enum Nitro {
+        name: "nitro",
+        url_path: |enclave| format!("/v1/{}", std::str::from_utf8(enclave).expect("valid utf8")),
+        parse_mr_enclave: parse_nitro_mr_enclave,
+        format_mr_enclave: format_nitro_mr_enclave,
+        handshake: nitro_handshake,
+        // Nitro measurements end up in the URL path, so the zeros are textual.
+        test_mr_enclave: b"00000000.00000000.00000000",
+    }
+}
 
 impl EnclaveKind for Cdsi {
     fn url_path(enclave: &[u8]) -> PathAndQuery {
         PathAndQuery::try_from(format!("/v1/{}/discovery", hex::encode(enclave))).unwrap()
     }
+
+    fn parse_mr_enclave(s: &str) -> Result<Vec<u8>, ParseError> {
+        parse_sgx_mr_enclave(s)
+    }
 }
 
-impl EnclaveKind for Sgx {
-    fn url_path(enclave: &[u8]) -> PathAndQuery {
-        PathAndQuery::try_from(format!("/v1/{}", hex::encode(enclave))).unwrap()
+/// Checks an SVR2-style SGX attestation.
+fn svr2_handshake<E: EnclaveKind>(
+    params: &EndpointParams<E>,
+    attestation_message: &[u8],
+) -> enclave::Result<enclave::Handshake> {
+    attest::svr2::new_handshake_with_override(
+        params.mr_enclave.as_ref(),
+        attestation_message,
+        params.clock.now(),
+        params.raft_config_override,
+    )
+}
+
+/// Checks a Nitro attestation, reusing chain validations from the
+/// [`NitroChainCache`] if one is set.
+fn nitro_handshake(
+    params: &EndpointParams<Nitro>,
+    attestation_message: &[u8],
+) -> enclave::Result<enclave::Handshake> {
+    let mr_enclave = params.mr_enclave.as_ref();
+    let now = params.clock.now();
+    match &params.nitro_chain_cache {
+        Some(cache) => nitro::new_handshake_with_chain_cache(
+            mr_enclave,
+            attestation_message,
+            now,
+            params.raft_config_override,
+            cache,
+        ),
+        None => nitro::new_handshake(
+            mr_enclave,
+            attestation_message,
+            now,
+            params.raft_config_override,
+        ),
     }
 }
 
-impl EnclaveKind for Nitro {
-    fn url_path(enclave: &[u8]) -> PathAndQuery {
-        PathAndQuery::try_from(format!(
-            "/v1/{}",
-            std::str::from_utf8(enclave).expect("valid utf8")
-        ))
-        .unwrap()
+/// SGX measurements are the hex encoding of a SHA-256 hash.
+fn parse_sgx_mr_enclave(s: &str) -> Result<Vec<u8>, ParseError> {
+    const SGX_MR_ENCLAVE_LEN: usize = 32;
+    let bytes = hex::decode(s).map_err(|_| ParseError::InvalidHex)?;
+    if bytes.len() != SGX_MR_ENCLAVE_LEN {
+        return Err(ParseError::InvalidLength {
+            expected: SGX_MR_ENCLAVE_LEN,
+            actual: bytes.len(),
+        });
     }
+    Ok(bytes)
 }
 
-impl Svr3Flavor for Sgx {}
+/// Nitro measurements are versions of the form `xxxxxxxx.xxxxxxxx.xxxxxxxx`,
+/// each part being eight hex digits, and are used as-is.
+fn parse_nitro_mr_enclave(s: &str) -> Result<Vec<u8>, ParseError> {
+    let parts = s.split('.').collect::<Vec<_>>();
+    let is_valid = parts.len() == 3
+        && parts
+            .iter()
+            .all(|part| part.len() == 8 && part.bytes().all(|b| b.is_ascii_hexdigit()));
+    if !is_valid {
+        return Err(ParseError::InvalidFormat);
+    }
+    Ok(s.as_bytes().to_vec())
+}
 
-impl Svr3Flavor for Nitro {}
+fn format_nitro_mr_enclave(enclave: &[u8]) -> String {
+    String::from_utf8_lossy(enclave).into_owned()
+}
 
 pub trait IntoConnections {
-    type Connections: ArrayIsh<AttestedConnection> + Send;
+    type Connection: AttestedConnectionLike;
+    type Connections: ArrayIsh<Self::Connection> + Send;
     fn into_connections(self) -> Self::Connections;
 }
 
@@ -65,6 +238,7 @@ impl<A> IntoConnections for A
 where
     A: Into<AttestedConnection>,
 {
+    type Connection = AttestedConnection;
     type Connections = [AttestedConnection; 1];
     fn into_connections(self) -> Self::Connections {
         [self.into()]
@@ -76,6 +250,7 @@ where
     A: Into<AttestedConnection>,
     B: Into<AttestedConnection>,
 {
+    type Connection = AttestedConnection;
     type Connections = [AttestedConnection; 2];
     fn into_connections(self) -> Self::Connections {
         [self.0.into(), self.1.into()]
@@ -88,12 +263,23 @@ where
     B: Into<AttestedConnection>,
     C: Into<AttestedConnection>,
 {
+    type Connection = AttestedConnection;
     type Connections = [AttestedConnection; 3];
     fn into_connections(self) -> Self::Connections {
         [self.0.into(), self.1.into(), self.2.into()]
     }
 }
 
+/// Lets a [`PpssSetup`] for tests use scripted connections.
+#[cfg(test)]
+impl<const N: usize> IntoConnections for [FakeAttestedConnection; N] {
+    type Connection = FakeAttestedConnection;
+    type Connections = Self;
+    fn into_connections(self) -> Self::Connections {
+        self
+    }
+}
+
 pub trait ArrayIsh<T>: AsRef<[T]> + AsMut<[T]> {
     const N: usize;
 }
@@ -106,15 +292,80 @@ pub trait PpssSetup {
     type Connections: IntoConnections + Send;
     type ServerIds: ArrayIsh<u64> + Send;
     const N: usize = Self::ServerIds::N;
-    fn server_ids() -> Self::ServerIds;
+    fn server_ids(&self) -> Self::ServerIds;
+    /// Measurements of the enclaves the connections are made to.
+    ///
+    /// Share sets record these at backup time, so that a restore can tell
+    /// whether it is talking to the same enclaves.
+    fn mr_enclaves(&self) -> Vec<&[u8]>;
+
+    /// Where [`PpssOps`](crate::svr3::PpssOps) reports the operations it runs.
+    fn request_logger(&self) -> &dyn RequestLogger {
+        &NoopRequestLogger
+    }
+
+    /// The most restore tries the enclaves let a backup allow.
+    fn max_allowed_tries(&self) -> u32 {
+        libsignal_svr3::MAX_ALLOWED_TRIES
+    }
+
+    /// Checks `max_tries` against [`Self::max_allowed_tries`], so that a
+    /// backup bound to be rejected can fail without connecting.
+    ///
+    /// A backup allowing no tries could never be restored, but `max_tries`
+    /// being a [`NonZeroU32`] already rules that out.
+    fn validate_backup_params(&self, max_tries: NonZeroU32) -> Result<(), ValidationError> {
+        let max = self.max_allowed_tries();
+        if max_tries.get() <= max {
+            Ok(())
+        } else {
+            Err(ValidationError {
+                max_tries: max_tries.get(),
+                min: 1,
+                max,
+            })
+        }
+    }
 }
 
 impl PpssSetup for Svr3Env<'_> {
     type Connections = (SvrConnection<Sgx>, SvrConnection<Nitro>);
     type ServerIds = [u64; 2];
 
-    fn server_ids() -> Self::ServerIds {
-        [1, 2]
+    fn server_ids(&self) -> Self::ServerIds {
+        Svr3Env::server_ids(self)
+    }
+
+    fn mr_enclaves(&self) -> Vec<&[u8]> {
+        vec![self.sgx().mr_enclave.inner, self.nitro().mr_enclave.inner]
+    }
+
+    fn request_logger(&self) -> &dyn RequestLogger {
+        Svr3Env::request_logger(self)
+    }
+}
+
+/// Lets a setup shared between tasks be used for
+/// [`PpssOps`](crate::svr3::PpssOps) directly.
+impl<P: PpssSetup> PpssSetup for Arc<P> {
+    type Connections = P::Connections;
+    type ServerIds = P::ServerIds;
+    const N: usize = P::N;
+
+    fn server_ids(&self) -> Self::ServerIds {
+        P::server_ids(self)
+    }
+
+    fn mr_enclaves(&self) -> Vec<&[u8]> {
+        P::mr_enclaves(self)
+    }
+
+    fn request_logger(&self) -> &dyn RequestLogger {
+        P::request_logger(self)
+    }
+
+    fn max_allowed_tries(&self) -> u32 {
+        P::max_allowed_tries(self)
     }
 }
 
@@ -133,6 +384,46 @@ impl<Bytes, E: EnclaveKind> MrEnclave<Bytes, E> {
     }
 }
 
+impl<Bytes: AsRef<[u8]>, E> MrEnclave<Bytes, E> {
+    /// Lowercase hex encoding of the measurement.
+    pub fn as_hex(&self) -> String {
+        hex::encode(self.inner.as_ref())
+    }
+
+    /// Padded base64 encoding of the measurement, using the standard alphabet.
+    pub fn as_base64(&self) -> String {
+        BASE64_STANDARD.encode(self.inner.as_ref())
+    }
+}
+
+impl<E: EnclaveKind> MrEnclave<Vec<u8>, E> {
+    pub fn from_hex(s: &str) -> Result<Self, ParseError> {
+        hex::decode(s)
+            .map(Self::new)
+            .map_err(|_| ParseError::InvalidHex)
+    }
+
+    pub fn from_base64(s: &str) -> Result<Self, ParseError> {
+        BASE64_STANDARD
+            .decode(s)
+            .map(Self::new)
+            .map_err(|_| ParseError::InvalidBase64)
+    }
+}
+
+/// Failure to decode a textual [`MrEnclave`] representation.
+#[derive(Debug, Eq, PartialEq, displaydoc::Display, thiserror::Error)]
+pub enum ParseError {
+    /// invalid hex encoding
+    InvalidHex,
+    /// invalid base64 encoding
+    InvalidBase64,
+    /// expected {expected} bytes, got {actual}
+    InvalidLength { expected: usize, actual: usize },
+    /// not a valid measurement for this enclave kind
+    InvalidFormat,
+}
+
 impl<Bytes: AsRef<[u8]>, S> AsRef<[u8]> for MrEnclave<Bytes, S> {
     fn as_ref(&self) -> &[u8] {
         self.inner.as_ref()
@@ -145,6 +436,125 @@ pub struct EnclaveEndpoint<'a, E: EnclaveKind> {
     pub mr_enclave: MrEnclave<&'a [u8], E>,
 }
 
+/// Loads an endpoint from configuration, checking the measurement with
+/// [`EnclaveKind::parse_mr_enclave`]. Like [`DomainConfig`], the loaded data is
+/// interned.
+impl<'de, E: EnclaveKind> Deserialize<'de> for EnclaveEndpoint<'static, E> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Repr {
+            domain_config: DomainConfig,
+            mr_enclave: String,
+        }
+        let Repr {
+            domain_config,
+            mr_enclave,
+        } = Repr::deserialize(deserializer)?;
+        let mr_enclave = E::parse_mr_enclave(&mr_enclave).map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            domain_config,
+            mr_enclave: MrEnclave::new(intern(mr_enclave.into_boxed_slice())),
+        })
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl<E: EnclaveKind> EnclaveEndpoint<'static, E> {
+    /// An endpoint for a test server listening on `127.0.0.1:port`.
+    ///
+    /// The server's certificate is not verified, and the measurement is
+    /// [`EnclaveKind::TEST_MR_ENCLAVE`].
+    pub const fn test_endpoint(port: u16) -> Self {
+        Self {
+            domain_config: DomainConfig {
+                hostname: "127.0.0.1",
+                port,
+                proxy_path: "/",
+                path_prefix: None,
+                route_weights: None,
+                ip_v4: &[Ipv4Addr::LOCALHOST],
+                ip_v6: &[],
+                cert: &RootCertificates::INSECURE_SKIP_VERIFICATION,
+            },
+            mr_enclave: MrEnclave::new(E::TEST_MR_ENCLAVE),
+        }
+    }
+}
+
+impl<E: EnclaveKind> EnclaveEndpoint<'_, E> {
+    /// Describes where the endpoint is, how it is reached, and which enclave
+    /// it expects there.
+    pub fn fingerprint(&self) -> EnclaveFingerprint {
+        let DomainConfig {
+            hostname,
+            port,
+            path_prefix,
+            route_weights,
+            ..
+        } = self.domain_config;
+        let mr_enclave = E::format_mr_enclave(self.mr_enclave.as_ref());
+        let mut hasher = Sha256::new()
+            .chain_update(hostname)
+            .chain_update([0u8])
+            .chain_update(port.to_be_bytes())
+            .chain_update(&mr_enclave);
+        // Only hashed when set, so that the hashes of configurations without
+        // them stay the same. The tags keep the two apart.
+        if let Some(prefix) = path_prefix {
+            hasher = hasher
+                .chain_update([0u8, b'p'])
+                .chain_update(prefix.as_str())
+                .chain_update([0u8]);
+        }
+        if let Some(weights) = route_weights {
+            hasher = weights.iter().fold(
+                hasher
+                    .chain_update([0u8, b'w'])
+                    .chain_update((weights.len() as u64).to_be_bytes()),
+                |hasher, weight| hasher.chain_update(weight.to_be_bytes()),
+            );
+        }
+        EnclaveFingerprint {
+            hostname,
+            port,
+            path_prefix,
+            route_weights,
+            mr_enclave,
+            hash: short_hash(hasher),
+        }
+    }
+}
+
+/// Which enclave a client is configured to use, and where, for support to
+/// confirm from the client's logs.
+///
+/// Fingerprints can only be made from configuration, never from anything the
+/// user provides, so they are safe to log.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnclaveFingerprint {
+    hostname: &'static str,
+    port: u16,
+    path_prefix: Option<PathPrefix>,
+    route_weights: Option<&'static [u32]>,
+    /// As written in configuration files: hex for SGX, PCR versions for Nitro.
+    mr_enclave: String,
+    hash: String,
+}
+
+impl EnclaveFingerprint {
+    /// Short hash of the rest of the fingerprint, which only changes when the
+    /// configuration does.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// Lowercase hex of the first eight bytes of the digest.
+pub(crate) fn short_hash(hasher: Sha256) -> String {
+    hex::encode(&hasher.finalize()[..8])
+}
+
 pub trait NewHandshake {
     fn new_handshake(
         params: &EndpointParams<Self>,
@@ -154,9 +564,16 @@ pub trait NewHandshake {
         Self: EnclaveKind + Sized;
 }
 
+#[derive_where(Clone)]
 pub struct EndpointParams<E: EnclaveKind> {
     pub(crate) mr_enclave: MrEnclave<&'static [u8], E>,
     pub(crate) raft_config_override: Option<&'static RaftConfig>,
+    /// If set, the raft group id the enclave must present.
+    pub(crate) expected_group_id: Option<u64>,
+    /// Provides the current time for checking attestations.
+    pub(crate) clock: &'static dyn Clock,
+    /// Validated Nitro certificate chains; only Nitro attestations use it.
+    pub(crate) nitro_chain_cache: Option<Arc<NitroChainCache>>,
 }
 
 impl<E: EnclaveKind> EndpointParams<E> {
@@ -164,6 +581,9 @@ impl<E: EnclaveKind> EndpointParams<E> {
         Self {
             mr_enclave,
             raft_config_override: None,
+            expected_group_id: None,
+            clock: &SystemClock,
+            nitro_chain_cache: None,
         }
     }
 
@@ -171,70 +591,405 @@ impl<E: EnclaveKind> EndpointParams<E> {
         self.raft_config_override = Some(raft_config);
         self
     }
+
+    pub fn with_expected_group_id(mut self, group_id: u64) -> Self {
+        self.expected_group_id = Some(group_id);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: &'static dyn Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Rejects `handshake` if the enclave presented a raft group id other than
+    /// the expected one.
+    fn check_group_id(&self, handshake: enclave::Handshake) -> enclave::Result<enclave::Handshake> {
+        let Some(expected) = self.expected_group_id else {
+            return Ok(handshake);
+        };
+        match handshake.raft_group_id() {
+            Some(actual) if actual == expected => Ok(handshake),
+            Some(actual) => Err(enclave::Error::GroupIdMismatch { expected, actual }),
+            None => Err(enclave::Error::AttestationDataError {
+                reason: "Claims must contain a raft group config".to_string(),
+            }),
+        }
+    }
+}
+
+impl EndpointParams<Nitro> {
+    /// Reuses validations of the attestations' certificate chains from
+    /// `cache`, which can be shared with other endpoints. Off by default.
+    pub fn with_chain_cache(mut self, cache: Arc<NitroChainCache>) -> Self {
+        self.nitro_chain_cache = Some(cache);
+        self
+    }
 }
 
+/// How reachable an enclave's servers have been lately, as seen by
+/// [`EnclaveEndpointConnection::health`].
+///
+/// Only getting the websocket connected counts: a failed attestation isn't
+/// something the network can fix. Once every websocket connected this way has
+/// closed, the health goes back to [`Self::Offline`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionHealth {
+    /// The last connection was made over the first route tried.
+    Connected,
+    /// The last connection was made, but other routes failed or are cooling
+    /// down.
+    Degraded,
+    /// A connection is being made while none is open.
+    Reconnecting,
+    /// No connection is open: the last attempt failed, the connections have
+    /// all closed, or none was made yet.
+    Offline,
+}
+
+impl ConnectionHealth {
+    /// The health after connecting ended in `state`. `routes_failed` is
+    /// whether any route failed along the way or is cooling down.
+    fn after_connecting<T, E>(state: &ServiceState<T, E>, routes_failed: bool) -> Self {
+        match state {
+            ServiceState::Active(..) if !routes_failed => Self::Connected,
+            ServiceState::Active(..) => Self::Degraded,
+            ServiceState::Cooldown(_)
+            | ServiceState::Error(_)
+            | ServiceState::TimedOut
+            | ServiceState::AllRoutesFailed { .. } => Self::Offline,
+        }
+    }
+}
+
+/// The [`ConnectionHealth`] of an [`EnclaveEndpointConnection`] and its clones.
+#[derive(Debug)]
+struct HealthTracker {
+    health: watch::Sender<ConnectionHealth>,
+    /// Websockets connected and not closed yet. Only changed while holding
+    /// the lock on `health`, so that both change together.
+    open_connections: AtomicUsize,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self {
+            health: watch::channel(ConnectionHealth::Offline).0,
+            open_connections: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl HealthTracker {
+    fn connecting(&self) {
+        self.health.send_if_modified(|health| {
+            let was_offline = *health == ConnectionHealth::Offline;
+            if was_offline {
+                *health = ConnectionHealth::Reconnecting;
+            }
+            was_offline
+        });
+    }
+
+    /// Sets the health after connecting; `opened` is whether that opened a
+    /// websocket, which has to be [closed](Self::closed) later.
+    fn after_connecting(&self, new_health: ConnectionHealth, opened: bool) {
+        self.health.send_if_modified(|health| {
+            if opened {
+                self.open_connections.fetch_add(1, Ordering::SeqCst);
+            }
+            let changed = *health != new_health;
+            *health = new_health;
+            changed
+        });
+    }
+
+    fn closed(&self) {
+        self.health.send_if_modified(|health| {
+            let was_last = self.open_connections.fetch_sub(1, Ordering::SeqCst) == 1;
+            let changed = was_last
+                && matches!(
+                    *health,
+                    ConnectionHealth::Connected | ConnectionHealth::Degraded
+                );
+            if changed {
+                *health = ConnectionHealth::Offline;
+            }
+            changed
+        });
+    }
+}
+
+/// Everything needed to connect to an enclave, including the connection
+/// manager that throttles attempts after failures.
+///
+/// Clones are cheap and share the connection manager's state, so tasks that
+/// connect to the same enclave through clones of one value are throttled as
+/// one: a failure seen by any of them counts towards the cooldown of all. The
+/// `with_*` methods only change the value they are called on, and
+/// [`Self::with_clock`] gives it throttling state of its own, so they are best
+/// called before cloning.
+#[derive_where(Clone; C)]
 pub struct EnclaveEndpointConnection<E: EnclaveKind, C> {
     pub(crate) endpoint_connection: EndpointConnection<C>,
     pub(crate) params: EndpointParams<E>,
+    pub(crate) attestation_timeout: Duration,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) rekey_interval: Option<NonZeroU64>,
+    pub(crate) network_state: Arc<NetworkState>,
+    /// Clears the chain cache on network changes for as long as `self` or a
+    /// clone of it is around. There is at most one per connection, however
+    /// often the cache or network state is set.
+    chain_cache_hook: Option<Arc<ChangeHookGuard>>,
+    health: Arc<HealthTracker>,
 }
 
 impl<E: EnclaveKind> EnclaveEndpointConnection<E, SingleRouteThrottlingConnectionManager> {
-    pub fn new(endpoint: EnclaveEndpoint<'static, E>, connect_timeout: Duration) -> Self {
-        Self::with_custom_properties(endpoint, connect_timeout, None)
+    /// `timeouts` can also be a single [`Duration`], which every phase of
+    /// connecting may take in full; see [`ConnectTimeouts::from_total`].
+    pub fn new(
+        endpoint: EnclaveEndpoint<'static, E>,
+        timeouts: impl Into<ConnectTimeouts>,
+    ) -> Self {
+        Self::with_custom_properties(endpoint, timeouts, None)
     }
 
     pub fn with_custom_properties(
         endpoint: EnclaveEndpoint<'static, E>,
-        connect_timeout: Duration,
+        timeouts: impl Into<ConnectTimeouts>,
         raft_config_override: Option<&'static RaftConfig>,
     ) -> Self {
+        let timeouts = timeouts.into();
         Self {
             endpoint_connection: EndpointConnection {
                 manager: SingleRouteThrottlingConnectionManager::new(
-                    endpoint.domain_config.connection_params(),
-                    connect_timeout,
+                    endpoint
+                        .domain_config
+                        .connection_params()
+                        .with_connect_timeouts(timeouts),
+                    timeouts.websocket,
+                ),
+                config: make_ws_config(
+                    E::url_path(endpoint.mr_enclave.as_ref()),
+                    timeouts.websocket,
                 ),
-                config: make_ws_config(E::url_path(endpoint.mr_enclave.as_ref()), connect_timeout),
             },
             params: EndpointParams {
                 mr_enclave: endpoint.mr_enclave,
                 raft_config_override,
+                expected_group_id: None,
+                clock: &SystemClock,
+                nitro_chain_cache: None,
+            },
+            attestation_timeout: timeouts.attestation,
+            idle_timeout: ENCLAVE_IDLE_TIMEOUT,
+            rekey_interval: None,
+            network_state: Arc::default(),
+            chain_cache_hook: None,
+            health: Arc::default(),
+        }
+        .with_path_prefix(endpoint.domain_config.path_prefix)
+    }
+
+    /// Uses `clock` both for checking attestations and for connection
+    /// cooldowns.
+    pub fn with_clock(mut self, clock: &'static dyn Clock) -> Self {
+        self.endpoint_connection.manager = self.endpoint_connection.manager.with_clock(clock);
+        self.params = self.params.with_clock(clock);
+        self
+    }
+
+    /// Falls back to `additional_params`, in order, when the route `self`
+    /// connects through fails; that route stays the first one tried.
+    ///
+    /// Everything else carries over, including what attestation checks and
+    /// the cooldown of the existing route. The fallbacks get its timeouts.
+    pub fn into_multi_route(
+        self,
+        additional_params: Vec<ConnectionParams>,
+    ) -> EnclaveEndpointConnection<E, MultiRouteConnectionManager> {
+        let Self {
+            endpoint_connection: EndpointConnection { manager, config },
+            params,
+            attestation_timeout,
+            idle_timeout,
+            rekey_interval,
+            network_state,
+            chain_cache_hook,
+            health,
+        } = self;
+        EnclaveEndpointConnection {
+            endpoint_connection: EndpointConnection {
+                manager: manager.into_multi_route(additional_params),
+                config,
             },
+            params,
+            attestation_timeout,
+            idle_timeout,
+            rekey_interval,
+            network_state,
+            chain_cache_hook,
+            health,
         }
     }
 }
 
 impl<E: EnclaveKind> EnclaveEndpointConnection<E, MultiRouteConnectionManager> {
+    /// Unlike [`Self::new`], this has no [`DomainConfig`] to take a
+    /// [`DomainConfig::path_prefix`] from; use [`Self::with_path_prefix`].
     pub fn new_multi(
         mr_enclave: MrEnclave<&'static [u8], E>,
         connection_params: impl IntoIterator<Item = ConnectionParams>,
-        connect_timeout: Duration,
+        timeouts: impl Into<ConnectTimeouts>,
     ) -> Self {
+        let timeouts = timeouts.into();
         Self {
             endpoint_connection: EndpointConnection::new_multi(
-                connection_params,
-                connect_timeout,
-                make_ws_config(E::url_path(mr_enclave.as_ref()), connect_timeout),
+                connection_params
+                    .into_iter()
+                    .map(|params| params.with_connect_timeouts(timeouts)),
+                timeouts.websocket,
+                make_ws_config(E::url_path(mr_enclave.as_ref()), timeouts.websocket),
             ),
-            params: EndpointParams {
-                mr_enclave,
-                raft_config_override: None,
-            },
+            params: EndpointParams::new(mr_enclave),
+            attestation_timeout: timeouts.attestation,
+            idle_timeout: ENCLAVE_IDLE_TIMEOUT,
+            rekey_interval: None,
+            network_state: Arc::default(),
+            chain_cache_hook: None,
+            health: Arc::default(),
         }
     }
+
+    /// Picks the route to try first according to `policy`, such as the one
+    /// from [`DomainConfig::route_selection`].
+    pub fn with_route_selection(mut self, policy: RouteSelectionPolicy) -> Self {
+        self.endpoint_connection = self.endpoint_connection.with_route_selection(policy);
+        self
+    }
 }
 
-impl NewHandshake for Sgx {
-    fn new_handshake(
-        params: &EndpointParams<Self>,
-        attestation_message: &[u8],
-    ) -> enclave::Result<enclave::Handshake> {
-        attest::svr2::new_handshake_with_override(
-            params.mr_enclave.as_ref(),
-            attestation_message,
-            SystemTime::now(),
-            params.raft_config_override,
-        )
+impl<C> EnclaveEndpointConnection<Nitro, C> {
+    /// Reuses validations of the attestations' certificate chains from
+    /// `cache`; see [`EndpointParams::with_chain_cache`].
+    ///
+    /// The cache is cleared whenever the network changes, since the servers
+    /// reached, and the chains they present, may change with it.
+    pub fn with_chain_cache(mut self, cache: Arc<NitroChainCache>) -> Self {
+        self.params = self.params.with_chain_cache(cache);
+        self.clear_chain_cache_on_network_change();
+        self
+    }
+}
+
+impl<E: EnclaveKind, C> EnclaveEndpointConnection<E, C> {
+    /// Connects to the enclave's websocket path below `prefix`, replacing any
+    /// prefix set before; see [`DomainConfig::path_prefix`].
+    ///
+    /// Attestation is unaffected: the measurement checked is still the one
+    /// given when `self` was made.
+    pub fn with_path_prefix(mut self, prefix: Option<PathPrefix>) -> Self {
+        self.endpoint_connection.config.endpoint =
+            ws_path(prefix, &E::url_path(self.params.mr_enclave.as_ref()));
+        self
+    }
+
+    /// Fails attestation unless the enclave presents the raft group id
+    /// `group_id`. Only SVR enclaves present one.
+    pub fn with_expected_group_id(mut self, group_id: u64) -> Self {
+        self.params = self.params.with_expected_group_id(group_id);
+        self
+    }
+
+    /// Closes connections left unused for `idle_timeout`, instead of the
+    /// default [`ENCLAVE_IDLE_TIMEOUT`].
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Rekeys the Noise session of each connection after every `interval`
+    /// transport messages in each direction.
+    ///
+    /// Off by default: the enclave has to follow the same schedule, see
+    /// [`attest::client_connection`], or the connection breaks at the first
+    /// rekey.
+    pub fn with_rekey_interval(mut self, interval: NonZeroU64) -> Self {
+        self.rekey_interval = Some(interval);
+        self
+    }
+
+    /// Shares `network_state` with the connections made, so that their
+    /// failures after a network change are reported as such.
+    ///
+    /// A network change also clears the chain cache set with
+    /// [`EnclaveEndpointConnection::with_chain_cache`], if any.
+    pub fn with_network_state(mut self, network_state: Arc<NetworkState>) -> Self {
+        self.network_state = network_state;
+        self.clear_chain_cache_on_network_change();
+        self
+    }
+
+    fn clear_chain_cache_on_network_change(&mut self) {
+        // Replacing the previous hook, if any, unregisters it.
+        self.chain_cache_hook = self.params.nitro_chain_cache.as_ref().map(|cache| {
+            let cache = Arc::downgrade(cache);
+            Arc::new(self.network_state.on_change(move || {
+                if let Some(cache) = cache.upgrade() {
+                    cache.clear()
+                }
+            }))
+        });
+    }
+
+    /// Follows the [`ConnectionHealth`] of connections made through `self`
+    /// and its clones, starting with the current one.
+    ///
+    /// A new value is sent only when the health changes.
+    pub fn health(&self) -> watch::Receiver<ConnectionHealth> {
+        self.health.health.subscribe()
+    }
+
+    /// Connects the websocket through the connection manager, keeping
+    /// [`Self::health`] up to date.
+    pub(crate) async fn connect_websocket<S>(
+        &self,
+        connector: S,
+    ) -> ServiceState<S::Service, S::Error>
+    where
+        C: ConnectionManager,
+        S: ServiceConnector + Send + Sync,
+        S::Service: Send + Sync,
+        S::Channel: Send + Sync,
+        S::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater + 'static,
+    {
+        self.health.connecting();
+        let mut failures = Vec::new();
+        let state = ServiceInitializer::new(connector, &self.endpoint_connection.manager)
+            .connect_recording(&mut failures)
+            .await;
+        let routes_failed = !failures.is_empty()
+            || self
+                .endpoint_connection
+                .manager
+                .is_any_route_cooling_down()
+                .await;
+        let new_health = ConnectionHealth::after_connecting(&state, routes_failed);
+        match &state {
+            ServiceState::Active(_, service_status) => {
+                self.health.after_connecting(new_health, true);
+                let health = Arc::downgrade(&self.health);
+                let service_status = service_status.clone();
+                tokio::spawn(async move {
+                    service_status.stopped().await;
+                    if let Some(health) = health.upgrade() {
+                        health.closed();
+                    }
+                });
+            }
+            _ => self.health.after_connecting(new_health, false),
+        }
+        state
     }
 }
 
@@ -246,21 +1001,380 @@ impl NewHandshake for Cdsi {
         cds2::new_handshake(
             params.mr_enclave.as_ref(),
             attestation_message,
-            SystemTime::now(),
+            params.clock.now(),
         )
     }
 }
 
-impl NewHandshake for Nitro {
-    fn new_handshake(
-        params: &EndpointParams<Self>,
-        attestation_message: &[u8],
-    ) -> enclave::Result<enclave::Handshake> {
-        nitro::new_handshake(
-            params.mr_enclave.as_ref(),
-            attestation_message,
-            SystemTime::now(),
-            params.raft_config_override,
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use assert_matches::assert_matches;
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::infra::connection_manager::ConnectionAttemptOutcome;
+    use crate::infra::errors::NetError;
+    use crate::svr3::PpssOps;
+    use crate::test_support::attestation::clock_at;
+
+    const MEASUREMENT: [u8; 32] =
+        hex!("a8a261420a6bb9b61aa25bf8a79e8bd20d7652531feb3381cbffd446d270be95");
+
+    fn assert_ppss_ops<P: PpssOps>(_setup: &P) {}
+
+    #[tokio::test]
+    async fn shared_setup_forwards_to_inner() {
+        let env = Arc::new(crate::env::STAGING.svr3);
+        assert_ppss_ops(&env);
+
+        let tasks = [env.clone(), env.clone()].map(|env| {
+            tokio::spawn(async move {
+                let mr_enclaves: Vec<Vec<u8>> =
+                    env.mr_enclaves().into_iter().map(Vec::from).collect();
+                (PpssSetup::server_ids(&env), mr_enclaves)
+            })
+        });
+        for task in tasks {
+            let (server_ids, mr_enclaves) = task.await.expect("task did not panic");
+            assert_eq!(server_ids, crate::env::STAGING.svr3.server_ids());
+            assert_eq!(
+                mr_enclaves,
+                [
+                    attest::constants::ENCLAVE_ID_SVR3_SGX_STAGING,
+                    attest::constants::ENCLAVE_ID_SVR3_NITRO_STAGING
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn mr_enclave_hex_round_trip() {
+        let mr_enclave = MrEnclave::<_, Sgx>::new(MEASUREMENT.as_slice());
+        let encoded = mr_enclave.as_hex();
+        assert_eq!(encoded.len(), 64);
+
+        let decoded = MrEnclave::<_, Sgx>::from_hex(&encoded).expect("valid hex");
+        assert_eq!(decoded.as_ref(), MEASUREMENT);
+    }
+
+    #[test]
+    fn mr_enclave_base64_round_trip() {
+        let mr_enclave = MrEnclave::<_, Sgx>::new(MEASUREMENT.as_slice());
+        let encoded = mr_enclave.as_base64();
+        assert_eq!(encoded.len(), 44);
+
+        let decoded = MrEnclave::<_, Sgx>::from_base64(&encoded).expect("valid base64");
+        assert_eq!(decoded.as_ref(), MEASUREMENT);
+        assert_eq!(decoded.as_hex(), mr_enclave.as_hex());
+    }
+
+    #[test]
+    fn mr_enclave_rejects_invalid_encodings() {
+        assert_matches!(
+            MrEnclave::<_, Nitro>::from_hex("not hex"),
+            Err(ParseError::InvalidHex)
+        );
+        assert_matches!(
+            MrEnclave::<_, Nitro>::from_base64("not base64!"),
+            Err(ParseError::InvalidBase64)
+        );
+    }
+
+    #[test]
+    fn endpoint_from_config_checks_measurement() {
+        fn endpoint_json(mr_enclave: &str) -> String {
+            format!(
+                r#"{{
+                    "domain_config": {{"hostname": "svr3.example.com", "proxy_path": "/svr3"}},
+                    "mr_enclave": "{mr_enclave}"
+                }}"#
+            )
+        }
+
+        let endpoint: EnclaveEndpoint<'static, Sgx> =
+            serde_json::from_str(&endpoint_json(&hex::encode(MEASUREMENT))).expect("valid");
+        assert_eq!(endpoint.mr_enclave.as_ref(), MEASUREMENT);
+        assert_eq!(endpoint.domain_config.hostname, "svr3.example.com");
+
+        let too_short = endpoint_json(&hex::encode(&MEASUREMENT[..31]));
+        assert!(serde_json::from_str::<EnclaveEndpoint<'static, Sgx>>(&too_short).is_err());
+
+        let endpoint: EnclaveEndpoint<'static, Nitro> =
+            serde_json::from_str(&endpoint_json("3b3dda58.52b91975.02dfde15")).expect("valid");
+        assert_eq!(endpoint.mr_enclave.as_ref(), b"3b3dda58.52b91975.02dfde15");
+        assert!(
+            serde_json::from_str::<EnclaveEndpoint<'static, Nitro>>(&endpoint_json("3b3dda58"))
+                .is_err()
+        );
+    }
+
+    const SVR2_HANDSHAKE_START: &[u8] = attest::svr2::testutil::HANDSHAKE_START;
+    const SVR2_STAGING_GROUP_ID: u64 = 16934825672495360159;
+
+    /// Parameters for the enclave that produced [`SVR2_HANDSHAKE_START`], at a
+    /// time when its attestation was valid.
+    fn svr2_staging_params() -> EndpointParams<Sgx> {
+        EndpointParams::new(MrEnclave::new(attest::constants::ENCLAVE_ID_SVR2_STAGING)).with_clock(
+            clock_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1709245753)),
+        )
+    }
+
+    #[test]
+    fn handshake_surfaces_presented_group_id() {
+        let handshake = Sgx::new_handshake(&svr2_staging_params(), SVR2_HANDSHAKE_START)
+            .expect("valid attestation");
+        assert_eq!(handshake.raft_group_id(), Some(SVR2_STAGING_GROUP_ID));
+
+        let params = svr2_staging_params().with_expected_group_id(SVR2_STAGING_GROUP_ID);
+        let handshake =
+            Sgx::new_handshake(&params, SVR2_HANDSHAKE_START).expect("expected group id");
+        assert_eq!(handshake.raft_group_id(), Some(SVR2_STAGING_GROUP_ID));
+    }
+
+    #[test]
+    fn nitro_chain_cache_is_reused_until_network_changes() {
+        let fixture = &crate::test_support::attestation::nitro_fixtures()[0];
+        let clock = clock_at(fixture.valid.start);
+        let cache = Arc::new(NitroChainCache::default());
+        let network_state = Arc::new(NetworkState::new());
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Nitro>::test_endpoint(0),
+            Duration::from_secs(10),
+        )
+        .with_clock(clock)
+        .with_chain_cache(cache.clone())
+        .with_network_state(network_state.clone());
+
+        // The fixture's PCRs are rejected, but only after its chain validated.
+        for _ in 0..3 {
+            assert!(Nitro::new_handshake(&connection.params, fixture.message).is_err());
+        }
+        assert_eq!(cache.chain_validations(), 1);
+        assert_eq!(cache.len(), 1);
+
+        network_state.mark_changed();
+        assert!(cache.is_empty());
+        let _ = Nitro::new_handshake(&connection.params, fixture.message);
+        assert_eq!(cache.chain_validations(), 2);
+
+        // Setting both the cache and the network state registers one hook,
+        // which goes away with the last clone of the connection.
+        assert_eq!(network_state.hook_count(), 1);
+        let clone = connection.clone();
+        drop(connection);
+        assert_eq!(network_state.hook_count(), 1);
+        drop(clone);
+        assert_eq!(network_state.hook_count(), 0);
+    }
+
+    #[test]
+    fn handshake_rejects_unexpected_group_id() {
+        let params = svr2_staging_params().with_expected_group_id(1234);
+        let Err(error) = Sgx::new_handshake(&params, SVR2_HANDSHAKE_START) else {
+            panic!("group id should not match");
+        };
+        assert_matches!(
+            error,
+            enclave::Error::GroupIdMismatch {
+                expected: 1234,
+                actual: SVR2_STAGING_GROUP_ID
+            }
+        );
+
+        // Both ids make it into the (log-safe) error shown to callers.
+        let message = crate::svr::Error::AttestationError(error).to_string();
+        assert!(message.contains("1234"), "{message}");
+        assert!(
+            message.contains(&SVR2_STAGING_GROUP_ID.to_string()),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_endpoint_points_at_local_server() {
+        fn check<E: EnclaveKind>() {
+            let endpoint = EnclaveEndpoint::<E>::test_endpoint(8080);
+            let params = endpoint.domain_config.connection_params();
+            assert_eq!(&*params.host, "127.0.0.1");
+            assert_eq!(params.port, 8080);
+            assert_matches!(params.certs, RootCertificates::InsecureSkipVerification(_));
+            assert_eq!(endpoint.mr_enclave.as_ref(), E::TEST_MR_ENCLAVE);
+
+            // Builds the websocket path from the measurement, which must not panic.
+            let _connection = EnclaveEndpointConnection::new(endpoint, Duration::from_secs(10));
+        }
+        check::<Sgx>();
+        check::<Nitro>();
+        check::<Cdsi>();
+    }
+
+    fn ws_path_with_prefix(path_prefix: Option<&'static str>) -> String {
+        let mut endpoint = EnclaveEndpoint::<Sgx>::test_endpoint(8080);
+        endpoint.domain_config.path_prefix = path_prefix.map(PathPrefix::from_static);
+        EnclaveEndpointConnection::new(endpoint, Duration::from_secs(10))
+            .endpoint_connection
+            .config
+            .endpoint
+            .to_string()
+    }
+
+    #[test]
+    fn path_prefix_is_prepended_to_ws_path() {
+        let unprefixed = Sgx::url_path(Sgx::TEST_MR_ENCLAVE).to_string();
+        assert_eq!(ws_path_with_prefix(None), unprefixed);
+        assert_eq!(ws_path_with_prefix(Some("/")), unprefixed);
+        assert_eq!(
+            ws_path_with_prefix(Some("/svr3")),
+            format!("/svr3{unprefixed}")
+        );
+        assert_eq!(
+            ws_path_with_prefix(Some("/svr3/")),
+            format!("/svr3{unprefixed}")
+        );
+        assert_eq!(
+            ws_path_with_prefix(Some("enclaves/svr3")),
+            format!("/enclaves/svr3{unprefixed}")
+        );
+    }
+
+    #[test]
+    fn path_prefix_for_multi_route_connection() {
+        let endpoint = EnclaveEndpoint::<Nitro>::test_endpoint(8080);
+        let connection = EnclaveEndpointConnection::new_multi(
+            endpoint.mr_enclave,
+            [endpoint.domain_config.connection_params()],
+            Duration::from_secs(10),
+        );
+        let unprefixed = Nitro::url_path(Nitro::TEST_MR_ENCLAVE).to_string();
+        assert_eq!(connection.endpoint_connection.config.endpoint, unprefixed);
+
+        let connection = connection
+            .with_path_prefix(Some(PathPrefix::from_static("/svr3/")))
+            .with_path_prefix(Some(PathPrefix::from_static("/nitro")));
+        assert_eq!(
+            connection.endpoint_connection.config.endpoint,
+            format!("/nitro{unprefixed}")
+        );
+    }
+
+    #[tokio::test]
+    async fn into_multi_route_tries_existing_route_first() {
+        static RAFT_CONFIG: RaftConfig = RaftConfig {
+            min_voting_replicas: 3,
+            max_voting_replicas: 5,
+            super_majority: 0,
+            group_id: 1234,
+        };
+        let endpoint = EnclaveEndpoint::<Sgx>::test_endpoint(8080);
+        let primary = endpoint.domain_config.connection_params();
+        let fallback = ConnectionParams {
+            host: "fallback.signal.org".into(),
+            ..primary.clone()
+        };
+        let connection = EnclaveEndpointConnection::with_custom_properties(
+            endpoint,
+            Duration::from_secs(10),
+            Some(&RAFT_CONFIG),
         )
+        .with_path_prefix(Some(PathPrefix::from_static("/svr3")))
+        .into_multi_route(vec![fallback.clone()]);
+
+        assert_eq!(connection.params.mr_enclave.as_ref(), Sgx::TEST_MR_ENCLAVE);
+        assert_matches!(
+            connection.params.raft_config_override,
+            Some(raft_config) if std::ptr::eq(raft_config, &RAFT_CONFIG)
+        );
+        assert_eq!(
+            connection.endpoint_connection.config.endpoint,
+            format!("/svr3{}", Sgx::url_path(Sgx::TEST_MR_ENCLAVE))
+        );
+
+        // The existing route is tried first, and the fallback once it fails.
+        let attempted = std::sync::Mutex::new(vec![]);
+        let outcome: ConnectionAttemptOutcome<_, NetError> = connection
+            .endpoint_connection
+            .manager
+            .connect_or_wait(|params| {
+                attempted
+                    .lock()
+                    .expect("not poisoned")
+                    .push(params.host.clone());
+                std::future::ready(if params.host == primary.host {
+                    Err(NetError::Timeout)
+                } else {
+                    Ok(params.host.clone())
+                })
+            })
+            .await;
+        assert_matches!(outcome, ConnectionAttemptOutcome::Attempted(Ok(host)) if host == fallback.host);
+        let attempted = attempted.into_inner().expect("not poisoned");
+        assert_eq!(attempted.first(), Some(&primary.host));
+        assert_eq!(attempted.last(), Some(&fallback.host));
+    }
+
+    #[test]
+    fn test_mr_enclaves_are_valid_measurements() {
+        assert_eq!(
+            Sgx::parse_mr_enclave(&hex::encode(Sgx::TEST_MR_ENCLAVE)).as_deref(),
+            Ok(Sgx::TEST_MR_ENCLAVE)
+        );
+        let nitro = std::str::from_utf8(Nitro::TEST_MR_ENCLAVE).expect("textual");
+        assert_eq!(
+            Nitro::parse_mr_enclave(nitro).as_deref(),
+            Ok(Nitro::TEST_MR_ENCLAVE)
+        );
+    }
+
+    #[test]
+    fn flavor_names_are_stable() {
+        // Logs and diagnostics identify enclaves by these names.
+        assert_eq!(Sgx::flavor_name(), "sgx");
+        assert_eq!(Nitro::flavor_name(), "nitro");
+    }
+
+    impl_svr3_flavor! {
+        enum Experimental {
+            name: "experimental",
+            url_path: |enclave| format!("/v2/{}/experimental", hex::encode(enclave)),
+            parse_mr_enclave: parse_sgx_mr_enclave,
+            handshake: svr2_handshake,
+        }
+    }
+
+    #[test]
+    fn flavor_declared_with_macro() {
+        assert_eq!(Experimental::flavor_name(), "experimental");
+        assert_eq!(
+            Experimental::url_path(&MEASUREMENT).as_str(),
+            format!("/v2/{}/experimental", hex::encode(MEASUREMENT))
+        );
+        assert_eq!(
+            Experimental::parse_mr_enclave(&Experimental::format_mr_enclave(&MEASUREMENT))
+                .as_deref(),
+            Ok(&MEASUREMENT[..])
+        );
+        assert_eq!(Experimental::TEST_MR_ENCLAVE, Sgx::TEST_MR_ENCLAVE);
+
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Experimental>::test_endpoint(8080),
+            Duration::from_secs(10),
+        );
+        assert_eq!(
+            connection.endpoint_connection.config.endpoint,
+            Experimental::url_path(Experimental::TEST_MR_ENCLAVE).to_string()
+        );
+    }
+
+    #[test]
+    fn parse_sgx_mr_enclave_length() {
+        assert_matches!(
+            Sgx::parse_mr_enclave(&hex::encode([0; 33])),
+            Err(ParseError::InvalidLength {
+                expected: 32,
+                actual: 33
+            })
+        );
     }
 }
@@ -6,22 +6,45 @@
 use const_str::ip_addr;
 use std::collections::HashMap;
 use std::iter;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use std::time::Duration;
 
+use http::uri::PathAndQuery;
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 
-use crate::enclave::{Cdsi, EnclaveEndpoint, MrEnclave, Nitro, Sgx};
+use crate::enclave::{
+    short_hash, Cdsi, EnclaveEndpoint, EnclaveFingerprint, MrEnclave, Nitro, Sgx,
+};
 use crate::infra::certs::RootCertificates;
-use crate::infra::dns::LookupResult;
-use crate::infra::{ConnectionParams, HttpRequestDecorator, HttpRequestDecoratorSeq};
+use crate::infra::connection_manager::RouteSelectionPolicy;
+use crate::infra::dns::{self, DnsResolver, LookupResult};
+use crate::infra::errors::LogSafeDisplay;
+use crate::infra::{
+    ConnectTimeouts, ConnectionParams, HttpRequestDecorator, HttpRequestDecoratorSeq,
+};
+use crate::svr3::{NoopRequestLogger, RequestLogger};
+use crate::utils::intern;
 
 pub(crate) const WS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
 pub(crate) const WS_MAX_IDLE_TIME: Duration = Duration::from_secs(15);
+pub(crate) const WS_MAX_QUEUED_SEND_BYTES: NonZeroUsize = match NonZeroUsize::new(1 << 20) {
+    Some(len) => len,
+    None => unreachable!(),
+};
+pub(crate) const WS_SEND_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long an attested enclave connection may go unused before it is closed
+/// on the client side; a little under the time after which the enclave
+/// servers close idle connections themselves.
+pub const ENCLAVE_IDLE_TIMEOUT: Duration = Duration::from_secs(50);
 
 pub const DOMAIN_CONFIG_CHAT: DomainConfig = DomainConfig {
     hostname: "chat.signal.org",
+    port: 443,
     ip_v4: &[
         ip_addr!(v4, "76.223.92.165"),
         ip_addr!(v4, "13.248.212.111"),
@@ -32,10 +55,13 @@ pub const DOMAIN_CONFIG_CHAT: DomainConfig = DomainConfig {
     ],
     cert: &RootCertificates::Signal,
     proxy_path: "/service",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_CHAT_STAGING: DomainConfig = DomainConfig {
     hostname: "chat.staging.signal.org",
+    port: 443,
     ip_v4: &[
         ip_addr!(v4, "76.223.72.142"),
         ip_addr!(v4, "13.248.206.115"),
@@ -46,70 +72,96 @@ pub const DOMAIN_CONFIG_CHAT_STAGING: DomainConfig = DomainConfig {
     ],
     cert: &RootCertificates::Signal,
     proxy_path: "/service-staging",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_CDSI: DomainConfig = DomainConfig {
     hostname: "cdsi.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "40.122.45.194")],
     ip_v6: &[ip_addr!(v6, "2603:1030:7::1")],
     cert: &RootCertificates::Signal,
     proxy_path: "/cdsi",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_CDSI_STAGING: DomainConfig = DomainConfig {
     hostname: "cdsi.staging.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "104.43.162.137")],
     ip_v6: &[ip_addr!(v6, "2603:1030:7::732")],
     cert: &RootCertificates::Signal,
     proxy_path: "/cdsi-staging",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR2: DomainConfig = DomainConfig {
     hostname: "svr2.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "20.66.40.69")],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr2",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR2_STAGING: DomainConfig = DomainConfig {
     hostname: "svr2.staging.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "20.253.229.239")],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr2-staging",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR3_SGX: DomainConfig = DomainConfig {
     hostname: "svr3.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "143.244.220.150")],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr3-sgx",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR3_SGX_STAGING: DomainConfig = DomainConfig {
     hostname: "backend1.svr3.staging.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "13.88.63.29")],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr3-sgx-staging",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR3_NITRO: DomainConfig = DomainConfig {
     hostname: "devnull.signal.org",
+    port: 443,
     ip_v4: &[],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr3-nitro",
+    path_prefix: None,
+    route_weights: None,
 };
 
 pub const DOMAIN_CONFIG_SVR3_NITRO_STAGING: DomainConfig = DomainConfig {
     hostname: "backend2.svr3.staging.signal.org",
+    port: 443,
     ip_v4: &[ip_addr!(v4, "75.2.86.85"), ip_addr!(v4, "99.83.239.137")],
     ip_v6: &[],
     cert: &RootCertificates::Signal,
     proxy_path: "/svr3-nitro-staging",
+    path_prefix: None,
+    route_weights: None,
 };
 
 const PROXY_CONFIG_F: ProxyConfig = ProxyConfig {
@@ -132,15 +184,154 @@ const PROXY_CONFIG_G: ProxyConfig = ProxyConfig {
     ],
 };
 
-#[derive(Clone, Copy)]
+/// Configuration for one domain.
+///
+/// Built-in configurations are constants; when loaded from a config file, the
+/// deserialized values are interned, since they are meant to live for the
+/// whole program just like the constants. Loading the same values again
+/// reuses them.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "DomainConfigRepr")]
 pub struct DomainConfig {
     pub hostname: &'static str,
+    pub port: u16,
     pub proxy_path: &'static str,
+    /// Path the domain serves its enclave websockets below, if not at the root.
+    ///
+    /// Unlike [`Self::proxy_path`], this applies to direct connections too; a
+    /// trailing slash makes no difference. See [`ws_path`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<PathPrefix>,
+    /// Weights for [`RouteSelectionPolicy::WeightedRandom`], in the order of
+    /// [`Self::connection_params_with_fallback`]: the direct route first, then
+    /// the proxies, which are not shuffled when there are weights. Without
+    /// them, routes are tried in order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_weights: Option<&'static [u32]>,
     pub ip_v4: &'static [Ipv4Addr],
     pub ip_v6: &'static [Ipv6Addr],
     pub cert: &'static RootCertificates,
 }
 
+#[derive(Deserialize)]
+struct DomainConfigRepr {
+    hostname: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    proxy_path: String,
+    #[serde(default)]
+    path_prefix: Option<String>,
+    #[serde(default)]
+    route_weights: Option<Vec<u32>>,
+    #[serde(default)]
+    ip_v4: Vec<Ipv4Addr>,
+    #[serde(default)]
+    ip_v6: Vec<Ipv6Addr>,
+    #[serde(default)]
+    cert: RootCertificates,
+}
+
+/// Rejects a `path_prefix` that is not a valid [`PathPrefix`].
+impl TryFrom<DomainConfigRepr> for DomainConfig {
+    type Error = InvalidPathPrefix;
+
+    fn try_from(value: DomainConfigRepr) -> Result<Self, Self::Error> {
+        let DomainConfigRepr {
+            hostname,
+            port,
+            proxy_path,
+            path_prefix,
+            route_weights,
+            ip_v4,
+            ip_v6,
+            cert,
+        } = value;
+        if let Some(prefix) = &path_prefix {
+            PathPrefix::validate(prefix)?;
+        }
+        Ok(Self {
+            hostname: intern(hostname.into_boxed_str()),
+            port,
+            proxy_path: intern(proxy_path.into_boxed_str()),
+            path_prefix: path_prefix.map(|prefix| PathPrefix(intern(prefix.into_boxed_str()))),
+            route_weights: route_weights.map(|weights| intern(weights.into_boxed_slice())),
+            ip_v4: intern(ip_v4.into_boxed_slice()),
+            ip_v6: intern(ip_v6.into_boxed_slice()),
+            cert: intern(Box::new(cert)),
+        })
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// invalid path prefix {0:?}
+pub struct InvalidPathPrefix(String);
+
+/// A path to serve enclave websockets below; see [`DomainConfig::path_prefix`].
+///
+/// Leading and trailing slashes make no difference. Otherwise only what RFC
+/// 3986 allows in a path segment is accepted, so that a prefix can go in front
+/// of any path; in particular, there can be no query or fragment.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct PathPrefix(&'static str);
+
+impl PathPrefix {
+    pub fn new(prefix: &'static str) -> Result<Self, InvalidPathPrefix> {
+        Self::validate(prefix)?;
+        Ok(Self(prefix))
+    }
+
+    /// Like [`Self::new`], but panics if `prefix` is invalid, which for a
+    /// constant happens at compile time.
+    pub const fn from_static(prefix: &'static str) -> Self {
+        if !is_valid_path_prefix(prefix) {
+            panic!("invalid path prefix");
+        }
+        Self(prefix)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    fn validate(prefix: &str) -> Result<(), InvalidPathPrefix> {
+        if is_valid_path_prefix(prefix) {
+            Ok(())
+        } else {
+            Err(InvalidPathPrefix(prefix.to_owned()))
+        }
+    }
+}
+
+const fn is_valid_path_prefix(prefix: &str) -> bool {
+    let bytes = prefix.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => {}
+            b'/' | b'-' | b'.' | b'_' | b'~' | b'%' | b':' | b'@' => {}
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => {}
+            _ => return false,
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Puts `prefix`, if any, in front of `path`, with a single slash between them.
+pub fn ws_path(prefix: Option<PathPrefix>, path: &PathAndQuery) -> PathAndQuery {
+    let prefix = match prefix.map(|prefix| prefix.0.trim_matches('/')) {
+        None | Some("") => return path.clone(),
+        Some(prefix) => prefix,
+    };
+    PathAndQuery::try_from(format!("/{prefix}{path}"))
+        .expect("path prefixes only have characters allowed in paths")
+}
+
+fn default_port() -> u16 {
+    443
+}
+
 impl DomainConfig {
     pub fn static_fallback(&self) -> (&'static str, LookupResult) {
         (
@@ -153,21 +344,62 @@ impl DomainConfig {
         ConnectionParams::new(
             self.hostname,
             self.hostname,
-            443,
+            self.port,
             HttpRequestDecoratorSeq::default(),
             *self.cert,
         )
     }
 
+    /// The direct route, then the proxies, alternating between providers.
+    ///
+    /// Each provider's proxies are shuffled, unless there are
+    /// [`Self::route_weights`]: those are given by position, so the proxies
+    /// are kept in a fixed order for them.
     pub fn connection_params_with_fallback(&self) -> Vec<ConnectionParams> {
         let direct = self.connection_params();
-        let rng = thread_rng();
-        let shuffled_g_params =
-            PROXY_CONFIG_G.shuffled_connection_params(self.proxy_path, rng.clone());
-        let shuffled_f_params = PROXY_CONFIG_F.shuffled_connection_params(self.proxy_path, rng);
-        let proxy_params = itertools::interleave(shuffled_g_params, shuffled_f_params);
+        let proxy_params: Vec<_> = if self.route_weights.is_some() {
+            itertools::interleave(
+                PROXY_CONFIG_G.connection_params(self.proxy_path),
+                PROXY_CONFIG_F.connection_params(self.proxy_path),
+            )
+            .collect()
+        } else {
+            let rng = thread_rng();
+            let shuffled_g_params =
+                PROXY_CONFIG_G.shuffled_connection_params(self.proxy_path, rng.clone());
+            let shuffled_f_params = PROXY_CONFIG_F.shuffled_connection_params(self.proxy_path, rng);
+            itertools::interleave(shuffled_g_params, shuffled_f_params).collect()
+        };
         iter::once(direct).chain(proxy_params).collect()
     }
+
+    /// How to pick among the routes of [`Self::connection_params_with_fallback`].
+    pub fn route_selection(&self) -> RouteSelectionPolicy {
+        match self.route_weights {
+            Some(weights) => RouteSelectionPolicy::WeightedRandom {
+                weights: weights.to_vec(),
+            },
+            None => RouteSelectionPolicy::OrderedFailover,
+        }
+    }
+
+    /// Resolves the domain's addresses without connecting to any of them.
+    ///
+    /// Both IPv6 and IPv4 addresses are returned, in the order connections
+    /// try them: alternating between the two, starting with IPv6.
+    ///
+    /// Unlike connections, this never falls back to the static addresses of
+    /// `resolver` or of the domain: if the lookup fails, so does this.
+    pub async fn resolve_to_ip_addrs(
+        &self,
+        resolver: &DnsResolver,
+    ) -> Result<Vec<IpAddr>, dns::Error> {
+        Ok(resolver
+            .lookup_ip_without_fallback(self.hostname)
+            .await?
+            .into_iter()
+            .collect())
+    }
 }
 
 pub struct ProxyConfig {
@@ -176,6 +408,16 @@ pub struct ProxyConfig {
 }
 
 impl ProxyConfig {
+    /// Routes through the proxy with each of its SNIs, in the order listed.
+    pub fn connection_params<'a>(
+        &'a self,
+        proxy_path: &'static str,
+    ) -> impl Iterator<Item = ConnectionParams> + 'a {
+        self.sni_list
+            .iter()
+            .map(move |sni| self.connection_params_for_sni(sni, proxy_path))
+    }
+
     pub fn shuffled_connection_params<'a>(
         &'a self,
         proxy_path: &'static str,
@@ -183,15 +425,23 @@ impl ProxyConfig {
     ) -> impl Iterator<Item = ConnectionParams> + 'a {
         let mut sni_list = self.sni_list.to_vec();
         sni_list.shuffle(&mut rng);
-        sni_list.into_iter().map(move |sni| {
-            ConnectionParams::new(
-                sni,
-                self.hostname,
-                443,
-                HttpRequestDecorator::PathPrefix(proxy_path).into(),
-                RootCertificates::Native,
-            )
-        })
+        sni_list
+            .into_iter()
+            .map(move |sni| self.connection_params_for_sni(sni, proxy_path))
+    }
+
+    fn connection_params_for_sni(
+        &self,
+        sni: &'static str,
+        proxy_path: &'static str,
+    ) -> ConnectionParams {
+        ConnectionParams::new(
+            sni,
+            self.hostname,
+            443,
+            HttpRequestDecorator::PathPrefix(proxy_path).into(),
+            RootCertificates::Native,
+        )
     }
 }
 
@@ -221,9 +471,44 @@ impl<'a> Env<'a, Svr3Env<'a>> {
     }
 }
 
-pub struct Svr3Env<'a>(EnclaveEndpoint<'a, Sgx>, EnclaveEndpoint<'a, Nitro>);
+/// Server IDs of the SVR3 enclaves run by Signal, in `(sgx, nitro)` order.
+pub const SIGNAL_SVR3_SERVER_IDS: [u64; 2] = [1, 2];
+
+pub struct Svr3Env<'a>(
+    EnclaveEndpoint<'a, Sgx>,
+    EnclaveEndpoint<'a, Nitro>,
+    [u64; 2],
+    Option<Arc<dyn RequestLogger + Send + Sync>>,
+);
 
 impl<'a> Svr3Env<'a> {
+    /// Describes an SVR3 deployment other than the ones run by Signal.
+    ///
+    /// `server_ids` are the IDs the enclaves were configured with, in
+    /// `(sgx, nitro)` order. They are mixed into the shares, so backups made
+    /// against one deployment can't be restored from another.
+    pub const fn custom(
+        sgx: EnclaveEndpoint<'a, Sgx>,
+        nitro: EnclaveEndpoint<'a, Nitro>,
+        server_ids: [u64; 2],
+    ) -> Self {
+        Self(sgx, nitro, server_ids, None)
+    }
+
+    /// Reports every operation other than a query run against this
+    /// environment to `logger`, replacing any logger set before.
+    ///
+    /// Without one, events are dropped as by [`NoopRequestLogger`].
+    pub fn with_request_logger(mut self, logger: Arc<dyn RequestLogger + Send + Sync>) -> Self {
+        self.3 = Some(logger);
+        self
+    }
+
+    /// The logger set with [`Self::with_request_logger`], if any.
+    pub fn request_logger(&self) -> &dyn RequestLogger {
+        self.3.as_deref().unwrap_or(&NoopRequestLogger)
+    }
+
     #[inline]
     pub fn sgx(&self) -> EnclaveEndpoint<'a, Sgx> {
         self.0
@@ -233,6 +518,169 @@ impl<'a> Svr3Env<'a> {
     pub fn nitro(&self) -> EnclaveEndpoint<'a, Nitro> {
         self.1
     }
+
+    #[inline]
+    pub fn server_ids(&self) -> [u64; 2] {
+        self.2
+    }
+
+    /// Routes to the SGX enclave: the direct route first, then the proxies.
+    ///
+    /// Unless the domain has route weights, the proxies are shuffled anew on
+    /// every call, so their order here is not the one a later connection will
+    /// try them in; see [`DomainConfig::connection_params_with_fallback`].
+    pub fn sgx_connection_params(&self) -> Vec<ConnectionParams> {
+        self.sgx().domain_config.connection_params_with_fallback()
+    }
+
+    /// Routes to the Nitro enclave, like [`Self::sgx_connection_params`].
+    pub fn nitro_connection_params(&self) -> Vec<ConnectionParams> {
+        self.nitro().domain_config.connection_params_with_fallback()
+    }
+
+    /// Describes the enclaves and server IDs of this environment, along with
+    /// the path prefixes and route weights used to reach the enclaves, for
+    /// support to confirm which ones a client uses; see [`EnclaveFingerprint`].
+    ///
+    /// The CDSI equivalent is [`EnclaveEndpoint::fingerprint`].
+    pub fn fingerprint(&self) -> Svr3Fingerprint {
+        let sgx = self.sgx().fingerprint();
+        let nitro = self.nitro().fingerprint();
+        let server_ids = self.server_ids();
+        let hash = short_hash(
+            server_ids.iter().fold(
+                Sha256::new()
+                    .chain_update(sgx.hash())
+                    .chain_update(nitro.hash()),
+                |hasher, id| hasher.chain_update(id.to_be_bytes()),
+            ),
+        );
+        Svr3Fingerprint {
+            sgx,
+            nitro,
+            server_ids,
+            hash,
+        }
+    }
+}
+
+/// Backup parameters the enclaves would reject, from
+/// [`PpssSetup::validate_backup_params`](crate::enclave::PpssSetup::validate_backup_params).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, displaydoc::Display, thiserror::Error)]
+/// max_tries must be between {min} and {max}, but is {max_tries}
+pub struct ValidationError {
+    pub max_tries: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Only has the numbers.
+impl LogSafeDisplay for ValidationError {}
+
+/// How long connecting to each enclave of an [`Svr3Env`] may take, for when
+/// they warrant different budgets, say because one is in another region.
+///
+/// A single [`Duration`] or [`ConnectTimeouts`] converts into the same
+/// timeouts for both enclaves, which is how connecting worked before.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Svr3ConnectTimeouts {
+    pub sgx: ConnectTimeouts,
+    pub nitro: ConnectTimeouts,
+}
+
+impl From<ConnectTimeouts> for Svr3ConnectTimeouts {
+    fn from(timeouts: ConnectTimeouts) -> Self {
+        Self {
+            sgx: timeouts,
+            nitro: timeouts,
+        }
+    }
+}
+
+impl From<Duration> for Svr3ConnectTimeouts {
+    fn from(total: Duration) -> Self {
+        ConnectTimeouts::from_total(total).into()
+    }
+}
+
+/// Log-safe description of an [`Svr3Env`], from [`Svr3Env::fingerprint`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Svr3Fingerprint {
+    sgx: EnclaveFingerprint,
+    nitro: EnclaveFingerprint,
+    server_ids: [u64; 2],
+    hash: String,
+}
+
+impl Svr3Fingerprint {
+    /// Short hash of the rest of the fingerprint, which only changes when the
+    /// configuration does.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// Loads a custom environment from configuration; see [`Svr3Env::custom`].
+///
+/// Like [`EnclaveEndpoint`], the loaded data is interned, which makes the result
+/// usable anywhere the built-in environments are.
+impl<'de> Deserialize<'de> for Svr3Env<'static> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Repr {
+            sgx: EnclaveEndpoint<'static, Sgx>,
+            nitro: EnclaveEndpoint<'static, Nitro>,
+            server_ids: [u64; 2],
+        }
+        let Repr {
+            sgx,
+            nitro,
+            server_ids,
+        } = Repr::deserialize(deserializer)?;
+        Ok(Self::custom(sgx, nitro, server_ids))
+    }
+}
+
+impl Svr3Env<'static> {
+    /// Picks the [`STAGING`] or [`PROD`] SVR3 environment.
+    pub fn from_flags(use_staging: bool) -> &'static Self {
+        if use_staging {
+            &STAGING.svr3
+        } else {
+            &PROD.svr3
+        }
+    }
+
+    /// Adds mutually exclusive `--staging` and `--production` flags to `command`.
+    ///
+    /// Use [`Svr3Env::from_arg_matches`] to pick the environment from the parsed arguments.
+    #[cfg(feature = "cli")]
+    pub fn register_clap_args(command: clap::Command) -> clap::Command {
+        use clap::{Arg, ArgAction};
+        command
+            .arg(
+                Arg::new("staging")
+                    .long("staging")
+                    .action(ArgAction::SetTrue)
+                    .help("Use the staging SVR3 environment (the default)"),
+            )
+            .arg(
+                Arg::new("production")
+                    .long("production")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("staging")
+                    .help("Use the production SVR3 environment"),
+            )
+    }
+
+    /// Picks the environment selected by the flags from [`Svr3Env::register_clap_args`].
+    ///
+    /// Staging is used unless `--production` was passed.
+    #[cfg(feature = "cli")]
+    pub fn from_arg_matches(matches: &clap::ArgMatches) -> &'static Self {
+        Self::from_flags(!matches.get_flag("production"))
+    }
 }
 
 pub const STAGING: Env<'static, Svr3Env> = Env {
@@ -254,6 +702,8 @@ pub const STAGING: Env<'static, Svr3Env> = Env {
             domain_config: DOMAIN_CONFIG_SVR3_NITRO_STAGING,
             mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_SVR3_NITRO_STAGING),
         },
+        SIGNAL_SVR3_SERVER_IDS,
+        None,
     ),
 };
 
@@ -276,9 +726,406 @@ pub const PROD: Env<'static, Svr3Env> = Env {
             domain_config: DOMAIN_CONFIG_SVR3_NITRO,
             mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_SVR3_NITRO_PROD),
         },
+        SIGNAL_SVR3_SERVER_IDS,
+        None,
     ),
 };
 
 pub mod constants {
     pub const WEB_SOCKET_PATH: &str = "/v1/websocket/";
 }
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::infra::dns::testutil::FakeDnsLookup;
+
+    #[test]
+    fn svr3_env_from_flags() {
+        let staging = Svr3Env::from_flags(true);
+        let prod = Svr3Env::from_flags(false);
+
+        assert_eq!(
+            staging.sgx().domain_config.hostname,
+            STAGING.svr3.sgx().domain_config.hostname
+        );
+        assert_eq!(
+            prod.sgx().domain_config.hostname,
+            PROD.svr3.sgx().domain_config.hostname
+        );
+        assert_ne!(
+            staging.sgx().domain_config.hostname,
+            prod.sgx().domain_config.hostname
+        );
+        assert_ne!(
+            staging.nitro().domain_config.hostname,
+            prod.nitro().domain_config.hostname
+        );
+        assert_ne!(
+            staging.sgx().mr_enclave.as_ref(),
+            prod.sgx().mr_enclave.as_ref()
+        );
+    }
+
+    #[test]
+    fn svr3_backup_params_are_checked_against_enclave_limits() {
+        use nonzero_ext::nonzero;
+
+        use crate::enclave::PpssSetup as _;
+
+        let env = STAGING.svr3;
+        assert_eq!(env.validate_backup_params(nonzero!(1u32)), Ok(()));
+        assert_eq!(env.validate_backup_params(nonzero!(255u32)), Ok(()));
+        let error = env
+            .validate_backup_params(nonzero!(256u32))
+            .expect_err("too many");
+        assert_eq!(
+            error,
+            ValidationError {
+                max_tries: 256,
+                min: 1,
+                max: 255,
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "max_tries must be between 1 and 255, but is 256"
+        );
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn svr3_env_from_arg_matches() {
+        let command = Svr3Env::register_clap_args(clap::Command::new("test"));
+        let hostname = |args: &[&str]| {
+            let matches = command
+                .clone()
+                .try_get_matches_from(args)
+                .expect("valid args");
+            Svr3Env::from_arg_matches(&matches)
+                .sgx()
+                .domain_config
+                .hostname
+        };
+
+        let staging = STAGING.svr3.sgx().domain_config.hostname;
+        assert_eq!(hostname(&["test"]), staging);
+        assert_eq!(hostname(&["test", "--staging"]), staging);
+        assert_eq!(
+            hostname(&["test", "--production"]),
+            PROD.svr3.sgx().domain_config.hostname
+        );
+        assert!(command
+            .try_get_matches_from(["test", "--staging", "--production"])
+            .is_err());
+    }
+
+    #[test]
+    fn domain_config_round_trip() {
+        let config = STAGING.svr3.sgx().domain_config;
+        let json = serde_json::to_string(&config).expect("can serialize");
+        let loaded: DomainConfig = serde_json::from_str(&json).expect("can deserialize");
+
+        assert_eq!(loaded.hostname, config.hostname);
+        assert_eq!(loaded.port, config.port);
+        assert_eq!(loaded.proxy_path, config.proxy_path);
+        assert_eq!(loaded.ip_v4, config.ip_v4);
+        assert_eq!(loaded.ip_v6, config.ip_v6);
+        assert_eq!(serde_json::to_string(&loaded).expect("can serialize"), json);
+    }
+
+    #[test]
+    fn domain_config_addresses_are_optional() {
+        let loaded: DomainConfig =
+            serde_json::from_str(r#"{"hostname": "svr3.example.com", "proxy_path": "/svr3"}"#)
+                .expect("can deserialize");
+        assert_eq!(loaded.port, 443);
+        assert!(loaded.ip_v4.is_empty() && loaded.ip_v6.is_empty());
+        assert!(matches!(loaded.cert, RootCertificates::Native));
+    }
+
+    #[test]
+    fn domain_config_path_prefix() {
+        let loaded: DomainConfig = serde_json::from_str(
+            r#"{"hostname": "svr3.example.com", "proxy_path": "/svr3", "path_prefix": "/enclaves/"}"#,
+        )
+        .expect("can deserialize");
+        assert_eq!(
+            loaded.path_prefix,
+            Some(PathPrefix::from_static("/enclaves/"))
+        );
+
+        for invalid in ["/enclaves?x=1", "/enclaves#x", "/enc laves"] {
+            let json = format!(
+                r#"{{"hostname": "svr3.example.com", "proxy_path": "/svr3", "path_prefix": "{invalid}"}}"#
+            );
+            assert!(
+                serde_json::from_str::<DomainConfig>(&json).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn path_prefix_validation() {
+        for valid in [
+            "",
+            "/",
+            "svr3",
+            "/enclaves/svr3/",
+            "/a-b.c_d~e%20:@!$&'()*+,;=",
+        ] {
+            assert_matches!(PathPrefix::new(valid), Ok(_), "{valid}");
+        }
+        for invalid in ["/svr3?x=1", "/svr3#x", "/sv r3", "/svr3\\", "/svr\u{e9}"] {
+            assert_matches!(PathPrefix::new(invalid), Err(_), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn ws_path_puts_a_single_slash_after_the_prefix() {
+        let path = PathAndQuery::from_static("/enclave/abcd");
+        assert_eq!(ws_path(None, &path), path);
+        for prefix in ["", "/", "//"] {
+            assert_eq!(ws_path(Some(PathPrefix::from_static(prefix)), &path), path);
+        }
+        for prefix in ["svr3", "/svr3", "svr3/", "/svr3//"] {
+            assert_eq!(
+                ws_path(Some(PathPrefix::from_static(prefix)), &path),
+                "/svr3/enclave/abcd"
+            );
+        }
+    }
+
+    #[test]
+    fn domain_config_route_weights() {
+        assert_eq!(
+            DOMAIN_CONFIG_CHAT.route_selection(),
+            RouteSelectionPolicy::OrderedFailover
+        );
+        let loaded: DomainConfig = serde_json::from_str(
+            r#"{"hostname": "chat.example.com", "proxy_path": "/service", "route_weights": [9, 1]}"#,
+        )
+        .expect("can deserialize");
+        assert_eq!(
+            loaded.route_selection(),
+            RouteSelectionPolicy::WeightedRandom {
+                weights: vec![9, 1]
+            }
+        );
+    }
+
+    #[test]
+    fn weighted_routes_keep_their_order() {
+        let loaded: DomainConfig = serde_json::from_str(
+            r#"{"hostname": "chat.example.com", "proxy_path": "/service", "route_weights": [9, 1]}"#,
+        )
+        .expect("can deserialize");
+        let snis = |params: Vec<ConnectionParams>| -> Vec<String> {
+            params.iter().map(|params| params.sni.to_string()).collect()
+        };
+
+        let expected = snis(loaded.connection_params_with_fallback());
+        assert_eq!(
+            expected[..3],
+            [
+                "chat.example.com",
+                PROXY_CONFIG_G.sni_list[0],
+                PROXY_CONFIG_F.sni_list[0]
+            ]
+        );
+        // Shuffling would reorder the proxies well within this many tries.
+        for _ in 0..20 {
+            assert_eq!(snis(loaded.connection_params_with_fallback()), expected);
+        }
+    }
+
+    #[test]
+    fn svr3_connection_params() {
+        fn check(params: Vec<ConnectionParams>, hostname: &str) {
+            let (direct, proxies) = params.split_first().expect("has a direct route");
+            assert_eq!(&*direct.host, hostname);
+            assert_eq!(&*direct.sni, hostname);
+            assert_matches!(direct.certs, RootCertificates::Signal);
+            assert!(!proxies.is_empty());
+            for route in &params {
+                assert_eq!(route.port, 443, "{}", route.host);
+            }
+            for proxy in proxies {
+                assert_matches!(proxy.certs, RootCertificates::Native);
+            }
+        }
+        check(PROD.svr3.sgx_connection_params(), "svr3.signal.org");
+        check(PROD.svr3.nitro_connection_params(), "devnull.signal.org");
+        check(
+            STAGING.svr3.sgx_connection_params(),
+            "backend1.svr3.staging.signal.org",
+        );
+        check(
+            STAGING.svr3.nitro_connection_params(),
+            "backend2.svr3.staging.signal.org",
+        );
+    }
+
+    #[test]
+    fn custom_svr3_env() {
+        let env = Svr3Env::custom(STAGING.svr3.sgx(), PROD.svr3.nitro(), [7, 8]);
+        assert_eq!(
+            env.sgx().domain_config.hostname,
+            STAGING.svr3.sgx().domain_config.hostname
+        );
+        assert_eq!(
+            env.nitro().mr_enclave.as_ref(),
+            PROD.svr3.nitro().mr_enclave.as_ref()
+        );
+        assert_eq!(env.server_ids(), [7, 8]);
+        assert_eq!(STAGING.svr3.server_ids(), SIGNAL_SVR3_SERVER_IDS);
+    }
+
+    #[test]
+    fn svr3_env_from_config() {
+        let json = format!(
+            r#"{{
+                "sgx": {{
+                    "domain_config": {{"hostname": "sgx.example.com", "proxy_path": "/svr3-sgx"}},
+                    "mr_enclave": "{sgx}"
+                }},
+                "nitro": {{
+                    "domain_config": {{"hostname": "nitro.example.com", "proxy_path": "/svr3-nitro"}},
+                    "mr_enclave": "3b3dda58.52b91975.02dfde15"
+                }},
+                "server_ids": [10, 20]
+            }}"#,
+            sgx = hex::encode([0x5a; 32]),
+        );
+        let env: Svr3Env<'static> = serde_json::from_str(&json).expect("can deserialize");
+        assert_eq!(env.sgx().domain_config.hostname, "sgx.example.com");
+        assert_eq!(env.sgx().mr_enclave.as_ref(), [0x5a; 32]);
+        assert_eq!(env.nitro().domain_config.hostname, "nitro.example.com");
+        assert_eq!(
+            env.nitro().mr_enclave.as_ref(),
+            b"3b3dda58.52b91975.02dfde15"
+        );
+        assert_eq!(env.server_ids(), [10, 20]);
+
+        let missing_ids = json.replace(r#""server_ids": [10, 20]"#, r#""unused": 0"#);
+        assert!(serde_json::from_str::<Svr3Env<'static>>(&missing_ids).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_to_ip_addrs_prefers_ipv6() {
+        let resolver = DnsResolver::default().with_lookup(FakeDnsLookup(HashMap::from([(
+            DOMAIN_CONFIG_CHAT.hostname,
+            Ok(LookupResult::new(
+                vec![ip_addr!(v4, "1.1.1.1"), ip_addr!(v4, "2.2.2.2")],
+                vec![ip_addr!(v6, "::1")],
+            )),
+        )])));
+
+        assert_eq!(
+            DOMAIN_CONFIG_CHAT
+                .resolve_to_ip_addrs(&resolver)
+                .await
+                .expect("resolves"),
+            [
+                IpAddr::V6(ip_addr!(v6, "::1")),
+                IpAddr::V4(ip_addr!(v4, "1.1.1.1")),
+                IpAddr::V4(ip_addr!(v4, "2.2.2.2")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_to_ip_addrs_reports_why_lookup_failed() {
+        let resolver = DnsResolver::default().with_lookup(FakeDnsLookup(HashMap::from([(
+            DOMAIN_CONFIG_CDSI.hostname,
+            Err(dns::Error::ServFail),
+        )])));
+
+        assert_matches!(
+            DOMAIN_CONFIG_CDSI.resolve_to_ip_addrs(&resolver).await,
+            Err(dns::Error::ServFail)
+        );
+        assert_matches!(
+            DOMAIN_CONFIG_CHAT.resolve_to_ip_addrs(&resolver).await,
+            Err(dns::Error::NxDomain)
+        );
+
+        // Even when a connection would use the static addresses instead.
+        let resolver = DnsResolver::new_with_static_fallback(HashMap::from([
+            DOMAIN_CONFIG_CDSI.static_fallback()
+        ]))
+        .with_lookup(FakeDnsLookup(HashMap::from([(
+            DOMAIN_CONFIG_CDSI.hostname,
+            Err(dns::Error::ServFail),
+        )])));
+        assert_matches!(resolver.lookup_ip(DOMAIN_CONFIG_CDSI.hostname).await, Ok(_));
+        assert_matches!(
+            DOMAIN_CONFIG_CDSI.resolve_to_ip_addrs(&resolver).await,
+            Err(dns::Error::ServFail)
+        );
+    }
+
+    #[test]
+    fn staging_fingerprints_are_stable() {
+        // If these fail after a change to the staging configuration above,
+        // update the hashes; otherwise, something else changed the fingerprint.
+        assert_eq!(STAGING.svr3.fingerprint().hash(), "6875ccb85100b11b");
+        assert_eq!(STAGING.cdsi.fingerprint().hash(), "bca20bd31dad6546");
+
+        let fingerprint = serde_json::to_value(STAGING.svr3.fingerprint()).expect("serializes");
+        assert_eq!(
+            fingerprint["nitro"]["mrEnclave"],
+            "3b3dda58.52b91975.02dfde15"
+        );
+        assert_eq!(fingerprint["serverIds"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn fingerprint_covers_server_ids() {
+        let staging = &STAGING.svr3;
+        let other_ids = Svr3Env::custom(staging.sgx(), staging.nitro(), [2, 1]);
+        assert_ne!(staging.fingerprint().hash(), other_ids.fingerprint().hash());
+        assert_eq!(staging.fingerprint().sgx, other_ids.fingerprint().sgx);
+    }
+
+    #[test]
+    fn fingerprint_covers_path_prefix_and_route_weights() {
+        let staging = &STAGING.svr3;
+        let with_nitro_config = |domain_config| {
+            let nitro = EnclaveEndpoint {
+                domain_config,
+                ..staging.nitro()
+            };
+            Svr3Env::custom(staging.sgx(), nitro, [1, 2]).fingerprint()
+        };
+        let nitro_config = staging.nitro().domain_config;
+
+        let prefixed = with_nitro_config(DomainConfig {
+            path_prefix: Some(PathPrefix::from_static("/svr3")),
+            ..nitro_config
+        });
+        let weighted = with_nitro_config(DomainConfig {
+            route_weights: Some(&[9, 1]),
+            ..nitro_config
+        });
+        let hashes = [
+            staging.fingerprint().hash().to_owned(),
+            prefixed.hash().to_owned(),
+            weighted.hash().to_owned(),
+        ];
+        assert_ne!(hashes[0], hashes[1]);
+        assert_ne!(hashes[0], hashes[2]);
+        assert_ne!(hashes[1], hashes[2]);
+        assert_ne!(staging.fingerprint().nitro, prefixed.nitro);
+        assert_eq!(staging.fingerprint().sgx, prefixed.sgx);
+
+        let prefixed = serde_json::to_value(prefixed).expect("serializes");
+        assert_eq!(prefixed["nitro"]["pathPrefix"], "/svr3");
+        assert_eq!(prefixed["sgx"]["pathPrefix"], serde_json::Value::Null);
+        let weighted = serde_json::to_value(weighted).expect("serializes");
+        assert_eq!(weighted["nitro"]["routeWeights"], serde_json::json!([9, 1]));
+    }
+}
@@ -0,0 +1,235 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Stable numeric codes for [`NetError`] and [`svr::Error`].
+//!
+//! Every variant of both types has a `u32` code from one flat namespace, so
+//! the app languages can share a single error enumeration, and the constants
+//! below are exported to `signal_ffi.h` as they are, with a `Signal` prefix
+//! (`SignalNET_CERT_ERROR`, and so on). Codes are never
+//! renumbered or reused: new variants get new codes, and the codes of removed
+//! variants stay retired. `0` is never an error code.
+//!
+//! | Code | Error |
+//! |-----:|-------|
+//! |    1 | [`NetError::CertError`] |
+//! |    2 | [`NetError::DnsError`] |
+//! |    3 | [`NetError::TcpConnectionFailed`] |
+//! |    4 | [`NetError::SslError`] |
+//! |    5 | [`NetError::SslFailedHandshake`] |
+//! |    6 | [`NetError::ContentLengthHeaderInvalid`] |
+//! |    7 | [`NetError::ContentLengthHeaderDoesntMatchDataSize`] |
+//! |    8 | [`NetError::Http2FailedHandshake`] |
+//! |    9 | [`NetError::Timeout`] |
+//! |   10 | [`NetError::ConnectTimeout`] |
+//! |   11 | [`NetError::Failure`] |
+//! |   12 | [`NetError::Io`] |
+//! |   13 | [`NetError::IncomingDataInvalid`] |
+//! |   14 | [`NetError::RequestHasInvalidHeader`] |
+//! |   15 | [`NetError::UnexpectedFrameReceived`] |
+//! |   16 | [`NetError::ChannelClosed`] |
+//! |   17 | [`NetError::WebSocketError`] |
+//! |   18 | [`NetError::ChannelClosedWithError`] |
+//! |   19 | [`NetError::ChannelClosedByRemotePeer`] |
+//! |   20 | [`NetError::ChannelClosedByLocalPeer`] |
+//! |   21 | [`NetError::ChannelIdle`] |
+//! |   22 | [`NetError::NoServiceConnection`] |
+//! |   23 | [`NetError::ServerRequestMissingId`] |
+//! |   24 | [`NetError::FailedToPassMessageToIncomingChannel`] |
+//! |   25 | [`NetError::HttpInterruptedDuringReceive`] |
+//! |   26 | [`NetError::InvalidHttpRequestComponent`] |
+//! |   27 | [`NetError::RateLimited`] |
+//! |   28 | [`NetError::AllRoutesFailed`] |
+//! |   29 | [`NetError::SendTimeout`] |
+//! |   30 | [`NetError::ClientDeprecated`] |
+//! |  101 | [`svr::Error::Protocol`] |
+//! |  102 | [`svr::Error::AttestationError`] |
+//! |  103 | [`svr::Error::NetworkChanged`] |
+//! |  104 | [`svr::Error::Cancelled`] |
+//!
+//! [`svr::Error::Net`] has the code of the [`NetError`] it wraps.
+
+use crate::infra::errors::NetError;
+use crate::svr;
+
+pub const NET_CERT_ERROR: u32 = 1;
+pub const NET_DNS_ERROR: u32 = 2;
+pub const NET_TCP_CONNECTION_FAILED: u32 = 3;
+pub const NET_SSL_ERROR: u32 = 4;
+pub const NET_SSL_FAILED_HANDSHAKE: u32 = 5;
+pub const NET_CONTENT_LENGTH_HEADER_INVALID: u32 = 6;
+pub const NET_CONTENT_LENGTH_HEADER_DOESNT_MATCH_DATA_SIZE: u32 = 7;
+pub const NET_HTTP2_FAILED_HANDSHAKE: u32 = 8;
+pub const NET_TIMEOUT: u32 = 9;
+pub const NET_CONNECT_TIMEOUT: u32 = 10;
+pub const NET_FAILURE: u32 = 11;
+pub const NET_IO: u32 = 12;
+pub const NET_INCOMING_DATA_INVALID: u32 = 13;
+pub const NET_REQUEST_HAS_INVALID_HEADER: u32 = 14;
+pub const NET_UNEXPECTED_FRAME_RECEIVED: u32 = 15;
+pub const NET_CHANNEL_CLOSED: u32 = 16;
+pub const NET_WEBSOCKET_ERROR: u32 = 17;
+pub const NET_CHANNEL_CLOSED_WITH_ERROR: u32 = 18;
+pub const NET_CHANNEL_CLOSED_BY_REMOTE_PEER: u32 = 19;
+pub const NET_CHANNEL_CLOSED_BY_LOCAL_PEER: u32 = 20;
+pub const NET_CHANNEL_IDLE: u32 = 21;
+pub const NET_NO_SERVICE_CONNECTION: u32 = 22;
+pub const NET_SERVER_REQUEST_MISSING_ID: u32 = 23;
+pub const NET_FAILED_TO_PASS_MESSAGE_TO_INCOMING_CHANNEL: u32 = 24;
+pub const NET_HTTP_INTERRUPTED_DURING_RECEIVE: u32 = 25;
+pub const NET_INVALID_HTTP_REQUEST_COMPONENT: u32 = 26;
+pub const NET_RATE_LIMITED: u32 = 27;
+pub const NET_ALL_ROUTES_FAILED: u32 = 28;
+pub const NET_SEND_TIMEOUT: u32 = 29;
+pub const NET_CLIENT_DEPRECATED: u32 = 30;
+
+pub const SVR_PROTOCOL: u32 = 101;
+pub const SVR_ATTESTATION_ERROR: u32 = 102;
+pub const SVR_NETWORK_CHANGED: u32 = 103;
+pub const SVR_CANCELLED: u32 = 104;
+
+/// The stable code for `error`; see the [module docs](self).
+pub fn for_net_error(error: &NetError) -> u32 {
+    match error {
+        NetError::CertError => NET_CERT_ERROR,
+        NetError::DnsError => NET_DNS_ERROR,
+        NetError::TcpConnectionFailed(_) => NET_TCP_CONNECTION_FAILED,
+        NetError::SslError => NET_SSL_ERROR,
+        NetError::SslFailedHandshake => NET_SSL_FAILED_HANDSHAKE,
+        NetError::ContentLengthHeaderInvalid => NET_CONTENT_LENGTH_HEADER_INVALID,
+        NetError::ContentLengthHeaderDoesntMatchDataSize => {
+            NET_CONTENT_LENGTH_HEADER_DOESNT_MATCH_DATA_SIZE
+        }
+        NetError::Http2FailedHandshake => NET_HTTP2_FAILED_HANDSHAKE,
+        NetError::Timeout => NET_TIMEOUT,
+        NetError::ConnectTimeout(_) => NET_CONNECT_TIMEOUT,
+        NetError::Failure => NET_FAILURE,
+        NetError::Io(_) => NET_IO,
+        NetError::IncomingDataInvalid => NET_INCOMING_DATA_INVALID,
+        NetError::RequestHasInvalidHeader => NET_REQUEST_HAS_INVALID_HEADER,
+        NetError::UnexpectedFrameReceived => NET_UNEXPECTED_FRAME_RECEIVED,
+        NetError::ChannelClosed => NET_CHANNEL_CLOSED,
+        NetError::WebSocketError(_) => NET_WEBSOCKET_ERROR,
+        NetError::ChannelClosedWithError => NET_CHANNEL_CLOSED_WITH_ERROR,
+        NetError::ChannelClosedByRemotePeer => NET_CHANNEL_CLOSED_BY_REMOTE_PEER,
+        NetError::ChannelClosedByLocalPeer => NET_CHANNEL_CLOSED_BY_LOCAL_PEER,
+        NetError::ChannelIdle => NET_CHANNEL_IDLE,
+        NetError::NoServiceConnection => NET_NO_SERVICE_CONNECTION,
+        NetError::ServerRequestMissingId => NET_SERVER_REQUEST_MISSING_ID,
+        NetError::FailedToPassMessageToIncomingChannel => {
+            NET_FAILED_TO_PASS_MESSAGE_TO_INCOMING_CHANNEL
+        }
+        NetError::HttpInterruptedDuringReceive => NET_HTTP_INTERRUPTED_DURING_RECEIVE,
+        NetError::InvalidHttpRequestComponent => NET_INVALID_HTTP_REQUEST_COMPONENT,
+        NetError::RateLimited { .. } => NET_RATE_LIMITED,
+        NetError::AllRoutesFailed { .. } => NET_ALL_ROUTES_FAILED,
+        NetError::SendTimeout => NET_SEND_TIMEOUT,
+        NetError::ClientDeprecated => NET_CLIENT_DEPRECATED,
+    }
+}
+
+/// The stable code for `error`; see the [module docs](self).
+pub fn for_svr_error(error: &svr::Error) -> u32 {
+    match error {
+        svr::Error::Net(net) => for_net_error(net),
+        svr::Error::Protocol => SVR_PROTOCOL,
+        svr::Error::AttestationError(_) => SVR_ATTESTATION_ERROR,
+        svr::Error::NetworkChanged => SVR_NETWORK_CHANGED,
+        svr::Error::Cancelled => SVR_CANCELLED,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io;
+
+    use super::*;
+    use crate::infra::connection_manager::RouteAttempts;
+    use crate::infra::errors::ConnectPhase;
+    use crate::infra::ws;
+
+    /// One error of every variant, with the code it must keep.
+    fn every_variant() -> Vec<(svr::Error, u32)> {
+        let net = [
+            (NetError::CertError, 1),
+            (NetError::DnsError, 2),
+            (NetError::TcpConnectionFailed(io::ErrorKind::TimedOut), 3),
+            (NetError::SslError, 4),
+            (NetError::SslFailedHandshake, 5),
+            (NetError::ContentLengthHeaderInvalid, 6),
+            (NetError::ContentLengthHeaderDoesntMatchDataSize, 7),
+            (NetError::Http2FailedHandshake, 8),
+            (NetError::Timeout, 9),
+            (NetError::ConnectTimeout(ConnectPhase::Dns), 10),
+            (NetError::Failure, 11),
+            (NetError::Io(io::ErrorKind::UnexpectedEof), 12),
+            (NetError::IncomingDataInvalid, 13),
+            (NetError::RequestHasInvalidHeader, 14),
+            (NetError::UnexpectedFrameReceived, 15),
+            (NetError::ChannelClosed, 16),
+            (NetError::WebSocketError(ws::Error::Closed), 17),
+            (NetError::ChannelClosedWithError, 18),
+            (NetError::ChannelClosedByRemotePeer, 19),
+            (NetError::ChannelClosedByLocalPeer, 20),
+            (NetError::ChannelIdle, 21),
+            (NetError::NoServiceConnection, 22),
+            (NetError::ServerRequestMissingId, 23),
+            (NetError::FailedToPassMessageToIncomingChannel, 24),
+            (NetError::HttpInterruptedDuringReceive, 25),
+            (NetError::InvalidHttpRequestComponent, 26),
+            (
+                NetError::RateLimited {
+                    retry_after_seconds: 1,
+                },
+                27,
+            ),
+            (
+                NetError::AllRoutesFailed {
+                    attempts: RouteAttempts::default(),
+                },
+                28,
+            ),
+            (NetError::SendTimeout, 29),
+            (NetError::ClientDeprecated, 30),
+        ];
+        let svr = [
+            (svr::Error::Protocol, 101),
+            (
+                svr::Error::AttestationError(attest::enclave::Error::AttestationDataError {
+                    reason: "invalid".to_string(),
+                }),
+                102,
+            ),
+            (svr::Error::NetworkChanged, 103),
+            (svr::Error::Cancelled, 104),
+        ];
+        net.into_iter()
+            .map(|(error, code)| (svr::Error::Net(error), code))
+            .chain(svr)
+            .collect()
+    }
+
+    #[test]
+    fn codes_are_stable() {
+        for (error, code) in every_variant() {
+            assert_eq!(for_svr_error(&error), code, "{error:?}");
+            if let svr::Error::Net(net) = &error {
+                assert_eq!(for_net_error(net), code, "{net:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        let variants = every_variant();
+        let codes: HashSet<_> = variants
+            .iter()
+            .map(|(error, _)| for_svr_error(error))
+            .collect();
+        assert_eq!(codes.len(), variants.len());
+        assert!(!codes.contains(&0));
+    }
+}
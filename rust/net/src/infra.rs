@@ -3,36 +3,45 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::string::ToString;
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::env::{WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME};
+use crate::env::{
+    WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME, WS_MAX_QUEUED_SEND_BYTES, WS_SEND_STALL_TIMEOUT,
+};
 use ::http::uri::PathAndQuery;
 use ::http::Uri;
 use async_trait::async_trait;
 use boring::ssl::{SslConnector, SslConnectorBuilder, SslMethod};
+use futures_util::future::select_ok;
 use futures_util::TryFutureExt;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_boring::SslStream;
 
 use crate::infra::certs::RootCertificates;
 use crate::infra::connection_manager::{
-    MultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
+    MultiRouteConnectionManager, RouteSelectionPolicy, SingleRouteThrottlingConnectionManager,
 };
-use crate::infra::dns::DnsResolver;
-use crate::infra::errors::NetError;
+use crate::infra::correlation::CorrelationId;
+use crate::infra::dns::{DnsResolver, LookupResult};
+use crate::infra::errors::{ConnectPhase, LogSafeDisplay, NetError};
 use crate::infra::ws::WebSocketConfig;
-use crate::utils::first_ok;
+use crate::utils::timeout;
 
 pub mod certs;
+pub mod clock;
 pub mod connection_manager;
+pub mod correlation;
 pub mod dns;
 pub mod errors;
 pub(crate) mod http;
+pub mod network_state;
 pub(crate) mod reconnect;
 pub(crate) mod tokio_executor;
 pub(crate) mod tokio_io;
@@ -41,7 +50,7 @@ pub mod ws;
 const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(200);
 
 /// A collection of commonly used decorators for HTTP requests.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub enum HttpRequestDecorator {
     /// Adds the following header to the request:
     /// ```text
@@ -52,6 +61,23 @@ pub enum HttpRequestDecorator {
     PathPrefix(&'static str),
     /// Applies generic decoration logic.
     Generic(fn(hyper::http::request::Builder) -> hyper::http::request::Builder),
+    /// Adds the following header to the request:
+    /// ```text
+    /// x-correlation-id: <id>
+    /// ```
+    CorrelationId(CorrelationId),
+}
+
+// Hand-written so that credentials don't end up in logs.
+impl fmt::Debug for HttpRequestDecorator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeaderAuth(_) => f.debug_tuple("HeaderAuth").field(&"***").finish(),
+            Self::PathPrefix(prefix) => f.debug_tuple("PathPrefix").field(prefix).finish(),
+            Self::Generic(decorator) => f.debug_tuple("Generic").field(decorator).finish(),
+            Self::CorrelationId(id) => f.debug_tuple("CorrelationId").field(id).finish(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -69,16 +95,62 @@ impl From<HttpRequestDecorator> for HttpRequestDecoratorSeq {
 /// - `port` to connect to,
 /// - `http_request_decorator`, a [HttpRequestDecorator] to apply to all HTTP requests,
 /// - `certs`, [RootCertificates] representing trusted certificates,
-/// - `dns_resolver`, a [DnsResolver] to use when resolving DNS.
+/// - `dns_resolver`, a [DnsResolver] to use when resolving DNS,
+/// - `connect_timeouts`, the [ConnectTimeouts] for the phases up to the transport connection.
 /// This is also applicable to WebSocket connections (in this case, `http_request_decorator` will
 /// only be applied to the initial connection upgrade request).
-#[derive(Clone, Debug)]
+///
+/// The serialized form only has `sni`, `host`, `port`, and `certs`; decorators and timeouts are
+/// added by the code making the connection and are not part of the configuration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "ConnectionParamsRepr", from = "ConnectionParamsRepr")]
 pub struct ConnectionParams {
     pub sni: Arc<str>,
     pub host: Arc<str>,
     pub port: u16,
     pub http_request_decorator: HttpRequestDecoratorSeq,
     pub certs: RootCertificates,
+    pub connect_timeouts: ConnectTimeouts,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConnectionParamsRepr {
+    sni: String,
+    host: String,
+    port: u16,
+    #[serde(default)]
+    certs: RootCertificates,
+}
+
+impl From<ConnectionParams> for ConnectionParamsRepr {
+    fn from(value: ConnectionParams) -> Self {
+        let ConnectionParams {
+            sni,
+            host,
+            port,
+            http_request_decorator: _,
+            certs,
+            connect_timeouts: _,
+        } = value;
+        Self {
+            sni: sni.to_string(),
+            host: host.to_string(),
+            port,
+            certs,
+        }
+    }
+}
+
+impl From<ConnectionParamsRepr> for ConnectionParams {
+    fn from(value: ConnectionParamsRepr) -> Self {
+        let ConnectionParamsRepr {
+            sni,
+            host,
+            port,
+            certs,
+        } = value;
+        Self::new(&sni, &host, port, HttpRequestDecoratorSeq::default(), certs)
+    }
 }
 
 impl ConnectionParams {
@@ -95,6 +167,7 @@ impl ConnectionParams {
             port,
             http_request_decorator,
             certs,
+            connect_timeouts: ConnectTimeouts::UNLIMITED,
         }
     }
 
@@ -108,8 +181,101 @@ impl ConnectionParams {
         self.certs = certs;
         self
     }
+
+    pub fn with_connect_timeouts(mut self, connect_timeouts: ConnectTimeouts) -> Self {
+        self.connect_timeouts = connect_timeouts;
+        self
+    }
+
+    /// Describes the route for logging, with any credentials replaced by `***`.
+    pub fn masked_display(&self) -> MaskedDisplay {
+        let Self {
+            sni,
+            host,
+            port,
+            http_request_decorator: HttpRequestDecoratorSeq(decorators),
+            certs: _,
+            connect_timeouts: _,
+        } = self;
+        let mut details = vec![];
+        if sni != host {
+            details.push(format!("sni: {sni}"));
+        }
+        details.extend(decorators.iter().map(|decorator| match decorator {
+            HttpRequestDecorator::HeaderAuth(_) => "auth: ***".to_string(),
+            HttpRequestDecorator::PathPrefix(prefix) => format!("path prefix: {prefix}"),
+            HttpRequestDecorator::Generic(_) => "custom decorator".to_string(),
+            // Left out, so that routes are described the same for every
+            // operation.
+            HttpRequestDecorator::CorrelationId(_) => "correlation id".to_string(),
+        }));
+        let mut display = format!("https://{host}:{port}");
+        if !details.is_empty() {
+            display.push_str(&format!(" ({})", details.join(", ")));
+        }
+        MaskedDisplay(display)
+    }
+}
+
+/// Limits on how long each phase of making a connection may take.
+///
+/// A phase that runs out of time fails the attempt with
+/// [`NetError::ConnectTimeout`], naming the phase.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectTimeouts {
+    /// Resolving the host name.
+    pub dns: Duration,
+    /// Making the TCP connection and the TLS handshake.
+    pub transport: Duration,
+    /// The websocket upgrade request.
+    pub ws_upgrade: Duration,
+    /// The attestation handshake, for connections to enclaves.
+    pub attestation: Duration,
+    /// Establishing the websocket as a whole, across all phases before
+    /// attestation and all the routes tried.
+    pub websocket: Duration,
+}
+
+impl ConnectTimeouts {
+    /// No limits on the individual phases.
+    pub const UNLIMITED: Self = Self::from_total(Duration::MAX);
+
+    /// Allows every phase, and the websocket connection as a whole, up to
+    /// `total`.
+    ///
+    /// This is how the single connect timeout the endpoint connections used
+    /// to take was applied.
+    pub const fn from_total(total: Duration) -> Self {
+        Self {
+            dns: total,
+            transport: total,
+            ws_upgrade: total,
+            attestation: total,
+            websocket: total,
+        }
+    }
 }
 
+impl From<Duration> for ConnectTimeouts {
+    fn from(total: Duration) -> Self {
+        Self::from_total(total)
+    }
+}
+
+/// Log-safe description of a [`ConnectionParams`] route.
+///
+/// This is intentionally not a URL and can't be used to connect to anything.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MaskedDisplay(String);
+
+impl fmt::Display for MaskedDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl LogSafeDisplay for MaskedDisplay {}
+
 impl HttpRequestDecoratorSeq {
     pub fn decorate_request(
         &self,
@@ -129,6 +295,9 @@ impl HttpRequestDecorator {
         match self {
             Self::Generic(decorator) => decorator(request_builder),
             Self::HeaderAuth(auth) => request_builder.header(::http::header::AUTHORIZATION, auth),
+            Self::CorrelationId(id) => {
+                request_builder.header(correlation::CORRELATION_ID_HEADER, id.to_string())
+            }
             Self::PathPrefix(prefix) => {
                 let uri = request_builder.uri_ref().expect("request has URI set");
                 let mut parts = (*uri).clone().into_parts();
@@ -151,6 +320,12 @@ pub trait AsyncDuplexStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
 
 impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncDuplexStream for S {}
 
+/// Makes the encrypted byte streams that connections run on.
+///
+/// The stream must already be secured with TLS, negotiating `alpn`. HTTP
+/// requests and the websocket upgrade are made over it by this crate, so a
+/// connection that is already a websocket, such as one opened through a
+/// browser's WebSocket API, can't serve as a transport.
 #[async_trait]
 pub trait TransportConnector: Clone + Send + Sync {
     type Stream: AsyncDuplexStream + 'static;
@@ -162,6 +337,21 @@ pub trait TransportConnector: Clone + Send + Sync {
     ) -> Result<StreamAndHost<Self::Stream>, NetError>;
 }
 
+/// Makes plain TCP connections, without TLS.
+///
+/// This is only useful for checking that a host can be reached at all; see
+/// [`Svr3Env::assert_reachable`](crate::env::Svr3Env::assert_reachable).
+#[async_trait]
+pub trait TcpConnector: Clone + Send + Sync {
+    async fn connect_tcp(&self, host: &str, port: u16) -> Result<TcpStream, NetError>;
+}
+
+/// Connects over TCP with TLS, resolving hosts with a [`DnsResolver`].
+///
+/// Clones share the resolver, and with it any answers it caches, so one
+/// connector can be cloned for each connection instead of being created
+/// anew. Connections made through clones are independent of each other, and
+/// any number of them can be in progress at once.
 #[derive(Clone)]
 pub struct TcpSslTransportConnector {
     dns_resolver: Arc<DnsResolver>,
@@ -176,40 +366,74 @@ impl TransportConnector for TcpSslTransportConnector {
         connection_params: &ConnectionParams,
         alpn: &[u8],
     ) -> Result<StreamAndHost<Self::Stream>, NetError> {
-        let StreamAndHost(tcp_stream, remote_address) = connect_tcp(
-            &self.dns_resolver,
-            &connection_params.sni,
-            connection_params.port,
+        let ConnectTimeouts { dns, transport, .. } = connection_params.connect_timeouts;
+        let dns_lookup = timeout(
+            dns,
+            NetError::ConnectTimeout(ConnectPhase::Dns),
+            resolve(&self.dns_resolver, &connection_params.sni),
         )
         .await?;
 
-        let ssl_config = Self::builder(connection_params.certs, alpn)?
-            .build()
-            .configure()?;
+        timeout(
+            transport,
+            NetError::ConnectTimeout(ConnectPhase::Transport),
+            async {
+                let StreamAndHost(tcp_stream, remote_address) =
+                    connect_tcp(dns_lookup, connection_params.port).await?;
 
-        let ssl_stream = tokio_boring::connect(ssl_config, &connection_params.sni, tcp_stream)
-            .await
-            .map_err(|_| NetError::SslFailedHandshake)?;
+                let ssl_config = Self::builder(connection_params.certs, alpn)?
+                    .build()
+                    .configure()?;
+
+                let ssl_stream =
+                    tokio_boring::connect(ssl_config, &connection_params.sni, tcp_stream)
+                        .await
+                        .map_err(|_| NetError::SslFailedHandshake)?;
+
+                Ok(StreamAndHost(ssl_stream, remote_address))
+            },
+        )
+        .await
+    }
+}
 
-        Ok(StreamAndHost(ssl_stream, remote_address))
+#[async_trait]
+impl TcpConnector for TcpSslTransportConnector {
+    async fn connect_tcp(&self, host: &str, port: u16) -> Result<TcpStream, NetError> {
+        let dns_lookup = resolve(&self.dns_resolver, host).await?;
+        let StreamAndHost(tcp_stream, _remote_address) = connect_tcp(dns_lookup, port).await?;
+        Ok(tcp_stream)
     }
 }
 
 impl TcpSslTransportConnector {
     pub fn new(resolver: DnsResolver) -> Self {
+        Self::new_with_shared_resolver(Arc::new(resolver))
+    }
+
+    /// Like [`Self::new`], for a resolver that is also used elsewhere.
+    pub fn new_with_shared_resolver(resolver: Arc<DnsResolver>) -> Self {
         Self {
-            dns_resolver: Arc::new(resolver),
+            dns_resolver: resolver,
         }
     }
 
+    pub fn dns_resolver(&self) -> &Arc<DnsResolver> {
+        &self.dns_resolver
+    }
+
     fn builder(certs: RootCertificates, alpn: &[u8]) -> Result<SslConnectorBuilder, NetError> {
         let mut ssl = SslConnector::builder(SslMethod::tls_client())?;
+        if matches!(certs, RootCertificates::InsecureSkipVerification(_)) {
+            ssl.set_verify(boring::ssl::SslVerifyMode::NONE);
+        }
         ssl.set_verify_cert_store(certs.try_into()?)?;
         ssl.set_alpn_protos(alpn)?;
         Ok(ssl)
     }
 }
 
+#[derive(Clone)]
 pub struct EndpointConnection<C> {
     pub manager: C,
     pub config: WebSocketConfig,
@@ -234,6 +458,37 @@ impl EndpointConnection<MultiRouteConnectionManager> {
             config,
         }
     }
+
+    /// Picks the route to try first according to `policy`.
+    pub fn with_route_selection(mut self, policy: RouteSelectionPolicy) -> Self {
+        self.manager = self.manager.with_route_selection(policy);
+        self
+    }
+}
+
+impl<C> EndpointConnection<C> {
+    /// Replaces the websocket configuration used for new connections.
+    pub fn with_custom_ws_config(mut self, config: WebSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Changes part of the websocket configuration used for new connections,
+    /// leaving the rest as it was.
+    ///
+    /// ```ignore
+    /// let connection = connection.with_ws_config_override(|mut config| {
+    ///     config.ws_config.max_message_size = Some(1 << 20);
+    ///     config
+    /// });
+    /// ```
+    pub fn with_ws_config_override(
+        mut self,
+        f: impl FnOnce(WebSocketConfig) -> WebSocketConfig,
+    ) -> Self {
+        self.config = f(self.config);
+        self
+    }
 }
 
 pub fn make_ws_config(
@@ -246,14 +501,12 @@ pub fn make_ws_config(
         max_connection_time: connect_timeout,
         keep_alive_interval: WS_KEEP_ALIVE_INTERVAL,
         max_idle_time: WS_MAX_IDLE_TIME,
+        max_queued_send_bytes: WS_MAX_QUEUED_SEND_BYTES,
+        send_stall_timeout: WS_SEND_STALL_TIMEOUT,
     }
 }
 
-pub(crate) async fn connect_tcp(
-    dns_resolver: &DnsResolver,
-    host: &str,
-    port: u16,
-) -> Result<StreamAndHost<TcpStream>, NetError> {
+async fn resolve(dns_resolver: &DnsResolver, host: &str) -> Result<LookupResult, NetError> {
     let dns_lookup = dns_resolver
         .lookup_ip(host)
         .await
@@ -262,7 +515,13 @@ pub(crate) async fn connect_tcp(
     if dns_lookup.is_empty() {
         return Err(NetError::DnsError);
     }
+    Ok(dns_lookup)
+}
 
+async fn connect_tcp(
+    dns_lookup: LookupResult,
+    port: u16,
+) -> Result<StreamAndHost<TcpStream>, NetError> {
     // The idea is to go through the list of candidate IP addresses
     // and to attempt a connection to each of them, giving each one a `CONNECTION_ATTEMPT_DELAY` headstart
     // before moving on to the next candidate.
@@ -273,7 +532,7 @@ pub(crate) async fn connect_tcp(
     // This way we can start all futures at once and simply wait for the first one to complete successfully.
     let staggered_futures = dns_lookup.into_iter().enumerate().map(|(idx, ip)| {
         let delay = CONNECTION_ATTEMPT_DELAY * idx.try_into().unwrap();
-        async move {
+        Box::pin(async move {
             if !delay.is_zero() {
                 tokio::time::sleep(delay).await;
             }
@@ -283,12 +542,16 @@ pub(crate) async fn connect_tcp(
                 })
                 .await
                 .map(|r| StreamAndHost(r, ip_addr_to_host(ip)))
-        }
+        })
     });
 
-    first_ok(staggered_futures)
+    // If every attempt fails, the error from the last one to fail is reported.
+    // `select_ok` panics when given no futures, but `resolve` never produces
+    // an empty lookup.
+    select_ok(staggered_futures)
         .await
-        .ok_or(NetError::TcpConnectionFailed)
+        .map(|(stream, _remaining)| stream)
+        .map_err(|e| NetError::TcpConnectionFailed(e.kind()))
 }
 
 fn ip_addr_to_host(ip: IpAddr) -> url::Host {
@@ -300,9 +563,27 @@ fn ip_addr_to_host(ip: IpAddr) -> url::Host {
 
 #[cfg(test)]
 pub(crate) mod test {
+    use std::time::Duration;
+
+    use ::http::uri::PathAndQuery;
+    use assert_matches::assert_matches;
+    use futures_util::{SinkExt as _, StreamExt as _};
     use hyper::Request;
+    use warp::Filter as _;
 
-    use crate::infra::HttpRequestDecorator;
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::connection_manager::MultiRouteConnectionManager;
+    use crate::infra::correlation::{CorrelationId, CORRELATION_ID_HEADER};
+    use crate::infra::dns::DnsResolver;
+    use crate::infra::errors::{ConnectPhase, NetError};
+    use crate::infra::reconnect::ServiceConnector as _;
+    use crate::infra::test::shared::{InMemoryWarpConnector, TIMEOUT_DURATION};
+    use crate::infra::ws::{NextOrClose, WebSocketClientConnector, WebSocketConfig};
+    use crate::infra::{
+        make_ws_config, ConnectTimeouts, ConnectionParams, EndpointConnection,
+        HttpRequestDecorator, HttpRequestDecoratorSeq, TcpConnector as _, TcpSslTransportConnector,
+        TransportConnector as _,
+    };
     use crate::utils::basic_authorization;
 
     pub(crate) mod shared {
@@ -318,7 +599,7 @@ pub(crate) mod test {
         use warp::{Filter, Reply};
 
         use crate::infra::connection_manager::ConnectionManager;
-        use crate::infra::errors::{LogSafeDisplay, NetError};
+        use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
         use crate::infra::reconnect::{
             ServiceConnector, ServiceInitializer, ServiceState, ServiceStatus,
         };
@@ -334,6 +615,8 @@ pub(crate) mod test {
 
         impl LogSafeDisplay for TestError {}
 
+        impl RetryLater for TestError {}
+
         // the choice of the constant value is dictated by a vague notion of being
         // "not too many, but also not just once or twice"
         pub(crate) const FEW_ATTEMPTS: u16 = 3;
@@ -399,7 +682,7 @@ pub(crate) mod test {
             C: ServiceConnector + Send + Sync + 'static,
             C::Service: Clone + Send + Sync + 'static,
             C::Channel: Send + Sync,
-            C::Error: Send + Sync + Debug + LogSafeDisplay,
+            C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
         {
             pub async fn start<M>(service_connector: C, connection_manager: M) -> Self
             where
@@ -450,4 +733,302 @@ pub(crate) mod test {
             parts.headers.get(http::header::AUTHORIZATION).unwrap()
         );
     }
+
+    #[test]
+    fn correlation_id_decorator() {
+        let id = CorrelationId::random();
+        let builder = Request::get("https://chat.signal.org/");
+        let builder = HttpRequestDecorator::CorrelationId(id).decorate_request(builder);
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        assert_eq!(
+            id.to_string(),
+            parts.headers.get(CORRELATION_ID_HEADER).unwrap()
+        );
+    }
+
+    #[test]
+    fn masked_display_hides_credentials() {
+        let auth = basic_authorization("usrnm", "psswd");
+        let params = ConnectionParams::new(
+            "inbox.google.com",
+            "chat.signal.org",
+            443,
+            HttpRequestDecoratorSeq(vec![
+                HttpRequestDecorator::HeaderAuth(auth.clone()),
+                HttpRequestDecorator::PathPrefix("/service"),
+            ]),
+            RootCertificates::Native,
+        );
+
+        let displayed = params.masked_display().to_string();
+        assert_eq!(
+            displayed,
+            "https://chat.signal.org:443 (sni: inbox.google.com, auth: ***, path prefix: /service)"
+        );
+        assert!(!displayed.contains(&auth));
+
+        let debugged = format!("{params:?}");
+        assert!(debugged.contains("chat.signal.org"));
+        assert!(!debugged.contains(&auth));
+    }
+
+    #[test]
+    fn masked_display_without_details() {
+        let params = ConnectionParams::new(
+            "chat.signal.org",
+            "chat.signal.org",
+            443,
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::Native,
+        );
+        assert_eq!(
+            params.masked_display().to_string(),
+            "https://chat.signal.org:443"
+        );
+    }
+
+    fn endpoint_connection(
+        config: WebSocketConfig,
+    ) -> (
+        ConnectionParams,
+        EndpointConnection<MultiRouteConnectionManager>,
+    ) {
+        let params = ConnectionParams::new(
+            "chat.signal.org",
+            "chat.signal.org",
+            443,
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::Native,
+        );
+        let connection =
+            EndpointConnection::new_multi([params.clone()], Duration::from_secs(10), config);
+        (params, connection)
+    }
+
+    #[test]
+    fn custom_ws_config_replaces_default() {
+        let default_config =
+            make_ws_config(PathAndQuery::from_static("/v1"), Duration::from_secs(10));
+        let mut custom = make_ws_config(PathAndQuery::from_static("/v2"), Duration::from_secs(1));
+        custom.ws_config.max_message_size = Some(1024);
+        custom.max_idle_time = Duration::from_secs(1);
+
+        let (_, connection) = endpoint_connection(default_config);
+        let connection = connection.with_custom_ws_config(custom);
+        assert_eq!(connection.config.endpoint, "/v2");
+        assert_eq!(
+            connection.config.max_connection_time,
+            Duration::from_secs(1)
+        );
+        assert_eq!(connection.config.max_idle_time, Duration::from_secs(1));
+        assert_eq!(connection.config.ws_config.max_message_size, Some(1024));
+    }
+
+    #[tokio::test]
+    async fn ws_config_override_applies_to_new_connections() {
+        const MESSAGE_LEN: usize = 64;
+        let server = warp::ws().map(|ws: warp::ws::Ws| {
+            ws.on_upgrade(|mut socket| async move {
+                socket
+                    .send(warp::ws::Message::binary(vec![0; MESSAGE_LEN]))
+                    .await
+                    .expect("can send");
+                // Hold the connection open until the client hangs up.
+                while let Some(Ok(_)) = socket.next().await {}
+            })
+        });
+        let default_config =
+            make_ws_config(PathAndQuery::from_static("/"), Duration::from_secs(10));
+
+        let receive = |config: WebSocketConfig| {
+            let server = server.clone();
+            async move {
+                let (params, connection) = endpoint_connection(config);
+                let connector = WebSocketClientConnector::new(
+                    InMemoryWarpConnector::new(server),
+                    connection.config,
+                );
+                let channel = connector
+                    .connect_channel(&params)
+                    .await
+                    .expect("can connect");
+                let (mut client, _status) = connector.start_service(channel);
+                client.receive().await
+            }
+        };
+
+        // The message fits within the default limits...
+        let (_, connection) = endpoint_connection(default_config.clone());
+        assert_matches!(receive(connection.config).await, Ok(NextOrClose::Next(_)));
+
+        // ...but not within the overridden one.
+        let (_, connection) = endpoint_connection(default_config);
+        let connection = connection.with_ws_config_override(|mut config| {
+            config.ws_config.max_message_size = Some(MESSAGE_LEN / 2);
+            config
+        });
+        assert_eq!(
+            connection.config.ws_config.max_message_size,
+            Some(MESSAGE_LEN / 2)
+        );
+        assert_matches!(receive(connection.config).await, Err(_));
+    }
+
+    #[tokio::test]
+    async fn stalled_tls_handshake_times_out() {
+        // The kernel completes the TCP handshake for the listener, but nothing
+        // ever answers the TLS client hello.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("can bind");
+        let port = listener.local_addr().expect("bound").port();
+        let params = ConnectionParams::new(
+            "127.0.0.1",
+            "127.0.0.1",
+            port,
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::Signal,
+        )
+        .with_connect_timeouts(ConnectTimeouts {
+            transport: TIMEOUT_DURATION,
+            ..ConnectTimeouts::UNLIMITED
+        });
+
+        let connector = TcpSslTransportConnector::new(DnsResolver::default());
+        assert_matches!(
+            connector.connect(&params, b"").await,
+            Err(NetError::ConnectTimeout(ConnectPhase::Transport))
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_tcp_connection_keeps_error_kind() {
+        // Nothing listens on the port once the listener is gone.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("can bind");
+        let port = listener.local_addr().expect("bound").port();
+        drop(listener);
+
+        let connector = TcpSslTransportConnector::new(DnsResolver::default());
+        let error = connector
+            .connect_tcp("127.0.0.1", port)
+            .await
+            .expect_err("refused");
+        assert_matches!(
+            error,
+            NetError::TcpConnectionFailed(std::io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(
+            error.io_error_kind(),
+            Some(std::io::ErrorKind::ConnectionRefused)
+        );
+    }
+
+    #[tokio::test]
+    async fn stalled_websocket_upgrade_times_out() {
+        let server = warp::any().then(std::future::pending::<&'static str>);
+        let (params, connection) = endpoint_connection(make_ws_config(
+            PathAndQuery::from_static("/"),
+            Duration::from_secs(10),
+        ));
+        let params = params.with_connect_timeouts(ConnectTimeouts {
+            ws_upgrade: TIMEOUT_DURATION,
+            ..ConnectTimeouts::UNLIMITED
+        });
+
+        let connector =
+            WebSocketClientConnector::new(InMemoryWarpConnector::new(server), connection.config);
+        assert_matches!(
+            connector.connect_channel(&params).await,
+            Err(NetError::ConnectTimeout(ConnectPhase::WebSocketUpgrade))
+        );
+    }
+
+    #[tokio::test]
+    async fn deprecated_client_is_reported_on_upgrade_and_on_close() {
+        let (params, connection) = endpoint_connection(make_ws_config(
+            PathAndQuery::from_static("/"),
+            Duration::from_secs(10),
+        ));
+
+        // Refused before the upgrade...
+        let server = warp::any().map(|| {
+            warp::reply::with_status(
+                "",
+                warp::http::StatusCode::from_u16(499).expect("valid status"),
+            )
+        });
+        let connector = WebSocketClientConnector::new(
+            InMemoryWarpConnector::new(server),
+            connection.config.clone(),
+        );
+        assert_matches!(
+            connector.connect_channel(&params).await,
+            Err(NetError::ClientDeprecated)
+        );
+
+        // ...or hung up on afterwards.
+        let server = warp::ws().map(|ws: warp::ws::Ws| {
+            ws.on_upgrade(|mut socket| async move {
+                socket
+                    .send(warp::ws::Message::close_with(4499u16, "deprecated"))
+                    .await
+                    .expect("can send");
+            })
+        });
+        let connector =
+            WebSocketClientConnector::new(InMemoryWarpConnector::new(server), connection.config);
+        let channel = connector
+            .connect_channel(&params)
+            .await
+            .expect("can connect");
+        let (mut client, _status) = connector.start_service(channel);
+        assert_matches!(
+            client
+                .receive()
+                .await
+                .expect("closed cleanly")
+                .next_or_close_reason(NetError::Failure),
+            Err(NetError::ClientDeprecated)
+        );
+    }
+
+    #[test]
+    fn connect_timeouts_from_total() {
+        let total = Duration::from_secs(5);
+        assert_eq!(
+            ConnectTimeouts::from(total),
+            ConnectTimeouts {
+                dns: total,
+                transport: total,
+                ws_upgrade: total,
+                attestation: total,
+                websocket: total,
+            }
+        );
+    }
+
+    #[test]
+    fn connection_params_round_trip() {
+        const DER: &[u8] = &[0x30, 0x03, 0x02, 0x01, 0x01];
+        let params = ConnectionParams::new(
+            "inbox.google.com",
+            "chat.signal.org",
+            8443,
+            HttpRequestDecorator::PathPrefix("/service").into(),
+            RootCertificates::FromDer(DER),
+        );
+
+        let json = serde_json::to_string(&params).expect("can serialize");
+        let loaded: ConnectionParams = serde_json::from_str(&json).expect("can deserialize");
+
+        assert_eq!(&*loaded.sni, "inbox.google.com");
+        assert_eq!(&*loaded.host, "chat.signal.org");
+        assert_eq!(loaded.port, 8443);
+        assert!(matches!(loaded.certs, RootCertificates::FromDer(der) if der == DER));
+        // Decorators are not part of the configuration.
+        assert!(loaded.http_request_decorator.0.is_empty());
+        assert_eq!(serde_json::to_string(&loaded).expect("can serialize"), json);
+    }
 }
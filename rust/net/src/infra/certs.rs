@@ -3,12 +3,17 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use boring::error::ErrorStack;
 use boring::x509::store::{X509Store, X509StoreBuilder};
 use boring::x509::X509;
 
 use lazy_static::lazy_static;
 use rustls_native_certs::Certificate;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::infra::errors::LogSafeDisplay;
+use crate::utils::intern;
 
 lazy_static! {
     static ref NATIVE_CERTS: Vec<Certificate> =
@@ -23,21 +28,86 @@ pub enum Error {
     BadDer,
 }
 
+/// Never includes the certificate data.
+impl LogSafeDisplay for Error {}
+
 impl From<ErrorStack> for Error {
     fn from(_value: ErrorStack) -> Self {
         Self::BadDer
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+/// Serialized as `"native"`, `"signal"`, or `{ "from_der": "<base64>" }`.
+///
+/// [`RootCertificates::InsecureSkipVerification`] has no serialized form, so
+/// that no configuration can turn off certificate verification, and can only
+/// be made in test builds, from `RootCertificates::INSECURE_SKIP_VERIFICATION`.
+///
+/// Root certificates are expected to be loaded once for the lifetime of the
+/// program, so deserialized DER data is [interned](crate::utils::intern) to
+/// get a `'static` reference.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(try_from = "RootCertificatesRepr")]
 pub enum RootCertificates {
     #[default]
     Native,
     Signal,
     FromDer(&'static [u8]),
+    /// Accepts any server certificate; only for local test servers with
+    /// self-signed certificates.
+    InsecureSkipVerification(OnlyForTests),
+}
+
+/// Keeps [`RootCertificates::InsecureSkipVerification`] from being made
+/// outside of test builds, while leaving the shape of [`RootCertificates`] the
+/// same under every feature set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnlyForTests(());
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RootCertificatesRepr {
+    Native,
+    Signal,
+    FromDer(String),
+}
+
+impl Serialize for RootCertificates {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            Self::Native => RootCertificatesRepr::Native,
+            Self::Signal => RootCertificatesRepr::Signal,
+            Self::FromDer(der) => RootCertificatesRepr::FromDer(BASE64_STANDARD.encode(der)),
+            Self::InsecureSkipVerification(_) => {
+                return Err(serde::ser::Error::custom(
+                    "skipping certificate verification can't be serialized",
+                ))
+            }
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl TryFrom<RootCertificatesRepr> for RootCertificates {
+    type Error = base64::DecodeError;
+
+    fn try_from(value: RootCertificatesRepr) -> Result<Self, Self::Error> {
+        Ok(match value {
+            RootCertificatesRepr::Native => Self::Native,
+            RootCertificatesRepr::Signal => Self::Signal,
+            RootCertificatesRepr::FromDer(der) => {
+                Self::FromDer(intern(BASE64_STANDARD.decode(der)?.into_boxed_slice()))
+            }
+        })
+    }
 }
 
 impl RootCertificates {
+    /// Accepts any server certificate; see
+    /// [`RootCertificates::InsecureSkipVerification`].
+    #[cfg(any(test, feature = "test-support"))]
+    pub const INSECURE_SKIP_VERIFICATION: Self = Self::InsecureSkipVerification(OnlyForTests(()));
+
     fn load(&self) -> Result<Vec<X509>, Error> {
         fn from_der(der: &[u8]) -> Result<X509, Error> {
             X509::from_der(der).map_err(From::from)
@@ -49,6 +119,7 @@ impl RootCertificates {
             RootCertificates::Native => NATIVE_CERTS.iter().map(|cert| from_der(&cert.0)).collect(),
             RootCertificates::FromDer(der) => from_der(der).map(singleton),
             RootCertificates::Signal => from_der(SIGNAL_ROOT_CERT_DER).map(singleton),
+            RootCertificates::InsecureSkipVerification(_) => Ok(vec![]),
         }
     }
 }
@@ -64,3 +135,16 @@ impl TryInto<X509Store> for RootCertificates {
         Ok(store_builder.build())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skipping_verification_has_no_serialized_form() {
+        assert!(
+            serde_json::from_str::<RootCertificates>(r#""insecure_skip_verification""#).is_err()
+        );
+        assert!(serde_json::to_string(&RootCertificates::INSECURE_SKIP_VERIFICATION).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Source of the current time, replaceable in tests.
+//!
+//! Code that makes decisions based on the time of day (attestation validity)
+//! or on elapsed time (connection cooldowns) reads it from a [`Clock`], which
+//! is [`SystemClock`] unless a [`TestClock`] is swapped in. Timers such as
+//! timeouts still run on tokio's clock, which tests can control with
+//! `tokio::time::pause`.
+
+use std::fmt::Debug;
+use std::panic::RefUnwindSafe;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::Instant;
+
+pub trait Clock: Debug + Send + Sync + RefUnwindSafe {
+    /// The current wall-clock time.
+    fn now(&self) -> SystemTime;
+    /// The current monotonic time.
+    fn instant_now(&self) -> Instant;
+}
+
+/// The real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant_now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[derive(Debug)]
+pub struct TestClock {
+    times: Mutex<(SystemTime, Instant)>,
+}
+
+impl TestClock {
+    /// Creates a clock reading `start`. Its monotonic time starts at the
+    /// current [`Instant`].
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            times: Mutex::new((start, Instant::now())),
+        }
+    }
+
+    /// Moves both the wall-clock and the monotonic time forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut times = self.times.lock().expect("not poisoned");
+        times.0 += duration;
+        times.1 += duration;
+    }
+
+    /// Sets the wall-clock time, e.g. to simulate clock skew, leaving the
+    /// monotonic time alone.
+    pub fn set_now(&self, now: SystemTime) {
+        self.times.lock().expect("not poisoned").0 = now;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.times.lock().expect("not poisoned").0
+    }
+
+    fn instant_now(&self) -> Instant {
+        self.times.lock().expect("not poisoned").1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_only_moves_when_told() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = TestClock::new(start);
+        let instant = clock.instant_now();
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.instant_now(), instant);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+        assert_eq!(clock.instant_now(), instant + Duration::from_secs(5));
+
+        clock.set_now(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.instant_now(), instant + Duration::from_secs(5));
+    }
+}
@@ -4,22 +4,31 @@
 //
 
 use std::cmp::{max, min};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::future::Future;
-use std::ops::Add;
 use std::panic::RefUnwindSafe;
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use rand::distributions::{Distribution as _, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng as _;
 use tokio::sync::Mutex;
-use tokio::time::{timeout_at, Instant};
+use tokio::time::{timeout, timeout_at, Instant};
 
-use crate::infra::errors::LogSafeDisplay;
+use crate::infra::clock::{Clock, SystemClock};
+use crate::infra::errors::{ErrorCategory, LogSafeDisplay, RetryLater};
 use crate::infra::ConnectionParams;
 
 pub(crate) const MAX_COOLDOWN_INTERVAL: Duration = Duration::from_secs(64);
 
+/// The longest a server's `retry_after` hint can hold a route back.
+///
+/// The hint comes off the wire, so a bad one shouldn't make a route unusable
+/// for longer than this. An hour is well past any rate limit the servers set.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
 const COOLDOWN_INTERVALS: [Duration; 8] = [
     Duration::from_secs(0),
     Duration::from_secs(1),
@@ -45,20 +54,61 @@ pub enum ConnectionAttemptOutcome<T, E> {
     WaitUntil(Instant),
 }
 
+/// Outcome of [`SingleRouteThrottlingConnectionManager::connect_ignoring_cooldown`].
+///
+/// Unlike [`ConnectionAttemptOutcome`], each variant also says what became of
+/// the cooldown.
+#[derive(Debug)]
+pub enum ForcedAttemptOutcome<T, E> {
+    /// The attempt succeeded and ended any cooldown.
+    Connected(T),
+    /// The attempt failed; the cooldown is as it was before the attempt.
+    Failed(E),
+    /// The attempt timed out; the cooldown is as it was before the attempt.
+    TimedOut,
+}
+
 /// Encapsulates the logic that for every connection attempt decides
 /// whether or not an attempt is to be made in the first place, and, if yes,
 /// which [ConnectionParams] are to be used for the attempt.
+///
+/// `connection_fn` is given its own copy of the parameters, so that the routes
+/// of a manager can be replaced while attempts through them are in flight.
 #[async_trait]
 pub trait ConnectionManager: Clone + Send + Sync {
-    async fn connect_or_wait<'a, T, E, Fun, Fut>(
-        &'a self,
+    async fn connect_or_wait<T, E, Fun, Fut>(
+        &self,
         connection_fn: Fun,
     ) -> ConnectionAttemptOutcome<T, E>
     where
         T: Send,
-        E: Send + Debug + LogSafeDisplay,
-        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send;
+
+    /// Like [`Self::connect_or_wait`], but also adds to `failures` how each
+    /// route tried along the way failed.
+    ///
+    /// Only managers with several routes record anything: with a single route,
+    /// the outcome already says how it failed.
+    async fn connect_or_wait_recording<T, E, Fun, Fut>(
+        &self,
+        connection_fn: Fun,
+        failures: &mut Vec<RouteAttemptError>,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        let _ = failures;
+        self.connect_or_wait(connection_fn).await
+    }
+
+    /// Whether a route is cooling down after failing, so that attempts skip
+    /// it for now.
+    async fn is_any_route_cooling_down(&self) -> bool;
 }
 
 #[async_trait]
@@ -66,18 +116,159 @@ impl<C> ConnectionManager for &'_ C
 where
     C: ConnectionManager,
 {
-    async fn connect_or_wait<'a, T, E, Fun, Fut>(
-        &'a self,
+    async fn connect_or_wait<T, E, Fun, Fut>(
+        &self,
         connection_fn: Fun,
     ) -> ConnectionAttemptOutcome<T, E>
     where
         T: Send,
-        E: Send + Debug + LogSafeDisplay,
-        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send,
     {
         (*self).connect_or_wait(connection_fn).await
     }
+
+    async fn connect_or_wait_recording<T, E, Fun, Fut>(
+        &self,
+        connection_fn: Fun,
+        failures: &mut Vec<RouteAttemptError>,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        (*self)
+            .connect_or_wait_recording(connection_fn, failures)
+            .await
+    }
+
+    async fn is_any_route_cooling_down(&self) -> bool {
+        (*self).is_any_route_cooling_down().await
+    }
+}
+
+/// How one route failed during a connection attempt.
+///
+/// Everything in it is safe to log.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouteAttemptError {
+    /// Position of the route in the manager's order of preference.
+    pub route_index: usize,
+    pub failure: RouteFailure,
+    /// How long the route was tried for.
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RouteFailure {
+    /// The attempt failed with an error, kept as its category and log-safe
+    /// description.
+    Error {
+        category: ErrorCategory,
+        description: String,
+    },
+    /// The attempt took longer than the route's timeout.
+    TimedOut,
+}
+
+impl RouteFailure {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Error { category, .. } => *category,
+            Self::TimedOut => ErrorCategory::Timeout,
+        }
+    }
+}
+
+impl fmt::Display for RouteAttemptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            route_index,
+            failure,
+            duration,
+        } = self;
+        let millis = duration.as_millis();
+        match failure {
+            RouteFailure::Error { description, .. } => {
+                write!(
+                    f,
+                    "route {route_index} failed after {millis}ms: {description}"
+                )
+            }
+            RouteFailure::TimedOut => write!(f, "route {route_index} timed out after {millis}ms"),
+        }
+    }
+}
+
+impl LogSafeDisplay for RouteAttemptError {}
+
+/// The routes that failed during a connection attempt that found none to
+/// connect through, in the order they were tried.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RouteAttempts(pub Vec<RouteAttemptError>);
+
+impl RouteAttempts {
+    /// The category every route failed with, or [`ErrorCategory::Network`],
+    /// like the default of [`RetryLater::category`], if they failed in
+    /// different ways or none were recorded.
+    pub fn category(&self) -> ErrorCategory {
+        let mut categories = self.0.iter().map(|attempt| attempt.failure.category());
+        match categories.next() {
+            Some(first) if categories.all(|category| category == first) => first,
+            _ => ErrorCategory::Network,
+        }
+    }
+}
+
+/// All on one line, separated by semicolons.
+impl fmt::Display for RouteAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, attempt) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{attempt}")?;
+        }
+        Ok(())
+    }
+}
+
+impl LogSafeDisplay for RouteAttempts {}
+
+/// A point-in-time view of a route's state, for diagnostics.
+#[derive(Clone, Debug)]
+pub struct RouteHealth {
+    /// The route, as it is shown in logs.
+    pub route: String,
+    /// When the latest attempt that resulted in a connection ended.
+    pub last_success: Option<Instant>,
+    /// When the latest failed or timed out attempt ended.
+    pub last_failure: Option<Instant>,
+    /// How long the latest attempt that resulted in a connection took.
+    pub latest_latency: Option<Duration>,
+    /// Failures since the latest success, up to the length of the cooldown
+    /// schedule.
+    pub consecutive_failures: u16,
+    /// Time left until the route is tried again, zero if it can be tried right
+    /// away.
+    pub cooldown_remaining: Duration,
+}
+
+impl RouteHealth {
+    pub fn is_cooling_down(&self) -> bool {
+        !self.cooldown_remaining.is_zero()
+    }
+}
+
+/// Outcomes of past attempts, kept only to be reported in [`RouteHealth`].
+#[derive(Clone, Copy, Debug, Default)]
+struct AttemptHistory {
+    last_success: Option<Instant>,
+    last_failure: Option<Instant>,
+    latest_latency: Option<Duration>,
 }
 
 #[derive(Clone, Debug)]
@@ -85,9 +276,19 @@ struct ThrottlingConnectionManagerState {
     consecutive_fails: u16,
     next_attempt: Instant,
     latest_attempt: Instant,
+    history: AttemptHistory,
 }
 
 impl ThrottlingConnectionManagerState {
+    fn new(now: Instant) -> Self {
+        Self {
+            consecutive_fails: 0,
+            next_attempt: now,
+            latest_attempt: now,
+            history: AttemptHistory::default(),
+        }
+    }
+
     /// Produces a new state after a success or failure.
     ///
     /// The logic here is to track an attempt start time and to take it into
@@ -96,8 +297,24 @@ impl ThrottlingConnectionManagerState {
     /// discarded. If, however, outcomes of failed attempts are arriving out of
     /// order in which attempts started, those failures will still be reflected
     /// in `consecutive_fails`.
-    fn after_attempt(self, was_successful: bool, attempt_start_time: Instant) -> Self {
+    ///
+    /// If the server provided a `retry_after` hint with the failure, it is used
+    /// instead of the local cooldown schedule, but never for less than the
+    /// schedule says nor for more than [`MAX_RETRY_AFTER`].
+    fn after_attempt(
+        self,
+        was_successful: bool,
+        attempt_start_time: Instant,
+        retry_after: Option<Duration>,
+        now: Instant,
+    ) -> Self {
         let mut s = self;
+        if was_successful {
+            s.history.last_success = Some(now);
+            s.history.latest_latency = Some(now.saturating_duration_since(attempt_start_time));
+        } else {
+            s.history.last_failure = Some(now);
+        }
         if was_successful {
             // comparing using `>=` to guarantee that successful attempt takes precedence
             if attempt_start_time >= s.latest_attempt {
@@ -108,10 +325,14 @@ impl ThrottlingConnectionManagerState {
         } else if attempt_start_time > s.latest_attempt || s.consecutive_fails > 0 {
             s.latest_attempt = max(attempt_start_time, s.latest_attempt);
             let idx: usize = s.consecutive_fails.into();
-            let cooldown_interval = COOLDOWN_INTERVALS
+            let scheduled = *COOLDOWN_INTERVALS
                 .get(idx)
                 .unwrap_or(&MAX_COOLDOWN_INTERVAL);
-            s.next_attempt = Instant::now() + *cooldown_interval;
+            let cooldown_interval = match retry_after {
+                Some(hint) => hint.clamp(scheduled, MAX_RETRY_AFTER),
+                None => scheduled,
+            };
+            s.next_attempt = now + cooldown_interval;
             s.consecutive_fails = min(
                 s.consecutive_fails.saturating_add(1),
                 (COOLDOWN_INTERVALS.len() - 1).try_into().unwrap(),
@@ -129,24 +350,229 @@ pub struct SingleRouteThrottlingConnectionManager {
     state: Arc<Mutex<ThrottlingConnectionManagerState>>,
     connection_params: ConnectionParams,
     connection_timeout: Duration,
+    clock: &'static dyn Clock,
 }
 
 /// A connection manager that holds a list of [SingleRouteThrottlingConnectionManager] instances
 /// and iterates over them until it can find one that results in a successful connection attempt.
 /// If none did, it will return [ConnectionAttemptOutcome::WaitUntil] with the minimum possible
 /// cooldown time (based on cooldown times returned by all throttling connection managers).
+///
+/// Which route is tried first is up to the manager's [`RouteSelectionPolicy`]. By default, it is
+/// the most preferred one that isn't cooling down.
+///
+/// Clones share their routes, including updates made through
+/// [`MultiRouteConnectionManager::update_routes`].
 #[derive(Clone)]
 pub struct MultiRouteConnectionManager<M = SingleRouteThrottlingConnectionManager> {
-    route_managers: Vec<M>,
+    /// The latest routes; attempts hold on to the list they started with.
+    routes: Arc<std::sync::Mutex<Arc<RouteList<M>>>>,
     connection_timeout: Duration,
+    clock: &'static dyn Clock,
+    route_selection: RouteSelectionPolicy,
+    /// Draws routes for [`RouteSelectionPolicy::WeightedRandom`], shared with clones.
+    rng: Arc<std::sync::Mutex<StdRng>>,
+}
+
+/// The routes of a [`MultiRouteConnectionManager`] and its clones, in order of preference.
+///
+/// Updating the routes swaps in a new list. Attempts still going through an older list keep it
+/// alive until they finish, after which it is dropped.
+struct RouteList<M> {
+    route_managers: Vec<M>,
+    /// Index of the route that last resulted in a connection, for
+    /// [`RouteSelectionPolicy::StickyFailover`].
+    sticky_route: std::sync::Mutex<Option<usize>>,
+}
+
+impl<M> RouteList<M> {
+    fn new(route_managers: Vec<M>) -> Self {
+        Self {
+            route_managers,
+            sticky_route: Default::default(),
+        }
+    }
+
+    fn sticky_route(&self) -> Option<usize> {
+        (*self.sticky_route.lock().expect("not poisoned"))
+            .filter(|index| *index < self.route_managers.len())
+    }
+
+    fn set_sticky_route(&self, index: Option<usize>) {
+        *self.sticky_route.lock().expect("not poisoned") = index;
+    }
+
+    /// Clears the sticky route if it is still `index`; another attempt may
+    /// have succeeded through a different route in the meantime.
+    fn forget_sticky_route(&self, index: usize) {
+        let mut sticky_route = self.sticky_route.lock().expect("not poisoned");
+        if *sticky_route == Some(index) {
+            *sticky_route = None;
+        }
+    }
+}
+
+/// How a [`MultiRouteConnectionManager`] picks the route to try first on each attempt.
+///
+/// Whatever the policy, routes cooling down after failures are skipped, and the rest are tried
+/// in order of preference if the first one fails.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum RouteSelectionPolicy {
+    /// Start with the most preferred route.
+    #[default]
+    OrderedFailover,
+    /// Start with the route that last resulted in a connection, if any, and otherwise with the
+    /// most preferred one, to make use of warm connections and stick to the same servers.
+    ///
+    /// The route stops being sticky as soon as an attempt through it fails.
+    StickyFailover,
+    /// Start with a route drawn at random, each with a probability proportional to its weight,
+    /// to spread connections over the routes and keep the fallbacks in use.
+    ///
+    /// `weights` are given in order of preference. Routes past the end of the list have no
+    /// weight, and are only tried when failing over. Without any weight at all, this is the
+    /// same as [`Self::OrderedFailover`].
+    WeightedRandom { weights: Vec<u32> },
 }
 
 impl<M> MultiRouteConnectionManager<M> {
     pub fn new(route_managers: Vec<M>, connection_timeout: Duration) -> Self {
         Self {
-            route_managers,
+            routes: Arc::new(std::sync::Mutex::new(Arc::new(RouteList::new(
+                route_managers,
+            )))),
             connection_timeout,
+            clock: &SystemClock,
+            route_selection: RouteSelectionPolicy::default(),
+            rng: Arc::new(std::sync::Mutex::new(StdRng::from_entropy())),
+        }
+    }
+
+    pub fn with_route_selection(mut self, policy: RouteSelectionPolicy) -> Self {
+        self.route_selection = policy;
+        self
+    }
+
+    /// Draws routes for [`RouteSelectionPolicy::WeightedRandom`] with `rng`, so that tests can
+    /// make the draws repeatable.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_rng(mut self, rng: StdRng) -> Self {
+        self.rng = Arc::new(std::sync::Mutex::new(rng));
+        self
+    }
+
+    /// Uses `clock` to compute when the next attempt may happen. It should be
+    /// the same clock the route managers use.
+    pub fn with_clock(mut self, clock: &'static dyn Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Forgets the route that last resulted in a connection, so that the next
+    /// attempt goes through the routes in order of preference again.
+    ///
+    /// Only matters with [`RouteSelectionPolicy::StickyFailover`].
+    pub fn clear_sticky_route(&self) {
+        self.latest_routes().set_sticky_route(None);
+    }
+
+    /// The routes as last updated, for a new attempt to go through.
+    fn latest_routes(&self) -> Arc<RouteList<M>> {
+        self.routes.lock().expect("not poisoned").clone()
+    }
+
+    /// The order to try `routes` in: the one picked by the
+    /// [`RouteSelectionPolicy`], if any, followed by the rest in order of
+    /// preference.
+    ///
+    /// Also returns the sticky route, if that is the one picked.
+    fn route_order(&self, routes: &RouteList<M>) -> (Option<usize>, impl Iterator<Item = usize>) {
+        let route_count = routes.route_managers.len();
+        let (sticky, first) = match &self.route_selection {
+            RouteSelectionPolicy::OrderedFailover => (None, None),
+            RouteSelectionPolicy::StickyFailover => {
+                let sticky = routes.sticky_route();
+                (sticky, sticky)
+            }
+            RouteSelectionPolicy::WeightedRandom { weights } => {
+                (None, self.draw_route(route_count, weights))
+            }
+        };
+        let rest = (0..route_count).filter(move |index| Some(*index) != first);
+        (sticky, first.into_iter().chain(rest))
+    }
+
+    /// Picks one of `route_count` routes with a probability proportional to
+    /// its weight, or none if no route has any weight.
+    fn draw_route(&self, route_count: usize, weights: &[u32]) -> Option<usize> {
+        let weights =
+            (0..route_count).map(|index| weights.get(index).copied().map_or(0, u64::from));
+        let distribution = WeightedIndex::new(weights).ok()?;
+        Some(distribution.sample(&mut *self.rng.lock().expect("not poisoned")))
+    }
+}
+
+impl MultiRouteConnectionManager {
+    /// Replaces the routes with `connection_params`, in order of preference,
+    /// for this manager and every clone of it, like the ones held by running
+    /// services.
+    ///
+    /// Routes are told apart by host, SNI and port. A route that was already
+    /// there keeps its cooldown state and takes on the rest of the new
+    /// parameters, such as certificates and request decorators. New routes
+    /// start out like those of a new manager, and the ones no longer listed
+    /// are dropped.
+    ///
+    /// Attempts in flight during the update carry on through the routes they
+    /// started with. Their outcomes still count towards the cooldown of the
+    /// routes that were kept.
+    ///
+    /// The sticky route is cleared, so the new order of preference applies
+    /// from the next attempt on.
+    pub fn update_routes(&self, connection_params: impl IntoIterator<Item = ConnectionParams>) {
+        let mut latest = self.routes.lock().expect("not poisoned");
+        let mut previous: Vec<_> = latest.route_managers.iter().collect();
+        let mut kept = 0;
+        let route_managers: Vec<_> = connection_params
+            .into_iter()
+            .map(|params| {
+                match previous
+                    .iter()
+                    .position(|route_manager| route_manager.is_same_route(&params))
+                {
+                    Some(index) => {
+                        kept += 1;
+                        previous.swap_remove(index).clone().with_params(params)
+                    }
+                    None => {
+                        SingleRouteThrottlingConnectionManager::new(params, self.connection_timeout)
+                            .with_clock(self.clock)
+                    }
+                }
+            })
+            .collect();
+        let (added, removed) = (route_managers.len() - kept, previous.len());
+        *latest = Arc::new(RouteList::new(route_managers));
+        log::info!(
+            "Routes updated: {} kept, {} added, {} removed",
+            kept,
+            added,
+            removed
+        );
+    }
+
+    /// Reports the state of each route, in order of preference.
+    ///
+    /// This only reads what the routes keep track of anyway and makes no
+    /// attempts, so it is cheap enough to call for a debug screen or a log
+    /// dump.
+    pub async fn health_snapshot(&self) -> Vec<RouteHealth> {
+        let routes = self.latest_routes();
+        let mut snapshot = Vec::with_capacity(routes.route_managers.len());
+        for route_manager in &routes.route_managers {
+            snapshot.push(route_manager.health().await);
         }
+        snapshot
     }
 }
 
@@ -163,39 +589,83 @@ where
     /// limitations it will soon reach the "cooldown" state and no time will be wasted
     /// on trying it. As a result, it's unlikely that we will be waiting on more than one
     /// connection attempt, except maybe the case of the few first requests.
-    async fn connect_or_wait<'a, T, E, Fun, Fut>(
-        &'a self,
+    ///
+    /// With [`RouteSelectionPolicy::StickyFailover`], the route that resulted in the last
+    /// connection is tried before all others. If it fails, it stops being sticky, and the others
+    /// are tried in order as usual. With [`RouteSelectionPolicy::WeightedRandom`], a route drawn
+    /// for the attempt is tried first instead.
+    async fn connect_or_wait<T, E, Fun, Fut>(
+        &self,
         connection_fn: Fun,
     ) -> ConnectionAttemptOutcome<T, E>
     where
         T: Send,
-        E: Send + Debug + LogSafeDisplay,
-        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send,
     {
-        let now = Instant::now();
-        let deadline = now + self.connection_timeout;
-        let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
-        for route_manager in self.route_managers.iter() {
+        self.connect_or_wait_recording(connection_fn, &mut Vec::new())
+            .await
+    }
+
+    /// Records a failure for every route that was tried and didn't result in
+    /// a connection. Routes skipped because they were cooling down are left
+    /// out.
+    async fn connect_or_wait_recording<T, E, Fun, Fut>(
+        &self,
+        connection_fn: Fun,
+        failures: &mut Vec<RouteAttemptError>,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        let deadline = Instant::now() + self.connection_timeout;
+        let mut earliest_retry = self.clock.instant_now() + MAX_COOLDOWN_INTERVAL;
+        let routes = self.latest_routes();
+        let (sticky, route_order) = self.route_order(&routes);
+        for index in route_order {
+            let route_manager = &routes.route_managers[index];
             loop {
+                let attempt_start = Instant::now();
                 let result_or_timeout =
                     timeout_at(deadline, route_manager.connect_or_wait(&connection_fn)).await;
                 let result = match result_or_timeout {
                     Ok(r) => r,
                     Err(_) => return ConnectionAttemptOutcome::TimedOut,
                 };
+                let mut record_failure = |failure| {
+                    failures.push(RouteAttemptError {
+                        route_index: index,
+                        failure,
+                        duration: attempt_start.elapsed(),
+                    })
+                };
                 match result {
                     ConnectionAttemptOutcome::Attempted(Ok(r)) => {
+                        if self.route_selection == RouteSelectionPolicy::StickyFailover {
+                            routes.set_sticky_route(Some(index));
+                        }
                         return ConnectionAttemptOutcome::Attempted(Ok(r));
                     }
+                    ConnectionAttemptOutcome::Attempted(Err(e)) if e.is_permanent() => {
+                        // The other routes lead to the same servers.
+                        log::info!("Connection attempt failed permanently: {}", e);
+                        return ConnectionAttemptOutcome::Attempted(Err(e));
+                    }
                     ConnectionAttemptOutcome::Attempted(Err(e)) => {
                         log::debug!("Connection attempt failed with an error: {:?}", e);
                         log::info!("Connection attempt failed with an error: {}", e);
-                        continue;
+                        record_failure(RouteFailure::Error {
+                            category: e.category(),
+                            description: e.to_string(),
+                        });
                     }
                     ConnectionAttemptOutcome::TimedOut => {
                         log::info!("Connection attempt timed out");
-                        continue;
+                        record_failure(RouteFailure::TimedOut);
                     }
                     ConnectionAttemptOutcome::WaitUntil(i) => {
                         if i < earliest_retry {
@@ -204,10 +674,22 @@ where
                         break;
                     }
                 }
+                if sticky == Some(index) {
+                    routes.forget_sticky_route(index);
+                }
             }
         }
         ConnectionAttemptOutcome::WaitUntil(earliest_retry)
     }
+
+    async fn is_any_route_cooling_down(&self) -> bool {
+        for route_manager in &self.latest_routes().route_managers {
+            if route_manager.is_any_route_cooling_down().await {
+                return true;
+            }
+        }
+        false
+    }
 }
 
 impl SingleRouteThrottlingConnectionManager {
@@ -215,13 +697,158 @@ impl SingleRouteThrottlingConnectionManager {
         Self {
             connection_params,
             connection_timeout,
-            state: Arc::new(Mutex::new(ThrottlingConnectionManagerState {
-                consecutive_fails: 0,
-                next_attempt: Instant::now(),
-                latest_attempt: Instant::now(),
-            })),
+            clock: &SystemClock,
+            state: Arc::new(Mutex::new(ThrottlingConnectionManagerState::new(
+                Instant::now(),
+            ))),
+        }
+    }
+
+    /// Tracks cooldowns using `clock` instead of the system clock.
+    ///
+    /// Any attempts made before the switch are forgotten.
+    pub fn with_clock(mut self, clock: &'static dyn Clock) -> Self {
+        self.clock = clock;
+        self.state = Arc::new(Mutex::new(ThrottlingConnectionManagerState::new(
+            clock.instant_now(),
+        )));
+        self
+    }
+
+    /// Makes this the first route of a [`MultiRouteConnectionManager`], with
+    /// `fallbacks` tried after it in order.
+    ///
+    /// The route keeps its cooldown, which stays shared with clones of
+    /// `self`. The fallbacks get the same timeouts and clock.
+    pub fn into_multi_route(
+        self,
+        fallbacks: impl IntoIterator<Item = ConnectionParams>,
+    ) -> MultiRouteConnectionManager {
+        let connect_timeouts = self.connection_params.connect_timeouts;
+        let connection_timeout = self.connection_timeout;
+        let clock = self.clock;
+        let route_managers = std::iter::once(self)
+            .chain(fallbacks.into_iter().map(|params| {
+                Self::new(
+                    params.with_connect_timeouts(connect_timeouts),
+                    connection_timeout,
+                )
+                .with_clock(clock)
+            }))
+            .collect();
+        MultiRouteConnectionManager::new(route_managers, connection_timeout).with_clock(clock)
+    }
+
+    /// Clears the cooldown left by failed attempts, so that the next attempt
+    /// is made right away.
+    ///
+    /// Meant for when connectivity is known to be back, say because the OS
+    /// reported the network returning, so that reconnecting doesn't wait out a
+    /// backoff that no longer applies. Failures of attempts started before the
+    /// reset don't bring the cooldown back.
+    pub async fn reset_cooldown(&self) {
+        let mut s = self.state.lock().await;
+        let was_cooling_down = s.consecutive_fails > 0;
+        *s = ThrottlingConnectionManagerState {
+            history: s.history,
+            ..ThrottlingConnectionManagerState::new(self.clock.instant_now())
+        };
+        drop(s);
+
+        if was_cooling_down {
+            log::info!(
+                "Cooldown for {} reset",
+                self.connection_params.masked_display()
+            );
         }
     }
+
+    /// Makes exactly one attempt right away, even while cooling down.
+    ///
+    /// Meant for probing connectivity on the user's request, like a "retry
+    /// now" button. A success counts like any other and ends the cooldown, but
+    /// a failure isn't recorded: the backoff schedule carries on as if the
+    /// attempt hadn't been made.
+    pub async fn connect_ignoring_cooldown<T, E, Fun, Fut>(
+        &self,
+        connection_fn: Fun,
+    ) -> ForcedAttemptOutcome<T, E>
+    where
+        Fun: FnOnce(ConnectionParams) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let attempt_start_time = self.clock.instant_now();
+        let connection_result_or_timeout = timeout(
+            self.connection_timeout,
+            connection_fn(self.connection_params.clone()),
+        )
+        .await;
+
+        match connection_result_or_timeout {
+            Ok(Ok(connection)) => {
+                let mut s = self.state.lock().await;
+                let new_state = s.clone().after_attempt(
+                    true,
+                    attempt_start_time,
+                    None,
+                    self.clock.instant_now(),
+                );
+                *s = new_state;
+                ForcedAttemptOutcome::Connected(connection)
+            }
+            Ok(Err(e)) => {
+                log::info!(
+                    "Forced connection attempt via {} failed; cooldown unchanged",
+                    self.connection_params.masked_display()
+                );
+                ForcedAttemptOutcome::Failed(e)
+            }
+            Err(_) => {
+                log::info!(
+                    "Forced connection attempt via {} timed out; cooldown unchanged",
+                    self.connection_params.masked_display()
+                );
+                ForcedAttemptOutcome::TimedOut
+            }
+        }
+    }
+
+    /// Reports the state of the route as of now.
+    pub async fn health(&self) -> RouteHealth {
+        let s = self.state.lock().await.clone();
+        let AttemptHistory {
+            last_success,
+            last_failure,
+            latest_latency,
+        } = s.history;
+        RouteHealth {
+            route: self.connection_params.masked_display().to_string(),
+            last_success,
+            last_failure,
+            latest_latency,
+            consecutive_failures: s.consecutive_fails,
+            cooldown_remaining: s
+                .next_attempt
+                .saturating_duration_since(self.clock.instant_now()),
+        }
+    }
+
+    /// Whether `connection_params` lead to the same place as this route, so
+    /// that its attempt history applies to them.
+    fn is_same_route(&self, connection_params: &ConnectionParams) -> bool {
+        let ConnectionParams {
+            sni, host, port, ..
+        } = &self.connection_params;
+        *sni == connection_params.sni
+            && *host == connection_params.host
+            && *port == connection_params.port
+    }
+
+    /// Switches to `connection_params`, keeping the state shared with clones.
+    fn with_params(mut self, connection_params: ConnectionParams) -> Self {
+        self.connection_params = connection_params;
+        self
+    }
 }
 
 /// Declare &SingleRouteThrottlingConnectionManager unwind-safe.
@@ -232,27 +859,39 @@ impl RefUnwindSafe for SingleRouteThrottlingConnectionManager {}
 
 #[async_trait]
 impl ConnectionManager for SingleRouteThrottlingConnectionManager {
-    async fn connect_or_wait<'a, T, E, Fun, Fut>(
-        &'a self,
+    async fn connect_or_wait<T, E, Fun, Fut>(
+        &self,
         connection_fn: Fun,
     ) -> ConnectionAttemptOutcome<T, E>
     where
         T: Send,
-        E: Send,
-        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        E: Send + RetryLater,
+        Fun: Fn(ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send,
     {
         let state = self.state.lock().await.clone();
-        let attempt_start_time = Instant::now();
+        let attempt_start_time = self.clock.instant_now();
         if attempt_start_time < state.next_attempt {
             return ConnectionAttemptOutcome::WaitUntil(state.next_attempt);
         }
-        let connection_result_or_timeout = timeout_at(
-            attempt_start_time.add(self.connection_timeout),
-            connection_fn(&self.connection_params),
+        let connection_result_or_timeout = timeout(
+            self.connection_timeout,
+            connection_fn(self.connection_params.clone()),
         )
         .await;
 
+        if matches!(&connection_result_or_timeout, Ok(Err(e)) if e.is_permanent()) {
+            // Waiting wouldn't help, so the route isn't put into cooldown.
+            log::info!(
+                "Connection attempt via {} failed permanently",
+                self.connection_params.masked_display()
+            );
+            return connection_result_or_timeout.map_or(
+                ConnectionAttemptOutcome::TimedOut,
+                ConnectionAttemptOutcome::Attempted,
+            );
+        }
+
         let mut s = self.state.lock().await;
 
         // Ensure unwind safety by atomically updating the locked state with
@@ -260,29 +899,54 @@ impl ConnectionManager for SingleRouteThrottlingConnectionManager {
         let was_successful = connection_result_or_timeout
             .as_ref()
             .map_or(false, |r| r.is_ok());
-        let new_state = s.clone().after_attempt(was_successful, attempt_start_time);
+        let retry_after = match &connection_result_or_timeout {
+            Ok(Err(e)) => e.retry_after(),
+            _ => None,
+        };
+        let new_state = s.clone().after_attempt(
+            was_successful,
+            attempt_start_time,
+            retry_after,
+            self.clock.instant_now(),
+        );
         *s = new_state;
+        drop(s);
+
+        if !was_successful {
+            log::info!(
+                "Connection attempt via {} failed",
+                self.connection_params.masked_display()
+            );
+        }
 
         connection_result_or_timeout.map_or(ConnectionAttemptOutcome::TimedOut, |result| {
             ConnectionAttemptOutcome::Attempted(result)
         })
     }
+
+    async fn is_any_route_cooling_down(&self) -> bool {
+        self.health().await.is_cooling_down()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::borrow::Borrow;
     use std::future;
+    use std::time::SystemTime;
 
     use assert_matches::assert_matches;
+    use rand::SeedableRng as _;
     use tokio::time;
 
     use crate::infra::certs::RootCertificates;
+    use crate::infra::clock::TestClock;
+    use crate::infra::errors::NetError;
     use crate::infra::test::shared::{
         TestError, FEW_ATTEMPTS, LONG_CONNECTION_TIME, MANY_ATTEMPTS, TIMEOUT_DURATION,
         TIME_ADVANCE_VALUE,
     };
-    use crate::infra::HttpRequestDecoratorSeq;
+    use crate::infra::{ConnectTimeouts, HttpRequestDecoratorSeq};
 
     use super::*;
 
@@ -386,6 +1050,225 @@ mod test {
         assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
     }
 
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn single_route_manager_honors_server_retry_after() {
+        const RETRY_AFTER_SECONDS: u32 = 100;
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params("chat.staging.signal.org"),
+            TIMEOUT_DURATION,
+        );
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<(), NetError> = manager
+            .connect_or_wait(|_| {
+                future::ready(Err(NetError::RateLimited {
+                    retry_after_seconds: RETRY_AFTER_SECONDS,
+                }))
+            })
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Err(NetError::RateLimited { .. }))
+        );
+
+        // the local schedule would have allowed a retry by now, but the server asked for more
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+        let attempt_outcome: ConnectionAttemptOutcome<(), NetError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+
+        time::advance(Duration::from_secs(RETRY_AFTER_SECONDS.into())).await;
+        let attempt_outcome: ConnectionAttemptOutcome<(), NetError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
+    #[test]
+    fn server_retry_after_is_clamped() {
+        let start = Instant::now();
+        let first_failure = start + Duration::from_secs(1);
+        // After one failure, the schedule asks for COOLDOWN_INTERVALS[1].
+        let after_one_failure = ThrottlingConnectionManagerState::new(start).after_attempt(
+            false,
+            first_failure,
+            None,
+            first_failure,
+        );
+        let cooldown_with_hint = |retry_after_seconds: u32| {
+            let state = after_one_failure.clone().after_attempt(
+                false,
+                first_failure,
+                Some(Duration::from_secs(retry_after_seconds.into())),
+                first_failure,
+            );
+            state.next_attempt - first_failure
+        };
+
+        assert_eq!(cooldown_with_hint(u32::MAX), MAX_RETRY_AFTER);
+        assert_eq!(cooldown_with_hint(0), COOLDOWN_INTERVALS[1]);
+        assert_eq!(cooldown_with_hint(100), Duration::from_secs(100));
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn single_route_manager_does_not_cool_down_after_permanent_failure() {
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params("chat.staging.signal.org"),
+            TIMEOUT_DURATION,
+        );
+        // More failures than it takes to cool down, each attempted and
+        // reported as it is.
+        for _ in 0..MANY_ATTEMPTS {
+            let attempt_outcome: ConnectionAttemptOutcome<(), NetError> = manager
+                .connect_or_wait(|_| future::ready(Err(NetError::ClientDeprecated)))
+                .await;
+            assert_matches!(
+                attempt_outcome,
+                ConnectionAttemptOutcome::Attempted(Err(NetError::ClientDeprecated))
+            );
+        }
+        assert!(!manager.health().await.is_cooling_down());
+    }
+
+    #[tokio::test]
+    async fn single_route_manager_cooldown_follows_clock() {
+        let (manager, clock) = manager_with_test_clock();
+
+        // The first failure has no cooldown, the second one does.
+        for _ in 0..2 {
+            let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = manager
+                .connect_or_wait(|_| future::ready(Err(TestError::Expected)))
+                .await;
+            assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Err(_)));
+        }
+        let expected_retry = clock.instant_now() + COOLDOWN_INTERVALS[1];
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::WaitUntil(i) if i == expected_retry
+        );
+
+        clock.advance(COOLDOWN_INTERVALS[1]);
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn single_route_manager_reset_cooldown_allows_next_attempt() {
+        let (manager, clock) = manager_with_test_clock();
+
+        for _ in 0..MANY_ATTEMPTS {
+            let _attempt_outcome: ConnectionAttemptOutcome<(), TestError> = manager
+                .connect_or_wait(|_| future::ready(Err(TestError::Expected)))
+                .await;
+        }
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+
+        manager.reset_cooldown().await;
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn single_route_manager_forced_attempt_during_cooldown() {
+        let (manager, clock) = manager_with_test_clock();
+
+        for _ in 0..MANY_ATTEMPTS {
+            let _attempt_outcome: ConnectionAttemptOutcome<(), TestError> = manager
+                .connect_or_wait(|_| future::ready(Err(TestError::Expected)))
+                .await;
+        }
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        let cooldown_end = assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::WaitUntil(i) => i
+        );
+
+        // A failed forced attempt is made, but leaves the cooldown as it was.
+        let attempt_outcome: ForcedAttemptOutcome<(), TestError> = manager
+            .connect_ignoring_cooldown(|_| future::ready(Err(TestError::Expected)))
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ForcedAttemptOutcome::Failed(TestError::Expected)
+        );
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::WaitUntil(i) if i == cooldown_end
+        );
+
+        // A successful one ends the cooldown.
+        let attempt_outcome: ForcedAttemptOutcome<(), TestError> = manager
+            .connect_ignoring_cooldown(|_| future::ready(Ok(())))
+            .await;
+        assert_matches!(attempt_outcome, ForcedAttemptOutcome::Connected(()));
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn single_route_manager_ignores_failures_started_before_reset() {
+        let (manager, clock) = manager_with_test_clock();
+
+        // The first failure has no cooldown, but a second one would.
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = manager
+            .connect_or_wait(|_| future::ready(Err(TestError::Expected)))
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Err(_)));
+
+        let network_returned = tokio::sync::Notify::new();
+        let (attempt_outcome, ()) = tokio::join!(
+            manager.connect_or_wait(|_| async {
+                network_returned.notified().await;
+                Err::<(), _>(TestError::Expected)
+            }),
+            async {
+                clock.advance(TIME_ADVANCE_VALUE);
+                manager.reset_cooldown().await;
+                network_returned.notify_one();
+            },
+        );
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Err(_)));
+
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn single_route_manager_into_multi_route_keeps_route_first() {
+        let timeouts = ConnectTimeouts::from_total(TIMEOUT_DURATION);
+        let multi_route_manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(ROUTE_1).with_connect_timeouts(timeouts),
+            TIMEOUT_DURATION,
+        )
+        .into_multi_route([example_connection_params(ROUTE_2)]);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_1).await;
+
+        // route1 stops working, and the fallback is tried with the same timeouts
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<_, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| async move {
+                let timeouts = connection_params.connect_timeouts;
+                let route = simulate_connect(connection_params, false).await?;
+                Ok((route, timeouts))
+            })
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok((ROUTE_2, t))) if t == timeouts
+        );
+    }
+
     #[tokio::test(flavor = "current_thread", start_paused = true)]
     async fn multi_route_manager_picks_working_route() {
         let manager_1 = SingleRouteThrottlingConnectionManager::new(
@@ -416,6 +1299,51 @@ mod test {
         validate_expected_route(&multi_route_manager, true, ROUTE_1).await;
     }
 
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_sticks_to_last_good_route() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION,
+        )
+        .with_route_selection(RouteSelectionPolicy::StickyFailover);
+
+        // route1 is down, so route2 is used
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, false, ROUTE_2).await;
+
+        // route1 is back and out of its cooldown, but route2 keeps being tried first,
+        // including through clones
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+        for _ in 0..3 {
+            time::advance(TIME_ADVANCE_VALUE).await;
+            validate_expected_route(&multi_route_manager.clone(), true, ROUTE_2).await;
+        }
+
+        // once route2 fails, the routes are tried in order again
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| async move {
+                match &*connection_params.host {
+                    ROUTE_1 => Ok(ROUTE_1),
+                    _ => Err(TestError::Expected),
+                }
+            })
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok(ROUTE_1))
+        );
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_1).await;
+    }
+
     #[tokio::test(flavor = "current_thread", start_paused = true)]
     async fn multi_route_manager_picks_working_route_after_timed_out_route() {
         let timing_out_route_manager = SingleRouteThrottlingConnectionManager::new(
@@ -457,6 +1385,398 @@ mod test {
         assert_matches!(attempt_outcome, ConnectionAttemptOutcome::TimedOut);
     }
 
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_stops_at_permanent_failure() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION,
+        );
+        let attempt_outcome: ConnectionAttemptOutcome<&str, NetError> = multi_route_manager
+            .connect_or_wait(|connection_params| async move {
+                match connection_params.host.borrow() {
+                    ROUTE_1 => Err(NetError::ClientDeprecated),
+                    _ => Ok(ROUTE_2),
+                }
+            })
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Err(NetError::ClientDeprecated))
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_update_keeps_state_of_remaining_routes() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            vec![SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_1),
+                TIMEOUT_DURATION,
+            )],
+            TIMEOUT_DURATION,
+        );
+        let running_service_manager = multi_route_manager.clone();
+
+        // route1 stops working before the update
+        for _ in 0..3 {
+            time::advance(TIME_ADVANCE_VALUE).await;
+            let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> =
+                running_service_manager
+                    .connect_or_wait(|connection_params| simulate_connect(connection_params, false))
+                    .await;
+            assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+        }
+        multi_route_manager.update_routes([ROUTE_1, ROUTE_2].map(example_connection_params));
+
+        // route1 is working again, but the cooldown carried over the update
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_2).await;
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_1).await;
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_update_reaches_clones() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            vec![SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_1),
+                TIMEOUT_DURATION,
+            )],
+            TIMEOUT_DURATION,
+        );
+        let running_service_manager = multi_route_manager.clone();
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&running_service_manager, true, ROUTE_1).await;
+
+        // Updated through either one, the routes change for both, and neither
+        // sticks to the route it used before.
+        running_service_manager.update_routes([ROUTE_2, ROUTE_1].map(example_connection_params));
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_2).await;
+        multi_route_manager.update_routes([ROUTE_1].map(example_connection_params));
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&running_service_manager, true, ROUTE_1).await;
+        assert_eq!(running_service_manager.health_snapshot().await.len(), 1);
+    }
+
+    #[test]
+    fn multi_route_manager_update_frees_replaced_routes() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            vec![SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_1),
+                TIMEOUT_DURATION,
+            )],
+            TIMEOUT_DURATION,
+        );
+        // What an attempt started before the updates holds on to.
+        let in_flight = multi_route_manager.latest_routes();
+        let original = Arc::downgrade(&in_flight);
+        let intermediate = {
+            multi_route_manager.update_routes([ROUTE_2].map(example_connection_params));
+            Arc::downgrade(&multi_route_manager.latest_routes())
+        };
+        multi_route_manager.update_routes([ROUTE_1, ROUTE_2].map(example_connection_params));
+
+        assert!(intermediate.upgrade().is_none());
+        assert!(original.upgrade().is_some());
+        drop(in_flight);
+        assert!(original.upgrade().is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_update_follows_new_order_and_drops_routes() {
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION,
+        );
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_1).await;
+
+        multi_route_manager.update_routes([ROUTE_2, ROUTE_1].map(example_connection_params));
+        time::advance(TIME_ADVANCE_VALUE).await;
+        validate_expected_route(&multi_route_manager, true, ROUTE_2).await;
+
+        multi_route_manager.update_routes([ROUTE_THAT_TIMES_OUT].map(example_connection_params));
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, true))
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::TimedOut);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_reports_route_health() {
+        const LATENCY: Duration = Duration::from_millis(20);
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION,
+        );
+
+        // route1 fails twice, which puts it in cooldown; route2 takes a while
+        // to connect every time
+        for _ in 0..2 {
+            time::advance(TIME_ADVANCE_VALUE).await;
+            let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = multi_route_manager
+                .connect_or_wait(|connection_params| async move {
+                    match &*connection_params.host {
+                        ROUTE_1 => Err(TestError::Expected),
+                        _ => {
+                            time::sleep(LATENCY).await;
+                            Ok(())
+                        }
+                    }
+                })
+                .await;
+            assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+        }
+
+        let now = Instant::now();
+        let [route_1, route_2]: [RouteHealth; 2] = multi_route_manager
+            .health_snapshot()
+            .await
+            .try_into()
+            .expect("one per route");
+
+        assert_eq!(route_1.route, format!("https://{ROUTE_1}:443"));
+        assert_eq!(route_1.last_success, None);
+        assert_eq!(route_1.last_failure, Some(now - LATENCY));
+        assert_eq!(route_1.latest_latency, None);
+        assert_eq!(route_1.consecutive_failures, 2);
+        assert_eq!(route_1.cooldown_remaining, COOLDOWN_INTERVALS[1] - LATENCY);
+        assert!(route_1.is_cooling_down());
+
+        assert_eq!(route_2.route, format!("https://{ROUTE_2}:443"));
+        assert_eq!(route_2.last_success, Some(now));
+        assert_eq!(route_2.last_failure, None);
+        assert_eq!(route_2.latest_latency, Some(LATENCY));
+        assert_eq!(route_2.consecutive_failures, 0);
+        assert!(!route_2.is_cooling_down());
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_records_how_each_route_failed() {
+        const LATENCY: Duration = Duration::from_millis(20);
+        let multi_route_manager = MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_THAT_TIMES_OUT, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION * 5,
+        );
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let mut failures = vec![];
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = multi_route_manager
+            .connect_or_wait_recording(
+                |connection_params| async move {
+                    match &*connection_params.host {
+                        ROUTE_1 => {
+                            time::sleep(LATENCY).await;
+                            Err(TestError::Expected)
+                        }
+                        ROUTE_2 => Err(TestError::Unexpected("certificate mismatch")),
+                        _ => {
+                            time::sleep(LONG_CONNECTION_TIME).await;
+                            Ok(())
+                        }
+                    }
+                },
+                &mut failures,
+            )
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+
+        // Each route is tried again right after its first failure, and only
+        // cools down after the second.
+        let failure = |route_index, failure, duration| RouteAttemptError {
+            route_index,
+            failure,
+            duration,
+        };
+        let error = |description: &str| RouteFailure::Error {
+            category: ErrorCategory::Network,
+            description: description.to_owned(),
+        };
+        let expected = error("expected error");
+        let unexpected = error("unexpected error");
+        assert_eq!(
+            failures,
+            [
+                failure(0, expected.clone(), LATENCY),
+                failure(0, expected, LATENCY),
+                failure(1, RouteFailure::TimedOut, TIMEOUT_DURATION),
+                failure(1, RouteFailure::TimedOut, TIMEOUT_DURATION),
+                failure(2, unexpected.clone(), Duration::ZERO),
+                failure(2, unexpected, Duration::ZERO),
+            ]
+        );
+        assert_eq!(
+            RouteAttempts(failures[1..4].to_vec()).to_string(),
+            "route 0 failed after 20ms: expected error; \
+             route 1 timed out after 100ms; \
+             route 1 timed out after 100ms"
+        );
+
+        // Routes that are cooling down aren't tried, so there is nothing to
+        // record.
+        failures.clear();
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = multi_route_manager
+            .connect_or_wait_recording(|_| future::ready(Err(TestError::Expected)), &mut failures)
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+        assert!(failures.is_empty());
+    }
+
+    #[test]
+    fn route_attempts_category_follows_failures() {
+        let attempt = |failure| RouteAttemptError {
+            route_index: 0,
+            failure,
+            duration: Duration::ZERO,
+        };
+        let rate_limited = || RouteFailure::Error {
+            category: ErrorCategory::RateLimited,
+            description: "rate limited".to_owned(),
+        };
+
+        let all_rate_limited =
+            RouteAttempts(vec![attempt(rate_limited()), attempt(rate_limited())]);
+        assert_eq!(all_rate_limited.category(), ErrorCategory::RateLimited);
+
+        let all_timed_out = RouteAttempts(vec![attempt(RouteFailure::TimedOut)]);
+        assert_eq!(all_timed_out.category(), ErrorCategory::Timeout);
+        assert_eq!(
+            NetError::AllRoutesFailed {
+                attempts: all_timed_out
+            }
+            .category(),
+            ErrorCategory::Timeout
+        );
+
+        let mixed = RouteAttempts(vec![
+            attempt(rate_limited()),
+            attempt(RouteFailure::TimedOut),
+        ]);
+        assert_eq!(mixed.category(), ErrorCategory::Network);
+        assert_eq!(RouteAttempts::default().category(), ErrorCategory::Network);
+    }
+
+    fn weighted_route_manager(weights: Vec<u32>, seed: u64) -> MultiRouteConnectionManager {
+        MultiRouteConnectionManager::new(
+            [ROUTE_1, ROUTE_2]
+                .map(|host| {
+                    SingleRouteThrottlingConnectionManager::new(
+                        example_connection_params(host),
+                        TIMEOUT_DURATION,
+                    )
+                })
+                .into(),
+            TIMEOUT_DURATION,
+        )
+        .with_route_selection(RouteSelectionPolicy::WeightedRandom { weights })
+        .with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    async fn routes_used(
+        multi_route_manager: &MultiRouteConnectionManager,
+        attempts: usize,
+    ) -> Vec<&'static str> {
+        let mut routes = Vec::with_capacity(attempts);
+        for _ in 0..attempts {
+            let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+                .connect_or_wait(|connection_params| async move {
+                    match &*connection_params.host {
+                        ROUTE_1 => Ok(ROUTE_1),
+                        ROUTE_2 => Ok(ROUTE_2),
+                        _ => Err(TestError::Unexpected("not configured for the route")),
+                    }
+                })
+                .await;
+            routes.push(assert_matches!(
+                attempt_outcome,
+                ConnectionAttemptOutcome::Attempted(Ok(route)) => route
+            ));
+        }
+        routes
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_spreads_attempts_by_weight() {
+        const ATTEMPTS: usize = 4000;
+        let routes = routes_used(&weighted_route_manager(vec![3, 1], 42), ATTEMPTS).await;
+
+        // Unlike with the default policy, a working route doesn't stick.
+        let on_route_2 = routes.iter().filter(|route| **route == ROUTE_2).count();
+        let fraction = on_route_2 as f64 / ATTEMPTS as f64;
+        assert!((0.22..0.28).contains(&fraction), "{fraction}");
+
+        // The same seed makes the same picks.
+        let again = routes_used(&weighted_route_manager(vec![3, 1], 42), ATTEMPTS).await;
+        assert_eq!(routes, again);
+
+        // Routes without a weight are never picked first.
+        let routes = routes_used(&weighted_route_manager(vec![1], 42), 100).await;
+        assert!(routes.iter().all(|route| *route == ROUTE_1));
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn multi_route_manager_fails_over_from_weighted_route() {
+        // The second route is always picked first, but it is down.
+        let multi_route_manager = weighted_route_manager(vec![0, 1], 42);
+        let mut failures = vec![];
+        for _ in 0..2 {
+            time::advance(TIME_ADVANCE_VALUE).await;
+            let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+                .connect_or_wait_recording(
+                    |connection_params| async move {
+                        match &*connection_params.host {
+                            ROUTE_1 => Ok(ROUTE_1),
+                            _ => Err(TestError::Expected),
+                        }
+                    },
+                    &mut failures,
+                )
+                .await;
+            assert_matches!(
+                attempt_outcome,
+                ConnectionAttemptOutcome::Attempted(Ok(ROUTE_1))
+            );
+        }
+
+        // The picked route failed twice on the first attempt, cooling down only
+        // after the second failure, and was skipped on the next attempt.
+        let failed_routes: Vec<_> = failures.iter().map(|f| f.route_index).collect();
+        assert_eq!(failed_routes, [1, 1]);
+        let health = multi_route_manager.health_snapshot().await;
+        assert!(health[1].is_cooling_down());
+        assert!(!health[0].is_cooling_down());
+    }
+
     async fn validate_expected_route(
         multi_route_manager: &MultiRouteConnectionManager,
         route1_healthy: bool,
@@ -474,9 +1794,9 @@ mod test {
     }
 
     async fn simulate_connect(
-        connection_params: &ConnectionParams,
+        connection_params: ConnectionParams,
         route1_healthy: bool,
-    ) -> Result<&str, TestError> {
+    ) -> Result<&'static str, TestError> {
         let route1_response = match route1_healthy {
             true => Ok(ROUTE_1),
             false => Err(TestError::Expected),
@@ -492,6 +1812,18 @@ mod test {
         }
     }
 
+    /// A manager for a single route, with a clock the test advances by hand.
+    fn manager_with_test_clock() -> (SingleRouteThrottlingConnectionManager, &'static TestClock) {
+        let clock: &'static TestClock = Box::leak(Box::new(TestClock::new(SystemTime::now())));
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params("chat.staging.signal.org"),
+            TIMEOUT_DURATION,
+        )
+        .with_clock(clock);
+        clock.advance(TIME_ADVANCE_VALUE);
+        (manager, clock)
+    }
+
     fn example_connection_params(host: &str) -> ConnectionParams {
         ConnectionParams::new(
             host,
@@ -0,0 +1,87 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Identifiers tying a client operation to the server's logs of it.
+//!
+//! A [`CorrelationId`] is sent in the [`CORRELATION_ID_HEADER`] of the
+//! websocket upgrade request, and the client puts the same ID in its own logs
+//! and reports of the operation, so that both sides can be matched up.
+
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::infra::errors::LogSafeDisplay;
+
+/// The header of the websocket upgrade request the [`CorrelationId`] is sent
+/// in.
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// A client-chosen ID for one operation, such as connecting to an enclave or
+/// a backup over several connections.
+///
+/// [`CorrelationId::random`] IDs have nothing to do with the account, so they
+/// are safe to log. IDs made [from](CorrelationId::from) a caller's own
+/// [`Uuid`] must be just as unrelated to the account; in particular, they
+/// must not be derived from its UID.
+///
+/// Displayed and serialized as a hyphenated UUID.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CorrelationId(Uuid);
+
+impl CorrelationId {
+    /// A new random (version 4) ID.
+    pub fn random() -> Self {
+        Self(uuid::Builder::from_random_bytes(rand::random()).into_uuid())
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for CorrelationId {
+    fn from(value: Uuid) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+impl LogSafeDisplay for CorrelationId {}
+
+impl Serialize for CorrelationId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn random_ids_are_distinct_v4_uuids() {
+        let a = CorrelationId::random();
+        let b = CorrelationId::random();
+        assert_ne!(a, b);
+        assert_eq!(a.as_uuid().get_version(), Some(uuid::Version::Random));
+    }
+
+    #[test]
+    fn ids_display_and_serialize_as_hyphenated_uuids() {
+        let id = CorrelationId::from(Uuid::from_u128(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef));
+        assert_eq!(id.to_string(), "01234567-89ab-cdef-0123-456789abcdef");
+        assert_eq!(
+            serde_json::to_value(id).expect("serializes"),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+    }
+}
@@ -3,24 +3,41 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use async_trait::async_trait;
 use itertools::{Either, Itertools};
 use std::collections::HashMap;
+use std::fmt::Debug;
 use std::iter::Map;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::vec::IntoIter;
 
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::infra::errors::LogSafeDisplay;
 use crate::utils;
 
 const RESOLUTION_TIMEOUT: Duration = Duration::from_secs(1);
 const SIGNAL_DOMAIN_SUFFIX: &str = ".signal.org";
 
-#[derive(displaydoc::Display, Debug, thiserror::Error)]
+#[derive(displaydoc::Display, Debug, thiserror::Error, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     /// DNS lookup failed
     LookupFailed,
+    /// domain name does not exist
+    NxDomain,
+    /// DNS server failed to answer
+    ServFail,
+    /// DNS lookup timed out
+    Timeout,
 }
 
+/// The name that was looked up is left out, along with whatever the resolver
+/// said about it.
+impl LogSafeDisplay for Error {}
+
 #[derive(Debug, Default, Clone)]
 pub struct LookupResult {
     ipv4: Vec<Ipv4Addr>,
@@ -54,24 +71,209 @@ impl LookupResult {
     }
 }
 
+/// Where a [`DnsResolver`] gets its answers from.
+#[async_trait]
+pub trait DnsLookup: Debug + Send + Sync {
+    async fn dns_lookup(&self, hostname: &str) -> Result<LookupResult, Error>;
+}
+
+/// Looks names up with the operating system's resolver.
 #[derive(Debug, Default)]
+pub struct SystemDnsLookup;
+
+#[async_trait]
+impl DnsLookup for SystemDnsLookup {
+    async fn dns_lookup(&self, hostname: &str) -> Result<LookupResult, Error> {
+        let (ipv4s, ipv6s): (Vec<_>, Vec<_>) = getaddrinfo::lookup(hostname)
+            .await?
+            .into_iter()
+            .partition_map(|ip| match ip {
+                IpAddr::V4(v4) => Either::Left(v4),
+                IpAddr::V6(v6) => Either::Right(v6),
+            });
+        match LookupResult::new(ipv4s, ipv6s) {
+            lookup_result if !lookup_result.is_empty() => Ok(lookup_result),
+            _ => Err(Error::LookupFailed),
+        }
+    }
+}
+
+/// System lookups that tell failures apart by their `EAI_*` code.
+///
+/// The standard library keeps nothing of a failed `getaddrinfo` but the
+/// message for its code, which varies between platforms and languages, so
+/// this calls it directly, on a blocking thread as the standard library would.
+#[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+mod getaddrinfo {
+    use std::ffi::CString;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::Error;
+
+    pub(super) async fn lookup(hostname: &str) -> Result<Vec<IpAddr>, Error> {
+        let hostname = CString::new(hostname).map_err(|_| Error::NxDomain)?;
+        tokio::task::spawn_blocking(move || lookup_blocking(&hostname))
+            .await
+            .map_err(|_| Error::LookupFailed)?
+    }
+
+    fn lookup_blocking(hostname: &CString) -> Result<Vec<IpAddr>, Error> {
+        let hints = libc::addrinfo {
+            ai_socktype: libc::SOCK_STREAM,
+            // SAFETY: all zeros is a valid addrinfo, with null pointers.
+            ..unsafe { std::mem::zeroed() }
+        };
+        let mut list = std::ptr::null_mut();
+        // SAFETY: the pointers are valid for the duration of the call, and
+        // `list` is only read if it succeeds.
+        let code =
+            unsafe { libc::getaddrinfo(hostname.as_ptr(), std::ptr::null(), &hints, &mut list) };
+        if code != 0 {
+            return Err(error_from_code(code));
+        }
+
+        let mut addrs = vec![];
+        let mut next = list;
+        while !next.is_null() {
+            // SAFETY: a node of the list returned above, which is not freed
+            // until after the loop. Its address matches its family.
+            let info = unsafe { &*next };
+            match info.ai_family {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in) };
+                    addrs.push(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into());
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(info.ai_addr as *const libc::sockaddr_in6) };
+                    addrs.push(Ipv6Addr::from(addr.sin6_addr.s6_addr).into());
+                }
+                _ => {}
+            }
+            next = info.ai_next;
+        }
+        // SAFETY: returned by the successful call above, and freed only here.
+        unsafe { libc::freeaddrinfo(list) };
+        Ok(addrs)
+    }
+
+    fn error_from_code(code: libc::c_int) -> Error {
+        match code {
+            libc::EAI_NONAME | libc::EAI_NODATA => Error::NxDomain,
+            libc::EAI_AGAIN | libc::EAI_FAIL => Error::ServFail,
+            _ => Error::LookupFailed,
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn errors_are_classified_by_code() {
+            assert_eq!(error_from_code(libc::EAI_NONAME), Error::NxDomain);
+            assert_eq!(error_from_code(libc::EAI_NODATA), Error::NxDomain);
+            assert_eq!(error_from_code(libc::EAI_AGAIN), Error::ServFail);
+            assert_eq!(error_from_code(libc::EAI_FAIL), Error::ServFail);
+            assert_eq!(error_from_code(libc::EAI_MEMORY), Error::LookupFailed);
+        }
+
+        #[tokio::test]
+        async fn localhost_resolves_to_loopback() {
+            let addrs = lookup("localhost").await.expect("resolves");
+            assert!(!addrs.is_empty());
+            assert!(addrs.iter().all(IpAddr::is_loopback), "{addrs:?}");
+        }
+    }
+}
+
+/// Without the codes, failures can't be told apart.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+mod getaddrinfo {
+    use std::net::IpAddr;
+
+    use super::Error;
+
+    pub(super) async fn lookup(hostname: &str) -> Result<Vec<IpAddr>, Error> {
+        Ok(tokio::net::lookup_host((hostname, 443))
+            .await
+            .map_err(|_| Error::LookupFailed)?
+            .map(|addr| addr.ip())
+            .collect())
+    }
+}
+
+/// Resolves hostnames for [`TcpSslTransportConnector`](super::TcpSslTransportConnector).
+///
+/// A resolver can be shared between any number of connectors and concurrent
+/// lookups, usually behind an [`Arc`]. With [`Self::with_cache`], concurrent
+/// lookups of the same name are answered by a single query.
+#[derive(Debug)]
 pub struct DnsResolver {
     static_map: HashMap<&'static str, LookupResult>,
+    lookup: Box<dyn DnsLookup>,
+    cache: Option<DnsCache>,
+}
+
+/// Successful answers by hostname, each kept for `ttl`.
+#[derive(Debug)]
+struct DnsCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Arc<OnceCell<(Instant, LookupResult)>>>>,
+}
+
+impl DnsCache {
+    /// The entry for `hostname`, replacing it first if it has expired.
+    ///
+    /// Callers looking up the same name share the entry, and with it the
+    /// query that fills it in.
+    fn entry(&self, hostname: &str) -> Arc<OnceCell<(Instant, LookupResult)>> {
+        let mut entries = self.entries.lock().expect("not poisoned");
+        let entry = entries.entry(hostname.to_owned()).or_default();
+        if matches!(entry.get(), Some((resolved_at, _)) if resolved_at.elapsed() >= self.ttl) {
+            *entry = Default::default();
+        }
+        entry.clone()
+    }
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new_with_static_fallback(HashMap::new())
+    }
 }
 
 impl DnsResolver {
     pub fn new_with_static_fallback(static_map: HashMap<&'static str, LookupResult>) -> Self {
-        Self { static_map }
+        Self {
+            static_map,
+            lookup: Box::new(SystemDnsLookup),
+            cache: None,
+        }
+    }
+
+    /// Keeps successful answers for `ttl`, rather than querying again for
+    /// each lookup. Failed lookups and static fallbacks are never cached.
+    pub fn with_cache(mut self, ttl: Duration) -> Self {
+        self.cache = Some(DnsCache {
+            ttl,
+            entries: Default::default(),
+        });
+        self
+    }
+
+    /// Uses `lookup` instead of the system resolver; the static fallback
+    /// still applies when it fails.
+    pub fn with_lookup(mut self, lookup: impl DnsLookup + 'static) -> Self {
+        self.lookup = Box::new(lookup);
+        self
     }
 
+    /// Looks up `hostname`, falling back to the static map if that fails.
+    ///
+    /// When there is no fallback either, the error says why the lookup
+    /// failed.
     pub async fn lookup_ip(&self, hostname: &str) -> Result<LookupResult, Error> {
-        utils::timeout(
-            RESOLUTION_TIMEOUT,
-            Error::LookupFailed,
-            self.dns_lookup(hostname),
-        )
-        .await
-        .or_else(|e| {
+        self.cached_lookup(hostname).await.or_else(|e| {
             if hostname.ends_with(SIGNAL_DOMAIN_SUFFIX) {
                 log::warn!(
                     "DNS lookup failed for [{}], falling back to static map. Error: {:?}",
@@ -79,35 +281,169 @@ impl DnsResolver {
                     e
                 )
             }
-            self.static_map
+            self.static_map.get(hostname).ok_or(e).cloned()
+        })
+    }
+
+    /// Like [`Self::lookup_ip`], but fails rather than answer from the
+    /// static map, for callers that need to know what the name resolves to
+    /// right now.
+    pub async fn lookup_ip_without_fallback(&self, hostname: &str) -> Result<LookupResult, Error> {
+        self.cached_lookup(hostname).await
+    }
+
+    async fn cached_lookup(&self, hostname: &str) -> Result<LookupResult, Error> {
+        let lookup = || {
+            utils::timeout(
+                RESOLUTION_TIMEOUT,
+                Error::Timeout,
+                self.lookup.dns_lookup(hostname),
+            )
+        };
+        let Some(cache) = &self.cache else {
+            return lookup().await;
+        };
+        let entry = cache.entry(hostname);
+        // A failed lookup leaves the entry empty, so the next caller tries
+        // again.
+        let (_resolved_at, result) = entry
+            .get_or_try_init(|| async { Ok::<_, Error>((Instant::now(), lookup().await?)) })
+            .await?;
+        Ok(result.clone())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testutil {
+    use super::*;
+
+    /// Answers from a fixed table, and with [`Error::NxDomain`] for any name
+    /// not in it.
+    #[derive(Debug, Default)]
+    pub(crate) struct FakeDnsLookup(pub(crate) HashMap<&'static str, Result<LookupResult, Error>>);
+
+    #[async_trait]
+    impl DnsLookup for FakeDnsLookup {
+        async fn dns_lookup(&self, hostname: &str) -> Result<LookupResult, Error> {
+            self.0
                 .get(hostname)
-                .ok_or(Error::LookupFailed)
                 .cloned()
-        })
+                .unwrap_or(Err(Error::NxDomain))
+        }
     }
 
-    async fn dns_lookup<'a>(&self, hostname: &'a str) -> Result<LookupResult, Error> {
-        let lookup_result = tokio::net::lookup_host((hostname, 443))
-            .await
-            .map_err(|_| Error::LookupFailed)?;
+    /// Like [`FakeDnsLookup`], but counts the lookups made and yields before
+    /// answering, so that concurrent lookups overlap.
+    #[derive(Debug, Default)]
+    pub(crate) struct CountingDnsLookup {
+        pub(crate) answers: FakeDnsLookup,
+        pub(crate) count: Arc<std::sync::atomic::AtomicUsize>,
+    }
 
-        let (ipv4s, ipv6s): (Vec<_>, Vec<_>) =
-            lookup_result.into_iter().partition_map(|ip| match ip {
-                SocketAddr::V4(v4) => Either::Left(*v4.ip()),
-                SocketAddr::V6(v6) => Either::Right(*v6.ip()),
-            });
-        match LookupResult::new(ipv4s, ipv6s) {
-            lookup_result if !lookup_result.is_empty() => Ok(lookup_result),
-            _ => Err(Error::LookupFailed),
+    #[async_trait]
+    impl DnsLookup for CountingDnsLookup {
+        async fn dns_lookup(&self, hostname: &str) -> Result<LookupResult, Error> {
+            let _ = self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            self.answers.dns_lookup(hostname).await
+        }
+    }
+
+    /// Never answers.
+    #[derive(Debug)]
+    pub(crate) struct UnresponsiveDnsLookup;
+
+    #[async_trait]
+    impl DnsLookup for UnresponsiveDnsLookup {
+        async fn dns_lookup(&self, _hostname: &str) -> Result<LookupResult, Error> {
+            std::future::pending().await
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::infra::dns::LookupResult;
+    use crate::infra::dns::testutil::{CountingDnsLookup, FakeDnsLookup, UnresponsiveDnsLookup};
+    use crate::infra::dns::{DnsResolver, Error, LookupResult};
+    use assert_matches::assert_matches;
     use const_str::ip_addr;
+    use std::collections::HashMap;
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_lookup_times_out() {
+        let resolver = DnsResolver::default().with_lookup(UnresponsiveDnsLookup);
+        assert_matches!(
+            resolver.lookup_ip("chat.signal.org").await,
+            Err(Error::Timeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn static_fallback_replaces_failed_lookup() {
+        let fallback = LookupResult::new(vec![ip_addr!(v4, "1.1.1.1")], vec![]);
+        let resolver =
+            DnsResolver::new_with_static_fallback(HashMap::from([("chat.signal.org", fallback)]))
+                .with_lookup(FakeDnsLookup(HashMap::from([(
+                    "chat.signal.org",
+                    Err(Error::ServFail),
+                )])));
+
+        let addrs: Vec<IpAddr> = resolver
+            .lookup_ip("chat.signal.org")
+            .await
+            .expect("falls back")
+            .into_iter()
+            .collect();
+        assert_eq!(addrs, [IpAddr::V4(ip_addr!(v4, "1.1.1.1"))]);
+        assert_matches!(
+            resolver.lookup_ip("cdsi.signal.org").await,
+            Err(Error::NxDomain)
+        );
+
+        // Unless asked not to.
+        assert_matches!(
+            resolver.lookup_ip_without_fallback("chat.signal.org").await,
+            Err(Error::ServFail)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cache_answers_until_it_expires() {
+        let lookup = CountingDnsLookup {
+            answers: FakeDnsLookup(HashMap::from([(
+                "chat.signal.org",
+                Ok(LookupResult::new(vec![ip_addr!(v4, "1.1.1.1")], vec![])),
+            )])),
+            ..Default::default()
+        };
+        let count = lookup.count.clone();
+        let resolver = DnsResolver::default()
+            .with_lookup(lookup)
+            .with_cache(Duration::from_secs(60));
+
+        let (first, second) = tokio::join!(
+            resolver.lookup_ip("chat.signal.org"),
+            resolver.lookup_ip("chat.signal.org")
+        );
+        assert_matches!((first, second), (Ok(_), Ok(_)));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Failures aren't cached.
+        for _ in 0..2 {
+            assert_matches!(
+                resolver.lookup_ip("cdsi.signal.org").await,
+                Err(Error::NxDomain)
+            );
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_matches!(resolver.lookup_ip("chat.signal.org").await, Ok(_));
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
 
     #[test]
     fn lookup_result_iterates_in_the_right_order() {
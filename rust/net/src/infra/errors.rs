@@ -4,11 +4,86 @@
 //
 
 use std::fmt::Display;
+use std::time::Duration;
 
+use serde::Serialize;
+
+use crate::infra::connection_manager::RouteAttempts;
 use crate::infra::{certs, dns};
 
+/// Marks types whose [`Display`] output can be logged as is.
+///
+/// That rules out user identifiers and credentials, hostnames (routes are
+/// shown by their index, enclaves by their flavor name), and dumps of bytes
+/// received from the network. Each implementation notes what its output is
+/// limited to.
 pub trait LogSafeDisplay: Display {}
 
+/// Attestation failures describe what didn't match in terms of public values:
+/// enclave and platform measurements as hex, TCB statuses, and raft group
+/// settings. Claims and other bytes the enclave sent are never included.
+impl LogSafeDisplay for attest::enclave::Error {}
+
+/// Wraps the Noise error, which only names what went wrong.
+impl LogSafeDisplay for attest::client_connection::Error {}
+
+/// Coarse classification of a failure, suitable for showing to users.
+///
+/// Re-exported as [`crate::svr::ErrorCategory`], where the errors of enclave
+/// operations are classified.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum ErrorCategory {
+    Timeout,
+    RateLimited,
+    /// The server could not be reached.
+    TcpConnect,
+    /// The connection was cut off after being established.
+    ConnectionLost,
+    Network,
+    Attestation,
+    Protocol,
+    /// The caller gave up on the operation; nothing went wrong.
+    Cancelled,
+    /// The app has to be updated before the server accepts it again.
+    ClientDeprecated,
+}
+
+/// Errors that may carry a server-provided hint on when to try again.
+pub trait RetryLater {
+    /// Returns the delay requested by the server, or `None` if no hint was provided.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether the server said that trying again won't ever succeed.
+    ///
+    /// Connection managers give up on such failures right away, instead of
+    /// trying other routes or waiting out a cooldown first.
+    fn is_permanent(&self) -> bool {
+        false
+    }
+
+    /// How the failure is classified when a connection manager records it as
+    /// the way a route failed.
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::Network
+    }
+}
+
+/// A phase of establishing a connection, limited by the matching field of
+/// [`ConnectTimeouts`](crate::infra::ConnectTimeouts).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, displaydoc::Display, Serialize)]
+pub enum ConnectPhase {
+    /// DNS lookup
+    Dns,
+    /// TCP and TLS connection
+    Transport,
+    /// WebSocket upgrade
+    WebSocketUpgrade,
+    /// Attestation
+    Attestation,
+}
+
 #[derive(displaydoc::Display, Debug, thiserror::Error)]
 #[cfg_attr(test, derive(Eq, PartialEq))]
 pub enum NetError {
@@ -16,8 +91,8 @@ pub enum NetError {
     CertError,
     /// DNS lookup failed
     DnsError,
-    /// Failed to establish TCP connection to any of the IPs
-    TcpConnectionFailed,
+    /// Failed to establish TCP connection to any of the IPs: {0}
+    TcpConnectionFailed(std::io::ErrorKind),
     /// SSL error
     SslError,
     /// Failed to establish SSL connection
@@ -30,8 +105,12 @@ pub enum NetError {
     Http2FailedHandshake,
     /// Operation timed out
     Timeout,
+    /// {0} timed out
+    ConnectTimeout(ConnectPhase),
     /// Failure
     Failure,
+    /// IO error: {0}
+    Io(std::io::ErrorKind),
     /// Failed to decode data received from the server
     IncomingDataInvalid,
     /// Request object must contain only ASCII text as header names and values.
@@ -60,14 +139,143 @@ pub enum NetError {
     HttpInterruptedDuringReceive,
     /// Failed to create HTTP object: an invalid component (method/path/header key/header value)
     InvalidHttpRequestComponent,
+    /// Server asked to retry after {retry_after_seconds}s
+    RateLimited { retry_after_seconds: u32 },
+    /// All routes failed: {attempts}
+    AllRoutesFailed { attempts: RouteAttempts },
+    /// Sending stalled for too long
+    SendTimeout,
+    /// Server no longer accepts this version of the client
+    ClientDeprecated,
 }
 
 impl LogSafeDisplay for NetError {}
 
+impl RetryLater for NetError {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited {
+                retry_after_seconds,
+            } => Some(Duration::from_secs((*retry_after_seconds).into())),
+            _ => None,
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        matches!(self, Self::ClientDeprecated)
+    }
+
+    fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Timeout | Self::ConnectTimeout(_) | Self::SendTimeout => ErrorCategory::Timeout,
+            Self::RateLimited { .. } => ErrorCategory::RateLimited,
+            Self::ClientDeprecated => ErrorCategory::ClientDeprecated,
+            Self::TcpConnectionFailed(_) => ErrorCategory::TcpConnect,
+            Self::AllRoutesFailed { attempts } => attempts.category(),
+            Self::ChannelClosed | Self::ChannelClosedByRemotePeer | Self::ChannelIdle => {
+                ErrorCategory::ConnectionLost
+            }
+            Self::WebSocketError(ws) if ws.is_connection_closed() => ErrorCategory::ConnectionLost,
+            net => match net.io_error_kind() {
+                Some(std::io::ErrorKind::ConnectionRefused) => ErrorCategory::TcpConnect,
+                Some(
+                    std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe,
+                ) => ErrorCategory::ConnectionLost,
+                _ => ErrorCategory::Network,
+            },
+        }
+    }
+}
+
+impl NetError {
+    /// A stable name for the variant, e.g. `"connect_timeout"`, for reports
+    /// that are aggregated by it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::CertError => "cert_error",
+            Self::DnsError => "dns_error",
+            Self::TcpConnectionFailed(_) => "tcp_connection_failed",
+            Self::SslError => "ssl_error",
+            Self::SslFailedHandshake => "ssl_failed_handshake",
+            Self::ContentLengthHeaderInvalid => "content_length_header_invalid",
+            Self::ContentLengthHeaderDoesntMatchDataSize => {
+                "content_length_header_doesnt_match_data_size"
+            }
+            Self::Http2FailedHandshake => "http2_failed_handshake",
+            Self::Timeout => "timeout",
+            Self::ConnectTimeout(_) => "connect_timeout",
+            Self::Failure => "failure",
+            Self::Io(_) => "io",
+            Self::IncomingDataInvalid => "incoming_data_invalid",
+            Self::RequestHasInvalidHeader => "request_has_invalid_header",
+            Self::UnexpectedFrameReceived => "unexpected_frame_received",
+            Self::ChannelClosed => "channel_closed",
+            Self::WebSocketError(_) => "websocket_error",
+            Self::ChannelClosedWithError => "channel_closed_with_error",
+            Self::ChannelClosedByRemotePeer => "channel_closed_by_remote_peer",
+            Self::ChannelClosedByLocalPeer => "channel_closed_by_local_peer",
+            Self::ChannelIdle => "channel_idle",
+            Self::NoServiceConnection => "no_service_connection",
+            Self::ServerRequestMissingId => "server_request_missing_id",
+            Self::FailedToPassMessageToIncomingChannel => {
+                "failed_to_pass_message_to_incoming_channel"
+            }
+            Self::HttpInterruptedDuringReceive => "http_interrupted_during_receive",
+            Self::InvalidHttpRequestComponent => "invalid_http_request_component",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::AllRoutesFailed { .. } => "all_routes_failed",
+            Self::SendTimeout => "send_timeout",
+            Self::ClientDeprecated => "client_deprecated",
+        }
+    }
+
+    /// The phase of connecting that failed, if this is known to be a failure
+    /// to connect.
+    pub fn connect_phase(&self) -> Option<ConnectPhase> {
+        match self {
+            Self::ConnectTimeout(phase) => Some(*phase),
+            Self::DnsError => Some(ConnectPhase::Dns),
+            Self::CertError
+            | Self::TcpConnectionFailed(_)
+            | Self::SslError
+            | Self::SslFailedHandshake => Some(ConnectPhase::Transport),
+            _ => None,
+        }
+    }
+
+    /// The kind of the I/O error behind this one, if there was one.
+    ///
+    /// Only the kind is kept, not the original error, since its message may
+    /// not be safe to log.
+    pub fn io_error_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Self::TcpConnectionFailed(kind)
+            | Self::Io(kind)
+            | Self::WebSocketError(crate::infra::ws::Error::Io(kind)) => Some(*kind),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status the server refused the websocket upgrade with, if it
+    /// did.
+    ///
+    /// [`NetError::RateLimited`] and [`NetError::ClientDeprecated`] have none,
+    /// since the server can also say either by closing the websocket; use
+    /// [`RetryLater::retry_after`] and [`RetryLater::is_permanent`] for those.
+    pub fn http_status(&self) -> Option<http::StatusCode> {
+        match self {
+            Self::WebSocketError(crate::infra::ws::Error::Http(status)) => Some(*status),
+            _ => None,
+        }
+    }
+}
+
 impl From<std::io::Error> for NetError {
     fn from(value: std::io::Error) -> Self {
         log::error!("{}", value);
-        NetError::Failure
+        NetError::Io(value.kind())
     }
 }
 
@@ -91,6 +299,206 @@ impl From<boring::error::ErrorStack> for NetError {
 
 impl From<tungstenite::error::Error> for NetError {
     fn from(value: tungstenite::error::Error) -> Self {
+        if let tungstenite::error::Error::Http(response) = &value {
+            if response.status().as_u16() == CLIENT_DEPRECATED_STATUS {
+                return Self::ClientDeprecated;
+            }
+            if let Some(retry_after_seconds) = retry_after_seconds(response) {
+                return Self::RateLimited {
+                    retry_after_seconds,
+                };
+            }
+        }
         Self::WebSocketError(value.into())
     }
 }
+
+/// HTTP status the server rejects the websocket upgrade of a deprecated client
+/// with.
+const CLIENT_DEPRECATED_STATUS: u16 = 499;
+
+/// Extracts the `Retry-After` value (in seconds) from a `429 Too Many Requests` response.
+fn retry_after_seconds<T>(response: &tungstenite::http::Response<T>) -> Option<u32> {
+    if response.status() != tungstenite::http::StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    response
+        .headers()
+        .get(tungstenite::http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use tungstenite::http::{header, Response, StatusCode};
+
+    use super::*;
+
+    fn http_error(status: StatusCode, retry_after: Option<&str>) -> tungstenite::Error {
+        let mut builder = Response::builder().status(status);
+        if let Some(value) = retry_after {
+            builder = builder.header(header::RETRY_AFTER, value);
+        }
+        tungstenite::Error::Http(builder.body(None).expect("valid response"))
+    }
+
+    #[test]
+    fn too_many_requests_with_retry_after_is_rate_limited() {
+        let error = NetError::from(http_error(StatusCode::TOO_MANY_REQUESTS, Some("30")));
+        assert_eq!(
+            error,
+            NetError::RateLimited {
+                retry_after_seconds: 30
+            }
+        );
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn deprecated_client_status_is_permanent() {
+        let error = NetError::from(http_error(
+            StatusCode::from_u16(CLIENT_DEPRECATED_STATUS).expect("valid status"),
+            None,
+        ));
+        assert_eq!(error, NetError::ClientDeprecated);
+        assert!(error.is_permanent());
+        assert!(!NetError::from(http_error(StatusCode::TOO_MANY_REQUESTS, None)).is_permanent());
+    }
+
+    #[test]
+    fn no_retry_after_hint_without_header() {
+        let error = NetError::from(http_error(StatusCode::TOO_MANY_REQUESTS, None));
+        assert_eq!(error.retry_after(), None);
+        let error = NetError::from(http_error(StatusCode::INTERNAL_SERVER_ERROR, Some("30")));
+        assert_eq!(error.retry_after(), None);
+    }
+
+    #[test]
+    fn http_status_of_refused_upgrades() {
+        let error = NetError::from(http_error(StatusCode::SERVICE_UNAVAILABLE, None));
+        assert_eq!(error.http_status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        let error = NetError::from(http_error(StatusCode::TOO_MANY_REQUESTS, Some("30")));
+        assert_eq!(error.http_status(), None);
+        assert_eq!(NetError::Timeout.http_status(), None);
+    }
+}
+
+/// Checks that the errors reachable from SVR connections and operations can be
+/// logged, both at compile time and by looking at what they say.
+#[cfg(test)]
+mod log_safe_test {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::infra::connection_manager::{RouteAttemptError, RouteFailure};
+    use crate::infra::ws::AttestedConnectionError;
+
+    const fn assert_log_safe<T: LogSafeDisplay>() {}
+
+    const _: () = {
+        assert_log_safe::<NetError>();
+        assert_log_safe::<RouteAttempts>();
+        assert_log_safe::<certs::Error>();
+        assert_log_safe::<dns::Error>();
+        assert_log_safe::<crate::infra::ws::Error>();
+        assert_log_safe::<crate::infra::ws::error::SpaceError>();
+        assert_log_safe::<crate::infra::ws::error::ProtocolError>();
+        assert_log_safe::<crate::infra::ws::error::HttpFormatError>();
+        assert_log_safe::<AttestedConnectionError>();
+        assert_log_safe::<attest::enclave::Error>();
+        assert_log_safe::<attest::client_connection::Error>();
+        assert_log_safe::<crate::svr::Error>();
+        assert_log_safe::<crate::svr3::Error>();
+        assert_log_safe::<crate::svr3::DeserializeError>();
+        assert_log_safe::<crate::svr3::reachability::ReachabilityError>();
+        assert_log_safe::<crate::env::ValidationError>();
+    };
+
+    const UNKNOWN_MEASUREMENT: [u8; 32] = [0xab; 32];
+
+    /// Fails if `error` shows a host, the measurement or user ID used in these
+    /// tests, or a `Debug` dump of bytes.
+    fn assert_nothing_forbidden(error: &dyn LogSafeDisplay) {
+        let message = error.to_string();
+        let svr3 = &crate::env::STAGING.svr3;
+        let forbidden = [
+            svr3.sgx().domain_config.hostname,
+            svr3.nitro().domain_config.hostname,
+            "signal.org",
+            "abababab",
+            "171, 171",
+            "[",
+        ];
+        for substring in forbidden {
+            assert!(!message.contains(substring), "{substring:?} in {message:?}");
+        }
+    }
+
+    #[test]
+    fn representative_errors_have_nothing_forbidden() {
+        let now = SystemTime::now();
+        let sgx_error = attest::svr2::new_handshake(&UNKNOWN_MEASUREMENT, &[], now)
+            .err()
+            .expect("unknown enclave");
+        let nitro_error = attest::nitro::new_handshake(&UNKNOWN_MEASUREMENT, &[], now, None)
+            .err()
+            .expect("unknown enclave");
+        let all_routes_failed = NetError::AllRoutesFailed {
+            attempts: RouteAttempts(vec![
+                RouteAttemptError {
+                    route_index: 0,
+                    failure: RouteFailure::Error {
+                        category: ErrorCategory::Network,
+                        description: NetError::DnsError.to_string(),
+                    },
+                    duration: Duration::from_millis(20),
+                },
+                RouteAttemptError {
+                    route_index: 1,
+                    failure: RouteFailure::TimedOut,
+                    duration: Duration::from_secs(5),
+                },
+            ]),
+        };
+        let unreachable = crate::svr3::reachability::ReachabilityError::Unreachable {
+            enclave: "sgx",
+            host: crate::env::STAGING
+                .svr3
+                .sgx()
+                .domain_config
+                .hostname
+                .to_string(),
+            port: 443,
+            reason: NetError::ConnectTimeout(ConnectPhase::Transport),
+        };
+
+        let errors: [&dyn LogSafeDisplay; 7] = [
+            &crate::svr3::Error::AttestationError(sgx_error),
+            &AttestedConnectionError::Sgx(nitro_error),
+            &AttestedConnectionError::SendFailed(NetError::from(dns::Error::NxDomain)),
+            &crate::svr3::Error::RequestNotSent(all_routes_failed),
+            &crate::svr3::Error::from(libsignal_svr3::Error::BadResponseStatus(
+                libsignal_svr3::ErrorStatus::InvalidRequest,
+            )),
+            &crate::svr3::Error::from(crate::svr::Error::Protocol),
+            &unreachable,
+        ];
+        for error in errors {
+            assert_nothing_forbidden(error);
+        }
+    }
+
+    #[test]
+    fn unknown_enclaves_are_not_dumped() {
+        let error = attest::svr2::new_handshake(&UNKNOWN_MEASUREMENT, &[], SystemTime::now())
+            .err()
+            .expect("unknown enclave");
+        assert_eq!(
+            crate::svr::Error::AttestationError(error).to_string(),
+            "Enclave attestation failed: attestation data invalid: unknown mrenclave"
+        );
+    }
+}
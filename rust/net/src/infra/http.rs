@@ -157,6 +157,7 @@ mod test {
             port: FAKE_PORT,
             http_request_decorator: Default::default(),
             certs: crate::infra::certs::RootCertificates::Native,
+            connect_timeouts: crate::infra::ConnectTimeouts::UNLIMITED,
         };
     }
 
@@ -0,0 +1,193 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Tracking of changes to the device's network.
+//!
+//! When the active network interface changes, say from Wi-Fi to cellular,
+//! every connection made over the old one is dead, even if it hasn't noticed
+//! yet. The app learns about such changes from the OS and passes them on with
+//! [`NetworkState::mark_changed`]. Each connection takes a [`NetworkWatch`]
+//! when it starts connecting, and once the network has changed since, it
+//! reports its failures as the network having changed rather than as ordinary
+//! IO errors, so that callers know to reconnect. Connections made after the
+//! change are unaffected.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+type ChangeHook = Arc<dyn Fn() + Send + Sync>;
+
+#[derive(Default)]
+pub struct NetworkState {
+    /// Bumped by every [`NetworkState::mark_changed`].
+    generation: AtomicU64,
+    next_hook_id: AtomicU64,
+    on_change: Mutex<Vec<(u64, ChangeHook)>>,
+}
+
+impl std::fmt::Debug for NetworkState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NetworkState")
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NetworkState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the network changed, invalidating existing connections,
+    /// and runs the hooks added with [`Self::on_change`].
+    ///
+    /// The hooks run without any lock held, so they may add or remove hooks
+    /// themselves; such changes take effect from the next call.
+    pub fn mark_changed(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let hooks: Vec<ChangeHook> = self
+            .on_change
+            .lock()
+            .expect("not poisoned")
+            .iter()
+            .map(|(_id, hook)| hook.clone())
+            .collect();
+        for hook in hooks {
+            hook()
+        }
+    }
+
+    /// Runs `hook` on every later [`Self::mark_changed`], for state that
+    /// shouldn't outlive the network it was learned on.
+    ///
+    /// The hook is removed when the returned guard is dropped.
+    pub fn on_change(self: &Arc<Self>, hook: impl Fn() + Send + Sync + 'static) -> ChangeHookGuard {
+        let id = self.next_hook_id.fetch_add(1, Ordering::Relaxed);
+        self.on_change
+            .lock()
+            .expect("not poisoned")
+            .push((id, Arc::new(hook)));
+        ChangeHookGuard {
+            state: Arc::downgrade(self),
+            id,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn hook_count(&self) -> usize {
+        self.on_change.lock().expect("not poisoned").len()
+    }
+
+    /// Notes the current network, to tell later whether it has changed.
+    pub fn watch(self: &Arc<Self>) -> NetworkWatch {
+        NetworkWatch {
+            generation: self.generation.load(Ordering::Relaxed),
+            state: self.clone(),
+        }
+    }
+}
+
+/// Keeps a hook added with [`NetworkState::on_change`] registered until it is
+/// dropped.
+#[derive(Debug)]
+#[must_use = "the hook is removed as soon as the guard is dropped"]
+pub struct ChangeHookGuard {
+    state: Weak<NetworkState>,
+    id: u64,
+}
+
+impl Drop for ChangeHookGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.upgrade() {
+            state
+                .on_change
+                .lock()
+                .expect("not poisoned")
+                .retain(|(id, _hook)| *id != self.id)
+        }
+    }
+}
+
+/// The network as of a [`NetworkState::watch`], usually the one a connection
+/// was made on.
+///
+/// The default watches a network that never changes.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkWatch {
+    state: Arc<NetworkState>,
+    generation: u64,
+}
+
+impl NetworkWatch {
+    /// Whether the network changed since the watch was taken.
+    pub fn changed(&self) -> bool {
+        self.state.generation.load(Ordering::Relaxed) != self.generation
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watches_only_see_later_changes() {
+        let state = Arc::new(NetworkState::new());
+        let before = state.watch();
+        assert!(!before.changed());
+
+        state.mark_changed();
+        let after = state.watch();
+        assert!(before.changed());
+        assert!(!after.changed());
+
+        state.mark_changed();
+        assert!(after.changed());
+        assert!(!NetworkWatch::default().changed());
+    }
+
+    #[test]
+    fn hooks_run_until_their_guard_is_dropped() {
+        let state = Arc::new(NetworkState::new());
+        let calls = Arc::new(AtomicU64::new(0));
+        let guard = state.on_change({
+            let calls = calls.clone();
+            move || {
+                calls.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        state.mark_changed();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        drop(guard);
+        assert_eq!(state.hook_count(), 0);
+        state.mark_changed();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn hooks_can_change_hooks() {
+        let state = Arc::new(NetworkState::new());
+        let added = Arc::new(Mutex::new(Vec::new()));
+        let _guard = state.on_change({
+            let weak_state = Arc::downgrade(&state);
+            let added = added.clone();
+            move || {
+                let state = weak_state.upgrade().expect("still alive");
+                added
+                    .lock()
+                    .expect("not poisoned")
+                    .push(state.on_change(|| {}));
+            }
+        });
+
+        // Would deadlock if the hooks ran with the lock held.
+        state.mark_changed();
+        assert_eq!(state.hook_count(), 2);
+
+        added.lock().expect("not poisoned").clear();
+        assert_eq!(state.hook_count(), 1);
+    }
+}
@@ -4,8 +4,8 @@
 //
 
 use std::fmt::Debug;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
 use std::time::Duration;
 
 use async_trait::async_trait;
@@ -14,8 +14,10 @@ use tokio::sync::Mutex;
 use tokio::time::{timeout_at, Instant};
 use tokio_util::sync::CancellationToken;
 
-use crate::infra::connection_manager::{ConnectionAttemptOutcome, ConnectionManager};
-use crate::infra::errors::LogSafeDisplay;
+use crate::infra::connection_manager::{
+    ConnectionAttemptOutcome, ConnectionManager, RouteAttemptError, RouteAttempts,
+};
+use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
 use crate::infra::{ConnectionParams, HttpRequestDecorator};
 
 /// For a service that needs to go through some initialization procedure
@@ -33,6 +35,63 @@ pub enum ServiceState<T, E> {
     Error(E),
     /// Last connection attempt timed out.
     TimedOut,
+    /// Every route was either tried and failed, as recorded in `attempts`, or
+    /// is cooling down. Like [`Cooldown`](Self::Cooldown), no attempts are to
+    /// be made until `next_attempt_time`.
+    AllRoutesFailed {
+        next_attempt_time: Instant,
+        attempts: RouteAttempts,
+    },
+}
+
+impl<T, E> ServiceState<T, E> {
+    /// Transforms the service of an [`Active`](Self::Active) state, leaving the
+    /// other states as they are.
+    pub fn map_active<U, F: FnOnce(T) -> U>(self, f: F) -> ServiceState<U, E> {
+        self.and_then_active(|service| Ok(f(service)))
+    }
+
+    /// Like [`Self::map_active`], but a failed transformation turns the state
+    /// into [`Error`](Self::Error).
+    pub fn and_then_active<U, F: FnOnce(T) -> Result<U, E>>(self, f: F) -> ServiceState<U, E> {
+        match self {
+            Self::Active(service, status) => match f(service) {
+                Ok(service) => ServiceState::Active(service, status),
+                Err(e) => ServiceState::Error(e),
+            },
+            Self::Cooldown(instant) => ServiceState::Cooldown(instant),
+            Self::Error(e) => ServiceState::Error(e),
+            Self::TimedOut => ServiceState::TimedOut,
+            Self::AllRoutesFailed {
+                next_attempt_time,
+                attempts,
+            } => ServiceState::AllRoutesFailed {
+                next_attempt_time,
+                attempts,
+            },
+        }
+    }
+
+    /// Returns the service of an [`Active`](Self::Active) state, and turns the
+    /// other states into errors.
+    ///
+    /// A cooldown is reported as [`NetError::NoServiceConnection`], a timeout
+    /// as [`NetError::Timeout`], and failures of all routes as
+    /// [`NetError::AllRoutesFailed`].
+    pub fn ok(self) -> Result<(T, ServiceStatus<E>), E>
+    where
+        E: From<NetError>,
+    {
+        match self {
+            Self::Active(service, status) => Ok((service, status)),
+            Self::Cooldown(_) => Err(NetError::NoServiceConnection.into()),
+            Self::Error(e) => Err(e),
+            Self::TimedOut => Err(NetError::Timeout.into()),
+            Self::AllRoutesFailed { attempts, .. } => {
+                Err(NetError::AllRoutesFailed { attempts }.into())
+            }
+        }
+    }
 }
 
 /// Represents the logic needed to establish a connection over some transport.
@@ -50,6 +109,14 @@ pub trait ServiceConnector: Clone {
     ) -> Result<Self::Channel, Self::Error>;
 
     fn start_service(&self, channel: Self::Channel) -> (Self::Service, ServiceStatus<Self::Error>);
+
+    /// Whether the services it starts may ask to be replaced right away with
+    /// [`ServiceStatus::stop_service_and_reconnect`].
+    ///
+    /// A [`ServiceWithReconnect`] only watches for such requests if so.
+    fn reconnects_on_request(&self) -> bool {
+        false
+    }
 }
 
 #[async_trait]
@@ -71,6 +138,10 @@ where
     fn start_service(&self, channel: Self::Channel) -> (Self::Service, ServiceStatus<Self::Error>) {
         (*self).start_service(channel)
     }
+
+    fn reconnects_on_request(&self) -> bool {
+        (*self).reconnects_on_request()
+    }
 }
 
 #[derive(Clone)]
@@ -107,6 +178,10 @@ where
     fn start_service(&self, channel: Self::Channel) -> (Self::Service, ServiceStatus<Self::Error>) {
         self.inner.start_service(channel)
     }
+
+    fn reconnects_on_request(&self) -> bool {
+        self.inner.reconnects_on_request()
+    }
 }
 
 #[derive(Debug)]
@@ -114,6 +189,7 @@ where
 pub struct ServiceStatus<E> {
     maybe_error: Arc<OnceLock<E>>,
     service_cancellation: CancellationToken,
+    reconnect_requested: Arc<AtomicBool>,
 }
 
 impl<E> Default for ServiceStatus<E> {
@@ -121,6 +197,7 @@ impl<E> Default for ServiceStatus<E> {
         Self {
             maybe_error: Arc::new(OnceLock::new()),
             service_cancellation: CancellationToken::new(),
+            reconnect_requested: Arc::default(),
         }
     }
 }
@@ -135,6 +212,19 @@ impl<E> ServiceStatus<E> {
         self.maybe_error.get_or_init(|| error);
     }
 
+    /// Like [`Self::stop_service_with_error`], but also asks for the service
+    /// to be replaced right away rather than when it is next needed.
+    ///
+    /// Only a [`ServiceWithReconnect`] acts on this.
+    pub fn stop_service_and_reconnect(&self, error: E) {
+        self.reconnect_requested.store(true, Ordering::SeqCst);
+        self.stop_service_with_error(error);
+    }
+
+    pub fn is_reconnect_requested(&self) -> bool {
+        self.reconnect_requested.load(Ordering::SeqCst)
+    }
+
     pub fn is_stopped(&self) -> bool {
         self.service_cancellation.is_cancelled()
     }
@@ -159,7 +249,7 @@ where
     C: ServiceConnector + Send + Sync + 'a,
     C::Service: Send + Sync + 'a,
     C::Channel: Send + Sync,
-    C::Error: Send + Sync + Debug + LogSafeDisplay,
+    C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
 {
     pub fn new(service_connector: C, connection_manager: M) -> Self {
         Self {
@@ -169,17 +259,32 @@ where
     }
 
     pub async fn connect(&self) -> ServiceState<C::Service, C::Error> {
+        self.connect_recording(&mut Vec::new()).await
+    }
+
+    /// Like [`Self::connect`], but also records in `failures`, which should
+    /// start out empty, how each route tried along the way failed, even if a
+    /// later route worked.
+    pub async fn connect_recording(
+        &self,
+        failures: &mut Vec<RouteAttemptError>,
+    ) -> ServiceState<C::Service, C::Error> {
         log::debug!("attempting a connection");
         let connection_attempt_result = self
             .connection_manager
-            .connect_or_wait(|connection_params| {
-                log::debug!(
-                    "trying to connect to {}:{}",
-                    connection_params.host,
-                    connection_params.port
-                );
-                self.service_connector.connect_channel(connection_params)
-            })
+            .connect_or_wait_recording(
+                |connection_params| async move {
+                    log::debug!(
+                        "trying to connect to {}:{}",
+                        connection_params.host,
+                        connection_params.port
+                    );
+                    self.service_connector
+                        .connect_channel(&connection_params)
+                        .await
+                },
+                failures,
+            )
             .await;
 
         match connection_attempt_result {
@@ -192,6 +297,14 @@ where
                 log::debug!("connection attempt failed due to an error: {:?}", e);
                 ServiceState::Error(e)
             }
+            ConnectionAttemptOutcome::WaitUntil(i) if !failures.is_empty() => {
+                let attempts = RouteAttempts(failures.clone());
+                log::info!("all routes failed: {}", attempts);
+                ServiceState::AllRoutesFailed {
+                    next_attempt_time: i,
+                    attempts,
+                }
+            }
             ConnectionAttemptOutcome::WaitUntil(i) => {
                 log::debug!(
                     "connection will not be attempted for another {} seconds",
@@ -225,7 +338,7 @@ where
     C: ServiceConnector + Send + Sync + 'static,
     C::Service: Clone + Send + Sync + 'static,
     C::Channel: Send + Sync,
-    C::Error: Send + Sync + Debug + LogSafeDisplay,
+    C::Error: Send + Sync + Debug + LogSafeDisplay + RetryLater,
 {
     pub fn new(service_connector: C, connection_manager: M, connection_timeout: Duration) -> Self {
         // We're starting in a `Cooldown` state with a `next_attempt_time` set to `now`,
@@ -284,7 +397,10 @@ where
                         log::info!("Service stopped due to an error: {}", error);
                     }
                 }
-                ServiceState::Cooldown(next_attempt_time) => {
+                ServiceState::Cooldown(next_attempt_time)
+                | ServiceState::AllRoutesFailed {
+                    next_attempt_time, ..
+                } => {
                     // checking if the `next_attempt_time` is still in the future
                     if next_attempt_time > &deadline {
                         log::debug!("All possible routes are in cooldown state");
@@ -301,6 +417,10 @@ where
                         return None;
                     }
                 }
+                ServiceState::Error(e) if e.is_permanent() => {
+                    log::info!("Connection attempt failed permanently: {}", e);
+                    return None;
+                }
                 ServiceState::Error(e) => {
                     // short circuiting mechanism is responsibility of the `ConnectionManager`,
                     // so here we're just going to keep trying until we get into
@@ -314,6 +434,61 @@ where
                     result
                 }
                 Err(_) => ServiceState::TimedOut,
+            };
+            let reconnects_on_request = self
+                .data
+                .service_initializer
+                .service_connector
+                .reconnects_on_request();
+            match &*guard {
+                ServiceState::Active(_, service_status) if reconnects_on_request => {
+                    tokio::spawn(Self::reconnect_when_requested(
+                        Arc::downgrade(&self.data),
+                        service_status.clone(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the service as soon as it stops, if it asked for that with
+    /// [`ServiceStatus::stop_service_and_reconnect`], so that the next caller
+    /// finds a live service instead of waiting for a new connection.
+    ///
+    /// Gives up if the service was replaced some other way in the meantime,
+    /// or once the new connection can't be made within the connection
+    /// timeout; the next caller then tries again as usual.
+    async fn reconnect_when_requested(
+        data: Weak<ServiceWithReconnectData<C, M>>,
+        mut service_status: ServiceStatus<C::Error>,
+    ) {
+        loop {
+            service_status.stopped().await;
+            if !service_status.is_reconnect_requested() {
+                return;
+            }
+            let Some(data) = data.upgrade() else {
+                return;
+            };
+            let deadline = Instant::now() + data.connection_timeout;
+            let Ok(mut guard) = timeout_at(deadline, data.state.lock()).await else {
+                return;
+            };
+            if !matches!(&*guard, ServiceState::Active(_, status) if status.is_stopped()) {
+                return;
+            }
+            log::info!("Reconnecting ahead of the next request");
+            *guard = match timeout_at(deadline, data.service_initializer.connect()).await {
+                Ok(result) => {
+                    data.reconnect_count.fetch_add(1, Ordering::Relaxed);
+                    result
+                }
+                Err(_) => ServiceState::TimedOut,
+            };
+            match &*guard {
+                ServiceState::Active(_, new_status) => service_status = new_status.clone(),
+                _ => return,
             }
         }
     }
@@ -334,10 +509,13 @@ mod test {
 
     use crate::infra::certs::RootCertificates;
     use crate::infra::connection_manager::{
+        MultiRouteConnectionManager, RouteAttemptError, RouteAttempts, RouteFailure,
         SingleRouteThrottlingConnectionManager, MAX_COOLDOWN_INTERVAL,
     };
+    use crate::infra::errors::ErrorCategory;
+    use crate::infra::errors::NetError;
     use crate::infra::reconnect::{
-        ServiceConnector, ServiceState, ServiceStatus, ServiceWithReconnect,
+        ServiceConnector, ServiceInitializer, ServiceState, ServiceStatus, ServiceWithReconnect,
     };
     use crate::infra::test::shared::{
         TestError, LONG_CONNECTION_TIME, NORMAL_CONNECTION_TIME, TIMEOUT_DURATION,
@@ -365,6 +543,7 @@ mod test {
         attempts: Arc<AtomicI32>,
         time_to_connect: Arc<Mutex<Duration>>,
         service_healthy: Arc<AtomicBool>,
+        reconnects_on_request: bool,
     }
 
     impl TestServiceConnector {
@@ -373,9 +552,15 @@ mod test {
                 attempts: Arc::new(AtomicI32::new(0)),
                 time_to_connect: Arc::new(Mutex::new(NORMAL_CONNECTION_TIME)),
                 service_healthy: Arc::new(AtomicBool::new(true)),
+                reconnects_on_request: false,
             }
         }
 
+        fn with_reconnects_on_request(mut self) -> Self {
+            self.reconnects_on_request = true;
+            self
+        }
+
         fn attempts_made(&self) -> i32 {
             self.attempts.fetch_or(0, Ordering::Relaxed)
         }
@@ -420,6 +605,10 @@ mod test {
             let service = TestService::new(service_status_arc.clone());
             (service, service_status_arc)
         }
+
+        fn reconnects_on_request(&self) -> bool {
+            self.reconnects_on_request
+        }
     }
 
     fn example_connection_params() -> ConnectionParams {
@@ -456,6 +645,56 @@ mod test {
         assert_eq!(connector.attempts_made(), 1);
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn service_replaced_right_away_when_asked() {
+        let connector = TestServiceConnector::new().with_reconnects_on_request();
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+
+        // A service that just stops is replaced when it is next needed...
+        let service = service_with_reconnect.service_clone().await;
+        service.expect("service is present").close_channel();
+        time::sleep(TIMEOUT_DURATION).await;
+        assert_eq!(connector.attempts_made(), 1);
+
+        // ...but one that asks to be replaced is replaced right away.
+        let service = service_with_reconnect.service_clone().await;
+        assert_eq!(connector.attempts_made(), 2);
+        service
+            .expect("service is present")
+            .service_status
+            .stop_service_and_reconnect(TestError::Expected);
+        time::sleep(TIMEOUT_DURATION).await;
+        assert_eq!(connector.attempts_made(), 3);
+        assert_matches!(
+            *service_with_reconnect.data.state.lock().await,
+            ServiceState::Active(_, ref status) if !status.is_stopped()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn service_not_replaced_early_unless_connector_allows() {
+        let connector = TestServiceConnector::new();
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+
+        let service = service_with_reconnect.service_clone().await;
+        service
+            .expect("service is present")
+            .service_status
+            .stop_service_and_reconnect(TestError::Expected);
+        time::sleep(TIMEOUT_DURATION).await;
+        assert_eq!(connector.attempts_made(), 1);
+    }
+
     #[tokio::test]
     async fn moving_to_inactive_on_channel_closed() {
         let connector = TestServiceConnector::new();
@@ -507,6 +746,48 @@ mod test {
             .is_none());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn connect_reports_failures_of_all_routes() {
+        let connector = TestServiceConnector::new();
+        connector.set_service_healthy(false);
+        let manager = MultiRouteConnectionManager::new(
+            std::iter::repeat_with(|| {
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(),
+                    TIMEOUT_DURATION,
+                )
+            })
+            .take(2)
+            .collect(),
+            TIMEOUT_DURATION * 5,
+        );
+        let service_initializer = ServiceInitializer::new(connector.clone(), manager);
+
+        let attempts = assert_matches!(
+            service_initializer.connect().await,
+            ServiceState::AllRoutesFailed { attempts, .. } => attempts
+        );
+        let failure = |route_index| RouteAttemptError {
+            route_index,
+            failure: RouteFailure::Error {
+                category: ErrorCategory::Network,
+                description: "expected error".to_owned(),
+            },
+            duration: NORMAL_CONNECTION_TIME,
+        };
+        assert_eq!(
+            attempts,
+            RouteAttempts(vec![failure(0), failure(0), failure(1), failure(1)])
+        );
+
+        // Without any route tried, it's a plain cooldown.
+        assert_matches!(
+            service_initializer.connect().await,
+            ServiceState::Cooldown(_)
+        );
+        assert_eq!(connector.attempts_made(), 4);
+    }
+
     #[tokio::test]
     async fn retry_connection_after_service_disconnected() {
         let connector = TestServiceConnector::new();
@@ -637,4 +918,67 @@ mod test {
         let service = service_with_reconnect.service_clone().await;
         assert!(service.is_some());
     }
+
+    #[test]
+    fn service_state_combinators_only_touch_active_services() {
+        let active = || ServiceState::<u32, NetError>::Active(1, ServiceStatus::default());
+
+        assert_matches!(active().map_active(|n| n + 1), ServiceState::Active(2, _));
+        assert_matches!(
+            active().and_then_active(|n| Ok::<_, NetError>(n + 1)),
+            ServiceState::Active(2, _)
+        );
+        assert_matches!(
+            active().and_then_active(|_| Err::<u32, _>(NetError::Failure)),
+            ServiceState::Error(NetError::Failure)
+        );
+
+        let now = Instant::now();
+        assert_matches!(
+            ServiceState::<u32, NetError>::Cooldown(now).map_active(|n| n + 1),
+            ServiceState::Cooldown(i) if i == now
+        );
+        assert_matches!(
+            ServiceState::<u32, _>::Error(NetError::Failure).and_then_active(|n| Ok(n + 1)),
+            ServiceState::Error(NetError::Failure)
+        );
+        assert_matches!(
+            ServiceState::<u32, NetError>::TimedOut.map_active(|n| n + 1),
+            ServiceState::TimedOut
+        );
+    }
+
+    #[test]
+    fn service_state_ok_reports_inactive_states_as_errors() {
+        assert_matches!(
+            ServiceState::<u32, NetError>::Active(1, ServiceStatus::default()).ok(),
+            Ok((1, _))
+        );
+        assert_matches!(
+            ServiceState::<u32, NetError>::Cooldown(Instant::now()).ok(),
+            Err(NetError::NoServiceConnection)
+        );
+        assert_matches!(
+            ServiceState::<u32, _>::Error(NetError::Failure).ok(),
+            Err(NetError::Failure)
+        );
+        assert_matches!(
+            ServiceState::<u32, NetError>::TimedOut.ok(),
+            Err(NetError::Timeout)
+        );
+
+        let attempts = RouteAttempts(vec![RouteAttemptError {
+            route_index: 1,
+            failure: RouteFailure::TimedOut,
+            duration: TIMEOUT_DURATION,
+        }]);
+        assert_matches!(
+            ServiceState::<u32, NetError>::AllRoutesFailed {
+                next_attempt_time: Instant::now(),
+                attempts: attempts.clone(),
+            }
+            .ok(),
+            Err(NetError::AllRoutesFailed { attempts: a }) if a == attempts
+        );
+    }
 }
@@ -3,24 +3,33 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::future::Future;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
 use derive_where::derive_where;
 use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{SinkExt as _, StreamExt};
+use futures_util::{SinkExt as _, StreamExt, TryFutureExt as _};
 use http::uri::PathAndQuery;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
 use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::{CancellationToken, DropGuard};
 use tungstenite::handshake::client::generate_key;
+use tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tungstenite::protocol::frame::Frame;
 use tungstenite::protocol::CloseFrame;
 use tungstenite::{http, Message};
 
-use crate::infra::errors::NetError;
+use crate::infra::correlation::CorrelationId;
+use crate::infra::errors::{ConnectPhase, LogSafeDisplay, NetError};
+use crate::infra::network_state::NetworkWatch;
 use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
 use crate::infra::{AsyncDuplexStream, ConnectionParams, StreamAndHost, TransportConnector};
 use crate::utils::timeout;
@@ -39,12 +48,22 @@ pub struct WebSocketConfig {
     pub max_connection_time: Duration,
     pub keep_alive_interval: Duration,
     pub max_idle_time: Duration,
+    /// Most bytes of messages that may be waiting to be written out at once.
+    ///
+    /// Sends beyond that wait for earlier ones to be written, so a stalled
+    /// uplink holds up the senders instead of growing the queue.
+    pub max_queued_send_bytes: NonZeroUsize,
+    /// How long a send may wait for room in the queue, and then for its
+    /// message to be written out, before it fails with
+    /// [`NetError::SendTimeout`] and the connection is closed.
+    pub send_stall_timeout: Duration,
 }
 
 #[derive(Clone)]
 pub struct WebSocketClientConnector<T> {
     transport_connector: T,
     cfg: WebSocketConfig,
+    reconnect_on_ping_failure: bool,
 }
 
 impl<T: TransportConnector> WebSocketClientConnector<T> {
@@ -52,8 +71,32 @@ impl<T: TransportConnector> WebSocketClientConnector<T> {
         Self {
             transport_connector,
             cfg,
+            reconnect_on_ping_failure: false,
         }
     }
+
+    /// Replaces a connection whose keepalive pings go unanswered while it is
+    /// idle, so that the next request finds a live one.
+    ///
+    /// A ping goes unanswered if no pong has come back by the time the next
+    /// one is due. The connection counts as idle if nothing but control frames was sent
+    /// since the last frame was received, so no request can be waiting for a
+    /// response that would then be lost. Otherwise the failure is only
+    /// reported to the caller, as it is by default. The new connection is made
+    /// by the [`ServiceWithReconnect`](crate::infra::reconnect::ServiceWithReconnect)
+    /// running the service, through the same routes; a service started some
+    /// other way just stops.
+    ///
+    /// This doesn't help [`AttestedConnection`]s: their session is tied to the
+    /// websocket it was established over, so a new websocket needs a new
+    /// attestation handshake, which only connecting anew performs. They also
+    /// only read from the websocket while waiting for a response, so pings
+    /// aren't even sent while they are idle; their idle timeout takes care of
+    /// closing them ahead of time instead.
+    pub fn with_reconnect_on_ping_failure(mut self) -> Self {
+        self.reconnect_on_ping_failure = true;
+        self
+    }
 }
 
 #[async_trait]
@@ -89,8 +132,14 @@ where
             channel.1,
             self.cfg.keep_alive_interval,
             self.cfg.max_idle_time,
+            SendQueue::new(self.cfg.max_queued_send_bytes, self.cfg.send_stall_timeout),
+            self.reconnect_on_ping_failure,
         )
     }
+
+    fn reconnects_on_request(&self) -> bool {
+        self.reconnect_on_ping_failure
+    }
 }
 
 fn start_ws_service<S: AsyncDuplexStream>(
@@ -98,21 +147,28 @@ fn start_ws_service<S: AsyncDuplexStream>(
     remote_address: url::Host,
     keep_alive_interval: Duration,
     max_idle_time: Duration,
+    send_queue: SendQueue,
+    reconnect_on_ping_failure: bool,
 ) -> (WebSocketClient<S>, ServiceStatus<NetError>) {
     let service_status = ServiceStatus::default();
     let (ws_sink, ws_stream) = channel.split();
     let ws_client_writer = WebSocketClientWriter {
         ws_sink: Arc::new(Mutex::new(ws_sink)),
+        send_queue: Arc::new(send_queue),
         service_status: service_status.clone(),
+        last_data_sent: Default::default(),
     };
     let ws_client_reader = WebSocketClientReader {
         ws_stream,
+        peeked: None,
         keep_alive_interval,
         max_idle_time,
+        reconnect_on_ping_failure,
         ws_writer: ws_client_writer.clone(),
         service_status: service_status.clone(),
         last_frame_received: Instant::now(),
         last_keepalive_sent: Instant::now(),
+        awaiting_pong: false,
     };
     (
         WebSocketClient {
@@ -128,36 +184,209 @@ fn start_ws_service<S: AsyncDuplexStream>(
 #[derive(Debug)]
 pub(crate) struct WebSocketClientWriter<S> {
     ws_sink: Arc<Mutex<SplitSink<WebSocketStream<S>, Message>>>,
+    send_queue: Arc<SendQueue>,
     service_status: ServiceStatus<NetError>,
+    /// When a message other than a control frame was last sent, if ever.
+    last_data_sent: Arc<std::sync::Mutex<Option<Instant>>>,
+}
+
+impl<S> WebSocketClientWriter<S> {
+    /// Whether anything other than control frames was sent at or after
+    /// `instant`.
+    fn sent_data_since(&self, instant: Instant) -> bool {
+        self.last_data_sent
+            .lock()
+            .expect("not poisoned")
+            .is_some_and(|sent| sent >= instant)
+    }
 }
 
 impl<S: AsyncDuplexStream> WebSocketClientWriter<S> {
     pub async fn send(&self, message: impl Into<Message>) -> Result<(), NetError> {
+        let message = message.into();
+        if !matches!(
+            message,
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_)
+        ) {
+            *self.last_data_sent.lock().expect("not poisoned") = Some(Instant::now());
+        }
+        let stall_timeout = self.send_queue.stall_timeout;
         run_and_update_status(&self.service_status, || async {
-            let mut guard = self.ws_sink.lock().await;
-            guard.send(message.into()).await?;
-            guard.flush().await?;
-            Ok(())
+            let _queued =
+                tokio::time::timeout(stall_timeout, self.send_queue.enqueue(message.len()))
+                    .await
+                    .map_err(|_| NetError::SendTimeout)?;
+            timeout(stall_timeout, NetError::SendTimeout, async {
+                let mut guard = self.ws_sink.lock().await;
+                guard.send(message).await?;
+                guard.flush().await?;
+                Ok(())
+            })
+            .await
         })
         .await
+        .inspect_err(|e| {
+            if matches!(e, NetError::SendTimeout) {
+                log::warn!(
+                    "websocket send stalled for {}s, closing the connection",
+                    stall_timeout.as_secs()
+                );
+            }
+        })
+    }
+}
+
+/// Bounds the bytes of the messages a [`WebSocketClientWriter`] has accepted
+/// but not yet written out.
+///
+/// Every message takes room for its length until it has been flushed, or
+/// the send is abandoned. A message longer than the whole queue takes all of
+/// it, so it is sent once the queue is empty rather than never.
+#[derive(Debug)]
+pub(crate) struct SendQueue {
+    room: tokio::sync::Semaphore,
+    capacity: u32,
+    stall_timeout: Duration,
+    queued_bytes: AtomicUsize,
+    high_water_mark: AtomicUsize,
+}
+
+/// How full the send queue of a [`WebSocketClient`] is, and has been.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SendQueueStats {
+    /// Bytes of messages waiting to be written out right now.
+    pub queued_bytes: usize,
+    /// The most `queued_bytes` has been since the connection was made.
+    pub high_water_mark: usize,
+}
+
+/// Room in a [`SendQueue`] taken by one message, given back when dropped.
+struct QueuedMessage<'a> {
+    queue: &'a SendQueue,
+    len: usize,
+    _room: tokio::sync::SemaphorePermit<'a>,
+}
+
+impl SendQueue {
+    pub(crate) fn new(max_bytes: NonZeroUsize, stall_timeout: Duration) -> Self {
+        // The semaphore hands out room in u32 chunks, and has a limit of its
+        // own on 32-bit targets.
+        let capacity = max_bytes.get().min(tokio::sync::Semaphore::MAX_PERMITS);
+        let capacity = u32::try_from(capacity).unwrap_or(u32::MAX);
+        Self {
+            room: tokio::sync::Semaphore::new(capacity as usize),
+            capacity,
+            stall_timeout,
+            queued_bytes: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits for room for a message of `len` bytes, in the order messages
+    /// arrive.
+    async fn enqueue(&self, len: usize) -> QueuedMessage<'_> {
+        let needed = u32::try_from(len).map_or(self.capacity, |len| len.min(self.capacity));
+        let room = self
+            .room
+            .acquire_many(needed)
+            .await
+            .expect("the semaphore is never closed");
+        let queued_bytes = self.queued_bytes.fetch_add(len, Ordering::SeqCst) + len;
+        self.high_water_mark
+            .fetch_max(queued_bytes, Ordering::SeqCst);
+        QueuedMessage {
+            queue: self,
+            len,
+            _room: room,
+        }
+    }
+
+    fn stats(&self) -> SendQueueStats {
+        SendQueueStats {
+            queued_bytes: self.queued_bytes.load(Ordering::SeqCst),
+            high_water_mark: self.high_water_mark.load(Ordering::SeqCst),
+        }
     }
 }
 
+impl Drop for QueuedMessage<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .queued_bytes
+            .fetch_sub(self.len, Ordering::SeqCst);
+    }
+}
+
+/// A frame as read from the incoming half of a websocket.
+type IncomingFrame = Option<Result<Message, tungstenite::Error>>;
+
 #[derive(Debug)]
 pub(crate) struct WebSocketClientReader<S> {
     ws_stream: SplitStream<WebSocketStream<S>>,
+    /// A frame read ahead of [`Self::next_incoming`], which returns it first.
+    ///
+    /// Only [`Self::has_pending_message`] reads ahead, so outside of tests this
+    /// is always empty.
+    peeked: Option<IncomingFrame>,
     ws_writer: WebSocketClientWriter<S>,
     service_status: ServiceStatus<NetError>,
     keep_alive_interval: Duration,
     max_idle_time: Duration,
+    /// See [`WebSocketClientConnector::with_reconnect_on_ping_failure`].
+    reconnect_on_ping_failure: bool,
     last_frame_received: Instant,
     last_keepalive_sent: Instant,
+    /// Whether the last keepalive ping is still waiting for its pong.
+    awaiting_pong: bool,
+}
+
+/// What [`WebSocketClientReader::next_incoming`] reads.
+enum Incoming {
+    Message(NextOrClose<TextOrBinary>),
+    Pong(Vec<u8>),
 }
 
 impl<S: AsyncDuplexStream> WebSocketClientReader<S> {
     pub async fn next(&mut self) -> Result<NextOrClose<TextOrBinary>, NetError> {
+        loop {
+            match self.next_incoming().await? {
+                Incoming::Message(message) => return Ok(message),
+                Incoming::Pong(_) => continue,
+            }
+        }
+    }
+
+    /// Whether the next frame, if it has arrived already, is a text or binary
+    /// message. Doesn't wait for one, and leaves it to be read.
+    #[cfg(any(test, feature = "test-support"))]
+    fn has_pending_message(&mut self) -> bool {
+        use futures_util::FutureExt as _;
+        if self.peeked.is_none() {
+            self.peeked = self.ws_stream.next().now_or_never();
+        }
+        matches!(
+            self.peeked,
+            Some(Some(Ok(Message::Text(_) | Message::Binary(_))))
+        )
+    }
+
+    /// The frame read ahead, if any, or else the next one from the stream.
+    ///
+    /// Cancel-safe: a frame read ahead is returned without waiting.
+    async fn next_frame(
+        peeked: &mut Option<IncomingFrame>,
+        ws_stream: &mut SplitStream<WebSocketStream<S>>,
+    ) -> IncomingFrame {
+        match peeked.take() {
+            Some(frame) => frame,
+            None => ws_stream.next().await,
+        }
+    }
+
+    /// Like [`Self::next`], but also returns pongs.
+    async fn next_incoming(&mut self) -> Result<Incoming, NetError> {
         enum Event {
-            Message(Option<Result<Message, tungstenite::Error>>),
+            Message(IncomingFrame),
             SendKeepAlive,
             IdleTimeout,
             StopService,
@@ -168,14 +397,27 @@ impl<S: AsyncDuplexStream> WebSocketClientReader<S> {
                 let next_ping_time = self.last_keepalive_sent + self.keep_alive_interval;
                 let idle_timeout_time = self.last_frame_received + self.max_idle_time;
                 let maybe_message = match tokio::select! {
-                    maybe_message = self.ws_stream.next() => Event::Message(maybe_message),
+                    maybe_message = Self::next_frame(&mut self.peeked, &mut self.ws_stream) => {
+                        Event::Message(maybe_message)
+                    }
                     _ = tokio::time::sleep_until(next_ping_time) => Event::SendKeepAlive,
                     _ = tokio::time::sleep_until(idle_timeout_time) => Event::IdleTimeout,
                     _ = self.service_status.stopped() => Event::StopService,
                 } {
                     Event::SendKeepAlive => {
+                        if self.awaiting_pong
+                            && self.reconnect_on_ping_failure
+                            && !self.ws_writer.sent_data_since(self.last_frame_received)
+                        {
+                            log::warn!("keepalive ping went unanswered");
+                            log::info!("no request outstanding, asking for a new connection");
+                            self.service_status
+                                .stop_service_and_reconnect(NetError::ChannelIdle);
+                            return Err(NetError::ChannelIdle);
+                        }
                         self.ws_writer.send(Message::Ping(vec![])).await?;
                         self.last_keepalive_sent = Instant::now();
+                        self.awaiting_pong = true;
                         continue;
                     }
                     Event::Message(maybe_message) => maybe_message,
@@ -192,7 +434,7 @@ impl<S: AsyncDuplexStream> WebSocketClientReader<S> {
                 let message = match maybe_message {
                     None | Some(Err(tungstenite::Error::ConnectionClosed)) => {
                         log::warn!("websocket connection was unexpectedly closed");
-                        return Ok(NextOrClose::Close(None));
+                        return Ok(Incoming::Message(NextOrClose::Close(None)));
                     }
                     Some(Err(e)) => {
                         log::trace!("websocket error: {e}");
@@ -203,12 +445,18 @@ impl<S: AsyncDuplexStream> WebSocketClientReader<S> {
                 // finally, looking at the type of the message
                 self.last_frame_received = Instant::now();
                 match message {
-                    Message::Text(t) => return Ok(NextOrClose::Next(t.into())),
-                    Message::Binary(b) => return Ok(NextOrClose::Next(b.into())),
-                    Message::Ping(_) | Message::Pong(_) => continue,
+                    Message::Text(t) => return Ok(Incoming::Message(NextOrClose::Next(t.into()))),
+                    Message::Binary(b) => {
+                        return Ok(Incoming::Message(NextOrClose::Next(b.into())))
+                    }
+                    Message::Ping(_) => continue,
+                    Message::Pong(payload) => {
+                        self.awaiting_pong = false;
+                        return Ok(Incoming::Pong(payload));
+                    }
                     Message::Close(close_frame) => {
                         self.service_status.stop_service();
-                        return Ok(NextOrClose::Close(close_frame));
+                        return Ok(Incoming::Message(NextOrClose::Close(close_frame)));
                     }
                     Message::Frame(_) => unreachable!("only for sending"),
                 }
@@ -236,6 +484,14 @@ where
     result
 }
 
+/// Nothing can read from the websocket anymore, so the service is over, even
+/// if it was dropped without being closed.
+impl<S> Drop for WebSocketClientReader<S> {
+    fn drop(&mut self) {
+        self.service_status.stop_service();
+    }
+}
+
 async fn connect_websocket<T: TransportConnector>(
     connection_params: &ConnectionParams,
     endpoint: PathAndQuery,
@@ -272,10 +528,15 @@ async fn connect_websocket<T: TransportConnector>(
         .http_request_decorator
         .decorate_request(request_builder);
 
-    let (ws_stream, _response) = tokio_tungstenite::client_async_with_config(
-        request_builder.body(()).expect("can get request body"),
-        ssl_stream,
-        Some(ws_config),
+    let (ws_stream, _response) = timeout(
+        connection_params.connect_timeouts.ws_upgrade,
+        NetError::ConnectTimeout(ConnectPhase::WebSocketUpgrade),
+        tokio_tungstenite::client_async_with_config(
+            request_builder.body(()).expect("can get request body"),
+            ssl_stream,
+            Some(ws_config),
+        )
+        .map_err(NetError::from),
     )
     .await?;
 
@@ -325,6 +586,41 @@ impl<S: AsyncDuplexStream> WebSocketClient<S> {
         self.ws_client_writer.send(item).await
     }
 
+    /// Sends `payload` as a single binary message split into frames of at
+    /// most `fragment_len` bytes.
+    ///
+    /// `on_fragment_sent` is called with the number of bytes sent so far after
+    /// each frame is flushed. A frame that can't be written within
+    /// `fragment_timeout` fails the send with [`NetError::Timeout`].
+    pub(crate) async fn send_fragmented(
+        &mut self,
+        payload: &[u8],
+        fragment_len: NonZeroUsize,
+        fragment_timeout: Duration,
+        mut on_fragment_sent: impl FnMut(usize),
+    ) -> Result<(), FragmentedSendError> {
+        let fragment_count = payload.len().div_ceil(fragment_len.get());
+        let mut sent = 0;
+        for (i, fragment) in payload.chunks(fragment_len.get()).enumerate() {
+            let opcode = if i == 0 { Data::Binary } else { Data::Continue };
+            let final_fragment = i + 1 == fragment_count;
+            let frame = Frame::message(fragment.to_vec(), OpCode::Data(opcode), final_fragment);
+            timeout(
+                fragment_timeout,
+                NetError::Timeout,
+                self.ws_client_writer.send(Message::Frame(frame)),
+            )
+            .await
+            .map_err(|error| FragmentedSendError {
+                error: error.into(),
+                final_fragment,
+            })?;
+            sent += fragment.len();
+            on_fragment_sent(sent);
+        }
+        Ok(())
+    }
+
     /// Receives a message on the connection.
     ///
     /// Returns the next text or binary message received on the wrapped socket.
@@ -332,14 +628,110 @@ impl<S: AsyncDuplexStream> WebSocketClient<S> {
     pub(crate) async fn receive(&mut self) -> Result<NextOrClose<TextOrBinary>, NetError> {
         self.ws_client_reader.next().await
     }
+
+    /// Sends a ping and waits for the matching pong, returning the round-trip
+    /// time.
+    ///
+    /// Meant for when no response is outstanding: a message arriving before
+    /// the pong fails the ping with [`NetError::UnexpectedFrameReceived`].
+    /// Either way, a failed ping leaves the connection [closed](Self::is_closed).
+    pub(crate) async fn ping(&mut self) -> Result<Duration, NetError> {
+        let payload = rand::random::<[u8; 8]>().to_vec();
+        let start = Instant::now();
+        self.ws_client_writer
+            .send(Message::Ping(payload.clone()))
+            .await?;
+        loop {
+            let error = match self.ws_client_reader.next_incoming().await? {
+                Incoming::Pong(received) if received == payload => return Ok(start.elapsed()),
+                // Answers to keepalive pings.
+                Incoming::Pong(_) => continue,
+                Incoming::Message(NextOrClose::Close(_)) => NetError::ChannelClosedByRemotePeer,
+                Incoming::Message(NextOrClose::Next(_)) => NetError::UnexpectedFrameReceived,
+            };
+            self.ws_client_reader.service_status.stop_service();
+            return Err(error);
+        }
+    }
+
+    /// Whether a message has been received and not yet read; see
+    /// [`WebSocketClientReader::has_pending_message`].
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn has_pending_message(&mut self) -> bool {
+        self.ws_client_reader.has_pending_message()
+    }
+
+    /// Whether the connection has failed or been stopped.
+    ///
+    /// This only reflects what has already been observed; it does not check
+    /// the socket.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.ws_client_reader.service_status.is_stopped()
+    }
+
+    /// How full the queue of messages waiting to be sent is; see
+    /// [`WebSocketConfig::max_queued_send_bytes`].
+    pub fn send_queue_stats(&self) -> SendQueueStats {
+        self.ws_client_writer.send_queue.stats()
+    }
+
+    /// Tells the server that the connection is being closed normally.
+    pub(crate) async fn close(&mut self) -> Result<(), NetError> {
+        self.ws_client_writer
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            })))
+            .await
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+#[ignore_extra_doc_attributes]
 pub enum AttestedConnectionError {
+    /// Protocol error after establishing a connection
     Protocol,
+    /// Noise channel error: {0}
     ClientConnection(attest::client_connection::Error),
+    /// Enclave attestation failed: {0}
     Sgx(attest::enclave::Error),
+    /// Network error: {0}
     Net(NetError),
+    /// Sending the request failed: {0}
+    ///
+    /// Writing a request to the websocket failed, so the server cannot have
+    /// received all of it.
+    SendFailed(NetError),
+}
+
+/// Made up of the messages of the wrapped errors, which are log-safe
+/// themselves; nothing about the connection's host or user is added.
+impl LogSafeDisplay for AttestedConnectionError {}
+
+impl AttestedConnectionError {
+    /// Whether the failed exchange can be repeated without risk of the server
+    /// handling the request twice.
+    ///
+    /// There are no request IDs for the server to deduplicate on, so this is
+    /// only the case when the failure happened before the whole request was
+    /// handed to the transport. Anything after that, including a response that
+    /// can't be read, may have been processed.
+    pub fn is_safe_to_retry(&self) -> bool {
+        match self {
+            // Attestation happens before any request is sent.
+            Self::Sgx(_) | Self::SendFailed(_) => true,
+            Self::Protocol | Self::ClientConnection(_) | Self::Net(_) => false,
+        }
+    }
+}
+
+/// Failure partway through sending a fragmented message.
+#[derive(Debug)]
+pub(crate) struct FragmentedSendError {
+    pub(crate) error: AttestedConnectionError,
+    /// Whether the failed frame was the last one, in which case the peer may
+    /// have received the whole message.
+    pub(crate) final_fragment: bool,
 }
 
 impl From<enclave::Error> for AttestedConnectionError {
@@ -367,26 +759,312 @@ pub type DefaultStream = tokio_boring::SslStream<tokio::net::TcpStream>;
 pub struct AttestedConnection<S = DefaultStream> {
     websocket: WebSocketClient<S>,
     client_connection: ClientConnection,
+    activity: Arc<Activity>,
+    traffic: Arc<std::sync::Mutex<TrafficCounters>>,
+    wire_bytes: WireBytes,
+    /// Stops the idle timer, if there is one, when the connection is dropped.
+    idle_timer: Option<DropGuard>,
+    /// The account the connection was authenticated for, if known.
+    uid: Option<[u8; 16]>,
+    /// The ID sent along with the websocket upgrade, if any.
+    correlation_id: Option<CorrelationId>,
+    /// The network the connection was made on.
+    network: NetworkWatch,
+    #[cfg(any(test, feature = "test-support"))]
+    interceptors: Interceptors,
+}
+
+/// Bytes of websocket messages sent and received over an
+/// [`AttestedConnection`], handshake included.
+///
+/// These are the encrypted messages, unlike the plaintext counted by
+/// [`TrafficCounters`].
+#[derive(Debug, Default)]
+struct WireBytes {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl WireBytes {
+    fn sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn received(&self, len: usize) {
+        self.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+/// Tracks when an [`AttestedConnection`] was last used.
+#[derive(Debug)]
+struct Activity {
+    last: std::sync::Mutex<Instant>,
+    /// When a send or receive last succeeded, or when the connection was made
+    /// if none has yet.
+    last_success: std::sync::Mutex<Instant>,
+    /// Number of sends and receives in progress.
+    in_progress: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            last: std::sync::Mutex::new(Instant::now()),
+            last_success: std::sync::Mutex::new(Instant::now()),
+            in_progress: AtomicUsize::new(0),
+        })
+    }
+
+    fn succeeded(&self) {
+        *self.last_success.lock().expect("not poisoned") = Instant::now();
+    }
+
+    /// Counts the connection as in use until the returned guard is dropped.
+    fn start(&self) -> ActivityGuard<'_> {
+        self.in_progress.fetch_add(1, Ordering::SeqCst);
+        ActivityGuard(self)
+    }
+
+    /// Time since the connection was last used, or zero if it is in use.
+    fn idle_time(&self) -> Duration {
+        if self.in_progress.load(Ordering::SeqCst) > 0 {
+            return Duration::ZERO;
+        }
+        self.last.lock().expect("not poisoned").elapsed()
+    }
+}
+
+struct ActivityGuard<'a>(&'a Activity);
+
+impl Drop for ActivityGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.last.lock().expect("not poisoned") = Instant::now();
+        self.0.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Plaintext traffic over an [`AttestedConnection`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttestedTraffic {
+    pub requests_sent: u32,
+    pub responses_received: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Time from sending each answered request to receiving its response,
+    /// assuming responses arrive in the order the requests were sent.
+    ///
+    /// Only the latest [`MAX_ROUND_TRIP_TIMES`] are kept, so a long-lived
+    /// connection doesn't keep growing.
+    pub round_trip_times: Vec<Duration>,
+}
+
+/// How many of the latest round trip times an [`AttestedConnection`] keeps.
+pub const MAX_ROUND_TRIP_TIMES: usize = 64;
+
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    totals: AttestedTraffic,
+    /// Number of round trips ever timed, including those no longer in
+    /// `totals.round_trip_times`.
+    round_trips: usize,
+    /// When each request still waiting for its response was sent.
+    unanswered: VecDeque<Instant>,
+}
+
+impl TrafficCounters {
+    fn sent(&mut self, len: usize) {
+        self.totals.requests_sent += 1;
+        self.totals.bytes_sent += len as u64;
+        self.unanswered.push_back(Instant::now());
+    }
+
+    fn received(&mut self, len: usize) {
+        self.totals.responses_received += 1;
+        self.totals.bytes_received += len as u64;
+        if let Some(sent_at) = self.unanswered.pop_front() {
+            let times = &mut self.totals.round_trip_times;
+            if times.len() == MAX_ROUND_TRIP_TIMES {
+                times.remove(0);
+            }
+            times.push(sent_at.elapsed());
+            self.round_trips += 1;
+        }
+    }
+}
+
+/// Reads the traffic of an [`AttestedConnection`] from the time the meter was
+/// taken, even after the connection has been handed on or dropped.
+#[derive(Clone, Debug)]
+pub struct TrafficMeter {
+    counters: Arc<std::sync::Mutex<TrafficCounters>>,
+    start: AttestedTraffic,
+    start_round_trips: usize,
+}
+
+impl TrafficMeter {
+    fn new(counters: Arc<std::sync::Mutex<TrafficCounters>>) -> Self {
+        let (start, start_round_trips) = {
+            let counters = counters.lock().expect("not poisoned");
+            (counters.totals.clone(), counters.round_trips)
+        };
+        Self {
+            counters,
+            start,
+            start_round_trips,
+        }
+    }
+
+    pub fn read(&self) -> AttestedTraffic {
+        let counters = self.counters.lock().expect("not poisoned");
+        let totals = &counters.totals;
+        let new_round_trips = counters.round_trips - self.start_round_trips;
+        let times = &totals.round_trip_times;
+        AttestedTraffic {
+            requests_sent: totals.requests_sent - self.start.requests_sent,
+            responses_received: totals.responses_received - self.start.responses_received,
+            bytes_sent: totals.bytes_sent - self.start.bytes_sent,
+            bytes_received: totals.bytes_received - self.start.bytes_received,
+            round_trip_times: times[times.len().saturating_sub(new_round_trips)..].to_vec(),
+        }
+    }
+}
+
+/// Transforms a plaintext message passing through an [`AttestedConnection`].
+#[cfg(any(test, feature = "test-support"))]
+type Interceptor = Box<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Interceptors of an [`AttestedConnection`], each applied in the order it
+/// was added.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Default)]
+struct Interceptors {
+    requests: Vec<Interceptor>,
+    responses: Vec<Interceptor>,
 }
 
-impl AsMut<AttestedConnection> for AttestedConnection {
-    fn as_mut(&mut self) -> &mut AttestedConnection {
+#[cfg(any(test, feature = "test-support"))]
+impl Interceptors {
+    fn apply<'a>(interceptors: &[Interceptor], bytes: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        interceptors
+            .iter()
+            .fold(bytes, |bytes, interceptor| interceptor(&*bytes).into())
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Debug for Interceptors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Interceptors")
+            .field("requests", &self.requests.len())
+            .field("responses", &self.responses.len())
+            .finish()
+    }
+}
+
+/// Stops `service_status` once the connection has been idle for
+/// `idle_timeout`.
+async fn stop_when_idle(
+    activity: Arc<Activity>,
+    idle_timeout: Duration,
+    service_status: ServiceStatus<NetError>,
+) {
+    loop {
+        let idle_time = activity.idle_time();
+        if idle_time >= idle_timeout {
+            log::info!(
+                "closing attested connection idle for {}s",
+                idle_time.as_secs()
+            );
+            service_status.stop_service_with_error(NetError::ChannelIdle);
+            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(idle_timeout - idle_time) => {}
+            _ = service_status.stopped() => return,
+        }
+    }
+}
+
+impl<S> AsMut<AttestedConnection<S>> for AttestedConnection<S> {
+    fn as_mut(&mut self) -> &mut AttestedConnection<S> {
         self
     }
 }
 
-pub(crate) async fn run_attested_interaction<C: AsMut<AttestedConnection>, B: AsRef<[u8]>>(
+/// What code on top of an [`AttestedConnection`] needs from it: exchanging
+/// plaintext messages, and what is known about the connection.
+///
+/// Being generic over this rather than the stream lets that code be tested
+/// against a scripted fake, without a server on the other end.
+#[async_trait]
+pub trait AttestedConnectionLike: Send {
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), AttestedConnectionError>;
+
+    async fn receive_bytes(&mut self) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError>;
+
+    fn is_closed(&self) -> bool;
+
+    /// Starts reading the plaintext traffic over this connection from now on.
+    fn traffic_meter(&self) -> TrafficMeter;
+
+    /// The account the connection was authenticated for, if known.
+    fn uid(&self) -> Option<[u8; 16]>;
+
+    /// The ID the connection was made with, for matching up with the
+    /// server's logs.
+    fn correlation_id(&self) -> Option<CorrelationId>;
+
+    /// Whether the network changed since the connection was made, which it
+    /// can't recover from.
+    fn network_changed(&self) -> bool;
+}
+
+#[async_trait]
+impl<S: AsyncDuplexStream> AttestedConnectionLike for AttestedConnection<S> {
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), AttestedConnectionError> {
+        AttestedConnection::send_bytes(self, bytes).await
+    }
+
+    async fn receive_bytes(&mut self) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError> {
+        AttestedConnection::receive_bytes(self).await
+    }
+
+    fn is_closed(&self) -> bool {
+        AttestedConnection::is_closed(self)
+    }
+
+    fn traffic_meter(&self) -> TrafficMeter {
+        AttestedConnection::traffic_meter(self)
+    }
+
+    fn uid(&self) -> Option<[u8; 16]> {
+        AttestedConnection::uid(self)
+    }
+
+    fn correlation_id(&self) -> Option<CorrelationId> {
+        AttestedConnection::correlation_id(self)
+    }
+
+    fn network_changed(&self) -> bool {
+        AttestedConnection::network_changed(self)
+    }
+}
+
+pub(crate) async fn run_attested_interaction<C, B>(
     connection: &mut C,
     bytes: B,
-) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError> {
-    let connection = connection.as_mut();
-    connection.send_bytes(bytes).await?;
+) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError>
+where
+    C: AttestedConnectionLike + ?Sized,
+    B: AsRef<[u8]>,
+{
+    connection.send_bytes(bytes.as_ref()).await?;
     connection.receive_bytes().await
 }
 
 #[derive(Clone, Eq, PartialEq)]
 #[cfg_attr(test, derive(Debug))]
-pub(crate) enum NextOrClose<T> {
+pub enum NextOrClose<T> {
     Next(T),
     Close(Option<CloseFrame<'static>>),
 }
@@ -398,6 +1076,53 @@ impl<T> NextOrClose<T> {
             Self::Next(t) => Ok(t),
         }
     }
+
+    /// Like [`Self::next_or`], but a close frame saying why the server hung up
+    /// is reported as that reason instead of `failure`: a backoff hint as
+    /// [`NetError::RateLimited`], and a deprecated client as
+    /// [`NetError::ClientDeprecated`].
+    pub fn next_or_close_reason(self, failure: NetError) -> Result<T, NetError> {
+        match self {
+            Self::Close(Some(frame)) => {
+                if let Some(RateLimitExceededResponse {
+                    retry_after_seconds,
+                }) = RateLimitExceededResponse::from_close_frame(&frame)
+                {
+                    return Err(NetError::RateLimited {
+                        retry_after_seconds,
+                    });
+                }
+                if u16::from(frame.code) == CLIENT_DEPRECATED_CLOSE_CODE {
+                    return Err(NetError::ClientDeprecated);
+                }
+                Err(failure)
+            }
+            Self::Close(None) => Err(failure),
+            Self::Next(t) => Ok(t),
+        }
+    }
+}
+
+/// Code of the websocket close frame the server sends to a client version it
+/// no longer accepts.
+pub(crate) const CLIENT_DEPRECATED_CLOSE_CODE: u16 = 4499;
+
+/// Backoff request sent by the server as the reason of a websocket close frame.
+#[derive(serde::Deserialize)]
+pub(crate) struct RateLimitExceededResponse {
+    pub(crate) retry_after_seconds: u32,
+}
+
+impl RateLimitExceededResponse {
+    /// Numeric code set by the server on the websocket close frame.
+    pub(crate) const CLOSE_CODE: u16 = 4008;
+
+    pub(crate) fn from_close_frame(frame: &CloseFrame<'_>) -> Option<Self> {
+        if u16::from(frame.code) != Self::CLOSE_CODE {
+            return None;
+        }
+        serde_json::from_str(&frame.reason).ok()
+    }
 }
 
 impl<S> AttestedConnection<S>
@@ -409,14 +1134,126 @@ where
         mut websocket: WebSocketClient<S>,
         new_handshake: impl FnOnce(&[u8]) -> enclave::Result<enclave::Handshake>,
     ) -> Result<Self, AttestedConnectionError> {
-        let client_connection = authenticate(&mut websocket, new_handshake).await?;
+        let wire_bytes = WireBytes::default();
+        let client_connection = authenticate(&mut websocket, &wire_bytes, new_handshake).await?;
 
         Ok(Self {
             websocket,
             client_connection,
+            activity: Activity::new(),
+            traffic: Default::default(),
+            wire_bytes,
+            idle_timer: None,
+            uid: None,
+            correlation_id: None,
+            network: NetworkWatch::default(),
+            #[cfg(any(test, feature = "test-support"))]
+            interceptors: Interceptors::default(),
         })
     }
 
+    /// Closes the connection once it has gone unused for `idle_timeout`.
+    ///
+    /// Servers close connections left idle for too long themselves, which
+    /// callers only find out about when they next try to use one. Closing it
+    /// on this side first marks it as [closed](Self::is_closed) ahead of time,
+    /// so that it can be replaced before use instead.
+    pub(crate) fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        let cancel = CancellationToken::new();
+        let timer = stop_when_idle(
+            self.activity.clone(),
+            idle_timeout,
+            self.websocket.ws_client_reader.service_status.clone(),
+        );
+        let cancelled = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                () = timer => {}
+                () = cancelled.cancelled() => {}
+            }
+        });
+        self.idle_timer = Some(cancel.drop_guard());
+        self
+    }
+
+    /// Replaces the Noise transport keys after every `interval` transport
+    /// messages in each direction.
+    ///
+    /// The enclave has to rekey on the same schedule, see
+    /// [`attest::client_connection`] for the exact rule. Messages are counted
+    /// from the end of the handshake, so this is best set right after
+    /// connecting.
+    pub(crate) fn with_rekey_interval(mut self, interval: NonZeroU64) -> Self {
+        self.client_connection = self.client_connection.with_rekey_interval(interval);
+        self
+    }
+
+    /// Passes each request through `f` before it is encrypted and sent.
+    ///
+    /// Meant for tests that tamper with the protocol, say by truncating or
+    /// replaying messages. Interceptors added earlier are applied first.
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn with_request_interceptor(
+        mut self,
+        f: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.interceptors.requests.push(Box::new(f));
+        self
+    }
+
+    /// Passes each response through `f` after it is received and decrypted.
+    ///
+    /// Interceptors added earlier are applied first.
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn with_response_interceptor(
+        mut self,
+        f: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.interceptors.responses.push(Box::new(f));
+        self
+    }
+
+    /// Whether a message from the enclave is waiting to be received.
+    #[cfg(any(test, feature = "test-support"))]
+    pub(crate) fn has_pending_messages(&mut self) -> bool {
+        self.websocket.has_pending_message()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn intercept_request<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        Interceptors::apply(&self.interceptors.requests, bytes.into())
+    }
+
+    #[cfg(not(any(test, feature = "test-support")))]
+    fn intercept_request<'a>(&self, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        bytes.into()
+    }
+
+    #[cfg(any(test, feature = "test-support"))]
+    fn intercept_response(&self, bytes: Vec<u8>) -> Vec<u8> {
+        Interceptors::apply(&self.interceptors.responses, bytes.into()).into_owned()
+    }
+
+    #[cfg(not(any(test, feature = "test-support")))]
+    fn intercept_response(&self, bytes: Vec<u8>) -> Vec<u8> {
+        bytes
+    }
+
+    /// Like [`Self::connect`], but gives up if attestation takes longer than
+    /// `attestation_timeout`.
+    pub(crate) async fn connect_with_timeout(
+        websocket: WebSocketClient<S>,
+        attestation_timeout: Duration,
+        new_handshake: impl FnOnce(&[u8]) -> enclave::Result<enclave::Handshake>,
+    ) -> Result<Self, AttestedConnectionError> {
+        timeout(
+            attestation_timeout,
+            AttestedConnectionError::Net(NetError::ConnectTimeout(ConnectPhase::Attestation)),
+            Self::connect(websocket, new_handshake),
+        )
+        .await
+    }
+
     pub(crate) async fn send(
         &mut self,
         request: impl prost::Message,
@@ -425,15 +1262,56 @@ where
         self.send_bytes(request).await
     }
 
+    /// Like [`Self::send`], but splits the encrypted request across several
+    /// websocket frames, reporting `(bytes_sent, total)` after each one.
+    pub(crate) async fn send_fragmented(
+        &mut self,
+        request: impl prost::Message,
+        fragment_len: NonZeroUsize,
+        fragment_timeout: Duration,
+        mut on_progress: impl FnMut(usize, usize),
+    ) -> Result<(), FragmentedSendError> {
+        let _active = self.activity.start();
+        let request = request.encode_to_vec();
+        let request = self.intercept_request(&request);
+        let ciphertext =
+            self.client_connection
+                .send(&request)
+                .map_err(|e| FragmentedSendError {
+                    error: e.into(),
+                    final_fragment: false,
+                })?;
+        let total = ciphertext.len();
+        self.websocket
+            .send_fragmented(&ciphertext, fragment_len, fragment_timeout, |sent| {
+                on_progress(sent, total)
+            })
+            .await?;
+        self.wire_bytes.sent(total);
+        self.traffic
+            .lock()
+            .expect("not poisoned")
+            .sent(request.len());
+        self.activity.succeeded();
+        Ok(())
+    }
+
     pub(crate) async fn send_bytes<B: AsRef<[u8]>>(
         &mut self,
         bytes: B,
     ) -> Result<(), AttestedConnectionError> {
-        let request = self.client_connection.send(bytes.as_ref())?;
+        let _active = self.activity.start();
+        let bytes = self.intercept_request(bytes.as_ref());
+        let request = self.client_connection.send(&bytes)?;
+        let request_len = request.len();
         self.websocket
             .send(request.into())
             .await
-            .map_err(Into::into)
+            .map_err(AttestedConnectionError::SendFailed)?;
+        self.wire_bytes.sent(request_len);
+        self.traffic.lock().expect("not poisoned").sent(bytes.len());
+        self.activity.succeeded();
+        Ok(())
     }
 
     pub(crate) async fn receive<T: prost::Message + Default>(
@@ -451,75 +1329,201 @@ where
     pub(crate) async fn receive_bytes(
         &mut self,
     ) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError> {
+        let _active = self.activity.start();
         let received = self.websocket.receive().await?;
         let received = match received {
             NextOrClose::Close(frame) => return Ok(NextOrClose::Close(frame)),
             NextOrClose::Next(t) => t.try_into_binary()?,
         };
-        self.client_connection
-            .recv(&received)
-            .map(NextOrClose::Next)
-            .map_err(Into::into)
+        self.wire_bytes.received(received.len());
+        let received = self.client_connection.recv(&received)?;
+        self.traffic
+            .lock()
+            .expect("not poisoned")
+            .received(received.len());
+        self.activity.succeeded();
+        Ok(NextOrClose::Next(self.intercept_response(received)))
     }
-}
 
-impl TextOrBinary {
-    fn try_into_binary(self) -> Result<Vec<u8>, AttestedConnectionError> {
-        match self {
-            TextOrBinary::Text(_) => Err(AttestedConnectionError::Protocol),
-            TextOrBinary::Binary(b) => Ok(b),
-        }
+    pub(crate) fn is_closed(&self) -> bool {
+        self.websocket.is_closed()
     }
-}
 
-async fn authenticate<S: AsyncDuplexStream>(
-    websocket: &mut WebSocketClient<S>,
-    new_handshake: impl FnOnce(&[u8]) -> enclave::Result<enclave::Handshake>,
-) -> Result<ClientConnection, AttestedConnectionError> {
-    let attestation_msg = websocket
+    /// Checks that the connection is alive with a websocket ping.
+    ///
+    /// The ping bypasses the Noise session, so it doesn't count as a
+    /// transport message or as activity on the connection.
+    pub(crate) async fn ping(&mut self) -> Result<Duration, NetError> {
+        self.websocket.ping().await
+    }
+
+    /// How long the connection has gone without sending or receiving
+    /// anything. Zero while a send or receive is in progress.
+    pub(crate) fn time_since_last_activity(&self) -> Duration {
+        self.activity.idle_time()
+    }
+
+    /// When a message was last sent or received successfully, or when the
+    /// connection was made if none has been yet.
+    ///
+    /// Unlike [`Self::time_since_last_activity`], failed and ongoing sends and
+    /// receives don't count, so this can tell which of several connections was
+    /// least recently used.
+    pub(crate) fn last_activity(&self) -> Instant {
+        *self.activity.last_success.lock().expect("not poisoned")
+    }
+
+    /// Starts reading the traffic over this connection from now on.
+    pub(crate) fn traffic_meter(&self) -> TrafficMeter {
+        TrafficMeter::new(self.traffic.clone())
+    }
+
+    /// Records which account the connection was authenticated for, for
+    /// reporting; see [`crate::svr3::RequestEvent`].
+    pub(crate) fn with_uid(mut self, uid: [u8; 16]) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub(crate) fn uid(&self) -> Option<[u8; 16]> {
+        self.uid
+    }
+
+    /// Records the ID the websocket upgrade was sent with, for reporting.
+    pub(crate) fn with_correlation_id(mut self, correlation_id: CorrelationId) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    pub(crate) fn correlation_id(&self) -> Option<CorrelationId> {
+        self.correlation_id
+    }
+
+    /// Records the network the connection was made on, taken before it
+    /// started connecting.
+    pub(crate) fn with_network(mut self, network: NetworkWatch) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub(crate) fn network_changed(&self) -> bool {
+        self.network.changed()
+    }
+
+    /// Total size of the encrypted messages sent so far, including the
+    /// handshake.
+    ///
+    /// Websocket framing and TLS add to what actually goes over the network.
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.wire_bytes.sent.load(Ordering::Relaxed)
+    }
+
+    /// Like [`Self::bytes_sent`], but for messages received, including the
+    /// attestation.
+    pub(crate) fn bytes_received(&self) -> u64 {
+        self.wire_bytes.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) async fn close(mut self) -> Result<(), NetError> {
+        self.websocket.close().await
+    }
+
+    /// Sends a Close frame, then leaves the rest of the closing handshake to a
+    /// background task.
+    ///
+    /// The task waits up to `timeout` for the server's Close frame, skipping
+    /// anything else received in the meantime, and drops the connection
+    /// either way.
+    pub(crate) async fn close_and_forget(mut self, timeout: Duration)
+    where
+        S: 'static,
+    {
+        if let Err(e) = self.websocket.close().await {
+            log::debug!("failed to send close frame: {e}");
+        }
+        let _closing = tokio::spawn(async move {
+            let answered = tokio::time::timeout(timeout, async {
+                while let Ok(NextOrClose::Next(_)) = self.websocket.receive().await {}
+            })
+            .await;
+            if answered.is_err() {
+                log::debug!("server didn't answer close frame within {timeout:?}");
+            }
+        });
+    }
+}
+
+impl TextOrBinary {
+    fn try_into_binary(self) -> Result<Vec<u8>, AttestedConnectionError> {
+        match self {
+            TextOrBinary::Text(_) => Err(AttestedConnectionError::Protocol),
+            TextOrBinary::Binary(b) => Ok(b),
+        }
+    }
+}
+
+async fn authenticate<S: AsyncDuplexStream>(
+    websocket: &mut WebSocketClient<S>,
+    wire_bytes: &WireBytes,
+    new_handshake: impl FnOnce(&[u8]) -> enclave::Result<enclave::Handshake>,
+) -> Result<ClientConnection, AttestedConnectionError> {
+    let attestation_msg = websocket
         .receive()
         .await?
-        .next_or(NetError::Failure)?
+        .next_or_close_reason(NetError::Failure)?
         .try_into_binary()?;
+    wire_bytes.received(attestation_msg.len());
     let handshake = new_handshake(attestation_msg.as_ref())?;
 
-    websocket
-        .send(Vec::from(handshake.initial_request()).into())
-        .await?;
+    let initial_request = handshake.initial_request();
+    websocket.send(Vec::from(initial_request).into()).await?;
+    wire_bytes.sent(initial_request.len());
 
     let initial_response = websocket
         .receive()
         .await?
-        .next_or(NetError::Failure)?
+        .next_or_close_reason(NetError::Failure)?
         .try_into_binary()?;
+    wire_bytes.received(initial_response.len());
 
     Ok(handshake.complete(&initial_response)?)
 }
 
 #[cfg(test)]
-mod test {
-    use crate::env::{WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME};
-    use assert_matches::assert_matches;
-    use futures_util::{pin_mut, poll};
-    use tokio::io::DuplexStream;
+pub(crate) mod testutil {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicBool;
+    use std::task::{ready, Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
 
     use super::*;
+    use crate::env::{
+        WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME, WS_MAX_QUEUED_SEND_BYTES, WS_SEND_STALL_TIMEOUT,
+    };
 
-    impl<T: Debug> NextOrClose<T> {
-        fn unwrap_next(self) -> T
-        where
-            T: Debug,
-        {
-            match self {
-                Self::Next(t) => t,
-                s @ Self::Close(_) => panic!("unwrap called on {s:?}"),
-            }
+    /// A transport connector whose connection attempts never finish.
+    #[derive(Clone)]
+    pub(crate) struct NeverConnects;
+
+    #[async_trait]
+    impl TransportConnector for NeverConnects {
+        type Stream = DuplexStream;
+
+        async fn connect(
+            &self,
+            _connection_params: &ConnectionParams,
+            _alpn: &[u8],
+        ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+            std::future::pending().await
         }
     }
 
-    const MESSAGE_TEXT: &str = "text";
+    pub(crate) const FAKE_ATTESTATION: &[u8] = attest::svr2::testutil::HANDSHAKE_START;
 
-    async fn fake_websocket() -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+    pub(crate) async fn fake_websocket(
+    ) -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
         let (client, server) = tokio::io::duplex(1024);
         let req = url::Url::parse("ws://localhost:8080/").unwrap();
         let client_future = tokio_tungstenite::client_async(req, client);
@@ -530,7 +1534,7 @@ mod test {
         (server_stream, client_stream)
     }
 
-    fn websocket_test_client<S: AsyncDuplexStream>(
+    pub(crate) fn websocket_test_client<S: AsyncDuplexStream>(
         channel: WebSocketStream<S>,
     ) -> WebSocketClient<S> {
         start_ws_service(
@@ -538,10 +1542,435 @@ mod test {
             url::Host::Domain("localhost".to_string()),
             WS_KEEP_ALIVE_INTERVAL,
             WS_MAX_IDLE_TIME,
+            SendQueue::new(WS_MAX_QUEUED_SEND_BYTES, WS_SEND_STALL_TIMEOUT),
+            false,
         )
         .0
     }
 
+    /// What a fake attested server does in response to a decrypted client message.
+    pub(crate) enum AttestedServerOutput {
+        /// Encrypt and send the payload back to the client.
+        Message(Vec<u8>),
+        /// Close the websocket with the given frame and stop serving.
+        Close(Option<CloseFrame<'static>>),
+    }
+
+    /// Performs the server side of the attested handshake with the known
+    /// private key (K of NK), returning the established Noise transport.
+    pub(crate) async fn attested_server_handshake(
+        websocket: &mut WebSocketStream<impl AsyncDuplexStream>,
+        private_key: impl AsRef<[u8]>,
+    ) -> snow::TransportState {
+        let mut server_hs =
+            snow::Builder::new(attest::client_connection::NOISE_PATTERN.parse().unwrap())
+                .local_private_key(private_key.as_ref())
+                .build_responder()
+                .unwrap();
+
+        // The server first sends over its attestation message.
+        websocket
+            .send(Message::Binary(FAKE_ATTESTATION.to_vec()))
+            .await
+            .unwrap();
+
+        // Wait for the handshake from the client.
+        let Some(Ok(Message::Binary(incoming))) = websocket.next().await else {
+            panic!("expected a binary handshake message");
+        };
+        assert_eq!(server_hs.read_message(&incoming, &mut []).unwrap(), 0);
+
+        let mut message = vec![0u8; 48];
+        let write_size = server_hs.write_message(&[], &mut message).unwrap();
+
+        assert_eq!(write_size, 48);
+        assert!(server_hs.is_handshake_finished());
+
+        websocket.send(Message::Binary(message)).await.unwrap();
+
+        server_hs.into_transport_mode().unwrap()
+    }
+
+    /// Runs a fake SGX server that sets up a session and then replies to
+    /// incoming messages as directed by `on_message`.
+    pub(crate) async fn run_attested_server(
+        websocket: WebSocketStream<impl AsyncDuplexStream>,
+        private_key: impl AsRef<[u8]>,
+        on_message: impl FnMut(Vec<u8>) -> Vec<AttestedServerOutput>,
+    ) {
+        run_attested_server_with_rekey_interval(websocket, private_key, None, on_message).await
+    }
+
+    /// Like [`run_attested_server`], but rekeying the session after every
+    /// `rekey_interval` transport messages, the same way a client configured
+    /// with [`AttestedConnection::with_rekey_interval`] does.
+    pub(crate) async fn run_attested_server_with_rekey_interval(
+        mut websocket: WebSocketStream<impl AsyncDuplexStream>,
+        private_key: impl AsRef<[u8]>,
+        rekey_interval: Option<NonZeroU64>,
+        mut on_message: impl FnMut(Vec<u8>) -> Vec<AttestedServerOutput>,
+    ) {
+        let server_transport = attested_server_handshake(&mut websocket, private_key).await;
+        // The session logic is the same on both ends, so reuse the client's.
+        let mut server_connection = ClientConnection::new(server_transport);
+        if let Some(interval) = rekey_interval {
+            server_connection = server_connection.with_rekey_interval(interval);
+        }
+
+        while let Some(Ok(message)) = websocket.next().await {
+            let incoming = match message {
+                Message::Binary(incoming) => incoming,
+                // Pongs are sent automatically on the next read.
+                Message::Ping(_) | Message::Pong(_) => continue,
+                _ => break,
+            };
+            let payload = server_connection.recv(&incoming).unwrap();
+
+            for output in on_message(payload) {
+                match output {
+                    AttestedServerOutput::Message(payload) => {
+                        let outgoing = server_connection.send(&payload).unwrap();
+                        websocket.send(Message::Binary(outgoing)).await.unwrap();
+                    }
+                    AttestedServerOutput::Close(frame) => {
+                        websocket.send(Message::Close(frame)).await.unwrap();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs a fake SGX server that sets up a session and then echos back
+    /// incoming messages.
+    pub(crate) async fn run_attested_echo_server(
+        websocket: WebSocketStream<impl AsyncDuplexStream>,
+        private_key: impl AsRef<[u8]>,
+    ) {
+        run_attested_server(websocket, private_key, |payload| {
+            vec![AttestedServerOutput::Message(payload)]
+        })
+        .await
+    }
+
+    /// How many more bytes a [`FaultyStream`] lets through in each direction
+    /// before failing.
+    pub(crate) struct Faults {
+        write_budget: AtomicUsize,
+        read_budget: AtomicUsize,
+        reads_closed: AtomicBool,
+    }
+
+    impl Faults {
+        pub(crate) fn none() -> Arc<Self> {
+            Arc::new(Self {
+                write_budget: AtomicUsize::new(usize::MAX),
+                read_budget: AtomicUsize::new(usize::MAX),
+                reads_closed: AtomicBool::new(false),
+            })
+        }
+
+        /// Makes reads report the end of the stream, as if the server had
+        /// half-closed the connection, while writes keep working.
+        pub(crate) fn close_reads(&self) {
+            self.reads_closed.store(true, Ordering::SeqCst);
+        }
+
+        pub(crate) fn fail_writes_after(&self, bytes: usize) {
+            self.write_budget.store(bytes, Ordering::SeqCst);
+        }
+
+        pub(crate) fn fail_reads_after(&self, bytes: usize) {
+            self.read_budget.store(bytes, Ordering::SeqCst);
+        }
+    }
+
+    /// A stream that starts failing once it has used up its [`Faults`] budget.
+    pub(crate) struct FaultyStream {
+        inner: DuplexStream,
+        faults: Arc<Faults>,
+    }
+
+    impl AsyncWrite for FaultyStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let allowed = match self.faults.write_budget.load(Ordering::SeqCst) {
+                0 => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                budget => budget.min(buf.len()),
+            };
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..allowed]))?;
+            self.faults
+                .write_budget
+                .fetch_sub(written, Ordering::SeqCst);
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    impl AsyncRead for FaultyStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.faults.reads_closed.load(Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+            let allowed = match self.faults.read_budget.load(Ordering::SeqCst) {
+                0 => return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+                budget => budget.min(buf.remaining()),
+            };
+            let mut limited = vec![0; allowed];
+            let mut limited = ReadBuf::new(&mut limited);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+            self.faults
+                .read_budget
+                .fetch_sub(limited.filled().len(), Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Connects to an attested echo server over a [`FaultyStream`].
+    ///
+    /// Requests that make it to the server are passed on through the returned
+    /// receiver.
+    pub(crate) async fn faulty_attested_connection() -> (
+        AttestedConnection<FaultyStream>,
+        Arc<Faults>,
+        tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (client, server) = tokio::io::duplex(1024);
+        let faults = Faults::none();
+        let client = FaultyStream {
+            inner: client,
+            faults: faults.clone(),
+        };
+        let req = url::Url::parse("ws://localhost:8080/").unwrap();
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::client_async(req, client),
+            tokio_tungstenite::accept_async(server)
+        );
+        let (client, _) = client.unwrap();
+
+        let (requests_tx, requests_rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = tokio::spawn(run_attested_server(
+            server.unwrap(),
+            attest::sgx_session::testutil::private_key(),
+            move |payload| {
+                requests_tx.send(payload.clone()).unwrap();
+                vec![AttestedServerOutput::Message(payload)]
+            },
+        ));
+
+        let connection = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        (connection, faults, requests_rx, server)
+    }
+
+    /// What a [`FakeAttestedConnection`] answers a request with.
+    pub(crate) type FakeReply = Result<NextOrClose<Vec<u8>>, AttestedConnectionError>;
+
+    type RequestMatcher = Box<dyn Fn(&[u8]) -> bool + Send>;
+    type Responder = Box<dyn FnOnce(&[u8]) -> FakeReply + Send>;
+
+    /// An [`AttestedConnectionLike`] that follows a script instead of talking
+    /// to a server.
+    ///
+    /// Each request is checked against the next step of the script, and the
+    /// test panics if it doesn't match or there are no steps left. The step's
+    /// reply is then returned by the next receive, except for
+    /// [`SendFailed`](AttestedConnectionError::SendFailed), which fails the
+    /// send itself. Once a close has been received, later sends fail, as they
+    /// would on a real connection.
+    #[derive(Default)]
+    pub(crate) struct FakeAttestedConnection {
+        script: VecDeque<(RequestMatcher, Responder)>,
+        replies: VecDeque<FakeReply>,
+        closed: bool,
+        traffic: Arc<std::sync::Mutex<TrafficCounters>>,
+        uid: Option<[u8; 16]>,
+        correlation_id: Option<CorrelationId>,
+        network: NetworkWatch,
+    }
+
+    impl FakeAttestedConnection {
+        pub(crate) fn new() -> Self {
+            Self::default()
+        }
+
+        /// Answers the next request, which has to satisfy `matches`, with what
+        /// `respond` makes of it.
+        pub(crate) fn on_request(
+            mut self,
+            matches: impl Fn(&[u8]) -> bool + Send + 'static,
+            respond: impl FnOnce(&[u8]) -> FakeReply + Send + 'static,
+        ) -> Self {
+            self.script
+                .push_back((Box::new(matches), Box::new(respond)));
+            self
+        }
+
+        /// Like [`Self::on_request`], but for any request.
+        pub(crate) fn then(
+            self,
+            respond: impl FnOnce(&[u8]) -> FakeReply + Send + 'static,
+        ) -> Self {
+            self.on_request(|_| true, respond)
+        }
+
+        /// Closes the connection instead of answering the next request.
+        pub(crate) fn then_hang_up(self) -> Self {
+            self.then(|_| Ok(NextOrClose::Close(None)))
+        }
+
+        /// Fails the exchange of the next request with `error`.
+        pub(crate) fn then_fail(self, error: AttestedConnectionError) -> Self {
+            self.then(|_| Err(error))
+        }
+
+        pub(crate) fn with_uid(mut self, uid: [u8; 16]) -> Self {
+            self.uid = Some(uid);
+            self
+        }
+
+        pub(crate) fn with_correlation_id(mut self, correlation_id: CorrelationId) -> Self {
+            self.correlation_id = Some(correlation_id);
+            self
+        }
+
+        pub(crate) fn with_network(mut self, network: NetworkWatch) -> Self {
+            self.network = network;
+            self
+        }
+    }
+
+    #[async_trait]
+    impl AttestedConnectionLike for FakeAttestedConnection {
+        async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), AttestedConnectionError> {
+            if self.closed {
+                return Err(AttestedConnectionError::SendFailed(NetError::ChannelClosed));
+            }
+            let (matches, respond) = self
+                .script
+                .pop_front()
+                .unwrap_or_else(|| panic!("unscripted request {}", hex::encode(bytes)));
+            assert!(
+                matches(bytes),
+                "request doesn't match the script: {}",
+                hex::encode(bytes)
+            );
+            match respond(bytes) {
+                Err(AttestedConnectionError::SendFailed(e)) => {
+                    return Err(AttestedConnectionError::SendFailed(e))
+                }
+                reply => self.replies.push_back(reply),
+            }
+            self.traffic.lock().expect("not poisoned").sent(bytes.len());
+            Ok(())
+        }
+
+        async fn receive_bytes(&mut self) -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError> {
+            let reply = self
+                .replies
+                .pop_front()
+                .expect("only receive after sending a request");
+            match &reply {
+                Ok(NextOrClose::Next(response)) => self
+                    .traffic
+                    .lock()
+                    .expect("not poisoned")
+                    .received(response.len()),
+                Ok(NextOrClose::Close(_)) => self.closed = true,
+                Err(_) => {}
+            }
+            reply
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed
+        }
+
+        fn traffic_meter(&self) -> TrafficMeter {
+            TrafficMeter::new(self.traffic.clone())
+        }
+
+        fn uid(&self) -> Option<[u8; 16]> {
+            self.uid
+        }
+
+        fn correlation_id(&self) -> Option<CorrelationId> {
+            self.correlation_id
+        }
+
+        fn network_changed(&self) -> bool {
+            self.network.changed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use assert_matches::assert_matches;
+    use futures_util::{pin_mut, poll};
+    use nonzero_ext::nonzero;
+
+    use super::testutil::*;
+    use super::*;
+
+    impl<T: Debug> NextOrClose<T> {
+        fn unwrap_next(self) -> T
+        where
+            T: Debug,
+        {
+            match self {
+                Self::Next(t) => t,
+                s @ Self::Close(_) => panic!("unwrap called on {s:?}"),
+            }
+        }
+    }
+
+    const MESSAGE_TEXT: &str = "text";
+
+    #[test]
+    fn close_frame_with_retry_after_is_rate_limited() {
+        let close = NextOrClose::<()>::Close(Some(CloseFrame {
+            code: RateLimitExceededResponse::CLOSE_CODE.into(),
+            reason: r#"{"retry_after_seconds":42}"#.into(),
+        }));
+        assert_matches!(
+            close.next_or_close_reason(NetError::Failure),
+            Err(NetError::RateLimited {
+                retry_after_seconds: 42
+            })
+        );
+
+        let close = NextOrClose::<()>::Close(Some(CloseFrame {
+            code: tungstenite::protocol::frame::coding::CloseCode::Normal,
+            reason: r#"{"retry_after_seconds":42}"#.into(),
+        }));
+        assert_matches!(
+            close.next_or_close_reason(NetError::Failure),
+            Err(NetError::Failure)
+        );
+    }
+
     #[tokio::test]
     async fn websocket_client_sends_pong_on_server_ping() {
         let (mut server, mut client) = fake_websocket().await;
@@ -591,6 +2020,53 @@ mod test {
         assert_eq!(receive_unsolicited.await, Ok(NextOrClose::Next(item)));
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_pings_ask_for_reconnect_only_when_idle() {
+        use crate::env::{
+            WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME, WS_MAX_QUEUED_SEND_BYTES,
+            WS_SEND_STALL_TIMEOUT,
+        };
+
+        for (reconnect_on_ping_failure, request_sent, expect_reconnect) in [
+            (true, false, true),
+            (true, true, false),
+            (false, false, false),
+        ] {
+            // The server never reads, so it never answers the pings.
+            let (_server, client) = fake_websocket().await;
+            let (mut ws, service_status) = start_ws_service(
+                client,
+                url::Host::Domain("localhost".to_string()),
+                WS_KEEP_ALIVE_INTERVAL,
+                WS_MAX_IDLE_TIME,
+                SendQueue::new(WS_MAX_QUEUED_SEND_BYTES, WS_SEND_STALL_TIMEOUT),
+                reconnect_on_ping_failure,
+            );
+            if request_sent {
+                ws.send(TextOrBinary::Binary(vec![1]))
+                    .await
+                    .expect("can send");
+            }
+            let start = Instant::now();
+            assert_eq!(ws.receive().await, Err(NetError::ChannelIdle));
+            assert_eq!(
+                service_status.is_reconnect_requested(),
+                expect_reconnect,
+                "policy on: {reconnect_on_ping_failure}, request sent: {request_sent}"
+            );
+            // A reconnect is asked for as soon as a ping goes unanswered, not
+            // once the connection times out for being idle.
+            assert_eq!(
+                start.elapsed(),
+                if expect_reconnect {
+                    2 * WS_KEEP_ALIVE_INTERVAL
+                } else {
+                    WS_MAX_IDLE_TIME
+                }
+            );
+        }
+    }
+
     #[tokio::test]
     async fn websocket_remote_hangs_up() {
         let (mut server, client) = fake_websocket().await;
@@ -612,64 +2088,55 @@ mod test {
         assert_eq!(handle.await.expect("joined"), Ok(()));
     }
 
-    const FAKE_ATTESTATION: &[u8] =
-        include_bytes!("../../../attest/tests/data/svr2handshakestart.data");
-
-    /// Runs a fake SGX server that sets up a session and then echos back
-    /// incoming messages.
-    async fn run_attested_echo_server(
-        websocket: WebSocketStream<impl AsyncDuplexStream>,
-        private_key: impl AsRef<[u8]>,
-    ) {
-        let mut websocket = websocket_test_client(websocket);
-        // Start the server with a known private key (K of NK).
-        let mut server_hs =
-            snow::Builder::new(attest::client_connection::NOISE_PATTERN.parse().unwrap())
-                .local_private_key(private_key.as_ref())
-                .build_responder()
-                .unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn stalled_sends_queue_up_to_the_limit_then_time_out() {
+        use crate::env::{WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME};
 
-        // The server first sends over its attestation message.
-        websocket
-            .send(Vec::from(FAKE_ATTESTATION).into())
-            .await
-            .unwrap();
+        const MAX_QUEUED_BYTES: usize = 4096;
+        const STALL_TIMEOUT: Duration = Duration::from_secs(10);
 
-        // Wait for the handshake from the client.
-        let incoming = websocket
-            .receive()
-            .await
-            .unwrap()
-            .unwrap_next()
-            .try_into_binary()
-            .unwrap();
-        assert_eq!(server_hs.read_message(&incoming, &mut []).unwrap(), 0);
-
-        let mut message = vec![0u8; 48];
-        let write_size = server_hs.write_message(&[], &mut message).unwrap();
-
-        assert_eq!(write_size, 48);
-        assert!(server_hs.is_handshake_finished());
-
-        websocket.send(message.into()).await.unwrap();
-
-        let mut server_transport = server_hs.into_transport_mode().unwrap();
-
-        while let NextOrClose::Next(incoming) = websocket.receive().await.unwrap() {
-            let incoming = incoming.try_into_binary().unwrap();
-            let mut payload = vec![0; incoming.len()];
-            let read = server_transport
-                .read_message(&incoming, &mut payload)
-                .unwrap();
-            payload.truncate(read);
+        // The server never reads, so writes stop once the stream's buffer is
+        // full.
+        let (_server, client) = fake_websocket().await;
+        let (ws, _service_status) = start_ws_service(
+            client,
+            url::Host::Domain("localhost".to_string()),
+            WS_KEEP_ALIVE_INTERVAL,
+            WS_MAX_IDLE_TIME,
+            SendQueue::new(nonzero!(MAX_QUEUED_BYTES), STALL_TIMEOUT),
+            false,
+        );
+        let sends: Vec<_> = (0..6)
+            .map(|_| {
+                let writer = ws.ws_client_writer.clone();
+                tokio::spawn(async move { writer.send(Message::Binary(vec![0; 1000])).await })
+            })
+            .collect();
+
+        // Before the timeout, the sends that don't fit wait instead of
+        // queueing more.
+        tokio::time::sleep(STALL_TIMEOUT / 2).await;
+        let stats = ws.send_queue_stats();
+        assert!(
+            stats.queued_bytes > 0 && stats.queued_bytes <= MAX_QUEUED_BYTES,
+            "{stats:?}"
+        );
+        assert!(stats.high_water_mark <= MAX_QUEUED_BYTES, "{stats:?}");
+        assert!(sends.iter().any(|send| !send.is_finished()));
+        assert!(!ws.is_closed());
 
-            let mut outgoing = vec![0; incoming.len() * 2];
-            let written = server_transport
-                .write_message(&payload, &mut outgoing)
-                .unwrap();
-            outgoing.truncate(written);
-            websocket.send(outgoing.into()).await.unwrap();
+        let results = futures_util::future::join_all(sends).await;
+        for result in &results {
+            assert_matches!(result, Ok(Ok(()) | Err(NetError::SendTimeout)));
         }
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Ok(Err(NetError::SendTimeout)))));
+        assert!(ws.is_closed());
+        assert_eq!(
+            ws.ws_client_writer.send(Message::Binary(vec![1])).await,
+            Err(NetError::ChannelClosed)
+        );
     }
 
     const ECHO_BYTES: &[u8] = b"two nibbles to a byte";
@@ -696,6 +2163,183 @@ mod test {
         assert_eq!(&response, ECHO_BYTES);
     }
 
+    #[tokio::test]
+    async fn attested_connection_rekeys_in_step_with_server() {
+        let rekey_interval = nonzero!(3u64);
+        let (server, client) = fake_websocket().await;
+        tokio::task::spawn(run_attested_server_with_rekey_interval(
+            server,
+            attest::sgx_session::testutil::private_key(),
+            Some(rekey_interval),
+            |payload| vec![AttestedServerOutput::Message(payload)],
+        ));
+        let mut connection = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .unwrap()
+        .with_rekey_interval(rekey_interval);
+
+        // Goes through several rekeys in each direction.
+        for i in 0..10u8 {
+            let request = [ECHO_BYTES, &[i]].concat();
+            connection.send(request.clone()).await.unwrap();
+            let response: Vec<u8> = connection.receive().await.unwrap().unwrap_next();
+            assert_eq!(response, request);
+        }
+    }
+
+    #[tokio::test]
+    async fn traffic_meter_counts_from_when_it_was_taken() {
+        let (server, client) = fake_websocket().await;
+        tokio::task::spawn(run_attested_echo_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+        ));
+        let mut connection = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .unwrap();
+
+        let whole_connection = connection.traffic_meter();
+        connection.send_bytes(ECHO_BYTES).await.unwrap();
+        connection.receive_bytes().await.unwrap().unwrap_next();
+        let from_second_request = connection.traffic_meter();
+        connection
+            .send_bytes([ECHO_BYTES, ECHO_BYTES].concat())
+            .await
+            .unwrap();
+        // Sent but not answered yet.
+        let unanswered = from_second_request.read();
+        assert_eq!(unanswered.requests_sent, 1);
+        assert_eq!(unanswered.responses_received, 0);
+        assert!(unanswered.round_trip_times.is_empty());
+        connection.receive_bytes().await.unwrap().unwrap_next();
+
+        let traffic = whole_connection.read();
+        assert_eq!(traffic.requests_sent, 2);
+        assert_eq!(traffic.responses_received, 2);
+        assert_eq!(traffic.bytes_sent, 3 * ECHO_BYTES.len() as u64);
+        assert_eq!(traffic.bytes_received, 3 * ECHO_BYTES.len() as u64);
+        assert_eq!(traffic.round_trip_times.len(), 2);
+
+        let traffic = from_second_request.read();
+        assert_eq!(traffic.requests_sent, 1);
+        assert_eq!(traffic.responses_received, 1);
+        assert_eq!(traffic.bytes_sent, 2 * ECHO_BYTES.len() as u64);
+        assert_eq!(traffic.bytes_received, 2 * ECHO_BYTES.len() as u64);
+        assert_eq!(traffic.round_trip_times.len(), 1);
+
+        // The meters outlive the connection.
+        drop(connection);
+        assert_eq!(whole_connection.read().requests_sent, 2);
+    }
+
+    #[test]
+    fn traffic_meter_keeps_only_the_latest_round_trip_times() {
+        let counters = Arc::new(std::sync::Mutex::new(TrafficCounters::default()));
+        let whole_connection = TrafficMeter::new(counters.clone());
+        let exchange = |count| {
+            let mut counters = counters.lock().expect("not poisoned");
+            for _ in 0..count {
+                counters.sent(1);
+                counters.received(1);
+            }
+        };
+
+        exchange(MAX_ROUND_TRIP_TIMES);
+        let recent = TrafficMeter::new(counters.clone());
+        exchange(10);
+
+        let traffic = whole_connection.read();
+        assert_eq!(
+            traffic.responses_received as usize,
+            MAX_ROUND_TRIP_TIMES + 10
+        );
+        assert_eq!(traffic.round_trip_times.len(), MAX_ROUND_TRIP_TIMES);
+        assert_eq!(recent.read().round_trip_times.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn attested_connection_closes_itself_when_idle() {
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+        let (server, client) = fake_websocket().await;
+        tokio::task::spawn(run_attested_echo_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+        ));
+        let mut connection = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .unwrap()
+        .with_idle_timeout(IDLE_TIMEOUT);
+        tokio::time::pause();
+
+        // Using the connection restarts the countdown.
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert!(connection.time_since_last_activity() >= Duration::from_secs(4));
+        connection.send_bytes(ECHO_BYTES).await.unwrap();
+        assert_eq!(connection.time_since_last_activity(), Duration::ZERO);
+
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert!(!connection.is_closed());
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(connection.is_closed());
+        assert_matches!(
+            connection.send_bytes(ECHO_BYTES).await,
+            Err(AttestedConnectionError::SendFailed(NetError::ChannelClosed))
+        );
+    }
+
+    #[tokio::test]
+    async fn wire_bytes_are_counted_in_both_directions() {
+        // Each Noise transport message carries a 16-byte authentication tag.
+        const TAG_LEN: u64 = 16;
+        let (mut connection, _faults, _requests, _server) = faulty_attested_connection().await;
+        let handshake_sent = connection.bytes_sent();
+        assert!(handshake_sent > 0);
+        // The attestation, then the 48-byte Noise handshake response.
+        let handshake_received = FAKE_ATTESTATION.len() as u64 + 48;
+        assert_eq!(connection.bytes_received(), handshake_received);
+
+        connection.send_bytes(ECHO_BYTES).await.unwrap();
+        let message_len = ECHO_BYTES.len() as u64 + TAG_LEN;
+        assert_eq!(connection.bytes_sent(), handshake_sent + message_len);
+        assert_eq!(connection.bytes_received(), handshake_received);
+
+        assert_matches!(connection.receive_bytes().await, Ok(NextOrClose::Next(_)));
+        assert_eq!(connection.bytes_sent(), handshake_sent + message_len);
+        assert_eq!(
+            connection.bytes_received(),
+            handshake_received + message_len
+        );
+    }
+
+    #[tokio::test]
+    async fn last_activity_only_moves_on_success() {
+        let (mut connection, faults, _requests, _server) = faulty_attested_connection().await;
+        tokio::time::pause();
+        let connected = connection.last_activity();
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        connection.send_bytes(ECHO_BYTES).await.unwrap();
+        let sent = connection.last_activity();
+        assert_eq!(sent, connected + Duration::from_secs(1));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_matches!(connection.receive_bytes().await, Ok(NextOrClose::Next(_)));
+        let received = connection.last_activity();
+        assert_eq!(received, sent + Duration::from_secs(1));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        faults.fail_writes_after(0);
+        connection.send_bytes(ECHO_BYTES).await.unwrap_err();
+        assert_eq!(connection.last_activity(), received);
+    }
+
     #[tokio::test]
     async fn attested_connection_invalid_handshake() {
         // Start the server with a known private key (K of NK).
@@ -717,6 +2361,24 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn attested_connection_attestation_times_out() {
+        // The server accepts the websocket but never sends its attestation.
+        let (_server, client) = fake_websocket().await;
+
+        assert_matches!(
+            AttestedConnection::connect_with_timeout(
+                websocket_test_client(client),
+                Duration::from_millis(100),
+                |_| attest::sgx_session::testutil::handshake_from_tests_data(),
+            )
+            .await,
+            Err(AttestedConnectionError::Net(NetError::ConnectTimeout(
+                ConnectPhase::Attestation
+            )))
+        );
+    }
+
     #[tokio::test]
     async fn attested_connection_invalid_decode() {
         // Start the server with a known private key (K of NK).
@@ -741,4 +2403,103 @@ mod test {
             AttestedConnectionError::Protocol
         );
     }
+
+    #[tokio::test]
+    async fn attested_interaction_failing_before_write_is_safe_to_retry() {
+        let (mut connection, faults, mut requests, server) = faulty_attested_connection().await;
+        faults.fail_writes_after(0);
+
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't write");
+        assert_matches!(error, AttestedConnectionError::SendFailed(_));
+        assert!(error.is_safe_to_retry());
+
+        // Once the connection has failed, later requests aren't written at all.
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("connection is closed");
+        assert_matches!(
+            error,
+            AttestedConnectionError::SendFailed(NetError::ChannelClosed)
+        );
+        assert!(error.is_safe_to_retry());
+
+        drop(connection);
+        server.await.expect("server did not panic");
+        assert_matches!(requests.try_recv(), Err(_));
+    }
+
+    #[tokio::test]
+    async fn attested_interaction_failing_mid_write_is_safe_to_retry() {
+        let (mut connection, faults, mut requests, server) = faulty_attested_connection().await;
+        // Enough for the frame header, but not the whole request.
+        faults.fail_writes_after(4);
+
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't finish writing");
+        assert_matches!(error, AttestedConnectionError::SendFailed(_));
+        assert!(error.is_safe_to_retry());
+
+        drop(connection);
+        server.await.expect("server did not panic");
+        assert_matches!(requests.try_recv(), Err(_));
+    }
+
+    #[tokio::test]
+    async fn attested_interaction_failing_after_write_is_not_safe_to_retry() {
+        let (mut connection, faults, mut requests, _server) = faulty_attested_connection().await;
+        faults.fail_reads_after(0);
+
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't read");
+        assert_matches!(error, AttestedConnectionError::Net(_));
+        assert!(!error.is_safe_to_retry());
+        assert_eq!(requests.recv().await.as_deref(), Some(ECHO_BYTES));
+    }
+
+    #[tokio::test]
+    async fn broken_connection_keeps_io_error_kind() {
+        use crate::infra::errors::ErrorCategory;
+
+        let (mut connection, faults, _requests, _server) = faulty_attested_connection().await;
+        faults.fail_reads_after(0);
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't read");
+        let net = assert_matches!(&error, AttestedConnectionError::Net(net) => net);
+        assert_eq!(net.io_error_kind(), Some(io::ErrorKind::ConnectionReset));
+        assert_eq!(
+            crate::svr::Error::from(error).category(),
+            ErrorCategory::ConnectionLost
+        );
+
+        let (mut connection, faults, _requests, _server) = faulty_attested_connection().await;
+        faults.fail_writes_after(0);
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't write");
+        let net = assert_matches!(&error, AttestedConnectionError::SendFailed(net) => net);
+        assert_eq!(net.io_error_kind(), Some(io::ErrorKind::BrokenPipe));
+        assert_eq!(
+            crate::svr::Error::from(error).category(),
+            ErrorCategory::ConnectionLost
+        );
+    }
+
+    #[tokio::test]
+    async fn attested_interaction_failing_mid_read_is_not_safe_to_retry() {
+        let (mut connection, faults, mut requests, _server) = faulty_attested_connection().await;
+        // Part of the response arrives before the connection fails.
+        faults.fail_reads_after(4);
+
+        let error = run_attested_interaction(&mut connection, ECHO_BYTES)
+            .await
+            .expect_err("can't finish reading");
+        assert_matches!(error, AttestedConnectionError::Net(_));
+        assert!(!error.is_safe_to_retry());
+        assert_eq!(requests.recv().await.as_deref(), Some(ECHO_BYTES));
+    }
 }
@@ -17,8 +17,8 @@ pub enum Error {
     /// The connection is closed
     Closed,
 
-    /// Reading or writing failed
-    Io,
+    /// Reading or writing failed: {0}
+    Io(std::io::ErrorKind),
 
     /// Space: {0}
     Space(SpaceError),
@@ -45,6 +45,20 @@ pub enum Error {
 
 impl LogSafeDisplay for Error {}
 
+impl Error {
+    /// Whether the other end went away, with or without closing the
+    /// connection properly.
+    pub(crate) fn is_connection_closed(&self) -> bool {
+        matches!(
+            self,
+            Self::Closed
+                | Self::Protocol(ProtocolError(
+                    tungstenite::error::ProtocolError::ResetWithoutClosingHandshake
+                ))
+        )
+    }
+}
+
 /// Mirror of [`tungstenite::error::CapacityError`] and [`tungstenite::Error::SendQueueFull`].
 ///
 /// Provides a user-data-free [`std::fmt::Display`] implementation.
@@ -56,6 +70,9 @@ pub enum SpaceError {
     SendQueueFull,
 }
 
+/// Capacity errors only give sizes and limits.
+impl LogSafeDisplay for SpaceError {}
+
 /// Mirror of [`tungstenite::error::ProtocolError`].
 ///
 /// Provides a user-data-free [`std::fmt::Display`] implementation.
@@ -100,6 +117,10 @@ impl std::fmt::Display for ProtocolError {
     }
 }
 
+/// Only the variant name, plus the name of an invalid header; never a header
+/// value or any frame contents.
+impl LogSafeDisplay for ProtocolError {}
+
 /// Mirror of [`http::Error`].
 ///
 /// Provides a user-data-free [`std::fmt::Display`] implementation.
@@ -120,6 +141,9 @@ impl std::fmt::Display for HttpFormatError {
     }
 }
 
+/// Only names the part of the request that was invalid, not its value.
+impl LogSafeDisplay for HttpFormatError {}
+
 impl From<http::Error> for HttpFormatError {
     fn from(value: http::Error) -> Self {
         // Try to figure out the actual error type since there's no enum to
@@ -148,7 +172,10 @@ impl From<tungstenite::Error> for Error {
             tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
                 Self::Closed
             }
-            tungstenite::Error::Io(_) | tungstenite::Error::AttackAttempt => Self::Io,
+            tungstenite::Error::Io(e) => Self::Io(e.kind()),
+            // Reported when a response looks malicious, which is as good as
+            // unreadable.
+            tungstenite::Error::AttackAttempt => Self::Io(std::io::ErrorKind::InvalidData),
             tungstenite::Error::Tls(_) => Self::UnexpectedTlsError,
             tungstenite::Error::Capacity(e) => Self::Space(SpaceError::from(e)),
             tungstenite::Error::Protocol(e) => Self::Protocol(ProtocolError::from(e)),
@@ -3,13 +3,24 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+//! Connections to the Signal services: chat, CDSI, and the SVR enclaves.
+//!
+//! Connections are made over native TCP sockets with BoringSSL (see
+//! [`infra::TcpSslTransportConnector`]), so this crate only supports native
+//! targets, not WebAssembly.
+
 pub mod auth;
 pub mod cdsi;
 pub mod chat;
 pub mod enclave;
 pub mod env;
+pub mod error_codes;
 pub mod infra;
+#[cfg(any(test, feature = "proptest-support"))]
+pub mod proptest_support;
 pub mod proto;
 pub mod svr;
 pub mod svr3;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 pub mod utils;
@@ -0,0 +1,334 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Building blocks for property-based tests of code built on SVR3.
+//!
+//! These are the strategies and the transition model used by the
+//! `svr3_prop_test` example, exported so that other crates can build their
+//! own state machine tests on top of them.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+use lazy_static::lazy_static;
+use libsignal_svr3::test_support::InMemorySvr3Server;
+use libsignal_svr3::{Backup, ErrorStatus, MaskedShareSet, Restore};
+use proptest::prelude::*;
+use rand_core::OsRng;
+
+pub use crate::svr3::Secret;
+
+/// Upper bound (exclusive) on the number of tries generated by [`max_tries`],
+/// unless `SVR3_MAX_TRIES_LIMIT` is set.
+pub const MAX_TRIES_LIMIT: u32 = 10;
+
+lazy_static! {
+    static ref ENV_MAX_TRIES_LIMIT: Result<u32, InvalidMaxTriesLimit> =
+        parse_max_tries_limit(std::env::var("SVR3_MAX_TRIES_LIMIT").ok());
+}
+
+#[derive(Clone, Debug, displaydoc::Display, thiserror::Error)]
+/// SVR3_MAX_TRIES_LIMIT must be an integer of at least 2, got {0:?}
+pub struct InvalidMaxTriesLimit(String);
+
+/// Upper bound (exclusive) on the number of tries generated by [`max_tries`]:
+/// `SVR3_MAX_TRIES_LIMIT` if set, [`MAX_TRIES_LIMIT`] otherwise.
+///
+/// The environment is only read on the first call. Tries start at 1, so
+/// limits below 2 are rejected.
+pub fn max_tries_limit() -> Result<u32, InvalidMaxTriesLimit> {
+    ENV_MAX_TRIES_LIMIT.clone()
+}
+
+fn parse_max_tries_limit(value: Option<String>) -> Result<u32, InvalidMaxTriesLimit> {
+    let Some(value) = value else {
+        return Ok(MAX_TRIES_LIMIT);
+    };
+    match value.parse() {
+        Ok(limit) if limit >= 2 => Ok(limit),
+        _ => Err(InvalidMaxTriesLimit(value)),
+    }
+}
+
+pub type Uid = [u8; 16];
+
+/// A single step of an SVR3 state machine test.
+#[derive(Clone, Debug)]
+pub enum Transition {
+    SetUid(Uid),
+    Backup(Secret, u32),
+    Restore,
+    RestoreWithBadPassword,
+}
+
+/// Picks one of a small set of UIDs, so that tests revisit the same accounts.
+pub fn uid() -> impl Strategy<Value = Uid> {
+    prop_oneof![
+        Just([0u8; 16]),
+        Just([1u8; 16]),
+        Just([2u8; 16]),
+        Just([3u8; 16]),
+    ]
+}
+
+pub fn secret() -> impl Strategy<Value = Secret> {
+    any::<[u8; 32]>().prop_map(Secret::from)
+}
+
+/// Panics if [`max_tries_limit`] fails.
+pub fn max_tries() -> impl Strategy<Value = u32> {
+    1..max_tries_limit().unwrap_or_else(|e| panic!("{e}"))
+}
+
+prop_compose! {
+    pub fn backup_pair()(s in secret(), t in max_tries()) -> (Secret, u32) {
+        (s, t)
+    }
+}
+
+/// What the reference model expects the system under test to have observed
+/// for the last transition.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TransitionOutcome {
+    Nothing,
+    NotFound,
+    Restored(Secret),
+    MaxTriesReached,
+    BadCommitment,
+}
+
+impl Arbitrary for TransitionOutcome {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            Just(Self::Nothing),
+            Just(Self::NotFound),
+            secret().prop_map(Self::Restored),
+            Just(Self::MaxTriesReached),
+            Just(Self::BadCommitment),
+        ]
+        .boxed()
+    }
+}
+
+/// Reference model of what SVR3 stores for each UID.
+///
+/// Rather than keeping its own count of tries, the model backs up to and
+/// restores from an [`InMemorySvr3Server`], so it can't drift from the
+/// server semantics the net tests are run against.
+#[derive(Clone, Debug)]
+pub struct InMemoryStorage {
+    uid: Option<Uid>,
+    server: InMemorySvr3Server,
+    share_sets: HashMap<Uid, MaskedShareSet>,
+    last_transition_outcome: TransitionOutcome,
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        InMemoryStorage {
+            uid: None,
+            server: InMemorySvr3Server::new(),
+            share_sets: HashMap::default(),
+            last_transition_outcome: TransitionOutcome::Nothing,
+        }
+    }
+}
+
+impl InMemoryStorage {
+    const SERVER_ID: u64 = 1;
+    const PASSWORD: &'static str = "password";
+    const BAD_PASSWORD: &'static str = "bad password";
+
+    /// The UID set by the last [`Transition::SetUid`], if any.
+    pub fn uid(&self) -> Option<Uid> {
+        self.uid
+    }
+
+    pub fn last_transition_outcome(&self) -> &TransitionOutcome {
+        &self.last_transition_outcome
+    }
+
+    pub fn num_backups(&self) -> usize {
+        self.share_sets.len()
+    }
+
+    /// The UIDs that have data stored, in sorted order.
+    pub fn known_uids(&self) -> Vec<Uid> {
+        let mut uids: Vec<_> = self.share_sets.keys().copied().collect();
+        uids.sort();
+        uids
+    }
+
+    /// Restore attempts left for `uid`, or `None` if nothing is stored.
+    pub fn tries_left(&self, uid: &Uid) -> Option<u32> {
+        self.server.tries_remaining(uid)
+    }
+
+    /// Updates the model for `transition`, recording its expected outcome.
+    ///
+    /// # Panics
+    ///
+    /// If `transition` is a backup or restore and no UID has been set, or a
+    /// backup with no tries.
+    pub fn apply(&mut self, transition: &Transition) {
+        match transition {
+            Transition::SetUid(uid) => {
+                log::info!("MODEL: set uid to {}", hex::encode(uid));
+                self.uid = Some(*uid);
+                self.last_transition_outcome = TransitionOutcome::Nothing;
+            }
+            Transition::Backup(secret, tries_left) => {
+                log::info!("MODEL: backup");
+                log::debug!(
+                    "[{}] with {} tries",
+                    hex::encode(secret.as_bytes()),
+                    tries_left
+                );
+                let uid = self.uid.expect("uid must be set");
+                let share_set = self.backup(uid, secret, *tries_left);
+                let _ = self.share_sets.insert(uid, share_set);
+                self.last_transition_outcome = TransitionOutcome::Nothing;
+            }
+            Transition::Restore | Transition::RestoreWithBadPassword => {
+                let password = match transition {
+                    Transition::RestoreWithBadPassword => Self::BAD_PASSWORD,
+                    _ => Self::PASSWORD,
+                };
+                log::info!("MODEL: restore -> ");
+                let uid = self.uid.expect("uid must be set");
+                self.last_transition_outcome = self.restore(uid, password);
+            }
+        }
+    }
+
+    fn backup(&mut self, uid: Uid, secret: &Secret, tries: u32) -> MaskedShareSet {
+        let tries = NonZeroU32::new(tries).expect("tries start at 1");
+        let backup = Backup::new(
+            &[Self::SERVER_ID],
+            Self::PASSWORD,
+            *secret.as_bytes(),
+            tries,
+            &mut OsRng,
+        )
+        .expect("can create backup");
+        let responses = self.round_trip(uid, &backup.requests);
+        backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup")
+    }
+
+    fn restore(&mut self, uid: Uid, password: &str) -> TransitionOutcome {
+        let Some(share_set) = self.share_sets.get(&uid).cloned() else {
+            log::info!("\tnot found");
+            return TransitionOutcome::NotFound;
+        };
+        let restore = Restore::new(password, share_set, &mut OsRng).expect("can create restore");
+        let responses = self.round_trip(uid, &restore.requests);
+        match restore.finalize(&responses) {
+            Ok(secret) => {
+                log::info!("\tgood restore");
+                TransitionOutcome::Restored(Secret::from(secret))
+            }
+            Err(libsignal_svr3::Error::RestoreFailed(_)) => {
+                log::info!("\tbad commitment");
+                TransitionOutcome::BadCommitment
+            }
+            // The server drops data once its tries have run out.
+            Err(libsignal_svr3::Error::BadResponseStatus(ErrorStatus::Missing)) => {
+                log::info!("\tno more attempts");
+                let _ = self.share_sets.remove(&uid);
+                TransitionOutcome::MaxTriesReached
+            }
+            Err(e) => unreachable!("in-memory restore failed: {e}"),
+        }
+    }
+
+    fn round_trip(&mut self, uid: Uid, requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        requests
+            .iter()
+            .map(|request| {
+                self.server
+                    .handle_request(uid, request)
+                    .expect("valid request")
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UID: Uid = [1; 16];
+
+    #[test]
+    fn max_tries_limit_defaults_and_validates() {
+        assert_eq!(parse_max_tries_limit(None).ok(), Some(MAX_TRIES_LIMIT));
+        assert_eq!(parse_max_tries_limit(Some("3".to_owned())).ok(), Some(3));
+        for invalid in ["0", "1", "-5", "ten", ""] {
+            let error = parse_max_tries_limit(Some(invalid.to_owned())).expect_err(invalid);
+            assert_eq!(
+                error.to_string(),
+                format!("SVR3_MAX_TRIES_LIMIT must be an integer of at least 2, got {invalid:?}")
+            );
+        }
+    }
+
+    /// A model state and the transition that should lead from it to
+    /// `outcome`.
+    fn seeded_for(outcome: &TransitionOutcome) -> (InMemoryStorage, Transition) {
+        let mut state = InMemoryStorage::default();
+        state.apply(&Transition::SetUid(UID));
+        let transition = match outcome {
+            TransitionOutcome::Nothing => Transition::SetUid(UID),
+            TransitionOutcome::NotFound => Transition::Restore,
+            TransitionOutcome::Restored(secret) => {
+                state.apply(&Transition::Backup(secret.clone(), 1));
+                Transition::Restore
+            }
+            TransitionOutcome::MaxTriesReached => {
+                state.apply(&Transition::Backup(Secret::from([0; 32]), 1));
+                state.apply(&Transition::Restore);
+                Transition::Restore
+            }
+            TransitionOutcome::BadCommitment => {
+                state.apply(&Transition::Backup(Secret::from([0; 32]), 1));
+                Transition::RestoreWithBadPassword
+            }
+        };
+        (state, transition)
+    }
+
+    proptest! {
+        #[test]
+        fn seeded_transitions_have_the_expected_outcome(outcome in any::<TransitionOutcome>()) {
+            let (mut state, transition) = seeded_for(&outcome);
+            let backups_before = state.num_backups();
+            state.apply(&transition);
+            prop_assert_eq!(state.last_transition_outcome(), &outcome);
+            prop_assert_eq!(state.uid(), Some(UID));
+
+            match outcome {
+                // Running out of tries loses the data.
+                TransitionOutcome::MaxTriesReached => {
+                    prop_assert_eq!(state.num_backups(), backups_before - 1);
+                    prop_assert!(state.known_uids().is_empty());
+                }
+                TransitionOutcome::NotFound => prop_assert_eq!(state.num_backups(), 0),
+                // Any attempt uses up a try, but keeps the data around.
+                TransitionOutcome::Restored(_) | TransitionOutcome::BadCommitment => {
+                    prop_assert_eq!(state.known_uids(), vec![UID]);
+                    prop_assert_eq!(state.tries_left(&UID), Some(0));
+                }
+                TransitionOutcome::Nothing => {
+                    prop_assert_eq!(state.num_backups(), backups_before)
+                }
+            }
+        }
+    }
+}
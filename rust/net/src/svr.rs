@@ -3,19 +3,42 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::future::Future;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
 
+use async_trait::async_trait;
+use serde::Serialize;
 use thiserror::Error;
+use tokio::time::Instant;
 
 use crate::auth::HttpBasicAuth;
 use crate::enclave::{EnclaveEndpointConnection, NewHandshake, Svr3Flavor};
 use crate::infra::connection_manager::ConnectionManager;
-use crate::infra::errors::{LogSafeDisplay, NetError};
-use crate::infra::reconnect::{ServiceConnectorWithDecorator, ServiceInitializer, ServiceState};
+use crate::infra::correlation::CorrelationId;
+use crate::infra::errors::{ConnectPhase, LogSafeDisplay, NetError, RetryLater};
+use crate::infra::reconnect::ServiceConnectorWithDecorator;
 use crate::infra::ws::{
-    AttestedConnection, AttestedConnectionError, DefaultStream, WebSocketClientConnector,
+    AttestedConnection, AttestedConnectionError, DefaultStream, TrafficMeter,
+    WebSocketClientConnector, WebSocketConfig,
 };
-use crate::infra::{AsyncDuplexStream, TransportConnector};
+use crate::infra::{
+    AsyncDuplexStream, ConnectionParams, HttpRequestDecorator, MaskedDisplay, StreamAndHost,
+    TransportConnector,
+};
+
+/// The largest websocket message, or frame, accepted from an SVR enclave.
+///
+/// Responses to requests take a few hundred bytes, and the attestation that
+/// starts a connection a few tens of kilobytes. Anything larger is refused
+/// before it is buffered, rather than allowing the general websocket limit of
+/// tens of megabytes.
+pub(crate) const MAX_ENCLAVE_MESSAGE_LEN: usize = 1 << 20;
+
+/// How long [`SvrConnection::close_and_forget`] leaves the enclave to answer
+/// the Close frame before dropping the connection anyway.
+pub const CLOSE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Debug, Error, displaydoc::Display)]
 pub enum Error {
@@ -24,16 +47,194 @@ pub enum Error {
     /// Protocol error after establishing a connection
     Protocol,
     /// Enclave attestation failed: {0}
-    AttestationError(attest::enclave::Error),
+    AttestationError(#[source] attest::enclave::Error),
+    /// Network changed since the connection was made
+    NetworkChanged,
+    /// Operation cancelled by caller
+    Cancelled,
 }
 
 impl LogSafeDisplay for Error {}
 
+pub use crate::infra::errors::ErrorCategory;
+
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Net(net) => net.category(),
+            Self::NetworkChanged => ErrorCategory::ConnectionLost,
+            Self::AttestationError(_) => ErrorCategory::Attestation,
+            Self::Protocol => ErrorCategory::Protocol,
+            Self::Cancelled => ErrorCategory::Cancelled,
+        }
+    }
+
+    /// The attestation failure behind this error, if that is what it is.
+    pub fn as_attestation_error(&self) -> Option<&attest::enclave::Error> {
+        match self {
+            Self::AttestationError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// The network failure behind this error, if that is what it is.
+    pub fn as_net_error(&self) -> Option<&NetError> {
+        match self {
+            Self::Net(net) => Some(net),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status the enclave's server turned the connection down with;
+    /// see [`NetError::http_status`].
+    pub fn http_status(&self) -> Option<http::StatusCode> {
+        self.as_net_error().and_then(NetError::http_status)
+    }
+
+    /// Describes the error for an analytics or logging backend; see
+    /// [`ErrorReport`].
+    pub fn to_report(&self) -> ErrorReport {
+        // The enclave won't start passing attestation or speaking the protocol
+        // just because it's asked again.
+        let (code, phase, retryable) = match self {
+            Self::Net(net) => return net.to_report(),
+            Self::Protocol => ("protocol", None, false),
+            Self::AttestationError(_) => {
+                ("attestation_failed", Some(ConnectPhase::Attestation), false)
+            }
+            Self::NetworkChanged => ("network_changed", None, true),
+            Self::Cancelled => ("cancelled", None, true),
+        };
+        ErrorReport {
+            code,
+            kind: self.category(),
+            phase,
+            enclave: None,
+            retryable,
+            retry_after_secs: None,
+            correlation_id: None,
+        }
+    }
+}
+
+impl NetError {
+    /// Describes the error for an analytics or logging backend; see
+    /// [`ErrorReport`].
+    pub fn to_report(&self) -> ErrorReport {
+        ErrorReport {
+            code: self.code(),
+            kind: self.category(),
+            phase: self.connect_phase(),
+            enclave: None,
+            retryable: !self.is_permanent(),
+            retry_after_secs: self.retry_after().map(|delay| delay.as_secs()),
+            correlation_id: None,
+        }
+    }
+}
+
+/// A structured description of an [`Error`] or [`NetError`], for clients
+/// that forward errors to an analytics or logging backend.
+///
+/// It serializes to a JSON object like
+///
+/// ```json
+/// {"code": "connect_timeout", "kind": "Timeout", "phase": "Transport",
+///  "enclave": null, "retryable": true, "retryAfterSecs": null,
+///  "correlationId": null}
+/// ```
+///
+/// Like the errors' log-safe [`Display`](std::fmt::Display), it never holds
+/// hosts, user identifiers, or anything received from the server beyond
+/// the retry hint.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorReport {
+    /// A stable name for the most specific variant of the error, e.g.
+    /// `"rate_limited"`.
+    pub code: &'static str,
+    pub kind: ErrorCategory,
+    /// The phase of connecting that failed, if known.
+    pub phase: Option<ConnectPhase>,
+    /// [Name](Svr3Flavor::flavor_name) of the enclave, if set with
+    /// [`ErrorReport::with_enclave`].
+    pub enclave: Option<&'static str>,
+    /// Whether trying again might succeed; `false` when the server said it
+    /// won't, and for attestation and protocol failures, which retrying
+    /// doesn't fix.
+    pub retryable: bool,
+    /// The delay the server asked for before trying again.
+    pub retry_after_secs: Option<u64>,
+    /// The ID of the operation that failed, if set with
+    /// [`ErrorReport::with_correlation_id`].
+    pub correlation_id: Option<CorrelationId>,
+}
+
+impl ErrorReport {
+    /// Attributes the error to the enclave of the given flavor.
+    pub fn with_enclave(self, enclave: &'static str) -> Self {
+        Self {
+            enclave: Some(enclave),
+            ..self
+        }
+    }
+
+    /// Attributes the error to the operation with the given ID, e.g. the
+    /// [`SvrConnection::correlation_id`] of the connection it happened on.
+    pub fn with_correlation_id(self, correlation_id: CorrelationId) -> Self {
+        Self {
+            correlation_id: Some(correlation_id),
+            ..self
+        }
+    }
+}
+
+/// What happened during a [`SvrConnection::connect_with_diagnostics`] call.
+///
+/// Phases that were never reached are left as `None`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectDiagnostics {
+    /// The ID the connection attempt was made with.
+    pub correlation_id: Option<CorrelationId>,
+    /// The route used by the last transport connection attempt.
+    pub route: Option<MaskedDisplay>,
+    /// Time spent establishing the websocket, including any retries.
+    pub websocket_time: Option<Duration>,
+    /// Time spent on the attestation handshake.
+    pub attestation_time: Option<Duration>,
+}
+
+impl RetryLater for Error {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Net(net) => net.retry_after(),
+            Self::Protocol | Self::AttestationError(_) | Self::NetworkChanged | Self::Cancelled => {
+                None
+            }
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        match self {
+            Self::Net(net) => net.is_permanent(),
+            Self::Protocol | Self::AttestationError(_) | Self::NetworkChanged | Self::Cancelled => {
+                false
+            }
+        }
+    }
+
+    fn category(&self) -> ErrorCategory {
+        Error::category(self)
+    }
+}
+
 impl From<AttestedConnectionError> for Error {
     fn from(value: AttestedConnectionError) -> Self {
         match value {
             AttestedConnectionError::ClientConnection(_) => Self::Protocol,
-            AttestedConnectionError::Net(net) => Self::Net(net),
+            AttestedConnectionError::Net(net) | AttestedConnectionError::SendFailed(net) => {
+                Self::Net(net)
+            }
             AttestedConnectionError::Protocol => Self::Protocol,
             AttestedConnectionError::Sgx(err) => Self::AttestationError(err),
         }
@@ -45,19 +246,136 @@ pub struct SvrConnection<Flavor: Svr3Flavor, S = DefaultStream> {
     witness: PhantomData<Flavor>,
 }
 
-impl<Flavor: Svr3Flavor> From<SvrConnection<Flavor>> for AttestedConnection {
-    fn from(conn: SvrConnection<Flavor>) -> Self {
+impl<Flavor: Svr3Flavor, S> From<SvrConnection<Flavor, S>> for AttestedConnection<S> {
+    fn from(conn: SvrConnection<Flavor, S>) -> Self {
         conn.inner
     }
 }
 
 impl<Flavor: Svr3Flavor, S> SvrConnection<Flavor, S> {
+    /// Failures are reported as [`Error::NetworkChanged`] once the network
+    /// `inner` was made on has changed.
     pub fn new(inner: AttestedConnection<S>) -> Self {
         Self {
             inner,
             witness: PhantomData,
         }
     }
+
+    /// The [name](Svr3Flavor::flavor_name) of the enclave flavor this
+    /// connection was made to.
+    pub fn flavor_name(&self) -> &'static str {
+        Flavor::flavor_name()
+    }
+
+    /// The ID the connection was made with, if any; see
+    /// [`Self::connect_with_correlation_id`].
+    pub fn correlation_id(&self) -> Option<CorrelationId> {
+        self.inner.correlation_id()
+    }
+
+    /// Reinterprets this connection as one to an `E2` enclave, if that is the
+    /// flavor it was made with.
+    ///
+    /// Returns `None` (dropping the connection) if the flavors don't match.
+    pub fn assert_enclave_is<E2: Svr3Flavor>(self) -> Option<SvrConnection<E2, S>> {
+        (Flavor::flavor_name() == E2::flavor_name()).then(|| SvrConnection::new(self.inner))
+    }
+}
+
+impl<Flavor: Svr3Flavor, S: AsyncDuplexStream> SvrConnection<Flavor, S> {
+    /// Passes each outgoing message through `f` before it is encrypted, for
+    /// tests that tamper with the protocol, say by flipping bits or replaying
+    /// old messages.
+    ///
+    /// Interceptors are applied in the order they were added, and stay with
+    /// the connection when it is turned into an [`AttestedConnection`].
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_request_interceptor(
+        mut self,
+        f: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.inner = self.inner.with_request_interceptor(f);
+        self
+    }
+
+    /// Like [`Self::with_request_interceptor`], but for incoming messages,
+    /// which `f` sees after they are decrypted.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_response_interceptor(
+        mut self,
+        f: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.inner = self.inner.with_response_interceptor(f);
+        self
+    }
+
+    /// Whether a message from the enclave has arrived and not been received.
+    ///
+    /// Doesn't wait, so a message still on its way isn't noticed.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn has_pending_messages(&mut self) -> bool {
+        self.inner.has_pending_messages()
+    }
+
+    /// Panics if [`Self::has_pending_messages`].
+    ///
+    /// Meant for the end of a test's exchange with a fake enclave, where an
+    /// unread message means the fake sent more than the test expected, and
+    /// would otherwise be taken as the response to the next request.
+    #[cfg(any(test, feature = "test-support"))]
+    #[track_caller]
+    pub fn assert_no_pending_messages(&mut self) {
+        assert!(
+            !self.has_pending_messages(),
+            "unexpected message pending on {} connection; the server sent more than was received",
+            self.flavor_name()
+        );
+    }
+
+    /// Serializes `msg` and sends it over the attested connection.
+    pub async fn send_typed<M: prost::Message>(&mut self, msg: M) -> Result<(), Error> {
+        self.inner
+            .send(msg)
+            .await
+            .map_err(|e| self.check_network_change(e.into()))
+    }
+
+    /// Receives the next message and decodes it as an `M`.
+    ///
+    /// A message that doesn't decode is reported as [`Error::Protocol`].
+    pub async fn recv_typed<M: prost::Message + Default>(&mut self) -> Result<M, Error> {
+        let received = self
+            .inner
+            .receive()
+            .await
+            .map_err(|e| self.check_network_change(e.into()))?;
+        Ok(received.next_or_close_reason(NetError::Failure)?)
+    }
+
+    /// Checks that the connection still works, returning the round-trip time.
+    ///
+    /// The enclave has no request that it answers without acting on it, so
+    /// this sends a websocket ping instead. Nothing reaches the enclave, so
+    /// stored values and try counters are left alone, as is the time until
+    /// the connection is closed for being idle. If the connection is dead,
+    /// the error is in [`ErrorCategory::ConnectionLost`].
+    pub async fn ping(&mut self) -> Result<Duration, Error> {
+        self.inner
+            .ping()
+            .await
+            .map_err(|e| self.check_network_change(e.into()))
+    }
+
+    /// Reports a network failure as [`Error::NetworkChanged`] if the network
+    /// changed, since the connection can't recover from that.
+    fn check_network_change(&self, error: Error) -> Error {
+        match error {
+            Error::Net(NetError::RateLimited { .. } | NetError::ClientDeprecated) => error,
+            Error::Net(_) if self.inner.network_changed() => Error::NetworkChanged,
+            error => error,
+        }
+    }
 }
 
 impl<E: Svr3Flavor, S: AsyncDuplexStream> SvrConnection<E, S>
@@ -65,36 +383,1032 @@ where
     E: Svr3Flavor + NewHandshake + Sized,
     S: AsyncDuplexStream,
 {
+    /// Connects through the connection manager of `connection`, so that the
+    /// attempt is throttled along with those made through its clones.
+    ///
+    /// The connection gets a [random](CorrelationId::random) correlation ID;
+    /// see [`Self::connect_with_correlation_id`].
     pub async fn connect<C, T>(
         auth: impl HttpBasicAuth,
         connection: &EnclaveEndpointConnection<E, C>,
         transport_connector: T,
     ) -> Result<Self, Error>
+    where
+        C: ConnectionManager,
+        T: TransportConnector<Stream = S>,
+    {
+        Self::connect_with_correlation_id(
+            auth,
+            connection,
+            transport_connector,
+            CorrelationId::random(),
+        )
+        .await
+    }
+
+    /// Like [`Self::connect`], but with the given correlation ID.
+    ///
+    /// The ID is sent to the server with the websocket upgrade, tags this
+    /// side's logs of the attempt, and is reported for the operations run over
+    /// the connection. Connecting to each enclave of an operation with the
+    /// same ID ties them all together.
+    pub async fn connect_with_correlation_id<C, T>(
+        auth: impl HttpBasicAuth,
+        connection: &EnclaveEndpointConnection<E, C>,
+        transport_connector: T,
+        correlation_id: CorrelationId,
+    ) -> Result<Self, Error>
+    where
+        C: ConnectionManager,
+        T: TransportConnector<Stream = S>,
+    {
+        let mut diagnostics = ConnectDiagnostics::default();
+        Self::connect_recording(
+            auth,
+            connection,
+            transport_connector,
+            correlation_id,
+            &mut diagnostics,
+        )
+        .await
+    }
+
+    /// Like [`Self::connect`], but also reports the route used and how long
+    /// each phase of the connection took.
+    pub async fn connect_with_diagnostics<C, T>(
+        auth: impl HttpBasicAuth,
+        connection: &EnclaveEndpointConnection<E, C>,
+        transport_connector: T,
+    ) -> (Result<Self, Error>, ConnectDiagnostics)
+    where
+        C: ConnectionManager,
+        T: TransportConnector<Stream = S>,
+    {
+        let mut diagnostics = ConnectDiagnostics::default();
+        let route = Arc::new(std::sync::Mutex::new(None));
+        let transport_connector = RouteRecordingConnector {
+            inner: transport_connector,
+            route: route.clone(),
+        };
+        let result = Self::connect_recording(
+            auth,
+            connection,
+            transport_connector,
+            CorrelationId::random(),
+            &mut diagnostics,
+        )
+        .await;
+        diagnostics.route = route.lock().expect("not poisoned").take();
+        (result, diagnostics)
+    }
+
+    async fn connect_recording<C, T>(
+        auth: impl HttpBasicAuth,
+        connection: &EnclaveEndpointConnection<E, C>,
+        transport_connector: T,
+        correlation_id: CorrelationId,
+        diagnostics: &mut ConnectDiagnostics,
+    ) -> Result<Self, Error>
+    where
+        C: ConnectionManager,
+        T: TransportConnector<Stream = S>,
+    {
+        diagnostics.correlation_id = Some(correlation_id);
+        let result = Self::connect_attested(
+            auth,
+            connection,
+            transport_connector,
+            correlation_id,
+            diagnostics,
+        )
+        .await;
+        match &result {
+            Ok(_) => log::debug!(
+                "[{correlation_id}] connected to {} enclave",
+                E::flavor_name()
+            ),
+            Err(e) => log::info!(
+                "[{correlation_id}] failed to connect to {} enclave: {e}",
+                E::flavor_name()
+            ),
+        }
+        result
+    }
+
+    async fn connect_attested<C, T>(
+        auth: impl HttpBasicAuth,
+        connection: &EnclaveEndpointConnection<E, C>,
+        transport_connector: T,
+        correlation_id: CorrelationId,
+        diagnostics: &mut ConnectDiagnostics,
+    ) -> Result<Self, Error>
     where
         C: ConnectionManager,
         T: TransportConnector<Stream = S>,
     {
         // TODO: This is almost a direct copy of CdsiConnection::connect. They can be unified.
+        // SVR3 usernames are hex-encoded UIDs, see Auth::from_uid_and_secret.
+        let uid = hex::decode(auth.username())
+            .ok()
+            .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok());
+        // Taken first, so that a change while connecting counts.
+        let network = connection.network_state.watch();
         let auth_decorator = auth.into();
         let websocket_connector = WebSocketClientConnector::new(
             transport_connector,
-            connection.endpoint_connection.config.clone(),
+            with_enclave_message_limits(connection.endpoint_connection.config.clone()),
+        );
+        let websocket_connector = ServiceConnectorWithDecorator::new(
+            &websocket_connector,
+            HttpRequestDecorator::CorrelationId(correlation_id),
         );
         let connector = ServiceConnectorWithDecorator::new(&websocket_connector, auth_decorator);
-        let service_initializer =
-            ServiceInitializer::new(&connector, &connection.endpoint_connection.manager);
-        let connection_attempt_result = service_initializer.connect().await;
-        let websocket = match connection_attempt_result {
-            ServiceState::Active(websocket, _) => Ok(websocket),
-            ServiceState::Cooldown(_) => Err(Error::Net(NetError::NoServiceConnection)),
-            ServiceState::Error(e) => Err(Error::Net(e)),
-            ServiceState::TimedOut => Err(Error::Net(NetError::Timeout)),
-        }?;
-        let attested = AttestedConnection::connect(websocket, |attestation_msg| {
-            E::new_handshake(&connection.params, attestation_msg)
-        })
-        .await?;
+        let websocket_start = Instant::now();
+        let connection_attempt_result = connection.connect_websocket(&connector).await;
+        diagnostics.websocket_time = Some(websocket_start.elapsed());
+        let (websocket, _) = connection_attempt_result.ok()?;
+        let attestation_start = Instant::now();
+        let attested = AttestedConnection::connect_with_timeout(
+            websocket,
+            connection.attestation_timeout,
+            |attestation_msg| E::new_handshake(&connection.params, attestation_msg),
+        )
+        .await;
+        diagnostics.attestation_time = Some(attestation_start.elapsed());
 
+        let mut attested = attested?
+            .with_idle_timeout(connection.idle_timeout)
+            .with_correlation_id(correlation_id)
+            .with_network(network);
+        if let Some(interval) = connection.rekey_interval {
+            attested = attested.with_rekey_interval(interval);
+        }
+        if let Some(uid) = uid {
+            attested = attested.with_uid(uid);
+        }
         Ok(Self::new(attested))
     }
 }
+
+/// Lowers the message and frame size limits of `config` to
+/// [`MAX_ENCLAVE_MESSAGE_LEN`], keeping any that are lower already.
+fn with_enclave_message_limits(mut config: WebSocketConfig) -> WebSocketConfig {
+    let limit = |configured: Option<usize>| {
+        Some(configured.map_or(MAX_ENCLAVE_MESSAGE_LEN, |len| {
+            len.min(MAX_ENCLAVE_MESSAGE_LEN)
+        }))
+    };
+    config.ws_config.max_message_size = limit(config.ws_config.max_message_size);
+    config.ws_config.max_frame_size = limit(config.ws_config.max_frame_size);
+    config
+}
+
+impl<Flavor: Svr3Flavor, S: AsyncDuplexStream> SvrConnection<Flavor, S> {
+    /// Whether the connection is already known to be unusable, e.g. because a
+    /// previous send or receive failed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// How long the connection has gone without sending or receiving
+    /// anything.
+    ///
+    /// Connections are closed once this reaches the idle timeout of the
+    /// [`EnclaveEndpointConnection`] they were made with.
+    pub fn time_since_last_activity(&self) -> Duration {
+        self.inner.time_since_last_activity()
+    }
+
+    /// When a message was last sent or received successfully over this
+    /// connection, or when it was made if none has been yet.
+    ///
+    /// Of several idle connections, the one with the earliest last activity
+    /// is the least recently used.
+    pub fn last_activity(&self) -> Instant {
+        self.inner.last_activity()
+    }
+
+    /// Starts reading the traffic over this connection from now on.
+    ///
+    /// The meter keeps counting after the connection is handed to an
+    /// operation, so it can be read once the operation is done.
+    pub fn traffic_meter(&self) -> TrafficMeter {
+        self.inner.traffic_meter()
+    }
+
+    /// Total size of the encrypted messages sent over this connection,
+    /// including the attestation handshake.
+    ///
+    /// Unlike [`Self::traffic_meter`], this counts what goes over the wire,
+    /// though without websocket framing and TLS.
+    pub fn bytes_sent(&self) -> u64 {
+        self.inner.bytes_sent()
+    }
+
+    /// Like [`Self::bytes_sent`], but for messages received, including the
+    /// attestation.
+    pub fn bytes_received(&self) -> u64 {
+        self.inner.bytes_received()
+    }
+
+    /// Closes the connection normally, without sending any requests.
+    pub(crate) async fn close(self) -> Result<(), Error> {
+        Ok(self.inner.close().await?)
+    }
+
+    /// Closes the connection without waiting for the enclave to acknowledge
+    /// it, say once an operation has succeeded and its result is all that
+    /// matters.
+    ///
+    /// The returned future is done as soon as the Close frame has been sent.
+    /// Waiting for the enclave's Close frame is left to a task spawned on the
+    /// current runtime, which drops the connection when it arrives or after
+    /// [`CLOSE_RESPONSE_TIMEOUT`], whichever comes first. Failing to send the
+    /// Close frame is not reported; the connection is dropped all the same.
+    pub fn close_and_forget(self) -> impl Future<Output = ()>
+    where
+        S: 'static,
+    {
+        self.inner.close_and_forget(CLOSE_RESPONSE_TIMEOUT)
+    }
+}
+
+/// Remembers the route of the most recent connection attempt.
+#[derive(Clone)]
+struct RouteRecordingConnector<T> {
+    inner: T,
+    route: Arc<std::sync::Mutex<Option<MaskedDisplay>>>,
+}
+
+#[async_trait]
+impl<T: TransportConnector> TransportConnector for RouteRecordingConnector<T> {
+    type Stream = T::Stream;
+
+    async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+        alpn: &[u8],
+    ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+        *self.route.lock().expect("not poisoned") = Some(connection_params.masked_display());
+        self.inner.connect(connection_params, alpn).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use assert_matches::assert_matches;
+    use tokio::sync::watch;
+    use warp::Filter as _;
+
+    use super::*;
+    use crate::auth::Auth;
+    use crate::enclave::{ConnectionHealth, EnclaveEndpoint, Nitro, Sgx};
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::connection_manager::MAX_COOLDOWN_INTERVAL;
+    use crate::infra::correlation::CORRELATION_ID_HEADER;
+    use crate::infra::network_state::NetworkState;
+    use crate::infra::test::shared::InMemoryWarpConnector;
+    use crate::infra::ws::testutil::{
+        attested_server_handshake, fake_websocket, faulty_attested_connection,
+        run_attested_echo_server, websocket_test_client,
+    };
+    use crate::infra::ws::NextOrClose;
+    use crate::infra::HttpRequestDecoratorSeq;
+    use crate::proto::chat_websocket::WebSocketRequestMessage;
+
+    async fn connect_to_echo_server() -> SvrConnection<Sgx, tokio::io::DuplexStream> {
+        let (server, client) = fake_websocket().await;
+        tokio::spawn(run_attested_echo_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+        ));
+        let attested = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        SvrConnection::new(attested)
+    }
+
+    #[tokio::test]
+    async fn typed_round_trip() {
+        let mut connection = connect_to_echo_server().await;
+        let message = WebSocketRequestMessage {
+            verb: Some("PUT".to_string()),
+            path: Some("/v1/backup".to_string()),
+            body: Some(b"body".to_vec()),
+            headers: vec!["content-type: application/x-protobuf".to_string()],
+            id: Some(42),
+        };
+
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        let received: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        assert_eq!(received, message);
+    }
+
+    /// Waits for a message to arrive without receiving it.
+    async fn wait_for_pending_message(
+        connection: &mut SvrConnection<Sgx, tokio::io::DuplexStream>,
+    ) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !connection.has_pending_messages() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("message arrives");
+    }
+
+    #[tokio::test]
+    async fn unread_messages_are_pending() {
+        let mut connection = connect_to_echo_server().await;
+        assert!(!connection.has_pending_messages());
+
+        let message = WebSocketRequestMessage {
+            id: Some(1),
+            ..Default::default()
+        };
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        wait_for_pending_message(&mut connection).await;
+        // Checking doesn't consume the message.
+        assert!(connection.has_pending_messages());
+        let received: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        assert_eq!(received, message);
+        connection.assert_no_pending_messages();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected message pending on sgx connection")]
+    async fn extra_message_fails_assertion() {
+        let mut connection = connect_to_echo_server().await;
+        let message = WebSocketRequestMessage::default();
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        connection.send_typed(message).await.expect("can send");
+        let _: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        wait_for_pending_message(&mut connection).await;
+        connection.assert_no_pending_messages();
+    }
+
+    /// Connects to a server that reports the first frame it receives after
+    /// the handshake, and how long after that frame the client went away.
+    ///
+    /// The server only answers a Close frame if `answer_close` is set.
+    async fn connect_to_closing_server(
+        answer_close: bool,
+    ) -> (
+        SvrConnection<Sgx, tokio::io::DuplexStream>,
+        tokio::task::JoinHandle<(Option<tungstenite::Message>, Duration)>,
+    ) {
+        use futures_util::StreamExt as _;
+        use tokio::io::AsyncReadExt as _;
+
+        let (mut server, client) = fake_websocket().await;
+        let server = tokio::spawn(async move {
+            let _transport = attested_server_handshake(
+                &mut server,
+                attest::sgx_session::testutil::private_key(),
+            )
+            .await;
+            let first = server.next().await.and_then(Result::ok);
+            let received_at = Instant::now();
+            if answer_close {
+                // Reading on sends the automatic answer to a Close frame.
+                while let Some(Ok(_)) = server.next().await {}
+            } else {
+                // Reading the raw stream keeps the websocket from answering.
+                let mut buf = [0; 1024];
+                while server.get_mut().read(&mut buf).await.expect("can read") > 0 {}
+            }
+            (first, received_at.elapsed())
+        });
+        let attested = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        (SvrConnection::new(attested), server)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_and_forget_gives_up_on_unanswered_close() {
+        let (connection, server) = connect_to_closing_server(false).await;
+        let start = Instant::now();
+        connection.close_and_forget().await;
+        // With the clock paused, waiting for the server would have taken the
+        // whole timeout.
+        assert!(start.elapsed() < CLOSE_RESPONSE_TIMEOUT);
+
+        let (first, dropped_after) = server.await.expect("server ran");
+        assert_matches!(
+            first,
+            Some(tungstenite::Message::Close(Some(frame)))
+                if frame.code == tungstenite::protocol::frame::coding::CloseCode::Normal
+        );
+        assert!(
+            dropped_after <= CLOSE_RESPONSE_TIMEOUT,
+            "dropped after {dropped_after:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_and_forget_drops_connection_once_close_is_answered() {
+        let (connection, server) = connect_to_closing_server(true).await;
+        connection.close_and_forget().await;
+        let (first, dropped_after) = server.await.expect("server ran");
+        assert_matches!(first, Some(tungstenite::Message::Close(_)));
+        assert!(
+            dropped_after < CLOSE_RESPONSE_TIMEOUT,
+            "dropped after {dropped_after:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_typed_rejects_undecodable_message() {
+        let mut connection = connect_to_echo_server().await;
+        // An unterminated varint can't be decoded as any message.
+        connection
+            .inner
+            .send_bytes([0x08, 0xff])
+            .await
+            .expect("can send");
+        assert_matches!(
+            connection.recv_typed::<WebSocketRequestMessage>().await,
+            Err(Error::Protocol)
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_response_is_a_protocol_error() {
+        let mut connection = connect_to_echo_server()
+            .await
+            .with_response_interceptor(|bytes| bytes[..bytes.len() - 1].to_vec());
+        let message = WebSocketRequestMessage {
+            body: Some(b"body".to_vec()),
+            ..Default::default()
+        };
+        connection.send_typed(message).await.expect("can send");
+        assert_matches!(
+            connection.recv_typed::<WebSocketRequestMessage>().await,
+            Err(Error::Protocol)
+        );
+    }
+
+    #[tokio::test]
+    async fn interceptors_are_applied_in_order() {
+        let append = |suffix: &'static [u8]| move |bytes: &[u8]| [bytes, suffix].concat();
+        let mut connection = connect_to_echo_server()
+            .await
+            .with_request_interceptor(append(b"1"))
+            .with_response_interceptor(append(b"3"))
+            .with_request_interceptor(append(b"2"))
+            .with_response_interceptor(append(b"4"));
+        connection.inner.send_bytes(b"0").await.expect("can send");
+        assert_matches!(
+            connection.inner.receive_bytes().await,
+            Ok(NextOrClose::Next(echoed)) if echoed == b"01234"
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_leaves_session_untouched() {
+        let mut connection = connect_to_echo_server().await;
+        let meter = connection.traffic_meter();
+        connection.ping().await.expect("connection is alive");
+        assert_eq!(meter.read().requests_sent, 0);
+
+        // The Noise session is still in step with the enclave's.
+        let message = WebSocketRequestMessage {
+            id: Some(1),
+            ..Default::default()
+        };
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        let received: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn ping_fails_on_half_closed_connection() {
+        let (attested, faults, _requests, _server) = faulty_attested_connection().await;
+        let mut connection = SvrConnection::<Sgx, _>::new(attested);
+        faults.close_reads();
+
+        let error = connection.ping().await.expect_err("no pong arrives");
+        assert_eq!(error.category(), ErrorCategory::ConnectionLost);
+        assert!(connection.is_closed());
+    }
+
+    #[tokio::test]
+    async fn assert_enclave_is_checks_flavor() {
+        let connection = connect_to_echo_server().await;
+        assert!(connection.assert_enclave_is::<Nitro>().is_none());
+
+        let connection = connect_to_echo_server().await;
+        assert_eq!(connection.flavor_name(), "sgx");
+        let mut connection = connection
+            .assert_enclave_is::<Sgx>()
+            .expect("connection is to an SGX enclave");
+        // The connection is still usable after the conversion.
+        let message = WebSocketRequestMessage {
+            id: Some(1),
+            ..Default::default()
+        };
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        let received: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn failures_after_network_change_are_reported_as_such() {
+        let message = WebSocketRequestMessage {
+            id: Some(1),
+            ..Default::default()
+        };
+
+        // Before the network changes, a broken connection is an ordinary
+        // network error.
+        let (attested, faults, _requests, _server) = faulty_attested_connection().await;
+        let network_state = Arc::new(NetworkState::new());
+        let mut connection =
+            SvrConnection::<Sgx, _>::new(attested.with_network(network_state.watch()));
+        faults.fail_writes_after(0);
+        assert_matches!(
+            connection.send_typed(message.clone()).await,
+            Err(Error::Net(net)) if net.io_error_kind() == Some(io::ErrorKind::BrokenPipe)
+        );
+
+        let (attested, faults, _requests, _server) = faulty_attested_connection().await;
+        let mut connection =
+            SvrConnection::<Sgx, _>::new(attested.with_network(network_state.watch()));
+        network_state.mark_changed();
+        faults.fail_writes_after(0);
+        let error = connection
+            .send_typed(message.clone())
+            .await
+            .expect_err("can't send");
+        assert_matches!(error, Error::NetworkChanged);
+        assert_eq!(error.category(), ErrorCategory::ConnectionLost);
+        assert_matches!(
+            crate::svr3::Error::from(error),
+            crate::svr3::Error::NetworkChanged
+        );
+
+        let (attested, faults, _requests, _server) = faulty_attested_connection().await;
+        let mut connection =
+            SvrConnection::<Sgx, _>::new(attested.with_network(network_state.watch()));
+        network_state.mark_changed();
+        faults.fail_reads_after(0);
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        assert_matches!(
+            connection.recv_typed::<WebSocketRequestMessage>().await,
+            Err(Error::NetworkChanged)
+        );
+
+        // Connections made since then are on the new network.
+        let (attested, faults, _requests, _server) = faulty_attested_connection().await;
+        let mut connection =
+            SvrConnection::<Sgx, _>::new(attested.with_network(network_state.watch()));
+        faults.fail_writes_after(0);
+        assert_matches!(
+            connection.send_typed(message).await,
+            Err(Error::Net(net)) if net.io_error_kind() == Some(io::ErrorKind::BrokenPipe)
+        );
+
+        // Undecodable messages are still protocol errors.
+        let (attested, _faults, _requests, _server) = faulty_attested_connection().await;
+        let mut connection =
+            SvrConnection::<Sgx, _>::new(attested.with_network(network_state.watch()));
+        network_state.mark_changed();
+        connection
+            .inner
+            .send_bytes([0x08, 0xff])
+            .await
+            .expect("can send");
+        assert_matches!(
+            connection.recv_typed::<WebSocketRequestMessage>().await,
+            Err(Error::Protocol)
+        );
+    }
+
+    /// Fails every connection attempt as if the TCP connection failed with
+    /// the given kind of error.
+    #[derive(Clone)]
+    struct FailingConnector(io::ErrorKind);
+
+    #[async_trait]
+    impl TransportConnector for FailingConnector {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(
+            &self,
+            _connection_params: &ConnectionParams,
+            _alpn: &[u8],
+        ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+            Err(NetError::TcpConnectionFailed(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_tcp_connection_is_categorized() {
+        for kind in [io::ErrorKind::ConnectionRefused, io::ErrorKind::TimedOut] {
+            let connection = EnclaveEndpointConnection::new(
+                EnclaveEndpoint::<Sgx>::test_endpoint(8443),
+                Duration::from_secs(10),
+            );
+            let auth = Auth {
+                username: "username".to_string(),
+                password: "password".to_string(),
+            };
+            let error = SvrConnection::<Sgx, _>::connect(auth, &connection, FailingConnector(kind))
+                .await
+                .err()
+                .expect("can't connect");
+            assert_eq!(error.category(), ErrorCategory::TcpConnect, "{kind:?}");
+            assert_matches!(error, Error::Net(net) if net.io_error_kind() == Some(kind));
+        }
+    }
+
+    /// Fails every connection attempt, counting them.
+    #[derive(Clone, Default)]
+    struct CountingConnector(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl CountingConnector {
+        fn attempts(&self) -> usize {
+            self.0.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl TransportConnector for CountingConnector {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(
+            &self,
+            _connection_params: &ConnectionParams,
+            _alpn: &[u8],
+        ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(NetError::TcpConnectionFailed(
+                io::ErrorKind::ConnectionRefused,
+            ))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_of_endpoint_connection_share_one_throttle() {
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Sgx>::test_endpoint(8443),
+            Duration::from_secs(10),
+        );
+        let clone = connection.clone();
+        let connector = CountingConnector::default();
+        let auth = || Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+
+        // Attempts made at the very instant the manager was created don't
+        // count towards its cooldown.
+        tokio::time::advance(Duration::from_millis(5)).await;
+        let (first, second) = tokio::join!(
+            SvrConnection::<Sgx, _>::connect(auth(), &connection, connector.clone()),
+            SvrConnection::<Sgx, _>::connect(auth(), &clone, connector.clone()),
+        );
+        assert!(first.is_err() && second.is_err());
+        assert_eq!(connector.attempts(), 2);
+
+        // Had each clone been throttled on its own, a single failure would not
+        // have put either of them in cooldown yet.
+        for connection in [&connection, &clone] {
+            let result =
+                SvrConnection::<Sgx, _>::connect(auth(), connection, connector.clone()).await;
+            assert!(result.is_err());
+        }
+        assert_eq!(connector.attempts(), 2);
+    }
+
+    #[tokio::test]
+    async fn correlation_id_is_sent_with_upgrade() {
+        let received = Arc::new(std::sync::Mutex::new(None));
+        let server = {
+            let received = received.clone();
+            warp::header::<String>(CORRELATION_ID_HEADER)
+                .and(warp::ws())
+                .map(move |id: String, ws: warp::ws::Ws| {
+                    *received.lock().expect("not poisoned") = Some(id);
+                    ws.on_upgrade(|_socket| async {})
+                })
+        };
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Sgx>::test_endpoint(8443),
+            Duration::from_secs(10),
+        );
+        let auth = Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+        let correlation_id = CorrelationId::random();
+
+        // The server never attests, but the ID goes out before that.
+        SvrConnection::<Sgx, _>::connect_with_correlation_id(
+            auth,
+            &connection,
+            InMemoryWarpConnector::new(server),
+            correlation_id,
+        )
+        .await
+        .err()
+        .expect("no attestation");
+        assert_eq!(
+            *received.lock().expect("not poisoned"),
+            Some(correlation_id.to_string())
+        );
+    }
+
+    /// Fails on routes to the `down` hosts, and otherwise reaches a websocket
+    /// server that never sends an attestation.
+    #[derive(Clone)]
+    struct PartialOutageConnector {
+        down: &'static [&'static str],
+    }
+
+    #[async_trait]
+    impl TransportConnector for PartialOutageConnector {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(
+            &self,
+            connection_params: &ConnectionParams,
+            alpn: &[u8],
+        ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+            if self.down.contains(&&*connection_params.host) {
+                return Err(NetError::TcpConnectionFailed(
+                    io::ErrorKind::ConnectionRefused,
+                ));
+            }
+            let server = warp::ws().map(|ws: warp::ws::Ws| ws.on_upgrade(|_socket| async {}));
+            InMemoryWarpConnector::new(server)
+                .connect(connection_params, alpn)
+                .await
+        }
+    }
+
+    /// Runs `connect`, which should make a connection with the health
+    /// `expected` that is closed right away, and checks that the health goes
+    /// through [`ConnectionHealth::Reconnecting`] and back to offline.
+    async fn expect_connection(
+        health: &mut watch::Receiver<ConnectionHealth>,
+        connect: impl Future<Output = ()>,
+        expected: ConnectionHealth,
+    ) {
+        let ((), ()) = tokio::join!(connect, async {
+            health.changed().await.expect("sender is alive");
+            assert_eq!(*health.borrow_and_update(), ConnectionHealth::Reconnecting);
+            health.changed().await.expect("sender is alive");
+            assert_eq!(*health.borrow_and_update(), expected);
+        });
+        health
+            .wait_for(|health| *health == ConnectionHealth::Offline)
+            .await
+            .expect("sender is alive");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn endpoint_health_follows_connection_attempts() {
+        const ROUTE_1: &str = "route1.signal.org";
+        const ROUTE_2: &str = "route2.signal.org";
+        let connection = EnclaveEndpointConnection::new_multi(
+            EnclaveEndpoint::<Sgx>::test_endpoint(8443).mr_enclave,
+            [ROUTE_1, ROUTE_2].map(|host| {
+                ConnectionParams::new(
+                    host,
+                    host,
+                    8443,
+                    HttpRequestDecoratorSeq::default(),
+                    RootCertificates::Native,
+                )
+            }),
+            Duration::from_secs(10),
+        );
+        let connect = |down: &'static [&'static str]| {
+            let connection = &connection;
+            async move {
+                let auth = Auth {
+                    username: "username".to_string(),
+                    password: "password".to_string(),
+                };
+                // The servers never attest, so every attempt ends in an error.
+                SvrConnection::<Sgx, _>::connect(auth, connection, PartialOutageConnector { down })
+                    .await
+                    .err()
+                    .expect("no attestation");
+            }
+        };
+
+        let mut health = connection.health();
+        assert_eq!(*health.borrow_and_update(), ConnectionHealth::Offline);
+
+        // Attempts made at the very instant the managers were created don't
+        // count towards their cooldown.
+        tokio::time::advance(Duration::from_millis(5)).await;
+        connect(&[ROUTE_1, ROUTE_2]).await;
+        assert_eq!(*health.borrow_and_update(), ConnectionHealth::Offline);
+
+        // Once the cooldowns are over, the second route works. Each websocket
+        // is closed when its attestation fails, which takes the health back
+        // to offline.
+        tokio::time::advance(Duration::from_secs(2)).await;
+        expect_connection(&mut health, connect(&[ROUTE_1]), ConnectionHealth::Degraded).await;
+
+        // The first one is still cooling down, so the second one is used...
+        expect_connection(&mut health, connect(&[]), ConnectionHealth::Degraded).await;
+
+        // ...until the first one can be tried again.
+        tokio::time::advance(MAX_COOLDOWN_INTERVAL).await;
+        expect_connection(&mut health, connect(&[]), ConnectionHealth::Connected).await;
+
+        // Subscribers that come late see the current health right away.
+        assert_eq!(
+            *connection.clone().health().borrow(),
+            ConnectionHealth::Offline
+        );
+    }
+
+    #[test]
+    fn enclave_message_limits_only_lower_configured_ones() {
+        let default_config = crate::infra::make_ws_config(
+            http::uri::PathAndQuery::from_static("/v1"),
+            Duration::from_secs(10),
+        );
+        assert!(default_config
+            .ws_config
+            .max_message_size
+            .is_some_and(|len| len > MAX_ENCLAVE_MESSAGE_LEN));
+
+        let limited = with_enclave_message_limits(default_config.clone());
+        assert_eq!(
+            limited.ws_config.max_message_size,
+            Some(MAX_ENCLAVE_MESSAGE_LEN)
+        );
+        assert_eq!(
+            limited.ws_config.max_frame_size,
+            Some(MAX_ENCLAVE_MESSAGE_LEN)
+        );
+
+        let mut lower = default_config;
+        lower.ws_config.max_message_size = Some(1024);
+        lower.ws_config.max_frame_size = None;
+        let limited = with_enclave_message_limits(lower);
+        assert_eq!(limited.ws_config.max_message_size, Some(1024));
+        assert_eq!(
+            limited.ws_config.max_frame_size,
+            Some(MAX_ENCLAVE_MESSAGE_LEN)
+        );
+    }
+
+    #[test]
+    fn io_error_kinds_are_categorized() {
+        for (kind, category) in [
+            (io::ErrorKind::ConnectionRefused, ErrorCategory::TcpConnect),
+            (
+                io::ErrorKind::ConnectionReset,
+                ErrorCategory::ConnectionLost,
+            ),
+            (
+                io::ErrorKind::ConnectionAborted,
+                ErrorCategory::ConnectionLost,
+            ),
+            (io::ErrorKind::BrokenPipe, ErrorCategory::ConnectionLost),
+            (io::ErrorKind::Other, ErrorCategory::Network),
+        ] {
+            let io_error = Error::Net(NetError::Io(kind));
+            assert_eq!(io_error.category(), category, "{kind:?}");
+            assert!(io_error.to_string().contains(&kind.to_string()));
+
+            let ws_error = Error::Net(NetError::WebSocketError(crate::infra::ws::Error::Io(kind)));
+            assert_eq!(ws_error.category(), category, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn error_reports_serialize_to_stable_json() {
+        use serde_json::json;
+
+        let timeout = Error::Net(NetError::ConnectTimeout(ConnectPhase::Transport)).to_report();
+        let correlation_id = CorrelationId::from(uuid::Uuid::from_u128(1));
+        assert_eq!(
+            serde_json::to_value(
+                timeout
+                    .with_enclave(Sgx::flavor_name())
+                    .with_correlation_id(correlation_id)
+            )
+            .expect("serializes"),
+            json!({
+                "code": "connect_timeout",
+                "kind": "Timeout",
+                "phase": "Transport",
+                "enclave": "sgx",
+                "retryable": true,
+                "retryAfterSecs": null,
+                "correlationId": "00000000-0000-0000-0000-000000000001",
+            })
+        );
+
+        let rate_limited = NetError::RateLimited {
+            retry_after_seconds: 30,
+        };
+        assert_eq!(
+            serde_json::to_value(rate_limited.to_report()).expect("serializes"),
+            json!({
+                "code": "rate_limited",
+                "kind": "RateLimited",
+                "phase": null,
+                "enclave": null,
+                "retryable": true,
+                "retryAfterSecs": 30,
+                "correlationId": null,
+            })
+        );
+
+        assert_eq!(
+            serde_json::to_value(Error::Net(NetError::ClientDeprecated).to_report())
+                .expect("serializes"),
+            json!({
+                "code": "client_deprecated",
+                "kind": "ClientDeprecated",
+                "phase": null,
+                "enclave": null,
+                "retryable": false,
+                "retryAfterSecs": null,
+                "correlationId": null,
+            })
+        );
+
+        let attestation = Error::AttestationError(attest::enclave::Error::AttestationDataError {
+            reason: "unknown mrenclave".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_value(attestation.to_report().with_enclave(Nitro::flavor_name()))
+                .expect("serializes"),
+            json!({
+                "code": "attestation_failed",
+                "kind": "Attestation",
+                "phase": "Attestation",
+                "enclave": "nitro",
+                "retryable": false,
+                "retryAfterSecs": null,
+                "correlationId": null,
+            })
+        );
+        assert!(!Error::Protocol.to_report().retryable);
+        assert!(Error::NetworkChanged.to_report().retryable);
+    }
+
+    #[test]
+    fn inner_errors_can_be_inspected() {
+        let attestation = Error::AttestationError(attest::enclave::Error::AttestationDataError {
+            reason: "unknown mrenclave".to_string(),
+        });
+        assert_matches!(
+            attestation.as_attestation_error(),
+            Some(attest::enclave::Error::AttestationDataError { .. })
+        );
+        assert_matches!(
+            std::error::Error::source(&attestation)
+                .and_then(|source| source.downcast_ref::<attest::enclave::Error>()),
+            Some(attest::enclave::Error::AttestationDataError { .. })
+        );
+        assert_eq!(attestation.as_net_error(), None);
+        assert_eq!(attestation.http_status(), None);
+
+        let refused = Error::Net(NetError::WebSocketError(crate::infra::ws::Error::Http(
+            http::StatusCode::FORBIDDEN,
+        )));
+        assert_matches!(
+            std::error::Error::source(&refused)
+                .and_then(|source| source.downcast_ref::<NetError>()),
+            Some(NetError::WebSocketError(_))
+        );
+        assert_eq!(refused.http_status(), Some(http::StatusCode::FORBIDDEN));
+        assert_matches!(refused.as_attestation_error(), None);
+    }
+}
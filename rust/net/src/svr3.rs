@@ -6,107 +6,89 @@
 use thiserror::Error;
 
 use crate::enclave::{IntoConnections, PpssSetup};
-use crate::infra::errors::{LogSafeDisplay, NetError};
-use crate::infra::ws::{run_attested_interaction, AttestedConnectionError};
+use crate::env::ValidationError;
+use crate::infra::errors::{LogSafeDisplay, NetError, RetryLater};
+use crate::infra::ws::{
+    run_attested_interaction, AttestedConnectionError, AttestedConnectionLike, NextOrClose,
+};
 use async_trait::async_trait;
-use bincode::Options as _;
-use futures_util::future::try_join_all;
-use libsignal_svr3::{Backup, MaskedShareSet, Restore};
+use futures_util::future::join_all;
+use libsignal_svr3::{
+    Backup, MaskedShareSet, Query, Remove, Restore, StrengthenedPassword, Strengthening,
+};
 use rand_core::CryptoRngCore;
-use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::future::Future;
 use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use zeroize::Zeroizing;
 
-const MASKED_SHARE_SET_FORMAT: u8 = 0;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod diagnostics;
+pub mod operation_log;
+pub mod pool;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod reachability;
+pub mod request_log;
+pub mod secret_derivation;
+pub mod share_set_store;
+pub mod traffic;
+pub use libsignal_svr3::{
+    Argon2Strengthener, DeserializeError, OpaqueMaskedShareSet, PasswordStrengthener,
+    SerializeError,
+};
+pub use operation_log::OperationLog;
+use request_log::RequestRecorder;
+pub use request_log::{NoopRequestLogger, RecoveryEvent, RequestEvent, RequestLogger};
+pub use secret_derivation::{Secret, SecretDerivation};
+#[cfg(unix)]
+pub use share_set_store::FileShareSetStore;
+pub use share_set_store::{InMemoryShareSetStore, ShareSetStore, StoredShareSet};
 
-#[derive(Clone)]
-#[cfg_attr(test, derive(Debug))]
-pub struct OpaqueMaskedShareSet {
-    inner: SerializableMaskedShareSet,
-}
-
-// Non pub version of ppss::MaskedShareSet used for serialization
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct SerializableMaskedShareSet {
-    server_ids: Vec<u64>,
-    masked_shares: Vec<[u8; 32]>,
-    commitment: [u8; 32],
-}
+impl LogSafeDisplay for DeserializeError {}
 
-impl From<MaskedShareSet> for SerializableMaskedShareSet {
-    fn from(value: MaskedShareSet) -> Self {
-        Self {
-            server_ids: value.server_ids,
-            masked_shares: value.masked_shares,
-            commitment: value.commitment,
-        }
-    }
-}
+/// Identifier of the account an operation is for.
+pub type Uid = [u8; 16];
 
-impl SerializableMaskedShareSet {
-    fn into(self) -> MaskedShareSet {
-        MaskedShareSet {
-            server_ids: self.server_ids,
-            masked_shares: self.masked_shares,
-            commitment: self.commitment,
-        }
+/// Identifies a set of enclaves by their measurements.
+///
+/// The order of `mr_enclaves` doesn't matter.
+fn environment_hash<'a>(mr_enclaves: impl IntoIterator<Item = &'a [u8]>) -> [u8; 32] {
+    let mut mr_enclaves: Vec<_> = mr_enclaves.into_iter().collect();
+    mr_enclaves.sort_unstable();
+    mr_enclaves.dedup();
+    let mut hasher = Sha256::new_with_prefix(b"libsignal-net SVR3 environment");
+    for mr_enclave in mr_enclaves {
+        hasher.update((mr_enclave.len() as u64).to_be_bytes());
+        hasher.update(mr_enclave);
     }
+    hasher.finalize().into()
 }
 
-#[derive(Debug)]
-pub struct SerializeError;
-
-#[derive(Debug, Eq, PartialEq, displaydoc::Display, Error)]
-pub enum DeserializeError {
-    /// Unexpected OpaqueMaskedShareSet serialization format version {0}
-    BadVersion(u8),
-    /// Unsupported OpaqueMaskedShareSet serialization format
-    BadFormat,
-}
-
-impl LogSafeDisplay for DeserializeError {}
-
-impl OpaqueMaskedShareSet {
-    fn new(inner: MaskedShareSet) -> Self {
-        Self {
-            inner: inner.into(),
+/// Makes sure `share_set` is restored from the enclaves it was backed up to,
+/// identified by `expected`.
+///
+/// Share sets made before the environment was recorded can't be checked, and
+/// are let through.
+fn check_environment(
+    share_set: &OpaqueMaskedShareSet,
+    expected: &[u8; 32],
+    allow_enclave_migration: bool,
+) -> Result<(), Error> {
+    match share_set.was_backed_up_to(expected) {
+        None => {
+            log::warn!("share set does not record its enclaves; restoring without checking them");
+            Ok(())
         }
-    }
-    fn into_inner(self) -> MaskedShareSet {
-        self.inner.into()
-    }
-
-    // OpaqueMaskedShareSet should be presented to the clients as an opaque blob,
-    // therefore serialize/deserialize should be the only public APIs for it.
-    pub fn serialize(&self) -> Result<Vec<u8>, SerializeError> {
-        let mut buf = vec![MASKED_SHARE_SET_FORMAT];
-
-        Self::bincode_options()
-            .serialize_into(&mut buf, &self.inner)
-            .map_err(|_| SerializeError)?;
-        Ok(buf)
-    }
-
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
-        match bytes {
-            [] => Err(DeserializeError::BadFormat),
-            [MASKED_SHARE_SET_FORMAT, data @ ..] => Self::bincode_deserialize(data),
-            [v, ..] => Err(DeserializeError::BadVersion(*v)),
+        Some(true) => Ok(()),
+        Some(false) if allow_enclave_migration => {
+            log::info!("restoring share set from different enclaves than it was backed up to");
+            Ok(())
         }
-    }
-
-    fn bincode_options() -> impl bincode::Options {
-        // Using options to reject possible trailing bytes but retain the fixed representation for integers.
-        // See https://docs.rs/bincode/latest/bincode/config/index.html#options-struct-vs-bincode-functions
-        bincode::config::DefaultOptions::new()
-            .reject_trailing_bytes()
-            .with_fixint_encoding()
-    }
-
-    fn bincode_deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
-        let inner = Self::bincode_options()
-            .deserialize(bytes)
-            .map_err(|_| DeserializeError::BadFormat)?;
-        Ok(Self { inner })
+        Some(false) => Err(Error::EnvironmentMismatch),
     }
 }
 
@@ -124,21 +106,158 @@ impl OpaqueMaskedShareSet {
 pub enum Error {
     /// Network error: {0}
     Net(#[from] NetError),
+    /// Network error before the request was fully sent: {0}
+    ///
+    /// None of the enclaves can have processed the request, so the operation
+    /// can be retried.
+    RequestNotSent(#[source] NetError),
     /// Protocol error after establishing a connection: {0}
     Protocol(String),
     /// Enclave attestation failed: {0}
-    AttestationError(attest::enclave::Error),
+    AttestationError(#[source] attest::enclave::Error),
     /// SVR3 request failed with status {0}
     RequestFailed(libsignal_svr3::ErrorStatus),
-    /// Failure to restore data
+    /// Failure to restore data, {0} tries remaining
     ///
     /// This could be caused by an invalid password or share set.
-    RestoreFailed,
+    RestoreFailed(u32),
     /// Restore request failed with MISSING status,
     ///
     /// This could mean either the data was never backed-up or we ran out of attempts to restore
     /// it.
     DataMissing,
+    /// Operation cancelled by caller
+    Cancelled,
+    /// Network changed since the connections were made
+    ///
+    /// The connections are unusable, so the operation has to be run again with
+    /// new ones rather than retried on them.
+    NetworkChanged,
+    /// Share set was backed up to different enclaves
+    ///
+    /// Restoring it from these ones requires allowing enclave migration.
+    EnvironmentMismatch,
+    /// Password strengthening failed: {0}
+    ///
+    /// Either the strengthener's parameters are invalid, or they aren't the
+    /// ones the share set was backed up with.
+    Strengthening(String),
+    /// Only some of the enclaves accepted the new backup
+    ///
+    /// `accepted` says which ones did, in the order of the setup's server IDs.
+    /// The others still hold their part of the previous backup, but neither
+    /// backup can be restored without all the parts, and there is no share
+    /// set for the new one: it is derived from every enclave's response, and
+    /// the ones that didn't accept sent none. Backing up again, over new
+    /// connections, gets every enclave back in step.
+    EnclaveDisagreement { accepted: Vec<bool> },
+    /// Invalid backup parameters: {0}
+    ///
+    /// The enclaves would reject the backup, so nothing was sent.
+    InvalidBackupParams(#[from] ValidationError),
+}
+
+/// The strings in [`Error::Protocol`] and [`Error::Strengthening`] only ever
+/// come from the messages of other errors, which don't include any share set,
+/// password, or response bytes.
+impl LogSafeDisplay for Error {}
+
+impl Error {
+    /// Whether the operation can be repeated without risk of it having taken
+    /// effect already.
+    ///
+    /// This matters most for restores, each of which uses up one of the tries
+    /// allowed by the backup: retrying after a failure that may have reached
+    /// the enclaves could spend a second one.
+    pub fn is_safe_to_retry(&self) -> bool {
+        match self {
+            Self::RequestNotSent(_) | Self::AttestationError(_) => true,
+            Self::Net(_)
+            | Self::Protocol(_)
+            | Self::RequestFailed(_)
+            | Self::RestoreFailed(_)
+            | Self::DataMissing
+            | Self::Cancelled
+            | Self::NetworkChanged
+            | Self::EnvironmentMismatch
+            | Self::Strengthening(_)
+            | Self::EnclaveDisagreement { .. }
+            | Self::InvalidBackupParams(_) => false,
+        }
+    }
+
+    /// The attestation failure behind this error, if that is what it is.
+    pub fn as_attestation_error(&self) -> Option<&attest::enclave::Error> {
+        match self {
+            Self::AttestationError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// The network failure behind this error, whether or not the request got
+    /// through before it.
+    pub fn as_net_error(&self) -> Option<&NetError> {
+        match self {
+            Self::Net(net) | Self::RequestNotSent(net) => Some(net),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status an enclave's server turned the connection down with;
+    /// see [`NetError::http_status`].
+    pub fn http_status(&self) -> Option<http::StatusCode> {
+        self.as_net_error().and_then(NetError::http_status)
+    }
+
+    /// Whether a request couldn't be sent because the connection had been
+    /// lost, as when the enclave closed it for idling.
+    ///
+    /// Like any [`Error::RequestNotSent`], this is safe to retry, though only
+    /// over new connections.
+    pub fn is_stale_connection(&self) -> bool {
+        match self {
+            Self::RequestNotSent(net) => {
+                net.category() == crate::svr::ErrorCategory::ConnectionLost
+            }
+            _ => false,
+        }
+    }
+}
+
+impl RetryLater for Error {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Net(net) | Self::RequestNotSent(net) => net.retry_after(),
+            Self::Protocol(_)
+            | Self::AttestationError(_)
+            | Self::RequestFailed(_)
+            | Self::RestoreFailed(_)
+            | Self::DataMissing
+            | Self::Cancelled
+            | Self::NetworkChanged
+            | Self::EnvironmentMismatch
+            | Self::Strengthening(_)
+            | Self::EnclaveDisagreement { .. }
+            | Self::InvalidBackupParams(_) => None,
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        match self {
+            Self::Net(net) | Self::RequestNotSent(net) => net.is_permanent(),
+            Self::Protocol(_)
+            | Self::AttestationError(_)
+            | Self::RequestFailed(_)
+            | Self::RestoreFailed(_)
+            | Self::DataMissing
+            | Self::Cancelled
+            | Self::NetworkChanged
+            | Self::EnvironmentMismatch
+            | Self::Strengthening(_)
+            | Self::EnclaveDisagreement { .. }
+            | Self::InvalidBackupParams(_) => false,
+        }
+    }
 }
 
 impl From<DeserializeError> for Error {
@@ -155,12 +274,15 @@ impl From<attest::enclave::Error> for Error {
 
 impl From<libsignal_svr3::Error> for Error {
     fn from(err: libsignal_svr3::Error) -> Self {
-        use libsignal_svr3::{Error as LogicError, PPSSError};
+        use libsignal_svr3::Error as LogicError;
         match err {
-            LogicError::Ppss(PPSSError::InvalidCommitment) => Self::RestoreFailed,
+            LogicError::RestoreFailed(tries_remaining) => Self::RestoreFailed(tries_remaining),
             LogicError::BadResponseStatus(libsignal_svr3::ErrorStatus::Missing) => {
                 Self::DataMissing
             }
+            LogicError::Argon2(_)
+            | LogicError::StrengthenerMismatch
+            | LogicError::StrengtheningOutOfRange => Self::Strengthening(err.to_string()),
             LogicError::Oprf(_)
             | LogicError::Ppss(_)
             | LogicError::BadData
@@ -177,120 +299,2475 @@ impl From<super::svr::Error> for Error {
             SvrError::Net(inner) => Self::Net(inner),
             SvrError::Protocol => Self::Protocol("General SVR protocol error".to_string()),
             SvrError::AttestationError(inner) => Self::AttestationError(inner),
+            SvrError::NetworkChanged => Self::NetworkChanged,
+            SvrError::Cancelled => Self::Cancelled,
         }
     }
 }
 
 impl From<AttestedConnectionError> for Error {
     fn from(err: AttestedConnectionError) -> Self {
-        Self::from(super::svr::Error::from(err))
+        match err {
+            AttestedConnectionError::SendFailed(inner) => Self::RequestNotSent(inner),
+            err => Self::from(super::svr::Error::from(err)),
+        }
+    }
+}
+
+/// Runs `attempt` again as long as it fails with an error that is
+/// [safe to retry](Error::is_safe_to_retry), up to `max_attempts` times in
+/// total.
+///
+/// A failed attempt leaves its connections unusable, so each call to `attempt`
+/// should make new ones.
+pub async fn retry_if_not_sent<T, F, Fut>(
+    max_attempts: NonZeroU32,
+    mut attempt: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut attempts_left = max_attempts.get();
+    loop {
+        attempts_left -= 1;
+        match attempt().await {
+            Err(err) if attempts_left > 0 && err.is_safe_to_retry() => {
+                log::info!("retrying SVR3 operation: {err}");
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends each request over the matching connection and collects the responses.
+///
+/// Every exchange is run to completion even if another one fails, so that the
+/// result only reports [`Error::RequestNotSent`] if none of the enclaves can
+/// have processed its request.
+async fn run_interactions<C: AttestedConnectionLike>(
+    connections: &mut [C],
+    requests: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let results = exchange_all(connections, requests).await;
+    collect_responses(results).map_err(|e| check_network_change(connections, e))
+}
+
+/// Like [`run_interactions`], but leaves the result of each exchange to the
+/// caller.
+async fn exchange_all<C: AttestedConnectionLike>(
+    connections: &mut [C],
+    requests: &[Vec<u8>],
+) -> Vec<Result<NextOrClose<Vec<u8>>, AttestedConnectionError>> {
+    join_all(
+        connections
+            .iter_mut()
+            .zip(requests)
+            .map(|(connection, request)| run_attested_interaction(connection, request)),
+    )
+    .await
+}
+
+/// Reports a network failure as [`Error::NetworkChanged`] if the network
+/// changed since any of `connections` was made, since they can't recover from
+/// that.
+fn check_network_change<C: AttestedConnectionLike>(connections: &[C], error: Error) -> Error {
+    match error {
+        Error::Net(NetError::RateLimited { .. } | NetError::ClientDeprecated) => error,
+        Error::Net(_) if connections.iter().any(|c| c.network_changed()) => Error::NetworkChanged,
+        error => error,
+    }
+}
+
+fn collect_responses(
+    results: Vec<Result<NextOrClose<Vec<u8>>, AttestedConnectionError>>,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let none_sent = results
+        .iter()
+        .all(|result| matches!(result, Err(AttestedConnectionError::SendFailed(_))));
+    results
+        .into_iter()
+        .map(|result| match result {
+            Ok(next_or_close) => Ok(next_or_close.next_or_close_reason(NetError::Failure)?),
+            // Another enclave may have handled its part of the operation.
+            Err(AttestedConnectionError::SendFailed(inner)) if !none_sent => Err(Error::Net(inner)),
+            Err(err) => Err(err.into()),
+        })
+        .collect()
+}
+
+/// The requests for a backup to the enclaves of a [`PpssSetup`], made by
+/// [`prepare_backup_request`].
+pub struct BackupRequest<'a> {
+    backup: Backup<'a>,
+    environment: [u8; 32],
+}
+
+impl BackupRequest<'_> {
+    /// One request for each of the setup's enclaves, in the same order.
+    pub fn requests(&self) -> &[Vec<u8>] {
+        &self.backup.requests
+    }
+}
+
+/// The offline first half of [`PpssOps::backup`], which makes the requests to
+/// send to `setup`'s enclaves.
+///
+/// Fails with [`Error::InvalidBackupParams`] if `max_tries` is outside the
+/// range the enclaves allow; see [`PpssSetup::validate_backup_params`].
+pub fn prepare_backup_request<'a>(
+    setup: &(impl PpssSetup + ?Sized),
+    password: &'a str,
+    secret: [u8; 32],
+    max_tries: NonZeroU32,
+    strengthener: Option<&dyn PasswordStrengthener>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<BackupRequest<'a>, Error> {
+    setup.validate_backup_params(max_tries)?;
+    let backup = Backup::new_with_strengthener(
+        setup.server_ids().as_ref(),
+        password,
+        secret,
+        max_tries,
+        strengthener,
+        rng,
+    )?;
+    Ok(BackupRequest {
+        backup,
+        environment: environment_hash(setup.mr_enclaves()),
+    })
+}
+
+/// The offline second half of [`PpssOps::backup`], which makes the share set
+/// from the enclaves' `responses`, in the order of the requests.
+pub fn parse_backup_response(
+    request: BackupRequest<'_>,
+    responses: &[Vec<u8>],
+    rng: &mut impl CryptoRngCore,
+) -> Result<OpaqueMaskedShareSet, Error> {
+    let BackupRequest {
+        backup,
+        environment,
+    } = request;
+    Ok(backup.finalize_with_environment(rng, responses, environment)?)
+}
+
+/// The requests for a restore from the enclaves of a [`PpssSetup`], made by
+/// [`prepare_restore_request`].
+pub struct RestoreRequest<'a> {
+    restore: Restore<'a>,
+}
+
+impl RestoreRequest<'_> {
+    /// One request for each of the share set's enclaves, in the same order.
+    pub fn requests(&self) -> &[Vec<u8>] {
+        &self.restore.requests
+    }
+}
+
+/// The offline first half of [`PpssOps::restore`], which checks `share_set`
+/// against `setup` and makes the requests to send to its enclaves.
+pub fn prepare_restore_request<'a>(
+    setup: &(impl PpssSetup + ?Sized),
+    password: &'a str,
+    share_set: OpaqueMaskedShareSet,
+    allow_enclave_migration: bool,
+    strengthener: Option<&dyn PasswordStrengthener>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<RestoreRequest<'a>, Error> {
+    check_environment(
+        &share_set,
+        &environment_hash(setup.mr_enclaves()),
+        allow_enclave_migration,
+    )?;
+    let restore = Restore::from_share_set(password, share_set, strengthener, rng)?;
+    Ok(RestoreRequest { restore })
+}
+
+/// The offline second half of [`PpssOps::restore`], which recovers the secret
+/// from the enclaves' `responses`, in the order of the requests.
+pub fn parse_restore_response(
+    request: RestoreRequest<'_>,
+    responses: &[Vec<u8>],
+) -> Result<[u8; 32], Error> {
+    Ok(request.restore.finalize(responses)?)
+}
+
+/// Like [`prepare_backup_request`], but any strengthening of the password runs
+/// on the blocking thread pool, where its memory-hard hashing doesn't hold up
+/// other tasks.
+async fn strengthen_and_prepare_backup<'a>(
+    setup: &(impl PpssSetup + ?Sized),
+    password: &'a str,
+    secret: [u8; 32],
+    max_tries: NonZeroU32,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut (impl CryptoRngCore + Send),
+) -> Result<BackupRequest<'a>, Error> {
+    let Some(strengthener) = strengthener else {
+        return prepare_backup_request(setup, password, secret, max_tries, None, rng);
+    };
+    setup.validate_backup_params(max_tries)?;
+    let strengthening = Strengthening::generate(strengthener.params(), rng)?;
+    let password = strengthen(strengthening, Some(strengthener), password).await?;
+    let backup = Backup::new_strengthened(
+        setup.server_ids().as_ref(),
+        password,
+        secret,
+        max_tries,
+        rng,
+    )?;
+    Ok(BackupRequest {
+        backup,
+        environment: environment_hash(setup.mr_enclaves()),
+    })
+}
+
+/// Like [`prepare_restore_request`], but any strengthening of the password
+/// runs on the blocking thread pool, as for [`strengthen_and_prepare_backup`].
+async fn strengthen_and_prepare_restore<'a>(
+    setup: &(impl PpssSetup + ?Sized),
+    password: &'a str,
+    share_set: OpaqueMaskedShareSet,
+    allow_enclave_migration: bool,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut (impl CryptoRngCore + Send),
+) -> Result<RestoreRequest<'a>, Error> {
+    let Some(strengthening) = share_set.strengthening().copied() else {
+        return prepare_restore_request(
+            setup,
+            password,
+            share_set,
+            allow_enclave_migration,
+            None,
+            rng,
+        );
+    };
+    check_environment(
+        &share_set,
+        &environment_hash(setup.mr_enclaves()),
+        allow_enclave_migration,
+    )?;
+    let password = strengthen(strengthening, strengthener, password).await?;
+    let restore = Restore::from_share_set_strengthened(password, share_set, rng)?;
+    Ok(RestoreRequest { restore })
+}
+
+/// Runs [`Strengthening::apply`] on the blocking thread pool.
+async fn strengthen(
+    strengthening: Strengthening,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    password: &str,
+) -> Result<StrengthenedPassword, Error> {
+    let password = Zeroizing::new(password.as_bytes().to_vec());
+    let strengthened = tokio::task::spawn_blocking(move || {
+        strengthening.apply(strengthener.as_deref(), &password)
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+    Ok(strengthened)
+}
+
+/// Checks `password` against `share_set`, then backs up `new_secret` in its
+/// place; see [`PpssOps::rotate_secret`].
+#[allow(clippy::too_many_arguments)]
+async fn rotate_secret_over<C: AttestedConnectionLike>(
+    setup: &(impl PpssSetup + ?Sized),
+    connections: &mut [C],
+    share_set: OpaqueMaskedShareSet,
+    password: &str,
+    new_secret: [u8; 32],
+    max_tries: NonZeroU32,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<OpaqueMaskedShareSet, Error> {
+    // Checked up front, so that a backup bound to be rejected doesn't use up
+    // a restore try first.
+    setup.validate_backup_params(max_tries)?;
+    // Nothing is replaced unless the password restores the current backup.
+    let _old_secret = restore_over(
+        setup,
+        connections,
+        share_set,
+        password,
+        strengthener.clone(),
+        rng,
+    )
+    .await?;
+    replace_backup_over(
+        setup,
+        connections,
+        password,
+        new_secret,
+        max_tries,
+        strengthener,
+        rng,
+    )
+    .await
+}
+
+/// Backs up `new_secret` unless `stored` was backed up the same way; see
+/// [`PpssOps::backup_if_changed`].
+#[allow(clippy::too_many_arguments)]
+async fn backup_if_changed_over<C: AttestedConnectionLike>(
+    setup: &(impl PpssSetup + ?Sized),
+    connections: &mut [C],
+    stored: Option<&StoredShareSet>,
+    password: &str,
+    new_secret: [u8; 32],
+    max_tries: NonZeroU32,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<BackupIfChangedResult, Error> {
+    setup.validate_backup_params(max_tries)?;
+    let unchanged = stored.is_some_and(|stored| {
+        stored.share_set.strengthening().map(|s| s.params)
+            == strengthener.as_ref().map(|s| s.params())
+            && stored.was_backed_up_with(password, &new_secret, max_tries)
+    });
+    if unchanged {
+        return Ok(BackupIfChangedResult::Unchanged);
     }
+    replace_backup_over(
+        setup,
+        connections,
+        password,
+        new_secret,
+        max_tries,
+        strengthener,
+        rng,
+    )
+    .await
+    .map(BackupIfChangedResult::Updated)
+}
+
+/// Restores from `share_set` over `connections`, which can be used for
+/// another request afterwards.
+async fn restore_over<C: AttestedConnectionLike>(
+    setup: &(impl PpssSetup + ?Sized),
+    connections: &mut [C],
+    share_set: OpaqueMaskedShareSet,
+    password: &str,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<[u8; 32], Error> {
+    let restore =
+        strengthen_and_prepare_restore(setup, password, share_set, false, strengthener, rng)
+            .await?;
+    let responses = run_interactions(connections, restore.requests()).await?;
+    parse_restore_response(restore, &responses)
+}
+
+/// Backs up `new_secret` over `connections`, in place of whatever the
+/// enclaves hold, failing with [`Error::EnclaveDisagreement`] if only some of
+/// them accept it.
+async fn replace_backup_over<C: AttestedConnectionLike>(
+    setup: &(impl PpssSetup + ?Sized),
+    connections: &mut [C],
+    password: &str,
+    new_secret: [u8; 32],
+    max_tries: NonZeroU32,
+    strengthener: Option<Arc<dyn PasswordStrengthener>>,
+    rng: &mut impl CryptoRngCore,
+) -> Result<OpaqueMaskedShareSet, Error> {
+    let backup =
+        strengthen_and_prepare_backup(setup, password, new_secret, max_tries, strengthener, rng)
+            .await?;
+    let results = exchange_all(connections, backup.requests()).await;
+    let accepted: Vec<bool> = results
+        .iter()
+        .map(|result| {
+            matches!(result, Ok(NextOrClose::Next(response)) if Backup::is_accepted(response))
+        })
+        .collect();
+    if accepted.contains(&true) && accepted.contains(&false) {
+        return Err(Error::EnclaveDisagreement { accepted });
+    }
+    let responses = collect_responses(results).map_err(|e| check_network_change(connections, e))?;
+    parse_backup_response(backup, &responses, rng)
+}
+
+/// What [`PpssOps::backup_if_changed`] did.
+#[derive(Debug)]
+pub enum BackupIfChangedResult {
+    /// The secret was already backed up; nothing was written.
+    Unchanged,
+    /// The secret was backed up anew, with this share set, which was also
+    /// stored.
+    Updated(OpaqueMaskedShareSet),
+    /// The secret was backed up anew, with this share set, but storing it
+    /// failed, so the caller has to keep it some other way.
+    NotStored(OpaqueMaskedShareSet, std::io::Error),
 }
 
+/// The SVR3 operations on the enclaves of a [`PpssSetup`].
+///
+/// The operations only read the setup, and each one uses up the connections
+/// it is given. Operations for different accounts can therefore run
+/// concurrently against one setup, over connections made through clones of a
+/// single [`TcpSslTransportConnector`](crate::infra::TcpSslTransportConnector)
+/// that share its resolver.
 #[async_trait]
 pub trait PpssOps: PpssSetup {
+    /// Backs up `secret`, protected by `password`.
+    ///
+    /// With a `strengthener`, such as an [`Argon2Strengthener`], the password
+    /// is run through it first, and the returned share set records how, for
+    /// [`Self::restore`] to do the same. The strengthening runs on the
+    /// runtime's blocking thread pool rather than in the calling task.
     async fn backup(
+        &self,
         connections: Self::Connections,
         password: &str,
         secret: [u8; 32],
         max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<OpaqueMaskedShareSet, Error>;
 
+    /// Restores the secret backed up in `share_set`.
+    ///
+    /// Fails with [`Error::EnvironmentMismatch`] if `share_set` was backed up
+    /// to different enclaves than this setup's, unless
+    /// `allow_enclave_migration` is set. That is only meant for moving backups
+    /// to new enclaves, which restores from the old ones on purpose.
+    ///
+    /// The password is strengthened the way the share set records, with
+    /// `strengthener` if one is given. It has to have the recorded parameters,
+    /// or the restore fails with [`Error::Strengthening`] before using up a
+    /// try.
     async fn restore(
+        &self,
         connections: Self::Connections,
         password: &str,
         share_set: OpaqueMaskedShareSet,
+        allow_enclave_migration: bool,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<[u8; 32], Error>;
+
+    /// Removes the stored data from all the enclaves.
+    ///
+    /// Succeeds even if nothing had been backed up.
+    async fn remove(&self, connections: Self::Connections) -> Result<(), Error>;
+
+    /// Replaces the secret backed up in `share_set` with `new_secret`,
+    /// returning the share set for the new backup.
+    ///
+    /// The password is checked first, by restoring the current backup over
+    /// the same connections, and nothing is replaced if that fails. Like any
+    /// restore, a wrong password uses up a try. The new backup is protected by
+    /// the same password, strengthened with `strengthener` if one is given.
+    ///
+    /// If only some of the enclaves accept the new backup, fails with
+    /// [`Error::EnclaveDisagreement`]. If none do, the current backup is left
+    /// as it was.
+    #[allow(clippy::too_many_arguments)]
+    async fn rotate_secret(
+        &self,
+        connections: Self::Connections,
+        share_set: OpaqueMaskedShareSet,
+        password: &str,
+        new_secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, Error>;
+
+    /// Returns the number of restore attempts left before the data is gone.
+    ///
+    /// Fails with [`Error::DataMissing`] if nothing is backed up, including
+    /// when the attempts have already run out.
+    async fn query(&self, connections: Self::Connections) -> Result<u32, Error>;
+
+    /// Backs up `new_secret` for `uid` unless the backup kept in
+    /// `share_set_store` was made with the same arguments, so that backing up
+    /// again doesn't write anything or replace the share set needlessly.
+    ///
+    /// The comparison is against the [commitment](StoredShareSet) stored with
+    /// the share set, in constant time, so it doesn't ask the enclaves or use
+    /// up a try; the connections are only used if something changed. It also
+    /// can't tell whether the enclaves still hold the backup, which
+    /// [`PpssOps::query`] can.
+    ///
+    /// A new share set is put in `share_set_store` as well as returned. The
+    /// store is left as it was if that fails, since the backup was made all
+    /// the same.
+    #[allow(clippy::too_many_arguments)]
+    async fn backup_if_changed(
+        &self,
+        uid: Uid,
+        connections: Self::Connections,
+        password: &str,
+        new_secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        share_set_store: &mut (impl ShareSetStore + Send),
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<BackupIfChangedResult, Error>;
 }
 
 #[async_trait]
-impl<Env: PpssSetup> PpssOps for Env {
+impl<Env: PpssSetup + Sync> PpssOps for Env {
     async fn backup(
+        &self,
         connections: Self::Connections,
         password: &str,
         secret: [u8; 32],
         max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<OpaqueMaskedShareSet, Error> {
-        let server_ids = Self::server_ids();
-        let backup = Backup::new(server_ids.as_ref(), password, secret, max_tries, rng)?;
         let mut connections = connections.into_connections();
-        let futures = connections
-            .as_mut()
-            .iter_mut()
-            .zip(&backup.requests)
-            .map(|(connection, request)| run_attested_interaction(connection, request));
-        let result = try_join_all(futures).await?;
-        let responses = result
-            .into_iter()
-            .map(|next_or_close| next_or_close.next_or(NetError::Failure))
-            .collect::<Result<Vec<_>, _>>()?;
-        let share_set = backup.finalize(rng, &responses)?;
-        Ok(OpaqueMaskedShareSet::new(share_set))
+        let recorder = RequestRecorder::start("backup", connections.as_ref());
+        let result = async {
+            let request =
+                strengthen_and_prepare_backup(self, password, secret, max_tries, strengthener, rng)
+                    .await?;
+            let responses = run_interactions(connections.as_mut(), request.requests()).await?;
+            parse_backup_response(request, &responses, rng)
+        }
+        .await;
+        recorder.finish(self.request_logger(), &result);
+        result
     }
 
     async fn restore(
+        &self,
         connections: Self::Connections,
         password: &str,
         share_set: OpaqueMaskedShareSet,
+        allow_enclave_migration: bool,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<[u8; 32], Error> {
-        let restore = Restore::new(password, share_set.into_inner(), rng)?;
         let mut connections = connections.into_connections();
-        let futures = connections
-            .as_mut()
-            .iter_mut()
-            .zip(&restore.requests)
-            .map(|(connection, request)| run_attested_interaction(connection, request));
-        let result = try_join_all(futures).await?;
-        let responses = result
-            .into_iter()
-            .map(|next_or_close| next_or_close.next_or(NetError::Failure))
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(restore.finalize(&responses)?)
+        let recorder = RequestRecorder::start("restore", connections.as_ref());
+        let result = async {
+            let request = strengthen_and_prepare_restore(
+                self,
+                password,
+                share_set,
+                allow_enclave_migration,
+                strengthener,
+                rng,
+            )
+            .await?;
+            let responses = run_interactions(connections.as_mut(), request.requests()).await?;
+            parse_restore_response(request, &responses)
+        }
+        .await;
+        recorder.finish(self.request_logger(), &result);
+        result
+    }
+
+    async fn remove(&self, connections: Self::Connections) -> Result<(), Error> {
+        let remove = Remove::new(self.server_ids().as_ref());
+        let mut connections = connections.into_connections();
+        let recorder = RequestRecorder::start("remove", connections.as_ref());
+        let result = run_interactions(connections.as_mut(), &remove.requests)
+            .await
+            .and_then(|responses| Ok(remove.finalize(&responses)?));
+        recorder.finish(self.request_logger(), &result);
+        result
+    }
+
+    async fn rotate_secret(
+        &self,
+        connections: Self::Connections,
+        share_set: OpaqueMaskedShareSet,
+        password: &str,
+        new_secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, Error> {
+        let mut connections = connections.into_connections();
+        let recorder = RequestRecorder::start("rotate", connections.as_ref());
+        let result = rotate_secret_over(
+            self,
+            connections.as_mut(),
+            share_set,
+            password,
+            new_secret,
+            max_tries,
+            strengthener,
+            rng,
+        )
+        .await;
+        recorder.finish(self.request_logger(), &result);
+        result
+    }
+
+    async fn query(&self, connections: Self::Connections) -> Result<u32, Error> {
+        let query = Query::new(self.server_ids().as_ref());
+        let mut connections = connections.into_connections();
+        let responses = run_interactions(connections.as_mut(), &query.requests).await?;
+        Ok(query.finalize(&responses)?)
+    }
+
+    async fn backup_if_changed(
+        &self,
+        uid: Uid,
+        connections: Self::Connections,
+        password: &str,
+        new_secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        share_set_store: &mut (impl ShareSetStore + Send),
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<BackupIfChangedResult, Error> {
+        let mut connections = connections.into_connections();
+        let recorder = RequestRecorder::start("backup_if_changed", connections.as_ref());
+        let result = backup_if_changed_over(
+            self,
+            connections.as_mut(),
+            share_set_store.get(&uid),
+            password,
+            new_secret,
+            max_tries,
+            strengthener,
+            rng,
+        )
+        .await;
+        recorder.finish(self.request_logger(), &result);
+        match result {
+            Ok(BackupIfChangedResult::Updated(share_set)) => {
+                let stored =
+                    StoredShareSet::new(share_set.clone(), password, &new_secret, max_tries);
+                Ok(match share_set_store.set(uid, stored) {
+                    Ok(()) => BackupIfChangedResult::Updated(share_set),
+                    Err(e) => BackupIfChangedResult::NotStored(share_set, e),
+                })
+            }
+            result => result,
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+
+    use assert_matches::assert_matches;
+    use libsignal_svr3::test_support::{oprf_output, InMemorySvr3Server, Uid};
+    use libsignal_svr3::{StrengthenerParams, Strengthening};
+    use nonzero_ext::nonzero;
+    use rand::rngs::OsRng;
+    use rand::SeedableRng as _;
+    use rand_chacha::ChaCha20Rng;
+    use serde::{Deserialize, Serialize};
+    use tokio::io::DuplexStream;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_util::sync::CancellationToken;
+
+    use super::blocking::{recover_stale_connections, BlockingError};
+    use super::request_log::{CapturingRequestLogger, RecoveryEvent};
     use super::*;
+    use crate::auth::Auth;
+    use crate::enclave::{EnclaveEndpoint, EnclaveEndpointConnection, Nitro, Sgx, Svr3Flavor};
+    use crate::infra::connection_manager::{RouteAttemptError, RouteAttempts, RouteFailure};
+    use crate::infra::correlation::CorrelationId;
+    use crate::infra::dns::testutil::{CountingDnsLookup, FakeDnsLookup};
+    use crate::infra::dns::{DnsResolver, LookupResult};
+    use crate::infra::network_state::NetworkState;
+    use crate::infra::ws::testutil::{
+        fake_websocket, run_attested_server, websocket_test_client, AttestedServerOutput,
+        FakeAttestedConnection, NeverConnects,
+    };
+    use crate::infra::ws::{AttestedConnection, TrafficMeter};
+    use crate::infra::{AsyncDuplexStream, TcpConnector as _, TcpSslTransportConnector};
+    use crate::proto::chat_websocket::WebSocketRequestMessage;
+    use crate::svr::SvrConnection;
+    use crate::svr3::traffic::{EnclaveTraffic, EnclaveTrafficMeter};
+    use crate::utils::cancellable;
 
-    fn new_empty_share_set() -> OpaqueMaskedShareSet {
-        OpaqueMaskedShareSet {
-            inner: SerializableMaskedShareSet {
-                server_ids: vec![],
-                masked_shares: vec![],
-                commitment: [0; 32],
-            },
+    /// A share set as written by format version 0: the secret `[42; 32]`
+    /// backed up with the password `"password"` to servers 1 and 2, whose OPRF
+    /// keys are `[1; 32]` and `[2; 32]`.
+    ///
+    /// Clients keep serialized share sets around across upgrades, so this must
+    /// stay restorable. Add a fixture for each new format version rather than
+    /// changing this one.
+    const SHARE_SET_V0: &[u8] = include_bytes!("../tests/data/masked_share_set_v0.bin");
+
+    #[test]
+    fn share_set_v0_fixture_is_restorable() {
+        let share_set = OpaqueMaskedShareSet::deserialize(SHARE_SET_V0).expect("can deserialize");
+        assert_eq!(share_set.serialize().expect("can serialize"), SHARE_SET_V0);
+        assert_eq!(share_set.environment(), None);
+
+        let oprf_outputs = [
+            oprf_output([1; 32], 1, "password"),
+            oprf_output([2; 32], 2, "password"),
+        ];
+        assert_eq!(
+            libsignal_svr3::restore_from_oprf_outputs("password", share_set.clone(), &oprf_outputs)
+                .expect("can restore"),
+            [42; 32]
+        );
+
+        let MaskedShareSet {
+            server_ids,
+            strengthening,
+            ..
+        } = share_set.into_inner();
+        assert_eq!(server_ids, [1, 2]);
+        assert_eq!(strengthening, None);
+    }
+
+    /// A share set as written by format version 1, with placeholder contents:
+    /// the masked shares `[0x11; 32]` and `[0x22; 32]` for servers 1 and 2,
+    /// the commitment `[0x33; 32]` and the environment `[0x44; 32]`.
+    const SHARE_SET_V1: &[u8] = include_bytes!("../tests/data/masked_share_set_v1.bin");
+
+    #[test]
+    fn share_set_v1_fixture_is_readable() {
+        let share_set = OpaqueMaskedShareSet::deserialize(SHARE_SET_V1).expect("can deserialize");
+        assert_eq!(share_set.serialize().expect("can serialize"), SHARE_SET_V1);
+        assert_eq!(share_set.environment(), Some(&[0x44; 32]));
+
+        let MaskedShareSet {
+            server_ids,
+            masked_shares,
+            commitment,
+            strengthening,
+        } = share_set.into_inner();
+        assert_eq!(server_ids, [1, 2]);
+        assert_eq!(masked_shares, [[0x11; 32], [0x22; 32]]);
+        assert_eq!(commitment, [0x33; 32]);
+        assert_eq!(strengthening, None);
+    }
+
+    /// Like [`SHARE_SET_V1`], followed by Argon2id strengthening with 64 MiB,
+    /// 3 iterations, 1 lane and the salt `[0x55; 16]`.
+    const SHARE_SET_V2: &[u8] = include_bytes!("../tests/data/masked_share_set_v2.bin");
+
+    #[test]
+    fn share_set_v2_fixture_is_readable() {
+        let share_set = OpaqueMaskedShareSet::deserialize(SHARE_SET_V2).expect("can deserialize");
+        assert_eq!(share_set.serialize().expect("can serialize"), SHARE_SET_V2);
+        assert_eq!(share_set.environment(), Some(&[0x44; 32]));
+
+        let MaskedShareSet {
+            server_ids,
+            masked_shares,
+            commitment,
+            strengthening,
+        } = share_set.into_inner();
+        assert_eq!(server_ids, [1, 2]);
+        assert_eq!(masked_shares, [[0x11; 32], [0x22; 32]]);
+        assert_eq!(commitment, [0x33; 32]);
+        assert_eq!(
+            strengthening,
+            Some(Strengthening {
+                params: StrengthenerParams::Argon2id(Argon2Strengthener {
+                    memory_kb: 64 * 1024,
+                    iterations: 3,
+                    parallelism: 1,
+                }),
+                salt: [0x55; 16],
+            })
+        );
+    }
+
+    #[test]
+    fn serialized_share_set_restores_against_in_memory_servers() {
+        const UID: Uid = [1; 16];
+        const PASSWORD: &str = "password";
+        const SECRET: [u8; 32] = [42; 32];
+        let mut servers = [InMemorySvr3Server::new(), InMemorySvr3Server::new()];
+        let mut round_trip = |requests: &[Vec<u8>]| -> Vec<Vec<u8>> {
+            servers
+                .iter_mut()
+                .zip(requests)
+                .map(|(server, request)| {
+                    server.handle_request(UID, request).expect("valid request")
+                })
+                .collect()
+        };
+
+        let backup = Backup::new(&[1, 2], PASSWORD, SECRET, nonzero!(10u32), &mut OsRng)
+            .expect("can create backup");
+        let responses = round_trip(&backup.requests);
+        let share_set = backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup");
+        let serialized = OpaqueMaskedShareSet::new(share_set, [0x44; 32])
+            .serialize()
+            .expect("can serialize");
+
+        // It is written in the format of the fixture, which has the same
+        // server IDs and environment.
+        let share_set = OpaqueMaskedShareSet::deserialize(&serialized).expect("can deserialize");
+        let fixture = OpaqueMaskedShareSet::deserialize(SHARE_SET_V1).expect("can deserialize");
+        assert_eq!(share_set.environment(), fixture.environment());
+        assert_eq!(
+            share_set.clone().into_inner().server_ids,
+            fixture.into_inner().server_ids
+        );
+
+        let restore =
+            Restore::new(PASSWORD, share_set.into_inner(), &mut OsRng).expect("can create restore");
+        let responses = round_trip(&restore.requests);
+        assert_eq!(restore.finalize(&responses).expect("can restore"), SECRET);
+    }
+
+    #[test]
+    fn backup_and_restore_without_network() {
+        const UID: Uid = [1; 16];
+        const SECRET: [u8; 32] = [42; 32];
+        let setup = crate::env::STAGING.svr3;
+        let mut rng = ChaCha20Rng::from_seed([7; 32]);
+        let mut servers = [InMemorySvr3Server::new(), InMemorySvr3Server::new()];
+        let mut round_trip = |requests: &[Vec<u8>]| -> Vec<Vec<u8>> {
+            servers
+                .iter_mut()
+                .zip(requests)
+                .map(|(server, request)| {
+                    server.handle_request(UID, request).expect("valid request")
+                })
+                .collect()
+        };
+
+        let request =
+            prepare_backup_request(&setup, "password", SECRET, nonzero!(10u32), None, &mut rng)
+                .expect("can prepare backup");
+        let responses = round_trip(request.requests());
+        let share_set =
+            parse_backup_response(request, &responses, &mut rng).expect("can finalize backup");
+        assert_eq!(
+            share_set.environment(),
+            Some(&environment_hash(setup.mr_enclaves()))
+        );
+        let serialized = share_set.serialize().expect("can serialize");
+
+        let restore = |password: &'static str| {
+            let share_set =
+                OpaqueMaskedShareSet::deserialize(&serialized).expect("can deserialize");
+            prepare_restore_request(&setup, password, share_set, false, None, &mut OsRng)
+                .expect("can prepare restore")
+        };
+        let request = restore("wrong password");
+        let responses = round_trip(request.requests());
+        assert_matches!(
+            parse_restore_response(request, &responses),
+            Err(Error::RestoreFailed(9))
+        );
+
+        let request = restore("password");
+        let responses = round_trip(request.requests());
+        assert_eq!(
+            parse_restore_response(request, &responses).expect("can restore"),
+            SECRET
+        );
+
+        // The environment is checked before any request is made.
+        let share_set = OpaqueMaskedShareSet::deserialize(&serialized).expect("can deserialize");
+        assert_matches!(
+            prepare_restore_request(
+                &crate::env::PROD.svr3,
+                "password",
+                share_set,
+                false,
+                None,
+                &mut OsRng
+            ),
+            Err(Error::EnvironmentMismatch)
+        );
+    }
+
+    /// Share sets generated from fixed inputs.
+    ///
+    /// Each vector is made with a `ChaCha20Rng` seeded with `rng_seed`, talking
+    /// to in-memory servers that use `oprf_keys` instead of random keys, and
+    /// records the [`environment_hash`] of `mr_enclaves`. `oprf_outputs` are
+    /// what each server's key gives for the password, enough to recover the
+    /// secret from the share set with
+    /// [`restore_from_oprf_outputs`](libsignal_svr3::restore_from_oprf_outputs).
+    /// A mismatch means share sets no longer come out byte-for-byte the same. If
+    /// that is intended, regenerate the vectors with
+    ///
+    /// ```text
+    /// REGENERATE_SHARE_SET_VECTORS=1 cargo test -p libsignal-net share_set_vectors
+    /// ```
+    const SHARE_SET_VECTORS: &str = "tests/data/share_set_vectors.json";
+
+    #[derive(Serialize, Deserialize)]
+    struct ShareSetVector {
+        description: String,
+        rng_seed: String,
+        oprf_keys: Vec<String>,
+        mr_enclaves: Vec<String>,
+        server_ids: Vec<u64>,
+        password: String,
+        secret: String,
+        max_tries: u32,
+        share_set: String,
+        oprf_outputs: Vec<String>,
+    }
+
+    fn decode_hex_32(hex: &str) -> [u8; 32] {
+        hex::decode(hex)
+            .expect("valid hex")
+            .try_into()
+            .expect("32 bytes")
+    }
+
+    #[test]
+    fn share_set_vectors() {
+        const UID: Uid = [1; 16];
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(SHARE_SET_VECTORS);
+        let json = std::fs::read_to_string(&path).expect("can read vectors");
+        let mut vectors: Vec<ShareSetVector> = serde_json::from_str(&json).expect("valid vectors");
+        let regenerate = std::env::var_os("REGENERATE_SHARE_SET_VECTORS").is_some();
+
+        for vector in &mut vectors {
+            let mut rng = ChaCha20Rng::from_seed(decode_hex_32(&vector.rng_seed));
+            let mut servers: Vec<_> = vector
+                .oprf_keys
+                .iter()
+                .map(|key| InMemorySvr3Server::with_fixed_oprf_key(decode_hex_32(key)))
+                .collect();
+            let mut round_trip = |requests: &[Vec<u8>]| -> Vec<Vec<u8>> {
+                servers
+                    .iter_mut()
+                    .zip(requests)
+                    .map(|(server, request)| {
+                        server.handle_request(UID, request).expect("valid request")
+                    })
+                    .collect()
+            };
+            let secret = decode_hex_32(&vector.secret);
+
+            let backup = Backup::new(
+                &vector.server_ids,
+                &vector.password,
+                secret,
+                vector.max_tries.try_into().expect("nonzero"),
+                &mut rng,
+            )
+            .expect("can create backup");
+            let responses = round_trip(&backup.requests);
+            let share_set = backup
+                .finalize(&mut rng, &responses)
+                .expect("can finalize backup");
+            let mr_enclaves: Vec<_> = vector
+                .mr_enclaves
+                .iter()
+                .map(|mr_enclave| hex::decode(mr_enclave).expect("valid hex"))
+                .collect();
+            let environment = environment_hash(mr_enclaves.iter().map(Vec::as_slice));
+            let serialized = hex::encode(
+                OpaqueMaskedShareSet::new(share_set, environment)
+                    .serialize()
+                    .expect("can serialize"),
+            );
+
+            let oprf_outputs: Vec<_> = vector
+                .oprf_keys
+                .iter()
+                .zip(&vector.server_ids)
+                .map(|(key, server_id)| {
+                    hex::encode(oprf_output(
+                        decode_hex_32(key),
+                        *server_id,
+                        &vector.password,
+                    ))
+                })
+                .collect();
+
+            if regenerate {
+                vector.share_set = serialized;
+                vector.oprf_outputs = oprf_outputs;
+                continue;
+            }
+            assert_eq!(serialized, vector.share_set, "{}", vector.description);
+            assert_eq!(oprf_outputs, vector.oprf_outputs, "{}", vector.description);
+
+            // The committed share set is still good for a restore.
+            let share_set = OpaqueMaskedShareSet::deserialize(
+                &hex::decode(&vector.share_set).expect("valid hex"),
+            )
+            .expect("can deserialize");
+            let oprf_outputs: Vec<[u8; 64]> = vector
+                .oprf_outputs
+                .iter()
+                .map(|output| {
+                    hex::decode(output)
+                        .expect("valid hex")
+                        .try_into()
+                        .expect("64 bytes")
+                })
+                .collect();
+            assert_eq!(
+                libsignal_svr3::restore_from_oprf_outputs(
+                    &vector.password,
+                    share_set.clone(),
+                    &oprf_outputs
+                )
+                .expect("can restore offline"),
+                secret,
+                "{}",
+                vector.description
+            );
+            let restore = Restore::new(&vector.password, share_set.into_inner(), &mut rng)
+                .expect("can create restore");
+            let responses = round_trip(&restore.requests);
+            assert_eq!(
+                restore.finalize(&responses).expect("can restore"),
+                secret,
+                "{}",
+                vector.description
+            );
+        }
+
+        if regenerate {
+            let json = serde_json::to_string_pretty(&vectors).expect("can serialize vectors");
+            std::fs::write(&path, json + "\n").expect("can write vectors");
         }
     }
 
     #[test]
-    fn serialized_share_set_has_version() {
-        let bytes = new_empty_share_set().serialize().expect("can serialize");
-        assert_eq!(MASKED_SHARE_SET_FORMAT, bytes[0]);
+    fn environment_hash_identifies_set_of_enclaves() {
+        let [sgx, nitro] = [&[1u8; 32][..], &[2u8; 48][..]];
+        assert_eq!(
+            environment_hash([sgx, nitro]),
+            environment_hash([nitro, sgx])
+        );
+        assert_eq!(
+            environment_hash([sgx, nitro]),
+            environment_hash([sgx, nitro, sgx])
+        );
+        assert_ne!(environment_hash([sgx, nitro]), environment_hash([sgx]));
+
+        let hash = |env: &crate::env::Svr3Env| environment_hash(env.mr_enclaves());
+        assert_ne!(
+            hash(&crate::env::STAGING.svr3),
+            hash(&crate::env::PROD.svr3)
+        );
+    }
+
+    fn share_set_with_environment(environment: Option<[u8; 32]>) -> OpaqueMaskedShareSet {
+        let inner = MaskedShareSet {
+            server_ids: vec![],
+            masked_shares: vec![],
+            commitment: [0; 32],
+            strengthening: None,
+        };
+        match environment {
+            Some(environment) => OpaqueMaskedShareSet::new(inner, environment),
+            None => OpaqueMaskedShareSet::without_environment(inner),
+        }
     }
 
     #[test]
-    fn deserialize_share_set_supported_version() {
-        let there = new_empty_share_set().serialize().expect("can serialize");
-        let and_back = OpaqueMaskedShareSet::deserialize(&there);
-        assert!(and_back.is_ok(), "Should be able to deserialize");
+    fn restore_requires_matching_environment() {
+        let share_set = share_set_with_environment(Some([1; 32]));
+        assert_matches!(check_environment(&share_set, &[1; 32], false), Ok(()));
+        assert_matches!(
+            check_environment(&share_set, &[2; 32], false),
+            Err(Error::EnvironmentMismatch)
+        );
     }
 
     #[test]
-    fn deserialize_share_set_bad_version() {
-        let there = {
-            let mut bytes = new_empty_share_set().serialize().expect("can serialize");
-            bytes[0] = 0xff;
-            bytes
+    fn enclave_migration_allows_other_environment() {
+        let share_set = share_set_with_environment(Some([1; 32]));
+        assert_matches!(check_environment(&share_set, &[2; 32], true), Ok(()));
+    }
+
+    #[test]
+    fn share_set_without_environment_is_restorable() {
+        let share_set = share_set_with_environment(None);
+        assert_matches!(check_environment(&share_set, &[2; 32], false), Ok(()));
+    }
+
+    #[test]
+    fn restore_failure_reports_tries_remaining() {
+        let err = Error::from(libsignal_svr3::Error::RestoreFailed(3));
+        assert_matches!(err, Error::RestoreFailed(3));
+        assert_eq!(
+            err.to_string(),
+            "Failure to restore data, 3 tries remaining"
+        );
+    }
+
+    #[test]
+    fn route_failures_survive_conversion() {
+        let attempts = RouteAttempts(vec![RouteAttemptError {
+            route_index: 0,
+            failure: RouteFailure::Error {
+                category: crate::svr::ErrorCategory::Network,
+                description: "DNS lookup failed".to_owned(),
+            },
+            duration: Duration::from_millis(5),
+        }]);
+        let err = crate::svr::Error::Net(NetError::AllRoutesFailed {
+            attempts: attempts.clone(),
+        });
+        assert_eq!(err.category(), crate::svr::ErrorCategory::Network);
+
+        let err = Error::from(err);
+        assert_eq!(
+            err.to_string(),
+            "Network error: All routes failed: route 0 failed after 5ms: DNS lookup failed"
+        );
+        assert_matches!(
+            err,
+            Error::Net(NetError::AllRoutesFailed { attempts: a }) if a == attempts
+        );
+    }
+
+    #[test]
+    fn rate_limits_report_retry_after() {
+        let rate_limited = NetError::RateLimited {
+            retry_after_seconds: 5,
         };
-        let and_back = OpaqueMaskedShareSet::deserialize(&there);
-        assert!(matches!(
-            and_back.expect_err("Unexpected deserialization success"),
-            DeserializeError::BadVersion(_),
+        assert_eq!(
+            Error::Net(rate_limited).retry_after(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(Error::Net(NetError::Failure).retry_after(), None);
+        assert_eq!(Error::DataMissing.retry_after(), None);
+    }
+
+    /// Cancels `token` after a short delay.
+    fn cancel_soon(token: &CancellationToken) {
+        let token = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            token.cancel();
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_while_connecting() {
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Sgx>::test_endpoint(8443),
+            Duration::from_secs(10),
+        );
+        let auth = Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+        let token = CancellationToken::new();
+        cancel_soon(&token);
+
+        let result = cancellable(&token, Error::Cancelled, async {
+            let _connection: SvrConnection<Sgx, DuplexStream> =
+                SvrConnection::connect(auth, &connection, NeverConnects).await?;
+            Ok(())
+        })
+        .await;
+        assert_matches!(result, Err(Error::Cancelled));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_connection_phase_of_backup_is_not_a_timeout() {
+        let connection = EnclaveEndpointConnection::new(
+            EnclaveEndpoint::<Sgx>::test_endpoint(8443),
+            Duration::from_secs(10),
+        );
+        let auth = Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+        let token = CancellationToken::new();
+        cancel_soon(&token);
+
+        // Laid out like the app-facing operations: the whole backup has a
+        // deadline, and connecting can be cancelled on its own.
+        let result = crate::utils::timeout(
+            Duration::from_secs(60),
+            Error::Net(NetError::Timeout),
+            async {
+                let backup = Backup::new(&[1], "password", [0; 32], nonzero!(10u32), &mut OsRng)?;
+                let connection: SvrConnection<Sgx, DuplexStream> = cancellable(
+                    &token,
+                    crate::svr::Error::Cancelled,
+                    SvrConnection::connect(auth, &connection, NeverConnects),
+                )
+                .await?;
+                let mut connections: [AttestedConnection<DuplexStream>; 1] = [connection.into()];
+                run_interactions(&mut connections, &backup.requests).await
+            },
+        )
+        .await;
+        assert_matches!(result, Err(Error::Cancelled));
+        assert!(!Error::Cancelled.is_safe_to_retry());
+    }
+
+    fn not_sent() -> Result<NextOrClose<Vec<u8>>, AttestedConnectionError> {
+        Err(AttestedConnectionError::SendFailed(NetError::Failure))
+    }
+
+    #[test]
+    fn request_is_only_unsent_if_no_enclave_can_have_it() {
+        let err = collect_responses(vec![not_sent(), not_sent()]).expect_err("nothing was sent");
+        assert_matches!(err, Error::RequestNotSent(NetError::Failure));
+        assert!(err.is_safe_to_retry());
+
+        // The first enclave may already have handled its request.
+        let err = collect_responses(vec![Ok(NextOrClose::Next(vec![])), not_sent()])
+            .expect_err("second request was not sent");
+        assert_matches!(err, Error::Net(NetError::Failure));
+        assert!(!err.is_safe_to_retry());
+
+        let err = collect_responses(vec![
+            not_sent(),
+            Err(AttestedConnectionError::Net(NetError::Failure)),
+        ])
+        .expect_err("second response was not received");
+        assert_matches!(err, Error::Net(NetError::Failure));
+        assert!(!err.is_safe_to_retry());
+    }
+
+    #[tokio::test]
+    async fn retries_only_while_safe_and_within_budget() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_if_not_sent(nonzero!(3u32), || {
+            attempts += 1;
+            async { Err(Error::RequestNotSent(NetError::Failure)) }
+        })
+        .await;
+        assert_matches!(result, Err(Error::RequestNotSent(_)));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_if_not_sent(nonzero!(3u32), || {
+            attempts += 1;
+            async { Err(Error::Net(NetError::Failure)) }
+        })
+        .await;
+        assert_matches!(result, Err(Error::Net(_)));
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result = retry_if_not_sent(nonzero!(3u32), || {
+            attempts += 1;
+            let result = if attempts == 1 {
+                Err(Error::RequestNotSent(NetError::Failure))
+            } else {
+                Ok(attempts)
+            };
+            async move { result }
+        })
+        .await;
+        assert_matches!(result, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn cancel_in_flight_request_closes_connection() {
+        let (server, client) = fake_websocket().await;
+        // The server never answers, so the request can only end by being cancelled.
+        let server = tokio::spawn(run_attested_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+            |_request| vec![],
         ));
+        let attested = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        let mut connection = SvrConnection::<Sgx, _>::new(attested, Default::default());
+
+        let token = CancellationToken::new();
+        cancel_soon(&token);
+        let result = cancellable(&token, Error::Cancelled, async move {
+            connection
+                .send_typed(WebSocketRequestMessage::default())
+                .await?;
+            let _: WebSocketRequestMessage = connection.recv_typed().await?;
+            Ok(())
+        })
+        .await;
+        assert_matches!(result, Err(Error::Cancelled));
+
+        // Dropping the connection hangs up on the server.
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server sees the connection close")
+            .expect("server did not panic");
+    }
+
+    /// An in-memory SVR3 server reached over fake attested connections.
+    pub(super) struct FakeEnclave {
+        server: InMemorySvr3Server,
+        /// Plaintext traffic seen since last checked, from the client's
+        /// point of view.
+        requests: u32,
+        bytes_from_client: u64,
+        bytes_to_client: u64,
+        /// If set, the number of further requests to answer before hanging up
+        /// on the next one instead.
+        hang_up_after: Option<u32>,
+    }
+
+    impl FakeEnclave {
+        pub(super) fn new() -> Arc<std::sync::Mutex<Self>> {
+            Arc::new(std::sync::Mutex::new(Self {
+                server: InMemorySvr3Server::new(),
+                requests: 0,
+                bytes_from_client: 0,
+                bytes_to_client: 0,
+                hang_up_after: None,
+            }))
+        }
+
+        /// Checks `traffic` against what the enclave saw, and starts over.
+        fn assert_matches_traffic(&mut self, traffic: &EnclaveTraffic) {
+            assert_eq!(traffic.requests_sent, self.requests);
+            assert_eq!(traffic.responses_received, self.requests);
+            assert_eq!(traffic.bytes_sent, self.bytes_from_client);
+            assert_eq!(traffic.bytes_received, self.bytes_to_client);
+            assert_eq!(traffic.request_millis.len(), self.requests as usize);
+            self.requests = 0;
+            self.bytes_from_client = 0;
+            self.bytes_to_client = 0;
+        }
+    }
+
+    /// Serves one attested connection to `enclave` for account `uid`.
+    async fn serve_fake_enclave(
+        websocket: WebSocketStream<impl AsyncDuplexStream>,
+        enclave: Arc<std::sync::Mutex<FakeEnclave>>,
+        uid: Uid,
+    ) {
+        run_attested_server(
+            websocket,
+            attest::sgx_session::testutil::private_key(),
+            move |request| {
+                let mut enclave = enclave.lock().expect("not poisoned");
+                match &mut enclave.hang_up_after {
+                    None => {}
+                    Some(0) => {
+                        enclave.hang_up_after = None;
+                        return vec![AttestedServerOutput::Close(None)];
+                    }
+                    Some(remaining) => *remaining -= 1,
+                }
+                let response = enclave
+                    .server
+                    .handle_request(uid, &request)
+                    .expect("valid request");
+                enclave.requests += 1;
+                enclave.bytes_from_client += request.len() as u64;
+                enclave.bytes_to_client += response.len() as u64;
+                vec![AttestedServerOutput::Message(response)]
+            },
+        )
+        .await
+    }
+
+    async fn attested_svr_connection<Flavor: Svr3Flavor, S: AsyncDuplexStream + 'static>(
+        websocket: WebSocketStream<S>,
+    ) -> SvrConnection<Flavor, S> {
+        let attested = AttestedConnection::connect(websocket_test_client(websocket), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        SvrConnection::new(attested)
+    }
+
+    async fn connect_to_fake_enclave<Flavor: Svr3Flavor>(
+        enclave: &Arc<std::sync::Mutex<FakeEnclave>>,
+        uid: Uid,
+    ) -> SvrConnection<Flavor, DuplexStream> {
+        let (server, client) = fake_websocket().await;
+        tokio::spawn(serve_fake_enclave(server, enclave.clone(), uid));
+        attested_svr_connection(client).await
+    }
+
+    /// Like [`connect_to_fake_enclave`], but over a local TCP connection to
+    /// [`FAKE_ENCLAVE_HOST`], made through `connector`.
+    async fn connect_to_fake_enclave_over_tcp<Flavor: Svr3Flavor>(
+        enclave: &Arc<std::sync::Mutex<FakeEnclave>>,
+        uid: Uid,
+        connector: &TcpSslTransportConnector,
+    ) -> SvrConnection<Flavor, TcpStream> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("can bind");
+        let port = listener.local_addr().expect("bound").port();
+        let enclave = enclave.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("can accept");
+            let websocket = tokio_tungstenite::accept_async(stream)
+                .await
+                .expect("websocket upgrade");
+            serve_fake_enclave(websocket, enclave, uid).await
+        });
+
+        let stream = connector
+            .connect_tcp(FAKE_ENCLAVE_HOST, port)
+            .await
+            .expect("can connect");
+        let (websocket, _response) =
+            tokio_tungstenite::client_async(format!("ws://{FAKE_ENCLAVE_HOST}/"), stream)
+                .await
+                .expect("websocket upgrade");
+        attested_svr_connection(websocket).await
+    }
+
+    const FAKE_ENCLAVE_HOST: &str = "svr3.test";
+
+    #[tokio::test]
+    async fn traffic_meters_match_fake_enclaves() {
+        const UID: Uid = [1; 16];
+        const SECRET: [u8; 32] = [42; 32];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let connect = move || async move {
+            let sgx = connect_to_fake_enclave::<Sgx>(&enclaves[0], UID).await;
+            let nitro = connect_to_fake_enclave::<Nitro>(&enclaves[1], UID).await;
+            let meters = [
+                EnclaveTrafficMeter::new(&sgx),
+                EnclaveTrafficMeter::new(&nitro),
+            ];
+            let connections: [AttestedConnection<DuplexStream>; 2] = [sgx.into(), nitro.into()];
+            (connections, meters)
+        };
+        let check_traffic = |meters: &[EnclaveTrafficMeter; 2]| {
+            for (meter, enclave) in meters.iter().zip(enclaves) {
+                enclave
+                    .lock()
+                    .expect("not poisoned")
+                    .assert_matches_traffic(&meter.read());
+            }
+            let names: Vec<_> = meters.iter().map(|meter| meter.read().enclave).collect();
+            assert_eq!(names, ["sgx", "nitro"]);
+        };
+
+        let (mut connections, meters) = connect().await;
+        let backup = Backup::new(&[1, 2], "password", SECRET, nonzero!(10u32), &mut OsRng)
+            .expect("can create backup");
+        let responses = run_interactions(&mut connections, &backup.requests)
+            .await
+            .expect("backup succeeds");
+        let share_set = backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup");
+        check_traffic(&meters);
+
+        // Restores exchange differently sized messages over new connections.
+        let (mut connections, meters) = connect().await;
+        let restore = Restore::new("password", share_set, &mut OsRng).expect("can create restore");
+        let responses = run_interactions(&mut connections, &restore.requests)
+            .await
+            .expect("restore succeeds");
+        assert_eq!(restore.finalize(&responses).expect("can restore"), SECRET);
+        check_traffic(&meters);
+    }
+
+    #[tokio::test]
+    async fn concurrent_restores_share_one_dns_lookup() {
+        const UIDS: [Uid; 2] = [[1; 16], [2; 16]];
+        let secret = |uid: Uid| [uid[0]; 32];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+
+        // Back up for both accounts directly, leaving only the restores to go
+        // over the network.
+        let share_sets = UIDS.map(|uid| {
+            let backup = Backup::new(
+                &[1, 2],
+                "password",
+                secret(uid),
+                nonzero!(10u32),
+                &mut OsRng,
+            )
+            .expect("can create backup");
+            let responses: Vec<_> = enclaves
+                .iter()
+                .zip(&backup.requests)
+                .map(|(enclave, request)| {
+                    let mut enclave = enclave.lock().expect("not poisoned");
+                    enclave
+                        .server
+                        .handle_request(uid, request)
+                        .expect("valid request")
+                })
+                .collect();
+            backup
+                .finalize(&mut OsRng, &responses)
+                .expect("can finalize backup")
+        });
+
+        let lookup = CountingDnsLookup {
+            answers: FakeDnsLookup(HashMap::from([(
+                FAKE_ENCLAVE_HOST,
+                Ok(LookupResult::new(vec![Ipv4Addr::LOCALHOST], vec![])),
+            )])),
+            ..Default::default()
+        };
+        let lookups = lookup.count.clone();
+        let resolver = DnsResolver::default()
+            .with_lookup(lookup)
+            .with_cache(Duration::from_secs(60));
+        let connector = TcpSslTransportConnector::new_with_shared_resolver(Arc::new(resolver));
+
+        let restore = |uid: Uid, share_set: MaskedShareSet| {
+            let connector = connector.clone();
+            async move {
+                let sgx = connect_to_fake_enclave_over_tcp::<Sgx>(&enclaves[0], uid, &connector);
+                let nitro =
+                    connect_to_fake_enclave_over_tcp::<Nitro>(&enclaves[1], uid, &connector);
+                let (sgx, nitro) = tokio::join!(sgx, nitro);
+                let mut connections: [AttestedConnection<TcpStream>; 2] =
+                    [sgx.into(), nitro.into()];
+                let restore =
+                    Restore::new("password", share_set, &mut OsRng).expect("can create restore");
+                let responses = run_interactions(&mut connections, &restore.requests)
+                    .await
+                    .expect("restore succeeds");
+                restore.finalize(&responses).expect("can restore")
+            }
+        };
+        let [first, second] = share_sets;
+        let restored = tokio::join!(restore(UIDS[0], first), restore(UIDS[1], second));
+        assert_eq!(<[_; 2]>::from(restored), UIDS.map(secret));
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn operations_are_reported_to_the_request_logger() {
+        const UID: Uid = [3; 16];
+        let correlation_id = CorrelationId::random();
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = LoggingFakeSvr3Setup::default();
+        let connect = || {
+            let [sgx, nitro] = scripted_connections_to(enclaves, UID);
+            let meters = [sgx.traffic_meter(), nitro.traffic_meter()];
+            // Tagging one of the connections is enough.
+            ([sgx, nitro.with_correlation_id(correlation_id)], meters)
+        };
+        // Plaintext bytes exchanged with the enclaves, as metered by the
+        // connections themselves.
+        let total_traffic = |meters: &[TrafficMeter; 2]| {
+            meters
+                .iter()
+                .map(TrafficMeter::read)
+                .fold((0, 0), |(sent, received), traffic| {
+                    (sent + traffic.bytes_sent, received + traffic.bytes_received)
+                })
+        };
+        let check_event = |operation: &str, outcome: &str, meters: &[TrafficMeter; 2]| {
+            let events = setup.0.take();
+            let [event] = &events[..] else {
+                panic!("expected one event, got {events:?}");
+            };
+            assert_eq!(event.operation, operation);
+            assert_eq!(event.outcome, outcome);
+            assert_eq!(event.uid, Some(UID));
+            assert_eq!(event.correlation_id, Some(correlation_id));
+            let (bytes_sent, bytes_received) = total_traffic(meters);
+            assert!(bytes_sent > 0 && bytes_received > 0, "{operation}");
+            assert_eq!(
+                (event.bytes_sent, event.bytes_received),
+                (bytes_sent, bytes_received)
+            );
+        };
+
+        let (connections, meters) = connect();
+        let share_set = setup
+            .backup(
+                connections,
+                "password",
+                [42; 32],
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+        check_event("backup", "success", &meters);
+
+        let (connections, meters) = connect();
+        let error = setup
+            .restore(
+                connections,
+                "wrong password",
+                share_set,
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("wrong password");
+        assert_matches!(error, Error::RestoreFailed(9));
+        check_event("restore", "restore_failed", &meters);
+
+        let (connections, meters) = connect();
+        setup.remove(connections).await.expect("can remove");
+        check_event("remove", "success", &meters);
+
+        // Queries are not reported.
+        let (connections, _meters) = connect();
+        assert_matches!(setup.query(connections).await, Err(Error::DataMissing));
+        assert!(setup.0.take().is_empty());
+    }
+
+    #[test]
+    fn request_events_go_to_the_environment_logger() {
+        let event = RequestEvent {
+            operation: "remove",
+            uid: Some([4; 16]),
+            correlation_id: None,
+            duration: Duration::from_millis(5),
+            bytes_sent: 10,
+            bytes_received: 20,
+            outcome: "success",
+        };
+        let staging = &crate::env::STAGING.svr3;
+        let env = crate::env::Svr3Env::custom(staging.sgx(), staging.nitro(), [1, 2]);
+        // Without a logger, events are dropped.
+        PpssSetup::request_logger(&env).on_request(event.clone());
+
+        let logger = Arc::new(CapturingRequestLogger::default());
+        let env = Arc::new(env.with_request_logger(logger.clone()));
+        PpssSetup::request_logger(&env).on_request(event.clone());
+        assert_eq!(logger.take(), [event]);
+    }
+
+    async fn connect_to_fake_enclaves(
+        enclaves: &[Arc<std::sync::Mutex<FakeEnclave>>; 2],
+        uid: Uid,
+    ) -> [AttestedConnection<DuplexStream>; 2] {
+        let sgx = connect_to_fake_enclave::<Sgx>(&enclaves[0], uid).await;
+        let nitro = connect_to_fake_enclave::<Nitro>(&enclaves[1], uid).await;
+        [sgx.into(), nitro.into()]
+    }
+
+    /// Backs up `secret` to `enclaves` the way [`PpssOps::backup`] does.
+    async fn back_up_to_fake_enclaves(
+        enclaves: &[Arc<std::sync::Mutex<FakeEnclave>>; 2],
+        uid: Uid,
+        secret: [u8; 32],
+    ) -> OpaqueMaskedShareSet {
+        let setup = &crate::env::STAGING.svr3;
+        let mut connections = connect_to_fake_enclaves(enclaves, uid).await;
+        let request =
+            prepare_backup_request(setup, "password", secret, nonzero!(10u32), None, &mut OsRng)
+                .expect("can create backup");
+        let responses = run_interactions(&mut connections, request.requests())
+            .await
+            .expect("backup succeeds");
+        parse_backup_response(request, &responses, &mut OsRng).expect("can finalize backup")
+    }
+
+    /// Restores from `enclaves` the way [`PpssOps::restore`] does.
+    async fn restore_from_fake_enclaves(
+        enclaves: &[Arc<std::sync::Mutex<FakeEnclave>>; 2],
+        uid: Uid,
+        share_set: OpaqueMaskedShareSet,
+    ) -> Result<[u8; 32], Error> {
+        let setup = &crate::env::STAGING.svr3;
+        let mut connections = connect_to_fake_enclaves(enclaves, uid).await;
+        let request =
+            prepare_restore_request(setup, "password", share_set, false, None, &mut OsRng)?;
+        let responses = run_interactions(&mut connections, request.requests()).await?;
+        parse_restore_response(request, &responses)
+    }
+
+    fn tries_remaining(enclave: &std::sync::Mutex<FakeEnclave>, uid: &Uid) -> Option<u32> {
+        enclave
+            .lock()
+            .expect("not poisoned")
+            .server
+            .tries_remaining(uid)
+    }
+
+    const OLD_SECRET: [u8; 32] = [1; 32];
+    const NEW_SECRET: [u8; 32] = [2; 32];
+
+    #[tokio::test]
+    async fn rotate_secret_replaces_backup() {
+        const UID: Uid = [5; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let old_share_set = back_up_to_fake_enclaves(enclaves, UID, OLD_SECRET).await;
+
+        let mut connections = connect_to_fake_enclaves(enclaves, UID).await;
+        let new_share_set = rotate_secret_over(
+            &crate::env::STAGING.svr3,
+            &mut connections,
+            old_share_set.clone(),
+            "password",
+            NEW_SECRET,
+            nonzero!(3u32),
+            None,
+            &mut OsRng,
+        )
+        .await
+        .expect("can rotate");
+        for enclave in enclaves {
+            assert_eq!(tries_remaining(enclave, &UID), Some(3));
+        }
+
+        assert_eq!(
+            restore_from_fake_enclaves(enclaves, UID, new_share_set)
+                .await
+                .expect("can restore"),
+            NEW_SECRET
+        );
+        // The enclaves hold new keys, so the old share set is useless.
+        assert_matches!(
+            restore_from_fake_enclaves(enclaves, UID, old_share_set).await,
+            Err(Error::RestoreFailed(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_secret_with_wrong_password_keeps_backup() {
+        const UID: Uid = [6; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let share_set = back_up_to_fake_enclaves(enclaves, UID, OLD_SECRET).await;
+
+        let mut connections = connect_to_fake_enclaves(enclaves, UID).await;
+        let result = rotate_secret_over(
+            &crate::env::STAGING.svr3,
+            &mut connections,
+            share_set.clone(),
+            "wrong password",
+            NEW_SECRET,
+            nonzero!(3u32),
+            None,
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(result, Err(Error::RestoreFailed(9)));
+
+        assert_eq!(
+            restore_from_fake_enclaves(enclaves, UID, share_set)
+                .await
+                .expect("can restore"),
+            OLD_SECRET
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_secret_reports_enclave_disagreement() {
+        const UID: Uid = [7; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let share_set = back_up_to_fake_enclaves(enclaves, UID, OLD_SECRET).await;
+
+        // The Nitro enclave answers the restore, then hangs up on the backup.
+        enclaves[1].lock().expect("not poisoned").hang_up_after = Some(1);
+        let mut connections = connect_to_fake_enclaves(enclaves, UID).await;
+        let result = rotate_secret_over(
+            &crate::env::STAGING.svr3,
+            &mut connections,
+            share_set,
+            "password",
+            NEW_SECRET,
+            nonzero!(3u32),
+            None,
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(Error::EnclaveDisagreement { accepted }) if accepted == [true, false]
+        );
+        assert_eq!(tries_remaining(&enclaves[0], &UID), Some(3));
+        assert_eq!(tries_remaining(&enclaves[1], &UID), Some(9));
+    }
+
+    /// A setup whose operations run over [`FakeAttestedConnection`]s.
+    pub(super) struct FakeSvr3Setup;
+
+    impl PpssSetup for FakeSvr3Setup {
+        type Connections = [FakeAttestedConnection; 2];
+        type ServerIds = [u64; 2];
+
+        fn server_ids(&self) -> Self::ServerIds {
+            [1, 2]
+        }
+
+        fn mr_enclaves(&self) -> Vec<&[u8]> {
+            vec![b"fake sgx".as_slice(), b"fake nitro".as_slice()]
+        }
+    }
+
+    /// Like [`FakeSvr3Setup`], but reports operations to its logger.
+    #[derive(Default)]
+    struct LoggingFakeSvr3Setup(CapturingRequestLogger);
+
+    impl PpssSetup for LoggingFakeSvr3Setup {
+        type Connections = [FakeAttestedConnection; 2];
+        type ServerIds = [u64; 2];
+
+        fn server_ids(&self) -> Self::ServerIds {
+            FakeSvr3Setup.server_ids()
+        }
+
+        fn mr_enclaves(&self) -> Vec<&[u8]> {
+            vec![b"fake sgx".as_slice(), b"fake nitro".as_slice()]
+        }
+
+        fn request_logger(&self) -> &dyn RequestLogger {
+            &self.0
+        }
+    }
+
+    /// A connection that passes its next `requests` requests on to `enclave`.
+    fn scripted_connection_to(
+        enclave: &Arc<std::sync::Mutex<FakeEnclave>>,
+        uid: Uid,
+        requests: usize,
+    ) -> FakeAttestedConnection {
+        (0..requests).fold(
+            FakeAttestedConnection::new().with_uid(uid),
+            |connection, _| {
+                let enclave = enclave.clone();
+                connection.then(move |request| {
+                    let mut enclave = enclave.lock().expect("not poisoned");
+                    let response = enclave
+                        .server
+                        .handle_request(uid, request)
+                        .expect("valid request");
+                    Ok(NextOrClose::Next(response))
+                })
+            },
+        )
+    }
+
+    /// Connections that pass one request each on to `enclaves`.
+    pub(super) fn scripted_connections_to(
+        enclaves: &[Arc<std::sync::Mutex<FakeEnclave>>; 2],
+        uid: Uid,
+    ) -> [FakeAttestedConnection; 2] {
+        [
+            scripted_connection_to(&enclaves[0], uid, 1),
+            scripted_connection_to(&enclaves[1], uid, 1),
+        ]
+    }
+
+    fn not_sent() -> FakeAttestedConnection {
+        FakeAttestedConnection::new()
+            .then_fail(AttestedConnectionError::SendFailed(NetError::ChannelClosed))
+    }
+
+    #[tokio::test]
+    async fn failures_after_network_change_are_reported_as_such() {
+        let network_state = Arc::new(NetworkState::new());
+        let failing = |network_state: &Arc<NetworkState>| {
+            FakeAttestedConnection::new()
+                .with_network(network_state.watch())
+                .then_fail(AttestedConnectionError::Net(NetError::Failure))
+        };
+
+        let connections = [failing(&network_state), failing(&network_state)];
+        network_state.mark_changed();
+        let error = FakeSvr3Setup
+            .remove(connections)
+            .await
+            .expect_err("network changed");
+        assert_matches!(error, Error::NetworkChanged);
+
+        // Connections made after the change have only seen an ordinary failure.
+        let connections = [failing(&network_state), failing(&network_state)];
+        let error = FakeSvr3Setup
+            .query(connections)
+            .await
+            .expect_err("connections failed");
+        assert_matches!(error, Error::Net(NetError::Failure));
+    }
+
+    #[tokio::test]
+    async fn backup_with_too_many_tries_is_rejected_before_sending() {
+        // Unscripted connections panic if anything is sent over them.
+        let error = FakeSvr3Setup
+            .backup(
+                [FakeAttestedConnection::new(), FakeAttestedConnection::new()],
+                "password",
+                NEW_SECRET,
+                nonzero!(256u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("too many tries");
+        assert_matches!(
+            error,
+            Error::InvalidBackupParams(ValidationError {
+                max_tries: 256,
+                min: 1,
+                max: 255,
+            })
+        );
+        assert!(!error.is_safe_to_retry());
+    }
+
+    #[tokio::test]
+    async fn strengthened_backup_can_be_restored() {
+        const UID: Uid = [11; 16];
+        let strengthener: Arc<dyn PasswordStrengthener> = Arc::new(Argon2Strengthener {
+            memory_kb: 64,
+            iterations: 1,
+            parallelism: 1,
+        });
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let share_set = FakeSvr3Setup
+            .backup(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                NEW_SECRET,
+                nonzero!(10u32),
+                Some(strengthener.clone()),
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+        assert_eq!(
+            share_set
+                .strengthening()
+                .map(|strengthening| strengthening.params),
+            Some(strengthener.params())
+        );
+
+        // Without a strengthener given, the recorded parameters are used.
+        let restored = FakeSvr3Setup
+            .restore(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                share_set,
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can restore");
+        assert_eq!(restored, NEW_SECRET);
+    }
+
+    #[tokio::test]
+    async fn failed_backup_keeps_previous_one_only_if_not_sent() {
+        const UID: Uid = [8; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = FakeSvr3Setup;
+        let share_set = setup
+            .backup(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                OLD_SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+
+        // Neither enclave gets the new backup, so the previous one stays.
+        let error = setup
+            .backup(
+                [not_sent(), not_sent()],
+                "password",
+                NEW_SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("nothing sent");
+        assert_matches!(error, Error::RequestNotSent(_));
+        assert!(error.is_safe_to_retry());
+        let restored = setup
+            .restore(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                share_set.clone(),
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can restore");
+        assert_eq!(restored, OLD_SECRET);
+
+        // The SGX enclave replaces its part before the Nitro one hangs up, so
+        // the previous backup is gone and retrying would be no better.
+        let error = setup
+            .backup(
+                [
+                    scripted_connection_to(&enclaves[0], UID, 1),
+                    FakeAttestedConnection::new().then_hang_up(),
+                ],
+                "password",
+                NEW_SECRET,
+                nonzero!(3u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("Nitro enclave hung up");
+        assert_matches!(error, Error::Net(_));
+        assert!(!error.is_safe_to_retry());
+        assert_eq!(tries_remaining(&enclaves[0], &UID), Some(3));
+        assert_matches!(
+            setup
+                .restore(
+                    scripted_connections_to(enclaves, UID),
+                    "password",
+                    share_set,
+                    false,
+                    None,
+                    &mut OsRng,
+                )
+                .await,
+            Err(Error::RestoreFailed(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_secret_keeps_backup_if_no_enclave_accepts() {
+        const UID: Uid = [9; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = FakeSvr3Setup;
+        let share_set = setup
+            .backup(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                OLD_SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+
+        // Both enclaves answer the restore, then hang up on the new backup.
+        let connections =
+            scripted_connections_to(enclaves, UID).map(FakeAttestedConnection::then_hang_up);
+        let error = setup
+            .rotate_secret(
+                connections,
+                share_set.clone(),
+                "password",
+                NEW_SECRET,
+                nonzero!(3u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("enclaves hung up");
+        assert_matches!(error, Error::Net(_));
+
+        let restored = setup
+            .restore(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                share_set,
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can restore");
+        assert_eq!(restored, OLD_SECRET);
+    }
+
+    #[test]
+    fn only_connections_lost_before_sending_are_stale() {
+        assert!(Error::RequestNotSent(NetError::ChannelClosed).is_stale_connection());
+        assert!(!Error::RequestNotSent(NetError::SendTimeout).is_stale_connection());
+        // The enclave may have processed the request before hanging up.
+        assert!(!Error::Net(NetError::ChannelClosed).is_stale_connection());
+    }
+
+    #[test]
+    fn inner_errors_can_be_inspected() {
+        let attestation = Error::AttestationError(attest::enclave::Error::AttestationDataError {
+            reason: "unknown mrenclave".to_string(),
+        });
+        assert_matches!(
+            std::error::Error::source(&attestation)
+                .and_then(|source| source.downcast_ref::<attest::enclave::Error>()),
+            Some(attest::enclave::Error::AttestationDataError { .. })
+        );
+        assert!(attestation.as_attestation_error().is_some());
+
+        let not_sent = Error::RequestNotSent(NetError::ChannelClosed);
+        assert_matches!(
+            std::error::Error::source(&not_sent)
+                .and_then(|source| source.downcast_ref::<NetError>()),
+            Some(NetError::ChannelClosed)
+        );
+        assert_eq!(not_sent.as_net_error(), Some(&NetError::ChannelClosed));
+
+        let refused = Error::Net(NetError::WebSocketError(crate::infra::ws::Error::Http(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+        )));
+        assert_eq!(
+            refused.http_status(),
+            Some(http::StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(Error::Protocol("bad".to_string()).as_net_error(), None);
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("can build runtime")
+            .block_on(future)
+    }
+
+    /// Runs `operation` over `connections` like [`BlockingSvr3Client`] does,
+    /// reporting their traffic along with the result.
+    ///
+    /// [`BlockingSvr3Client`]: blocking::BlockingSvr3Client
+    fn run_metered<T, Fut: Future<Output = Result<T, Error>>>(
+        connections: [FakeAttestedConnection; 2],
+        operation: impl FnOnce([FakeAttestedConnection; 2]) -> Fut,
+    ) -> Result<(Result<T, Error>, Vec<EnclaveTraffic>), BlockingError> {
+        let meters: Vec<_> = connections
+            .iter()
+            .map(AttestedConnectionLike::traffic_meter)
+            .collect();
+        let result = block_on(operation(connections));
+        let traffic = meters
+            .iter()
+            .map(|meter| {
+                let traffic = meter.read();
+                EnclaveTraffic {
+                    enclave: "fake",
+                    requests_sent: traffic.requests_sent,
+                    responses_received: traffic.responses_received,
+                    bytes_sent: traffic.bytes_sent,
+                    bytes_received: traffic.bytes_received,
+                    request_millis: vec![],
+                }
+            })
+            .collect();
+        Ok((result, traffic))
+    }
+
+    #[test]
+    fn stale_connections_are_replaced_once() {
+        const UID: Uid = [12; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = FakeSvr3Setup;
+        let logger = CapturingRequestLogger::default();
+        let correlation_id = CorrelationId::random();
+
+        // The connections were lost before the backup, which goes through
+        // over new ones.
+        let mut reconnects = 0;
+        let share_set = recover_stale_connections(
+            "backup",
+            Some(correlation_id),
+            [not_sent(), not_sent()],
+            &logger,
+            |connections| {
+                run_metered(connections, |connections| async {
+                    setup
+                        .backup(
+                            connections,
+                            "password",
+                            NEW_SECRET,
+                            nonzero!(10u32),
+                            None,
+                            &mut OsRng,
+                        )
+                        .await
+                })
+            },
+            || {
+                reconnects += 1;
+                Ok(Some(scripted_connections_to(enclaves, UID)))
+            },
+        )
+        .expect("backed up over new connections");
+        assert_eq!(reconnects, 1);
+        assert_eq!(
+            logger.take_recoveries(),
+            [RecoveryEvent {
+                operation: "backup",
+                correlation_id: Some(correlation_id),
+                outcome: "request_not_sent",
+            }]
+        );
+        let restored = block_on(setup.restore(
+            scripted_connections_to(enclaves, UID),
+            "password",
+            share_set,
+            false,
+            None,
+            &mut OsRng,
+        ))
+        .expect("can restore");
+        assert_eq!(restored, NEW_SECRET);
+
+        // The new connections are lost as well, and that is the end of it.
+        let mut reconnects = 0;
+        let error = recover_stale_connections(
+            "remove",
+            None,
+            [not_sent(), not_sent()],
+            &logger,
+            |connections| run_metered(connections, |connections| setup.remove(connections)),
+            || {
+                reconnects += 1;
+                Ok(Some([not_sent(), not_sent()]))
+            },
+        )
+        .expect_err("lost twice");
+        assert_matches!(error, BlockingError::Svr3(Error::RequestNotSent(_)));
+        assert_eq!(reconnects, 1);
+
+        // Without new connections to run on, the first failure stands.
+        let error = recover_stale_connections(
+            "remove",
+            None,
+            [not_sent(), not_sent()],
+            &logger,
+            |connections| run_metered(connections, |connections| setup.remove(connections)),
+            || Ok(None),
+        )
+        .expect_err("not reconnected");
+        assert_matches!(error, BlockingError::Svr3(Error::RequestNotSent(_)));
+        assert_eq!(logger.take_recoveries().len(), 1);
+    }
+
+    #[test]
+    fn connections_lost_after_a_response_are_not_replaced() {
+        const UID: Uid = [13; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = FakeSvr3Setup;
+        let share_set = block_on(setup.backup(
+            scripted_connections_to(enclaves, UID),
+            "password",
+            OLD_SECRET,
+            nonzero!(10u32),
+            None,
+            &mut OsRng,
+        ))
+        .expect("can back up");
+
+        // Both enclaves answer the restore that checks the password, so it
+        // has used up a try by the time the new backup can't be sent.
+        let logger = CapturingRequestLogger::default();
+        let connections = [0, 1].map(|i| {
+            scripted_connection_to(&enclaves[i], UID, 1)
+                .then_fail(AttestedConnectionError::SendFailed(NetError::ChannelClosed))
+        });
+        let error = recover_stale_connections(
+            "rotate",
+            None,
+            connections,
+            &logger,
+            |connections| {
+                run_metered(connections, |connections| async {
+                    setup
+                        .rotate_secret(
+                            connections,
+                            share_set.clone(),
+                            "password",
+                            NEW_SECRET,
+                            nonzero!(10u32),
+                            None,
+                            &mut OsRng,
+                        )
+                        .await
+                })
+            },
+            || unreachable!("running the rotation again would use up another try"),
+        )
+        .expect_err("backup not sent");
+        assert_matches!(error, BlockingError::Svr3(Error::RequestNotSent(_)));
+        assert!(logger.take_recoveries().is_empty());
+        assert_eq!(tries_remaining(&enclaves[0], &UID), Some(9));
+    }
+
+    /// Runs [`PpssOps::backup_if_changed`] over connections that pass the
+    /// given number of `requests` on to each of `enclaves`; any further
+    /// request would panic.
+    async fn backup_if_changed_to_fake_enclaves(
+        enclaves: &[Arc<std::sync::Mutex<FakeEnclave>>; 2],
+        uid: Uid,
+        store: &mut InMemoryShareSetStore,
+        password: &str,
+        secret: [u8; 32],
+        requests: usize,
+    ) -> Result<BackupIfChangedResult, Error> {
+        FakeSvr3Setup
+            .backup_if_changed(
+                uid,
+                [0, 1].map(|i| scripted_connection_to(&enclaves[i], uid, requests)),
+                password,
+                secret,
+                nonzero!(10u32),
+                None,
+                store,
+                &mut OsRng,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn backup_if_changed_writes_only_new_secrets() {
+        const UID: Uid = [11; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let mut store = InMemoryShareSetStore::default();
+        let stored = |store: &InMemoryShareSetStore| {
+            store
+                .get(&UID)
+                .expect("stored")
+                .share_set
+                .serialize()
+                .expect("can serialize")
+        };
+
+        // Nothing is stored yet, so the secret is backed up right away.
+        let result = backup_if_changed_to_fake_enclaves(
+            enclaves, UID, &mut store, "password", OLD_SECRET, 1,
+        )
+        .await;
+        let share_set = assert_matches!(result, Ok(BackupIfChangedResult::Updated(s)) => s);
+        assert_eq!(
+            stored(&store),
+            share_set.serialize().expect("can serialize")
+        );
+
+        // The same backup sends nothing, so it doesn't use up a try either.
+        let result = backup_if_changed_to_fake_enclaves(
+            enclaves, UID, &mut store, "password", OLD_SECRET, 0,
+        )
+        .await;
+        assert_matches!(result, Ok(BackupIfChangedResult::Unchanged));
+        for enclave in enclaves {
+            assert_eq!(tries_remaining(enclave, &UID), Some(10));
+        }
+        assert_eq!(
+            stored(&store),
+            share_set.serialize().expect("can serialize")
+        );
+
+        // A new password is a change even with the same secret.
+        let result = backup_if_changed_to_fake_enclaves(
+            enclaves,
+            UID,
+            &mut store,
+            "new password",
+            OLD_SECRET,
+            1,
+        )
+        .await;
+        assert_matches!(result, Ok(BackupIfChangedResult::Updated(_)));
+
+        let result = backup_if_changed_to_fake_enclaves(
+            enclaves, UID, &mut store, "password", NEW_SECRET, 1,
+        )
+        .await;
+        let share_set = assert_matches!(result, Ok(BackupIfChangedResult::Updated(s)) => s);
+        assert_eq!(
+            stored(&store),
+            share_set.serialize().expect("can serialize")
+        );
+        let restored = FakeSvr3Setup
+            .restore(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                share_set,
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can restore");
+        assert_eq!(restored, NEW_SECRET);
+    }
+
+    #[tokio::test]
+    async fn rotate_secret_reports_which_enclaves_accepted() {
+        const UID: Uid = [12; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let share_set = FakeSvr3Setup
+            .backup(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                OLD_SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+
+        // The second enclave answers the restore, then fails the backup.
+        let connections = [
+            scripted_connection_to(&enclaves[0], UID, 2),
+            scripted_connection_to(&enclaves[1], UID, 1)
+                .then_fail(AttestedConnectionError::Net(NetError::Failure)),
+        ];
+        let error = FakeSvr3Setup
+            .rotate_secret(
+                connections,
+                share_set,
+                "password",
+                NEW_SECRET,
+                nonzero!(3u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("only one enclave accepted");
+        assert_matches!(
+            error,
+            Error::EnclaveDisagreement { accepted } if accepted == [true, false]
+        );
+        assert_eq!(tries_remaining(&enclaves[0], &UID), Some(3));
+        assert_eq!(tries_remaining(&enclaves[1], &UID), Some(9));
+    }
+
+    #[tokio::test]
+    async fn restore_reports_fewest_tries_remaining() {
+        const UID: Uid = [10; 16];
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let setup = FakeSvr3Setup;
+        let share_set = setup
+            .backup(
+                scripted_connections_to(enclaves, UID),
+                "password",
+                OLD_SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+
+        // Use up tries on the Nitro enclave alone.
+        for _ in 0..3 {
+            let restore = Restore::new("guess", share_set.clone().into_inner(), &mut OsRng)
+                .expect("can create restore");
+            let mut enclave = enclaves[1].lock().expect("not poisoned");
+            let _ = enclave.server.handle_request(UID, &restore.requests[1]);
+        }
+
+        let connections = scripted_connections_to(enclaves, UID);
+        let error = setup
+            .restore(
+                connections,
+                "wrong password",
+                share_set.clone(),
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("wrong password");
+        assert_matches!(error, Error::RestoreFailed(6));
+        assert_eq!(tries_remaining(&enclaves[0], &UID), Some(9));
+
+        let tries = setup
+            .query(scripted_connections_to(enclaves, UID))
+            .await
+            .expect("can query");
+        assert_eq!(tries, 6);
     }
 }
@@ -0,0 +1,489 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Blocking wrappers around the SVR3 operations.
+//!
+//! The async [`PpssOps`] API remains the primary one; [`BlockingSvr3Client`]
+//! exists for callers that don't run an async runtime of their own, such as
+//! command line tools.
+
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand_core::CryptoRngCore;
+use thiserror::Error;
+
+use super::request_log::{outcome, RecoveryEvent, RequestLogger};
+use super::traffic::{EnclaveTraffic, EnclaveTrafficMeter};
+use super::{Error, OpaqueMaskedShareSet, PasswordStrengthener, PpssOps};
+use crate::auth::Auth;
+use crate::enclave::{EnclaveEndpointConnection, PpssSetup};
+use crate::env::{Svr3ConnectTimeouts, Svr3Env};
+use crate::infra::correlation::CorrelationId;
+use crate::infra::dns::DnsResolver;
+use crate::infra::errors::{LogSafeDisplay, RetryLater};
+use crate::infra::TcpSslTransportConnector;
+use crate::svr::SvrConnection;
+
+/// Connects to both enclaves of `env` at the same time, each within its own
+/// timeouts.
+///
+/// As soon as either connection fails, the other attempt is abandoned and
+/// the failure returned: every enclave holds a share needed for every
+/// operation, so there is nothing to go ahead with.
+///
+/// Both connections serve the same operation, so they share `correlation_id`.
+async fn connect_both(
+    env: &Svr3Env<'static>,
+    sgx_auth: Auth,
+    nitro_auth: Auth,
+    timeouts: Svr3ConnectTimeouts,
+    connector: TcpSslTransportConnector,
+    correlation_id: CorrelationId,
+) -> Result<<Svr3Env<'static> as PpssSetup>::Connections, Error> {
+    let sgx_connection = EnclaveEndpointConnection::new(env.sgx(), timeouts.sgx);
+    let nitro_connection = EnclaveEndpointConnection::new(env.nitro(), timeouts.nitro);
+    Ok(futures_util::future::try_join(
+        SvrConnection::connect_with_correlation_id(
+            sgx_auth,
+            &sgx_connection,
+            connector.clone(),
+            correlation_id,
+        ),
+        SvrConnection::connect_with_correlation_id(
+            nitro_auth,
+            &nitro_connection,
+            connector,
+            correlation_id,
+        ),
+    )
+    .await?)
+}
+
+#[derive(Debug, Error, displaydoc::Display)]
+pub enum BlockingError {
+    /// Blocking SVR3 operations can't run inside an async context
+    InAsyncContext,
+    /// {0}
+    Svr3(#[from] Error),
+}
+
+impl LogSafeDisplay for BlockingError {}
+
+impl RetryLater for BlockingError {
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::InAsyncContext => None,
+            Self::Svr3(err) => err.retry_after(),
+        }
+    }
+
+    fn is_permanent(&self) -> bool {
+        match self {
+            Self::InAsyncContext => false,
+            Self::Svr3(err) => err.is_permanent(),
+        }
+    }
+}
+
+/// The result of an operation, along with what it exchanged with each enclave.
+type Metered<T> = (Result<T, Error>, Vec<EnclaveTraffic>);
+
+/// Runs an operation with `run`, then once more over the connections from
+/// `reconnect` if the first ones turned out to be stale.
+///
+/// Connections made ahead of time may have been closed by the enclaves for
+/// idling by the time they are used. That is only assumed if the operation
+/// [couldn't send a request](Error::is_stale_connection) for the connection
+/// being lost, and nothing had been received in answer to an earlier one:
+/// then none of the enclaves can have processed any of it, not even a restore
+/// that would use up a try. Any other failure, and any failure of the second
+/// attempt, is returned as it is.
+///
+/// `reconnect` can return `None` to give up on recovering, and its own failure
+/// is returned in place of the operation's. Otherwise the recovery is reported
+/// to `logger` before the operation is run again.
+pub(super) fn recover_stale_connections<C, T>(
+    operation: &'static str,
+    correlation_id: Option<CorrelationId>,
+    connections: C,
+    logger: &dyn RequestLogger,
+    mut run: impl FnMut(C) -> Result<Metered<T>, BlockingError>,
+    reconnect: impl FnOnce() -> Result<Option<C>, BlockingError>,
+) -> Result<T, BlockingError> {
+    let (result, traffic) = run(connections)?;
+    let err = match result {
+        Err(err)
+            if err.is_stale_connection()
+                && traffic
+                    .iter()
+                    .all(|traffic| traffic.responses_received == 0) =>
+        {
+            err
+        }
+        result => return Ok(result?),
+    };
+    let Some(connections) = reconnect()? else {
+        return Err(err.into());
+    };
+    log::info!("running SVR3 {operation} again over new connections: {err}");
+    logger.on_recovery(RecoveryEvent {
+        operation,
+        correlation_id,
+        outcome: outcome(&err),
+    });
+    Ok(run(connections)?.0?)
+}
+
+/// Synchronous SVR3 client, for callers that don't run an async runtime.
+///
+/// Each client drives its operations on a current-thread runtime of its own.
+/// Connections made by [`Self::connect`] belong to that runtime and should
+/// only be passed back to the same client.
+///
+/// The methods must not be called from within an async context: blocking
+/// there could deadlock the calling runtime. Instead of blocking, they return
+/// [`BlockingError::InAsyncContext`]. Like any tokio runtime, the client must
+/// not be dropped from within an async context either.
+///
+/// The client keeps the credentials of its last [`Self::connect`]. If the
+/// connections made then have been lost by the time an operation is run on
+/// them, before it could send anything, the client connects again with those
+/// credentials and runs the operation once more.
+pub struct BlockingSvr3Client {
+    runtime: tokio::runtime::Runtime,
+    env: &'static Svr3Env<'static>,
+    connect_timeouts: Svr3ConnectTimeouts,
+    transport_connector: TcpSslTransportConnector,
+    last_operation_traffic: Mutex<Vec<EnclaveTraffic>>,
+    last_credentials: Mutex<Option<Credentials>>,
+}
+
+/// What a [`BlockingSvr3Client::connect`] was called with, to connect again
+/// if the connections it made go stale.
+struct Credentials {
+    /// Identifies the connections made with these credentials.
+    correlation_id: CorrelationId,
+    sgx_auth: Auth,
+    nitro_auth: Auth,
+}
+
+impl BlockingSvr3Client {
+    /// A client for the enclaves of `env`, connecting to each within its
+    /// `connect_timeouts`.
+    ///
+    /// A single [`Duration`] waits at most that long for each connection.
+    pub fn new(
+        env: &'static Svr3Env<'static>,
+        connect_timeouts: impl Into<Svr3ConnectTimeouts>,
+    ) -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("can build runtime");
+        Self {
+            runtime,
+            env,
+            connect_timeouts: connect_timeouts.into(),
+            transport_connector: TcpSslTransportConnector::new(DnsResolver::default()),
+            last_operation_traffic: Mutex::default(),
+            last_credentials: Mutex::default(),
+        }
+    }
+
+    /// Connects through `connector` rather than one of the client's own, for
+    /// example to share its resolver with other clients.
+    pub fn with_transport_connector(mut self, connector: TcpSslTransportConnector) -> Self {
+        self.transport_connector = connector;
+        self
+    }
+
+    /// Connects to both enclaves at the same time, each within the client's
+    /// connect timeouts.
+    ///
+    /// Fails as soon as either connection does, abandoning the other attempt:
+    /// every operation needs both enclaves.
+    pub fn connect(
+        &self,
+        sgx_auth: Auth,
+        nitro_auth: Auth,
+    ) -> Result<<Svr3Env as PpssSetup>::Connections, BlockingError> {
+        let correlation_id = CorrelationId::random();
+        let connections = self.block_on(connect_both(
+            self.env,
+            sgx_auth.clone(),
+            nitro_auth.clone(),
+            self.connect_timeouts,
+            self.transport_connector.clone(),
+            correlation_id,
+        ))??;
+        *self.last_credentials.lock().expect("not poisoned") = Some(Credentials {
+            correlation_id,
+            sgx_auth,
+            nitro_auth,
+        });
+        Ok(connections)
+    }
+
+    /// Blocking version of [`PpssOps::backup`].
+    pub fn backup(
+        &self,
+        connections: <Svr3Env as PpssSetup>::Connections,
+        password: &str,
+        secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, BlockingError> {
+        self.run_recovering("backup", connections, |connections| {
+            self.block_on_metered(connections, |connections| {
+                self.env.backup(
+                    connections,
+                    password,
+                    secret,
+                    max_tries,
+                    strengthener.clone(),
+                    rng,
+                )
+            })
+        })
+    }
+
+    /// Blocking version of [`PpssOps::restore`].
+    pub fn restore(
+        &self,
+        connections: <Svr3Env as PpssSetup>::Connections,
+        password: &str,
+        share_set: OpaqueMaskedShareSet,
+        allow_enclave_migration: bool,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<[u8; 32], BlockingError> {
+        self.run_recovering("restore", connections, |connections| {
+            self.block_on_metered(connections, |connections| {
+                self.env.restore(
+                    connections,
+                    password,
+                    share_set.clone(),
+                    allow_enclave_migration,
+                    strengthener.clone(),
+                    rng,
+                )
+            })
+        })
+    }
+
+    /// Blocking version of [`PpssOps::remove`].
+    pub fn remove(
+        &self,
+        connections: <Svr3Env as PpssSetup>::Connections,
+    ) -> Result<(), BlockingError> {
+        self.run_recovering("remove", connections, |connections| {
+            self.block_on_metered(connections, |connections| self.env.remove(connections))
+        })
+    }
+
+    /// Blocking version of [`PpssOps::query`].
+    pub fn query(
+        &self,
+        connections: <Svr3Env as PpssSetup>::Connections,
+    ) -> Result<u32, BlockingError> {
+        self.run_recovering("query", connections, |connections| {
+            self.block_on_metered(connections, |connections| self.env.query(connections))
+        })
+    }
+
+    /// What the last backup, restore, remove or query exchanged with each
+    /// enclave.
+    ///
+    /// Failed operations count too, with whatever got through before the
+    /// failure. Empty until an operation has run.
+    pub fn last_operation_traffic(&self) -> Vec<EnclaveTraffic> {
+        self.last_operation_traffic
+            .lock()
+            .expect("not poisoned")
+            .clone()
+    }
+
+    /// Runs an operation with `run`, recovering from stale connections with
+    /// the credentials they were made with; see [`recover_stale_connections`].
+    fn run_recovering<T>(
+        &self,
+        operation: &'static str,
+        connections: <Svr3Env as PpssSetup>::Connections,
+        run: impl FnMut(<Svr3Env as PpssSetup>::Connections) -> Result<Metered<T>, BlockingError>,
+    ) -> Result<T, BlockingError> {
+        let correlation_id = connections.0.correlation_id();
+        let reconnect = || {
+            let credentials = self
+                .last_credentials
+                .lock()
+                .expect("not poisoned")
+                .as_ref()
+                .filter(|credentials| Some(credentials.correlation_id) == correlation_id)
+                .map(|credentials| (credentials.sgx_auth.clone(), credentials.nitro_auth.clone()));
+            // Connections that weren't made by the last connect are left be.
+            let Some((sgx_auth, nitro_auth)) = credentials else {
+                return Ok(None);
+            };
+            self.connect(sgx_auth, nitro_auth).map(Some)
+        };
+        recover_stale_connections(
+            operation,
+            correlation_id,
+            connections,
+            self.env.request_logger(),
+            run,
+            reconnect,
+        )
+    }
+
+    /// Runs `operation` on `connections`, keeping track of their traffic.
+    ///
+    /// The traffic is returned along with the output, as well as kept for
+    /// [`Self::last_operation_traffic`].
+    fn block_on_metered<F: Future>(
+        &self,
+        connections: <Svr3Env as PpssSetup>::Connections,
+        operation: impl FnOnce(<Svr3Env as PpssSetup>::Connections) -> F,
+    ) -> Result<(F::Output, Vec<EnclaveTraffic>), BlockingError> {
+        let meters = [
+            EnclaveTrafficMeter::new(&connections.0),
+            EnclaveTrafficMeter::new(&connections.1),
+        ];
+        let output = self.block_on(operation(connections))?;
+        let traffic: Vec<_> = meters.iter().map(EnclaveTrafficMeter::read).collect();
+        *self.last_operation_traffic.lock().expect("not poisoned") = traffic.clone();
+        Ok((output, traffic))
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> Result<F::Output, BlockingError> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(BlockingError::InAsyncContext);
+        }
+        Ok(self.runtime.block_on(future))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::enclave::EnclaveEndpoint;
+
+    fn client() -> BlockingSvr3Client {
+        BlockingSvr3Client::new(Svr3Env::from_flags(true), Duration::from_secs(10))
+    }
+
+    #[test]
+    fn runs_futures_outside_async_context() {
+        assert_matches!(client().block_on(async { 42 }), Ok(42));
+    }
+
+    #[test]
+    fn refuses_to_block_in_async_context() {
+        let client = client();
+        let caller = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("can build runtime");
+        caller.block_on(async {
+            assert_matches!(
+                client.block_on(async { 42 }),
+                Err(BlockingError::InAsyncContext)
+            );
+            // Nothing is attempted, not even the connection.
+            let auth = Auth::from_uid_and_secret([0; 16], [0; 32]);
+            assert_matches!(
+                client.connect(auth.clone(), auth),
+                Err(BlockingError::InAsyncContext)
+            );
+        });
+    }
+
+    /// An environment with the SGX and Nitro enclaves at the given local ports.
+    fn local_env(sgx_port: u16, nitro_port: u16) -> &'static Svr3Env<'static> {
+        Box::leak(Box::new(Svr3Env::custom(
+            EnclaveEndpoint::test_endpoint(sgx_port),
+            EnclaveEndpoint::test_endpoint(nitro_port),
+            [1, 2],
+        )))
+    }
+
+    /// Accepts TCP connections but never answers, so connecting to it can
+    /// only time out.
+    fn unresponsive_server() -> (std::net::TcpListener, u16) {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).expect("can bind");
+        let port = listener.local_addr().expect("bound").port();
+        (listener, port)
+    }
+
+    /// Connects to both enclaves of `env` the way the client does, returning
+    /// how long that took on the (paused) tokio clock.
+    async fn time_connecting(
+        env: &'static Svr3Env<'static>,
+        timeouts: Svr3ConnectTimeouts,
+    ) -> Duration {
+        let auth = Auth::from_uid_and_secret([0; 16], [0; 32]);
+        let start = tokio::time::Instant::now();
+        assert_matches!(
+            connect_both(
+                env,
+                auth.clone(),
+                auth,
+                timeouts,
+                TcpSslTransportConnector::new(DnsResolver::default()),
+                CorrelationId::random(),
+            )
+            .await,
+            Err(_)
+        );
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_enclave_connects_within_its_own_timeout() {
+        const SHORT: Duration = Duration::from_millis(100);
+        const LONG: Duration = Duration::from_secs(60);
+
+        let (_listener, port) = unresponsive_server();
+        let env = local_env(port, port);
+
+        for (sgx, nitro) in [(SHORT, LONG), (LONG, SHORT)] {
+            let timeouts = Svr3ConnectTimeouts {
+                sgx: sgx.into(),
+                nitro: nitro.into(),
+            };
+            // The enclave with the short timeout gives up, and the other
+            // attempt is abandoned with it.
+            let elapsed = time_connecting(env, timeouts).await;
+            assert!(elapsed < LONG, "took {elapsed:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_enclave_cuts_connecting_to_the_other_short() {
+        const TIMEOUT: Duration = Duration::from_secs(60);
+
+        let (_listener, unresponsive_port) = unresponsive_server();
+        // Nothing listens here once the listener is dropped, so connecting is
+        // refused right away.
+        let (listener, refusing_port) = unresponsive_server();
+        drop(listener);
+
+        for env in [
+            local_env(refusing_port, unresponsive_port),
+            local_env(unresponsive_port, refusing_port),
+        ] {
+            // Waiting out the unresponsive enclave would take the full timeout.
+            let elapsed = time_connecting(env, TIMEOUT.into()).await;
+            assert!(elapsed < TIMEOUT, "took {elapsed:?}");
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Connectivity checks for the SVR3 enclaves.
+//!
+//! [`diagnose`] goes through the same connect path as the real operations, up
+//! to and including attestation, then closes the connection without sending
+//! any requests. Stored data is never touched.
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::auth::HttpBasicAuth;
+use crate::enclave::{EnclaveEndpointConnection, NewHandshake, Svr3Flavor};
+use crate::infra::connection_manager::ConnectionManager;
+use crate::infra::TransportConnector;
+use crate::svr::{ErrorCategory, SvrConnection};
+
+/// Outcome of connecting to one enclave.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnclaveDiagnostics {
+    /// [Name](Svr3Flavor::flavor_name) of the enclave, e.g. `"sgx"`.
+    pub enclave: &'static str,
+    /// Why the connection failed, or `None` if it succeeded.
+    pub error: Option<ErrorCategory>,
+    /// Log-safe description of the route used by the last connection attempt.
+    pub route: Option<String>,
+    pub websocket_millis: Option<u64>,
+    pub attestation_millis: Option<u64>,
+}
+
+pub async fn diagnose<E, C, T>(
+    auth: impl HttpBasicAuth,
+    connection: &EnclaveEndpointConnection<E, C>,
+    transport_connector: T,
+) -> EnclaveDiagnostics
+where
+    E: Svr3Flavor + NewHandshake,
+    C: ConnectionManager,
+    T: TransportConnector,
+{
+    let enclave = E::flavor_name();
+    let (result, diagnostics) =
+        SvrConnection::connect_with_diagnostics(auth, connection, transport_connector).await;
+    let error = match result {
+        Ok(connection) => {
+            if let Err(e) = connection.close().await {
+                // The enclave was reachable, which is all this is checking.
+                log::debug!("failed to close diagnostic connection to {enclave}: {e}");
+            }
+            None
+        }
+        Err(e) => Some(e.category()),
+    };
+    EnclaveDiagnostics {
+        enclave,
+        error,
+        route: diagnostics.route.map(|route| route.to_string()),
+        websocket_millis: diagnostics.websocket_time.map(as_millis),
+        attestation_millis: diagnostics.attestation_time.map(as_millis),
+    }
+}
+
+pub(super) fn as_millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::auth::Auth;
+    use crate::enclave::{EnclaveEndpoint, Sgx};
+    use crate::infra::errors::NetError;
+    use crate::infra::{ConnectionParams, StreamAndHost};
+
+    #[derive(Clone)]
+    struct UnreachableConnector;
+
+    #[async_trait]
+    impl TransportConnector for UnreachableConnector {
+        type Stream = DuplexStream;
+
+        async fn connect(
+            &self,
+            _connection_params: &ConnectionParams,
+            _alpn: &[u8],
+        ) -> Result<StreamAndHost<Self::Stream>, NetError> {
+            Err(NetError::Failure)
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_enclave_is_reported() {
+        let endpoint = EnclaveEndpoint::<Sgx>::test_endpoint(8443);
+        let connection = EnclaveEndpointConnection::new(endpoint, Duration::from_secs(10));
+        let auth = Auth {
+            username: "username".to_string(),
+            password: "password".to_string(),
+        };
+
+        let diagnostics = diagnose(auth, &connection, UnreachableConnector).await;
+
+        assert_eq!(diagnostics.enclave, "sgx");
+        assert_eq!(diagnostics.error, Some(ErrorCategory::Network));
+        let route = diagnostics.route.expect("a connection was attempted");
+        assert!(route.starts_with("https://127.0.0.1:8443"), "{route}");
+        assert!(!route.contains("password"));
+        assert!(diagnostics.websocket_millis.is_some());
+        assert_eq!(diagnostics.attestation_millis, None);
+
+        let json = serde_json::to_value(&diagnostics).expect("can serialize");
+        assert_eq!(json["error"], "Network");
+        assert!(json["attestationMillis"].is_null());
+    }
+}
@@ -0,0 +1,291 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Append-only audit trail of SVR3 operations.
+//!
+//! [`OperationLog`] wraps the [`PpssOps`] calls of an environment and writes a
+//! [`LogEntry`] for each of them, one JSON object per line. Entries only carry
+//! the UID, the name of the operation, and its outcome; passwords, secrets,
+//! and share sets are never written. [`replay`] reads them back.
+
+use std::io::{BufRead, Write};
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rand_core::CryptoRngCore;
+use serde::{Deserialize, Serialize};
+
+use super::{Error, OpaqueMaskedShareSet, PasswordStrengthener, PpssOps, Uid};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OperationType {
+    Backup,
+    Restore,
+    Remove,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OperationOutcome {
+    Success,
+    /// The operation failed; `error` is the [`Error`] display string.
+    Failure {
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub timestamp: SystemTime,
+    pub uid: Uid,
+    pub operation: OperationType,
+    pub outcome: OperationOutcome,
+}
+
+/// Records every [`PpssOps`] call made through it to `writer`.
+pub struct OperationLog<Env, W> {
+    inner: Env,
+    writer: W,
+}
+
+impl<Env: PpssOps, W: Write + Send> OperationLog<Env, W> {
+    pub fn new(inner: Env, writer: W) -> Self {
+        Self { inner, writer }
+    }
+
+    pub fn inner(&self) -> &Env {
+        &self.inner
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    pub async fn backup(
+        &mut self,
+        uid: Uid,
+        connections: Env::Connections,
+        password: &str,
+        secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, Error> {
+        let timestamp = SystemTime::now();
+        let result = self
+            .inner
+            .backup(connections, password, secret, max_tries, strengthener, rng)
+            .await;
+        self.record(timestamp, uid, OperationType::Backup, &result);
+        result
+    }
+
+    pub async fn restore(
+        &mut self,
+        uid: Uid,
+        connections: Env::Connections,
+        password: &str,
+        share_set: OpaqueMaskedShareSet,
+        allow_enclave_migration: bool,
+        strengthener: Option<Arc<dyn PasswordStrengthener>>,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<[u8; 32], Error> {
+        let timestamp = SystemTime::now();
+        let result = self
+            .inner
+            .restore(
+                connections,
+                password,
+                share_set,
+                allow_enclave_migration,
+                strengthener,
+                rng,
+            )
+            .await;
+        self.record(timestamp, uid, OperationType::Restore, &result);
+        result
+    }
+
+    pub async fn remove(&mut self, uid: Uid, connections: Env::Connections) -> Result<(), Error> {
+        let timestamp = SystemTime::now();
+        let result = self.inner.remove(connections).await;
+        self.record(timestamp, uid, OperationType::Remove, &result);
+        result
+    }
+
+    fn record<T>(
+        &mut self,
+        timestamp: SystemTime,
+        uid: Uid,
+        operation: OperationType,
+        result: &Result<T, Error>,
+    ) {
+        let outcome = match result {
+            Ok(_) => OperationOutcome::Success,
+            Err(e) => OperationOutcome::Failure {
+                error: e.to_string(),
+            },
+        };
+        let entry = LogEntry {
+            timestamp,
+            uid,
+            operation,
+            outcome,
+        };
+        if let Err(e) = self.append(&entry) {
+            // The operation itself has already completed, so its result is
+            // still returned to the caller.
+            log::error!("failed to write {operation:?} to the operation log: {e}");
+        }
+    }
+
+    fn append(&mut self, entry: &LogEntry) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Parses the entries previously written by an [`OperationLog`], in order,
+/// whatever it wrapped or wrote to.
+pub fn replay(reader: impl BufRead) -> std::io::Result<Vec<LogEntry>> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+    use rand::rngs::OsRng;
+
+    use super::super::test::{scripted_connections_to, FakeEnclave, FakeSvr3Setup};
+    use super::*;
+
+    const UID: Uid = [0xab; 16];
+    const OTHER_UID: Uid = [0xcd; 16];
+    const SECRET: [u8; 32] = [0x5e; 32];
+
+    #[tokio::test]
+    async fn operations_are_recorded_in_order() {
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let mut log = OperationLog::new(FakeSvr3Setup, Vec::<u8>::new());
+
+        let start = SystemTime::now();
+        let share_set = log
+            .backup(
+                UID,
+                scripted_connections_to(enclaves, UID),
+                "password",
+                SECRET,
+                nonzero!(10u32),
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can back up");
+        let error = log
+            .restore(
+                UID,
+                scripted_connections_to(enclaves, UID),
+                "wrong password",
+                share_set.clone(),
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect_err("wrong password");
+        let restored = log
+            .restore(
+                UID,
+                scripted_connections_to(enclaves, UID),
+                "password",
+                share_set,
+                false,
+                None,
+                &mut OsRng,
+            )
+            .await
+            .expect("can restore");
+        assert_eq!(restored, SECRET);
+        log.remove(OTHER_UID, scripted_connections_to(enclaves, OTHER_UID))
+            .await
+            .expect("can remove");
+        let end = SystemTime::now();
+
+        let written = log.into_writer();
+        let entries = replay(written.as_slice()).expect("valid log");
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| (e.uid, e.operation, e.outcome.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (UID, OperationType::Backup, OperationOutcome::Success),
+                (
+                    UID,
+                    OperationType::Restore,
+                    OperationOutcome::Failure {
+                        error: error.to_string()
+                    }
+                ),
+                (UID, OperationType::Restore, OperationOutcome::Success),
+                (OTHER_UID, OperationType::Remove, OperationOutcome::Success),
+            ]
+        );
+        assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        assert!(entries
+            .iter()
+            .all(|e| start <= e.timestamp && e.timestamp <= end));
+
+        // The restored secret must never make it into the log.
+        let secret_json = serde_json::to_string(&SECRET).expect("can serialize");
+        let secret_json = secret_json
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .expect("array");
+        assert!(!String::from_utf8(written)
+            .expect("log is UTF-8")
+            .contains(secret_json));
+    }
+
+    /// Fails every write.
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn results_are_returned_even_if_they_cannot_be_recorded() {
+        let enclaves = &[FakeEnclave::new(), FakeEnclave::new()];
+        let mut log = OperationLog::new(FakeSvr3Setup, BrokenWriter);
+        log.remove(UID, scripted_connections_to(enclaves, UID))
+            .await
+            .expect("can remove");
+    }
+
+    #[test]
+    fn replay_rejects_malformed_entries() {
+        assert_matches!(
+            replay(&b"{\"not\":\"an entry\"}\n"[..]),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData
+        );
+        assert_matches!(replay(&b""[..]), Ok(entries) if entries.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Reuse of attested SVR3 connections across operations.
+//!
+//! Every SVR3 operation starts with a websocket connection and an attestation
+//! handshake per enclave. [`SvrConnectionPool`] keeps connections around once
+//! an operation is done with them, keyed by enclave and UID, so that an
+//! operation following shortly after can skip that work.
+//!
+//! A connection is only ever handed to one caller at a time: checking it out
+//! removes it from the pool until it is checked back in. If the pooled
+//! connection for a key is already in use, a second operation for the same key
+//! opens a new connection instead of waiting.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::Uid;
+use crate::enclave::Svr3Flavor;
+use crate::infra::ws::{AttestedConnection, DefaultStream};
+use crate::infra::AsyncDuplexStream;
+use crate::svr::{Error, SvrConnection};
+
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    /// Connections left idle for longer than this are dropped, not reused.
+    pub max_idle_time: Duration,
+    /// Connections are not reused once their attestation is older than this,
+    /// however recently they were used.
+    pub max_attestation_age: Duration,
+    /// Maximum number of idle connections kept, across all keys.
+    pub max_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_time: Duration::from_secs(30),
+            max_attestation_age: Duration::from_secs(5 * 60),
+            max_size: 8,
+        }
+    }
+}
+
+type PoolKey = (&'static str, Uid);
+
+struct IdleConnection<S> {
+    connection: AttestedConnection<S>,
+    attested_at: Instant,
+}
+
+pub struct SvrConnectionPool<S = DefaultStream> {
+    config: PoolConfig,
+    idle: Mutex<HashMap<PoolKey, Vec<IdleConnection<S>>>>,
+}
+
+/// A connection checked out of a [`SvrConnectionPool`].
+///
+/// Hand it back with [`SvrConnectionPool::checkin`] once the operation is
+/// done; dropping it instead just closes the connection.
+pub struct PooledConnection<E: Svr3Flavor, S = DefaultStream> {
+    connection: SvrConnection<E, S>,
+    uid: Uid,
+    attested_at: Instant,
+}
+
+impl<E: Svr3Flavor, S> PooledConnection<E, S> {
+    pub fn into_inner(self) -> SvrConnection<E, S> {
+        self.connection
+    }
+}
+
+impl<E: Svr3Flavor, S> Deref for PooledConnection<E, S> {
+    type Target = SvrConnection<E, S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl<E: Svr3Flavor, S> DerefMut for PooledConnection<E, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.connection
+    }
+}
+
+impl<S: AsyncDuplexStream> SvrConnectionPool<S> {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a pooled connection to an `E` enclave for `uid` if there is a
+    /// usable one, or else a new one made with `connect`.
+    pub async fn get_or_connect<E, F, Fut>(
+        &self,
+        uid: Uid,
+        connect: F,
+    ) -> Result<PooledConnection<E, S>, Error>
+    where
+        E: Svr3Flavor,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<SvrConnection<E, S>, Error>>,
+    {
+        if let Some(pooled) = self.checkout(uid) {
+            return Ok(pooled);
+        }
+        let connection = connect().await?;
+        Ok(PooledConnection {
+            connection,
+            uid,
+            attested_at: Instant::now(),
+        })
+    }
+
+    /// Takes the most recently used connection to an `E` enclave for `uid` out
+    /// of the pool, if there is one that can still be used.
+    pub fn checkout<E: Svr3Flavor>(&self, uid: Uid) -> Option<PooledConnection<E, S>> {
+        let now = Instant::now();
+        let key = (E::flavor_name(), uid);
+        let mut idle = self.idle.lock().expect("not poisoned");
+        let connections = idle.get_mut(&key)?;
+        let found = loop {
+            let Some(candidate) = connections.pop() else {
+                break None;
+            };
+            if self.is_reusable(&candidate, now) {
+                break Some(candidate);
+            }
+        };
+        if connections.is_empty() {
+            let _ = idle.remove(&key);
+        }
+        found.map(
+            |IdleConnection {
+                 connection,
+                 attested_at,
+             }| PooledConnection {
+                connection: SvrConnection::new(connection),
+                uid,
+                attested_at,
+            },
+        )
+    }
+
+    /// Makes `connection` available to later operations for the same enclave
+    /// and UID.
+    ///
+    /// The connection is dropped instead if it is closed, its attestation is
+    /// too old, or the pool is full.
+    pub fn checkin<E: Svr3Flavor>(&self, connection: PooledConnection<E, S>) {
+        let now = Instant::now();
+        let PooledConnection {
+            connection,
+            uid,
+            attested_at,
+        } = connection;
+        let entry = IdleConnection {
+            connection: connection.into(),
+            attested_at,
+        };
+        if !self.is_reusable(&entry, now) {
+            return;
+        }
+        let mut idle = self.idle.lock().expect("not poisoned");
+        self.evict_stale(&mut idle, now);
+        if idle.values().map(Vec::len).sum::<usize>() >= self.config.max_size {
+            return;
+        }
+        idle.entry((E::flavor_name(), uid)).or_default().push(entry);
+    }
+
+    /// Number of connections waiting to be reused.
+    pub fn idle_count(&self) -> usize {
+        let idle = self.idle.lock().expect("not poisoned");
+        idle.values().map(Vec::len).sum()
+    }
+
+    /// Drops the idle connections that can no longer be reused.
+    pub fn evict_expired(&self) {
+        let mut idle = self.idle.lock().expect("not poisoned");
+        self.evict_stale(&mut idle, Instant::now());
+    }
+
+    fn evict_stale(&self, idle: &mut HashMap<PoolKey, Vec<IdleConnection<S>>>, now: Instant) {
+        idle.retain(|_, connections| {
+            connections.retain(|c| self.is_reusable(c, now));
+            !connections.is_empty()
+        });
+    }
+
+    fn is_reusable(&self, idle: &IdleConnection<S>, now: Instant) -> bool {
+        !idle.connection.is_closed()
+            && !idle.connection.network_changed()
+            && idle.connection.time_since_last_activity() <= self.config.max_idle_time
+            && now.duration_since(idle.attested_at) <= self.config.max_attestation_age
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::enclave::{Nitro, Sgx};
+    use crate::infra::network_state::NetworkState;
+    use crate::infra::ws::testutil::{
+        fake_websocket, run_attested_echo_server, websocket_test_client,
+    };
+    use crate::proto::chat_websocket::WebSocketRequestMessage;
+
+    const UID: Uid = [1; 16];
+    const OTHER_UID: Uid = [2; 16];
+
+    type TestPool = SvrConnectionPool<DuplexStream>;
+
+    /// Connects to an echo server, returning the task running it as well.
+    async fn echo_connection() -> (SvrConnection<Sgx, DuplexStream>, JoinHandle<()>) {
+        let (server, client) = fake_websocket().await;
+        let server = tokio::spawn(run_attested_echo_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+        ));
+        let attested = AttestedConnection::connect(websocket_test_client(client), |_| {
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake succeeds");
+        (SvrConnection::new(attested), server)
+    }
+
+    /// Checks out a connection for `uid`, counting the new connections made.
+    async fn get(
+        pool: &TestPool,
+        uid: Uid,
+        connects: &AtomicUsize,
+    ) -> PooledConnection<Sgx, DuplexStream> {
+        pool.get_or_connect(uid, || async {
+            connects.fetch_add(1, Ordering::SeqCst);
+            Ok(echo_connection().await.0)
+        })
+        .await
+        .expect("can connect")
+    }
+
+    /// Like [`get`], but new connections close themselves after sitting
+    /// unused for `idle_timeout`.
+    async fn get_with_idle_timeout(
+        pool: &TestPool,
+        connects: &AtomicUsize,
+        idle_timeout: Duration,
+    ) -> PooledConnection<Sgx, DuplexStream> {
+        pool.get_or_connect(UID, || async {
+            connects.fetch_add(1, Ordering::SeqCst);
+            let attested = AttestedConnection::from(echo_connection().await.0);
+            Ok(SvrConnection::new(attested.with_idle_timeout(idle_timeout)))
+        })
+        .await
+        .expect("can connect")
+    }
+
+    #[tokio::test]
+    async fn checked_in_connection_is_reused() {
+        let pool = TestPool::new(PoolConfig::default());
+        let connects = AtomicUsize::new(0);
+
+        let connection = get(&pool, UID, &connects).await;
+        pool.checkin(connection);
+        assert_eq!(pool.idle_count(), 1);
+
+        let mut connection = get(&pool, UID, &connects).await;
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_count(), 0);
+
+        // The reused connection still works.
+        let message = WebSocketRequestMessage {
+            id: Some(1),
+            ..Default::default()
+        };
+        connection
+            .send_typed(message.clone())
+            .await
+            .expect("can send");
+        let received: WebSocketRequestMessage = connection.recv_typed().await.expect("can receive");
+        assert_eq!(received, message);
+        pool.checkin(connection);
+
+        // Connections are only handed out for the same UID and enclave.
+        assert!(pool.checkout::<Sgx>(OTHER_UID).is_none());
+        assert!(pool.checkout::<Nitro>(UID).is_none());
+        assert!(pool.checkout::<Sgx>(UID).is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrent_checkouts_get_separate_connections() {
+        let pool = TestPool::new(PoolConfig::default());
+        let connects = AtomicUsize::new(0);
+        pool.checkin(get(&pool, UID, &connects).await);
+
+        let first = get(&pool, UID, &connects).await;
+        let second = get(&pool, UID, &connects).await;
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+
+        pool.checkin(first);
+        pool.checkin(second);
+        assert_eq!(pool.idle_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_connections_are_evicted() {
+        let config = PoolConfig {
+            max_idle_time: Duration::from_secs(10),
+            max_attestation_age: Duration::from_secs(60),
+            max_size: 8,
+        };
+        let pool = TestPool::new(config);
+        let connects = AtomicUsize::new(0);
+        let connection = get(&pool, UID, &connects).await;
+        tokio::time::pause();
+
+        pool.checkin(connection);
+        tokio::time::advance(Duration::from_secs(5)).await;
+        pool.evict_expired();
+        assert_eq!(pool.idle_count(), 1);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        pool.evict_expired();
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn connections_expire_with_their_attestation() {
+        let config = PoolConfig {
+            max_idle_time: Duration::from_secs(60 * 60),
+            max_attestation_age: Duration::from_secs(60),
+            max_size: 8,
+        };
+        let pool = TestPool::new(config);
+        let connects = AtomicUsize::new(0);
+        let connection = get(&pool, UID, &connects).await;
+        tokio::time::pause();
+
+        tokio::time::advance(Duration::from_secs(59)).await;
+        pool.checkin(connection);
+        assert_eq!(pool.idle_count(), 1);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        pool.evict_expired();
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn connections_closed_for_being_idle_are_replaced() {
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+        // Only the connections' own idle timers expire anything here.
+        let pool = TestPool::new(PoolConfig {
+            max_idle_time: Duration::from_secs(60 * 60),
+            max_attestation_age: Duration::from_secs(60 * 60),
+            max_size: 8,
+        });
+        let connects = AtomicUsize::new(0);
+        let get = || get_with_idle_timeout(&pool, &connects, IDLE_TIMEOUT);
+        pool.checkin(get().await);
+        tokio::time::pause();
+
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        let connection = get().await;
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert!(connection.time_since_last_activity() >= Duration::from_secs(29));
+        pool.checkin(connection);
+
+        // Checking a connection in and out doesn't count as using it, so it is
+        // closed soon after, and replaced instead of being handed out.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(pool.idle_count(), 1);
+        let connection = get().await;
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert!(!connection.is_closed());
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_not_reused() {
+        let pool = TestPool::new(PoolConfig::default());
+        let (connection, server) = echo_connection().await;
+        let mut connection = pool
+            .get_or_connect(UID, || async { Ok(connection) })
+            .await
+            .expect("can connect");
+
+        server.abort();
+        let _ = server.await;
+        connection
+            .send_typed(WebSocketRequestMessage::default())
+            .await
+            .expect_err("server is gone");
+        assert!(connection.is_closed());
+
+        pool.checkin(connection);
+        assert_eq!(pool.idle_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn pool_size_is_limited() {
+        let pool = TestPool::new(PoolConfig {
+            max_size: 1,
+            ..PoolConfig::default()
+        });
+        let connects = AtomicUsize::new(0);
+        let first = get(&pool, UID, &connects).await;
+        let second = get(&pool, OTHER_UID, &connects).await;
+
+        pool.checkin(first);
+        pool.checkin(second);
+        assert_eq!(pool.idle_count(), 1);
+        assert!(pool.checkout::<Sgx>(UID).is_some());
+    }
+
+    #[tokio::test]
+    async fn connections_from_before_a_network_change_are_not_reused() {
+        let pool = TestPool::new(PoolConfig::default());
+        let network_state = Arc::new(NetworkState::new());
+        let connection = pool
+            .get_or_connect(UID, || async {
+                let attested = AttestedConnection::from(echo_connection().await.0);
+                Ok(SvrConnection::new(
+                    attested.with_network(network_state.watch()),
+                ))
+            })
+            .await
+            .expect("can connect");
+        pool.checkin(connection);
+        assert_eq!(pool.idle_count(), 1);
+
+        network_state.mark_changed();
+        assert!(pool.checkout::<Sgx>(UID).is_none());
+        assert_eq!(pool.idle_count(), 0);
+    }
+}
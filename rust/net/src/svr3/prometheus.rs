@@ -0,0 +1,322 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! SVR3 metrics in the Prometheus text exposition format.
+//!
+//! [`PrometheusMetrics`] is fed from the existing reporting hooks: as a
+//! [`RequestLogger`] it sees every operation run through
+//! [`PpssOps`](super::PpssOps), and [`PrometheusMetrics::record_connect`]
+//! takes the [`EnclaveDiagnostics`] of a connection attempt.
+//! [`PrometheusMetrics::gather`] renders everything recorded so far, to be
+//! served from a `/metrics` endpoint; see the `svr3_metrics` example.
+//!
+//! Series are labeled by enclave [name](crate::enclave::Svr3Flavor::flavor_name)
+//! and by the log-safe route description, so no user data ends up in them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use super::diagnostics::EnclaveDiagnostics;
+use super::request_log::{RequestEvent, RequestLogger};
+use crate::svr::ErrorCategory;
+
+/// Upper bounds, in seconds, of the operation latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counts connection attempts and operations, for a Prometheus scraper.
+///
+/// All methods can be called from any thread; each takes a lock only long
+/// enough to update or copy out the counts.
+#[derive(Default)]
+pub struct PrometheusMetrics(Mutex<Series>);
+
+#[derive(Default)]
+struct Series {
+    /// By enclave and route.
+    connects: BTreeMap<(&'static str, String), u64>,
+    /// By enclave, route, and reason.
+    connect_failures: BTreeMap<(&'static str, String, &'static str), u64>,
+    /// By enclave and route.
+    attestation_failures: BTreeMap<(&'static str, String), u64>,
+    /// By operation and outcome.
+    request_durations: BTreeMap<(&'static str, &'static str), Histogram>,
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Not cumulative; [`PrometheusMetrics::gather`] adds them up.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a connection attempt, and its failure if it failed.
+    ///
+    /// Attempts that never got as far as picking a route are counted under
+    /// the route `"none"`.
+    pub fn record_connect(&self, diagnostics: &EnclaveDiagnostics) {
+        let enclave = diagnostics.enclave;
+        let route = diagnostics.route.as_deref().unwrap_or("none").to_owned();
+        let mut series = self.0.lock().expect("not poisoned");
+        *series.connects.entry((enclave, route.clone())).or_default() += 1;
+        let Some(category) = diagnostics.error else {
+            return;
+        };
+        if category == ErrorCategory::Attestation {
+            *series
+                .attestation_failures
+                .entry((enclave, route.clone()))
+                .or_default() += 1;
+        }
+        *series
+            .connect_failures
+            .entry((enclave, route, reason(category)))
+            .or_default() += 1;
+    }
+
+    /// Everything recorded so far, in the Prometheus text exposition format.
+    pub fn gather(&self) -> String {
+        let series = self.0.lock().expect("not poisoned");
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "svr3_connects_total",
+            "counter",
+            "Connection attempts to SVR3 enclaves.",
+        );
+        for ((enclave, route), count) in &series.connects {
+            write_sample(
+                &mut out,
+                "svr3_connects_total",
+                &[("enclave", enclave), ("route", route)],
+                *count,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "svr3_connect_failures_total",
+            "counter",
+            "Failed connection attempts to SVR3 enclaves, by reason.",
+        );
+        for ((enclave, route, reason), count) in &series.connect_failures {
+            write_sample(
+                &mut out,
+                "svr3_connect_failures_total",
+                &[("enclave", enclave), ("route", route), ("reason", reason)],
+                *count,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "svr3_attestation_failures_total",
+            "counter",
+            "Connection attempts to SVR3 enclaves that failed attestation.",
+        );
+        for ((enclave, route), count) in &series.attestation_failures {
+            write_sample(
+                &mut out,
+                "svr3_attestation_failures_total",
+                &[("enclave", enclave), ("route", route)],
+                *count,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "svr3_request_duration_seconds",
+            "histogram",
+            "Duration of SVR3 operations, including password strengthening.",
+        );
+        for ((operation, outcome), histogram) in &series.request_durations {
+            let labels = [("operation", *operation), ("outcome", *outcome)];
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let le = bound.to_string();
+                write_sample(
+                    &mut out,
+                    "svr3_request_duration_seconds_bucket",
+                    &[labels[0], labels[1], ("le", le.as_str())],
+                    cumulative,
+                );
+            }
+            write_sample(
+                &mut out,
+                "svr3_request_duration_seconds_bucket",
+                &[labels[0], labels[1], ("le", "+Inf")],
+                histogram.count,
+            );
+            write_sample(
+                &mut out,
+                "svr3_request_duration_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            write_sample(
+                &mut out,
+                "svr3_request_duration_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+
+        out
+    }
+}
+
+/// Records the latency of every operation, by operation and outcome.
+impl RequestLogger for PrometheusMetrics {
+    fn on_request(&self, event: RequestEvent) {
+        self.0
+            .lock()
+            .expect("not poisoned")
+            .request_durations
+            .entry((event.operation, event.outcome))
+            .or_default()
+            .observe(event.duration.as_secs_f64());
+    }
+}
+
+/// The `reason` label for failures in `category`.
+fn reason(category: ErrorCategory) -> &'static str {
+    match category {
+        ErrorCategory::Timeout => "timeout",
+        ErrorCategory::RateLimited => "rate_limited",
+        ErrorCategory::TcpConnect => "tcp_connect",
+        ErrorCategory::ConnectionLost => "connection_lost",
+        ErrorCategory::Network => "network",
+        ErrorCategory::Attestation => "attestation",
+        ErrorCategory::Protocol => "protocol",
+        ErrorCategory::Cancelled => "cancelled",
+        ErrorCategory::ClientDeprecated => "client_deprecated",
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").expect("can write to String");
+    writeln!(out, "# TYPE {name} {kind}").expect("can write to String");
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl std::fmt::Display,
+) {
+    out.push_str(name);
+    out.push('{');
+    for (i, (label, label_value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(out, "{label}=\"").expect("can write to String");
+        for c in label_value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    writeln!(out, "}} {value}").expect("can write to String");
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn diagnostics(
+        enclave: &'static str,
+        route: Option<&str>,
+        error: Option<ErrorCategory>,
+    ) -> EnclaveDiagnostics {
+        EnclaveDiagnostics {
+            enclave,
+            error,
+            route: route.map(str::to_owned),
+            websocket_millis: None,
+            attestation_millis: None,
+        }
+    }
+
+    fn request(operation: &'static str, outcome: &'static str, millis: u64) -> RequestEvent {
+        RequestEvent {
+            operation,
+            uid: Some([0xab; 16]),
+            correlation_id: None,
+            duration: Duration::from_millis(millis),
+            bytes_sent: 0,
+            bytes_received: 0,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn gather_renders_recorded_series() {
+        let metrics = PrometheusMetrics::new();
+        metrics.record_connect(&diagnostics("sgx", Some("direct"), None));
+        metrics.record_connect(&diagnostics(
+            "sgx",
+            Some("direct"),
+            Some(ErrorCategory::Attestation),
+        ));
+        metrics.record_connect(&diagnostics("nitro", None, Some(ErrorCategory::Timeout)));
+        metrics.on_request(request("backup", "success", 250));
+        metrics.on_request(request("backup", "success", 3000));
+
+        let text = metrics.gather();
+        for expected in [
+            "# TYPE svr3_connects_total counter\n",
+            "svr3_connects_total{enclave=\"nitro\",route=\"none\"} 1\n",
+            "svr3_connects_total{enclave=\"sgx\",route=\"direct\"} 2\n",
+            "svr3_connect_failures_total{enclave=\"nitro\",route=\"none\",reason=\"timeout\"} 1\n",
+            "svr3_connect_failures_total{enclave=\"sgx\",route=\"direct\",reason=\"attestation\"} 1\n",
+            "svr3_attestation_failures_total{enclave=\"sgx\",route=\"direct\"} 1\n",
+            "# TYPE svr3_request_duration_seconds histogram\n",
+            "svr3_request_duration_seconds_bucket{operation=\"backup\",outcome=\"success\",le=\"0.1\"} 0\n",
+            "svr3_request_duration_seconds_bucket{operation=\"backup\",outcome=\"success\",le=\"0.25\"} 1\n",
+            "svr3_request_duration_seconds_bucket{operation=\"backup\",outcome=\"success\",le=\"2.5\"} 1\n",
+            "svr3_request_duration_seconds_bucket{operation=\"backup\",outcome=\"success\",le=\"5\"} 2\n",
+            "svr3_request_duration_seconds_bucket{operation=\"backup\",outcome=\"success\",le=\"+Inf\"} 2\n",
+            "svr3_request_duration_seconds_sum{operation=\"backup\",outcome=\"success\"} 3.25\n",
+            "svr3_request_duration_seconds_count{operation=\"backup\",outcome=\"success\"} 2\n",
+        ] {
+            assert!(text.contains(expected), "missing {expected:?} in:\n{text}");
+        }
+        assert!(!text.contains("abab"), "no user identifiers: {text}");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = PrometheusMetrics::new();
+        metrics.record_connect(&diagnostics("sgx", Some("a\"b\\c\nd"), None));
+        assert!(metrics
+            .gather()
+            .contains("svr3_connects_total{enclave=\"sgx\",route=\"a\\\"b\\\\c\\nd\"} 1\n"));
+    }
+}
@@ -0,0 +1,180 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Network-level reachability checks for the SVR3 enclaves.
+//!
+//! [`Svr3Env::assert_reachable`] only opens a TCP connection to each enclave
+//! host, skipping TLS, the websocket upgrade, and attestation, so it is much
+//! quicker than [`diagnose`](super::diagnostics::diagnose) on a poor network.
+//! It can't tell whether an enclave is actually working, only whether the
+//! device can get to it.
+
+use futures_util::future::join;
+use thiserror::Error;
+
+use crate::enclave::{Nitro, Sgx, Svr3Flavor};
+use crate::env::Svr3Env;
+use crate::infra::errors::{LogSafeDisplay, NetError};
+use crate::infra::{ConnectionParams, TcpConnector};
+
+#[derive(Debug, displaydoc::Display, Error)]
+pub enum ReachabilityError {
+    /// {enclave} enclave host is unreachable: {reason}
+    Unreachable {
+        /// The [flavor name](Svr3Flavor::flavor_name) of the enclave.
+        enclave: &'static str,
+        host: String,
+        port: u16,
+        reason: NetError,
+    },
+    /// several enclave hosts are unreachable
+    Multiple(Vec<ReachabilityError>),
+}
+
+/// Hosts are shown by the name of their enclave; `host` and `port` are there
+/// for callers to inspect, but aren't displayed.
+impl LogSafeDisplay for ReachabilityError {}
+
+impl<'a> Svr3Env<'a> {
+    /// Checks that a TCP connection can be made to every enclave host.
+    ///
+    /// The hosts are tried in parallel. If exactly one of them can't be
+    /// reached, the error is [`ReachabilityError::Unreachable`]; if several
+    /// can't, it is [`ReachabilityError::Multiple`], listing each of them.
+    pub async fn assert_reachable<T: TcpConnector>(
+        &self,
+        connector: T,
+    ) -> Result<(), ReachabilityError> {
+        let sgx = self.sgx().domain_config.connection_params();
+        let nitro = self.nitro().domain_config.connection_params();
+        let (sgx, nitro) = join(
+            check_reachable(&connector, Sgx::flavor_name(), &sgx),
+            check_reachable(&connector, Nitro::flavor_name(), &nitro),
+        )
+        .await;
+
+        let mut errors: Vec<_> = [sgx, nitro].into_iter().filter_map(Result::err).collect();
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(ReachabilityError::Multiple(errors)),
+        }
+    }
+
+    /// Like [`Self::assert_reachable`], but only reports whether all the
+    /// enclave hosts could be reached.
+    pub async fn is_reachable<T: TcpConnector>(&self, connector: T) -> bool {
+        match self.assert_reachable(connector).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::info!("SVR3 is not reachable: {e}");
+                false
+            }
+        }
+    }
+}
+
+async fn check_reachable<T: TcpConnector>(
+    connector: &T,
+    enclave: &'static str,
+    params: &ConnectionParams,
+) -> Result<(), ReachabilityError> {
+    match connector.connect_tcp(&params.host, params.port).await {
+        // Nothing is sent over the connection; it's closed right away.
+        Ok(_stream) => Ok(()),
+        Err(reason) => Err(ReachabilityError::Unreachable {
+            enclave,
+            host: params.host.to_string(),
+            port: params.port,
+            reason,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Connects to a local port, whatever the host.
+    #[derive(Clone)]
+    struct LocalConnector {
+        port: u16,
+    }
+
+    #[async_trait]
+    impl TcpConnector for LocalConnector {
+        async fn connect_tcp(&self, _host: &str, _port: u16) -> Result<TcpStream, NetError> {
+            TcpStream::connect(("127.0.0.1", self.port))
+                .await
+                .map_err(|_| NetError::Failure)
+        }
+    }
+
+    #[tokio::test]
+    async fn listening_hosts_are_reachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let connector = LocalConnector {
+            port: listener.local_addr().expect("bound").port(),
+        };
+
+        let env = &crate::env::STAGING.svr3;
+        env.assert_reachable(connector.clone())
+            .await
+            .expect("reachable");
+        assert!(env.is_reachable(connector).await);
+    }
+
+    #[tokio::test]
+    async fn every_unreachable_host_is_reported() {
+        // Nothing listens on the port once the listener is gone.
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let connector = LocalConnector {
+            port: listener.local_addr().expect("bound").port(),
+        };
+        drop(listener);
+
+        let env = &crate::env::STAGING.svr3;
+        let errors = assert_matches!(
+            env.assert_reachable(connector.clone()).await,
+            Err(ReachabilityError::Multiple(errors)) => errors
+        );
+        let hosts: Vec<_> = errors
+            .iter()
+            .map(|e| {
+                assert_matches!(
+                    e,
+                    ReachabilityError::Unreachable { host, port: 443, .. } => host.as_str()
+                )
+            })
+            .collect();
+        assert_eq!(
+            hosts,
+            [
+                env.sgx().domain_config.hostname,
+                env.nitro().domain_config.hostname
+            ]
+        );
+        assert!(!env.is_reachable(connector).await);
+    }
+
+    #[test]
+    fn unreachable_hosts_are_displayed_by_enclave() {
+        let host = crate::env::STAGING.svr3.sgx().domain_config.hostname;
+        let error = ReachabilityError::Unreachable {
+            enclave: Sgx::flavor_name(),
+            host: host.to_string(),
+            port: 443,
+            reason: NetError::Failure,
+        };
+        assert_eq!(
+            error.to_string(),
+            "sgx enclave host is unreachable: Failure"
+        );
+    }
+}
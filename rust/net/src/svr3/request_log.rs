@@ -0,0 +1,185 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Per-request reporting of SVR3 operations, for capacity planning.
+//!
+//! A [`RequestLogger`] set with
+//! [`Svr3Env::with_request_logger`](crate::env::Svr3Env::with_request_logger)
+//! is told about every backup, restore, removal, secret rotation, and
+//! conditional backup run through [`PpssOps`](super::PpssOps), whether it
+//! succeeded or not. Queries are not reported. Events never include
+//! passwords, secrets, or share sets.
+//!
+//! Operations whose connections were made with different correlation IDs are
+//! reported with the first one found.
+//!
+//! [`BlockingSvr3Client`](super::blocking::BlockingSvr3Client) also reports
+//! each operation it runs again over new connections, after the ones it was
+//! given turned out to be stale, with a [`RecoveryEvent`].
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::{Error, Uid};
+use crate::infra::correlation::CorrelationId;
+use crate::infra::ws::{AttestedConnectionLike, TrafficMeter};
+
+/// One completed SVR3 operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestEvent {
+    /// `"backup"`, `"restore"`, `"remove"`, `"rotate"`, or
+    /// `"backup_if_changed"`.
+    pub operation: &'static str,
+    /// The account the connections were authenticated for.
+    ///
+    /// `None` if their username isn't a hex-encoded UID, which is the case
+    /// for connections not made with
+    /// [`Auth::from_uid_and_secret`](crate::auth::Auth::from_uid_and_secret).
+    pub uid: Option<Uid>,
+    /// The ID the connections were made with, so that the operation can be
+    /// found in the enclaves' logs; see
+    /// [`SvrConnection::connect_with_correlation_id`](crate::svr::SvrConnection::connect_with_correlation_id).
+    pub correlation_id: Option<CorrelationId>,
+    /// From when the operation was started until it finished, including
+    /// password strengthening.
+    pub duration: Duration,
+    /// Plaintext bytes sent to all the enclaves together.
+    pub bytes_sent: u64,
+    /// Plaintext bytes received from all the enclaves together.
+    pub bytes_received: u64,
+    /// `"success"`, or the kind of [`Error`] the operation failed with, e.g.
+    /// `"restore_failed"`.
+    pub outcome: &'static str,
+}
+
+/// An operation run again over new connections, because the ones it was given
+/// had been lost before it could send its request.
+///
+/// The failed first attempt is reported with a [`RequestEvent`] of its own.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecoveryEvent {
+    /// As in [`RequestEvent::operation`], or `"query"`.
+    pub operation: &'static str,
+    /// The ID the lost connections were made with.
+    pub correlation_id: Option<CorrelationId>,
+    /// The kind of [`Error`] the first attempt failed with, as in
+    /// [`RequestEvent::outcome`].
+    pub outcome: &'static str,
+}
+
+/// Receives a [`RequestEvent`] after each SVR3 operation.
+///
+/// It is called on the task that ran the operation, so it shouldn't block.
+pub trait RequestLogger {
+    fn on_request(&self, event: RequestEvent);
+
+    /// Called before an operation is run again over new connections.
+    ///
+    /// Recoveries are rare enough that the default drops them.
+    fn on_recovery(&self, _event: RecoveryEvent) {}
+}
+
+/// Drops every event; the logger used unless another one is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopRequestLogger;
+
+impl RequestLogger for NoopRequestLogger {
+    fn on_request(&self, _event: RequestEvent) {}
+}
+
+/// Measures one operation over `connections`, to be reported once it is done.
+pub(super) struct RequestRecorder {
+    operation: &'static str,
+    uid: Option<Uid>,
+    correlation_id: Option<CorrelationId>,
+    start: Instant,
+    meters: Vec<TrafficMeter>,
+}
+
+impl RequestRecorder {
+    pub(super) fn start<C: AttestedConnectionLike>(
+        operation: &'static str,
+        connections: &[C],
+    ) -> Self {
+        Self {
+            operation,
+            uid: connections.iter().find_map(C::uid),
+            correlation_id: connections.iter().find_map(C::correlation_id),
+            start: Instant::now(),
+            meters: connections.iter().map(C::traffic_meter).collect(),
+        }
+    }
+
+    pub(super) fn finish<T>(self, logger: &dyn RequestLogger, result: &Result<T, Error>) {
+        let (bytes_sent, bytes_received) =
+            self.meters
+                .iter()
+                .map(TrafficMeter::read)
+                .fold((0, 0), |(sent, received), traffic| {
+                    (sent + traffic.bytes_sent, received + traffic.bytes_received)
+                });
+        logger.on_request(RequestEvent {
+            operation: self.operation,
+            uid: self.uid,
+            correlation_id: self.correlation_id,
+            duration: self.start.elapsed(),
+            bytes_sent,
+            bytes_received,
+            outcome: match result {
+                Ok(_) => "success",
+                Err(e) => outcome(e),
+            },
+        })
+    }
+}
+
+pub(super) fn outcome(error: &Error) -> &'static str {
+    match error {
+        Error::Net(_) => "net_error",
+        Error::RequestNotSent(_) => "request_not_sent",
+        Error::Protocol(_) => "protocol_error",
+        Error::AttestationError(_) => "attestation_failed",
+        Error::RequestFailed(_) => "request_failed",
+        Error::RestoreFailed(_) => "restore_failed",
+        Error::DataMissing => "data_missing",
+        Error::Cancelled => "cancelled",
+        Error::NetworkChanged => "network_changed",
+        Error::EnvironmentMismatch => "environment_mismatch",
+        Error::Strengthening(_) => "strengthening_failed",
+        Error::EnclaveDisagreement { .. } => "enclave_disagreement",
+        Error::InvalidBackupParams(_) => "invalid_backup_params",
+    }
+}
+
+/// Keeps every event it is given, for tests to check.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct CapturingRequestLogger {
+    requests: std::sync::Mutex<Vec<RequestEvent>>,
+    recoveries: std::sync::Mutex<Vec<RecoveryEvent>>,
+}
+
+#[cfg(test)]
+impl CapturingRequestLogger {
+    pub(crate) fn take(&self) -> Vec<RequestEvent> {
+        std::mem::take(&mut self.requests.lock().expect("not poisoned"))
+    }
+
+    pub(crate) fn take_recoveries(&self) -> Vec<RecoveryEvent> {
+        std::mem::take(&mut self.recoveries.lock().expect("not poisoned"))
+    }
+}
+
+#[cfg(test)]
+impl RequestLogger for CapturingRequestLogger {
+    fn on_request(&self, event: RequestEvent) {
+        self.requests.lock().expect("not poisoned").push(event)
+    }
+
+    fn on_recovery(&self, event: RecoveryEvent) {
+        self.recoveries.lock().expect("not poisoned").push(event)
+    }
+}
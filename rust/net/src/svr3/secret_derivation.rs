@@ -0,0 +1,142 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Deriving the secrets backed up with SVR3 from what apps already have.
+
+use std::fmt;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize as _;
+
+use super::{Error, PasswordStrengthener};
+
+/// A secret to back up with [`PpssOps::backup`](super::PpssOps::backup).
+///
+/// The bytes are left out of its `Debug` output, and wiped when it is
+/// dropped.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Secret([u8; 32]);
+
+impl Secret {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Secret {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+/// Derives [`Secret`]s, so that every app gets the same one from the same
+/// inputs.
+pub struct SecretDerivation;
+
+impl SecretDerivation {
+    const PIN_INFO: &'static [u8] = b"Signal-SVR3-pin-to-secret";
+
+    /// Derives the secret for a registration lock `pin`, stretching it with
+    /// `strengthener` salted with `salt`, then expanding the result with
+    /// HKDF-SHA-256 to keep it apart from other keys derived the same way.
+    ///
+    /// PINs are short enough that, unstretched, every one of them could be
+    /// tried offline against anything the secret protects. Use a
+    /// memory-hard strengthener, such as an
+    /// [`Argon2Strengthener`](super::Argon2Strengthener), at least as costly
+    /// as the one backups are made with, and the same one on every device.
+    pub fn from_registration_lock_pin(
+        pin: &str,
+        salt: &[u8; 32],
+        strengthener: &dyn PasswordStrengthener,
+    ) -> Result<Secret, Error> {
+        let key = strengthener.strengthen(pin.as_bytes(), salt)?;
+        let mut secret = Secret([0; 32]);
+        Hkdf::<Sha256>::new(None, key.as_ref())
+            .expand(Self::PIN_INFO, &mut secret.0)
+            .expect("valid output length");
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::svr3::Argon2Strengthener;
+
+    const SALT: [u8; 32] = [0x5a; 32];
+
+    /// Far cheaper than anything fit for production, to keep the tests fast.
+    const STRENGTHENER: Argon2Strengthener = Argon2Strengthener {
+        memory_kb: 256,
+        iterations: 2,
+        parallelism: 1,
+    };
+
+    fn derive(pin: &str, salt: &[u8; 32]) -> Secret {
+        SecretDerivation::from_registration_lock_pin(pin, salt, &STRENGTHENER)
+            .expect("valid parameters")
+    }
+
+    #[test]
+    fn pin_derivation_matches_argon2id_then_hkdf_sha256() {
+        assert_eq!(
+            derive("1234", &SALT).as_bytes(),
+            &hex!("91f1707465f0959a9a81c94a9a99eb5f83828f4b9fd01cc8b3222a58afaad33d")
+        );
+    }
+
+    #[test]
+    fn pin_derivation_depends_on_pin_salt_and_strengthener() {
+        let secret = derive("1234", &SALT);
+        assert_eq!(derive("1234", &SALT), secret);
+        assert_ne!(derive("1235", &SALT), secret);
+        assert_ne!(derive("", &SALT), secret);
+        assert_ne!(derive("1234", &[0x5b; 32]), secret);
+
+        let costlier = Argon2Strengthener {
+            iterations: 3,
+            ..STRENGTHENER
+        };
+        assert_ne!(
+            SecretDerivation::from_registration_lock_pin("1234", &SALT, &costlier)
+                .expect("valid parameters"),
+            secret
+        );
+    }
+
+    #[test]
+    fn pin_derivation_reports_invalid_parameters() {
+        let invalid = Argon2Strengthener {
+            memory_kb: 0,
+            ..STRENGTHENER
+        };
+        assert_matches!(
+            SecretDerivation::from_registration_lock_pin("1234", &SALT, &invalid),
+            Err(Error::Strengthening(_))
+        );
+    }
+
+    #[test]
+    fn debug_output_leaves_out_the_bytes() {
+        assert_eq!(format!("{:?}", Secret::from([0xab; 32])), "Secret(..)");
+    }
+}
@@ -0,0 +1,468 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Storage for the share sets returned by SVR3 backups.
+//!
+//! A share set is needed to restore the secret it was backed up with, so
+//! clients have to keep one around per account. [`ShareSetStore`] abstracts
+//! over where they are kept: [`InMemoryShareSetStore`] for tests and
+//! short-lived tools, and (on Unix) [`FileShareSetStore`] to keep them across
+//! runs.
+
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU32;
+
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+
+use super::{OpaqueMaskedShareSet, Uid};
+
+/// A share set, along with a commitment to what was backed up with it.
+///
+/// The commitment lets [`PpssOps::backup_if_changed`](super::PpssOps::backup_if_changed)
+/// tell whether backing up again would change anything without asking the
+/// enclaves, which would use up a try. It is keyed with the secret, so unlike
+/// the share set it can't be used to check guesses of the password.
+#[derive(Clone)]
+pub struct StoredShareSet {
+    pub share_set: OpaqueMaskedShareSet,
+    commitment: [u8; 32],
+}
+
+impl StoredShareSet {
+    /// Pairs `share_set` with a commitment to the backup it was returned by.
+    pub fn new(
+        share_set: OpaqueMaskedShareSet,
+        password: &str,
+        secret: &[u8; 32],
+        max_tries: NonZeroU32,
+    ) -> Self {
+        let commitment = Self::mac(password, secret, max_tries)
+            .finalize()
+            .into_bytes()
+            .into();
+        Self {
+            share_set,
+            commitment,
+        }
+    }
+
+    /// Whether the share set was backed up with exactly these arguments,
+    /// compared in constant time.
+    pub fn was_backed_up_with(
+        &self,
+        password: &str,
+        secret: &[u8; 32],
+        max_tries: NonZeroU32,
+    ) -> bool {
+        Self::mac(password, secret, max_tries)
+            .verify_slice(&self.commitment)
+            .is_ok()
+    }
+
+    fn mac(password: &str, secret: &[u8; 32], max_tries: NonZeroU32) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length is valid");
+        mac.update(b"libsignal-net SVR3 stored share set");
+        mac.update(&max_tries.get().to_be_bytes());
+        mac.update(password.as_bytes());
+        mac
+    }
+}
+
+/// Keeps the share set of the latest backup for each account.
+pub trait ShareSetStore {
+    fn get(&self, uid: &Uid) -> Option<&StoredShareSet>;
+
+    /// Stores `share_set` for `uid`, replacing any share set stored before.
+    ///
+    /// On failure, the store is left as it was.
+    fn set(&mut self, uid: Uid, share_set: StoredShareSet) -> io::Result<()>;
+
+    /// Forgets the share set for `uid`, returning whether there was one.
+    ///
+    /// On failure, the store is left as it was.
+    fn remove(&mut self, uid: &Uid) -> io::Result<bool>;
+
+    /// The accounts that have a share set stored, in sorted order.
+    fn all_uids(&self) -> Vec<Uid>;
+}
+
+#[derive(Clone, Default)]
+pub struct InMemoryShareSetStore(HashMap<Uid, StoredShareSet>);
+
+impl ShareSetStore for InMemoryShareSetStore {
+    fn get(&self, uid: &Uid) -> Option<&StoredShareSet> {
+        self.0.get(uid)
+    }
+
+    fn set(&mut self, uid: Uid, share_set: StoredShareSet) -> io::Result<()> {
+        let _ = self.0.insert(uid, share_set);
+        Ok(())
+    }
+
+    fn remove(&mut self, uid: &Uid) -> io::Result<bool> {
+        Ok(self.0.remove(uid).is_some())
+    }
+
+    fn all_uids(&self) -> Vec<Uid> {
+        let mut uids: Vec<_> = self.0.keys().copied().collect();
+        uids.sort_unstable();
+        uids
+    }
+}
+
+#[cfg(unix)]
+pub use file::FileShareSetStore;
+
+#[cfg(unix)]
+mod file {
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+    use std::fs::{File, OpenOptions};
+    use std::io::ErrorKind;
+    use std::os::fd::AsRawFd as _;
+    use std::path::{Path, PathBuf};
+
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    /// A [`ShareSetStore`] that writes every change through to a JSON file.
+    ///
+    /// The file holds an object mapping hex-encoded UIDs to objects with the
+    /// base64-encoded [serialized](OpaqueMaskedShareSet::serialize) share set
+    /// and the hex-encoded commitment. It is replaced as a whole on each
+    /// change, and synced to disk along with its directory before the change
+    /// returns, so readers never see a partial write.
+    ///
+    /// While a store is open, it holds an exclusive `flock` on a `.lock` file
+    /// next to the data file, and opening the same path again, from this
+    /// process or another, fails.
+    pub struct FileShareSetStore {
+        path: PathBuf,
+        /// Never read, only kept open: closing it would release the lock.
+        _lock: File,
+        share_sets: InMemoryShareSetStore,
+    }
+
+    /// How a [`StoredShareSet`] is written to the file.
+    #[derive(Serialize, Deserialize)]
+    struct Entry {
+        share_set: String,
+        commitment: String,
+    }
+
+    impl FileShareSetStore {
+        /// Opens the store at `path`, starting out empty if the file doesn't
+        /// exist yet.
+        pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+            let path = path.into();
+            let lock = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(with_suffix(&path, ".lock"))?;
+            lock_exclusive(&lock)?;
+
+            let share_sets = match std::fs::read(&path) {
+                Ok(contents) => parse(&contents)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Default::default(),
+                Err(e) => return Err(e),
+            };
+            Ok(Self {
+                path,
+                _lock: lock,
+                share_sets,
+            })
+        }
+
+        /// Writes the current contents to the file.
+        fn save(&self) -> io::Result<()> {
+            let contents: BTreeMap<String, Entry> = self
+                .share_sets
+                .0
+                .iter()
+                .map(|(uid, stored)| {
+                    let serialized = stored.share_set.serialize().map_err(|_| {
+                        io::Error::new(ErrorKind::InvalidData, "share set can't be serialized")
+                    })?;
+                    let entry = Entry {
+                        share_set: BASE64_STANDARD.encode(serialized),
+                        commitment: hex::encode(stored.commitment),
+                    };
+                    Ok((hex::encode(uid), entry))
+                })
+                .collect::<io::Result<_>>()?;
+
+            let temp_path = with_suffix(&self.path, ".tmp");
+            let temp = File::create(&temp_path)?;
+            serde_json::to_writer(&temp, &contents)?;
+            temp.sync_all()?;
+            std::fs::rename(temp_path, &self.path)?;
+            // The rename itself is only durable once the directory is synced.
+            let dir = match self.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()
+        }
+    }
+
+    impl ShareSetStore for FileShareSetStore {
+        fn get(&self, uid: &Uid) -> Option<&StoredShareSet> {
+            self.share_sets.get(uid)
+        }
+
+        fn set(&mut self, uid: Uid, share_set: StoredShareSet) -> io::Result<()> {
+            let previous = self.share_sets.0.insert(uid, share_set);
+            let result = self.save();
+            if result.is_err() {
+                let _ = match previous {
+                    Some(previous) => self.share_sets.0.insert(uid, previous),
+                    None => self.share_sets.0.remove(&uid),
+                };
+            }
+            result
+        }
+
+        fn remove(&mut self, uid: &Uid) -> io::Result<bool> {
+            let Some(previous) = self.share_sets.0.remove(uid) else {
+                return Ok(false);
+            };
+            let result = self.save();
+            if result.is_err() {
+                let _ = self.share_sets.0.insert(*uid, previous);
+            }
+            result.map(|()| true)
+        }
+
+        fn all_uids(&self) -> Vec<Uid> {
+            self.share_sets.all_uids()
+        }
+    }
+
+    fn parse(contents: &[u8]) -> io::Result<InMemoryShareSetStore> {
+        let invalid =
+            |e: &dyn std::fmt::Display| io::Error::new(ErrorKind::InvalidData, e.to_string());
+        let encoded: BTreeMap<String, Entry> = serde_json::from_slice(contents)?;
+        let share_sets = encoded
+            .into_iter()
+            .map(|(uid, entry)| {
+                let uid = <Uid>::try_from(hex::decode(uid).map_err(|e| invalid(&e))?)
+                    .map_err(|_| invalid(&"UID must be 16 bytes"))?;
+                let serialized = BASE64_STANDARD
+                    .decode(entry.share_set)
+                    .map_err(|e| invalid(&e))?;
+                let share_set =
+                    OpaqueMaskedShareSet::deserialize(&serialized).map_err(|e| invalid(&e))?;
+                let commitment =
+                    <[u8; 32]>::try_from(hex::decode(entry.commitment).map_err(|e| invalid(&e))?)
+                        .map_err(|_| invalid(&"commitment must be 32 bytes"))?;
+                Ok((
+                    uid,
+                    StoredShareSet {
+                        share_set,
+                        commitment,
+                    },
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(InMemoryShareSetStore(share_sets))
+    }
+
+    fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(path);
+        path.push(suffix);
+        path.into()
+    }
+
+    /// Takes an exclusive lock on `file`, failing rather than waiting if it is
+    /// already locked through any other open file, even in this process.
+    fn lock_exclusive(file: &File) -> io::Result<()> {
+        // SAFETY: the descriptor is open for as long as `file` is borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use assert_matches::assert_matches;
+        use nonzero_ext::nonzero;
+
+        use super::super::test::{serialized, stored_share_set};
+        use super::*;
+
+        const UID: Uid = [0xab; 16];
+        const OTHER_UID: Uid = [0xcd; 16];
+
+        /// A path under the temp dir unique to the calling test, with no files
+        /// left over from earlier runs.
+        fn fresh_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!(
+                "libsignal_net_share_sets_{}_{name}.json",
+                std::process::id()
+            ));
+            for suffix in ["", ".lock"] {
+                let _ = std::fs::remove_file(with_suffix(&path, suffix));
+            }
+            let temp_path = with_suffix(&path, ".tmp");
+            let _ = std::fs::remove_file(&temp_path);
+            let _ = std::fs::remove_dir(&temp_path);
+            path
+        }
+
+        #[test]
+        fn changes_are_kept_across_opens() {
+            let path = fresh_path("kept");
+            {
+                let mut store = FileShareSetStore::open(&path).expect("can open");
+                assert!(store.all_uids().is_empty());
+                store.set(UID, stored_share_set(1)).expect("can set");
+                store.set(OTHER_UID, stored_share_set(2)).expect("can set");
+                store.set(UID, stored_share_set(3)).expect("can set");
+            }
+            {
+                let mut store = FileShareSetStore::open(&path).expect("can reopen");
+                assert_eq!(store.all_uids(), [UID, OTHER_UID]);
+                assert_eq!(
+                    serialized(store.get(&UID)),
+                    serialized(Some(&stored_share_set(3)))
+                );
+                assert!(store.get(&UID).expect("present").was_backed_up_with(
+                    "password",
+                    &[3; 32],
+                    nonzero!(10u32)
+                ));
+                assert!(store.remove(&OTHER_UID).expect("can remove"));
+                assert!(!store.remove(&OTHER_UID).expect("can remove"));
+            }
+            let store = FileShareSetStore::open(&path).expect("can reopen");
+            assert_eq!(store.all_uids(), [UID]);
+            assert!(store.get(&OTHER_UID).is_none());
+        }
+
+        #[test]
+        fn same_path_cannot_be_opened_twice() {
+            let path = fresh_path("twice");
+            let store = FileShareSetStore::open(&path).expect("can open");
+            assert_matches!(
+                FileShareSetStore::open(&path),
+                Err(e) if e.kind() == ErrorKind::WouldBlock
+            );
+            drop(store);
+            FileShareSetStore::open(&path).expect("can open once closed");
+        }
+
+        #[test]
+        fn failed_writes_leave_store_unchanged() {
+            let path = fresh_path("failed");
+            let mut store = FileShareSetStore::open(&path).expect("can open");
+            store.set(UID, stored_share_set(1)).expect("can set");
+
+            // Nothing can be written with a directory in the way.
+            std::fs::create_dir(with_suffix(&path, ".tmp")).expect("can create");
+            store
+                .set(UID, stored_share_set(2))
+                .expect_err("can't write");
+            store
+                .set(OTHER_UID, stored_share_set(3))
+                .expect_err("can't write");
+            store.remove(&UID).expect_err("can't write");
+
+            assert_eq!(store.all_uids(), [UID]);
+            assert_eq!(
+                serialized(store.get(&UID)),
+                serialized(Some(&stored_share_set(1)))
+            );
+        }
+
+        #[test]
+        fn malformed_file_is_rejected() {
+            let path = fresh_path("malformed");
+            for contents in [
+                &b"[]"[..],
+                br#"{"abcd": "AQ=="}"#,
+                br#"{"abababababababababababababababab": "AQ=="}"#,
+                br#"{"abababababababababababababababab": {"share_set": "not base64", "commitment": ""}}"#,
+            ] {
+                std::fs::write(&path, contents).expect("can write");
+                assert_matches!(
+                    FileShareSetStore::open(&path),
+                    Err(e) if e.kind() == ErrorKind::InvalidData
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libsignal_svr3::MaskedShareSet;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+
+    /// A share set stored as if backed up with `"password"`, with `commitment`
+    /// filling both the share set's commitment and the secret, so that
+    /// different arguments give distinguishable share sets.
+    pub(super) fn stored_share_set(commitment: u8) -> StoredShareSet {
+        let share_set = OpaqueMaskedShareSet::new(
+            MaskedShareSet {
+                server_ids: vec![1, 2],
+                masked_shares: vec![[0x11; 32], [0x22; 32]],
+                commitment: [commitment; 32],
+                strengthening: None,
+            },
+            [0x44; 32],
+        );
+        StoredShareSet::new(share_set, "password", &[commitment; 32], nonzero!(10u32))
+    }
+
+    pub(super) fn serialized(stored: Option<&StoredShareSet>) -> Vec<u8> {
+        stored
+            .expect("present")
+            .share_set
+            .serialize()
+            .expect("can serialize")
+    }
+
+    #[test]
+    fn in_memory_store_replaces_and_removes() {
+        let mut store: Box<dyn ShareSetStore> = Box::<InMemoryShareSetStore>::default();
+        store.set([2; 16], stored_share_set(1)).expect("can set");
+        store.set([1; 16], stored_share_set(2)).expect("can set");
+        store.set([2; 16], stored_share_set(3)).expect("can set");
+        assert_eq!(store.all_uids(), [[1; 16], [2; 16]]);
+        assert_eq!(
+            serialized(store.get(&[2; 16])),
+            serialized(Some(&stored_share_set(3)))
+        );
+
+        assert!(store.remove(&[1; 16]).expect("can remove"));
+        assert!(!store.remove(&[1; 16]).expect("can remove"));
+        assert!(store.get(&[1; 16]).is_none());
+        assert_eq!(store.all_uids(), [[2; 16]]);
+    }
+
+    #[test]
+    fn commitment_covers_every_backup_argument() {
+        const SECRET: [u8; 32] = [7; 32];
+        let stored = StoredShareSet::new(
+            stored_share_set(1).share_set,
+            "password",
+            &SECRET,
+            nonzero!(10u32),
+        );
+        assert!(stored.was_backed_up_with("password", &SECRET, nonzero!(10u32)));
+        assert!(!stored.was_backed_up_with("password", &[8; 32], nonzero!(10u32)));
+        assert!(!stored.was_backed_up_with("other password", &SECRET, nonzero!(10u32)));
+        assert!(!stored.was_backed_up_with("password", &SECRET, nonzero!(9u32)));
+    }
+}
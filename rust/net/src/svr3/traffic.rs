@@ -0,0 +1,82 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Accounting of what SVR3 operations exchange with each enclave.
+//!
+//! Operations take their connections by value, so the traffic is measured with
+//! an [`EnclaveTrafficMeter`] taken from each connection beforehand:
+//!
+//! ```ignore
+//! let meters = [
+//!     EnclaveTrafficMeter::new(&connections.0),
+//!     EnclaveTrafficMeter::new(&connections.1),
+//! ];
+//! let share_set = env.backup(connections, password, secret, max_tries, None, rng).await?;
+//! let traffic: Vec<EnclaveTraffic> = meters.iter().map(EnclaveTrafficMeter::read).collect();
+//! ```
+//!
+//! [`BlockingSvr3Client`](super::blocking::BlockingSvr3Client) does this for
+//! every operation it runs.
+
+use serde::Serialize;
+
+use super::diagnostics::as_millis;
+use crate::enclave::Svr3Flavor;
+use crate::infra::ws::TrafficMeter;
+use crate::infra::AsyncDuplexStream;
+use crate::svr::SvrConnection;
+
+/// What was exchanged with one enclave.
+///
+/// Byte counts are of the plaintext, before encryption and after decryption.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnclaveTraffic {
+    /// [Name](Svr3Flavor::flavor_name) of the enclave, e.g. `"sgx"`.
+    pub enclave: &'static str,
+    pub requests_sent: u32,
+    pub responses_received: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Wall-clock time from sending each request to receiving its response,
+    /// for up to the latest [`MAX_ROUND_TRIP_TIMES`](crate::infra::ws::MAX_ROUND_TRIP_TIMES)
+    /// requests.
+    pub request_millis: Vec<u64>,
+}
+
+/// Measures the traffic over a connection to one enclave, from the time the
+/// meter was taken.
+#[derive(Clone, Debug)]
+pub struct EnclaveTrafficMeter {
+    enclave: &'static str,
+    meter: TrafficMeter,
+}
+
+impl EnclaveTrafficMeter {
+    pub fn new<Flavor: Svr3Flavor, S: AsyncDuplexStream>(
+        connection: &SvrConnection<Flavor, S>,
+    ) -> Self {
+        Self {
+            enclave: connection.flavor_name(),
+            meter: connection.traffic_meter(),
+        }
+    }
+
+    pub fn read(&self) -> EnclaveTraffic {
+        let traffic = self.meter.read();
+        EnclaveTraffic {
+            enclave: self.enclave,
+            requests_sent: traffic.requests_sent,
+            responses_received: traffic.responses_received,
+            bytes_sent: traffic.bytes_sent,
+            bytes_received: traffic.bytes_received,
+            request_millis: traffic
+                .round_trip_times
+                .into_iter()
+                .map(as_millis)
+                .collect(),
+        }
+    }
+}
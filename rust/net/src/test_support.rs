@@ -0,0 +1,98 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Helpers for tests that run servers locally, and recorded attestations for
+//! tests of attestation checks.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+pub mod attestation;
+
+/// How long [`wait_for_server_ready`] waits between connection attempts.
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum WaitError {
+    /// server did not start accepting connections in time
+    Timeout,
+    /// connecting failed in a way that waiting won't fix: {0}
+    ConnectionRefusedPermanently(io::ErrorKind),
+}
+
+/// Waits until a server accepts TCP connections on `addr`.
+///
+/// Meant to be called after starting a server in the background and before
+/// the first client connects to it, instead of sleeping for a guessed amount
+/// of time.
+pub async fn wait_for_server_ready(addr: SocketAddr, timeout: Duration) -> Result<(), WaitError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+            Ok(Ok(_stream)) => return Ok(()),
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Ok(Err(e)) => return Err(WaitError::ConnectionRefusedPermanently(e.kind())),
+            Err(_elapsed) => return Err(WaitError::Timeout),
+        }
+        if Instant::now() + RETRY_INTERVAL >= deadline {
+            return Err(WaitError::Timeout);
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ready_once_listening() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+        let addr = listener.local_addr().expect("bound");
+
+        let started = Instant::now();
+        wait_for_server_ready(addr, Duration::from_secs(5))
+            .await
+            .expect("server is listening");
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn waits_for_server_to_start() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+            listener.local_addr().expect("bound")
+        };
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let listener = TcpListener::bind(addr).await.expect("can bind again");
+            let _ = listener.accept().await;
+        });
+
+        wait_for_server_ready(addr, Duration::from_secs(5))
+            .await
+            .expect("server starts");
+        server.await.expect("server did not panic");
+    }
+
+    #[tokio::test]
+    async fn times_out_without_server() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.expect("can bind");
+            listener.local_addr().expect("bound")
+        };
+        assert_matches!(
+            wait_for_server_ready(addr, Duration::from_millis(50)).await,
+            Err(WaitError::Timeout)
+        );
+    }
+}
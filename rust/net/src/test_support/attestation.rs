@@ -0,0 +1,200 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Recorded attestation messages, for testing code that checks attestations.
+//!
+//! Each [`AttestationFixture`] is a handshake start message as sent by an
+//! enclave, together with the measurement it is checked against, whether
+//! [`NewHandshake::new_handshake`] should accept it, and the times at which it
+//! is current. Attestations only stay valid for a while, so checks run
+//! against a [`TestClock`] set to the time of interest instead of the real
+//! time.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use attest::enclave;
+use attest::nitro::NitroError;
+use hex_literal::hex;
+use lazy_static::lazy_static;
+
+use crate::enclave::{Cdsi, EnclaveKind, EndpointParams, MrEnclave, NewHandshake, Nitro, Sgx};
+use crate::infra::clock::TestClock;
+
+/// What [`NewHandshake::new_handshake`] should make of a fixture.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Expected {
+    Accepted,
+    /// Rejected only because the Nitro PCRs aren't the expected ones: the
+    /// document and its certificates check out while the fixture is current.
+    PcrsRejected,
+    Rejected,
+}
+
+impl Expected {
+    /// Whether `result` is what was expected of a current fixture.
+    pub fn matches(self, result: &enclave::Result<enclave::Handshake>) -> bool {
+        match (self, result) {
+            (Self::Accepted, Ok(_)) | (Self::Rejected, Err(_)) => true,
+            (Self::PcrsRejected, Err(error)) => is_pcr_mismatch(error),
+            _ => false,
+        }
+    }
+}
+
+fn is_pcr_mismatch(error: &enclave::Error) -> bool {
+    matches!(
+        error,
+        enclave::Error::AttestationError(error) if error.to_string() == NitroError::InvalidPcrs.to_string()
+    )
+}
+
+pub struct AttestationFixture<E: EnclaveKind> {
+    /// Identifies the fixture in test failures.
+    pub name: &'static str,
+    /// The handshake start message sent by the enclave.
+    pub message: &'static [u8],
+    /// The measurement the message is checked against.
+    pub mr_enclave: MrEnclave<&'static [u8], E>,
+    pub expected: Expected,
+    /// Times at which the attestation is current.
+    ///
+    /// It may also be current for a while before the start of the window, but
+    /// it is expired from the end on.
+    pub valid: Range<SystemTime>,
+}
+
+impl<E: EnclaveKind + NewHandshake> AttestationFixture<E> {
+    /// Parameters for checking the fixture as if at `now`.
+    pub fn params_at(&self, now: SystemTime) -> EndpointParams<E> {
+        EndpointParams::new(self.mr_enclave).with_clock(clock_at(now))
+    }
+
+    /// Checks the fixture as if at `now`.
+    pub fn new_handshake_at(&self, now: SystemTime) -> enclave::Result<enclave::Handshake> {
+        E::new_handshake(&self.params_at(now), self.message)
+    }
+}
+
+/// A [`TestClock`] stopped at `now`.
+///
+/// Parameters need a `'static` clock, so one is leaked for each distinct time
+/// and shared by every call for that time. The clocks are never advanced.
+pub fn clock_at(now: SystemTime) -> &'static TestClock {
+    lazy_static! {
+        static ref CLOCKS: Mutex<HashMap<SystemTime, &'static TestClock>> = Mutex::default();
+    }
+    CLOCKS
+        .lock()
+        .expect("not poisoned")
+        .entry(now)
+        .or_insert_with(|| Box::leak(Box::new(TestClock::new(now))))
+}
+
+const SVR2_HANDSHAKE_START: &[u8] = attest::svr2::testutil::HANDSHAKE_START;
+const CDSI_HANDSHAKE_START: &[u8] = include_bytes!("../../tests/data/cdsi_handshake_start.data");
+const NITRO_HANDSHAKE_START: &[u8] = include_bytes!("../../tests/data/nitro_handshake_start.data");
+
+const CDSI_TEST_MR_ENCLAVE: &[u8] =
+    &hex!("39d78f17f8aa9a8e9cdaf16595947a057bac21f014d1abfd6a99b2dfd4e18d1d");
+
+fn unix_time(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+pub fn sgx_fixtures() -> Vec<AttestationFixture<Sgx>> {
+    // The PCK CRL in the endorsements is next updated at 2024-03-31T15:36:50Z.
+    let valid = unix_time(1709245753)..unix_time(1711899410);
+    vec![
+        AttestationFixture {
+            name: "svr2-staging",
+            message: SVR2_HANDSHAKE_START,
+            mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_SVR2_STAGING),
+            expected: Expected::Accepted,
+            valid: valid.clone(),
+        },
+        AttestationFixture {
+            name: "svr2-staging-checked-against-svr3",
+            message: SVR2_HANDSHAKE_START,
+            mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_SVR3_SGX_STAGING),
+            expected: Expected::Rejected,
+            valid,
+        },
+    ]
+}
+
+pub fn cdsi_fixtures() -> Vec<AttestationFixture<Cdsi>> {
+    vec![AttestationFixture {
+        name: "cdsi-test",
+        message: CDSI_HANDSHAKE_START,
+        mr_enclave: MrEnclave::new(CDSI_TEST_MR_ENCLAVE),
+        expected: Expected::Accepted,
+        // The PCK CRL in the endorsements is next updated at
+        // 2022-07-21T21:15:11Z.
+        valid: unix_time(1655857680)..unix_time(1658438111),
+    }]
+}
+
+pub fn nitro_fixtures() -> Vec<AttestationFixture<Nitro>> {
+    vec![AttestationFixture {
+        name: "nitro-unknown-measurements",
+        message: NITRO_HANDSHAKE_START,
+        // The document's PCRs are not those of any known enclave, but
+        // everything else about it is valid.
+        mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_SVR3_NITRO_STAGING),
+        expected: Expected::PcrsRejected,
+        // The enclave's certificate is valid until 2024-01-16T20:44:55Z.
+        valid: unix_time(1705432216)..unix_time(1705437896),
+    }]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_fixtures<E: EnclaveKind + NewHandshake>(fixtures: Vec<AttestationFixture<E>>) {
+        assert!(!fixtures.is_empty());
+        for fixture in fixtures {
+            let name = fixture.name;
+            let last_valid = fixture.valid.end - Duration::from_secs(1);
+            for now in [fixture.valid.start, last_valid] {
+                let result = fixture.new_handshake_at(now);
+                assert!(
+                    fixture.expected.matches(&result),
+                    "{name}: expected {:?}, got {:?}",
+                    fixture.expected,
+                    result.err()
+                );
+            }
+            let expired = fixture.new_handshake_at(fixture.valid.end);
+            assert!(expired.is_err(), "{name} should have expired");
+            if fixture.expected == Expected::PcrsRejected {
+                // Rejected for its expiry, before the PCRs are checked.
+                assert!(
+                    !Expected::PcrsRejected.matches(&expired),
+                    "{name} should have expired: {:?}",
+                    expired.err()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sgx_fixtures_match_expectations() {
+        check_fixtures(sgx_fixtures());
+    }
+
+    #[test]
+    fn cdsi_fixtures_match_expectations() {
+        check_fixtures(cdsi_fixtures());
+    }
+
+    #[test]
+    fn nitro_fixtures_match_expectations() {
+        check_fixtures(nitro_fixtures());
+    }
+}
@@ -6,9 +6,15 @@
 use base64::prelude::{Engine as _, BASE64_STANDARD};
 use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
 use std::future;
 use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 /// Constructs the value of the `Authorization` header for the `Basic` auth scheme.
 pub(crate) fn basic_authorization(username: &str, password: &str) -> String {
@@ -33,6 +39,27 @@ where
     }
 }
 
+/// Runs a `Future` unless the `token` gets cancelled first.
+///
+/// Like [`timeout`], the return type is the same as that of the given `future`; if the
+/// token is cancelled before the future completes, the future is dropped and
+/// `cancelled_error` is returned. A token that is already cancelled stops the future
+/// before it is polled at all.
+pub async fn cancellable<T, E, F>(
+    token: &CancellationToken,
+    cancelled_error: E,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    tokio::select! {
+        biased;
+        () = token.cancelled() => Err(cancelled_error),
+        result = future => result,
+    }
+}
+
 /// Takes a series of `Future` objects that all return a `Result<T, E>`
 /// and returns when the first of them completes successfully.
 ///
@@ -49,10 +76,90 @@ where
         .await
 }
 
+lazy_static! {
+    /// For each type passed to [`intern`], a `HashSet<&'static T>` of the
+    /// values interned so far.
+    static ref INTERNED: Mutex<HashMap<TypeId, Box<dyn Any + Send>>> = Mutex::default();
+}
+
+/// Gives `value` a `'static` lifetime, leaking it only if no equal value has
+/// been interned before.
+///
+/// For configuration loaded at runtime that has to live as long as the
+/// built-in constants: loading the same configuration again reuses what was
+/// leaked the first time, rather than leaking another copy.
+pub(crate) fn intern<T>(value: Box<T>) -> &'static T
+where
+    T: ?Sized + Eq + Hash + Send + Sync + 'static,
+{
+    let mut interned = INTERNED.lock().expect("not poisoned");
+    let values = interned
+        .entry(TypeId::of::<T>())
+        .or_insert_with(|| Box::<HashSet<&'static T>>::default())
+        .downcast_mut::<HashSet<&'static T>>()
+        .expect("keyed by type");
+    if let Some(&existing) = values.get(&*value) {
+        return existing;
+    }
+    let leaked: &'static T = Box::leak(value);
+    values.insert(leaked);
+    leaked
+}
+
 #[cfg(test)]
 mod test {
-    use crate::utils::first_ok;
+    use crate::utils::{cancellable, first_ok, intern};
     use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn intern_leaks_each_distinct_value_once() {
+        let first: &'static str = intern("interned".into());
+        let again: &'static str = intern("interned".into());
+        assert!(std::ptr::eq(first, again));
+
+        let other: &'static str = intern("other".into());
+        assert_eq!(other, "other");
+        assert!(!std::ptr::eq(first, other));
+
+        // Values of different types are kept apart, even if their bytes match.
+        let bytes: &'static [u8] = intern(b"interned".to_vec().into_boxed_slice());
+        assert_eq!(bytes, b"interned");
+        assert!(std::ptr::eq(
+            bytes,
+            intern::<[u8]>(b"interned".to_vec().into_boxed_slice())
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancellable_stops_pending_future() {
+        let token = CancellationToken::new();
+        let cancel = {
+            let token = token.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                token.cancel();
+            }
+        };
+        let (result, ()) =
+            tokio::join!(cancellable(&token, "cancelled", future(30, Ok(1))), cancel);
+        assert_eq!(result, Err("cancelled"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancellable_token_is_reusable_after_completion() {
+        let token = CancellationToken::new();
+        assert_eq!(
+            cancellable(&token, "cancelled", future(10, Ok(1))).await,
+            Ok(1)
+        );
+        // Cancelling after completion is harmless, and stops any later operations.
+        token.cancel();
+        assert_eq!(
+            cancellable(&token, "cancelled", future(10, Ok(2))).await,
+            Err("cancelled")
+        );
+    }
 
     #[tokio::test(start_paused = true)]
     async fn first_ok_picks_the_result_from_earliest_finished_future() {
@@ -0,0 +1,127 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Backup and restore against in-memory servers.
+//!
+//! The `client` benchmarks leave the simulated servers out, to isolate the
+//! PPSS math: `backup/client` times `Backup::new` (blinding) and
+//! `Backup::finalize` (unblinding and masking the shares) against server
+//! responses prepared ahead of time, and `restore/client` times only
+//! `Restore::finalize` (unblinding, unmasking, and the commitment check). The
+//! `round_trip` benchmarks time the whole operation, from `Backup::new` or
+//! `Restore::new` to `finalize`, including the servers' work.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libsignal_svr3::test_support::{InMemorySvr3Server, Uid};
+use libsignal_svr3::{Backup, MaskedShareSet, Restore};
+use nonzero_ext::nonzero;
+use rand_core::OsRng;
+
+const UID: Uid = [1; 16];
+const PASSWORD: &str = "password";
+const SECRET: [u8; 32] = [42; 32];
+const ENCLAVE_COUNTS: [usize; 3] = [2, 3, 5];
+
+fn servers(n: usize) -> (Vec<u64>, Vec<InMemorySvr3Server>) {
+    let server_ids = (1..=n as u64).collect();
+    let servers = std::iter::repeat_with(InMemorySvr3Server::new)
+        .take(n)
+        .collect();
+    (server_ids, servers)
+}
+
+fn round_trip(servers: &mut [InMemorySvr3Server], requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    servers
+        .iter_mut()
+        .zip(requests)
+        .map(|(server, request)| server.handle_request(UID, request).expect("valid request"))
+        .collect()
+}
+
+fn backup(server_ids: &[u64], servers: &mut [InMemorySvr3Server]) -> MaskedShareSet {
+    let backup = Backup::new(server_ids, PASSWORD, SECRET, nonzero!(255u32), &mut OsRng)
+        .expect("can create backup");
+    let responses = round_trip(servers, &backup.requests);
+    backup
+        .finalize(&mut OsRng, &responses)
+        .expect("can finalize backup")
+}
+
+pub fn backup_and_restore(c: &mut Criterion) {
+    let mut group = c.benchmark_group("svr3");
+    group.throughput(Throughput::Elements(1));
+
+    for n in ENCLAVE_COUNTS {
+        group.bench_function(BenchmarkId::new("backup/client", n), |b| {
+            b.iter_batched(
+                || {
+                    let (server_ids, mut servers) = servers(n);
+                    // These responses evaluate a different backup's blinded
+                    // elements, so the share set made from them can't be
+                    // restored. Unblinding and masking cost the same for any
+                    // valid element, though, so they time the client just as
+                    // well.
+                    let template =
+                        Backup::new(&server_ids, PASSWORD, SECRET, nonzero!(255u32), &mut OsRng)
+                            .expect("can create backup");
+                    let responses = round_trip(&mut servers, &template.requests);
+                    (server_ids, responses)
+                },
+                |(server_ids, responses)| {
+                    Backup::new(&server_ids, PASSWORD, SECRET, nonzero!(255u32), &mut OsRng)
+                        .expect("can create backup")
+                        .finalize(&mut OsRng, &responses)
+                        .expect("can finalize backup")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        group.bench_function(BenchmarkId::new("backup/round_trip", n), |b| {
+            b.iter_batched(
+                || servers(n),
+                |(server_ids, mut servers)| backup(&server_ids, &mut servers),
+                BatchSize::SmallInput,
+            )
+        });
+
+        group.bench_function(BenchmarkId::new("restore/client", n), |b| {
+            b.iter_batched(
+                || {
+                    let (server_ids, mut servers) = servers(n);
+                    let share_set = backup(&server_ids, &mut servers);
+                    let restore =
+                        Restore::new(PASSWORD, share_set, &mut OsRng).expect("can create restore");
+                    let responses = round_trip(&mut servers, &restore.requests);
+                    (restore, responses)
+                },
+                |(restore, responses)| restore.finalize(&responses).expect("can restore"),
+                BatchSize::SmallInput,
+            )
+        });
+
+        group.bench_function(BenchmarkId::new("restore/round_trip", n), |b| {
+            b.iter_batched(
+                || {
+                    let (server_ids, mut servers) = servers(n);
+                    let share_set = backup(&server_ids, &mut servers);
+                    (servers, share_set)
+                },
+                |(mut servers, share_set)| {
+                    let restore =
+                        Restore::new(PASSWORD, share_set, &mut OsRng).expect("can create restore");
+                    let responses = round_trip(&mut servers, &restore.requests);
+                    restore.finalize(&responses).expect("can restore")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, backup_and_restore);
+criterion_main!(benches);
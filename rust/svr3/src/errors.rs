@@ -18,6 +18,14 @@ pub enum Error {
     BadResponse,
     /// Response status is not OK: {0}
     BadResponseStatus(ErrorStatus),
+    /// Restore failed with {0} tries remaining
+    RestoreFailed(u32),
+    /// Argon2 error: {0}
+    Argon2(argon2::Error),
+    /// Password strengthener doesn't match the one the share set was backed up with
+    StrengthenerMismatch,
+    /// Password strengthening parameters exceed what share sets can record
+    StrengtheningOutOfRange,
 }
 
 /// Represents an erroneous SVR3 response status
@@ -29,6 +37,7 @@ pub enum ErrorStatus {
     Error,
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
 impl From<OPRFError> for Error {
@@ -43,6 +52,12 @@ impl From<PPSSError> for Error {
     }
 }
 
+impl From<argon2::Error> for Error {
+    fn from(err: argon2::Error) -> Self {
+        Self::Argon2(err)
+    }
+}
+
 impl From<DecodeError> for Error {
     fn from(_err: DecodeError) -> Self {
         Self::BadData
@@ -2,10 +2,25 @@
 // Copyright 2023 Signal Messenger, LLC.
 // SPDX-License-Identifier: AGPL-3.0-only
 //
-use std::num::NonZeroU32;
+
+//! Client side of the SVR3 protocol.
+//!
+//! Without the default `std` feature this crate only needs `core` and `alloc`,
+//! so that the PPSS math and the share set serialization can be used on
+//! targets without an operating system.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+use core::ops::Deref;
 
 use prost::Message;
 use rand_core::CryptoRngCore;
+use zeroize::Zeroizing;
 
 mod oprf;
 mod ppss;
@@ -13,15 +28,46 @@ pub use ppss::{MaskedShareSet, OPRFSession};
 
 mod errors;
 pub use errors::{Error, ErrorStatus, OPRFError, PPSSError};
+mod share_set;
+pub use share_set::{DeserializeError, OpaqueMaskedShareSet, SerializeError};
+mod strengthen;
+pub use strengthen::{
+    Argon2Strengthener, PasswordStrengthener, StrengthenedPassword, StrengthenerParams,
+    Strengthening,
+};
 mod proto;
 use proto::svr3;
-use proto::svr3::{create_response, evaluate_response};
+use proto::svr3::{create_response, evaluate_response, query_response};
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 const CONTEXT: &str = "Signal_SVR3_20231121_PPSS_Context";
 
+/// The most restore tries a backup may allow; servers reject create requests
+/// asking for more.
+pub const MAX_ALLOWED_TRIES: u32 = 255;
+
+/// The password as it goes into the OPRFs and the commitment.
+enum OprfPassword<'a> {
+    Plain(&'a [u8]),
+    Strengthened(Zeroizing<[u8; 32]>),
+}
+
+impl Deref for OprfPassword<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Plain(password) => password,
+            Self::Strengthened(key) => key.as_slice(),
+        }
+    }
+}
+
 pub struct Backup<'a> {
     oprfs: Vec<OPRFSession>,
-    password: &'a str,
+    password: OprfPassword<'a>,
+    strengthening: Option<Strengthening>,
     secret: [u8; 32],
     server_ids: Vec<u64>,
     pub requests: Vec<Vec<u8>>,
@@ -35,7 +81,64 @@ impl<'a> Backup<'a> {
         max_tries: NonZeroU32,
         rng: &mut R,
     ) -> Result<Self, Error> {
-        let oprfs = ppss::begin_oprfs(CONTEXT, server_ids, password, rng)?;
+        Self::new_with_strengthener(server_ids, password, secret, max_tries, None, rng)
+    }
+
+    /// Like [`Self::new`], but if there is a `strengthener`, the password is
+    /// run through it first, and the share set records how.
+    pub fn new_with_strengthener<R: CryptoRngCore>(
+        server_ids: &[u64],
+        password: &'a str,
+        secret: [u8; 32],
+        max_tries: NonZeroU32,
+        strengthener: Option<&dyn PasswordStrengthener>,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        match strengthener {
+            None => Self::with_oprf_password(
+                server_ids,
+                OprfPassword::Plain(password.as_bytes()),
+                None,
+                secret,
+                max_tries,
+                rng,
+            ),
+            Some(strengthener) => {
+                let password = Strengthening::generate(strengthener.params(), rng)?
+                    .apply(Some(strengthener), password.as_bytes())?;
+                Self::new_strengthened(server_ids, password, secret, max_tries, rng)
+            }
+        }
+    }
+
+    /// Like [`Self::new_with_strengthener`], for a password that has already
+    /// been strengthened with a fresh [`Strengthening::generate`].
+    pub fn new_strengthened<R: CryptoRngCore>(
+        server_ids: &[u64],
+        password: StrengthenedPassword,
+        secret: [u8; 32],
+        max_tries: NonZeroU32,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        Self::with_oprf_password(
+            server_ids,
+            OprfPassword::Strengthened(password.key),
+            Some(password.strengthening),
+            secret,
+            max_tries,
+            rng,
+        )
+    }
+
+    fn with_oprf_password<R: CryptoRngCore>(
+        server_ids: &[u64],
+        password: OprfPassword<'a>,
+        strengthening: Option<Strengthening>,
+        secret: [u8; 32],
+        max_tries: NonZeroU32,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let oprfs = ppss::begin_oprfs(CONTEXT, server_ids, &password, rng)?;
         let requests = oprfs
             .iter()
             .map(|oprf| crate::make_create_request(max_tries.into(), &oprf.blinded_elt_bytes))
@@ -44,12 +147,21 @@ impl<'a> Backup<'a> {
         Ok(Self {
             oprfs,
             password,
+            strengthening,
             secret,
             server_ids: server_ids.into(),
             requests,
         })
     }
 
+    /// Whether `response` says the enclave stored its part of the backup.
+    ///
+    /// [`Self::finalize`] fails unless all of them did; this tells which ones
+    /// did.
+    pub fn is_accepted(response: &[u8]) -> bool {
+        decode_create_response(response).is_ok()
+    }
+
     pub fn finalize<R>(self, rng: &mut R, responses: &[Vec<u8>]) -> Result<MaskedShareSet, Error>
     where
         R: CryptoRngCore,
@@ -59,21 +171,43 @@ impl<'a> Backup<'a> {
             .map(|vec| decode_create_response(vec))
             .collect::<Result<Vec<_>, _>>()?;
         let outputs = ppss::finalize_oprfs(self.oprfs, &evaluated_elements)?;
-        Ok(ppss::backup_secret(
+        let share_set = ppss::backup_secret(
             CONTEXT,
-            self.password.as_bytes(),
+            &self.password,
             self.server_ids,
             outputs,
             &self.secret,
             rng,
         )
-        .expect("matching lengths of server_ids and outputs"))
+        .expect("matching lengths of server_ids and outputs");
+        Ok(MaskedShareSet {
+            strengthening: self.strengthening,
+            ..share_set
+        })
+    }
+
+    /// Like [`Self::finalize`], wrapping the share set for storage along with
+    /// the `environment` it was backed up to.
+    pub fn finalize_with_environment<R>(
+        self,
+        rng: &mut R,
+        responses: &[Vec<u8>],
+        environment: [u8; 32],
+    ) -> Result<OpaqueMaskedShareSet, Error>
+    where
+        R: CryptoRngCore,
+    {
+        let share_set = self.finalize(rng, responses)?;
+        Ok(OpaqueMaskedShareSet::with_environment(
+            share_set,
+            environment,
+        ))
     }
 }
 
 pub struct Restore<'a> {
     oprfs: Vec<OPRFSession>,
-    password: &'a str,
+    password: OprfPassword<'a>,
     share_set: MaskedShareSet,
     pub requests: Vec<Vec<u8>>,
 }
@@ -84,7 +218,52 @@ impl<'a> Restore<'a> {
         share_set: MaskedShareSet,
         rng: &mut R,
     ) -> Result<Self, Error> {
-        let oprfs = ppss::begin_oprfs(CONTEXT, &share_set.server_ids, password, rng)?;
+        Self::new_with_strengthener(password, share_set, None, rng)
+    }
+
+    /// Like [`Self::new`], computing the password strengthening recorded in
+    /// the share set with `strengthener` instead of the built-in
+    /// implementation. Share sets backed up without strengthening ignore it.
+    ///
+    /// Fails with [`Error::StrengthenerMismatch`] if `strengthener` doesn't
+    /// have the recorded parameters, since the restore would fail anyway,
+    /// using up a try.
+    pub fn new_with_strengthener<R: CryptoRngCore>(
+        password: &'a str,
+        share_set: MaskedShareSet,
+        strengthener: Option<&dyn PasswordStrengthener>,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let password = restore_password(password, &share_set, strengthener)?;
+        Self::with_oprf_password(password, share_set, rng)
+    }
+
+    /// Like [`Self::from_share_set`], for a password that has already been
+    /// strengthened the way the share set records.
+    ///
+    /// Fails with [`Error::StrengthenerMismatch`] if the share set records a
+    /// different strengthening, or none.
+    pub fn from_share_set_strengthened<R: CryptoRngCore>(
+        password: StrengthenedPassword,
+        share_set: OpaqueMaskedShareSet,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        if share_set.strengthening() != Some(&password.strengthening) {
+            return Err(Error::StrengthenerMismatch);
+        }
+        Self::with_oprf_password(
+            OprfPassword::Strengthened(password.key),
+            share_set.inner,
+            rng,
+        )
+    }
+
+    fn with_oprf_password<R: CryptoRngCore>(
+        password: OprfPassword<'a>,
+        share_set: MaskedShareSet,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let oprfs = ppss::begin_oprfs(CONTEXT, &share_set.server_ids, &password, rng)?;
         let requests = oprfs
             .iter()
             .map(|oprf| crate::make_evaluate_request(&oprf.blinded_elt_bytes))
@@ -97,15 +276,136 @@ impl<'a> Restore<'a> {
             requests,
         })
     }
+
+    /// Like [`Self::new_with_strengthener`], for a share set as clients store
+    /// it.
+    pub fn from_share_set<R: CryptoRngCore>(
+        password: &'a str,
+        share_set: OpaqueMaskedShareSet,
+        strengthener: Option<&dyn PasswordStrengthener>,
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        Self::new_with_strengthener(password, share_set.inner, strengthener, rng)
+    }
+
+    /// Recovers the secret from the servers' responses.
+    ///
+    /// A wrong password or share set is reported as [`Error::RestoreFailed`],
+    /// with the lowest number of tries any of the servers has left.
     pub fn finalize(self, responses: &[Vec<u8>]) -> Result<[u8; 32], Error> {
-        let evaluated_elements = responses
+        let (evaluated_elements, tries_remaining): (Vec<_>, Vec<_>) = responses
             .iter()
             .map(|vec| decode_evaluate_response(vec))
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
         let outputs = ppss::finalize_oprfs(self.oprfs, &evaluated_elements)?;
-        let (secret, _key) =
-            ppss::restore_secret(CONTEXT, self.password.as_bytes(), outputs, self.share_set)?;
-        Ok(secret)
+        match recombine_shares(&self.password, outputs, self.share_set) {
+            Ok(secret) => Ok(secret),
+            Err(PPSSError::InvalidCommitment) => Err(Error::RestoreFailed(
+                tries_remaining.into_iter().min().unwrap_or_default(),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Recovers the secret backed up in `share_set` from the OPRF outputs of its
+/// servers, without talking to them, e.g. for outputs kept in escrow.
+///
+/// `oprf_outputs` are the unblinded outputs of each server's OPRF on the
+/// password, in the order of the share set's server ids. The enclaves only
+/// ever return blinded evaluations, which [`Restore::finalize`] unblinds with
+/// the state of its requests before doing the same as this. If the share set
+/// was backed up with password strengthening, the password is strengthened
+/// again with the recorded parameters first, as the outputs are for that.
+///
+/// A wrong password, share set, or output is reported as
+/// [`PPSSError::InvalidCommitment`].
+pub fn restore_from_oprf_outputs(
+    password: &str,
+    share_set: OpaqueMaskedShareSet,
+    oprf_outputs: &[[u8; 64]],
+) -> Result<[u8; 32], Error> {
+    let share_set = share_set.inner;
+    let password = restore_password(password, &share_set, None)?;
+    Ok(recombine_shares(
+        &password,
+        oprf_outputs.to_vec(),
+        share_set,
+    )?)
+}
+
+/// The password as it went into the OPRFs when `share_set` was backed up.
+fn restore_password<'a>(
+    password: &'a str,
+    share_set: &MaskedShareSet,
+    strengthener: Option<&dyn PasswordStrengthener>,
+) -> Result<OprfPassword<'a>, Error> {
+    Ok(match &share_set.strengthening {
+        None => OprfPassword::Plain(password.as_bytes()),
+        Some(strengthening) => {
+            OprfPassword::Strengthened(strengthening.apply(strengthener, password.as_bytes())?.key)
+        }
+    })
+}
+
+/// Unmasks the shares with the OPRF outputs, combines them, and checks the
+/// result against the share set's commitment.
+fn recombine_shares(
+    password: &[u8],
+    oprf_outputs: Vec<[u8; 64]>,
+    share_set: MaskedShareSet,
+) -> Result<[u8; 32], PPSSError> {
+    ppss::restore_secret(CONTEXT, password, oprf_outputs, share_set).map(|(secret, _key)| secret)
+}
+
+/// Removes the data stored for the user from every server.
+///
+/// Removing data that was never backed up is not an error.
+pub struct Remove {
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl Remove {
+    pub fn new(server_ids: &[u64]) -> Self {
+        let request = make_remove_request().encode_to_vec();
+        Self {
+            requests: vec![request; server_ids.len()],
+        }
+    }
+
+    pub fn finalize(self, responses: &[Vec<u8>]) -> Result<(), Error> {
+        responses
+            .iter()
+            .try_for_each(|vec| decode_remove_response(vec))
+    }
+}
+
+/// Asks every server how many restore attempts are left for the user.
+pub struct Query {
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl Query {
+    pub fn new(server_ids: &[u64]) -> Self {
+        let request = make_query_request().encode_to_vec();
+        Self {
+            requests: vec![request; server_ids.len()],
+        }
+    }
+
+    /// Returns the number of tries remaining.
+    ///
+    /// Each server keeps its own count, and a restore needs all of them, so
+    /// this is the lowest count reported. Without any responses there is no
+    /// count, and the result is [`Error::BadResponse`].
+    pub fn finalize(self, responses: &[Vec<u8>]) -> Result<u32, Error> {
+        let tries = responses
+            .iter()
+            .map(|vec| decode_query_response(vec))
+            .collect::<Result<Vec<_>, _>>()?;
+        tries.into_iter().min().ok_or(Error::BadResponse)
     }
 }
 
@@ -165,14 +465,60 @@ impl From<evaluate_response::Status> for ErrorStatus {
     }
 }
 
-fn decode_evaluate_response(bytes: &[u8]) -> Result<[u8; 32], Error> {
+/// Returns the evaluated element and the number of tries remaining.
+fn decode_evaluate_response(bytes: &[u8]) -> Result<([u8; 32], u32), Error> {
     let decoded = svr3::Response::decode(bytes)?;
     if let Some(svr3::response::Inner::Evaluate(response)) = decoded.inner {
         if response.status() == evaluate_response::Status::Ok {
-            Ok(response
+            let evaluated_element = response
                 .evaluated_element
                 .try_into()
-                .expect("response should be of right size"))
+                .expect("response should be of right size");
+            Ok((evaluated_element, response.tries_remaining))
+        } else {
+            Err(Error::BadResponseStatus(response.status().into()))
+        }
+    } else {
+        Err(Error::BadResponse)
+    }
+}
+
+fn make_remove_request() -> svr3::Request {
+    svr3::Request {
+        inner: Some(svr3::request::Inner::Remove(svr3::RemoveRequest {})),
+    }
+}
+
+fn decode_remove_response(bytes: &[u8]) -> Result<(), Error> {
+    let decoded = svr3::Response::decode(bytes)?;
+    if let Some(svr3::response::Inner::Remove(svr3::RemoveResponse {})) = decoded.inner {
+        Ok(())
+    } else {
+        Err(Error::BadResponse)
+    }
+}
+
+fn make_query_request() -> svr3::Request {
+    svr3::Request {
+        inner: Some(svr3::request::Inner::Query(svr3::QueryRequest {})),
+    }
+}
+
+impl From<query_response::Status> for ErrorStatus {
+    fn from(status: query_response::Status) -> Self {
+        match status {
+            query_response::Status::Ok => unreachable!(),
+            query_response::Status::Unset => Self::Unset,
+            query_response::Status::Missing => Self::Missing,
+        }
+    }
+}
+
+fn decode_query_response(bytes: &[u8]) -> Result<u32, Error> {
+    let decoded = svr3::Response::decode(bytes)?;
+    if let Some(svr3::response::Inner::Query(response)) = decoded.inner {
+        if response.status() == query_response::Status::Ok {
+            Ok(response.tries_remaining)
         } else {
             Err(Error::BadResponseStatus(response.status().into()))
         }
@@ -278,6 +624,7 @@ mod test {
             server_ids: vec![1, 2, 3],
             masked_shares: vec![[0; 32], [1; 32], [2; 32]],
             commitment: [42; 32],
+            strengthening: None,
         }
     }
 
@@ -326,11 +673,13 @@ mod test {
             .take(3)
             .collect();
         let result = restore.finalize(&responses);
-        let is_ppss_error = matches!(result, Err(Error::Ppss(ppss::PPSSError::InvalidCommitment)));
-        assert_eq!(should_succeed, result.is_ok() || is_ppss_error);
+        let is_restore_failure = matches!(result, Err(Error::RestoreFailed(_)));
+        assert_eq!(should_succeed, result.is_ok() || is_restore_failure);
     }
 
     #[test_case(vec![1, 2, 3], Error::BadData; "bad_protobuf")]
+    // Field 1 declaring a length of 2^64 - 1.
+    #[test_case([&[0x0a][..], &[0xff; 9], &[0x01]].concat(), Error::BadData; "absurd_length")]
     #[test_case(
         make_create_response(svr3::create_response::Status::Ok).encode_to_vec(),
         Error::BadResponse;
@@ -341,4 +690,82 @@ mod test {
         let result = restore.finalize(&[response]);
         assert_matches!(result, Err(_expected));
     }
+
+    #[test]
+    fn remove_request_basic_checks() {
+        let remove = Remove::new(&[1, 2, 3]);
+        assert_eq!(3, remove.requests.len());
+        for request_bytes in remove.requests.into_iter() {
+            assert_matches!(
+                svr3::Request::decode(&*request_bytes),
+                Ok(svr3::Request {
+                    inner: Some(svr3::request::Inner::Remove(svr3::RemoveRequest {})),
+                })
+            );
+        }
+    }
+
+    #[test_case(vec![1, 2, 3] => matches Err(Error::BadData); "bad_protobuf")]
+    #[test_case(
+        make_evaluate_response(svr3::evaluate_response::Status::Ok).encode_to_vec()
+        => matches Err(Error::BadResponse);
+        "wrong_response_type")]
+    fn remove_invalid_response(response: Vec<u8>) -> Result<(), Error> {
+        Remove::new(&[1]).finalize(&[response])
+    }
+
+    #[test]
+    fn query_request_basic_checks() {
+        let query = Query::new(&[1, 2, 3]);
+        assert_eq!(3, query.requests.len());
+        for request_bytes in query.requests.into_iter() {
+            assert_matches!(
+                svr3::Request::decode(&*request_bytes),
+                Ok(svr3::Request {
+                    inner: Some(svr3::request::Inner::Query(svr3::QueryRequest {})),
+                })
+            );
+        }
+    }
+
+    fn make_query_response(status: svr3::query_response::Status, tries: u32) -> svr3::Response {
+        svr3::Response {
+            inner: Some(svr3::response::Inner::Query(svr3::QueryResponse {
+                status: status.into(),
+                tries_remaining: tries,
+            })),
+        }
+    }
+
+    #[test]
+    fn query_reports_lowest_tries_remaining() {
+        let query = Query::new(&[1, 2, 3]);
+        let responses = [5, 3, 4]
+            .map(|tries| make_query_response(svr3::query_response::Status::Ok, tries))
+            .map(|response| response.encode_to_vec());
+        assert_matches!(query.finalize(&responses), Ok(3));
+    }
+
+    #[test]
+    fn query_without_responses_fails() {
+        let query = Query::new(&[]);
+        assert_matches!(query.finalize(&[]), Err(Error::BadResponse));
+    }
+
+    #[test_case(vec![1, 2, 3] => matches Err(Error::BadData); "bad_protobuf")]
+    #[test_case(
+        make_evaluate_response(svr3::evaluate_response::Status::Ok).encode_to_vec()
+        => matches Err(Error::BadResponse);
+        "wrong_response_type")]
+    #[test_case(
+        make_query_response(svr3::query_response::Status::Missing, 0).encode_to_vec()
+        => matches Err(Error::BadResponseStatus(ErrorStatus::Missing));
+        "status_missing")]
+    #[test_case(
+        make_query_response(svr3::query_response::Status::Unset, 0).encode_to_vec()
+        => matches Err(Error::BadResponseStatus(ErrorStatus::Unset));
+        "status_unset")]
+    fn query_invalid_response(response: Vec<u8>) -> Result<u32, Error> {
+        Query::new(&[1]).finalize(&[response])
+    }
 }
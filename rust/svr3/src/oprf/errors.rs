@@ -2,7 +2,7 @@
 // Copyright 2023 Signal Messenger, LLC.
 // SPDX-License-Identifier: AGPL-3.0-only
 //
-use std::fmt;
+use core::fmt;
 
 #[derive(Debug)]
 pub enum OPRFError {
@@ -11,6 +11,7 @@ pub enum OPRFError {
     BlindError,
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for OPRFError {}
 
 impl fmt::Display for OPRFError {
@@ -2,8 +2,7 @@
 // Copyright 2023 Signal Messenger, LLC.
 // SPDX-License-Identifier: AGPL-3.0-only
 //
-use std::cmp;
-use std::convert::{TryFrom, TryInto};
+use core::cmp;
 
 use super::errors::OPRFError;
 use sha2::{Digest, Sha512};
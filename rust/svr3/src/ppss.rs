@@ -6,17 +6,19 @@
 //! Implements the Password Protected secret Sharing (PPSS) scheme of
 //! [JKKX16](https://eprint.iacr.org/2016/144.pdf) using XOR-based secret sharing.
 
+use alloc::vec::Vec;
+
 use curve25519_dalek::ristretto::CompressedRistretto;
 use curve25519_dalek::Scalar;
 use displaydoc::Display;
 use hkdf::Hkdf;
 use rand_core::CryptoRngCore;
 use sha2::{Digest, Sha256};
-use std::convert::TryInto;
 use subtle::ConstantTimeEq;
 
 use crate::oprf;
 use crate::oprf::errors::OPRFError;
+use crate::strengthen::Strengthening;
 
 #[derive(Display, Debug)]
 pub enum PPSSError {
@@ -28,6 +30,7 @@ pub enum PPSSError {
     LengthMismatch(&'static str),
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for PPSSError {}
 
 type Key = [u8; 32];
@@ -83,18 +86,18 @@ pub struct OPRFSession {
     oprf_input: Vec<u8>,
 }
 
-fn prepare_oprf_input(context: &'static str, server_id: u64, input: &str) -> Vec<u8> {
+fn prepare_oprf_input(context: &'static str, server_id: u64, input: &[u8]) -> Vec<u8> {
     let mut oprf_input_bytes = Vec::<u8>::new();
     oprf_input_bytes.extend_from_slice(context.as_bytes());
     oprf_input_bytes.extend_from_slice(&server_id.to_le_bytes());
-    oprf_input_bytes.extend_from_slice(input.as_bytes());
+    oprf_input_bytes.extend_from_slice(input);
     oprf_input_bytes
 }
 
 fn oprf_session_from_inputs<R: CryptoRngCore>(
     context: &'static str,
     server_id: u64,
-    input: &str,
+    input: &[u8],
     rng: &mut R,
 ) -> Result<OPRFSession, OPRFError> {
     let oprf_input = prepare_oprf_input(context, server_id, input);
@@ -115,7 +118,7 @@ fn oprf_session_from_inputs<R: CryptoRngCore>(
 pub fn begin_oprfs<R: CryptoRngCore>(
     context: &'static str,
     server_ids: &[u64],
-    input: &str,
+    input: &[u8],
     rng: &mut R,
 ) -> Result<Vec<OPRFSession>, OPRFError> {
     server_ids
@@ -151,7 +154,7 @@ pub fn finalize_oprfs(
     sessions: Vec<OPRFSession>,
     evaluated_elts: &[[u8; 32]],
 ) -> Result<Vec<[u8; 64]>, PPSSError> {
-    std::iter::zip(sessions, evaluated_elts)
+    core::iter::zip(sessions, evaluated_elts)
         .map(|(session, bytes)| finalize_single_oprf(session, bytes))
         .collect()
 }
@@ -163,6 +166,8 @@ pub struct MaskedShareSet {
     pub server_ids: Vec<u64>,
     pub masked_shares: Vec<KeyShare>,
     pub commitment: [u8; 32],
+    /// How the password was strengthened before being used, if it was.
+    pub strengthening: Option<Strengthening>,
 }
 
 fn compute_commitment(
@@ -234,6 +239,7 @@ pub fn backup_secret<R: CryptoRngCore>(
         server_ids,
         masked_shares,
         commitment,
+        strengthening: None,
     })
 }
 
@@ -339,7 +345,8 @@ mod tests {
         let server_ids = vec![4u64, 1, 6];
         let oprf_servers = OPRFServerSet::new(&server_ids);
         // get the blinds - they are in order of server_id
-        let oprf_init_sessions = begin_oprfs(CONTEXT, &server_ids, password, &mut rng).unwrap();
+        let oprf_init_sessions =
+            begin_oprfs(CONTEXT, &server_ids, password.as_bytes(), &mut rng).unwrap();
 
         // eval the oprfs
         let eval_elt_bytes: Vec<[u8; 32]> = oprf_init_sessions
@@ -360,8 +367,13 @@ mod tests {
         .unwrap();
 
         // Now reconstruct
-        let oprf_restore_sessions =
-            begin_oprfs(CONTEXT, &masked_shareset.server_ids, password, &mut rng).unwrap();
+        let oprf_restore_sessions = begin_oprfs(
+            CONTEXT,
+            &masked_shareset.server_ids,
+            password.as_bytes(),
+            &mut rng,
+        )
+        .unwrap();
 
         // eval the oprfs
         let restore_eval_elt_bytes: Vec<[u8; 32]> = oprf_restore_sessions
@@ -401,6 +413,7 @@ mod tests {
             server_ids: vec![42],
             masked_shares: vec![[0u8; 32]],
             commitment: [1u8; 32],
+            strengthening: None,
         };
         assert!(matches!(
             restore_secret(CONTEXT, b"password", vec![], share_set),
@@ -0,0 +1,513 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Serialized form of a [`MaskedShareSet`], as stored by clients.
+//!
+//! The encoding is the one share sets were first written with, bincode with
+//! fixed-size little-endian integers, written out by hand so that it needs
+//! neither `std` nor serde:
+//!
+//! - a format version byte,
+//! - the server IDs, as a `u64` count followed by each ID as a `u64`,
+//! - the masked shares, as a `u64` count followed by each 32-byte share,
+//! - the 32-byte commitment,
+//! - for format versions 1 and 2, the 32-byte environment,
+//! - for format version 2 only, the password strengthening: a byte for the
+//!   scheme, which is always 1 for Argon2id, its memory size, iterations and
+//!   parallelism as `u32`s, and the 16-byte salt.
+//!
+//! Counts and sizes read from a serialized share set are checked against
+//! [`OpaqueMaskedShareSet::MAX_SERVERS`] and
+//! [`OpaqueMaskedShareSet::MAX_ARGON2_MEMORY_KB`] before anything is allocated
+//! for them, so a small malicious blob can't make the client allocate a lot.
+//! Likewise, [`OpaqueMaskedShareSet::MAX_ARGON2_ITERATIONS`] keeps it from
+//! making the client strengthen the password for minutes on end.
+
+use alloc::vec::Vec;
+
+use displaydoc::Display;
+
+use crate::{Argon2Strengthener, MaskedShareSet, StrengthenerParams, Strengthening};
+
+/// Share sets without the environment they were backed up to.
+const MASKED_SHARE_SET_FORMAT: u8 = 0;
+/// Share sets followed by a hash identifying the enclaves they were backed up
+/// to.
+const MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT: u8 = 1;
+/// Like [`MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT`], followed by how the
+/// password was strengthened.
+const MASKED_SHARE_SET_FORMAT_WITH_STRENGTHENING: u8 = 2;
+
+const STRENGTHENER_ARGON2ID: u8 = 1;
+
+/// A [`MaskedShareSet`] as clients store it, as an opaque blob.
+///
+/// Only this crate looks inside: share sets are made by
+/// [`Backup::finalize_with_environment`](crate::Backup::finalize_with_environment)
+/// and used by [`Restore::from_share_set`](crate::Restore::from_share_set).
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "test-support"), derive(Debug))]
+pub struct OpaqueMaskedShareSet {
+    pub(crate) inner: MaskedShareSet,
+    /// Identifies the enclaves the backup was made to, if known.
+    environment: Option<[u8; 32]>,
+}
+
+#[derive(Debug)]
+pub struct SerializeError;
+
+#[derive(Debug, Eq, PartialEq, Display)]
+pub enum DeserializeError {
+    /// Unexpected OpaqueMaskedShareSet serialization format version {0}
+    BadVersion(u8),
+    /// Unsupported OpaqueMaskedShareSet serialization format
+    BadFormat,
+    /// Declared length {0} is out of range
+    LengthOutOfRange(u64),
+    /// Declared number of Argon2 iterations {0} is out of range
+    IterationsOutOfRange(u32),
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DeserializeError {}
+
+impl OpaqueMaskedShareSet {
+    /// The most enclaves a share set can be spread over, with one share each.
+    /// Deployments use two or three.
+    pub const MAX_SERVERS: usize = 16;
+    /// The most memory, in KiB, a share set can have the password strengthened
+    /// with: 256 MiB. Strengthening for a backup takes a few tens of MiB at
+    /// most.
+    pub const MAX_ARGON2_MEMORY_KB: u32 = 1 << 18;
+    /// The most passes over that memory a share set can have the password
+    /// strengthened with. A handful is typical.
+    pub const MAX_ARGON2_ITERATIONS: u32 = 10;
+
+    /// Wraps a share set backed up to the enclaves identified by
+    /// `environment`.
+    pub(crate) fn with_environment(inner: MaskedShareSet, environment: [u8; 32]) -> Self {
+        Self {
+            inner,
+            environment: Some(environment),
+        }
+    }
+
+    /// Like [`Self::with_environment`], for tests that make share sets from
+    /// parts.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn new(inner: MaskedShareSet, environment: [u8; 32]) -> Self {
+        Self::with_environment(inner, environment)
+    }
+
+    /// Wraps a share set the way ones read from format version 0 are, without
+    /// an environment.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn without_environment(inner: MaskedShareSet) -> Self {
+        Self {
+            inner,
+            environment: None,
+        }
+    }
+
+    /// Whether the share set was backed up to the enclaves identified by
+    /// `environment`, or `None` if it predates recording them.
+    pub fn was_backed_up_to(&self, environment: &[u8; 32]) -> Option<bool> {
+        self.environment
+            .as_ref()
+            .map(|recorded| recorded == environment)
+    }
+
+    /// How the password was strengthened for the backup, if it was, so that
+    /// it can be strengthened again before restoring.
+    pub fn strengthening(&self) -> Option<&Strengthening> {
+        self.inner.strengthening.as_ref()
+    }
+
+    /// The environment the share set was backed up to, if it was recorded.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn environment(&self) -> Option<&[u8; 32]> {
+        self.environment.as_ref()
+    }
+
+    /// The share set itself, for tests that look inside.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn into_inner(self) -> MaskedShareSet {
+        self.inner
+    }
+
+    // OpaqueMaskedShareSet should be presented to the clients as an opaque blob,
+    // therefore serialize/deserialize should be the only public APIs for it,
+    // besides the environment check and test helpers.
+    pub fn serialize(&self) -> Result<Vec<u8>, SerializeError> {
+        let MaskedShareSet {
+            server_ids,
+            masked_shares,
+            commitment,
+            strengthening,
+        } = &self.inner;
+        // Share sets read from the old format are written back the same way,
+        // rather than with an environment they weren't made with.
+        let version = match (self.environment, strengthening) {
+            (None, None) => MASKED_SHARE_SET_FORMAT,
+            (Some(_), None) => MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT,
+            (Some(_), Some(_)) => MASKED_SHARE_SET_FORMAT_WITH_STRENGTHENING,
+            // Strengthened backups are all newer than the environment.
+            (None, Some(_)) => return Err(SerializeError),
+        };
+
+        let mut buf = Vec::with_capacity(
+            1 + 8 + 8 * server_ids.len() + 8 + 32 * masked_shares.len() + 32 + 32 + 29,
+        );
+        buf.push(version);
+        buf.extend_from_slice(&(server_ids.len() as u64).to_le_bytes());
+        for id in server_ids {
+            buf.extend_from_slice(&id.to_le_bytes());
+        }
+        buf.extend_from_slice(&(masked_shares.len() as u64).to_le_bytes());
+        for share in masked_shares {
+            buf.extend_from_slice(share);
+        }
+        buf.extend_from_slice(commitment);
+        if let Some(environment) = &self.environment {
+            buf.extend_from_slice(environment);
+        }
+        if let Some(Strengthening { params, salt }) = strengthening {
+            match params {
+                StrengthenerParams::Argon2id(Argon2Strengthener {
+                    memory_kb,
+                    iterations,
+                    parallelism,
+                }) => {
+                    buf.push(STRENGTHENER_ARGON2ID);
+                    buf.extend_from_slice(&memory_kb.to_le_bytes());
+                    buf.extend_from_slice(&iterations.to_le_bytes());
+                    buf.extend_from_slice(&parallelism.to_le_bytes());
+                }
+            }
+            buf.extend_from_slice(salt);
+        }
+        Ok(buf)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let (has_environment, has_strengthening, data) = match bytes {
+            [] => return Err(DeserializeError::BadFormat),
+            [MASKED_SHARE_SET_FORMAT, data @ ..] => (false, false, data),
+            [MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT, data @ ..] => (true, false, data),
+            [MASKED_SHARE_SET_FORMAT_WITH_STRENGTHENING, data @ ..] => (true, true, data),
+            [v, ..] => return Err(DeserializeError::BadVersion(*v)),
+        };
+
+        let mut reader = Reader(data);
+        let server_ids = reader.read_vec(Self::MAX_SERVERS, |r| {
+            r.read_array().map(u64::from_le_bytes)
+        })?;
+        let masked_shares = reader.read_vec(Self::MAX_SERVERS, Reader::read_array)?;
+        let commitment = reader.read_array()?;
+        let environment = if has_environment {
+            Some(reader.read_array()?)
+        } else {
+            None
+        };
+        let strengthening = if has_strengthening {
+            Some(reader.read_strengthening()?)
+        } else {
+            None
+        };
+        if !reader.0.is_empty() {
+            return Err(DeserializeError::BadFormat);
+        }
+
+        Ok(Self {
+            inner: MaskedShareSet {
+                server_ids,
+                masked_shares,
+                commitment,
+                strengthening,
+            },
+            environment,
+        })
+    }
+}
+
+/// Reads the fields of a serialized share set, front to back.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DeserializeError> {
+        if self.0.len() < N {
+            return Err(DeserializeError::BadFormat);
+        }
+        let (array, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(array.try_into().expect("correct length"))
+    }
+
+    /// Reads a count of at most `max_count` followed by that many items.
+    fn read_vec<T>(
+        &mut self,
+        max_count: usize,
+        mut read_item: impl FnMut(&mut Self) -> Result<T, DeserializeError>,
+    ) -> Result<Vec<T>, DeserializeError> {
+        let count = u64::from_le_bytes(self.read_array()?);
+        // The count is used to allocate, so it must be checked first.
+        let count = usize::try_from(count)
+            .ok()
+            .filter(|count| *count <= max_count)
+            .ok_or(DeserializeError::LengthOutOfRange(count))?;
+        (0..count).map(|_| read_item(self)).collect()
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DeserializeError> {
+        self.read_array().map(u32::from_le_bytes)
+    }
+
+    fn read_strengthening(&mut self) -> Result<Strengthening, DeserializeError> {
+        let params = match self.read_array::<1>()? {
+            [STRENGTHENER_ARGON2ID] => {
+                // Restoring allocates this much memory.
+                let memory_kb = self.read_u32()?;
+                if memory_kb > OpaqueMaskedShareSet::MAX_ARGON2_MEMORY_KB {
+                    return Err(DeserializeError::LengthOutOfRange(memory_kb.into()));
+                }
+                // And spends this much time, memory size times iterations.
+                let iterations = self.read_u32()?;
+                if iterations > OpaqueMaskedShareSet::MAX_ARGON2_ITERATIONS {
+                    return Err(DeserializeError::IterationsOutOfRange(iterations));
+                }
+                StrengthenerParams::Argon2id(Argon2Strengthener {
+                    memory_kb,
+                    iterations,
+                    parallelism: self.read_u32()?,
+                })
+            }
+            _ => return Err(DeserializeError::BadFormat),
+        };
+        let salt = self.read_array()?;
+        Ok(Strengthening { params, salt })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn new_empty_share_set() -> MaskedShareSet {
+        MaskedShareSet {
+            server_ids: vec![],
+            masked_shares: vec![],
+            commitment: [0; 32],
+            strengthening: None,
+        }
+    }
+
+    #[test]
+    fn serialized_share_set_has_version() {
+        let share_set = OpaqueMaskedShareSet::without_environment(new_empty_share_set());
+        assert_eq!(share_set.was_backed_up_to(&[0; 32]), None);
+        let bytes = share_set.serialize().expect("can serialize");
+        assert_eq!(MASKED_SHARE_SET_FORMAT, bytes[0]);
+
+        let share_set = OpaqueMaskedShareSet::new(new_empty_share_set(), [0; 32]);
+        let bytes = share_set.serialize().expect("can serialize");
+        assert_eq!(MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT, bytes[0]);
+    }
+
+    #[test]
+    fn deserialize_share_set_supported_version() {
+        let there = OpaqueMaskedShareSet::without_environment(new_empty_share_set())
+            .serialize()
+            .expect("can serialize");
+        let and_back = OpaqueMaskedShareSet::deserialize(&there);
+        assert!(and_back.is_ok(), "Should be able to deserialize");
+    }
+
+    #[test]
+    fn deserialize_share_set_bad_version() {
+        let there = {
+            let mut bytes = OpaqueMaskedShareSet::without_environment(new_empty_share_set())
+                .serialize()
+                .expect("can serialize");
+            bytes[0] = 0xff;
+            bytes
+        };
+        let and_back = OpaqueMaskedShareSet::deserialize(&there);
+        assert!(matches!(
+            and_back.expect_err("Unexpected deserialization success"),
+            DeserializeError::BadVersion(_),
+        ));
+    }
+
+    #[test]
+    fn serialized_share_set_layout() {
+        let share_set = OpaqueMaskedShareSet::new(
+            MaskedShareSet {
+                server_ids: vec![1, 0x0102],
+                masked_shares: vec![[0x11; 32]],
+                commitment: [0x33; 32],
+                strengthening: None,
+            },
+            [0x44; 32],
+        );
+        let bytes = share_set.serialize().expect("can serialize");
+        let expected = [
+            &[MASKED_SHARE_SET_FORMAT_WITH_ENVIRONMENT][..],
+            &hex!("0200000000000000 0100000000000000 0201000000000000"),
+            &hex!("0100000000000000"),
+            &[0x11; 32],
+            &[0x33; 32],
+            &[0x44; 32],
+        ]
+        .concat();
+        assert_eq!(bytes, expected);
+
+        let share_set = OpaqueMaskedShareSet::deserialize(&bytes).expect("can deserialize");
+        assert_eq!(share_set.environment(), Some(&[0x44; 32]));
+        assert_eq!(share_set.was_backed_up_to(&[0x44; 32]), Some(true));
+        assert_eq!(share_set.was_backed_up_to(&[0x55; 32]), Some(false));
+        let MaskedShareSet {
+            server_ids,
+            masked_shares,
+            commitment,
+            strengthening,
+        } = share_set.into_inner();
+        assert_eq!(server_ids, [1, 0x0102]);
+        assert_eq!(masked_shares, [[0x11; 32]]);
+        assert_eq!(commitment, [0x33; 32]);
+        assert_eq!(strengthening, None);
+    }
+
+    #[test]
+    fn serialized_strengthened_share_set_layout() {
+        let strengthening = Strengthening {
+            params: StrengthenerParams::Argon2id(Argon2Strengthener {
+                memory_kb: 0x10000,
+                iterations: 3,
+                parallelism: 1,
+            }),
+            salt: [0x55; 16],
+        };
+        let share_set = OpaqueMaskedShareSet::new(
+            MaskedShareSet {
+                strengthening: Some(strengthening),
+                ..new_empty_share_set()
+            },
+            [0x44; 32],
+        );
+        let bytes = share_set.serialize().expect("can serialize");
+        let expected = [
+            &[MASKED_SHARE_SET_FORMAT_WITH_STRENGTHENING][..],
+            &hex!("0000000000000000 0000000000000000"),
+            &[0; 32],
+            &[0x44; 32],
+            &hex!("01 00000100 03000000 01000000"),
+            &[0x55; 16],
+        ]
+        .concat();
+        assert_eq!(bytes, expected);
+
+        let share_set = OpaqueMaskedShareSet::deserialize(&bytes).expect("can deserialize");
+        assert_eq!(share_set.environment(), Some(&[0x44; 32]));
+        assert_eq!(share_set.into_inner().strengthening, Some(strengthening));
+
+        let mut unknown_scheme = bytes;
+        unknown_scheme[1 + 16 + 32 + 32] = 0xff;
+        assert_matches!(
+            OpaqueMaskedShareSet::deserialize(&unknown_scheme),
+            Err(DeserializeError::BadFormat)
+        );
+    }
+
+    #[test]
+    fn strengthened_share_set_needs_environment() {
+        let share_set = OpaqueMaskedShareSet::without_environment(MaskedShareSet {
+            strengthening: Some(Strengthening {
+                params: StrengthenerParams::Argon2id(Argon2Strengthener {
+                    memory_kb: 64,
+                    iterations: 1,
+                    parallelism: 1,
+                }),
+                salt: [0; 16],
+            }),
+            ..new_empty_share_set()
+        });
+        assert_matches!(share_set.serialize(), Err(SerializeError));
+    }
+
+    #[test]
+    fn deserialize_rejects_malformed_share_sets() {
+        let bytes = OpaqueMaskedShareSet::new(new_empty_share_set(), [0x44; 32])
+            .serialize()
+            .expect("can serialize");
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let truncated = &bytes[..bytes.len() - 1];
+        // Claims more server IDs than there are bytes left.
+        let mut too_long = bytes.clone();
+        too_long[1..9].copy_from_slice(&10u64.to_le_bytes());
+
+        for malformed in [&trailing[..], truncated, &too_long, &[]] {
+            assert_matches!(
+                OpaqueMaskedShareSet::deserialize(malformed),
+                Err(DeserializeError::BadFormat)
+            );
+        }
+    }
+
+    #[test]
+    fn deserialize_rejects_absurd_lengths() {
+        let bytes = OpaqueMaskedShareSet::new(
+            MaskedShareSet {
+                strengthening: Some(Strengthening {
+                    params: StrengthenerParams::Argon2id(Argon2Strengthener {
+                        memory_kb: 64,
+                        iterations: 1,
+                        parallelism: 1,
+                    }),
+                    salt: [0; 16],
+                }),
+                ..new_empty_share_set()
+            },
+            [0x44; 32],
+        )
+        .serialize()
+        .expect("can serialize");
+        let with_field = |offset: usize, value: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+            bytes
+        };
+
+        let too_many = OpaqueMaskedShareSet::MAX_SERVERS as u64 + 1;
+        for (malformed, declared) in [
+            (with_field(1, &u64::MAX.to_le_bytes()), u64::MAX),
+            (with_field(1, &too_many.to_le_bytes()), too_many),
+            (with_field(9, &u64::MAX.to_le_bytes()), u64::MAX),
+            (
+                with_field(1 + 16 + 32 + 32 + 1, &u32::MAX.to_le_bytes()),
+                u32::MAX.into(),
+            ),
+        ] {
+            assert_eq!(
+                OpaqueMaskedShareSet::deserialize(&malformed).map(|_| ()),
+                Err(DeserializeError::LengthOutOfRange(declared))
+            );
+        }
+
+        let too_many = OpaqueMaskedShareSet::MAX_ARGON2_ITERATIONS + 1;
+        assert_eq!(
+            OpaqueMaskedShareSet::deserialize(&with_field(
+                1 + 16 + 32 + 32 + 1 + 4,
+                &too_many.to_le_bytes()
+            ))
+            .map(|_| ()),
+            Err(DeserializeError::IterationsOutOfRange(too_many))
+        );
+    }
+}
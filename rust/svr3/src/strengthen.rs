@@ -0,0 +1,253 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Optional memory-hard strengthening of the password before it is used for
+//! PPSS.
+//!
+//! Without it, checking a password guess against the share set only takes a
+//! few hashes once the OPRF outputs are known. A strengthened backup uses the
+//! output of a [`PasswordStrengthener`] in place of the password, both as the
+//! OPRF input and in the commitment, so that each guess costs as much memory
+//! and time as the strengthener is configured to take. The share set records
+//! the parameters and a random salt, see [`Strengthening`], so that restoring
+//! can repeat the computation.
+//!
+//! Since the strengthening is meant to be slow, async callers should run
+//! [`Strengthening::apply`] off their executor, and back up or restore with
+//! the resulting [`StrengthenedPassword`].
+
+use rand_core::CryptoRngCore;
+use zeroize::Zeroizing;
+
+use crate::{Error, OpaqueMaskedShareSet};
+
+/// Turns a password into the 32-byte key that PPSS uses in its place.
+///
+/// Implementations must be deterministic, since restoring recomputes the key
+/// from the password and the [`Strengthening`] recorded in the share set.
+pub trait PasswordStrengthener: Send + Sync {
+    /// The parameters to record in share sets backed up with this strengthener.
+    fn params(&self) -> StrengthenerParams;
+
+    /// Derives the key for `password`, salted with `salt`.
+    fn strengthen(&self, password: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error>;
+}
+
+/// The strengthening schemes share sets can record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum StrengthenerParams {
+    Argon2id(Argon2Strengthener),
+}
+
+impl StrengthenerParams {
+    /// The built-in implementation of these parameters.
+    pub fn strengthener(&self) -> &dyn PasswordStrengthener {
+        match self {
+            Self::Argon2id(argon2) => argon2,
+        }
+    }
+
+    /// Whether share sets can record these parameters; see
+    /// [`OpaqueMaskedShareSet::MAX_ARGON2_MEMORY_KB`] and
+    /// [`OpaqueMaskedShareSet::MAX_ARGON2_ITERATIONS`].
+    fn is_within_limits(&self) -> bool {
+        match self {
+            Self::Argon2id(argon2) => {
+                argon2.memory_kb <= OpaqueMaskedShareSet::MAX_ARGON2_MEMORY_KB
+                    && argon2.iterations <= OpaqueMaskedShareSet::MAX_ARGON2_ITERATIONS
+            }
+        }
+    }
+}
+
+/// How the password of a share set was strengthened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Strengthening {
+    pub params: StrengthenerParams,
+    pub salt: [u8; Self::SALT_LEN],
+}
+
+impl Strengthening {
+    pub const SALT_LEN: usize = 16;
+
+    /// Picks a fresh salt for strengthening the password of a new backup with
+    /// `params`.
+    pub fn generate(
+        params: StrengthenerParams,
+        rng: &mut impl CryptoRngCore,
+    ) -> Result<Self, Error> {
+        // A share set that couldn't be read back would be no use.
+        if !params.is_within_limits() {
+            return Err(Error::StrengtheningOutOfRange);
+        }
+        let mut salt = [0; Self::SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        Ok(Self { params, salt })
+    }
+
+    /// Strengthens `password` this way, which takes as long as the
+    /// parameters say.
+    ///
+    /// `strengthener` can stand in for the built-in implementation, but only
+    /// if it has these parameters. With any others a restore would be bound
+    /// to fail, using up one of the tries.
+    pub fn apply(
+        &self,
+        strengthener: Option<&dyn PasswordStrengthener>,
+        password: &[u8],
+    ) -> Result<StrengthenedPassword, Error> {
+        let strengthener = match strengthener {
+            None => self.params.strengthener(),
+            Some(strengthener) if strengthener.params() == self.params => strengthener,
+            Some(_) => return Err(Error::StrengthenerMismatch),
+        };
+        if !self.params.is_within_limits() {
+            return Err(Error::StrengtheningOutOfRange);
+        }
+        Ok(StrengthenedPassword {
+            strengthening: *self,
+            key: strengthener.strengthen(password, &self.salt)?,
+        })
+    }
+}
+
+/// A password run through [`Strengthening::apply`], to back up or restore
+/// with in its place.
+///
+/// The key is zeroed when dropped.
+pub struct StrengthenedPassword {
+    pub(crate) strengthening: Strengthening,
+    pub(crate) key: Zeroizing<[u8; 32]>,
+}
+
+/// Argon2id as specified by RFC 9106, version 0x13.
+///
+/// The lanes are computed one after another, so `parallelism` affects the
+/// output but doesn't spread the work over several threads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Argon2Strengthener {
+    /// Memory size in KiB, at least 8 per lane
+    pub memory_kb: u32,
+    /// Number of passes over the memory
+    pub iterations: u32,
+    /// Number of lanes
+    pub parallelism: u32,
+}
+
+impl PasswordStrengthener for Argon2Strengthener {
+    fn params(&self) -> StrengthenerParams {
+        StrengthenerParams::Argon2id(*self)
+    }
+
+    fn strengthen(&self, password: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+        let params =
+            argon2::Params::new(self.memory_kb, self.iterations, self.parallelism, Some(32))?;
+        let mut key = Zeroizing::new([0; 32]);
+        argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(password, salt, key.as_mut())?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use hex_literal::hex;
+    use rand_core::OsRng;
+
+    use super::*;
+
+    const WEAK: Argon2Strengthener = Argon2Strengthener {
+        memory_kb: 32,
+        iterations: 3,
+        parallelism: 4,
+    };
+
+    #[test]
+    fn argon2_output_is_stable() {
+        // The inputs of the Argon2id test vector of RFC 9106, section 5.3,
+        // minus the secret and associated data that the strengthener doesn't
+        // use. Any change to the output would break restoring existing backups.
+        let key = WEAK
+            .strengthen(&[0x01; 32], &[0x02; 16])
+            .expect("valid params");
+        assert_eq!(
+            *key,
+            hex!("03aab965c12001c9d7d0d2de33192c0494b684bb148196d73c1df1acaf6d0c2e")
+        );
+    }
+
+    #[test]
+    fn argon2_rejects_invalid_params() {
+        let too_little_memory = Argon2Strengthener {
+            memory_kb: 8,
+            ..WEAK
+        };
+        let no_iterations = Argon2Strengthener {
+            iterations: 0,
+            ..WEAK
+        };
+        for strengthener in [too_little_memory, no_iterations] {
+            assert_matches!(
+                strengthener.strengthen(b"password", &[0; 16]),
+                Err(Error::Argon2(_))
+            );
+        }
+    }
+
+    #[test]
+    fn apply_uses_the_recorded_params() {
+        let strengthening =
+            Strengthening::generate(WEAK.params(), &mut OsRng).expect("valid params");
+        assert_eq!(strengthening.params, StrengthenerParams::Argon2id(WEAK));
+        let key = WEAK
+            .strengthen(b"password", &strengthening.salt)
+            .expect("valid params");
+        let apply = |strengthener: Option<&dyn PasswordStrengthener>, password: &[u8]| {
+            strengthening
+                .apply(strengthener, password)
+                .map(|strengthened| strengthened.key)
+        };
+        assert_matches!(apply(None, b"password"), Ok(k) if k == key);
+        assert_matches!(apply(Some(&WEAK), b"password"), Ok(k) if k == key);
+        assert_matches!(apply(None, b"wrong password"), Ok(k) if k != key);
+    }
+
+    #[test]
+    fn apply_refuses_other_params() {
+        let strengthening =
+            Strengthening::generate(WEAK.params(), &mut OsRng).expect("valid params");
+        let other = Argon2Strengthener {
+            iterations: 4,
+            ..WEAK
+        };
+        assert_matches!(
+            strengthening.apply(Some(&other), b"password").err(),
+            Some(Error::StrengthenerMismatch)
+        );
+    }
+
+    #[test]
+    fn params_share_sets_cant_record_are_refused() {
+        // Refused before any strengthening, which would take far too long.
+        let too_many_iterations = Argon2Strengthener {
+            iterations: u32::MAX,
+            ..WEAK
+        };
+        assert_matches!(
+            Strengthening::generate(too_many_iterations.params(), &mut OsRng),
+            Err(Error::StrengtheningOutOfRange)
+        );
+        let strengthening = Strengthening {
+            params: too_many_iterations.params(),
+            salt: [0; Strengthening::SALT_LEN],
+        };
+        assert_matches!(
+            strengthening.apply(None, b"password").err(),
+            Some(Error::StrengtheningOutOfRange)
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! In-memory stand-in for an SVR3 server, for use in tests.
+//!
+//! [`InMemorySvr3Server`] answers the same protobuf requests a real server
+//! does, after the attested channel has been set up. It follows the semantics
+//! of the real server: every evaluation uses up one try, data is removed once
+//! the tries run out, and a restore with the wrong password is only detected
+//! by the client as a bad commitment.
+//!
+//! Only the plaintext exchange is simulated: requests and responses are
+//! passed in and out as bytes with [`InMemorySvr3Server::handle_request`].
+//! There is no attested transport to back up or restore over; a test that
+//! needs one has to set up the Noise session and websocket itself, the way
+//! `libsignal-net`'s own tests do.
+
+use std::collections::HashMap;
+
+use curve25519_dalek::ristretto::CompressedRistretto;
+use curve25519_dalek::scalar::Scalar;
+use prost::Message;
+use rand_core::OsRng;
+
+use crate::proto::svr3;
+use crate::proto::svr3::{create_response, evaluate_response, query_response};
+use crate::{ppss, CONTEXT};
+
+/// Account identifier the server stores data under.
+pub type Uid = [u8; 16];
+
+#[derive(Clone, Debug)]
+struct StoredKey {
+    oprf_key: Scalar,
+    tries_remaining: u32,
+}
+
+/// A single SVR3 server, storing per-UID OPRF keys in memory.
+#[derive(Clone, Debug, Default)]
+pub struct InMemorySvr3Server {
+    data: HashMap<Uid, StoredKey>,
+    fixed_oprf_key: Option<Scalar>,
+}
+
+impl InMemorySvr3Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a server that uses `oprf_key` for every backup instead of a
+    /// random key, so that backups made with a seeded RNG are reproducible.
+    pub fn with_fixed_oprf_key(oprf_key: [u8; 32]) -> Self {
+        Self {
+            fixed_oprf_key: Some(Scalar::from_bytes_mod_order(oprf_key)),
+            ..Self::default()
+        }
+    }
+
+    /// Number of restore attempts left for `uid`, or `None` if nothing is stored.
+    pub fn tries_remaining(&self, uid: &Uid) -> Option<u32> {
+        self.data.get(uid).map(|stored| stored.tries_remaining)
+    }
+
+    /// Handles a serialized SVR3 client request on behalf of `uid`.
+    ///
+    /// Returns the serialized response, or `None` if the request could not be
+    /// decoded, in which case a real server would drop the connection.
+    pub fn handle_request(&mut self, uid: Uid, request: &[u8]) -> Option<Vec<u8>> {
+        use svr3::request::Inner as Request;
+        use svr3::response::Inner as Response;
+
+        let response = match svr3::Request::decode(request).ok()?.inner? {
+            Request::Create(request) => Response::Create(self.create(uid, request)),
+            Request::Evaluate(request) => Response::Evaluate(self.evaluate(uid, request)),
+            Request::Remove(svr3::RemoveRequest {}) => {
+                let _ = self.data.remove(&uid);
+                Response::Remove(svr3::RemoveResponse {})
+            }
+            Request::Query(svr3::QueryRequest {}) => Response::Query(self.query(&uid)),
+        };
+        Some(
+            svr3::Response {
+                inner: Some(response),
+            }
+            .encode_to_vec(),
+        )
+    }
+
+    fn create(&mut self, uid: Uid, request: svr3::CreateRequest) -> svr3::CreateResponse {
+        let svr3::CreateRequest {
+            max_tries,
+            blinded_element,
+        } = request;
+        if !(1..=crate::MAX_ALLOWED_TRIES).contains(&max_tries) {
+            return create_response_with_status(create_response::Status::InvalidRequest);
+        }
+        let oprf_key = self
+            .fixed_oprf_key
+            .unwrap_or_else(|| Scalar::random(&mut OsRng));
+        let Some(evaluated_element) = evaluate(&oprf_key, &blinded_element) else {
+            return create_response_with_status(create_response::Status::InvalidRequest);
+        };
+        let _ = self.data.insert(
+            uid,
+            StoredKey {
+                oprf_key,
+                tries_remaining: max_tries,
+            },
+        );
+        svr3::CreateResponse {
+            status: create_response::Status::Ok.into(),
+            evaluated_element,
+        }
+    }
+
+    fn evaluate(&mut self, uid: Uid, request: svr3::EvaluateRequest) -> svr3::EvaluateResponse {
+        let svr3::EvaluateRequest { blinded_element } = request;
+        let Some(stored) = self.data.get_mut(&uid) else {
+            return evaluate_response_with_status(evaluate_response::Status::Missing);
+        };
+        if stored.tries_remaining == 0 {
+            let _ = self.data.remove(&uid);
+            return evaluate_response_with_status(evaluate_response::Status::Missing);
+        }
+        let Some(evaluated_element) = evaluate(&stored.oprf_key, &blinded_element) else {
+            return evaluate_response_with_status(evaluate_response::Status::InvalidRequest);
+        };
+        // The try is used up whether or not the client knows the right
+        // password; the server has no way to tell.
+        stored.tries_remaining -= 1;
+        svr3::EvaluateResponse {
+            status: evaluate_response::Status::Ok.into(),
+            evaluated_element,
+            tries_remaining: stored.tries_remaining,
+        }
+    }
+
+    fn query(&self, uid: &Uid) -> svr3::QueryResponse {
+        match self.data.get(uid) {
+            Some(stored) => svr3::QueryResponse {
+                status: query_response::Status::Ok.into(),
+                tries_remaining: stored.tries_remaining,
+            },
+            None => svr3::QueryResponse {
+                status: query_response::Status::Missing.into(),
+                tries_remaining: 0,
+            },
+        }
+    }
+}
+
+/// The output of the OPRF with `oprf_key` on `password` for the server
+/// `server_id`, as a client restoring from it unblinds it.
+///
+/// These are the outputs [`restore_from_oprf_outputs`] takes, for share sets
+/// backed up without password strengthening.
+///
+/// [`restore_from_oprf_outputs`]: crate::restore_from_oprf_outputs
+pub fn oprf_output(oprf_key: [u8; 32], server_id: u64, password: &str) -> [u8; 64] {
+    let sessions = ppss::begin_oprfs(CONTEXT, &[server_id], password.as_bytes(), &mut OsRng)
+        .expect("password doesn't hash to the identity");
+    let evaluated_element = evaluate(
+        &Scalar::from_bytes_mod_order(oprf_key),
+        &sessions[0].blinded_elt_bytes,
+    )
+    .expect("blinded element is a valid point")
+    .try_into()
+    .expect("32 bytes");
+    let [output] = ppss::finalize_oprfs(sessions, &[evaluated_element])
+        .expect("evaluated element is a valid point")
+        .try_into()
+        .expect("one output");
+    output
+}
+
+fn evaluate(oprf_key: &Scalar, blinded_element: &[u8]) -> Option<Vec<u8>> {
+    let blinded_element = CompressedRistretto::from_slice(blinded_element)
+        .ok()?
+        .decompress()?;
+    Some((oprf_key * blinded_element).compress().to_bytes().into())
+}
+
+fn create_response_with_status(status: create_response::Status) -> svr3::CreateResponse {
+    svr3::CreateResponse {
+        status: status.into(),
+        evaluated_element: vec![],
+    }
+}
+
+fn evaluate_response_with_status(status: evaluate_response::Status) -> svr3::EvaluateResponse {
+    svr3::EvaluateResponse {
+        status: status.into(),
+        evaluated_element: vec![],
+        tries_remaining: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::{
+        restore_from_oprf_outputs, Argon2Strengthener, Backup, Error, ErrorStatus, MaskedShareSet,
+        OpaqueMaskedShareSet, PPSSError, Query, Remove, Restore,
+    };
+
+    const UID: Uid = [1; 16];
+    const SECRET: [u8; 32] = [42; 32];
+
+    fn servers() -> [InMemorySvr3Server; 2] {
+        [InMemorySvr3Server::new(), InMemorySvr3Server::new()]
+    }
+
+    fn round_trip(servers: &mut [InMemorySvr3Server], requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        servers
+            .iter_mut()
+            .zip(requests)
+            .map(|(server, request)| server.handle_request(UID, request).expect("valid request"))
+            .collect()
+    }
+
+    fn backup(servers: &mut [InMemorySvr3Server], max_tries: u32) -> MaskedShareSet {
+        let backup = Backup::new(
+            &[1, 2],
+            "password",
+            SECRET,
+            max_tries.try_into().unwrap(),
+            &mut OsRng,
+        )
+        .expect("can create backup");
+        let responses = round_trip(servers, &backup.requests);
+        backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup")
+    }
+
+    fn restore(
+        servers: &mut [InMemorySvr3Server],
+        password: &str,
+        share_set: MaskedShareSet,
+    ) -> Result<[u8; 32], Error> {
+        let restore = Restore::new(password, share_set, &mut OsRng).expect("can create restore");
+        let responses = round_trip(servers, &restore.requests);
+        restore.finalize(&responses)
+    }
+
+    #[test]
+    fn backup_then_restore() {
+        let mut servers = servers();
+        let share_set = backup(&mut servers, 3);
+        assert_matches!(restore(&mut servers, "password", share_set), Ok(SECRET));
+        assert!(servers.iter().all(|s| s.tries_remaining(&UID) == Some(2)));
+    }
+
+    #[test]
+    fn bad_password_fails_restore_and_uses_a_try() {
+        let mut servers = servers();
+        let share_set = backup(&mut servers, 3);
+        assert_matches!(
+            restore(&mut servers, "wrong password", share_set.clone()),
+            Err(Error::RestoreFailed(2))
+        );
+        assert!(servers.iter().all(|s| s.tries_remaining(&UID) == Some(2)));
+        assert_matches!(restore(&mut servers, "password", share_set), Ok(SECRET));
+    }
+
+    #[test]
+    fn tries_limit_is_enforced() {
+        let mut servers = servers();
+        let share_set = backup(&mut servers, 1);
+        assert_matches!(
+            restore(&mut servers, "password", share_set.clone()),
+            Ok(SECRET)
+        );
+        assert_matches!(
+            restore(&mut servers, "password", share_set.clone()),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+        assert!(servers.iter().all(|s| s.tries_remaining(&UID).is_none()));
+    }
+
+    #[test]
+    fn restore_from_oprf_outputs_matches_restore() {
+        const OPRF_KEYS: [[u8; 32]; 2] = [[1; 32], [2; 32]];
+        let mut servers = OPRF_KEYS.map(InMemorySvr3Server::with_fixed_oprf_key);
+        let share_set = backup(&mut servers, 3);
+        let restore_offline = |password: &str| {
+            let outputs: Vec<_> = OPRF_KEYS
+                .iter()
+                .zip(&share_set.server_ids)
+                .map(|(key, server_id)| oprf_output(*key, *server_id, password))
+                .collect();
+            restore_from_oprf_outputs(
+                password,
+                OpaqueMaskedShareSet::without_environment(share_set.clone()),
+                &outputs,
+            )
+        };
+
+        assert_matches!(restore_offline("password"), Ok(SECRET));
+        assert_matches!(
+            restore_offline("wrong password"),
+            Err(Error::Ppss(PPSSError::InvalidCommitment))
+        );
+        // No tries are used up.
+        assert!(servers.iter().all(|s| s.tries_remaining(&UID) == Some(3)));
+        assert_matches!(restore(&mut servers, "password", share_set), Ok(SECRET));
+    }
+
+    const ARGON2: Argon2Strengthener = Argon2Strengthener {
+        memory_kb: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn strengthened_backup(servers: &mut [InMemorySvr3Server]) -> MaskedShareSet {
+        let backup = Backup::new_with_strengthener(
+            &[1, 2],
+            "password",
+            SECRET,
+            nonzero!(3u32),
+            Some(&ARGON2),
+            &mut OsRng,
+        )
+        .expect("can create backup");
+        let responses = round_trip(servers, &backup.requests);
+        backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup")
+    }
+
+    #[test]
+    fn strengthened_backup_then_restore() {
+        let mut servers = servers();
+        let share_set = strengthened_backup(&mut servers);
+        assert!(share_set.strengthening.is_some());
+        // The share set is enough to know how to strengthen the password.
+        assert_matches!(
+            restore(&mut servers, "password", share_set.clone()),
+            Ok(SECRET)
+        );
+        assert_matches!(
+            restore(&mut servers, "wrong password", share_set.clone()),
+            Err(Error::RestoreFailed(1))
+        );
+
+        let restore =
+            Restore::new_with_strengthener("password", share_set, Some(&ARGON2), &mut OsRng)
+                .expect("can create restore");
+        let responses = round_trip(&mut servers, &restore.requests);
+        assert_matches!(restore.finalize(&responses), Ok(SECRET));
+    }
+
+    #[test]
+    fn mismatched_strengthener_is_refused_before_using_a_try() {
+        let mut servers = servers();
+        let share_set = strengthened_backup(&mut servers);
+        let other = Argon2Strengthener {
+            memory_kb: 128,
+            ..ARGON2
+        };
+        assert_matches!(
+            Restore::new_with_strengthener("password", share_set, Some(&other), &mut OsRng).err(),
+            Some(Error::StrengthenerMismatch)
+        );
+        assert!(servers.iter().all(|s| s.tries_remaining(&UID) == Some(3)));
+    }
+
+    #[test]
+    fn unstrengthened_share_set_ignores_strengthener() {
+        let mut servers = servers();
+        let share_set = backup(&mut servers, 3);
+        let restore =
+            Restore::new_with_strengthener("password", share_set, Some(&ARGON2), &mut OsRng)
+                .expect("can create restore");
+        let responses = round_trip(&mut servers, &restore.requests);
+        assert_matches!(restore.finalize(&responses), Ok(SECRET));
+    }
+
+    fn query(servers: &mut [InMemorySvr3Server]) -> Result<u32, Error> {
+        let query = Query::new(&[1, 2]);
+        let responses = round_trip(servers, &query.requests);
+        query.finalize(&responses)
+    }
+
+    #[test]
+    fn query_counts_down_with_restores() {
+        let mut servers = servers();
+        assert_matches!(
+            query(&mut servers),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+        let share_set = backup(&mut servers, 3);
+        assert_matches!(query(&mut servers), Ok(3));
+        assert_matches!(restore(&mut servers, "password", share_set), Ok(SECRET));
+        assert_matches!(query(&mut servers), Ok(2));
+    }
+
+    #[test]
+    fn remove_deletes_data() {
+        let mut servers = servers();
+        let share_set = backup(&mut servers, 3);
+        let remove = Remove::new(&[1, 2]);
+        let responses = round_trip(&mut servers, &remove.requests);
+        remove.finalize(&responses).expect("can finalize remove");
+        assert_matches!(
+            restore(&mut servers, "password", share_set),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+    }
+
+    #[test]
+    fn create_rejects_out_of_range_tries() {
+        let mut server = InMemorySvr3Server::new();
+        let backup = Backup::new(&[1], "password", SECRET, nonzero!(1u32), &mut OsRng)
+            .expect("can create backup");
+        let mut request = svr3::Request::decode(backup.requests[0].as_slice()).unwrap();
+        let Some(svr3::request::Inner::Create(create)) = request.inner.as_mut() else {
+            unreachable!("backup sends create requests");
+        };
+        create.max_tries = crate::MAX_ALLOWED_TRIES + 1;
+
+        let response = server
+            .handle_request(UID, &request.encode_to_vec())
+            .expect("valid request");
+        assert_matches!(
+            svr3::Response::decode(response.as_slice()).unwrap().inner,
+            Some(svr3::response::Inner::Create(response))
+                if response.status() == create_response::Status::InvalidRequest
+        );
+        assert!(!Backup::is_accepted(&response));
+        assert_eq!(server.tries_remaining(&UID), None);
+
+        let response = server
+            .handle_request(UID, &backup.requests[0])
+            .expect("valid request");
+        assert!(Backup::is_accepted(&response));
+    }
+}
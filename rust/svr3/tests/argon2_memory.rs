@@ -0,0 +1,37 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Checks that Argon2 strengthening really takes the memory it is configured
+//! with, by measuring its peak heap usage.
+//!
+//! This is a test binary of its own with a single test, so that the counting
+//! allocator sees little besides the strengthening.
+
+mod support;
+
+use libsignal_svr3::{Argon2Strengthener, PasswordStrengthener};
+use support::peak_heap_usage;
+
+#[test]
+fn argon2_uses_configured_memory() {
+    for memory_kb in [256, 1024, 8192] {
+        let strengthener = Argon2Strengthener {
+            memory_kb,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let peak = peak_heap_usage(|| {
+            strengthener
+                .strengthen(b"password", &[0; 16])
+                .expect("valid params");
+        });
+
+        let configured = memory_kb as usize * 1024;
+        assert!(
+            (configured..configured + configured / 4).contains(&peak),
+            "{memory_kb} KiB configured, peak heap usage {peak} bytes"
+        );
+    }
+}
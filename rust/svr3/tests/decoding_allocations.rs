@@ -0,0 +1,73 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Checks that decoding share sets and enclave responses that declare absurd
+//! lengths fails without allocating anything like those lengths, by measuring
+//! peak heap usage.
+//!
+//! Like `argon2_memory`, this is a test binary of its own with a single test,
+//! so that the counting allocator sees little besides the decoding.
+
+mod support;
+
+use assert_matches::assert_matches;
+use nonzero_ext::nonzero;
+use rand_core::OsRng;
+
+use libsignal_svr3::{Backup, DeserializeError, Error, OpaqueMaskedShareSet};
+use support::peak_heap_usage;
+
+/// Well above what decoding a valid share set or response takes, and far
+/// below any of the declared lengths.
+const ALLOCATION_LIMIT: usize = 4096;
+
+#[test]
+fn absurd_lengths_are_rejected_before_allocating() {
+    let share_set = |version: u8, fields: &[&[u8]]| [&[version][..], &fields.concat()].concat();
+    let strengthened_share_set = |memory_kb: u32| {
+        share_set(
+            2,
+            &[
+                &0u64.to_le_bytes(),
+                &0u64.to_le_bytes(),
+                &[0; 32],
+                &[0; 32],
+                &[1],
+                &memory_kb.to_le_bytes(),
+                &1u32.to_le_bytes(),
+                &1u32.to_le_bytes(),
+                &[0; 16],
+            ],
+        )
+    };
+    let malicious_share_sets = [
+        (share_set(0, &[&u64::MAX.to_le_bytes()]), u64::MAX),
+        (share_set(1, &[&(1u64 << 40).to_le_bytes()]), 1 << 40),
+        (
+            share_set(0, &[&0u64.to_le_bytes(), &(1u64 << 40).to_le_bytes()]),
+            1 << 40,
+        ),
+        (strengthened_share_set(u32::MAX), u32::MAX.into()),
+    ];
+    for (bytes, declared) in malicious_share_sets {
+        let peak = peak_heap_usage(|| {
+            assert_eq!(
+                OpaqueMaskedShareSet::deserialize(&bytes).map(|_| ()),
+                Err(DeserializeError::LengthOutOfRange(declared))
+            );
+        });
+        assert!(peak < ALLOCATION_LIMIT, "peak heap usage {peak} bytes");
+    }
+
+    // A protobuf length-delimited field 1 declaring a length of 2^64 - 1.
+    let malicious_response = [&[0x0a][..], &[0xff; 9], &[0x01]].concat();
+    let backup = Backup::new(&[1, 2], "password", [0; 32], nonzero!(10u32), &mut OsRng)
+        .expect("can create backup");
+    let responses = vec![malicious_response.clone(), malicious_response];
+    let peak = peak_heap_usage(|| {
+        assert_matches!(backup.finalize(&mut OsRng, &responses), Err(Error::BadData));
+    });
+    assert!(peak < ALLOCATION_LIMIT, "peak heap usage {peak} bytes");
+}
@@ -56,9 +56,12 @@ public enum SignalError: Error {
     case networkError(String)
     case networkProtocolError(String)
     case rateLimitedError(retryAfter: TimeInterval, message: String)
+    case cancelled(String)
+    case clientDeprecated(String)
     case unknown(UInt32, String)
     case svrDataMissing(String)
     case svrRestoreFailed(String)
+    case svrEnclaveDisagreement(accepted: [Bool], message: String)
 }
 
 internal typealias SignalFfiErrorRef = OpaquePointer
@@ -173,10 +176,19 @@ internal func checkError(_ error: SignalFfiErrorRef?) throws {
             signal_error_get_retry_after_seconds(error, $0)
         }
         throw SignalError.rateLimitedError(retryAfter: TimeInterval(retryAfterSeconds), message: errStr)
+    case SignalErrorCodeCancelled:
+        throw SignalError.cancelled(errStr)
+    case SignalErrorCodeClientDeprecated:
+        throw SignalError.clientDeprecated(errStr)
     case SignalErrorCodeSvrDataMissing:
         throw SignalError.svrDataMissing(errStr)
     case SignalErrorCodeSvrRestoreFailed:
         throw SignalError.svrRestoreFailed(errStr)
+    case SignalErrorCodeSvrEnclaveDisagreement:
+        let accepted = try invokeFnReturningArray {
+            signal_error_get_svr_accepted(error, $0)
+        }
+        throw SignalError.svrEnclaveDisagreement(accepted: accepted.map { $0 != 0 }, message: errStr)
     default:
         throw SignalError.unknown(errType, errStr)
     }
@@ -20,6 +20,22 @@ public class Net {
 
         /// Signal's production environment.
         case production = 1
+
+        /// Describes the SVR3 and CDSI enclaves of this environment, for
+        /// checking which ones a client is configured with.
+        ///
+        /// The result is safe to log. It is a JSON object with the fields
+        /// `svr3` and `cdsi`, holding the hostnames, ports, path prefixes,
+        /// route weights, enclave measurements, and SVR3 server IDs, along
+        /// with a short `hash` of each part that only changes when the
+        /// configuration does.
+        public var fingerprint: String {
+            failOnError {
+                try invokeFnReturningString {
+                    signal_environment_fingerprint($0, self.rawValue)
+                }
+            }
+        }
     }
 
     /// An SVR3 client providing backup and restore functionality.
@@ -32,7 +48,18 @@ public class Net {
         self.svr3 = Svr3Client(self.asyncContext, self.connectionManager)
     }
 
-    /// Like ``cdsiLookup(auth:request:timeout:)`` but with the parameters to ``CdsiLookupRequest`` broken out.
+    /// Notifies libsignal that the device's network changed, for example from
+    /// Wi-Fi to cellular.
+    ///
+    /// Operations that then fail on connections made before the change throw a
+    /// `SignalError.networkError` saying so, and should be run again.
+    public func networkDidChange() {
+        self.connectionManager.withNativeHandle { connectionManager in
+            failOnError(signal_connection_manager_on_network_change(connectionManager))
+        }
+    }
+
+    /// Like ``cdsiLookup(auth:request:timeout:cancellation:)`` but with the parameters to ``CdsiLookupRequest`` broken out.
     public func cdsiLookup(
         auth: Auth,
         prevE164s: [String],
@@ -40,10 +67,11 @@ public class Net {
         acisAndAccessKeys: [AciAndAccessKey],
         returnAcisWithoutUaks: Bool,
         token: Data?,
-        timeout: TimeInterval
+        timeout: TimeInterval,
+        cancellation: CancellationSignal = CancellationSignal()
     ) async throws -> CdsiLookup {
         let request = try CdsiLookupRequest(e164s: e164s, prevE164s: prevE164s, acisAndAccessKeys: acisAndAccessKeys, token: token, returnAcisWithoutUaks: returnAcisWithoutUaks)
-        return try await self.cdsiLookup(auth: auth, request: request, timeout: timeout)
+        return try await self.cdsiLookup(auth: auth, request: request, timeout: timeout, cancellation: cancellation)
     }
 
     /// Starts a new CDSI lookup request.
@@ -57,6 +85,9 @@ public class Net {
     ///   - auth: The information to use when authenticating with the CDSI server.
     ///   - request: The CDSI request to be sent to the server.
     ///   - timeout: The amount of time to wait for the initial connection before giving up.
+    ///   - cancellation: If this gets cancelled, the lookup is abandoned and
+    ///     `SignalError.cancelled` is thrown, both here and from
+    ///     ``CdsiLookup/complete()``.
     ///
     /// - Returns:
     ///   An object representing the in-progress request. If this method
@@ -91,19 +122,22 @@ public class Net {
     public func cdsiLookup(
         auth: Auth,
         request: CdsiLookupRequest,
-        timeout: TimeInterval
+        timeout: TimeInterval,
+        cancellation: CancellationSignal = CancellationSignal()
     ) async throws -> CdsiLookup {
         let timeoutMs = durationToMillis(timeout)
         let handle: OpaquePointer = try await invokeAsyncFunction { promise, context in
             self.asyncContext.withNativeHandle { asyncContext in
                 self.connectionManager.withNativeHandle { connectionManager in
-                    request.withNativeHandle { request in
-                        signal_cdsi_lookup_new(promise, context, asyncContext, connectionManager, auth.username, auth.password, request, timeoutMs)
+                    cancellation.withNativeHandle { cancellation in
+                        request.withNativeHandle { request in
+                            signal_cdsi_lookup_new(promise, context, asyncContext, connectionManager, cancellation, auth.username, auth.password, request, timeoutMs)
+                        }
                     }
                 }
             }
         }
-        return CdsiLookup(native: handle, asyncContext: self.asyncContext)
+        return CdsiLookup(native: handle, asyncContext: self.asyncContext, cancellation: cancellation)
     }
 
     private var asyncContext: TokioAsyncContext
@@ -204,7 +238,7 @@ public class CdsiLookupRequest: NativeHandleOwner {
 
 /// CDSI lookup in progress.
 ///
-/// Returned by ``Net/cdsiLookup(auth:request:timeout:)`` when a request is successfully initiated.
+/// Returned by ``Net/cdsiLookup(auth:request:timeout:cancellation:)`` when a request is successfully initiated.
 public class CdsiLookup {
     class NativeCdsiLookup: NativeHandleOwner {
         override internal class func destroyNativeHandle(_ handle: OpaquePointer) -> SignalFfiErrorRef? {
@@ -214,10 +248,12 @@ public class CdsiLookup {
 
     private var asyncContext: TokioAsyncContext
     private var native: NativeCdsiLookup
+    private var cancellation: CancellationSignal
 
-    internal init(native: OpaquePointer, asyncContext: TokioAsyncContext) {
+    internal init(native: OpaquePointer, asyncContext: TokioAsyncContext, cancellation: CancellationSignal) {
         self.native = NativeCdsiLookup(owned: native)
         self.asyncContext = asyncContext
+        self.cancellation = cancellation
     }
 
     /// The token returned by the CDSI server.
@@ -244,12 +280,15 @@ public class CdsiLookup {
     ///
     /// - Throws: ``SignalError`` if the request fails for any reason, including
     ///   `SignalError.networkError` for a network-level connectivity issue,
-    ///   `SignalError.networkProtocolError` for a CDSI or attested connection protocol issue.
+    ///   `SignalError.networkProtocolError` for a CDSI or attested connection protocol issue,
+    ///   `SignalError.cancelled` if the ``CancellationSignal`` the lookup was started with gets cancelled.
     public func complete() async throws -> CdsiLookupResponse {
         let response: SignalFfiCdsiLookupResponse = try await invokeAsyncFunction { promise, context in
             self.asyncContext.withNativeHandle { asyncContext in
                 self.native.withNativeHandle { handle in
-                    signal_cdsi_lookup_complete(promise, context, asyncContext, handle)
+                    self.cancellation.withNativeHandle { cancellation in
+                        signal_cdsi_lookup_complete(promise, context, asyncContext, handle, cancellation)
+                    }
                 }
             }
         }
@@ -356,6 +395,30 @@ internal class TokioAsyncContext: NativeHandleOwner {
     }
 }
 
+/// Cancels in-flight network operations.
+///
+/// A single signal can be passed to any number of operations. Cancelling it
+/// stops all of them, including any started afterwards; cancelling after an
+/// operation has completed has no effect. Cancelled operations throw
+/// `SignalError.cancelled`.
+public class CancellationSignal: NativeHandleOwner {
+    public convenience init() {
+        var handle: OpaquePointer?
+        failOnError(signal_cancellation_signal_new(&handle))
+        self.init(owned: handle!)
+    }
+
+    public func cancel() {
+        self.withNativeHandle { handle in
+            failOnError(signal_cancellation_signal_cancel(handle))
+        }
+    }
+
+    override internal class func destroyNativeHandle(_ handle: OpaquePointer) -> SignalFfiErrorRef? {
+        signal_cancellation_signal_destroy(handle)
+    }
+}
+
 internal class ConnectionManager: NativeHandleOwner {
     convenience init(env: Net.Environment) {
         var handle: OpaquePointer?
@@ -56,7 +56,7 @@ public class Svr3Client {
     ///   - password: User-provided password that will be used to derive the
     ///     encryption key for the secret.
     ///   - maxTries: Maximum allowed number of restore attempts (successful
-    ///     or not). Each call to ``restore(password:shareSet:auth:timeout:)``
+    ///     or not). Each call to ``restore(password:shareSet:auth:timeout:cancellation:)``
     ///     that reaches the server will decrement the counter. Must be
     ///     positive.
     ///   - auth: An instance of ``Auth`` containing the username and password
@@ -66,6 +66,8 @@ public class Svr3Client {
     ///     too far apart in time.
     ///   - timeout: The maximum wall time libsignal is allowed to spend
     ///     communicating with SVR3 service.
+    ///   - cancellation: If this gets cancelled, the operation is abandoned
+    ///     and `SignalError.cancelled` is thrown.
     ///
     /// - Returns:
     ///   A byte array containing a serialized masked share set. It is supposed
@@ -96,25 +98,29 @@ public class Svr3Client {
         password: String,
         maxTries: UInt32,
         auth: Auth,
-        timeout: TimeInterval
+        timeout: TimeInterval,
+        cancellation: CancellationSignal = CancellationSignal()
     ) async throws -> [UInt8] {
         let timeoutMs = durationToMillis(timeout)
         let output = try await invokeAsyncFunction(returning: SignalOwnedBuffer.self) { promise, context in
             self.asyncContext.withNativeHandle { asyncContext in
                 self.connectionManager.withNativeHandle { connectionManager in
-                    secret.withUnsafeBorrowedBuffer { secretBuffer in
-                        signal_svr3_backup(
-                            promise,
-                            context,
-                            asyncContext,
-                            connectionManager,
-                            secretBuffer,
-                            password,
-                            maxTries,
-                            auth.username,
-                            auth.password,
-                            timeoutMs
-                        )
+                    cancellation.withNativeHandle { cancellation in
+                        secret.withUnsafeBorrowedBuffer { secretBuffer in
+                            signal_svr3_backup(
+                                promise,
+                                context,
+                                asyncContext,
+                                connectionManager,
+                                cancellation,
+                                secretBuffer,
+                                password,
+                                maxTries,
+                                auth.username,
+                                auth.password,
+                                timeoutMs
+                            )
+                        }
                     }
                 }
             }
@@ -131,7 +137,7 @@ public class Svr3Client {
     ///   - password: User-provided password that will be used to derive the
     ///     encryption key for the secret.
     ///   - shareSet: A serialized masked share set returned by
-    ///     ``backup(_:password:maxTries:auth:timeout:)``.
+    ///     ``backup(_:password:maxTries:auth:timeout:cancellation:)``.
     ///   - auth: An instance of ``Auth`` containing the username and password
     ///     obtained from the Chat Server. The password is an OTP which is
     ///     generally good for about 15 minutes, therefore it can be reused for
@@ -139,6 +145,8 @@ public class Svr3Client {
     ///     far apart in time.
     ///   - timeout: The maximum wall time libsignal is allowed to spend
     ///     communicating with SVR3 service.
+    ///   - cancellation: If this gets cancelled, the operation is abandoned
+    ///     and `SignalError.cancelled` is thrown.
     ///
     /// - Returns:
     ///   A byte array containing the restored secret.
@@ -168,20 +176,80 @@ public class Svr3Client {
         password: String,
         shareSet: some ContiguousBytes,
         auth: Auth,
-        timeout: TimeInterval
+        timeout: TimeInterval,
+        cancellation: CancellationSignal = CancellationSignal()
     ) async throws -> [UInt8] {
         let timeoutMs = durationToMillis(timeout)
         let output = try await invokeAsyncFunction(returning: SignalOwnedBuffer.self) { promise, context in
             self.asyncContext.withNativeHandle { asyncContext in
                 self.connectionManager.withNativeHandle { connectionManager in
-                    shareSet.withUnsafeBorrowedBuffer { shareSetBuffer in
-                        signal_svr3_restore(
+                    cancellation.withNativeHandle { cancellation in
+                        shareSet.withUnsafeBorrowedBuffer { shareSetBuffer in
+                            signal_svr3_restore(
+                                promise,
+                                context,
+                                asyncContext,
+                                connectionManager,
+                                cancellation,
+                                password,
+                                shareSetBuffer,
+                                auth.username,
+                                auth.password,
+                                timeoutMs
+                            )
+                        }
+                    }
+                }
+            }
+        }
+        defer {
+            signal_free_buffer(output.base, output.length)
+        }
+        return Array(UnsafeBufferPointer(start: output.base, count: output.length))
+    }
+
+    /// Remove a value stored in SVR3.
+    ///
+    /// This method will succeed even if the data has never been backed up in
+    /// the first place.
+    ///
+    /// - Parameters:
+    ///   - auth: An instance of ``Auth`` containing the username and password
+    ///     obtained from the Chat Server. The password is an OTP which is
+    ///     generally good for about 15 minutes, therefore it can be reused for
+    ///     the subsequent calls to either backup or restore that are not too
+    ///     far apart in time.
+    ///   - timeout: The maximum wall time libsignal is allowed to spend
+    ///     communicating with SVR3 service.
+    ///   - cancellation: If this gets cancelled, the operation is abandoned
+    ///     and `SignalError.cancelled` is thrown.
+    ///
+    /// - Throws:
+    ///   On error, throws a ``SignalError``. Expected error cases are
+    ///   - `SignalError.networkError` for a network-level connectivity issue,
+    ///     including timeouts.
+    ///   - `SignalError.networkProtocolError` for an SVR3 or attested
+    ///     connection protocol issue.
+    ///
+    /// ## Notes:
+    ///   - Error messages are expected to be log-safe and not contain any
+    ///     sensitive data.
+    public func remove(
+        auth: Auth,
+        timeout: TimeInterval,
+        cancellation: CancellationSignal = CancellationSignal()
+    ) async throws {
+        let timeoutMs = durationToMillis(timeout)
+        _ = try await invokeAsyncFunction(returning: Bool.self) { promise, context in
+            self.asyncContext.withNativeHandle { asyncContext in
+                self.connectionManager.withNativeHandle { connectionManager in
+                    cancellation.withNativeHandle { cancellation in
+                        signal_svr3_remove(
                             promise,
                             context,
                             asyncContext,
                             connectionManager,
-                            password,
-                            shareSetBuffer,
+                            cancellation,
                             auth.username,
                             auth.password,
                             timeoutMs
@@ -190,9 +258,54 @@ public class Svr3Client {
                 }
             }
         }
+    }
+
+    /// Check connectivity to each of the SVR3 enclaves.
+    ///
+    /// Connects and attests to every enclave, then disconnects without making
+    /// any requests, so no stored data is affected. Failures are reported in
+    /// the result rather than thrown.
+    ///
+    /// - Parameters:
+    ///   - auth: An instance of ``Auth`` containing the username and password
+    ///     obtained from the Chat Server.
+    ///
+    /// - Returns:
+    ///   One ``Svr3EnclaveDiagnostics`` per enclave.
+    public func connectDiagnostics(auth: Auth) async throws -> [Svr3EnclaveDiagnostics] {
+        let output = try await invokeAsyncFunction(returning: SignalOwnedBuffer.self) { promise, context in
+            self.asyncContext.withNativeHandle { asyncContext in
+                self.connectionManager.withNativeHandle { connectionManager in
+                    signal_svr3_connect_diagnostics(
+                        promise,
+                        context,
+                        asyncContext,
+                        connectionManager,
+                        auth.username,
+                        auth.password
+                    )
+                }
+            }
+        }
         defer {
             signal_free_buffer(output.base, output.length)
         }
-        return Array(UnsafeBufferPointer(start: output.base, count: output.length))
+        let json = Data(UnsafeBufferPointer(start: output.base, count: output.length))
+        return try JSONDecoder().decode([Svr3EnclaveDiagnostics].self, from: json)
     }
 }
+
+/// Result of a connection check against a single SVR3 enclave.
+///
+/// Durations are only present for the phases that were reached.
+public struct Svr3EnclaveDiagnostics: Decodable {
+    public var enclave: String
+    /// `nil` if the enclave was reached and attested successfully; otherwise
+    /// one of `Timeout`, `RateLimited`, `TcpConnect`, `ConnectionLost`,
+    /// `Network`, `Attestation`, or `Protocol`.
+    public var error: String?
+    /// A log-safe description of the route used, with credentials masked.
+    public var route: String?
+    public var websocketMillis: UInt64?
+    public var attestationMillis: UInt64?
+}
@@ -111,6 +111,74 @@ SPDX-License-Identifier: AGPL-3.0-only
  */
 #define SignalSECONDS_PER_DAY 86400
 
+#define SignalNET_CERT_ERROR 1
+
+#define SignalNET_DNS_ERROR 2
+
+#define SignalNET_TCP_CONNECTION_FAILED 3
+
+#define SignalNET_SSL_ERROR 4
+
+#define SignalNET_SSL_FAILED_HANDSHAKE 5
+
+#define SignalNET_CONTENT_LENGTH_HEADER_INVALID 6
+
+#define SignalNET_CONTENT_LENGTH_HEADER_DOESNT_MATCH_DATA_SIZE 7
+
+#define SignalNET_HTTP2_FAILED_HANDSHAKE 8
+
+#define SignalNET_TIMEOUT 9
+
+#define SignalNET_CONNECT_TIMEOUT 10
+
+#define SignalNET_FAILURE 11
+
+#define SignalNET_IO 12
+
+#define SignalNET_INCOMING_DATA_INVALID 13
+
+#define SignalNET_REQUEST_HAS_INVALID_HEADER 14
+
+#define SignalNET_UNEXPECTED_FRAME_RECEIVED 15
+
+#define SignalNET_CHANNEL_CLOSED 16
+
+#define SignalNET_WEBSOCKET_ERROR 17
+
+#define SignalNET_CHANNEL_CLOSED_WITH_ERROR 18
+
+#define SignalNET_CHANNEL_CLOSED_BY_REMOTE_PEER 19
+
+#define SignalNET_CHANNEL_CLOSED_BY_LOCAL_PEER 20
+
+#define SignalNET_CHANNEL_IDLE 21
+
+#define SignalNET_NO_SERVICE_CONNECTION 22
+
+#define SignalNET_SERVER_REQUEST_MISSING_ID 23
+
+#define SignalNET_FAILED_TO_PASS_MESSAGE_TO_INCOMING_CHANNEL 24
+
+#define SignalNET_HTTP_INTERRUPTED_DURING_RECEIVE 25
+
+#define SignalNET_INVALID_HTTP_REQUEST_COMPONENT 26
+
+#define SignalNET_RATE_LIMITED 27
+
+#define SignalNET_ALL_ROUTES_FAILED 28
+
+#define SignalNET_SEND_TIMEOUT 29
+
+#define SignalNET_CLIENT_DEPRECATED 30
+
+#define SignalSVR_PROTOCOL 101
+
+#define SignalSVR_ATTESTATION_ERROR 102
+
+#define SignalSVR_NETWORK_CHANGED 103
+
+#define SignalSVR_CANCELLED 104
+
 typedef enum {
   SignalCiphertextMessageTypeWhisper = 2,
   SignalCiphertextMessageTypePreKey = 3,
@@ -185,8 +253,11 @@ typedef enum {
   SignalErrorCodeNetwork = 133,
   SignalErrorCodeNetworkProtocol = 134,
   SignalErrorCodeRateLimited = 135,
+  SignalErrorCodeCancelled = 136,
+  SignalErrorCodeClientDeprecated = 137,
   SignalErrorCodeSvrDataMissing = 150,
   SignalErrorCodeSvrRestoreFailed = 151,
+  SignalErrorCodeSvrEnclaveDisagreement = 152,
 } SignalErrorCode;
 
 /**
@@ -200,6 +271,8 @@ typedef struct SignalAes256GcmEncryption SignalAes256GcmEncryption;
 
 typedef struct SignalAes256GcmSiv SignalAes256GcmSiv;
 
+typedef struct SignalCancellationSignal SignalCancellationSignal;
+
 typedef struct SignalCdsiLookup SignalCdsiLookup;
 
 typedef struct SignalChat SignalChat;
@@ -564,6 +637,8 @@ uint32_t signal_error_get_type(const SignalFfiError *err);
 
 SignalFfiError *signal_error_get_retry_after_seconds(const SignalFfiError *err, uint32_t *out);
 
+SignalFfiError *signal_error_get_svr_accepted(const SignalFfiError *err, SignalOwnedBuffer *out);
+
 void signal_error_free(SignalFfiError *err);
 
 SignalFfiError *signal_identitykeypair_deserialize(SignalPrivateKey **private_key, SignalPublicKey **public_key, SignalBorrowedBuffer input);
@@ -1306,8 +1381,18 @@ SignalFfiError *signal_tokio_async_context_destroy(SignalTokioAsyncContext *p);
 
 SignalFfiError *signal_connection_manager_new(SignalConnectionManager **out, uint8_t environment);
 
+SignalFfiError *signal_connection_manager_on_network_change(const SignalConnectionManager *connection_manager);
+
 SignalFfiError *signal_connection_manager_destroy(SignalConnectionManager *p);
 
+SignalFfiError *signal_environment_fingerprint(const char **out, uint8_t environment);
+
+SignalFfiError *signal_cancellation_signal_new(SignalCancellationSignal **out);
+
+SignalFfiError *signal_cancellation_signal_cancel(const SignalCancellationSignal *signal);
+
+SignalFfiError *signal_cancellation_signal_destroy(SignalCancellationSignal *p);
+
 SignalFfiError *signal_lookup_request_new(SignalLookupRequest **out);
 
 SignalFfiError *signal_lookup_request_add_e164(const SignalLookupRequest *request, const char *e164);
@@ -1324,19 +1409,23 @@ SignalFfiError *signal_lookup_request_destroy(SignalLookupRequest *p);
 
 SignalFfiError *signal_cdsi_lookup_destroy(SignalCdsiLookup *p);
 
-SignalFfiError *signal_cdsi_lookup_new(SignalCPromiseCdsiLookup promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const char *username, const char *password, const SignalLookupRequest *request, uint32_t timeout_millis);
+SignalFfiError *signal_cdsi_lookup_new(SignalCPromiseCdsiLookup promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const SignalCancellationSignal *cancellation, const char *username, const char *password, const SignalLookupRequest *request, uint32_t timeout_millis);
 
 SignalFfiError *signal_cdsi_lookup_token(SignalOwnedBuffer *out, const SignalCdsiLookup *lookup);
 
-SignalFfiError *signal_cdsi_lookup_complete(SignalCPromiseFfiCdsiLookupResponse promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalCdsiLookup *lookup);
+SignalFfiError *signal_cdsi_lookup_complete(SignalCPromiseFfiCdsiLookupResponse promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalCdsiLookup *lookup, const SignalCancellationSignal *cancellation);
 
 SignalFfiError *signal_create_otp(const char **out, const char *username, SignalBorrowedBuffer secret);
 
 SignalFfiError *signal_create_otp_from_base64(const char **out, const char *username, const char *secret);
 
-SignalFfiError *signal_svr3_backup(SignalCPromiseOwnedBufferOfc_uchar promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, SignalBorrowedBuffer secret, const char *password, uint32_t max_tries, const char *username, const char *enclave_password, uint32_t op_timeout_ms);
+SignalFfiError *signal_svr3_backup(SignalCPromiseOwnedBufferOfc_uchar promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const SignalCancellationSignal *cancellation, SignalBorrowedBuffer secret, const char *password, uint32_t max_tries, const char *username, const char *enclave_password, uint32_t op_timeout_ms);
 
-SignalFfiError *signal_svr3_restore(SignalCPromiseOwnedBufferOfc_uchar promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const char *password, SignalBorrowedBuffer share_set, const char *username, const char *enclave_password, uint32_t op_timeout_ms);
+SignalFfiError *signal_svr3_restore(SignalCPromiseOwnedBufferOfc_uchar promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const SignalCancellationSignal *cancellation, const char *password, SignalBorrowedBuffer share_set, const char *username, const char *enclave_password, uint32_t op_timeout_ms);
+
+SignalFfiError *signal_svr3_remove(SignalCPromisebool promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const SignalCancellationSignal *cancellation, const char *username, const char *enclave_password, uint32_t op_timeout_ms);
+
+SignalFfiError *signal_svr3_connect_diagnostics(SignalCPromiseOwnedBufferOfc_uchar promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalConnectionManager *connection_manager, const char *username, const char *enclave_password);
 
 SignalFfiError *signal_chat_destroy(SignalChat *p);
 
@@ -1500,4 +1589,10 @@ SignalFfiError *signal_testing_cdsi_lookup_response_convert(SignalCPromiseFfiCds
 
 SignalFfiError *signal_testing_cdsi_lookup_error_convert(bool *out);
 
+SignalFfiError *signal_testing_cdsi_rate_limited_error_convert(bool *out);
+
+SignalFfiError *signal_testing_svr3_client_deprecated_error_convert(bool *out);
+
+SignalFfiError *signal_testing_svr3_rate_limited_error_convert(bool *out);
+
 #endif /* SIGNAL_FFI_H_ */
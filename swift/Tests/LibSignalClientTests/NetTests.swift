@@ -51,6 +51,41 @@ final class NetTests: XCTestCase {
         }
     }
 
+    func testClientDeprecatedErrorConversion() async throws {
+        do {
+            var ignoredOut = false
+            try checkError(signal_testing_svr3_client_deprecated_error_convert(&ignoredOut))
+            XCTFail("should have failed")
+        } catch SignalError.clientDeprecated(_) {
+            // good
+        }
+    }
+
+    func testRateLimitedErrorConversion() async throws {
+        do {
+            var ignoredOut = false
+            try checkError(signal_testing_svr3_rate_limited_error_convert(&ignoredOut))
+            XCTFail("should have failed")
+        } catch SignalError.rateLimitedError(retryAfter: let retryAfter, message: _) {
+            XCTAssertEqual(retryAfter, 42)
+        }
+    }
+
+    func testEnvironmentFingerprint() throws {
+        let staging = try XCTUnwrap(JSONSerialization.jsonObject(with: Data(Net.Environment.staging.fingerprint.utf8)) as? [String: Any])
+        let production = try XCTUnwrap(JSONSerialization.jsonObject(with: Data(Net.Environment.production.fingerprint.utf8)) as? [String: Any])
+
+        let stagingSvr3 = try XCTUnwrap(staging["svr3"] as? [String: Any])
+        XCTAssertEqual(stagingSvr3["serverIds"] as? [Int], [1, 2])
+        let stagingNitro = try XCTUnwrap(stagingSvr3["nitro"] as? [String: Any])
+        for key in ["hostname", "port", "pathPrefix", "routeWeights", "mrEnclave", "hash"] {
+            XCTAssertTrue(stagingNitro.keys.contains(key), "missing \(key)")
+        }
+
+        let productionSvr3 = try XCTUnwrap(production["svr3"] as? [String: Any])
+        XCTAssertNotEqual(stagingSvr3["hash"] as? String, productionSvr3["hash"] as? String)
+    }
+
     func testCdsiLookupCompilation() async throws {
         try throwSkipForCompileOnlyTest()
 
@@ -66,6 +101,23 @@ final class NetTests: XCTestCase {
             _ = entry.e164
         }
     }
+
+    func testCdsiLookupCancelledBeforeStart() async throws {
+        let auth = Auth(username: "username", password: "password")
+        let request = try CdsiLookupRequest(e164s: [], prevE164s: [], acisAndAccessKeys: [], token: nil, returnAcisWithoutUaks: false)
+        let net = Net(env: .staging)
+        let cancellation = CancellationSignal()
+        cancellation.cancel()
+
+        do {
+            _ = try await net.cdsiLookup(auth: auth, request: request, timeout: TimeInterval(10), cancellation: cancellation)
+            XCTFail("Should have thrown")
+        } catch SignalError.cancelled(_) {
+            // Success!
+        } catch {
+            XCTFail("Unexpected error: \(error)")
+        }
+    }
 }
 
 final class Svr3Tests: TestCaseBase {
@@ -129,6 +181,41 @@ final class Svr3Tests: TestCaseBase {
         }
     }
 
+    func testRemoveDeletesData() async throws {
+        let auth = try Auth(username: self.username, enclaveSecret: self.getEnclaveSecret())
+        let net = Net(env: .staging)
+
+        let shareSet = try await net.svr3.backup(
+            self.storedSecret,
+            password: "password",
+            maxTries: 10,
+            auth: auth,
+            timeout: self.defaultTimeout
+        )
+        try await net.svr3.remove(auth: auth, timeout: self.defaultTimeout)
+
+        do {
+            _ = try await net.svr3.restore(
+                password: "password",
+                shareSet: shareSet,
+                auth: auth,
+                timeout: self.defaultTimeout
+            )
+            XCTFail("Should have thrown")
+        } catch SignalError.svrDataMissing(_) {
+            // Success!
+        } catch {
+            XCTFail("Unexpected exception: '\(error)'")
+        }
+    }
+
+    func testRemoveWithoutBackup() async throws {
+        let auth = try Auth(username: self.username, enclaveSecret: self.getEnclaveSecret())
+        let net = Net(env: .staging)
+
+        try await net.svr3.remove(auth: auth, timeout: self.defaultTimeout)
+    }
+
     func testCorruptedShareSet() async throws {
         let auth = try Auth(username: self.username, enclaveSecret: self.getEnclaveSecret())
         let net = Net(env: .staging)
@@ -293,6 +380,29 @@ final class Svr3Tests: TestCaseBase {
             XCTFail("Unexpected error: \(error)")
         }
     }
+
+    func testCancelledBeforeStart() async throws {
+        let auth = try Auth(username: self.username, enclaveSecret: self.getEnclaveSecret())
+        let net = Net(env: .staging)
+        let cancellation = CancellationSignal()
+        cancellation.cancel()
+
+        do {
+            _ = try await net.svr3.backup(
+                self.storedSecret,
+                password: "password",
+                maxTries: 1,
+                auth: auth,
+                timeout: self.defaultTimeout,
+                cancellation: cancellation
+            )
+            XCTFail("Should have thrown")
+        } catch SignalError.cancelled(_) {
+            // Success!
+        } catch {
+            XCTFail("Unexpected error: \(error)")
+        }
+    }
 }
 
 #endif